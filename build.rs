@@ -1,24 +1,34 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::hash::Hasher;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use cargo_bpf_lib as cargo_bpf;
 
 const CAPNP_SCHEMA: &'static str = "schema/ingraind.capnp";
+const PROTOBUF_SCHEMA: &'static str = "schema/ingraind.proto";
+const SYSCALL_TABLES: &'static str = "syscalls";
+const PROBE_HASHES: &'static str = "probe-hashes";
 
 fn main() {
     let cargo = PathBuf::from(env::var("CARGO").unwrap());
 
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let probes = Path::new("ingraind-probes");
-    cargo_bpf::build(
-        &cargo,
-        &probes,
-        &out_dir.join("target"),
-        Vec::new(),
-    )
-    .expect("couldn't compile ingraind-probes");
+    if let Some(source) = kernel_source(&out_dir) {
+        // picked up by redbpf when building the probes
+        env::set_var("KERNEL_SOURCE", &source);
+    }
+
+    build_probes(&cargo, &probes, &out_dir);
 
     build_capnp();
+    build_protobuf();
+    build_syscall_table(&out_dir);
+    build_probe_registry(&probes, &out_dir);
 
     cargo_bpf::probe_files(&probes)
         .expect("couldn't list probe files")
@@ -28,6 +38,162 @@ fn main() {
         });
 }
 
+/// Builds the probes whose sources changed since the last build. They all
+/// share `OUT_DIR/target`, so the dependencies of the package are compiled
+/// once, and cargo spreads the build over the jobs it's allowed.
+fn build_probes(cargo: &Path, package: &Path, out_dir: &Path) {
+    let target = out_dir.join("target");
+    let hashes_path = out_dir.join(PROBE_HASHES);
+    let mut hashes = fs::read_to_string(&hashes_path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?.to_string(), fields.next()?.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let current = probe_hashes(package);
+    let stale = current
+        .iter()
+        .filter(|(name, hash)| {
+            hashes.get(*name) != Some(hash) || !probe_elf(&target, name).exists()
+        })
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    if stale.is_empty() {
+        return;
+    }
+
+    cargo_bpf::build(cargo, package, &target, stale.clone())
+        .expect("couldn't compile ingraind-probes");
+    for name in stale {
+        let hash = current[&name].clone();
+        hashes.insert(name, hash);
+    }
+
+    let hashes = hashes
+        .iter()
+        .map(|(name, hash)| format!("{} {}\n", name, hash))
+        .collect::<String>();
+    fs::write(&hashes_path, hashes).expect("couldn't write probe hashes");
+}
+
+fn probe_elf(target: &Path, name: &str) -> PathBuf {
+    target
+        .join("bpf/programs")
+        .join(name)
+        .join(format!("{}.elf", name))
+}
+
+/// The names of the probes, which are the binaries of the package
+fn probe_names(package: &Path) -> Vec<String> {
+    let manifest = fs::read_to_string(package.join("Cargo.toml")).unwrap();
+    let manifest = manifest.parse::<toml::Value>().unwrap();
+    manifest["bin"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|bin| bin["name"].as_str())
+        .map(String::from)
+        .collect()
+}
+
+/// Hashes the sources of each probe, which are its own `main.rs` and every
+/// other file of the package, along with the contents of the kernel headers
+/// used
+fn probe_hashes(package: &Path) -> HashMap<String, String> {
+    let names = probe_names(package);
+
+    let is_main = |file: &str| {
+        names
+            .iter()
+            .any(|name| file.ends_with(&format!("src/{}/main.rs", name)))
+    };
+    let mut files = cargo_bpf::probe_files(package).expect("couldn't list probe files");
+    files.sort();
+
+    let mut common = DefaultHasher::new();
+    if let Some(source) = headers_source() {
+        hash_headers(&mut common, &source);
+    }
+    for file in files.iter().filter(|file| !is_main(file)) {
+        common.write(file.as_bytes());
+        common.write(&fs::read(file).unwrap_or_default());
+    }
+    let common = common.finish();
+
+    names
+        .iter()
+        .map(|name| {
+            let main = format!("src/{}/main.rs", name);
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u64(common);
+            for file in files.iter().filter(|file| file.ends_with(&main)) {
+                hasher.write(&fs::read(file).unwrap_or_default());
+            }
+            (name.to_string(), format!("{:016x}", hasher.finish()))
+        })
+        .collect()
+}
+
+/// The kernel tree the probes are built against: `KERNEL_SOURCE`, or the
+/// headers of the running kernel redbpf falls back to
+fn headers_source() -> Option<PathBuf> {
+    if let Some(source) = env::var_os("KERNEL_SOURCE") {
+        return Some(PathBuf::from(source));
+    }
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").ok()?;
+    Some(PathBuf::from(format!(
+        "/lib/modules/{}/build",
+        release.trim()
+    )))
+}
+
+/// Hashes the contents of the headers of a kernel tree: the generic ones,
+/// and those of the target architecture
+fn hash_headers(hasher: &mut DefaultHasher, source: &Path) {
+    let arch = match env::var("CARGO_CFG_TARGET_ARCH").unwrap().as_str() {
+        "x86" | "x86_64" => "x86",
+        "aarch64" => "arm64",
+        "powerpc" | "powerpc64" => "powerpc",
+        arch => arch,
+    }
+    .to_string();
+
+    for dir in &[
+        source.join("include"),
+        source.join("arch").join(arch).join("include"),
+    ] {
+        let mut files = vec![];
+        list_files(dir, &mut files);
+        files.sort();
+        for file in files {
+            hasher.write(
+                file.strip_prefix(source)
+                    .unwrap()
+                    .to_string_lossy()
+                    .as_bytes(),
+            );
+            hasher.write(&fs::read(&file).unwrap_or_default());
+        }
+    }
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        if path.is_dir() {
+            list_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
 #[cfg(feature = "capnp-encoding")]
 fn build_capnp() {
     use capnpc::{CompilerCommand, RustEdition};
@@ -39,4 +205,516 @@ fn build_capnp() {
 }
 
 #[cfg(not(feature = "capnp-encoding"))]
-fn build_capnp() {}
\ No newline at end of file
+fn build_capnp() {}
+
+#[cfg(feature = "protobuf-encoding")]
+fn build_protobuf() {
+    println!("cargo:rerun-if-changed={}", PROTOBUF_SCHEMA);
+    prost_build::compile_protos(&[PROTOBUF_SCHEMA], &["schema"])
+        .expect("protobuf schema generation failed");
+}
+
+#[cfg(not(feature = "protobuf-encoding"))]
+fn build_protobuf() {}
+
+/// Generates the system call numbers and names of the target architecture,
+/// which are left empty for architectures without a table
+fn build_syscall_table(out_dir: &Path) {
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let path = Path::new(SYSCALL_TABLES).join(format!("{}.tbl", arch));
+    println!("cargo:rerun-if-changed={}", path.display());
+    let table = match fs::read_to_string(&path) {
+        Ok(table) => table,
+        Err(_) => {
+            println!(
+                "cargo:warning=no syscall table for {}, syscalls are reported by number",
+                arch
+            );
+            String::new()
+        }
+    };
+
+    let mut code = "pub const SYSCALLS: &[(u64, &str)] = &[\n".to_string();
+    for line in table
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+    {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let nr = match fields.as_slice() {
+            [nr, _] => nr.parse::<u64>().ok(),
+            _ => None,
+        };
+        let nr = nr.unwrap_or_else(|| panic!("invalid syscall table entry: {}", line));
+        code.push_str(&format!("    ({}, {:?}),\n", nr, fields[1]));
+    }
+    code.push_str("];\n");
+
+    fs::write(out_dir.join("syscall_table.rs"), code).expect("couldn't write the syscall table");
+}
+
+/// A program or map declared in the source of a probe
+struct Item {
+    /// The kind of program, as the prefix of its section, or the type of map
+    kind: String,
+    name: String,
+}
+
+/// The maps that follow conventions shared by probes, which grains look up
+/// whatever their probe. Every probe declaring one must give it the same
+/// type.
+const COMMON_MAPS: &[&str] = &["settings", "filters", "filter_pids", "sampling"];
+
+/// The prefixes of the sections of programs
+const PROGRAM_KINDS: &[&str] = &[
+    "kprobe",
+    "kretprobe",
+    "uprobe",
+    "uretprobe",
+    "tracepoint",
+    "socketfilter",
+    "xdp",
+    "perf_event",
+    "cgroup",
+];
+
+/// Generates a module for every probe, with its code, its programs, its
+/// maps with the events of the perf maps, and the requirements it declares
+/// with `meta!`. The names of the programs and
+/// maps are constants, so grains referring to ones their probe doesn't
+/// declare fail to build, and so do the types of events that aren't in the
+/// probe's module of `ingraind_probes`. The maps shared by probes are
+/// constants of `common`. `PROBES` lists every probe, to find the one built
+/// into some code, and to check the probes replacing it.
+///
+/// The declarations are read from the probe's `main.rs`: programs by their
+/// attribute or `link_section`, and maps by their `#[map]` attribute and
+/// the static following it. Items under a `#[cfg(target_arch)]` of another
+/// architecture are skipped. The values of `meta!` are evaluated in the
+/// scope of `ingraind_probes`.
+fn build_probe_registry(package: &Path, out_dir: &Path) {
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let target = out_dir.join("target");
+    let names = probe_names(package);
+
+    let mut code = String::from(
+        "/// A probe built into ingraind\n\
+         pub struct Probe {\n    \
+             pub name: &'static str,\n    \
+             pub code: &'static [u8],\n    \
+             pub programs: &'static [(&'static str, &'static str)],\n    \
+             pub maps: &'static [(&'static str, &'static str)],\n    \
+             pub events: &'static [(&'static str, &'static str)],\n    \
+             pub meta: &'static [(&'static str, u32)],\n\
+         }\n\n",
+    );
+    code.push_str("pub const PROBES: &[Probe] = &[\n");
+    for name in names.iter() {
+        code.push_str(&format!(
+            "    Probe {{\n        name: {0:?},\n        code: {0}::CODE,\n        \
+             programs: {0}::PROGRAMS,\n        maps: {0}::MAPS,\n        \
+             events: {0}::EVENTS,\n        meta: {0}::META,\n    }},\n",
+            name
+        ));
+    }
+    code.push_str("];\n");
+
+    let mut common = HashMap::new();
+    for name in names.iter() {
+        let path = package.join("src").join(name).join("main.rs");
+        let source = fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("couldn't read the probe {}", path.display()));
+        let (programs, maps, metas) = probe_items(&source, &arch);
+
+        code.push_str(&format!("\npub mod {} {{\n", name));
+        code.push_str(&format!(
+            "    pub const CODE: &[u8] = include_bytes!({:?});\n\n",
+            probe_elf(&target, name)
+        ));
+
+        code.push_str("    /// The kind and name of every program, as in its section\n");
+        code.push_str("    pub const PROGRAMS: &[(&str, &str)] = &[\n");
+        for program in programs.iter() {
+            code.push_str(&format!(
+                "        ({:?}, {:?}),\n",
+                program.kind, program.name
+            ));
+        }
+        code.push_str("    ];\n\n");
+
+        code.push_str("    /// The name and type of every map\n");
+        code.push_str("    pub const MAPS: &[(&str, &str)] = &[\n");
+        for map in maps.iter() {
+            code.push_str(&format!("        ({:?}, {:?}),\n", map.name, map.kind));
+        }
+        code.push_str("    ];\n\n");
+
+        let events = maps
+            .iter()
+            .filter_map(|map| Some((map.name.as_str(), perf_map_event(&map.kind)?)))
+            .collect::<Vec<_>>();
+        code.push_str("    /// The type of the events of every perf map\n");
+        code.push_str("    pub const EVENTS: &[(&str, &str)] = &[\n");
+        for (map, event) in events.iter() {
+            code.push_str(&format!("        ({:?}, {:?}),\n", map, event));
+        }
+        code.push_str("    ];\n\n");
+
+        code.push_str("    /// The `meta/` sections, without the prefix, and their values\n");
+        code.push_str("    pub const META: &[(&str, u32)] = {\n");
+        code.push_str("        #[allow(unused_imports)]\n");
+        code.push_str("        use ingraind_probes::*;\n");
+        code.push_str("        &[\n");
+        for (section, value) in metas.iter() {
+            code.push_str(&format!("            ({:?}, {}),\n", section, value));
+        }
+        code.push_str("        ]\n    };\n\n");
+
+        // fails the build if the events aren't types of `ingraind_probes`
+        if !events.is_empty() {
+            code.push_str("    #[allow(dead_code, unused_imports, non_camel_case_types)]\n");
+            code.push_str("    mod event_types {\n");
+            code.push_str("        use ingraind_probes::sampling::Sampled;\n");
+            code.push_str("        use ingraind_probes::*;\n");
+            code.push_str(&format!("        use ingraind_probes::{}::*;\n\n", name));
+            for (map, event) in events.iter() {
+                code.push_str(&format!("        type {} = {};\n", map, event));
+            }
+            code.push_str("    }\n\n");
+        }
+
+        let mut program_names = programs.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        // kprobes and kretprobes of the same function share the name
+        program_names.sort();
+        program_names.dedup();
+        code.push_str("    pub mod programs {\n");
+        for program in program_names {
+            code.push_str(&format!(
+                "        pub const {}: &str = {:?};\n",
+                program.to_uppercase(),
+                program
+            ));
+        }
+        code.push_str("    }\n\n");
+
+        code.push_str("    pub mod maps {\n");
+        for map in maps.iter() {
+            code.push_str(&format!("        /// `{}`\n", map.kind));
+            code.push_str(&format!(
+                "        pub const {}: &str = {:?};\n",
+                map.name.to_uppercase(),
+                map.name
+            ));
+        }
+        code.push_str("    }\n}\n");
+
+        for map in maps
+            .into_iter()
+            .filter(|m| COMMON_MAPS.contains(&m.name.as_str()))
+        {
+            let kind = common
+                .entry(map.name.clone())
+                .or_insert_with(|| map.kind.clone());
+            if *kind != map.kind {
+                panic!(
+                    "the {} map of the {} probe is a {}, and a {} in others",
+                    map.name, name, map.kind, kind
+                );
+            }
+        }
+    }
+
+    code.push_str("\n/// The maps shared by probes\npub mod common {\n    pub mod maps {\n");
+    for name in COMMON_MAPS.iter() {
+        let kind = common
+            .get(*name)
+            .unwrap_or_else(|| panic!("no probe declares the {} map", name));
+        code.push_str(&format!("        /// `{}`\n", kind));
+        code.push_str(&format!(
+            "        pub const {}: &str = {:?};\n",
+            name.to_uppercase(),
+            name
+        ));
+    }
+    code.push_str("    }\n}\n");
+
+    code.push_str("\n/// The probe built into `code`\n");
+    code.push_str("pub fn find(code: &[u8]) -> Option<&'static Probe> {\n");
+    code.push_str("    PROBES.iter().find(|probe| std::ptr::eq(probe.code, code))\n}\n");
+
+    code.push_str("\n/// The `EVENTS` of the probe built into `code`\n");
+    code.push_str("pub fn events(code: &[u8]) -> &'static [(&'static str, &'static str)] {\n");
+    code.push_str("    find(code).map_or(&[], |probe| probe.events)\n}\n");
+
+    fs::write(out_dir.join("probes.rs"), code).expect("couldn't write the probe registry");
+}
+
+/// The programs, maps, and `meta!` sections with their values declared in
+/// the source of a probe
+fn probe_items(source: &str, arch: &str) -> (Vec<Item>, Vec<Item>, Vec<(String, String)>) {
+    let (mut programs, mut maps, mut metas) = (vec![], vec![], vec![]);
+    let mut lines = source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//"));
+    let mut attributes = vec![];
+
+    while let Some(line) = lines.next() {
+        if line.starts_with("#[") {
+            // attributes may span lines up to their closing bracket
+            let mut attribute = line.to_string();
+            while attribute.matches('[').count() > attribute.matches(']').count() {
+                match lines.next() {
+                    Some(line) => attribute.push_str(line),
+                    None => break,
+                }
+            }
+            attributes.push(attribute);
+            continue;
+        }
+        if line.starts_with("meta!(") {
+            let mut invocation = line.to_string();
+            while !invocation.ends_with(';') {
+                match lines.next() {
+                    Some(line) => invocation.push_str(line),
+                    None => break,
+                }
+            }
+            metas.push(
+                meta_section(&invocation)
+                    .unwrap_or_else(|| panic!("invalid meta section: {}", invocation)),
+            );
+            continue;
+        }
+        if attributes.is_empty() {
+            continue;
+        }
+
+        // the item the attributes are on, up to its body or initializer
+        let mut item = line.to_string();
+        while !item.ends_with(';') && !item.ends_with('{') {
+            match lines.next() {
+                Some(line) => {
+                    item.push(' ');
+                    item.push_str(line);
+                }
+                None => break,
+            }
+        }
+        let attributes = std::mem::replace(&mut attributes, vec![]);
+        if attributes.iter().any(|attr| other_arch(attr, arch)) {
+            continue;
+        }
+
+        for attr in attributes.iter() {
+            let (name, arg) = parse_attribute(attr);
+            match name {
+                "map" => {
+                    let (ident, kind) = static_declaration(&item)
+                        .unwrap_or_else(|| panic!("invalid declaration of map: {}", item));
+                    maps.push(Item {
+                        kind,
+                        name: arg.unwrap_or(ident).to_string(),
+                    });
+                }
+                "link_section" => {
+                    let section = arg.unwrap_or_default();
+                    let mut parts = section.splitn(2, '/');
+                    if let (Some(kind), Some(name)) = (parts.next(), parts.next()) {
+                        if PROGRAM_KINDS.contains(&kind) {
+                            programs.push(Item {
+                                kind: kind.to_string(),
+                                name: name.to_string(),
+                            });
+                        }
+                    }
+                }
+                "kprobe" | "kretprobe" | "uprobe" | "uretprobe" | "xdp" | "socket_filter"
+                | "tracepoint" => {
+                    let function = function_name(&item)
+                        .unwrap_or_else(|| panic!("invalid {} program: {}", name, item));
+                    // uprobes are named after their function
+                    let program = match name {
+                        "uprobe" | "uretprobe" => function,
+                        _ => arg.unwrap_or(function),
+                    };
+                    programs.push(Item {
+                        kind: name.replace('_', ""),
+                        name: program.to_string(),
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    (programs, maps, metas)
+}
+
+/// The section of `meta!("meta/<section>", value);` and its value, which is
+/// 1 if it's left out
+fn meta_section(invocation: &str) -> Option<(String, String)> {
+    let args = invocation.strip_prefix("meta!(")?.strip_suffix(");")?;
+    let args = args.trim().strip_prefix('"')?;
+    let end = args.find('"')?;
+    let section = args[..end].strip_prefix("meta/")?;
+    let value = args[end + 1..].trim().trim_start_matches(',').trim();
+    let value = if value.is_empty() { "1" } else { value };
+
+    Some((section.to_string(), value.to_string()))
+}
+
+/// The name of `#[name(arg)]` or `#[name = arg]`, and its argument without
+/// quotes, if it has one
+fn parse_attribute(attr: &str) -> (&str, Option<&str>) {
+    let inner = attr.trim_start_matches("#[").trim_end_matches(']').trim();
+    let end = inner
+        .find(|c: char| c == '(' || c == '=' || c.is_whitespace())
+        .unwrap_or_else(|| inner.len());
+    let (name, rest) = inner.split_at(end);
+    let rest = rest.trim();
+    let arg = if rest.starts_with('(') && rest.ends_with(')') {
+        Some(&rest[1..rest.len() - 1])
+    } else if rest.starts_with('=') {
+        Some(&rest[1..])
+    } else {
+        None
+    };
+
+    (
+        name,
+        arg.map(|arg| arg.trim().trim_matches('"'))
+            .filter(|arg| !arg.is_empty()),
+    )
+}
+
+/// Whether `attr` is a `#[cfg(target_arch)]` of another architecture
+fn other_arch(attr: &str, arch: &str) -> bool {
+    match parse_attribute(attr) {
+        ("cfg", Some(cfg)) => {
+            cfg.starts_with("target_arch") && !cfg.contains(&format!("{:?}", arch))
+        }
+        _ => false,
+    }
+}
+
+/// The name and type of `static mut name: Type = ...;`
+fn static_declaration(item: &str) -> Option<(&str, String)> {
+    let declaration = item.splitn(2, "static ").nth(1)?;
+    let declaration = declaration.trim_start_matches("mut ");
+    let mut parts = declaration.splitn(2, ':');
+    let name = parts.next()?.trim();
+    let kind = parts.next()?.split('=').next()?.trim();
+
+    Some((name, kind.to_string()))
+}
+
+/// The name of the function declared by `item`
+fn function_name(item: &str) -> Option<&str> {
+    let declaration = item.splitn(2, "fn ").nth(1)?;
+    Some(declaration.split(|c| c == '(' || c == '<').next()?.trim())
+}
+
+/// The type of the events of a `PerfMap<Event>`
+fn perf_map_event(kind: &str) -> Option<&str> {
+    Some(kind.strip_prefix("PerfMap<")?.strip_suffix('>')?.trim())
+}
+
+/// Finds the kernel headers to build the probes with, if redbpf's own
+/// lookup of the running kernel's headers needs help:
+///
+///  * `KERNEL_SOURCE` is used as it is
+///  * `KERNEL_HEADERS_PACKAGE` is a list of `.deb`, `.rpm` or tarball
+///    packages separated by `:`, which are extracted into `OUT_DIR`
+///  * `KERNEL_VERSION` is looked up in the usual places distributions
+///    install headers to
+///  * otherwise redbpf uses the headers of the running kernel
+fn kernel_source(out_dir: &Path) -> Option<PathBuf> {
+    for var in &["KERNEL_SOURCE", "KERNEL_HEADERS_PACKAGE", "KERNEL_VERSION"] {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+
+    if env::var_os("KERNEL_SOURCE").is_some() {
+        return None;
+    }
+
+    if let Some(packages) = env::var_os("KERNEL_HEADERS_PACKAGE") {
+        let dest = out_dir.join("kernel-headers");
+        for package in env::split_paths(&packages) {
+            println!("cargo:rerun-if-changed={}", package.display());
+            extract_package(&package, &dest);
+        }
+        let source = find_kernel_tree(&dest, 5)
+            .unwrap_or_else(|| panic!("no kernel headers found in {:?}", packages));
+        return Some(source);
+    }
+
+    let version = env::var("KERNEL_VERSION").ok()?;
+
+    [
+        format!("/lib/modules/{}/build", version),
+        format!("/lib/modules/{}/source", version),
+        format!("/usr/src/kernels/{}", version),
+        format!("/usr/src/linux-headers-{}", version),
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .find(|path| is_kernel_tree(path))
+}
+
+fn extract_package(package: &Path, dest: &Path) {
+    fs::create_dir_all(dest).unwrap();
+    let name = package.to_string_lossy();
+    let status = if name.ends_with(".deb") {
+        Command::new("dpkg-deb")
+            .arg("-x")
+            .arg(package)
+            .arg(dest)
+            .status()
+    } else if name.ends_with(".rpm") {
+        Command::new("sh")
+            .arg("-c")
+            .arg("rpm2cpio \"$0\" | cpio -idm --quiet")
+            .arg(fs::canonicalize(package).unwrap())
+            .current_dir(dest)
+            .status()
+    } else {
+        Command::new("tar")
+            .arg("-xf")
+            .arg(package)
+            .arg("-C")
+            .arg(dest)
+            .status()
+    };
+
+    match status {
+        Ok(status) if status.success() => {}
+        _ => panic!("couldn't extract kernel headers from {}", name),
+    }
+}
+
+fn is_kernel_tree(path: &Path) -> bool {
+    path.join("include/linux").is_dir() && path.join("Makefile").is_file()
+}
+
+/// Finds the root of a kernel tree at most `depth` levels below `path`
+fn find_kernel_tree(path: &Path, depth: usize) -> Option<PathBuf> {
+    if is_kernel_tree(path) {
+        return Some(path.to_path_buf());
+    }
+    if depth == 0 {
+        return None;
+    }
+
+    let mut entries = fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    entries
+        .iter()
+        .find_map(|entry| find_kernel_tree(entry, depth - 1))
+}
@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+use core::mem;
+use ingraind_probes::arp::{Event, ETH_P_ARP};
+use redbpf_probes::xdp::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("events")]
+static mut events: PerfMap<Event> = PerfMap::with_max_entries(1024);
+
+#[xdp("arp")]
+pub fn probe(ctx: XdpContext) -> XdpResult {
+    let eth = unsafe { *ctx.eth()? };
+    if u16::from_be(eth.h_proto) != ETH_P_ARP {
+        return Ok(XdpAction::Pass);
+    }
+
+    // ARP is rare enough that parsing can be left to user space
+    let event = Event {
+        h_source: eth.h_source,
+    };
+
+    unsafe {
+        events.insert(
+            &ctx,
+            &MapData::with_payload(event, mem::size_of::<ethhdr>() as u32, ctx.len() as u32),
+        )
+    };
+
+    Ok(XdpAction::Pass)
+}
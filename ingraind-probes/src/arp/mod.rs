@@ -0,0 +1,7 @@
+pub const ETH_P_ARP: u16 = 0x0806;
+
+#[repr(C)]
+pub struct Event {
+    /// The source address of the Ethernet frame
+    pub h_source: [u8; 6],
+}
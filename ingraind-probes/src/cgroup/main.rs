@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::cgroup::{CgroupCounter, FILE_READ, FILE_WRITE, NET_RX, NET_TX};
+use ingraind_probes::maps::Counters;
+use ingraind_probes::meta;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+meta!("meta/needs=bpf_get_current_cgroup_id");
+
+const S_IFMT: u16 = 0o170000;
+const S_IFREG: u16 = 0o100000;
+
+/// The bytes of each cgroup, read by ingraind every interval
+#[map("stats")]
+static mut stats: Counters<CgroupCounter> = Counters::with_max_entries(10240);
+
+/// Threads in the middle of reading or writing a regular file
+#[map("file_io")]
+static mut file_io: HashMap<u64, u8> = HashMap::with_max_entries(10240);
+
+#[kprobe("tcp_sendmsg")]
+pub fn tcp_sendmsg(regs: Registers) {
+    account(NET_TX, regs.parm3());
+}
+
+#[kprobe("udp_sendmsg")]
+pub fn udp_sendmsg(regs: Registers) {
+    account(NET_TX, regs.parm3());
+}
+
+#[kprobe("tcp_cleanup_rbuf")]
+pub fn tcp_cleanup_rbuf(regs: Registers) {
+    let copied = regs.parm2() as i32;
+    if copied > 0 {
+        account(NET_RX, copied as u64);
+    }
+}
+
+#[kprobe("vfs_read")]
+pub fn vfs_read_enter(regs: Registers) {
+    file_io_enter(regs);
+}
+
+#[kretprobe("vfs_read")]
+pub fn vfs_read_exit(regs: Registers) {
+    if let Some(len) = file_io_exit(regs) {
+        account(FILE_READ, len);
+    }
+}
+
+#[kprobe("vfs_write")]
+pub fn vfs_write_enter(regs: Registers) {
+    file_io_enter(regs);
+}
+
+#[kretprobe("vfs_write")]
+pub fn vfs_write_exit(regs: Registers) {
+    if let Some(len) = file_io_exit(regs) {
+        account(FILE_WRITE, len);
+    }
+}
+
+/// Only regular files are counted, sockets and pipes are accounted for
+/// elsewhere, or not at all
+#[inline(always)]
+fn file_io_enter(regs: Registers) {
+    let file = regs.parm1() as *const file;
+    let mode = unsafe {
+        bpf_probe_read(&(*file).f_inode as *const *mut inode)
+            .and_then(|inode| bpf_probe_read(&(*inode).i_mode as *const u16))
+    };
+
+    if let Ok(mode) = mode {
+        if mode & S_IFMT == S_IFREG {
+            unsafe { file_io.set(&bpf_get_current_pid_tgid(), &1) };
+        }
+    }
+}
+
+#[inline(always)]
+fn file_io_exit(regs: Registers) -> Option<u64> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    unsafe {
+        file_io.get(&pid_tgid)?;
+        file_io.delete(&pid_tgid);
+    }
+
+    let len = regs.rc() as i64;
+    if len > 0 {
+        Some(len as u64)
+    } else {
+        None
+    }
+}
+
+/// Adds to the counter of the current cgroup
+#[inline(always)]
+fn account(counter: u64, bytes: u64) {
+    let key = CgroupCounter {
+        cgroup_id: unsafe { bpf_get_current_cgroup_id() },
+        counter,
+    };
+    unsafe { stats.add(&key, bytes) };
+}
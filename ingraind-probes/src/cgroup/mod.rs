@@ -0,0 +1,14 @@
+pub const NET_TX: u64 = 0;
+pub const NET_RX: u64 = 1;
+pub const FILE_READ: u64 = 2;
+pub const FILE_WRITE: u64 = 3;
+
+/// The bytes of one kind of I/O of a cgroup, counted in the kernel
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct CgroupCounter {
+    /// The inode of the cgroup's directory on cgroup v2
+    pub cgroup_id: u64,
+    pub counter: u64,
+}
@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::cpu::CpuTime;
+use ingraind_probes::maps::{PerCpuArray, PerCpuHashMap};
+use ingraind_probes::sched::SchedSwitchArgs;
+use ingraind_probes::{kernel_version, meta};
+use redbpf_macros::{map, program};
+use redbpf_probes::helpers::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// `tracepoint` programs appeared in 4.7
+meta!("meta/min_kernel", kernel_version(4, 7));
+
+/// When the current task of each CPU was switched in
+#[map("switched_in_at")]
+static mut switched_in_at: PerCpuArray<u64> = PerCpuArray::with_max_entries(1);
+
+/// On-CPU time of every process on each CPU since it was first seen. ingraind
+/// reads it without deleting anything, and removes processes that exited.
+#[map("cpu_time")]
+static mut cpu_time: PerCpuHashMap<u32, CpuTime> = PerCpuHashMap::with_max_entries(10240);
+
+#[no_mangle]
+#[link_section = "tracepoint/sched_switch"]
+pub extern "C" fn sched_switch(ctx: *mut SchedSwitchArgs) -> i32 {
+    let now = bpf_ktime_get_ns();
+    let start = match unsafe { switched_in_at.get_mut(0) } {
+        Some(at) => {
+            let start = *at;
+            *at = now;
+            start
+        }
+        None => return 0,
+    };
+    if start == 0 {
+        return 0;
+    }
+
+    // the tracepoint runs in the context of the task switching out
+    let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    // the idle task
+    if pid == 0 {
+        return 0;
+    }
+
+    let time = match unsafe { cpu_time.get_mut(&pid) } {
+        Some(time) => time,
+        None => {
+            let time = CpuTime {
+                comm: [0; 16],
+                ns: 0,
+            };
+            unsafe {
+                cpu_time.set(&pid, &time);
+                match cpu_time.get_mut(&pid) {
+                    Some(time) => time,
+                    None => return 0,
+                }
+            }
+        }
+    };
+    // the values of other CPUs start zeroed
+    if time.ns == 0 {
+        time.comm = unsafe { (*ctx).prev_comm };
+    }
+    time.ns += now - start;
+
+    0
+}
@@ -0,0 +1,12 @@
+use cty::*;
+
+/// Nanoseconds a process spent on a CPU since it was first seen. Every CPU
+/// has its own value in a per-CPU map, so the counters don't need to be
+/// shared between CPUs.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct CpuTime {
+    pub comm: [c_char; 16],
+    pub ns: u64,
+}
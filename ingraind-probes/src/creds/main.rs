@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+use cty::c_char;
+use ingraind_probes::creds::CredChange;
+use ingraind_probes::{meta, Timestamped};
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+meta!("meta/needs=bpf_get_current_task");
+
+#[map("cred_changes")]
+static mut cred_changes: PerfMap<Timestamped<CredChange>> = PerfMap::with_max_entries(1024);
+
+/// All of setuid(), setresuid(), capset(), and execve() of setuid binaries
+/// install the new credentials through `commit_creds`
+#[kprobe("commit_creds")]
+pub fn commit_creds(regs: Registers) {
+    let _ = trace_commit_creds(regs);
+}
+
+#[inline(always)]
+fn trace_commit_creds(regs: Registers) -> Option<()> {
+    let new = regs.parm1() as *const cred;
+    let task = bpf_get_current_task() as *const task_struct;
+
+    unsafe {
+        let old = bpf_probe_read(&(*task).cred as *const *const cred).ok()?;
+        if old.is_null() || new.is_null() {
+            return None;
+        }
+
+        let old_euid = bpf_probe_read(&(*old).euid as *const kuid_t).ok()?.val;
+        let new_euid = bpf_probe_read(&(*new).euid as *const kuid_t).ok()?.val;
+        let old_caps = bpf_probe_read(&(*old).cap_effective as *const _ as *const u64).ok()?;
+        let new_caps = bpf_probe_read(&(*new).cap_effective as *const _ as *const u64).ok()?;
+
+        let gained_root = new_euid == 0 && old_euid != 0;
+        let gained_caps = new_caps & !old_caps != 0;
+        if !(gained_root || gained_caps) {
+            return None;
+        }
+
+        let parent = bpf_probe_read(&(*task).real_parent as *const *mut task_struct).ok()?;
+        let event = CredChange {
+            pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+            ppid: bpf_probe_read(&(*parent).tgid as *const pid_t).ok()? as u32,
+            old_uid: bpf_probe_read(&(*old).uid as *const kuid_t).ok()?.val,
+            new_uid: bpf_probe_read(&(*new).uid as *const kuid_t).ok()?.val,
+            old_euid,
+            new_euid,
+            old_caps,
+            new_caps,
+            comm: bpf_get_current_comm(),
+            parent_comm: bpf_probe_read(&(*parent).comm as *const [c_char; 16]).ok()?,
+        };
+
+        cred_changes.insert(regs.ctx, &Timestamped::now(event));
+    }
+
+    Some(())
+}
@@ -0,0 +1,16 @@
+use cty::*;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct CredChange {
+    pub pid: u32,
+    pub ppid: u32,
+    pub old_uid: u32,
+    pub new_uid: u32,
+    pub old_euid: u32,
+    pub new_euid: u32,
+    pub old_caps: u64,
+    pub new_caps: u64,
+    pub comm: [c_char; 16],
+    pub parent_comm: [c_char; 16],
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::db::{Database, Query, QueryLatency, QUERY_LEN};
+use ingraind_probes::{meta, Timestamped};
+use redbpf_probes::bindings;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+meta!("meta/needs=bpf_probe_read_str");
+
+#[map("queries")]
+static mut queries: HashMap<u64, Query> = HashMap::with_max_entries(10240);
+
+#[map("query_latency")]
+static mut query_latency: PerfMap<Timestamped<QueryLatency>> = PerfMap::with_max_entries(1024);
+
+#[uprobe]
+pub fn pq_exec_enter(regs: Registers) {
+    query_start(regs.parm2());
+}
+
+#[uretprobe]
+pub fn pq_exec_exit(regs: Registers) {
+    query_end(regs, Database::PostgresClient);
+}
+
+#[uprobe]
+pub fn exec_simple_query_enter(regs: Registers) {
+    query_start(regs.parm1());
+}
+
+#[uretprobe]
+pub fn exec_simple_query_exit(regs: Registers) {
+    query_end(regs, Database::PostgresServer);
+}
+
+#[uprobe]
+pub fn mysql_real_query_enter(regs: Registers) {
+    query_start(regs.parm2());
+}
+
+#[uretprobe]
+pub fn mysql_real_query_exit(regs: Registers) {
+    query_end(regs, Database::MysqlClient);
+}
+
+#[inline(always)]
+fn query_start(query: u64) {
+    let mut started = Query {
+        start: bpf_ktime_get_ns(),
+        query: [0; QUERY_LEN],
+    };
+    // queries are mostly shorter than the buffer, and may end right before
+    // an unmapped page
+    unsafe {
+        bindings::bpf_probe_read_str(
+            started.query.as_mut_ptr() as *mut _,
+            QUERY_LEN as i32,
+            query as *const _,
+        )
+    };
+
+    unsafe { queries.set(&bpf_get_current_pid_tgid(), &started) };
+}
+
+#[inline(always)]
+fn query_end(regs: Registers, database: Database) {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    unsafe {
+        let query = match queries.get(&pid_tgid) {
+            Some(query) => query,
+            None => return,
+        };
+
+        let event = QueryLatency {
+            pid: (pid_tgid >> 32) as u32,
+            database,
+            comm: bpf_get_current_comm(),
+            duration: bpf_ktime_get_ns() - query.start,
+            query: query.query,
+        };
+
+        queries.delete(&pid_tgid);
+        query_latency.insert(regs.ctx, &Timestamped::now(event));
+    }
+}
@@ -0,0 +1,31 @@
+use cty::*;
+
+pub const QUERY_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Database {
+    /// `PQexec` in libpq
+    PostgresClient,
+    /// `exec_simple_query` in the postgres server
+    PostgresServer,
+    /// `mysql_real_query` in libmysqlclient
+    MysqlClient,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct Query {
+    pub start: u64,
+    pub query: [u8; QUERY_LEN],
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct QueryLatency {
+    pub pid: u32,
+    pub database: Database,
+    pub comm: [c_char; 16],
+    pub duration: u64,
+    pub query: [u8; QUERY_LEN],
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::dhcp_ntp::{Event, DHCP_CLIENT_PORT, DHCP_SERVER_PORT, NTP_PORT};
+use redbpf_probes::xdp::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("events")]
+static mut events: PerfMap<Event> = PerfMap::with_max_entries(1024);
+
+#[xdp("dhcp_ntp")]
+pub fn probe(ctx: XdpContext) -> XdpResult {
+    let ip = unsafe { *ctx.ip()? };
+    if ip.protocol as u32 != IPPROTO_UDP {
+        return Ok(XdpAction::Pass);
+    }
+
+    let transport = ctx.transport()?;
+    let (sport, dport) = (transport.source(), transport.dest());
+
+    // only replies are interesting: DHCP offers and acks, and NTP responses
+    let dhcp = sport == DHCP_SERVER_PORT && dport == DHCP_CLIENT_PORT;
+    let ntp = sport == NTP_PORT;
+    if !(dhcp || ntp) {
+        return Ok(XdpAction::Pass);
+    }
+
+    let data = ctx.data()?;
+    let event = Event {
+        saddr: ip.saddr,
+        daddr: ip.daddr,
+        sport,
+        dport,
+    };
+
+    unsafe {
+        events.insert(
+            &ctx,
+            &MapData::with_payload(event, data.offset() as u32, ctx.len() as u32),
+        )
+    };
+
+    Ok(XdpAction::Pass)
+}
@@ -0,0 +1,5 @@
+pub use crate::dns::Event;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+pub const NTP_PORT: u16 = 123;
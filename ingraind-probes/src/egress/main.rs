@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::egress::{Destination, Policy, ACTION_DENY, POLICY};
+use ingraind_probes::maps::Counters;
+use ingraind_probes::{kernel_version, meta};
+use redbpf_macros::{map, program};
+use redbpf_probes::bindings::bpf_sock_addr;
+use redbpf_probes::maps::HashMap;
+
+program!(0xFFFFFFFE, "GPL");
+meta!("meta/min_kernel", kernel_version(4, 17));
+
+const ALLOW: i32 = 1;
+const REJECT: i32 = 0;
+
+#[map("policy")]
+static mut policy: HashMap<u8, Policy> = HashMap::with_max_entries(1);
+
+/// The connections rejected to every destination, read by ingraind
+#[map("denied")]
+static mut denied: Counters<Destination> = Counters::with_max_entries(4096);
+
+#[no_mangle]
+#[link_section = "cgroup/connect4"]
+pub extern "C" fn connect4(ctx: *mut bpf_sock_addr) -> i32 {
+    let ctx = unsafe { &*ctx };
+    decide(Destination {
+        // mapped into IPv6, like the networks of the rules
+        addr: [0, 0, 0xffffu32.to_be(), ctx.user_ip4],
+        port: u16::from_be(ctx.user_port as u16) as u32,
+    })
+}
+
+#[no_mangle]
+#[link_section = "cgroup/connect6"]
+pub extern "C" fn connect6(ctx: *mut bpf_sock_addr) -> i32 {
+    let ctx = unsafe { &*ctx };
+    decide(Destination {
+        addr: ctx.user_ip6,
+        port: u16::from_be(ctx.user_port as u16) as u32,
+    })
+}
+
+#[inline(always)]
+fn decide(dest: Destination) -> i32 {
+    let action = match unsafe { policy.get(&POLICY) } {
+        Some(p) => p.action(&dest),
+        None => return ALLOW,
+    };
+    if action == ACTION_DENY {
+        unsafe { denied.increment(&dest) };
+        return REJECT;
+    }
+
+    ALLOW
+}
@@ -0,0 +1,46 @@
+//! Enforces an egress policy on `connect()`, from a cgroup.
+//!
+//! The policy is a single `Policy` under the `POLICY` key of the `policy`
+//! map. Its rules are evaluated in order and the first one matching the
+//! destination decides, like in ingraind. Connections that are rejected are
+//! counted in the `denied` map by destination.
+
+use crate::filter::Cidr;
+
+#[cfg(feature = "probes")]
+mod probe;
+
+pub const POLICY: u8 = 0;
+
+pub const MAX_RULES: usize = 16;
+
+pub const ACTION_ALLOW: u8 = 1;
+pub const ACTION_DENY: u8 = 2;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Rule {
+    pub action: u8,
+    /// The destination port in host byte order, or 0 for any
+    pub port: u16,
+    pub net: Cidr,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Policy {
+    pub default_action: u8,
+    pub rules_len: u32,
+    pub rules: [Rule; MAX_RULES],
+}
+
+/// Where a rejected connection was going
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Destination {
+    /// In network byte order, like `in6_addr`, with IPv4 addresses mapped
+    /// into IPv6 ones
+    pub addr: [u32; 4],
+    /// In host byte order
+    pub port: u32,
+}
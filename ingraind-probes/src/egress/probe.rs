@@ -0,0 +1,26 @@
+use crate::egress::*;
+
+impl Policy {
+    /// What the first rule matching `dest` says, or the default action
+    #[inline(always)]
+    pub fn action(&self, dest: &Destination) -> u8 {
+        for i in 0..MAX_RULES {
+            if i as u32 >= self.rules_len {
+                break;
+            }
+
+            let rule = &self.rules[i];
+            let net = &rule.net;
+            if (rule.port == 0 || rule.port as u32 == dest.port)
+                && dest.addr[0] & net.mask[0] == net.addr[0]
+                && dest.addr[1] & net.mask[1] == net.addr[1]
+                && dest.addr[2] & net.mask[2] == net.addr[2]
+                && dest.addr[3] & net.mask[3] == net.addr[3]
+            {
+                return rule.action;
+            }
+        }
+
+        self.default_action
+    }
+}
@@ -2,8 +2,12 @@
 #![no_main]
 use redbpf_probes::kprobe::prelude::*;
 use ingraind_probes::file::{
-    Access, FileAccess, PathList, PathSegment, PATH_LIST_LEN, PATH_SEGMENT_LEN,
+    Access, FileAccess, FileVolume, FileVolumeKey, PathList, PathSegment, AGGREGATE, PATH_LIST_LEN,
+    PATH_SEGMENT_LEN,
 };
+use ingraind_probes::filter::{Filters, FILTERS};
+use ingraind_probes::maps::{atomic_add, insert_new};
+use ingraind_probes::{disabled, meta, Timestamped};
 
 enum AccessType {
     Read,
@@ -12,6 +16,8 @@ enum AccessType {
 
 program!(0xFFFFFFFE, "GPL");
 
+meta!("meta/needs=bpf_probe_read_str");
+
 const S_IFMT: u16 = 0o00170000;
 const S_IFREG: u16 = 0o0100000;
 
@@ -21,8 +27,25 @@ static mut actionlist: HashMap<u64, u8> = HashMap::with_max_entries(10240);
 #[map("files")]
 static mut files: HashMap<u64, *const file> = HashMap::with_max_entries(10240);
 
+#[map("filters")]
+static mut filters: HashMap<u8, Filters> = HashMap::with_max_entries(1);
+
+#[map("filter_pids")]
+static mut filter_pids: HashMap<u32, u8> = HashMap::with_max_entries(10240);
+
+#[map("settings")]
+static mut settings: HashMap<u8, u64> = HashMap::with_max_entries(16);
+
+/// Volumes per process and file, when aggregated in the kernel
+#[map("file_volumes")]
+static mut file_volumes: HashMap<FileVolumeKey, FileVolume> = HashMap::with_max_entries(10240);
+
+/// Paths of the files in `file_volumes`
+#[map("file_paths")]
+static mut file_paths: HashMap<u64, PathList> = HashMap::with_max_entries(10240);
+
 #[map("rw")]
-static mut rw: PerfMap<FileAccess> = PerfMap::with_max_entries(1024);
+static mut rw: PerfMap<Timestamped<FileAccess>> = PerfMap::with_max_entries(1024);
 
 #[kprobe("vfs_read")]
 pub fn trace_read_entry(regs: Registers) {
@@ -62,7 +85,7 @@ fn do_track_file_access(regs: Registers, access_type: AccessType) -> Option<()>
     let tid = bpf_get_current_pid_tgid();
 
     let size = regs.rc() as usize;
-    if size == 0 {
+    if size == 0 || unsafe { disabled(&mut settings) } || !allowed() {
         return None;
     }
     let file = unsafe { &**files.get(&tid)? };
@@ -93,14 +116,60 @@ fn do_track_file_access(regs: Registers, access_type: AccessType) -> Option<()>
     };
 
     if let Some(InodePolicy::Record) = dentry_to_path(path.dentry, &mut event.paths) {
-        unsafe {
-            rw.insert(regs.ctx, &event);
+        if aggregating() {
+            aggregate(&event);
+        } else {
+            unsafe {
+                rw.insert(regs.ctx, &Timestamped::now(event));
+            }
         }
     }
 
     Some(())
 }
 
+#[inline]
+fn allowed() -> bool {
+    unsafe {
+        match filters.get(&FILTERS) {
+            Some(f) => f.task_allowed(&mut filter_pids),
+            None => true,
+        }
+    }
+}
+
+#[inline]
+fn aggregating() -> bool {
+    unsafe { settings.get(&AGGREGATE) }.cloned().unwrap_or(0) != 0
+}
+
+/// Adds the access to the volume of the process and file, which user space
+/// scrapes and resets
+#[inline]
+fn aggregate(event: &FileAccess) {
+    let key = FileVolumeKey {
+        pid: event.tid as u64,
+        inode: event.inode,
+    };
+    let empty = FileVolume {
+        comm: event.comm,
+        read: 0,
+        write: 0,
+    };
+
+    unsafe {
+        if insert_new(&mut file_volumes, &key, &empty) {
+            file_paths.set(&event.inode, &event.paths);
+        }
+        if let Some(volume) = file_volumes.get_mut(&key) {
+            match event.access {
+                Access::Read(size) => atomic_add(&mut volume.read, size as u64),
+                Access::Write(size) => atomic_add(&mut volume.write, size as u64),
+            }
+        }
+    }
+}
+
 #[inline]
 fn dentry_to_path(mut dentry: *mut dentry, path_list: &mut PathList) -> Option<InodePolicy> {
     if dentry.is_null() {
@@ -3,8 +3,11 @@ use cty::*;
 pub const PATH_SEGMENT_LEN: usize = 32;
 pub const PATH_LIST_LEN: usize = 11;
 
+/// Key of the `settings` map that turns on aggregation in the kernel
+pub const AGGREGATE: u8 = 1;
+
 #[derive(Debug)]
-#[repr(u64)]
+#[repr(u32)]
 pub enum Access {
     Read(usize),
     Write(usize)
@@ -16,7 +19,7 @@ pub struct PathSegment {
     pub name: [u8; PATH_SEGMENT_LEN],
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 #[repr(C)]
 pub struct PathList(pub [PathSegment; PATH_LIST_LEN]);
 
@@ -31,3 +34,22 @@ pub struct FileAccess {
     pub paths: PathList,
 
 }
+
+/// The file accessed by a process, when volumes are aggregated in the
+/// kernel
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct FileVolumeKey {
+    pub pid: u64,
+    pub inode: u64,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct FileVolume {
+    pub comm: [c_char; 16],
+    pub read: u64,
+    pub write: u64,
+}
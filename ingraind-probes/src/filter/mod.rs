@@ -0,0 +1,57 @@
+//! Filters that probes consult before sending events to user space.
+//!
+//! Probes that support filtering define a `filters` map holding a single
+//! `Filters` under the `FILTERS` key, and a `filter_pids` map holding the
+//! pids to filter on. Each kind of filter has its own action, and events
+//! are only sent if they pass all of them.
+
+#[cfg(feature = "probes")]
+mod probe;
+
+pub const FILTERS: u8 = 0;
+
+pub const MAX_RANGES: usize = 8;
+pub const MAX_CIDRS: usize = 8;
+
+/// The filter isn't set
+pub const FILTER_NONE: u8 = 0;
+/// Only events matching the filter are sent
+pub const FILTER_ALLOW: u8 = 1;
+/// Events matching the filter are dropped
+pub const FILTER_DENY: u8 = 2;
+
+/// An inclusive range of uids or ports
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Range {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// A network in the IPv6 address space, with IPv4 networks mapped into it.
+///
+/// Both words are in network byte order, like `in6_addr`.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Cidr {
+    pub addr: [u32; 4],
+    pub mask: [u32; 4],
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Filters {
+    pub pids: u8,
+    pub uids: u8,
+    pub ports: u8,
+    pub addrs: u8,
+    pub uid_ranges_len: u32,
+    pub uid_ranges: [Range; MAX_RANGES],
+    pub port_ranges_len: u32,
+    pub port_ranges: [Range; MAX_RANGES],
+    pub cidrs_len: u32,
+    pub cidrs: [Cidr; MAX_CIDRS],
+}
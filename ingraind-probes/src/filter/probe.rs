@@ -0,0 +1,76 @@
+use redbpf_probes::kprobe::prelude::*;
+
+use crate::filter::*;
+use crate::network::Connection;
+
+impl Filters {
+    /// Whether events of the current task pass the pid and uid filters
+    #[inline(always)]
+    pub fn task_allowed(&self, pids: &mut HashMap<u32, u8>) -> bool {
+        let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+        let uid = bpf_get_current_uid_gid() as u32;
+
+        passes(self.pids, pids.get(&pid).is_some())
+            && passes(
+                self.uids,
+                in_ranges(&self.uid_ranges, self.uid_ranges_len, uid),
+            )
+    }
+
+    /// Whether events of the connection pass all filters. Ports match
+    /// either end of the connection, and networks the remote address.
+    #[inline(always)]
+    pub fn conn_allowed(&self, pids: &mut HashMap<u32, u8>, conn: &Connection) -> bool {
+        let dport = u16::from_be(conn.dport as u16) as u32;
+        let ports_match = in_ranges(&self.port_ranges, self.port_ranges_len, conn.sport)
+            || in_ranges(&self.port_ranges, self.port_ranges_len, dport);
+
+        self.task_allowed(pids)
+            && passes(self.ports, ports_match)
+            && passes(self.addrs, self.in_cidrs(&conn.daddr.words()))
+    }
+
+    #[inline(always)]
+    fn in_cidrs(&self, addr: &[u32; 4]) -> bool {
+        for i in 0..MAX_CIDRS {
+            if i as u32 >= self.cidrs_len {
+                break;
+            }
+
+            let cidr = &self.cidrs[i];
+            if addr[0] & cidr.mask[0] == cidr.addr[0]
+                && addr[1] & cidr.mask[1] == cidr.addr[1]
+                && addr[2] & cidr.mask[2] == cidr.addr[2]
+                && addr[3] & cidr.mask[3] == cidr.addr[3]
+            {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[inline(always)]
+fn passes(action: u8, matched: bool) -> bool {
+    match action {
+        FILTER_ALLOW => matched,
+        FILTER_DENY => !matched,
+        _ => true,
+    }
+}
+
+#[inline(always)]
+fn in_ranges(ranges: &[Range; MAX_RANGES], len: u32, value: u32) -> bool {
+    for i in 0..MAX_RANGES {
+        if i as u32 >= len {
+            break;
+        }
+
+        if ranges[i].start <= value && value <= ranges[i].end {
+            return true;
+        }
+    }
+
+    false
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::generic::{Call, MAX_ARGS, STRING_ARGS, STRING_LEN};
+use ingraind_probes::maps::PerCpuArray;
+use ingraind_probes::{disabled, meta, Timestamped};
+use redbpf_probes::bindings;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+meta!("meta/needs=bpf_probe_read_str");
+
+#[map("settings")]
+static mut settings: HashMap<u8, u64> = HashMap::with_max_entries(16);
+
+/// The call being built on each CPU, too large for the stack
+#[map("call")]
+static mut call: PerCpuArray<Timestamped<Call>> = PerCpuArray::with_max_entries(1);
+
+#[map("calls")]
+static mut calls: PerfMap<Timestamped<Call>> = PerfMap::with_max_entries(1024);
+
+/// Attached to the function given in the configuration
+#[kprobe("generic")]
+pub fn generic(regs: Registers) {
+    if unsafe { disabled(&mut settings) } {
+        return;
+    }
+    let string_args = unsafe { settings.get(&STRING_ARGS) }.cloned().unwrap_or(0);
+    let current = match unsafe { call.get_mut(0) } {
+        Some(current) => current,
+        None => return,
+    };
+
+    current.timestamp = bpf_ktime_get_ns();
+    current.event.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    current.event.comm = bpf_get_current_comm();
+    current.event.args = [
+        regs.parm1(),
+        regs.parm2(),
+        regs.parm3(),
+        regs.parm4(),
+        regs.parm5(),
+    ];
+
+    for i in 0..MAX_ARGS {
+        let string = &mut current.event.strings[i];
+        string[0] = 0;
+        if string_args & (1 << i) == 0 {
+            continue;
+        }
+
+        // strings are mostly shorter than the buffer, and may end right
+        // before an unmapped page
+        unsafe {
+            bindings::bpf_probe_read_str(
+                string.as_mut_ptr() as *mut _,
+                STRING_LEN as i32,
+                current.event.args[i] as *const _,
+            )
+        };
+    }
+
+    unsafe { calls.insert(regs.ctx, current) };
+}
@@ -0,0 +1,16 @@
+use cty::*;
+
+pub const MAX_ARGS: usize = 5;
+pub const STRING_LEN: usize = 64;
+
+/// Bit `n` is set when argument `n` is a string pointer
+pub const STRING_ARGS: u8 = 1;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct Call {
+    pub pid: u32,
+    pub comm: [c_char; 16],
+    pub args: [u64; MAX_ARGS],
+    pub strings: [[c_char; STRING_LEN]; MAX_ARGS],
+}
@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::harness::Sleep;
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("sleeps")]
+static mut sleeps: PerfMap<Timestamped<Sleep>> = PerfMap::with_max_entries(1024);
+
+/// Both nanosleep() and clock_nanosleep() end up here, so any
+/// `thread::sleep` is seen
+#[kprobe("hrtimer_nanosleep")]
+pub fn nanosleep(regs: Registers) {
+    let sleep = Sleep {
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        comm: bpf_get_current_comm(),
+    };
+
+    unsafe { sleeps.insert(regs.ctx, &Timestamped::now(sleep)) };
+}
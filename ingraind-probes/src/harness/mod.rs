@@ -0,0 +1,9 @@
+use cty::*;
+
+/// A process going to sleep
+#[derive(Debug)]
+#[repr(C)]
+pub struct Sleep {
+    pub pid: u32,
+    pub comm: [c_char; 16],
+}
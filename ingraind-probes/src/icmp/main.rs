@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::icmp::{Icmp, IcmpEvent};
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("icmp_events")]
+static mut icmp_events: PerfMap<Timestamped<IcmpEvent>> = PerfMap::with_max_entries(1024);
+
+#[kprobe("icmp_rcv")]
+pub fn icmp_receive(regs: Registers) {
+    let _ = trace_receive(regs);
+}
+
+#[kprobe("__icmp_send")]
+pub fn icmp_send(regs: Registers) {
+    let _ = trace_send(regs);
+}
+
+#[inline(always)]
+fn trace_receive(regs: Registers) -> Option<()> {
+    let skb = regs.parm1() as *const sk_buff;
+    let ip = ip_header(skb)?;
+    let (typ, code) = unsafe {
+        let head = bpf_probe_read(&(*skb).head as *const *mut u8).ok()?;
+        let offset = bpf_probe_read(&(*skb).transport_header as *const u16).ok()?;
+        let icmp = bpf_probe_read(head.add(offset as usize) as *const icmphdr).ok()?;
+        (icmp.type_, icmp.code)
+    };
+
+    let event = IcmpEvent::Receive(Icmp {
+        saddr: ip.saddr,
+        daddr: ip.daddr,
+        typ,
+        code,
+    });
+    unsafe {
+        icmp_events.insert(regs.ctx, &Timestamped::now(event));
+    }
+
+    Some(())
+}
+
+/// `__icmp_send` is called with the packet that triggered the error, so the
+/// message will be addressed to its source.
+#[inline(always)]
+fn trace_send(regs: Registers) -> Option<()> {
+    let skb = regs.parm1() as *const sk_buff;
+    let ip = ip_header(skb)?;
+
+    let event = IcmpEvent::Send(Icmp {
+        saddr: ip.daddr,
+        daddr: ip.saddr,
+        typ: regs.parm2() as u8,
+        code: regs.parm3() as u8,
+    });
+    unsafe {
+        icmp_events.insert(regs.ctx, &Timestamped::now(event));
+    }
+
+    Some(())
+}
+
+#[inline(always)]
+fn ip_header(skb: *const sk_buff) -> Option<iphdr> {
+    if skb.is_null() {
+        return None;
+    }
+
+    unsafe {
+        let head = bpf_probe_read(&(*skb).head as *const *mut u8).ok()?;
+        let offset = bpf_probe_read(&(*skb).network_header as *const u16).ok()?;
+        bpf_probe_read(head.add(offset as usize) as *const iphdr).ok()
+    }
+}
@@ -0,0 +1,15 @@
+#[repr(C)]
+#[derive(Debug)]
+pub struct Icmp {
+    pub saddr: u32,
+    pub daddr: u32,
+    pub typ: u8,
+    pub code: u8,
+}
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum IcmpEvent {
+    Send(Icmp),
+    Receive(Icmp),
+}
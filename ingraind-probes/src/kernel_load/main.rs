@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+use cty::c_char;
+use ingraind_probes::kernel_load::{KernelLoad, Process, BPF_OBJ_NAME_LEN, MODULE_NAME_LEN};
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// offset of `prog_name` in the BPF_PROG_LOAD variant of `union bpf_attr`,
+// which is stable UAPI
+const BPF_ATTR_PROG_NAME: usize = 48;
+
+#[map("host_pid")]
+static mut host_pid: HashMap<u8, u64> = HashMap::with_max_entries(1024);
+
+#[map("kernel_loads")]
+static mut kernel_loads: PerfMap<Timestamped<KernelLoad>> = PerfMap::with_max_entries(1024);
+
+/// Both `init_module` and `finit_module` end up here once the module image
+/// has been loaded and verified
+#[kprobe("do_init_module")]
+pub fn init_module(regs: Registers) {
+    let m = regs.parm1() as *const module;
+    let name = unsafe { bpf_probe_read(&(*m).name as *const [c_char; MODULE_NAME_LEN]) };
+
+    if let Ok(name) = name {
+        unsafe {
+            kernel_loads.insert(
+                regs.ctx,
+                &Timestamped::now(KernelLoad::Module(current_process(), name)),
+            );
+        }
+    }
+}
+
+/// Every program loaded through `bpf(BPF_PROG_LOAD, ...)` goes through the
+/// verifier
+#[kprobe("bpf_check")]
+pub fn bpf_prog_load(regs: Registers) {
+    let pid = bpf_get_current_pid_tgid() >> 32;
+    if let Some(ignore) = unsafe { host_pid.get(&1u8) } {
+        if *ignore == pid {
+            return;
+        }
+    }
+
+    let attr = regs.parm2() as *const u8;
+    let (prog_type, name) = unsafe {
+        match (
+            bpf_probe_read(attr as *const u32),
+            bpf_probe_read(attr.add(BPF_ATTR_PROG_NAME) as *const [c_char; BPF_OBJ_NAME_LEN]),
+        ) {
+            (Ok(prog_type), Ok(name)) => (prog_type, name),
+            _ => return,
+        }
+    };
+
+    unsafe {
+        kernel_loads.insert(
+            regs.ctx,
+            &Timestamped::now(KernelLoad::Bpf(current_process(), prog_type, name)),
+        );
+    }
+}
+
+#[inline(always)]
+fn current_process() -> Process {
+    Process {
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        comm: bpf_get_current_comm(),
+    }
+}
@@ -0,0 +1,13 @@
+use cty::*;
+
+pub use crate::memory::Process;
+
+pub const MODULE_NAME_LEN: usize = 56;
+pub const BPF_OBJ_NAME_LEN: usize = 16;
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum KernelLoad {
+    Module(Process, [c_char; MODULE_NAME_LEN]),
+    Bpf(Process, u32, [c_char; BPF_OBJ_NAME_LEN]),
+}
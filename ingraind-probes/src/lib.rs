@@ -1,6 +1,166 @@
 #![no_std]
+#[cfg(feature = "probes")]
+pub mod maps;
+
 pub mod syscalls;
 pub mod dns;
 pub mod network;
+pub mod egress;
 pub mod tls;
 pub mod file;
+pub mod tcp_latency;
+pub mod udp;
+pub mod icmp;
+pub mod listen;
+pub mod profile;
+pub mod memory;
+pub mod sched;
+pub mod cpu;
+pub mod creds;
+pub mod kernel_load;
+pub mod ptrace;
+pub mod unix;
+pub mod ssh;
+pub mod tty;
+pub mod dhcp_ntp;
+pub mod arp;
+pub mod usdt;
+pub mod ssl;
+pub mod db;
+pub mod generic;
+pub mod cgroup;
+pub mod filter;
+pub mod sampling;
+pub mod process_tree;
+pub mod harness;
+
+/// An event and the time it happened at, from `bpf_ktime_get_ns()`
+#[repr(C)]
+pub struct Timestamped<T> {
+    pub timestamp: u64,
+    pub event: T,
+}
+
+#[cfg(feature = "probes")]
+impl<T> Timestamped<T> {
+    #[inline(always)]
+    pub fn now(event: T) -> Self {
+        Timestamped {
+            timestamp: redbpf_probes::helpers::bpf_ktime_get_ns(),
+            event,
+        }
+    }
+}
+
+/// Key of the `settings` map of a probe that user space sets to a non-zero
+/// value while the grain is disabled
+pub const DISABLED: u8 = 0xFF;
+
+/// Whether user space disabled the grain, in which case the programs of the
+/// probe return before doing any work
+#[cfg(feature = "probes")]
+#[inline(always)]
+pub fn disabled(settings: &mut redbpf_probes::maps::HashMap<u8, u64>) -> bool {
+    settings.get(&DISABLED).cloned().unwrap_or(0) != 0
+}
+
+/// Embeds a requirement of the probe in the `meta/` section `$section`,
+/// which ingraind checks before loading it:
+///
+/// ```ignore
+/// meta!("meta/min_kernel", kernel_version(4, 18));
+/// meta!("meta/needs=bpf_probe_read_user");
+/// meta!("meta/min_kernel/udp_rcv", kernel_version(5, 10));
+/// ```
+///
+/// Appending a program's name scopes the requirement to it, and the
+/// program is skipped on kernels that don't meet it.
+#[macro_export]
+macro_rules! meta {
+    ($section:literal, $value:expr) => {
+        const _: () = {
+            #[used]
+            #[link_section = $section]
+            static META: u32 = $value;
+        };
+    };
+    ($section:literal) => {
+        $crate::meta!($section, 1);
+    };
+}
+
+/// The version of kernel `major.minor`, as the kernel encodes it
+pub const fn kernel_version(major: u32, minor: u32) -> u32 {
+    (major << 16) | (minor << 8)
+}
+
+/// The size of the buffer of `WithPayload` events
+pub const PAYLOAD_SIZE: usize = 256;
+
+/// An event followed by variable-length data, like a path, a DNS name or
+/// the arguments of a process.
+///
+/// Only the first `len` bytes of `payload` are read by ingraind, and probes
+/// send the event truncated after them with `maps::insert_truncated` and
+/// `sent_size`. The struct is too large for the stack of most programs, and
+/// is best kept in a per-CPU array.
+#[repr(C)]
+pub struct WithPayload<T> {
+    pub event: T,
+    pub len: u32,
+    pub payload: [u8; PAYLOAD_SIZE],
+}
+
+#[cfg(feature = "probes")]
+impl<T> WithPayload<T> {
+    /// The size of `outer`, which ends with this event, up to the end of the
+    /// `len` bytes of the payload
+    #[inline(always)]
+    pub fn sent_size<E>(&self, outer: &E) -> usize {
+        let payload_at = self.payload.as_ptr() as usize - outer as *const E as usize;
+        // masked for the verifier, which needs a bounded size
+        payload_at + (self.len as usize & (PAYLOAD_SIZE - 1))
+    }
+
+    /// Copies the NUL terminated string at `src` to the payload, without the
+    /// terminator, truncating it to `PAYLOAD_SIZE - 1` bytes
+    #[inline(always)]
+    pub fn read_str(&mut self, src: *const u8) -> Option<()> {
+        let read = unsafe {
+            redbpf_probes::bindings::bpf_probe_read_str(
+                self.payload.as_mut_ptr() as *mut _,
+                PAYLOAD_SIZE as i32,
+                src as *const _,
+            )
+        };
+        if read <= 0 {
+            self.len = 0;
+            return None;
+        }
+
+        self.len = read as u32 - 1;
+        Some(())
+    }
+
+    /// Copies `len` bytes at `src` to the payload, truncating them to
+    /// `PAYLOAD_SIZE - 1` bytes
+    #[inline(always)]
+    pub fn read_bytes(&mut self, src: *const u8, len: usize) -> Option<()> {
+        // masked for the verifier, which needs a bounded size
+        let len = len.min(PAYLOAD_SIZE - 1) & (PAYLOAD_SIZE - 1);
+        let read = unsafe {
+            redbpf_probes::bindings::bpf_probe_read(
+                self.payload.as_mut_ptr() as *mut _,
+                len as u32,
+                src as *const _,
+            )
+        };
+        if read < 0 {
+            self.len = 0;
+            return None;
+        }
+
+        self.len = len as u32;
+        Some(())
+    }
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::listen::{ListenEvent, Listener};
+use ingraind_probes::network::sock_details;
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("task_to_socket")]
+static mut task_to_socket: HashMap<u64, *const socket> = HashMap::with_max_entries(10240);
+
+#[map("listen_events")]
+static mut listen_events: PerfMap<Timestamped<ListenEvent>> = PerfMap::with_max_entries(1024);
+
+#[kprobe("inet_bind")]
+pub fn bind_v4_enter(regs: Registers) {
+    store_socket(regs)
+}
+
+#[kretprobe("inet_bind")]
+pub fn bind_v4_exit(regs: Registers) {
+    trace_listener(regs, ListenEvent::Bind)
+}
+
+#[kprobe("inet6_bind")]
+pub fn bind_v6_enter(regs: Registers) {
+    store_socket(regs)
+}
+
+#[kretprobe("inet6_bind")]
+pub fn bind_v6_exit(regs: Registers) {
+    trace_listener(regs, ListenEvent::Bind)
+}
+
+#[kprobe("inet_listen")]
+pub fn listen_enter(regs: Registers) {
+    store_socket(regs)
+}
+
+#[kretprobe("inet_listen")]
+pub fn listen_exit(regs: Registers) {
+    trace_listener(regs, ListenEvent::Listen)
+}
+
+#[inline(always)]
+fn store_socket(regs: Registers) {
+    unsafe {
+        task_to_socket.set(
+            &bpf_get_current_pid_tgid(),
+            &(regs.parm1() as *const socket),
+        )
+    };
+}
+
+#[inline(always)]
+fn trace_listener(regs: Registers, event: fn(Listener) -> ListenEvent) {
+    let _ = do_trace_listener(regs, event);
+}
+
+#[inline(always)]
+fn do_trace_listener(regs: Registers, event: fn(Listener) -> ListenEvent) -> Option<()> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let socket_ptr = unsafe { *task_to_socket.get(&pid_tgid)? };
+    unsafe {
+        task_to_socket.delete(&pid_tgid);
+    }
+
+    // the port is only assigned once the call succeeded
+    if regs.rc() as i32 != 0 {
+        return None;
+    }
+
+    let sk = unsafe { bpf_probe_read(&(*socket_ptr).sk as *const *mut sock) }.ok()?;
+    if sk.is_null() {
+        return None;
+    }
+
+    let listener = Listener {
+        conn: sock_details(unsafe { &*sk })?,
+        uid: bpf_get_current_uid_gid() as u32,
+    };
+    unsafe {
+        listen_events.insert(regs.ctx, &Timestamped::now(event(listener)));
+    }
+
+    Some(())
+}
@@ -0,0 +1,14 @@
+use crate::network::Connection;
+
+#[derive(Debug)]
+pub struct Listener {
+    pub conn: Connection,
+    pub uid: u32,
+}
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum ListenEvent {
+    Bind(Listener),
+    Listen(Listener),
+}
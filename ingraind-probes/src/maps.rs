@@ -0,0 +1,288 @@
+//! Map types redbpf-probes doesn't have yet, in the style of its own.
+
+use core::marker::PhantomData;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use cty::*;
+
+use redbpf_probes::bindings::*;
+use redbpf_probes::helpers::bpf_perf_event_output;
+use redbpf_probes::maps::{HashMap, PerfMap};
+
+use crate::profile::MAX_STACK_DEPTH;
+
+/// Counters in a `BPF_MAP_TYPE_HASH`, which many CPUs can increment at the
+/// same time without losing counts.
+#[repr(transparent)]
+pub struct Counters<K> {
+    def: bpf_map_def,
+    _k: PhantomData<K>,
+}
+
+impl<K> Counters<K> {
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_HASH,
+                key_size: mem::size_of::<K>() as u32,
+                value_size: mem::size_of::<u64>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _k: PhantomData,
+        }
+    }
+
+    /// Adds `value` to the counter of `key`, which starts from 0
+    #[inline(always)]
+    pub fn add(&mut self, key: &K, value: u64) {
+        let map = &mut self.def as *mut _ as *mut c_void;
+        let key = key as *const _ as *const c_void;
+        let zero = 0u64;
+        unsafe {
+            // only inserts if no other CPU did already
+            bpf_map_update_elem(
+                map,
+                key,
+                &zero as *const _ as *const c_void,
+                BPF_NOEXIST.into(),
+            );
+            let counter = bpf_map_lookup_elem(map, key) as *const AtomicU64;
+            if !counter.is_null() {
+                (*counter).fetch_add(value, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn increment(&mut self, key: &K) {
+        self.add(key, 1)
+    }
+}
+
+/// A `BPF_MAP_TYPE_STACK_TRACE` map of the instruction pointers of stacks
+#[repr(transparent)]
+pub struct StackTrace {
+    def: bpf_map_def,
+}
+
+impl StackTrace {
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_STACK_TRACE,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: (mem::size_of::<u64>() * MAX_STACK_DEPTH) as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Stores the stack `ctx` was taken in, and returns its id, or a
+    /// negative error
+    #[inline(always)]
+    pub fn stack_id<C>(&mut self, ctx: *mut C, flags: u64) -> i32 {
+        unsafe {
+            bpf_get_stackid(
+                ctx as *mut c_void,
+                &mut self.def as *mut _ as *mut c_void,
+                flags,
+            )
+        }
+    }
+}
+
+/// A `BPF_MAP_TYPE_LRU_HASH`, which evicts the least recently used entries
+/// when it's full instead of failing inserts
+#[repr(transparent)]
+pub struct LruHashMap<K, V> {
+    def: bpf_map_def,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<K, V> LruHashMap<K, V> {
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_LRU_HASH,
+                key_size: mem::size_of::<K>() as u32,
+                value_size: mem::size_of::<V>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&*(value as *const V))
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, key: &K, value: &V) {
+        unsafe {
+            bpf_map_update_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+                value as *const _ as *const c_void,
+                BPF_ANY.into(),
+            );
+        }
+    }
+
+    #[inline(always)]
+    pub fn delete(&mut self, key: &K) {
+        unsafe {
+            bpf_map_delete_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            );
+        }
+    }
+}
+
+/// A `BPF_MAP_TYPE_PERCPU_HASH`, where every CPU has its own value of each
+/// key, so values can be updated without atomics and without losing updates
+/// of other CPUs
+#[repr(transparent)]
+pub struct PerCpuHashMap<K, V> {
+    def: bpf_map_def,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
+impl<K, V> PerCpuHashMap<K, V> {
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_PERCPU_HASH,
+                key_size: mem::size_of::<K>() as u32,
+                value_size: mem::size_of::<V>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    /// The current CPU's value of `key`
+    #[inline(always)]
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut V))
+            }
+        }
+    }
+
+    /// Sets the current CPU's value of `key`. The values of the other CPUs
+    /// start zeroed when the key is new.
+    #[inline(always)]
+    pub fn set(&mut self, key: &K, value: &V) {
+        unsafe {
+            bpf_map_update_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+                value as *const _ as *const c_void,
+                BPF_ANY.into(),
+            );
+        }
+    }
+}
+
+/// A `BPF_MAP_TYPE_PERCPU_ARRAY`, mostly useful to hold values too large
+/// for the stack of a program
+#[repr(transparent)]
+pub struct PerCpuArray<T> {
+    def: bpf_map_def,
+    _t: PhantomData<T>,
+}
+
+impl<T> PerCpuArray<T> {
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<T>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _t: PhantomData,
+        }
+    }
+
+    /// The current CPU's value at `index`, which starts zeroed
+    #[inline(always)]
+    pub fn get_mut(&mut self, index: u32) -> Option<&mut T> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut T))
+            }
+        }
+    }
+}
+
+/// Sends the first `size` bytes of `data` to `map`, like `PerfMap::insert`
+/// sends all of it. Used to send `WithPayload` events only up to the end of
+/// their payload.
+#[inline(always)]
+pub fn insert_truncated<C, T>(map: &mut PerfMap<T>, ctx: *mut C, data: &T, size: usize) {
+    bpf_perf_event_output(
+        ctx as *mut c_void,
+        map as *mut _ as *mut c_void,
+        BPF_F_CURRENT_CPU as u64,
+        data as *const _ as *const c_void,
+        size.min(mem::size_of::<T>()) as u64,
+    );
+}
+
+/// Inserts `value` for `key` unless it's in `map` already, maybe added by
+/// another CPU at the same time, and returns whether it was inserted
+#[inline(always)]
+pub fn insert_new<K, V>(map: &mut HashMap<K, V>, key: &K, value: &V) -> bool {
+    unsafe {
+        bpf_map_update_elem(
+            map as *mut _ as *mut c_void,
+            key as *const _ as *const c_void,
+            value as *const _ as *const c_void,
+            BPF_NOEXIST.into(),
+        ) == 0
+    }
+}
+
+/// Adds `value` to a counter in a map value without losing what other CPUs
+/// add at the same time
+#[inline(always)]
+pub fn atomic_add(counter: &mut u64, value: u64) {
+    unsafe {
+        (*(counter as *mut u64 as *const AtomicU64)).fetch_add(value, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+use cty::c_char;
+use ingraind_probes::memory::{MemoryEvent, Process, MMAP_THRESHOLD, REPORT_MAJOR_FAULTS};
+use ingraind_probes::{disabled, Timestamped};
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+const VM_FAULT_MAJOR: u32 = 0x0004;
+
+#[map("settings")]
+static mut settings: HashMap<u8, u64> = HashMap::with_max_entries(16);
+
+#[map("memory_events")]
+static mut memory_events: PerfMap<Timestamped<MemoryEvent>> = PerfMap::with_max_entries(1024);
+
+#[kprobe("oom_kill_process")]
+pub fn oom_kill(regs: Registers) {
+    if unsafe { disabled(&mut settings) } {
+        return;
+    }
+    let _ = trace_oom_kill(regs);
+}
+
+#[kretprobe("handle_mm_fault")]
+pub fn mm_fault(regs: Registers) {
+    if unsafe { disabled(&mut settings) } {
+        return;
+    }
+    if unsafe { settings.get(&REPORT_MAJOR_FAULTS) }
+        .cloned()
+        .unwrap_or(0)
+        == 0
+    {
+        return;
+    }
+
+    if regs.rc() as u32 & VM_FAULT_MAJOR != 0 {
+        unsafe {
+            memory_events.insert(
+                regs.ctx,
+                &Timestamped::now(MemoryEvent::MajorFault(current_process())),
+            );
+        }
+    }
+}
+
+#[kprobe("vm_mmap_pgoff")]
+pub fn mmap(regs: Registers) {
+    if unsafe { disabled(&mut settings) } {
+        return;
+    }
+    let len = regs.parm3();
+    let threshold = match unsafe { settings.get(&MMAP_THRESHOLD) } {
+        Some(t) => *t,
+        None => return,
+    };
+
+    if len >= threshold {
+        unsafe {
+            memory_events.insert(
+                regs.ctx,
+                &Timestamped::now(MemoryEvent::LargeMmap(current_process(), len)),
+            );
+        }
+    }
+}
+
+#[inline(always)]
+fn trace_oom_kill(regs: Registers) -> Option<()> {
+    let oc = regs.parm1() as *const oom_control;
+    let victim = unsafe {
+        let chosen = bpf_probe_read(&(*oc).chosen as *const *mut task_struct).ok()?;
+        if chosen.is_null() {
+            return None;
+        }
+
+        Process {
+            pid: bpf_probe_read(&(*chosen).tgid as *const pid_t).ok()? as u32,
+            comm: bpf_probe_read(&(*chosen).comm as *const [c_char; 16]).ok()?,
+        }
+    };
+
+    unsafe {
+        memory_events.insert(
+            regs.ctx,
+            &Timestamped::now(MemoryEvent::OomKill(victim, current_process())),
+        );
+    }
+
+    Some(())
+}
+
+#[inline(always)]
+fn current_process() -> Process {
+    Process {
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        comm: bpf_get_current_comm(),
+    }
+}
@@ -0,0 +1,20 @@
+use cty::*;
+
+pub const MMAP_THRESHOLD: u8 = 1;
+pub const REPORT_MAJOR_FAULTS: u8 = 2;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct Process {
+    pub pid: u32,
+    pub comm: [c_char; 16],
+}
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum MemoryEvent {
+    /// The victim and the process that triggered the OOM killer
+    OomKill(Process, Process),
+    MajorFault(Process),
+    LargeMmap(Process, u64),
+}
@@ -1,18 +1,51 @@
 #![no_std]
 #![no_main]
 use redbpf_probes::kprobe::prelude::*;
-use ingraind_probes::network::{Connection, Message};
+use ingraind_probes::filter::{Filters, FILTERS};
+use ingraind_probes::maps::{atomic_add, insert_new, LruHashMap};
+use ingraind_probes::network::{
+    owner_key, sock_cookie, sock_details, Connection, Flow, FlowVolume, Message, TcpEvent,
+    AGGREGATE,
+};
+use ingraind_probes::{disabled, Timestamped};
 
 program!(0xFFFFFFFE, "GPL");
 
+const TCP_ESTABLISHED: u32 = 1;
+const TCP_CLOSE: u32 = 7;
+
 #[map("task_to_socket")]
 static mut task_to_socket: HashMap<u64, *const sock> = HashMap::with_max_entries(10240);
 
+/// The process that connected, accepted or sent from a socket, keyed by
+/// the socket's cookie, or its address if it has none
+#[map("sockets")]
+static mut sockets: LruHashMap<u64, Connection> = LruHashMap::with_max_entries(10240);
+
 #[map("ip_connections")]
-static mut ip_connections: PerfMap<Connection> = PerfMap::with_max_entries(1024);
+static mut ip_connections: PerfMap<Timestamped<Connection>> = PerfMap::with_max_entries(1024);
+
+#[map("filters")]
+static mut filters: HashMap<u8, Filters> = HashMap::with_max_entries(1);
+
+#[map("filter_pids")]
+static mut filter_pids: HashMap<u32, u8> = HashMap::with_max_entries(10240);
+
+#[map("settings")]
+static mut settings: HashMap<u8, u64> = HashMap::with_max_entries(16);
+
+/// Volumes per flow, when aggregated in the kernel
+#[map("flow_volumes")]
+static mut flow_volumes: HashMap<Flow, FlowVolume> = HashMap::with_max_entries(10240);
 
 #[map("ip_volume")]
-static mut ip_volumes: PerfMap<Message> = PerfMap::with_max_entries(1024);
+static mut ip_volumes: PerfMap<Timestamped<Message>> = PerfMap::with_max_entries(1024);
+
+#[map("tcp_lifecycle")]
+static mut tcp_lifecycle: PerfMap<Timestamped<TcpEvent>> = PerfMap::with_max_entries(1024);
+
+#[map("established_at")]
+static mut established_at: HashMap<u64, u64> = HashMap::with_max_entries(10240);
 
 #[kprobe("tcp_v4_connect")]
 pub fn connect_enter(regs: Registers) {
@@ -21,11 +54,17 @@ pub fn connect_enter(regs: Registers) {
 
 #[kretprobe("tcp_v4_connect")]
 pub fn connect(regs: Registers) {
-    if let Some(c) = conn_details(regs) {
-        unsafe {
-            ip_connections.insert(regs.ctx, &c);
-        }
-    }
+    trace_connect(regs)
+}
+
+#[kprobe("tcp_v6_connect")]
+pub fn connect_v6_enter(regs: Registers) {
+    store_socket(regs)
+}
+
+#[kretprobe("tcp_v6_connect")]
+pub fn connect_v6(regs: Registers) {
+    trace_connect(regs)
 }
 
 #[kprobe("tcp_sendmsg")]
@@ -50,7 +89,14 @@ pub fn recv_exit(regs: Registers) {
 
 #[kprobe("udp_sendmsg")]
 pub fn udp_send_enter(regs: Registers) {
-    trace_message(regs, Message::Send)
+    store_socket(regs);
+    trace_udp_send(regs)
+}
+
+#[kprobe("udpv6_sendmsg")]
+pub fn udp_v6_send_enter(regs: Registers) {
+    store_socket(regs);
+    trace_udp_send(regs)
 }
 
 #[kprobe("udp_rcv")]
@@ -58,86 +104,248 @@ pub fn udp_rcv_enter(regs: Registers) {
     trace_message(regs, Message::Receive)
 }
 
+#[kprobe("udpv6_rcv")]
+pub fn udp_v6_rcv_enter(regs: Registers) {
+    trace_message(regs, Message::Receive)
+}
+
+#[kretprobe("inet_csk_accept")]
+pub fn accept(regs: Registers) {
+    let socket = regs.rc() as *const sock;
+    if socket.is_null() {
+        return;
+    }
+
+    if let Some(c) = sock_details(unsafe { &*socket }) {
+        record_owner(socket, &c);
+        if allowed(&c) {
+            unsafe {
+                tcp_lifecycle.insert(regs.ctx, &Timestamped::now(TcpEvent::Accept(c)));
+            }
+        }
+    }
+}
+
+#[kprobe("tcp_set_state")]
+pub fn set_state(regs: Registers) {
+    let socket = regs.parm1() as *const sock;
+    let key = socket as u64;
+    match regs.parm2() as u32 {
+        TCP_ESTABLISHED => unsafe {
+            established_at.set(&key, &bpf_ktime_get_ns());
+        },
+        TCP_CLOSE => {
+            let details = owned_details(socket);
+
+            // the socket may have been assigned a cookie since it was
+            // recorded
+            unsafe {
+                sockets.delete(&key);
+                sockets.delete(&owner_key(socket, sock_cookie(&*socket)));
+            }
+
+            // only connections we have seen established have a meaningful
+            // duration
+            let start = match unsafe { established_at.get(&key) } {
+                Some(ts) => *ts,
+                None => return,
+            };
+            unsafe {
+                established_at.delete(&key);
+            }
+
+            if let Some(c) = details.filter(allowed) {
+                let duration = c.ts - start;
+                unsafe {
+                    tcp_lifecycle.insert(regs.ctx, &Timestamped::now(TcpEvent::Close(c, duration)));
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+#[kprobe("tcp_retransmit_skb")]
+pub fn retransmit(regs: Registers) {
+    trace_tcp_event(regs, TcpEvent::Retransmit)
+}
+
+#[kprobe("tcp_reset")]
+pub fn reset(regs: Registers) {
+    trace_tcp_event(regs, TcpEvent::Reset)
+}
+
+#[inline(always)]
+fn trace_tcp_event(regs: Registers, event: fn(Connection) -> TcpEvent) {
+    let socket = regs.parm1() as *const sock;
+    if socket.is_null() {
+        return;
+    }
+
+    if let Some(c) = owned_details(socket).filter(allowed) {
+        unsafe {
+            tcp_lifecycle.insert(regs.ctx, &Timestamped::now(event(c)));
+        }
+    }
+}
+
+/// The details of a socket, with the process that connected or accepted it.
+///
+/// Sockets are closed, reset and retransmitted on from softirq context too,
+/// where the current task has nothing to do with the socket. The process and
+/// its network namespace are unknown, and 0, if the socket was never
+/// recorded.
+#[inline(always)]
+fn owned_details(socket: *const sock) -> Option<Connection> {
+    let mut c = sock_details(unsafe { &*socket })?;
+    let owner = unsafe {
+        match sockets.get(&owner_key(socket, c.cookie)) {
+            Some(owner) => Some(*owner),
+            None => sockets.get(&(socket as u64)).cloned(),
+        }
+    };
+
+    match owner {
+        Some(owner) => {
+            c.pid = owner.pid;
+            c.comm = owner.comm;
+            c.netns = owner.netns;
+        }
+        None => {
+            c.pid = 0;
+            c.comm = [0; 16];
+            c.netns = 0;
+        }
+    }
+
+    Some(c)
+}
+
+#[inline(always)]
+fn trace_connect(regs: Registers) {
+    if let Some((socket, c)) = conn_details(regs) {
+        record_owner(socket, &c);
+        if allowed(&c) {
+            unsafe {
+                ip_connections.insert(regs.ctx, &Timestamped::now(c));
+            }
+        }
+    }
+}
+
+/// UDP sockets are owned by the process sending from them, as they may
+/// never be connected
+#[inline(always)]
+fn trace_udp_send(regs: Registers) {
+    if let Some((socket, c)) = conn_details(regs) {
+        record_owner(socket, &c);
+        send_message(regs, c, Message::Send);
+    }
+}
+
+/// Makes the owner of the socket known to grains that only see its packets
+#[inline(always)]
+fn record_owner(socket: *const sock, conn: &Connection) {
+    unsafe {
+        sockets.set(&owner_key(socket, conn.cookie), conn);
+    }
+}
+
 #[inline(always)]
 fn store_socket(regs: Registers) {
+    if unsafe { disabled(&mut settings) } {
+        return;
+    }
     unsafe { task_to_socket.set(&bpf_get_current_pid_tgid(), &(regs.parm1() as *const sock)) };
 }
 
 #[inline(always)]
 fn trace_message(regs: Registers, direction: fn(Connection, u16) -> Message) {
-    if let Some(c) = conn_details(regs) {
-        let len = regs.parm3() as u16;
+    if let Some((_, c)) = conn_details(regs) {
+        send_message(regs, c, direction);
+    }
+}
+
+#[inline(always)]
+fn send_message(regs: Registers, c: Connection, direction: fn(Connection, u16) -> Message) {
+    if allowed(&c) {
+        let len = regs.parm3();
+        let message = direction(c, len as u16);
+        if aggregating() {
+            aggregate(&message, len);
+            return;
+        }
+
         unsafe {
-            ip_volumes.insert(regs.ctx, &direction(c, len));
+            ip_volumes.insert(regs.ctx, &Timestamped::now(message));
         }
     }
 }
 
 #[inline(always)]
-pub fn conn_details(_regs: Registers) -> Option<Connection> {
-    let pid_tgid = bpf_get_current_pid_tgid();
-    let socket = unsafe {
-        match task_to_socket.get(&pid_tgid) {
-            Some(s) => &**s,
-            None => return None,
+fn allowed(conn: &Connection) -> bool {
+    unsafe {
+        if disabled(&mut settings) {
+            return false;
         }
-    };
 
-    let pid = (pid_tgid >> 32) as u32;
-    let ts = bpf_ktime_get_ns();
-    let family = socket.skc_family()?;
+        match filters.get(&FILTERS) {
+            Some(f) => f.conn_allowed(&mut filter_pids, conn),
+            None => true,
+        }
+    }
+}
 
-    let mut daddr = in6_addr {
-        in6_u: in6_addr__bindgen_ty_1 {
-            u6_addr32: [0, 0, 0, 0],
-        },
+#[inline(always)]
+fn aggregating() -> bool {
+    unsafe { settings.get(&AGGREGATE) }.cloned().unwrap_or(0) != 0
+}
+
+/// Adds the message to the volume of its flow, which user space scrapes
+/// and resets
+#[inline(always)]
+fn aggregate(message: &Message, len: u64) {
+    let (conn, sent, received) = match message {
+        Message::Send(conn, _) => (*conn, len, 0),
+        Message::Receive(conn, _) => (*conn, 0, len),
     };
-    let mut saddr = in6_addr {
-        in6_u: in6_addr__bindgen_ty_1 {
-            u6_addr32: [0, 0, 0, 0],
-        },
+    let flow = Flow {
+        saddr: conn.saddr,
+        daddr: conn.daddr,
+        sport: conn.sport,
+        dport: conn.dport,
+        typ: conn.typ,
     };
 
-    if family as u32 == AF_INET6 {
-        daddr = socket.skc_v6_daddr()?;
-        saddr = socket.skc_v6_rcv_saddr()?;
-    } else if family as u32 == AF_INET {
-        let dest = socket.skc_daddr()?;
-        let src = socket.skc_rcv_saddr()?;
-
-        daddr = in6_addr {
-            in6_u: in6_addr__bindgen_ty_1 {
-                u6_addr32: [0, 0, 0xFFFF0000, dest],
-            },
-        };
-        saddr = in6_addr {
-            in6_u: in6_addr__bindgen_ty_1 {
-                u6_addr32: [0, 0, 0xFFFF0000, src],
-            },
-        };
-    }
-
-    let dport = socket.skc_dport()?;
-    let sport = socket.skc_num()?;
+    let empty = FlowVolume {
+        conn,
+        sent: 0,
+        received: 0,
+    };
 
-    let typ = {
-        let typ = unsafe { bpf_probe_read(&socket._bitfield_1 as *const _ as *const u32) }.ok()?;
+    unsafe {
+        insert_new(&mut flow_volumes, &flow, &empty);
+        if let Some(volume) = flow_volumes.get_mut(&flow) {
+            atomic_add(&mut volume.sent, sent);
+            atomic_add(&mut volume.received, received);
+        }
+    }
+}
 
-        (typ & SK_FL_PROTO_MASK) >> SK_FL_PROTO_SHIFT
+#[inline(always)]
+pub fn conn_details(_regs: Registers) -> Option<(*const sock, Connection)> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let socket = unsafe {
+        match task_to_socket.get(&pid_tgid) {
+            Some(s) => *s,
+            None => return None,
+        }
     };
 
+    let conn = sock_details(unsafe { &*socket })?;
     unsafe {
         task_to_socket.delete(&pid_tgid);
     }
 
-    Some(Connection {
-        pid,
-        ts,
-        comm: bpf_get_current_comm(),
-        saddr: saddr.into(),
-        daddr: daddr.into(),
-        sport: sport as u32,
-        dport: dport as u32,
-        typ,
-    })
+    Some((socket, conn))
 }
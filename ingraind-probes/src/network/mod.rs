@@ -3,6 +3,15 @@ use cty::*;
 use core::fmt::Debug;
 use redbpf_probes::bindings::*;
 
+#[cfg(feature = "probes")]
+mod probe;
+#[cfg(feature = "probes")]
+pub use self::probe::*;
+
+/// Key of the `settings` map that turns on aggregation in the kernel
+pub const AGGREGATE: u8 = 1;
+
+#[derive(Clone, Copy)]
 #[repr(C)]
 pub struct Ipv6Addr(in6_addr);
 
@@ -13,6 +22,39 @@ impl From<in6_addr> for Ipv6Addr {
     }
 }
 
+impl Ipv6Addr {
+    /// The address as 32 bit words in network byte order
+    #[inline]
+    pub fn words(&self) -> [u32; 4] {
+        unsafe { self.0.in6_u.u6_addr32 }
+    }
+
+    /// Group `i` of the eight 16 bit groups of the address
+    #[cfg(feature = "serde")]
+    #[inline]
+    fn group(&self, i: usize) -> u16 {
+        u16::from_be(unsafe { self.0.in6_u.u6_addr16[i] })
+    }
+}
+
+/// Serialized in its full text form, like `0:0:0:0:0:ffff:a00:1`
+#[cfg(feature = "serde")]
+impl serde::Serialize for Ipv6Addr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!(
+            "{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
+            self.group(0),
+            self.group(1),
+            self.group(2),
+            self.group(3),
+            self.group(4),
+            self.group(5),
+            self.group(6),
+            self.group(7)
+        ))
+    }
+}
+
 impl Debug for Ipv6Addr {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         unsafe {
@@ -32,7 +74,8 @@ impl Debug for Ipv6Addr {
     }
 }
 
-#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy)]
 pub struct Connection {
     pub ts: u64,
     pub pid: u32,
@@ -42,10 +85,47 @@ pub struct Connection {
     pub comm: [c_char; 16],
     pub saddr: Ipv6Addr,
     pub daddr: Ipv6Addr,
+    pub netns: u32,
+    pub ifindex: u32,
+    /// The socket's cookie, or 0 if the kernel hasn't assigned one yet
+    pub cookie: u64,
+}
+
+/// The 5-tuple of a flow, when volumes are aggregated in the kernel
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Flow {
+    pub saddr: Ipv6Addr,
+    pub daddr: Ipv6Addr,
+    pub sport: u32,
+    pub dport: u32,
+    pub typ: u32,
+}
+
+/// The volume of a flow, with the details of the connection that last
+/// sent or received on it
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FlowVolume {
+    pub conn: Connection,
+    pub sent: u64,
+    pub received: u64,
 }
 
 #[derive(Debug)]
+#[repr(u32)]
 pub enum Message {
     Send(Connection, u16),
     Receive(Connection, u16),
 }
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum TcpEvent {
+    Accept(Connection),
+    Close(Connection, u64),
+    Retransmit(Connection),
+    Reset(Connection),
+}
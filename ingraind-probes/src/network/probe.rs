@@ -0,0 +1,102 @@
+use cty::c_int;
+use redbpf_probes::kprobe::prelude::*;
+
+use crate::network::Connection;
+
+#[inline(always)]
+pub fn sock_details(socket: &sock) -> Option<Connection> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let pid = (pid_tgid >> 32) as u32;
+    let ts = bpf_ktime_get_ns();
+    let family = socket.skc_family()?;
+
+    let mut daddr = in6_addr {
+        in6_u: in6_addr__bindgen_ty_1 {
+            u6_addr32: [0, 0, 0, 0],
+        },
+    };
+    let mut saddr = in6_addr {
+        in6_u: in6_addr__bindgen_ty_1 {
+            u6_addr32: [0, 0, 0, 0],
+        },
+    };
+
+    if family as u32 == AF_INET6 {
+        daddr = socket.skc_v6_daddr()?;
+        saddr = socket.skc_v6_rcv_saddr()?;
+    } else if family as u32 == AF_INET {
+        let dest = socket.skc_daddr()?;
+        let src = socket.skc_rcv_saddr()?;
+
+        daddr = in6_addr {
+            in6_u: in6_addr__bindgen_ty_1 {
+                u6_addr32: [0, 0, 0xFFFF0000, dest],
+            },
+        };
+        saddr = in6_addr {
+            in6_u: in6_addr__bindgen_ty_1 {
+                u6_addr32: [0, 0, 0xFFFF0000, src],
+            },
+        };
+    }
+
+    let dport = socket.skc_dport()?;
+    let sport = socket.skc_num()?;
+
+    let typ = {
+        let typ = unsafe { bpf_probe_read(&socket._bitfield_1 as *const _ as *const u32) }.ok()?;
+
+        (typ & SK_FL_PROTO_MASK) >> SK_FL_PROTO_SHIFT
+    };
+
+    let ifindex = unsafe {
+        bpf_probe_read(&socket.__sk_common.skc_bound_dev_if as *const c_int).unwrap_or(0)
+    };
+
+    Some(Connection {
+        pid,
+        ts,
+        comm: bpf_get_current_comm(),
+        saddr: saddr.into(),
+        daddr: daddr.into(),
+        sport: sport as u32,
+        dport: dport as u32,
+        typ,
+        netns: task_netns().unwrap_or(0),
+        ifindex: ifindex as u32,
+        cookie: sock_cookie(socket),
+    })
+}
+
+/// The socket's cookie, or 0 if it hasn't been assigned one. Cookies are
+/// assigned lazily, by the first `bpf_get_socket_cookie()` on the socket.
+#[inline(always)]
+pub fn sock_cookie(socket: &sock) -> u64 {
+    unsafe { bpf_probe_read(&socket.__sk_common.skc_cookie as *const _ as *const u64).unwrap_or(0) }
+}
+
+/// The key of the socket in the map of socket owners: its cookie, or its
+/// address until it's assigned one
+#[inline(always)]
+pub fn owner_key(socket: *const sock, cookie: u64) -> u64 {
+    if cookie != 0 {
+        cookie
+    } else {
+        socket as u64
+    }
+}
+
+/// Inode number of the current task's network namespace, as seen in
+/// `/proc/<pid>/ns/net`
+#[inline(always)]
+pub fn task_netns() -> Option<u32> {
+    let task = bpf_get_current_task() as *const task_struct;
+    unsafe {
+        let nsproxy = bpf_probe_read(&(*task).nsproxy as *const *mut nsproxy).ok()?;
+        if nsproxy.is_null() {
+            return None;
+        }
+        let net_ns = bpf_probe_read(&(*nsproxy).net_ns as *const *mut net).ok()?;
+        bpf_probe_read(&(*net_ns).ns.inum as *const u32).ok()
+    }
+}
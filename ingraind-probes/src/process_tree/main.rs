@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::process_tree::ProcessEvent;
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("process_events")]
+static mut process_events: PerfMap<Timestamped<ProcessEvent>> = PerfMap::with_max_entries(1024);
+
+/// Every new task is woken up for the first time here, with the parent
+/// still running
+#[kprobe("wake_up_new_task")]
+pub fn fork(regs: Registers) {
+    let _ = trace_fork(regs);
+}
+
+#[inline(always)]
+fn trace_fork(regs: Registers) -> Option<()> {
+    let task = regs.parm1() as *const task_struct;
+    let (pid, tgid) = unsafe {
+        (
+            bpf_probe_read(&(*task).pid as *const pid_t).ok()?,
+            bpf_probe_read(&(*task).tgid as *const pid_t).ok()?,
+        )
+    };
+    // new threads don't change the process tree
+    if pid != tgid {
+        return None;
+    }
+
+    let event = ProcessEvent::Fork {
+        pid: tgid as u32,
+        ppid: (bpf_get_current_pid_tgid() >> 32) as u32,
+    };
+    unsafe {
+        process_events.insert(regs.ctx, &Timestamped::now(event));
+    }
+
+    Some(())
+}
+
+/// The `comm` of the process is set to the name of the new program by the
+/// time `setup_new_exec` returns
+#[kretprobe("setup_new_exec")]
+pub fn exec(regs: Registers) {
+    let event = ProcessEvent::Exec {
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        comm: bpf_get_current_comm(),
+    };
+    unsafe {
+        process_events.insert(regs.ctx, &Timestamped::now(event));
+    }
+}
+
+#[kprobe("do_exit")]
+pub fn exit(regs: Registers) {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    // only the exit of the thread group leader ends the process
+    if pid_tgid as u32 != (pid_tgid >> 32) as u32 {
+        return;
+    }
+
+    let event = ProcessEvent::Exit {
+        pid: (pid_tgid >> 32) as u32,
+    };
+    unsafe {
+        process_events.insert(regs.ctx, &Timestamped::now(event));
+    }
+}
@@ -0,0 +1,11 @@
+use cty::*;
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum ProcessEvent {
+    /// A new process, and the one that forked it
+    Fork { pid: u32, ppid: u32 },
+    /// A process replaced by a new program, with its new `comm`
+    Exec { pid: u32, comm: [c_char; 16] },
+    Exit { pid: u32 },
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+use cty::*;
+use ingraind_probes::maps::{Counters, StackTrace};
+use ingraind_probes::profile::StackKey;
+use ingraind_probes::{kernel_version, meta};
+use redbpf_macros::{map, program};
+use redbpf_probes::helpers::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// `perf_event` programs appeared in 4.9
+meta!("meta/min_kernel", kernel_version(4, 9));
+meta!("meta/needs=bpf_get_stackid");
+
+const BPF_F_USER_STACK: u64 = 1 << 8;
+
+#[map("stacks")]
+static mut stacks: StackTrace = StackTrace::with_max_entries(16384);
+
+/// The samples taken of each stack, read by ingraind every interval
+#[map("counts")]
+static mut counts: Counters<StackKey> = Counters::with_max_entries(10240);
+
+#[no_mangle]
+#[link_section = "perf_event/profile"]
+pub extern "C" fn profile(ctx: *mut c_void) -> i32 {
+    let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    // the idle task
+    if pid == 0 {
+        return 0;
+    }
+
+    let key = StackKey {
+        pid,
+        user_stack: unsafe { stacks.stack_id(ctx, BPF_F_USER_STACK) },
+        kernel_stack: unsafe { stacks.stack_id(ctx, 0) },
+        comm: bpf_get_current_comm(),
+    };
+    unsafe { counts.increment(&key) };
+
+    0
+}
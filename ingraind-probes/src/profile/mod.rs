@@ -0,0 +1,17 @@
+use cty::*;
+
+/// The number of frames kept of each stack
+pub const MAX_STACK_DEPTH: usize = 127;
+
+/// The stacks a process was sampled in, counted in the `counts` map.
+///
+/// Stack ids are indices of the `stacks` map, or negative if the stack
+/// couldn't be collected, like the user stack of a kernel thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct StackKey {
+    pub pid: u32,
+    pub user_stack: i32,
+    pub kernel_stack: i32,
+    pub comm: [c_char; 16],
+}
@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+use cty::c_char;
+use ingraind_probes::ptrace::{Injection, Process};
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[repr(C)]
+struct AttachArgs {
+    task: *const task_struct,
+    request: u64,
+}
+
+#[map("attach_args")]
+static mut attach_args: HashMap<u64, AttachArgs> = HashMap::with_max_entries(1024);
+
+#[map("injections")]
+static mut injections: PerfMap<Timestamped<Injection>> = PerfMap::with_max_entries(1024);
+
+#[kprobe("ptrace_attach")]
+pub fn attach_enter(regs: Registers) {
+    let args = AttachArgs {
+        task: regs.parm1() as *const task_struct,
+        request: regs.parm2(),
+    };
+    unsafe { attach_args.set(&bpf_get_current_pid_tgid(), &args) };
+}
+
+#[kretprobe("ptrace_attach")]
+pub fn attach_exit(regs: Registers) {
+    let _ = trace_attach(regs);
+}
+
+/// Both PTRACE_POKETEXT and PTRACE_POKEDATA end up here
+#[kprobe("generic_ptrace_pokedata")]
+pub fn poke(regs: Registers) {
+    let tracee = match task_process(regs.parm1() as *const task_struct) {
+        Some(p) => p,
+        None => return,
+    };
+
+    let event = Injection::Poke(current_process(), tracee, regs.parm2());
+    unsafe {
+        injections.insert(regs.ctx, &Timestamped::now(event));
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[kprobe("__x64_sys_process_vm_writev")]
+pub fn process_vm_writev(regs: Registers) {
+    let pid = unsafe { bpf_probe_read(&(*(regs.parm1() as *const pt_regs)).di) };
+    trace_vm_write(regs, pid.map(|p| p as u32).ok());
+}
+
+#[cfg(target_arch = "aarch64")]
+#[kprobe("__arm64_sys_process_vm_writev")]
+pub fn process_vm_writev(regs: Registers) {
+    let pid = unsafe { bpf_probe_read(&(*(regs.parm1() as *const user_pt_regs)).regs[0]) };
+    trace_vm_write(regs, pid.map(|p| p as u32).ok());
+}
+
+#[inline(always)]
+fn trace_attach(regs: Registers) -> Option<()> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let (task, request) = unsafe {
+        let args = attach_args.get(&pid_tgid)?;
+        (args.task, args.request)
+    };
+    unsafe {
+        attach_args.delete(&pid_tgid);
+    }
+
+    if regs.rc() as i32 != 0 {
+        return None;
+    }
+
+    let event = Injection::Attach(current_process(), task_process(task)?, request);
+    unsafe {
+        injections.insert(regs.ctx, &Timestamped::now(event));
+    }
+
+    Some(())
+}
+
+#[inline(always)]
+fn trace_vm_write(regs: Registers, pid: Option<u32>) {
+    if let Some(pid) = pid {
+        unsafe {
+            injections.insert(
+                regs.ctx,
+                &Timestamped::now(Injection::VmWrite(current_process(), pid)),
+            );
+        }
+    }
+}
+
+#[inline(always)]
+fn task_process(task: *const task_struct) -> Option<Process> {
+    if task.is_null() {
+        return None;
+    }
+
+    unsafe {
+        Some(Process {
+            pid: bpf_probe_read(&(*task).tgid as *const pid_t).ok()? as u32,
+            comm: bpf_probe_read(&(*task).comm as *const [c_char; 16]).ok()?,
+        })
+    }
+}
+
+#[inline(always)]
+fn current_process() -> Process {
+    Process {
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        comm: bpf_get_current_comm(),
+    }
+}
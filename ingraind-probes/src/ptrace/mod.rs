@@ -0,0 +1,12 @@
+pub use crate::memory::Process;
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum Injection {
+    /// Tracer, tracee and the ptrace request used to attach
+    Attach(Process, Process, u64),
+    /// Tracer, tracee and the address written to
+    Poke(Process, Process, u64),
+    /// Writer and the pid of the process written to
+    VmWrite(Process, u32),
+}
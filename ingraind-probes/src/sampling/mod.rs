@@ -0,0 +1,22 @@
+//! Sampling that probes apply in the kernel, to keep perf buffers from
+//! overflowing during bursts.
+//!
+//! Probes that support it define a `sampling` map, where user space sets a
+//! divisor N under the `SAMPLE_DIVISOR` key while events come faster than it
+//! is configured to handle. The probe then sends one in N events, picked at
+//! random, wrapped in a `Sampled` that holds N.
+
+#[cfg(feature = "probes")]
+mod probe;
+
+#[cfg(feature = "probes")]
+pub use self::probe::sample;
+
+pub const SAMPLE_DIVISOR: u8 = 0;
+
+/// An event sent in place of `divisor` events
+#[repr(C)]
+pub struct Sampled<T> {
+    pub divisor: u64,
+    pub event: T,
+}
@@ -0,0 +1,20 @@
+use redbpf_probes::kprobe::prelude::*;
+
+use crate::sampling::*;
+
+/// Whether to send the current event, and the divisor it's sampled with if
+/// so
+#[inline(always)]
+pub fn sample(sampling: &mut HashMap<u8, u64>) -> Option<u64> {
+    let divisor = sampling.get(&SAMPLE_DIVISOR).cloned().unwrap_or(1);
+    if divisor <= 1 {
+        return Some(1);
+    }
+
+    let random = unsafe { redbpf_probes::bindings::bpf_get_prandom_u32() } as u64;
+    if random % divisor == 0 {
+        Some(divisor)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,165 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::maps::Counters;
+use ingraind_probes::sched::{
+    RunqLatency, SchedSwitchArgs, TaskArgs, HISTOGRAM_BUCKETS, HISTOGRAM_INTERVAL, HISTOGRAM_SUM,
+    MIN_LATENCY,
+};
+use ingraind_probes::{disabled, kernel_version, meta, Timestamped};
+use redbpf_macros::{map, program};
+use redbpf_probes::helpers::*;
+use redbpf_probes::maps::{HashMap, PerfMap};
+
+program!(0xFFFFFFFE, "GPL");
+
+// `prev_state` reports preemption separately since 4.14
+meta!("meta/min_kernel", kernel_version(4, 14));
+
+const TASK_RUNNING: i64 = 0;
+const TASK_REPORT_MAX: i64 = 0x100;
+
+#[map("settings")]
+static mut settings: HashMap<u8, u64> = HashMap::with_max_entries(16);
+
+#[map("enqueued_at")]
+static mut enqueued_at: HashMap<u32, u64> = HashMap::with_max_entries(10240);
+
+/// The process of each thread seen switching out, as the tracepoints only
+/// have thread ids
+#[map("tgids")]
+static mut tgids: HashMap<u32, u32> = HashMap::with_max_entries(10240);
+
+#[map("runq_latency")]
+static mut runq_latency: PerfMap<Timestamped<RunqLatency>> = PerfMap::with_max_entries(1024);
+
+/// Run queue latencies in microseconds, counted in log2 buckets by index,
+/// and their sum at `HISTOGRAM_SUM`
+#[map("histogram")]
+static mut histogram: Counters<u8> = Counters::with_max_entries(HISTOGRAM_BUCKETS as u32 + 1);
+
+#[no_mangle]
+#[link_section = "tracepoint/sched_wakeup"]
+pub extern "C" fn sched_wakeup(args: *mut TaskArgs) -> i32 {
+    enqueue(unsafe { (*args).pid });
+    0
+}
+
+#[no_mangle]
+#[link_section = "tracepoint/sched_wakeup_new"]
+pub extern "C" fn sched_wakeup_new(args: *mut TaskArgs) -> i32 {
+    enqueue(unsafe { (*args).pid });
+    0
+}
+
+#[no_mangle]
+#[link_section = "tracepoint/sched_process_exit"]
+pub extern "C" fn sched_process_exit(args: *mut TaskArgs) -> i32 {
+    let tid = unsafe { (*args).pid } as u32;
+    unsafe {
+        tgids.delete(&tid);
+        enqueued_at.delete(&tid);
+    }
+    0
+}
+
+#[no_mangle]
+#[link_section = "tracepoint/sched_switch"]
+pub extern "C" fn sched_switch(ctx: *mut SchedSwitchArgs) -> i32 {
+    if unsafe { disabled(&mut settings) } {
+        return 0;
+    }
+    let args = unsafe { &*ctx };
+
+    // the tracepoint runs in the context of the task switching out
+    let prev = args.prev_pid as u32;
+    let tgid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    unsafe { tgids.set(&prev, &tgid) };
+
+    // a task that was preempted while running goes right back on the run
+    // queue
+    if args.prev_state == TASK_RUNNING || args.prev_state & TASK_REPORT_MAX != 0 {
+        enqueue(args.prev_pid);
+    }
+
+    let next = args.next_pid as u32;
+    let start = match unsafe { enqueued_at.get(&next) } {
+        Some(ts) => *ts,
+        None => return 0,
+    };
+    unsafe {
+        enqueued_at.delete(&next);
+    }
+
+    let now = bpf_ktime_get_ns();
+    let latency = now - start;
+    let min_latency = unsafe { settings.get(&MIN_LATENCY) }.cloned().unwrap_or(0);
+    if latency < min_latency {
+        return 0;
+    }
+
+    let interval = unsafe { settings.get(&HISTOGRAM_INTERVAL) }
+        .cloned()
+        .unwrap_or(0);
+    if interval > 0 {
+        record(latency / 1000);
+        return 0;
+    }
+
+    let event = RunqLatency {
+        pid: unsafe { tgids.get(&next) }.cloned().unwrap_or(next),
+        cpu: bpf_get_smp_processor_id(),
+        latency,
+        comm: args.next_comm,
+    };
+    unsafe {
+        runq_latency.insert(ctx, &Timestamped::now(event));
+    }
+
+    0
+}
+
+#[inline(always)]
+fn enqueue(tid: i32) {
+    // the idle task is never waiting on a run queue
+    if tid != 0 && !unsafe { disabled(&mut settings) } {
+        unsafe { enqueued_at.set(&(tid as u32), &bpf_ktime_get_ns()) };
+    }
+}
+
+/// Counts the latency in the histogram, which user space reads every
+/// interval
+#[inline(always)]
+fn record(latency_us: u64) {
+    let bucket = log2(latency_us) as usize;
+    let bucket = if bucket < HISTOGRAM_BUCKETS {
+        bucket
+    } else {
+        HISTOGRAM_BUCKETS - 1
+    };
+
+    unsafe {
+        histogram.increment(&(bucket as u8));
+        histogram.add(&HISTOGRAM_SUM, latency_us);
+    }
+}
+
+/// The index of the highest bit set, without loops for the verifier
+#[inline(always)]
+fn log2(v: u64) -> u64 {
+    let mut v = v;
+    let mut r = ((v > 0xFFFF_FFFF) as u64) << 5;
+    v >>= r;
+    let mut shift = ((v > 0xFFFF) as u64) << 4;
+    v >>= shift;
+    r |= shift;
+    shift = ((v > 0xFF) as u64) << 3;
+    v >>= shift;
+    r |= shift;
+    shift = ((v > 0xF) as u64) << 2;
+    v >>= shift;
+    r |= shift;
+    shift = ((v > 0x3) as u64) << 1;
+    v >>= shift;
+    r |= shift;
+    r | (v >> 1)
+}
@@ -0,0 +1,39 @@
+use cty::*;
+
+pub const MIN_LATENCY: u8 = 1;
+pub const HISTOGRAM_INTERVAL: u8 = 2;
+
+pub const HISTOGRAM_BUCKETS: usize = 32;
+/// The key of the sum of the latencies in the histogram, next to the
+/// buckets
+pub const HISTOGRAM_SUM: u8 = 0xFF;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct RunqLatency {
+    pub pid: u32,
+    pub cpu: u32,
+    pub latency: u64,
+    pub comm: [c_char; 16],
+}
+
+/// The fields `sched_wakeup`, `sched_wakeup_new` and `sched_process_exit`
+/// start with
+#[repr(C)]
+pub struct TaskArgs {
+    pub common: u64,
+    pub comm: [c_char; 16],
+    pub pid: i32,
+}
+
+#[repr(C)]
+pub struct SchedSwitchArgs {
+    pub common: u64,
+    pub prev_comm: [c_char; 16],
+    pub prev_pid: i32,
+    pub prev_prio: i32,
+    pub prev_state: i64,
+    pub next_comm: [c_char; 16],
+    pub next_pid: i32,
+    pub next_prio: i32,
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+use cty::c_char;
+use ingraind_probes::ssh::{Session, SshSession};
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("sshd_exec")]
+static mut sshd_exec: HashMap<u64, u8> = HashMap::with_max_entries(1024);
+
+#[map("sessions")]
+static mut sessions: HashMap<u32, u64> = HashMap::with_max_entries(10240);
+
+#[map("ssh_sessions")]
+static mut ssh_sessions: PerfMap<Timestamped<SshSession>> = PerfMap::with_max_entries(1024);
+
+/// sshd forks a child for every session, which then executes the shell or
+/// command. `setup_new_exec` renames the task, so the old name is checked
+/// on entry and the new one on return.
+#[kprobe("setup_new_exec")]
+pub fn exec_enter(_regs: Registers) {
+    if is_sshd(&bpf_get_current_comm()) {
+        unsafe { sshd_exec.set(&bpf_get_current_pid_tgid(), &1) };
+    }
+}
+
+#[kretprobe("setup_new_exec")]
+pub fn exec_exit(regs: Registers) {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    unsafe {
+        if sshd_exec.get(&pid_tgid).is_none() {
+            return;
+        }
+        sshd_exec.delete(&pid_tgid);
+    }
+
+    // sshd re-executes itself for privilege separation
+    let comm = bpf_get_current_comm();
+    if is_sshd(&comm) {
+        return;
+    }
+
+    let pid = (pid_tgid >> 32) as u32;
+    unsafe {
+        sessions.set(&pid, &bpf_ktime_get_ns());
+        ssh_sessions.insert(
+            regs.ctx,
+            &Timestamped::now(SshSession::Start(Session { pid, comm })),
+        );
+    }
+}
+
+#[kprobe("do_exit")]
+pub fn exit(regs: Registers) {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let pid = (pid_tgid >> 32) as u32;
+
+    // only the thread group leader ends the session
+    if pid != pid_tgid as u32 {
+        return;
+    }
+
+    unsafe {
+        let start = match sessions.get(&pid) {
+            Some(start) => *start,
+            None => return,
+        };
+        sessions.delete(&pid);
+
+        let session = Session {
+            pid,
+            comm: bpf_get_current_comm(),
+        };
+        let duration = bpf_ktime_get_ns() - start;
+        ssh_sessions.insert(
+            regs.ctx,
+            &Timestamped::now(SshSession::End(session, duration)),
+        );
+    }
+}
+
+#[inline(always)]
+fn is_sshd(comm: &[c_char; 16]) -> bool {
+    comm[0] == b's' as c_char
+        && comm[1] == b's' as c_char
+        && comm[2] == b'h' as c_char
+        && comm[3] == b'd' as c_char
+        && comm[4] == 0
+}
@@ -0,0 +1,16 @@
+use cty::*;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct Session {
+    pub pid: u32,
+    pub comm: [c_char; 16],
+}
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum SshSession {
+    Start(Session),
+    /// The session leader exited after the given number of nanoseconds
+    End(Session, u64),
+}
@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::ssl::{SslData, SslEvent, SNI_LEN};
+use ingraind_probes::{meta, Timestamped};
+use redbpf_probes::bindings;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+meta!("meta/needs=bpf_probe_read_str");
+
+const SSL_CTRL_SET_TLSEXT_HOSTNAME: i32 = 55;
+
+#[map("read_args")]
+static mut read_args: HashMap<u64, u64> = HashMap::with_max_entries(10240);
+
+#[map("write_args")]
+static mut write_args: HashMap<u64, u64> = HashMap::with_max_entries(10240);
+
+#[map("servername_args")]
+static mut servername_args: HashMap<u64, u64> = HashMap::with_max_entries(10240);
+
+/// SSL pointer to the server name of the connection
+#[map("server_names")]
+static mut server_names: HashMap<u64, [u8; SNI_LEN]> = HashMap::with_max_entries(10240);
+
+#[map("ssl_events")]
+static mut ssl_events: PerfMap<Timestamped<SslEvent>> = PerfMap::with_max_entries(1024);
+
+#[uprobe]
+pub fn ssl_read_enter(regs: Registers) {
+    unsafe { read_args.set(&bpf_get_current_pid_tgid(), &regs.parm1()) };
+}
+
+#[uretprobe]
+pub fn ssl_read_exit(regs: Registers) {
+    if let Some(data) = unsafe { ssl_data(&mut read_args, regs) } {
+        unsafe { ssl_events.insert(regs.ctx, &Timestamped::now(SslEvent::Read(data))) };
+    }
+}
+
+#[uprobe]
+pub fn ssl_write_enter(regs: Registers) {
+    unsafe { write_args.set(&bpf_get_current_pid_tgid(), &regs.parm1()) };
+}
+
+#[uretprobe]
+pub fn ssl_write_exit(regs: Registers) {
+    if let Some(data) = unsafe { ssl_data(&mut write_args, regs) } {
+        unsafe { ssl_events.insert(regs.ctx, &Timestamped::now(SslEvent::Write(data))) };
+    }
+}
+
+/// Clients set the server name through `SSL_set_tlsext_host_name`, which
+/// is a macro around `SSL_ctrl`
+#[uprobe]
+pub fn ssl_ctrl(regs: Registers) {
+    if regs.parm2() as i32 != SSL_CTRL_SET_TLSEXT_HOSTNAME {
+        return;
+    }
+
+    if let Some(name) = read_name(regs.parm4()) {
+        unsafe { server_names.set(&regs.parm1(), &name) };
+    }
+}
+
+/// Servers look up the name the client asked for
+#[uprobe]
+pub fn get_servername_enter(regs: Registers) {
+    unsafe { servername_args.set(&bpf_get_current_pid_tgid(), &regs.parm1()) };
+}
+
+#[uretprobe]
+pub fn get_servername_exit(regs: Registers) {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    unsafe {
+        let ssl = match servername_args.get(&pid_tgid) {
+            Some(ssl) => *ssl,
+            None => return,
+        };
+        servername_args.delete(&pid_tgid);
+
+        if let Some(name) = read_name(regs.rc()) {
+            server_names.set(&ssl, &name);
+        }
+    }
+}
+
+#[uprobe]
+pub fn ssl_free(regs: Registers) {
+    unsafe { server_names.delete(&regs.parm1()) };
+}
+
+/// Reads the NUL terminated server name at `addr`, which may be shorter
+/// than the buffer and end right before an unmapped page
+#[inline(always)]
+fn read_name(addr: u64) -> Option<[u8; SNI_LEN]> {
+    if addr == 0 {
+        return None;
+    }
+
+    let mut name = [0u8; SNI_LEN];
+    let read = unsafe {
+        bindings::bpf_probe_read_str(
+            name.as_mut_ptr() as *mut _,
+            SNI_LEN as i32,
+            addr as *const _,
+        )
+    };
+    if read <= 0 {
+        return None;
+    }
+
+    Some(name)
+}
+
+#[inline(always)]
+unsafe fn ssl_data(args: &mut HashMap<u64, u64>, regs: Registers) -> Option<SslData> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let ssl = *args.get(&pid_tgid)?;
+    args.delete(&pid_tgid);
+
+    let len = regs.rc() as i32;
+    if len <= 0 {
+        return None;
+    }
+
+    Some(SslData {
+        pid: (pid_tgid >> 32) as u32,
+        len: len as u32,
+        comm: bpf_get_current_comm(),
+        sni: server_names.get(&ssl).map(|s| *s).unwrap_or([0u8; SNI_LEN]),
+    })
+}
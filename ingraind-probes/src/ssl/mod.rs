@@ -0,0 +1,20 @@
+use cty::*;
+
+pub const SNI_LEN: usize = 64;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct SslData {
+    pub pid: u32,
+    /// Plaintext bytes read or written
+    pub len: u32,
+    pub comm: [c_char; 16],
+    pub sni: [u8; SNI_LEN],
+}
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum SslEvent {
+    Read(SslData),
+    Write(SslData),
+}
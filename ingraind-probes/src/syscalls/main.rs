@@ -1,36 +1,63 @@
 #![no_std]
 #![no_main]
-use redbpf_probes::kprobe::prelude::*;
-use ingraind_probes::syscalls::SyscallTracepoint;
+use ingraind_probes::sampling::{sample, Sampled};
+use ingraind_probes::syscalls::{SysEnterArgs, SyscallTracepoint};
+use ingraind_probes::Timestamped;
+use redbpf_macros::{map, program};
+use redbpf_probes::helpers::*;
+use redbpf_probes::maps::{HashMap, PerfMap};
 
 program!(0xFFFFFFFE, "GPL");
 
 #[map("syscall_tp_trigger")]
-static mut syscall_event: PerfMap<SyscallTracepoint> = PerfMap::with_max_entries(1024);
+static mut syscall_event: PerfMap<Timestamped<Sampled<SyscallTracepoint>>> =
+    PerfMap::with_max_entries(1024);
 
 #[map("host_pid")]
 static mut host_pid: HashMap<u8, u64> = HashMap::with_max_entries(1024);
 
-#[kprobe("__x64_sys_clone")]
-pub fn syscall_enter(regs: Registers) {
+#[map("sampling")]
+static mut sampling: HashMap<u8, u64> = HashMap::with_max_entries(1);
+
+/// The numbers of the system calls to report
+#[map("monitored")]
+static mut monitored: HashMap<u64, u8> = HashMap::with_max_entries(512);
+
+/// The tracepoint has the syscall number on every kernel and architecture,
+/// unlike the arguments of the kernel's syscall functions, which are the
+/// registers of the calling process only on kernels from 4.17
+#[no_mangle]
+#[link_section = "tracepoint/sys_enter"]
+pub extern "C" fn sys_enter(args: *mut SysEnterArgs) -> i32 {
+    let syscall_nr = unsafe { (*args).id } as u64;
+    if unsafe { monitored.get(&syscall_nr) }.is_none() {
+        return 0;
+    }
+
     let k = 1u8;
     let ignore_pid = unsafe { host_pid.get(&k) };
     let pid_tgid = bpf_get_current_pid_tgid();
     if let Some(pid) = ignore_pid {
         if *pid == pid_tgid >> 32 {
-            return;
+            return 0;
         }
     }
 
-    #[cfg(target_arch = "x86_64")]
-    let syscall_nr = unsafe { (*(regs.ctx as *const pt_regs)).ax };
-    #[cfg(target_arch = "aarch64")]
-    let syscall_nr = unsafe { (*(regs.ctx as *const user_pt_regs)).regs[1] };
+    let divisor = match sample(unsafe { &mut sampling }) {
+        Some(divisor) => divisor,
+        None => return 0,
+    };
 
     let data = SyscallTracepoint {
         id: pid_tgid >> 32,
         syscall_nr,
         comm: bpf_get_current_comm(),
     };
-    unsafe { syscall_event.insert(regs.ctx, &data) };
+    let event = Sampled {
+        divisor,
+        event: data,
+    };
+    unsafe { syscall_event.insert(args, &Timestamped::now(event)) };
+
+    0
 }
@@ -6,4 +6,12 @@ pub struct SyscallTracepoint {
   pub id: c_ulonglong,
   pub syscall_nr: c_ulonglong,
   pub comm: [c_char; 16usize],
-}
\ No newline at end of file
+}
+
+/// The fields of the `raw_syscalls:sys_enter` tracepoint
+#[repr(C)]
+pub struct SysEnterArgs {
+  pub common: u64,
+  pub id: c_long,
+  pub args: [c_ulong; 6],
+}
@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::network::sock_details;
+use ingraind_probes::tcp_latency::{LatencyEvent, RTT_SAMPLE_INTERVAL};
+use ingraind_probes::{disabled, Timestamped};
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+const TCP_ESTABLISHED: u32 = 1;
+const TCP_CLOSE: u32 = 7;
+
+#[map("settings")]
+static mut settings: HashMap<u8, u64> = HashMap::with_max_entries(16);
+
+#[map("connect_start")]
+static mut connect_start: HashMap<u64, u64> = HashMap::with_max_entries(10240);
+
+#[map("last_rtt_sample")]
+static mut last_rtt_sample: HashMap<u64, u64> = HashMap::with_max_entries(10240);
+
+#[map("tcp_latency")]
+static mut tcp_latency: PerfMap<Timestamped<LatencyEvent>> = PerfMap::with_max_entries(1024);
+
+#[kprobe("tcp_v4_connect")]
+pub fn connect_v4(regs: Registers) {
+    record_connect(regs)
+}
+
+#[kprobe("tcp_v6_connect")]
+pub fn connect_v6(regs: Registers) {
+    record_connect(regs)
+}
+
+#[kprobe("tcp_set_state")]
+pub fn set_state(regs: Registers) {
+    if unsafe { disabled(&mut settings) } {
+        return;
+    }
+    let socket = regs.parm1() as *const sock;
+    let key = socket as u64;
+    match regs.parm2() as u32 {
+        TCP_ESTABLISHED => {
+            let start = match unsafe { connect_start.get(&key) } {
+                Some(ts) => *ts,
+                None => return,
+            };
+            unsafe {
+                connect_start.delete(&key);
+            }
+
+            if let Some(c) = sock_details(unsafe { &*socket }) {
+                let latency = c.ts - start;
+                unsafe {
+                    tcp_latency.insert(
+                        regs.ctx,
+                        &Timestamped::now(LatencyEvent::Connect(c, latency)),
+                    );
+                }
+            }
+        }
+        TCP_CLOSE => unsafe {
+            connect_start.delete(&key);
+            last_rtt_sample.delete(&key);
+        },
+        _ => (),
+    }
+}
+
+#[kprobe("tcp_rcv_established")]
+pub fn rcv_established(regs: Registers) {
+    let _ = sample_rtt(regs);
+}
+
+#[inline(always)]
+fn record_connect(regs: Registers) {
+    if unsafe { disabled(&mut settings) } {
+        return;
+    }
+    let key = regs.parm1();
+    unsafe { connect_start.set(&key, &bpf_ktime_get_ns()) };
+}
+
+#[inline(always)]
+fn sample_rtt(regs: Registers) -> Option<()> {
+    if unsafe { disabled(&mut settings) } {
+        return None;
+    }
+    let socket = regs.parm1() as *const sock;
+    let key = socket as u64;
+    let now = bpf_ktime_get_ns();
+
+    // tcp_rcv_established fires for every segment, so only sample each
+    // socket once per configured interval
+    let interval = unsafe { settings.get(&RTT_SAMPLE_INTERVAL) }
+        .cloned()
+        .unwrap_or(0);
+    if let Some(last) = unsafe { last_rtt_sample.get(&key) } {
+        if now - *last < interval {
+            return None;
+        }
+    }
+
+    // srtt_us is stored left-shifted by 3 in the kernel
+    let tp = socket as *const tcp_sock;
+    let srtt = unsafe { bpf_probe_read(&(*tp).srtt_us as *const u32) }.ok()? >> 3;
+    if srtt == 0 {
+        return None;
+    }
+
+    let c = sock_details(unsafe { &*socket })?;
+    unsafe {
+        last_rtt_sample.set(&key, &now);
+        tcp_latency.insert(regs.ctx, &Timestamped::now(LatencyEvent::Rtt(c, srtt)));
+    }
+
+    Some(())
+}
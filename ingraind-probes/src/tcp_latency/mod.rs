@@ -0,0 +1,10 @@
+use crate::network::Connection;
+
+pub const RTT_SAMPLE_INTERVAL: u8 = 1;
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum LatencyEvent {
+    Rtt(Connection, u32),
+    Connect(Connection, u64),
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+use cty::*;
+use ingraind_probes::maps::{insert_truncated, PerCpuArray};
+use ingraind_probes::tty::Command;
+use ingraind_probes::{meta, Timestamped, WithPayload};
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+meta!("meta/needs=bpf_get_current_task");
+
+/// The `comm` of the shells whose commands are recorded
+#[map("shells")]
+static mut shells: HashMap<[c_char; 16], u8> = HashMap::with_max_entries(64);
+
+/// The command being built on each CPU, too large for the stack
+#[map("command")]
+static mut command: PerCpuArray<Timestamped<WithPayload<Command>>> =
+    PerCpuArray::with_max_entries(1);
+
+#[map("commands")]
+static mut commands: PerfMap<Timestamped<WithPayload<Command>>> = PerfMap::with_max_entries(1024);
+
+/// The arguments are on the new program's stack once `finalize_exec` is
+/// called, at the end of loading it
+#[kprobe("finalize_exec")]
+pub fn exec(regs: Registers) {
+    let _ = trace_exec(regs);
+}
+
+#[inline(always)]
+fn trace_exec(regs: Registers) -> Option<()> {
+    let task = bpf_get_current_task() as *const task_struct;
+
+    unsafe {
+        // only commands run on a terminal
+        let signal = bpf_probe_read(&(*task).signal as *const *mut signal_struct).ok()?;
+        let tty = bpf_probe_read(&(*signal).tty as *const *mut tty_struct).ok()?;
+        if tty.is_null() {
+            return None;
+        }
+
+        // by an interactive shell
+        let parent = bpf_probe_read(&(*task).real_parent as *const *mut task_struct).ok()?;
+        let parent_comm = bpf_probe_read(&(*parent).comm as *const [c_char; 16]).ok()?;
+        shells.get(&parent_comm)?;
+
+        let mm = bpf_probe_read(&(*task).mm as *const *mut mm_struct).ok()?;
+        let arg_start = bpf_probe_read(&(*mm).arg_start as *const c_ulong).ok()?;
+        let arg_end = bpf_probe_read(&(*mm).arg_end as *const c_ulong).ok()?;
+
+        let event = command.get_mut(0)?;
+        event.timestamp = bpf_ktime_get_ns();
+        event.event.event = Command {
+            pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+            ppid: bpf_probe_read(&(*parent).tgid as *const pid_t).ok()? as u32,
+            uid: bpf_get_current_uid_gid() as u32,
+            comm: bpf_get_current_comm(),
+            parent_comm,
+            tty: bpf_probe_read(&(*tty).name as *const _ as *const [c_char; 16]).ok()?,
+        };
+        event
+            .event
+            .read_bytes(arg_start as *const u8, (arg_end - arg_start) as usize)?;
+
+        // only up to the end of the arguments
+        let size = event.event.sent_size(&*event);
+        insert_truncated(&mut commands, regs.ctx, event, size);
+    }
+
+    Some(())
+}
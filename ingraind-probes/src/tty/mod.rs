@@ -0,0 +1,16 @@
+use cty::*;
+
+/// A program executed by an interactive shell, followed by its command line
+/// with the arguments separated by NULs
+#[derive(Debug)]
+#[repr(C)]
+pub struct Command {
+    pub pid: u32,
+    pub ppid: u32,
+    pub uid: u32,
+    pub comm: [c_char; 16],
+    /// The shell that executed the command
+    pub parent_comm: [c_char; 16],
+    /// The name of the controlling terminal, like `pts/0`
+    pub tty: [c_char; 16],
+}
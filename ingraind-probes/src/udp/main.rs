@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+use cty::c_void;
+use ingraind_probes::network::sock_details;
+use ingraind_probes::udp::{Connection, Message};
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[repr(C)]
+struct RecvArgs {
+    socket: *const sock,
+    msg: *const msghdr,
+}
+
+#[map("recv_args")]
+static mut recv_args: HashMap<u64, RecvArgs> = HashMap::with_max_entries(10240);
+
+#[map("udp_volume")]
+static mut udp_volume: PerfMap<Timestamped<Message>> = PerfMap::with_max_entries(1024);
+
+#[kprobe("udp_sendmsg")]
+pub fn send_v4(regs: Registers) {
+    trace_send(regs)
+}
+
+#[kprobe("udpv6_sendmsg")]
+pub fn send_v6(regs: Registers) {
+    trace_send(regs)
+}
+
+#[kprobe("udp_recvmsg")]
+pub fn recv_v4_enter(regs: Registers) {
+    store_recv_args(regs)
+}
+
+#[kretprobe("udp_recvmsg")]
+pub fn recv_v4_exit(regs: Registers) {
+    trace_receive(regs)
+}
+
+#[kprobe("udpv6_recvmsg")]
+pub fn recv_v6_enter(regs: Registers) {
+    store_recv_args(regs)
+}
+
+#[kretprobe("udpv6_recvmsg")]
+pub fn recv_v6_exit(regs: Registers) {
+    trace_receive(regs)
+}
+
+#[inline(always)]
+fn trace_send(regs: Registers) {
+    let socket = regs.parm1() as *const sock;
+    let msg = regs.parm2() as *const msghdr;
+    let len = regs.parm3() as u16;
+
+    if let Some(c) = udp_details(socket, msg) {
+        unsafe {
+            udp_volume.insert(regs.ctx, &Timestamped::now(Message::Send(c, len)));
+        }
+    }
+}
+
+#[inline(always)]
+fn store_recv_args(regs: Registers) {
+    let args = RecvArgs {
+        socket: regs.parm1() as *const sock,
+        msg: regs.parm2() as *const msghdr,
+    };
+    unsafe { recv_args.set(&bpf_get_current_pid_tgid(), &args) };
+}
+
+#[inline(always)]
+fn trace_receive(regs: Registers) {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let (socket, msg) = match unsafe { recv_args.get(&pid_tgid) } {
+        Some(args) => (args.socket, args.msg),
+        None => return,
+    };
+    unsafe {
+        recv_args.delete(&pid_tgid);
+    }
+
+    let len = regs.rc() as i32;
+    if len <= 0 {
+        return;
+    }
+
+    if let Some(c) = udp_details(socket, msg) {
+        unsafe {
+            udp_volume.insert(regs.ctx, &Timestamped::now(Message::Receive(c, len as u16)));
+        }
+    }
+}
+
+/// Unconnected UDP sockets carry the peer address in `msg_name` instead of
+/// the socket, so prefer that whenever it's available.
+#[inline(always)]
+fn udp_details(socket: *const sock, msg: *const msghdr) -> Option<Connection> {
+    if socket.is_null() {
+        return None;
+    }
+
+    let mut conn = sock_details(unsafe { &*socket })?;
+    if msg.is_null() {
+        return Some(conn);
+    }
+
+    let name = unsafe { bpf_probe_read(&(*msg).msg_name as *const *mut c_void) }.ok()?;
+    if name.is_null() {
+        return Some(conn);
+    }
+
+    let family = unsafe { bpf_probe_read(name as *const u16) }.ok()?;
+    if family as u32 == AF_INET {
+        let sin = unsafe { bpf_probe_read(name as *const sockaddr_in) }.ok()?;
+        conn.daddr = in6_addr {
+            in6_u: in6_addr__bindgen_ty_1 {
+                u6_addr32: [0, 0, 0xFFFF0000, sin.sin_addr.s_addr],
+            },
+        }
+        .into();
+        conn.dport = sin.sin_port as u32;
+    } else if family as u32 == AF_INET6 {
+        let sin6 = unsafe { bpf_probe_read(name as *const sockaddr_in6) }.ok()?;
+        conn.daddr = sin6.sin6_addr.into();
+        conn.dport = sin6.sin6_port as u32;
+    }
+
+    Some(conn)
+}
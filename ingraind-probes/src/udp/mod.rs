@@ -0,0 +1 @@
+pub use crate::network::{Connection, Message};
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+use cty::c_void;
+use ingraind_probes::unix::{UnixMessage, UnixSend, UNIX_PATH_MAX};
+use ingraind_probes::Timestamped;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map("unix_volume")]
+static mut unix_volume: PerfMap<Timestamped<UnixMessage>> = PerfMap::with_max_entries(1024);
+
+#[kprobe("unix_stream_sendmsg")]
+pub fn stream_sendmsg(regs: Registers) {
+    if let Some(send) = unix_send(regs) {
+        unsafe {
+            unix_volume.insert(regs.ctx, &Timestamped::now(UnixMessage::Stream(send)));
+        }
+    }
+}
+
+#[kprobe("unix_dgram_sendmsg")]
+pub fn dgram_sendmsg(regs: Registers) {
+    if let Some(send) = unix_send(regs) {
+        unsafe {
+            unix_volume.insert(regs.ctx, &Timestamped::now(UnixMessage::Dgram(send)));
+        }
+    }
+}
+
+#[inline(always)]
+fn unix_send(regs: Registers) -> Option<UnixSend> {
+    let socket = regs.parm1() as *const socket;
+    let msg = regs.parm2() as *const msghdr;
+
+    let mut send = UnixSend {
+        pid: (bpf_get_current_pid_tgid() >> 32) as u32,
+        len: regs.parm3() as u32,
+        comm: bpf_get_current_comm(),
+        path: [0u8; UNIX_PATH_MAX],
+    };
+
+    // datagrams sent with sendto() carry the destination in the message
+    let name = unsafe { bpf_probe_read(&(*msg).msg_name as *const *mut c_void) }.ok()?;
+    if !name.is_null() {
+        let addr = unsafe { bpf_probe_read(name as *const sockaddr_un) }.ok()?;
+        send.path = unsafe { core::mem::transmute(addr.sun_path) };
+        return Some(send);
+    }
+
+    let sk = unsafe { bpf_probe_read(&(*socket).sk as *const *mut sock) }.ok()?;
+    let mut addr = unix_address(sk as *const unix_sock)?;
+
+    // clients usually don't bind, in which case the path is found on the
+    // listening end
+    if addr.is_null() {
+        let peer = unsafe { bpf_probe_read(&(*(sk as *const unix_sock)).peer as *const *mut sock) }
+            .ok()?;
+        addr = unix_address(peer as *const unix_sock)?;
+    }
+
+    if !addr.is_null() {
+        let name = unsafe { (*addr).name.as_ptr() };
+        let addr = unsafe { bpf_probe_read(name as *const sockaddr_un) }.ok()?;
+        send.path = unsafe { core::mem::transmute(addr.sun_path) };
+    }
+
+    Some(send)
+}
+
+#[inline(always)]
+fn unix_address(sk: *const unix_sock) -> Option<*const unix_address> {
+    if sk.is_null() {
+        return Some(core::ptr::null());
+    }
+
+    unsafe { bpf_probe_read(&(*sk).addr as *const *mut unix_address) }
+        .ok()
+        .map(|a| a as *const unix_address)
+}
@@ -0,0 +1,19 @@
+use cty::*;
+
+pub const UNIX_PATH_MAX: usize = 108;
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct UnixSend {
+    pub pid: u32,
+    pub len: u32,
+    pub comm: [c_char; 16],
+    pub path: [u8; UNIX_PATH_MAX],
+}
+
+#[derive(Debug)]
+#[repr(u32)]
+pub enum UnixMessage {
+    Stream(UnixSend),
+    Dgram(UnixSend),
+}
@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+use ingraind_probes::maps::PerCpuArray;
+use ingraind_probes::usdt::{
+    ArgSpec, UsdtEvent, ARG_MEMORY, ARG_STRING, MAX_ARGS, NO_REG, PT_REGS_LEN, STRING_LEN,
+};
+use ingraind_probes::{meta, Timestamped};
+use redbpf_probes::bindings;
+use redbpf_probes::kprobe::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+meta!("meta/needs=bpf_probe_read_str");
+
+#[map("args")]
+static mut args: HashMap<u8, ArgSpec> = HashMap::with_max_entries(MAX_ARGS as u32);
+
+/// The event being built on each CPU, too large for the stack
+#[map("event")]
+static mut event: PerCpuArray<Timestamped<UsdtEvent>> = PerCpuArray::with_max_entries(1);
+
+#[map("usdt_events")]
+static mut usdt_events: PerfMap<Timestamped<UsdtEvent>> = PerfMap::with_max_entries(1024);
+
+#[uprobe]
+pub fn usdt(regs: Registers) {
+    let current = match unsafe { event.get_mut(0) } {
+        Some(current) => current,
+        None => return,
+    };
+
+    let saved = &mut current.event.regs;
+    let read = unsafe {
+        bindings::bpf_probe_read(
+            saved.as_mut_ptr() as *mut _,
+            (PT_REGS_LEN * 8) as u32,
+            regs.ctx as *const _,
+        )
+    };
+    if read < 0 {
+        return;
+    }
+
+    current.timestamp = bpf_ktime_get_ns();
+    current.event.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    current.event.comm = bpf_get_current_comm();
+
+    // the arguments in the process' memory may change as soon as the probe
+    // returns, so they are read here instead of by ingraind
+    read_arg(&mut current.event, 0);
+    read_arg(&mut current.event, 1);
+    read_arg(&mut current.event, 2);
+    read_arg(&mut current.event, 3);
+    read_arg(&mut current.event, 4);
+    read_arg(&mut current.event, 5);
+    read_arg(&mut current.event, 6);
+    read_arg(&mut current.event, 7);
+
+    unsafe { usdt_events.insert(regs.ctx, current) };
+}
+
+#[inline(always)]
+fn read_arg(current: &mut UsdtEvent, i: u8) {
+    let spec = match unsafe { args.get(&i) } {
+        Some(spec) => *spec,
+        None => return,
+    };
+
+    let base = match spec.reg {
+        NO_REG => 0,
+        reg => match current.regs.get(reg as usize) {
+            Some(base) => *base,
+            None => return,
+        },
+    };
+    let mut value = (base as i64).wrapping_add(spec.disp) as u64;
+
+    if spec.kind & ARG_MEMORY != 0 {
+        // the size is applied in user space
+        value = unsafe { bpf_probe_read(value as *const u64) }.unwrap_or(0);
+        if let Some(read) = current.values.get_mut(i as usize) {
+            *read = value;
+        }
+    }
+
+    if spec.kind & ARG_STRING != 0 {
+        if let Some(string) = current.strings.get_mut(spec.slot as usize) {
+            let read = unsafe {
+                bindings::bpf_probe_read_str(
+                    string.as_mut_ptr() as *mut _,
+                    STRING_LEN as i32,
+                    value as *const _,
+                )
+            };
+            if read <= 0 {
+                string[0] = 0;
+            }
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use cty::*;
+
+/// The number of registers in `struct pt_regs` on x86_64
+pub const PT_REGS_LEN: usize = 21;
+
+/// The arguments that can be read from memory when the probe fires
+pub const MAX_ARGS: usize = 8;
+pub const MAX_STRING_ARGS: usize = 2;
+pub const STRING_LEN: usize = 128;
+
+/// Reads `len` bytes at the address
+pub const ARG_MEMORY: u8 = 1;
+/// Reads the NUL terminated string at the address, or at the pointer read
+/// with `ARG_MEMORY`
+pub const ARG_STRING: u8 = 2;
+
+/// The base register of constant addresses
+pub const NO_REG: u8 = 0xFF;
+
+/// How the probe reads an argument, set by ingraind for the arguments that
+/// point to memory
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct ArgSpec {
+    pub kind: u8,
+    /// The index in `regs` of the register the address is relative to
+    pub reg: u8,
+    /// The index in `strings` of a string argument
+    pub slot: u8,
+    pub disp: i64,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct UsdtEvent {
+    pub pid: u32,
+    pub comm: [c_char; 16],
+    /// The arguments are decoded in user space based on the argument
+    /// specification in the probe's note
+    pub regs: [u64; PT_REGS_LEN],
+    /// The arguments read from memory when the probe fired, by index
+    pub values: [u64; MAX_ARGS],
+    pub strings: [[c_char; STRING_LEN]; MAX_STRING_ARGS],
+}
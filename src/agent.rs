@@ -0,0 +1,444 @@
+//! Runs the probes and pipelines of a configuration.
+//!
+//! The daemon is a thin wrapper around `Agent`, which other applications can
+//! use to embed ingraind:
+//!
+//! ```no_run
+//! use ingraind::agent::Agent;
+//! use ingraind::config::{Backend, Grain};
+//!
+//! Agent::builder()
+//!     .grain(Grain::UDP, &["console"])
+//!     .backend("console", Backend::Console)
+//!     .run()
+//!     .unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::{Actor, Arbiter, Recipient, System};
+use failure::Error;
+
+use crate::aggregations::Rename;
+use crate::backends::{batch::Batcher, dedup::Dedup, proxy, Message};
+use crate::config::{Aggregator, Backend, Config, Grain, Pipeline, Probe};
+use crate::containers;
+use crate::control::Control;
+use crate::grains::workers::{Workers, WorkersConfig};
+use crate::grains::{prebuilt, stats, DecodeErrorSink};
+use crate::memlock;
+use crate::metrics::clock::ClockWatch;
+use crate::privileges::DropPrivileges;
+use crate::record::{Replay, ReplayProbe};
+use crate::shedding::LoadShedder;
+use crate::shutdown::Shutdown;
+use crate::telemetry::{Telemetry, TelemetryConfig};
+use crate::tenancy::{Tenancy, TenancyConfig, Tenants};
+
+pub struct Agent {
+    config: Config,
+    recipients: HashMap<String, Recipient<Message>>,
+}
+
+#[derive(Default)]
+pub struct AgentBuilder {
+    config: Config,
+    recipients: HashMap<String, Recipient<Message>>,
+}
+
+impl Agent {
+    pub fn builder() -> AgentBuilder {
+        AgentBuilder::default()
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Agent {
+            config,
+            recipients: HashMap::new(),
+        }
+    }
+
+    /// Starts the probes and pipelines in the current actix system.
+    ///
+    /// The system is stopped on SIGINT or SIGTERM, once the pipelines are
+    /// flushed.
+    pub fn start(self) {
+        let mut config = self.config;
+        let io = Arbiter::new();
+
+        if let Some(ref prebuilt) = config.prebuilt_probes {
+            prebuilt::load(prebuilt).expect("Invalid prebuilt probes");
+        }
+        memlock::preflight(&config.memlock, &config.probe)
+            .unwrap_or_else(|e| panic!("Unable to load the grains: {}", e));
+
+        #[cfg(feature = "admin-endpoint")]
+        {
+            if let Some(admin) = config.admin.take() {
+                use crate::admin::Admin;
+
+                let effective = serde_json::to_value(&config).unwrap();
+                Actor::start_in_arbiter(&io, |_| Admin::with_config(admin, effective));
+            }
+        }
+
+        proxy::set_default(config.proxy.clone());
+        let mut backends = start_pipelines(&mut config);
+        backends.extend(self.recipients);
+
+        if let Some(clock) = config.clock.take() {
+            let recipients = recipients(&backends, &clock.pipelines);
+            ClockWatch::with_config(clock, recipients).start();
+        }
+
+        let tenancy = tenancy(config.tenancy.take(), &backends);
+        let workers = config.workers.as_ref().map(Workers::start);
+        stats::expect_required(
+            config
+                .probe
+                .iter()
+                .filter(|p| p.required && p.grain.code().is_some())
+                .count(),
+        );
+        let probe_actors: Vec<_> = config
+            .probe
+            .drain(..)
+            .map(|probe| {
+                probe
+                    .check_limits(probe.grain.code())
+                    .unwrap_or_else(|e| panic!("Invalid limits: {}", e));
+                probe
+                    .metas()
+                    .and_then(|metas| probe.check_kernel(&metas))
+                    .unwrap_or_else(|e| panic!("Unsupported kernel: {}", e));
+                let policy = probe.attach_policy();
+                let reorder = probe.reorder_horizon();
+                let limiter = probe.limits.as_ref().and_then(|l| l.rate_limiter());
+                let decode_errors = probe.decode_errors.map(|config| DecodeErrorSink {
+                    recipients: recipients(&backends, &config.pipelines),
+                    sample_every: config.sample_every,
+                });
+                let mut actor = probe
+                    .grain
+                    .into_probe_actor(probe_recipients(&tenancy, &backends, &probe.pipelines))
+                    .with_policy(policy);
+                if let Some(horizon) = reorder {
+                    actor = actor.with_reorder(horizon);
+                }
+                if let Some(sink) = decode_errors {
+                    actor = actor.with_decode_errors(sink);
+                }
+                if let Some(limiter) = limiter {
+                    actor = actor.with_limiter(limiter);
+                }
+                actor
+            })
+            .map(|actor| match workers {
+                Some(ref workers) => actor.with_workers(workers),
+                None => actor,
+            })
+            .collect();
+
+        for actor in probe_actors {
+            actor.start(&io);
+        }
+
+        if let Some(control) = config.control.take() {
+            Control::with_config(control).start();
+        }
+
+        if config.privileges.user.is_some() {
+            let privileges = config.privileges.clone();
+            Actor::start_in_arbiter(&io, |_| DropPrivileges::with_config(privileges));
+        }
+
+        if let Some(telemetry) = config.telemetry.take() {
+            let recipients = recipients(&backends, &telemetry.pipelines);
+            Telemetry::with_config(telemetry, recipients).start();
+        }
+
+        #[cfg(feature = "kubernetes")]
+        {
+            if let Some(ref kubernetes) = config.kubernetes {
+                crate::kubernetes::watch(kubernetes);
+            }
+        }
+
+        if let Some(containers) = config.containers.take() {
+            containers::watch(containers);
+        }
+
+        if let Some(load_shedding) = config.load_shedding.take() {
+            LoadShedder::with_config(load_shedding).start();
+        }
+
+        let timeout = Duration::from_secs(config.shutdown_timeout_s);
+        Shutdown::new(io, backends.values().cloned().collect(), timeout).start();
+    }
+
+    /// Runs the agent in a new actix system, and blocks until it stops
+    pub fn run(self) -> Result<(), Error> {
+        let system = System::new("ingraind");
+        self.start();
+        system.run()?;
+
+        Ok(())
+    }
+
+    /// Feeds the events recorded to `path` through the probes and pipelines
+    /// in the current actix system, which is stopped once they're delivered.
+    ///
+    /// Measurements aren't labelled with tenants, since the processes they
+    /// came from ran on another host, or at another time.
+    pub fn replay(self, path: &str) {
+        let mut config = self.config;
+        let mut backends = start_pipelines(&mut config);
+        backends.extend(self.recipients);
+
+        let probes = config
+            .probe
+            .drain(..)
+            .filter_map(|probe| {
+                let grain = probe.grain.name();
+                let recipients = probe_recipients(&None, &backends, &probe.pipelines);
+                probe.grain.into_handlers().map(|handlers| ReplayProbe {
+                    grain,
+                    handlers,
+                    recipients,
+                })
+            })
+            .collect();
+        let pipelines = backends.values().cloned().collect();
+        let timeout = Duration::from_secs(config.shutdown_timeout_s);
+        Replay::new(path.to_string(), probes, pipelines, timeout).start();
+    }
+}
+
+impl AgentBuilder {
+    /// Sends the measurements of `grain` to the named pipelines. The agent
+    /// stops if the grain's programs fail to attach.
+    pub fn grain(mut self, grain: Grain, pipelines: &[&str]) -> Self {
+        self.config.probe.push(Probe {
+            pipelines: pipelines.iter().map(|p| p.to_string()).collect(),
+            grain,
+            required: true,
+            retry_interval_s: 0,
+            reorder_ms: None,
+            decode_errors: None,
+            limits: None,
+        });
+        self
+    }
+
+    /// Like `grain`, but programs that fail to attach are skipped, and
+    /// retried every `retry_interval`, in whole seconds and at least every
+    /// second
+    pub fn optional_grain(
+        mut self,
+        grain: Grain,
+        pipelines: &[&str],
+        retry_interval: Duration,
+    ) -> Self {
+        self.config.probe.push(Probe {
+            pipelines: pipelines.iter().map(|p| p.to_string()).collect(),
+            grain,
+            required: false,
+            retry_interval_s: retry_interval.as_secs().max(1),
+            reorder_ms: None,
+            decode_errors: None,
+            limits: None,
+        });
+        self
+    }
+
+    /// Adds a pipeline that sends measurements to `backend` as they are
+    pub fn backend(self, name: &str, backend: Backend) -> Self {
+        self.pipeline(name, backend, vec![])
+    }
+
+    /// Adds a pipeline that runs measurements through `steps` before
+    /// sending them to `backend`
+    pub fn pipeline(mut self, name: &str, backend: Backend, steps: Vec<Aggregator>) -> Self {
+        let steps = if steps.is_empty() { None } else { Some(steps) };
+        self.config.pipeline.insert(
+            name.to_string(),
+            Pipeline {
+                backend,
+                steps,
+                wal: None,
+                flush: None,
+                dedup: None,
+                supervisor: Default::default(),
+                namespace: None,
+                name_template: None,
+            },
+        );
+        self
+    }
+
+    /// Adds a pipeline that sends measurements to an actor of the
+    /// application. It has to be started in the actix system the agent runs
+    /// in.
+    pub fn recipient(mut self, name: &str, recipient: Recipient<Message>) -> Self {
+        self.recipients.insert(name.to_string(), recipient);
+        self
+    }
+
+    /// Reads the perf buffers of eBPF probes on a pool of threads
+    pub fn workers(mut self, workers: WorkersConfig) -> Self {
+        self.config.workers = Some(workers);
+        self
+    }
+
+    /// Reports the agent's own statistics
+    pub fn telemetry(mut self, telemetry: TelemetryConfig) -> Self {
+        self.config.telemetry = Some(telemetry);
+        self
+    }
+
+    /// How long to wait for backends to deliver what they hold on shutdown
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout_s = timeout.as_secs();
+        self
+    }
+
+    /// Validates the configuration, without loading anything
+    pub fn build(self) -> Result<Agent, Error> {
+        let recipients = self
+            .recipients
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        self.config.check_with(&recipients)?;
+
+        Ok(Agent {
+            config: self.config,
+            recipients: self.recipients,
+        })
+    }
+
+    pub fn run(self) -> Result<(), Error> {
+        self.build()?.run()
+    }
+}
+
+/// Starts every step and backend of the pipelines in `config`, and returns
+/// the entry point of each
+fn start_pipelines(config: &mut Config) -> HashMap<String, Recipient<Message>> {
+    let pipelines: Vec<_> = config.pipeline.drain().collect();
+    pipelines
+        .into_iter()
+        .map(|(key, pipeline)| {
+            let template = config
+                .name_template(&pipeline)
+                .unwrap_or_else(|e| panic!("pipeline {}: {}", key, e));
+            let (mut backend, deliver) = match pipeline.wal {
+                Some(wal) => (
+                    pipeline
+                        .backend
+                        .into_wal_recipient(&key, wal, pipeline.dedup, pipeline.supervisor)
+                        .unwrap_or_else(|e| panic!("pipeline {}: {}", key, e)),
+                    None,
+                ),
+                None => {
+                    let (backend, deliver) =
+                        pipeline.backend.into_recipients(&key, pipeline.supervisor);
+                    match pipeline.dedup {
+                        Some(dedup) => Dedup::launch(dedup, backend, deliver)
+                            .unwrap_or_else(|e| panic!("pipeline {}: {}", key, e)),
+                        None => (backend, deliver),
+                    }
+                }
+            };
+            if let Some(flush) = pipeline.flush {
+                backend = Batcher::launch(flush, &key, backend, deliver);
+            }
+            if let Some(template) = template {
+                backend = Rename::launch(template, backend);
+            }
+            let mut steps = pipeline.steps.unwrap_or_else(|| vec![]);
+
+            // pod details are added before the first Redact step, so
+            // they can be redacted, too
+            let first_redact = steps.iter().position(|step| match step {
+                Aggregator::Redact(_) => true,
+                _ => false,
+            });
+            if first_redact.is_none() {
+                backend = add_pod_details(&config, backend);
+            }
+
+            for (i, step) in steps.drain(..).enumerate().rev() {
+                backend = step.into_recipient(backend);
+                if first_redact == Some(i) {
+                    backend = add_pod_details(&config, backend);
+                }
+            }
+
+            (key, backend)
+        })
+        .collect()
+}
+
+#[cfg(feature = "kubernetes")]
+fn add_pod_details(config: &Config, backend: Recipient<Message>) -> Recipient<Message> {
+    use crate::kubernetes::AddKubernetesDetails;
+
+    match config.kubernetes {
+        Some(ref kubernetes) => AddKubernetesDetails::launch(kubernetes, backend),
+        None => backend,
+    }
+}
+
+#[cfg(not(feature = "kubernetes"))]
+fn add_pod_details(_config: &Config, backend: Recipient<Message>) -> Recipient<Message> {
+    backend
+}
+
+fn recipients(
+    backends: &HashMap<String, Recipient<Message>>,
+    pipelines: &[String],
+) -> Vec<Recipient<Message>> {
+    pipelines
+        .iter()
+        .map(|p| {
+            backends
+                .get(p)
+                .unwrap_or_else(|| panic!("Invalid configuration: pipeline {} not found!", p))
+                .clone()
+        })
+        .collect()
+}
+
+/// The tenants of measurements, and the pipelines of the tenants that set
+/// them
+type Routes = (Arc<Tenants>, Arc<HashMap<String, Vec<Recipient<Message>>>>);
+
+fn tenancy(
+    config: Option<TenancyConfig>,
+    backends: &HashMap<String, Recipient<Message>>,
+) -> Option<Routes> {
+    let tenants = Tenants::new(&config?).expect("Invalid tenancy");
+    let routes = tenants
+        .pipelines()
+        .iter()
+        .map(|(tenant, pipelines)| (tenant.clone(), recipients(backends, pipelines)))
+        .collect();
+
+    Some((Arc::new(tenants), Arc::new(routes)))
+}
+
+/// The pipelines of a probe, behind the tenancy layer if there is one
+fn probe_recipients(
+    tenancy: &Option<Routes>,
+    backends: &HashMap<String, Recipient<Message>>,
+    pipelines: &[String],
+) -> Vec<Recipient<Message>> {
+    let upstream = recipients(backends, pipelines);
+    match tenancy {
+        Some((tenants, routes)) => vec![Tenancy::launch(tenants.clone(), routes.clone(), upstream)],
+        None => upstream,
+    }
+}
@@ -10,7 +10,7 @@ use hdrhistogram::Histogram;
 use rayon::prelude::*;
 
 use crate::backends::Message;
-use crate::metrics::{kind, Measurement, Tags, Unit, UnitType};
+use crate::metrics::{kind, Deltas, Measurement, Tags, Unit, UnitType};
 
 const PERCENTILES: [f64; 6] = [25f64, 50f64, 75f64, 90f64, 95f64, 99f64];
 
@@ -34,6 +34,7 @@ pub struct Aggregator {
     timers: HashMap<MeasurementKey, AggregatedMetric<Vec<f64>>>,
     sets: HashMap<MeasurementKey, AggregatedMetric<HashSet<String>>>,
     histograms: HashMap<MeasurementKey, AggregatedMetric<Histogram<u64>>>,
+    deltas: Deltas,
 
     enable_histograms: bool,
 }
@@ -46,13 +47,17 @@ impl Aggregator {
             timers: HashMap::new(),
             sets: HashMap::new(),
             histograms: HashMap::new(),
+            deltas: Deltas::new(),
 
             enable_histograms,
         }
     }
 
     pub fn record<T: Into<Measurement>>(&mut self, measurement: T) {
-        let measurement = measurement.into();
+        let measurement = match self.deltas.apply(measurement.into()) {
+            Some(m) => m,
+            None => return,
+        };
         let key = measurement_key(&measurement);
         let Measurement {
             kind,
@@ -65,7 +70,7 @@ impl Aggregator {
 
         let v = match kind {
             x if (x & kind::COUNTER > 0) | (x & kind::GAUGE > 0) | (x & kind::TIMER > 0) => {
-                value.get() as f64
+                value.get_f64()
             }
             _ => 0f64,
         };
@@ -129,7 +134,14 @@ impl Aggregator {
                     value: Histogram::new(3).unwrap(),
                     tags,
                 });
-            am.value.saturating_record(value.get());
+            match value {
+                Unit::Histogram(ref buckets) => {
+                    for (bound, count) in buckets.iter() {
+                        am.value.saturating_record_n(bound, count);
+                    }
+                }
+                _ => am.value.saturating_record(value.get()),
+            }
         }
     }
 
@@ -146,36 +158,38 @@ impl Aggregator {
             + self.sets.len()
             + self.histograms.len();
         let mut metrics = Vec::with_capacity(capacity);
-        metrics.par_extend(self.counters.par_iter().map(|(k, v)| {
-            Measurement::new(
+        // measurements of units that aren't numbers can't be aggregated
+        metrics.par_extend(self.counters.par_iter().filter_map(|(k, v)| {
+            Some(Measurement::new(
                 kind::COUNTER,
                 k.name.clone(),
-                v.unit.to_unit(v.value as u64),
+                v.unit.to_unit_f64(v.value).ok()?,
                 v.tags.clone(),
-            )
+            ))
         }));
         self.counters.clear();
 
-        metrics.par_extend(self.gauges.par_iter().map(|(k, v)| {
-            Measurement::new(
+        metrics.par_extend(self.gauges.par_iter().filter_map(|(k, v)| {
+            Some(Measurement::new(
                 kind::GAUGE,
                 k.name.clone(),
-                v.unit.to_unit(v.value as u64),
+                v.unit.to_unit_f64(v.value).ok()?,
                 v.tags.clone(),
-            )
+            ))
         }));
         self.gauges.clear();
 
         metrics.par_extend(self.timers.par_iter().flat_map(|(k, v)| {
             let k = k.clone();
+            let unit = v.unit;
             let tags = v.tags.clone();
-            v.value.par_iter().map(move |t| {
-                Measurement::new(
+            v.value.par_iter().filter_map(move |t| {
+                Some(Measurement::new(
                     kind::TIMER,
                     k.name.clone(),
-                    Unit::Count(*t as u64),
+                    unit.to_unit_f64(*t).ok()?,
                     tags.clone(),
-                )
+                ))
             })
         }));
         self.timers.clear();
@@ -291,6 +305,10 @@ impl Handler<Message> for Buffer {
                 }
             }
             Message::Single(m) => self.aggregator.record(m),
+            Message::Flush => {
+                self.flush(ctx);
+                self.upstream.do_send(Message::Flush).unwrap();
+            }
         }
     }
 }
@@ -380,11 +398,10 @@ mod tests {
         assert_eq!(a.gauge(&foo).unwrap().value, 2f64);
         a.record(metric("foo:+3|g"));
         assert_eq!(a.gauge(&foo).unwrap().value, 5f64);
-        /*
-        FIXME: re-enable after switching everything to f64
-        a.record(metric("foo:-5|g"));
-        assert_eq!(a.gauge("foo").unwrap().value, -1f64);
-        */
+        a.record(metric("foo:-6|g"));
+        assert_eq!(a.gauge(&foo).unwrap().value, -1f64);
+        a.record(metric("foo:0.25|g"));
+        assert_eq!(a.gauge(&foo).unwrap().value, 0.25f64);
     }
 
     #[test]
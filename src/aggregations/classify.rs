@@ -0,0 +1,217 @@
+//! Tags measurements with a severity, and the MITRE ATT&CK technique they
+//! are evidence of.
+//!
+//! Rules match measurements by name, and by regular expressions on the
+//! values of their tags. The first matching rule adds its `severity`, and
+//! if set, its technique as `attack_technique` and its tactic as
+//! `attack_tactic`. Measurements no rule matches are passed on as they are.
+//!
+//! The rules of the step are checked first, then the bundled ruleset in
+//! `rules/attack.toml`, or the one at `ruleset` when it's set.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+use actix::prelude::*;
+use failure::{format_err, Error};
+use rayon::prelude::*;
+use regex::Regex as RegexMatcher;
+
+use crate::backends::Message;
+use crate::metrics::Measurement;
+
+const BUNDLED_RULESET: &str = include_str!("../../rules/attack.toml");
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClassifyConfig {
+    #[serde(default)]
+    pub rules: Vec<ClassificationRule>,
+    /// The path of a ruleset that replaces the bundled one
+    pub ruleset: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClassificationRule {
+    pub measurement: String,
+    /// Regular expressions the values of these tags must match
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    pub severity: Severity,
+    pub technique: Option<String>,
+    pub tactic: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Ruleset {
+    rule: Vec<ClassificationRule>,
+}
+
+struct Rule {
+    measurement: String,
+    tags: Vec<(String, RegexMatcher)>,
+    severity: Severity,
+    technique: Option<String>,
+    tactic: Option<String>,
+}
+
+impl Rule {
+    fn new(rule: ClassificationRule) -> Result<Rule, Error> {
+        let tags = rule
+            .tags
+            .into_iter()
+            .map(|(key, regex)| {
+                let matcher = RegexMatcher::new(&regex)
+                    .map_err(|e| format_err!("Invalid pattern for {}: {}", key, e))?;
+                Ok((key, matcher))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Rule {
+            measurement: rule.measurement,
+            tags,
+            severity: rule.severity,
+            technique: rule.technique,
+            tactic: rule.tactic,
+        })
+    }
+
+    fn matches(&self, m: &Measurement) -> bool {
+        m.name == self.measurement
+            && self.tags.iter().all(|(key, regex)| match m.tags.get(key) {
+                Some(value) => regex.is_match(value),
+                None => false,
+            })
+    }
+}
+
+fn load_rules(config: ClassifyConfig) -> Result<Vec<Rule>, Error> {
+    let ruleset = match config.ruleset {
+        Some(ref path) => fs::read_to_string(path)
+            .map_err(|e| format_err!("Failed to read ruleset {}: {}", path, e))?,
+        None => BUNDLED_RULESET.to_string(),
+    };
+    let ruleset: Ruleset = toml::from_str(&ruleset)?;
+
+    config
+        .rules
+        .into_iter()
+        .chain(ruleset.rule.into_iter())
+        .map(Rule::new)
+        .collect()
+}
+
+fn classify(m: &mut Measurement, rules: &[Rule]) {
+    let rule = match rules.iter().find(|rule| rule.matches(m)) {
+        Some(rule) => rule,
+        None => return,
+    };
+
+    m.tags.insert("severity", rule.severity.as_str());
+    if let Some(ref technique) = rule.technique {
+        m.tags.insert("attack_technique", technique);
+    }
+    if let Some(ref tactic) = rule.tactic {
+        m.tags.insert("attack_tactic", tactic);
+    }
+}
+
+pub struct Classify(Arc<Vec<Rule>>, Recipient<Message>);
+
+impl Classify {
+    pub fn launch(config: ClassifyConfig, upstream: Recipient<Message>) -> Recipient<Message> {
+        let rules = load_rules(config).expect("Invalid classification rules");
+
+        Classify(Arc::new(rules), upstream).start().recipient()
+    }
+}
+
+impl Actor for Classify {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for Classify {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let rules = self.0.clone();
+        match msg {
+            Message::List(ref mut ms) => ms.par_iter_mut().for_each(|m| classify(m, &rules)),
+            Message::Single(ref mut m) => classify(m, &rules),
+            Message::Flush => {}
+        }
+
+        self.1.do_send(msg).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::kind::COUNTER;
+    use crate::metrics::{Tags, Unit};
+
+    fn measurement(name: &str, tags: &[(&str, &str)]) -> Measurement {
+        let mut t = Tags::new();
+        for (k, v) in tags {
+            t.insert(*k, *v);
+        }
+        Measurement::new(COUNTER, name.to_string(), Unit::Count(1), t)
+    }
+
+    #[test]
+    fn bundled_ruleset() {
+        let config: ClassifyConfig = toml::from_str(
+            r#"
+[[rules]]
+measurement = "exec"
+severity = "low"
+[rules.tags]
+path = "^/tmp/"
+"#,
+        )
+        .unwrap();
+        let rules = load_rules(config).unwrap();
+
+        let mut root = measurement("creds.escalation", &[("new_euid", "0")]);
+        classify(&mut root, &rules);
+        assert_eq!(root.tags.get("severity"), Some("high"));
+        assert_eq!(root.tags.get("attack_technique"), Some("T1548"));
+        assert_eq!(root.tags.get("attack_tactic"), Some("privilege-escalation"));
+
+        let mut user = measurement("creds.escalation", &[("new_euid", "1000")]);
+        classify(&mut user, &rules);
+        assert_eq!(user.tags.get("severity"), Some("medium"));
+
+        let mut exec = measurement("exec", &[("path", "/tmp/x")]);
+        classify(&mut exec, &rules);
+        assert_eq!(exec.tags.get("severity"), Some("low"));
+        assert_eq!(exec.tags.get("attack_technique"), None);
+
+        let mut other = measurement("exec", &[("path", "/bin/sh")]);
+        classify(&mut other, &rules);
+        assert_eq!(other.tags.len(), 1);
+    }
+}
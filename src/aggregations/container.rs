@@ -8,12 +8,16 @@ use rayon::prelude::*;
 use regex::Regex;
 
 use crate::backends::Message;
+use crate::containers;
+use crate::grains::netns;
 use crate::metrics::Measurement;
 
 lazy_static! {
     // this pattern actually matches the Docker id from both
-    // Kubernetes and Docker-created containers
-    static ref DOCKER_PATTERN: Regex = Regex::new(r#"(?m):/.*/([a-z0-9]{64})$"#).unwrap();
+    // Kubernetes and Docker-created containers, and the scopes systemd
+    // creates for Docker and containerd
+    static ref DOCKER_PATTERN: Regex =
+        Regex::new(r#"(?m):/.*/(?:docker-|cri-containerd-)?([a-z0-9]{64})(?:\.scope)?$"#).unwrap();
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,6 +36,14 @@ impl Container {
 
 fn add_tags(msg: &mut Measurement) {
     if let Ok(cid) = get_docker_container_id(&DOCKER_PATTERN, msg) {
+        if let Some(container) = containers::lookup(&cid) {
+            if let Some(ref name) = container.name {
+                msg.tags.insert("container_name", name);
+            }
+            if let Some(ref image) = container.image {
+                msg.tags.insert("container_image", image);
+            }
+        }
         msg.tags.insert("docker_id", cid);
     }
 }
@@ -43,6 +55,7 @@ impl Handler<Message> for Container {
         match msg {
             Message::List(ref mut ms) => ms.par_iter_mut().for_each(move |m| add_tags(m)),
             Message::Single(ref mut m) => add_tags(m),
+            Message::Flush => {}
         }
 
         self.1.do_send(msg).unwrap();
@@ -51,12 +64,38 @@ impl Handler<Message> for Container {
 
 #[inline]
 fn get_docker_container_id(regex: &Regex, msg: &Measurement) -> Result<String, Error> {
+    match get_pid(msg).and_then(|pid| container_id_for_pid(regex, pid)) {
+        Ok(cid) => Ok(cid),
+        // events captured outside of the process context can still be
+        // attributed through their network namespace
+        Err(e) => match msg.tags.get("netns") {
+            Some(netns) => {
+                let pid = netns::pid_for_netns(u32::from_str(netns)?)
+                    .ok_or_else(|| format_err!("No process in netns"))?;
+                container_id_for_pid(regex, pid)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+#[inline]
+fn get_pid(msg: &Measurement) -> Result<u32, Error> {
     let pid_str = msg
         .tags
         .get("process_id")
         .ok_or_else(|| format_err!("No pid"))?;
-    let pid = u32::from_str(&pid_str)?;
 
+    Ok(u32::from_str(&pid_str)?)
+}
+
+/// The Docker container `pid` runs in, if any
+pub fn docker_id(pid: u32) -> Option<String> {
+    container_id_for_pid(&DOCKER_PATTERN, pid).ok()
+}
+
+#[inline]
+fn container_id_for_pid(regex: &Regex, pid: u32) -> Result<String, Error> {
     let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid))
         .or_else(|_| fs::read_to_string(format!("/proc/{}/cgroup", pid)))?;
 
@@ -127,6 +166,23 @@ mod test {
 2:rdma:/
 1:name=systemd:/kubepods/besteffort/poda21e738c-d6b6-11e8-82df-002590deaca4/a844b8599d5e23c620c646b69c6d93c4014247cd0be9ec142c44219b6467e07f
 
+"#;
+
+        assert_eq!(
+            container_id(&DOCKER_PATTERN, cgroup),
+            Some("a844b8599d5e23c620c646b69c6d93c4014247cd0be9ec142c44219b6467e07f".to_string())
+        );
+    }
+
+    #[test]
+    fn regex_can_match_systemd_scope() {
+        use crate::aggregations::container::container_id;
+        use crate::aggregations::container::DOCKER_PATTERN;
+
+        let cgroup = r#"
+4:pids:/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-poda21e738c.slice/cri-containerd-a844b8599d5e23c620c646b69c6d93c4014247cd0be9ec142c44219b6467e07f.scope
+1:name=systemd:/system.slice/docker-a844b8599d5e23c620c646b69c6d93c4014247cd0be9ec142c44219b6467e07f.scope
+0::/system.slice/containerd.service
 "#;
 
         assert_eq!(
@@ -0,0 +1,324 @@
+//! Joins measurements of different grains through the tags they share.
+//!
+//! Each rule records the `tags` of `source` measurements by the value of
+//! their `key` tag, and adds them to the `target` measurements that follow
+//! within `ttl_s` and whose `on` tag has the same value. For example, the
+//! name a process resolved before it connected to an address:
+//!
+//! ```text
+//! [[pipeline.console.steps]]
+//! type = "Correlate"
+//!
+//! [[pipeline.console.steps.rules]]
+//! source = "dns.answer_address"
+//! key = "id"
+//! tags = ["q_address_str"]
+//! target = "dns.answer_record"
+//! on = "id"
+//!
+//! [[pipeline.console.steps.rules]]
+//! source = "dns.answer_record"
+//! key = "address"
+//! tags = ["q_address_str"]
+//! target = "connection.out"
+//! on = "d_ip"
+//! prefix = "dns_"
+//! ```
+//!
+//! Rules are applied in order, so a measurement enriched by one rule can be
+//! the source of the next. Target measurements are enriched in place, unless
+//! the rule has an `emit` name, in which case the enriched copy is sent
+//! along with the original.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+
+use crate::backends::Message;
+use crate::metrics::{Measurement, TagStr, TagValue};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CorrelateConfig {
+    pub rules: Vec<CorrelationRule>,
+    /// How long the tags of a source measurement are kept
+    #[serde(default = "default_ttl_s")]
+    pub ttl_s: u64,
+    /// The most keys kept for each rule
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CorrelationRule {
+    pub source: String,
+    pub key: String,
+    pub tags: Vec<String>,
+    pub target: String,
+    pub on: String,
+    /// Prepended to the names of the tags added to targets
+    #[serde(default)]
+    pub prefix: String,
+    /// The name of the enriched copies of targets, if any
+    pub emit: Option<String>,
+}
+
+fn default_ttl_s() -> u64 {
+    60
+}
+
+fn default_max_entries() -> usize {
+    100_000
+}
+
+struct Entry {
+    tags: Vec<(TagStr, TagValue)>,
+    recorded: Instant,
+}
+
+struct Correlator {
+    rules: Vec<CorrelationRule>,
+    state: Vec<HashMap<String, Entry>>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl Correlator {
+    fn new(config: CorrelateConfig) -> Self {
+        Correlator {
+            state: config.rules.iter().map(|_| HashMap::new()).collect(),
+            rules: config.rules,
+            ttl: Duration::from_secs(config.ttl_s),
+            max_entries: config.max_entries,
+        }
+    }
+
+    /// Runs `m` through the rules, pushing it and any composite measurement
+    /// to `out`
+    fn process(&mut self, mut m: Measurement, now: Instant, out: &mut Vec<Measurement>) {
+        let ttl = self.ttl;
+        let max_entries = self.max_entries;
+        for (rule, state) in self.rules.iter().zip(self.state.iter_mut()) {
+            if m.name == rule.target {
+                let entry = m
+                    .tags
+                    .get(&rule.on)
+                    .and_then(|on| state.get(on))
+                    .filter(|entry| now.duration_since(entry.recorded) < ttl);
+
+                if let Some(entry) = entry {
+                    let joined = match rule.emit {
+                        Some(ref name) => {
+                            let mut composite = m.clone();
+                            composite.name = name.clone();
+                            out.push(composite);
+                            out.last_mut().unwrap()
+                        }
+                        None => &mut m,
+                    };
+                    for (k, v) in entry.tags.iter() {
+                        joined
+                            .tags
+                            .insert(format!("{}{}", rule.prefix, k), v.clone());
+                    }
+                }
+            }
+
+            if m.name == rule.source {
+                let key = match m.tags.get(&rule.key) {
+                    Some(key) => key.to_string(),
+                    None => continue,
+                };
+                if state.len() >= max_entries && !state.contains_key(&key) {
+                    continue;
+                }
+
+                let tags = m
+                    .tags
+                    .iter()
+                    .filter(|(k, _)| rule.tags.iter().any(|t| t == k.as_str()))
+                    .cloned()
+                    .collect();
+                state.insert(
+                    key,
+                    Entry {
+                        tags,
+                        recorded: now,
+                    },
+                );
+            }
+        }
+
+        out.push(m);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        for state in self.state.iter_mut() {
+            state.retain(|_, entry| now.duration_since(entry.recorded) < ttl);
+        }
+    }
+}
+
+pub struct Correlate {
+    correlator: Correlator,
+    upstream: Recipient<Message>,
+}
+
+impl Correlate {
+    pub fn launch(config: CorrelateConfig, upstream: Recipient<Message>) -> Recipient<Message> {
+        Correlate {
+            correlator: Correlator::new(config),
+            upstream,
+        }
+        .start()
+        .recipient()
+    }
+}
+
+impl Actor for Correlate {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.correlator.ttl, |act, _ctx| {
+            act.correlator.expire(Instant::now())
+        });
+    }
+}
+
+impl Handler<Message> for Correlate {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = Instant::now();
+        let mut out = vec![];
+        let msg = match msg {
+            Message::List(ms) => {
+                for m in ms {
+                    self.correlator.process(m, now, &mut out);
+                }
+                Message::List(out)
+            }
+            Message::Single(m) => {
+                self.correlator.process(m, now, &mut out);
+                if out.len() == 1 {
+                    Message::Single(out.pop().unwrap())
+                } else {
+                    Message::List(out)
+                }
+            }
+            Message::Flush => Message::Flush,
+        };
+
+        self.upstream.do_send(msg).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::kind::COUNTER;
+    use crate::metrics::{Tags, Unit};
+
+    fn measurement(name: &str, tags: &[(&str, &str)]) -> Measurement {
+        let mut t = Tags::new();
+        for (k, v) in tags {
+            t.insert(k.to_string(), v.to_string());
+        }
+        Measurement::new(COUNTER, name.to_string(), Unit::Count(1), t)
+    }
+
+    fn rule(source: &str, key: &str, tag: &str, target: &str, on: &str) -> CorrelationRule {
+        CorrelationRule {
+            source: source.to_string(),
+            key: key.to_string(),
+            tags: vec![tag.to_string()],
+            target: target.to_string(),
+            on: on.to_string(),
+            prefix: String::new(),
+            emit: None,
+        }
+    }
+
+    #[test]
+    fn join_dns_to_connection() {
+        let mut connection_rule = rule(
+            "dns.answer_record",
+            "address",
+            "q_address_str",
+            "connection.out",
+            "d_ip",
+        );
+        connection_rule.prefix = "dns_".to_string();
+        let mut correlator = Correlator::new(CorrelateConfig {
+            rules: vec![
+                rule(
+                    "dns.answer_address",
+                    "id",
+                    "q_address_str",
+                    "dns.answer_record",
+                    "id",
+                ),
+                connection_rule,
+            ],
+            ttl_s: 60,
+            max_entries: 10,
+        });
+
+        let now = Instant::now();
+        let mut out = vec![];
+        for m in vec![
+            measurement(
+                "dns.answer_address",
+                &[("id", "1"), ("q_address_str", "example.com")],
+            ),
+            measurement("dns.answer_record", &[("id", "1"), ("address", "1.2.3.4")]),
+            measurement("connection.out", &[("d_ip", "1.2.3.4")]),
+            measurement("connection.out", &[("d_ip", "4.3.2.1")]),
+        ] {
+            correlator.process(m, now, &mut out);
+        }
+
+        assert_eq!(out[1].tags.get("q_address_str"), Some("example.com"));
+        assert_eq!(out[2].tags.get("dns_q_address_str"), Some("example.com"));
+        assert_eq!(out[3].tags.get("dns_q_address_str"), None);
+
+        correlator.expire(now + Duration::from_secs(61));
+        out.clear();
+        correlator.process(
+            measurement("connection.out", &[("d_ip", "1.2.3.4")]),
+            now,
+            &mut out,
+        );
+        assert_eq!(out[0].tags.get("dns_q_address_str"), None);
+    }
+
+    #[test]
+    fn emit_composite() {
+        let mut exec_rule = rule("exec", "process_id", "path", "connection.out", "process_id");
+        exec_rule.emit = Some("connection.exec".to_string());
+        let mut correlator = Correlator::new(CorrelateConfig {
+            rules: vec![exec_rule],
+            ttl_s: 60,
+            max_entries: 10,
+        });
+
+        let now = Instant::now();
+        let mut out = vec![];
+        correlator.process(
+            measurement("exec", &[("process_id", "1"), ("path", "/bin/sh")]),
+            now,
+            &mut out,
+        );
+        correlator.process(
+            measurement("connection.out", &[("process_id", "1")]),
+            now,
+            &mut out,
+        );
+
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[1].name, "connection.exec");
+        assert_eq!(out[1].tags.get("path"), Some("/bin/sh"));
+        assert_eq!(out[2].tags.get("path"), None);
+    }
+}
@@ -49,6 +49,7 @@ impl Handler<Message> for Exec {
         match msg {
             Message::List(ref mut ms) => ms.par_iter().for_each(|m| run_command(command, rules, m)),
             Message::Single(ref mut m) => run_command(command, rules, m),
+            Message::Flush => {}
         }
 
         self.2.do_send(msg).unwrap();
@@ -72,7 +73,7 @@ fn run_command(command: &Vec<String>, rules: &Rules, msg: &Measurement) {
         == msg
             .tags
             .iter()
-            .filter_map(|(k, v)| rules.get(k).map(|r| r.is_match(v)))
+            .filter_map(|(k, v)| rules.get(k.as_str()).map(|r| r.is_match(v)))
             .all(|x| x == true)
     {
         return;
@@ -0,0 +1,66 @@
+//! Adds the ancestors of the process a measurement is about, from the
+//! process table of `grains::process_tree`.
+//!
+//! Measurements with a `process_id` tag get the pid of the parent as
+//! `parent_id`, and the `comm` of the parent, its parent, and so on, as
+//! `ancestor_comms`, separated by commas.
+
+use actix::prelude::*;
+
+use crate::backends::Message;
+use crate::grains::process_tree;
+use crate::metrics::Measurement;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LineageConfig {
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+}
+
+fn default_max_depth() -> usize {
+    16
+}
+
+pub struct Lineage(LineageConfig, Recipient<Message>);
+
+impl Lineage {
+    pub fn launch(config: LineageConfig, upstream: Recipient<Message>) -> Recipient<Message> {
+        Lineage(config, upstream).start().recipient()
+    }
+}
+
+impl Actor for Lineage {
+    type Context = Context<Self>;
+}
+
+fn add_tags(msg: &mut Measurement, max_depth: usize) {
+    let pid = match msg.tags.get("process_id").and_then(|p| p.parse().ok()) {
+        Some(pid) => pid,
+        None => return,
+    };
+
+    let ancestors = process_tree::ancestors(pid, max_depth);
+    if let Some(parent) = ancestors.first() {
+        msg.tags.insert("parent_id", parent.pid);
+        let comms = ancestors
+            .iter()
+            .map(|p| p.comm.as_str())
+            .collect::<Vec<_>>();
+        msg.tags.insert("ancestor_comms", comms.join(","));
+    }
+}
+
+impl Handler<Message> for Lineage {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let max_depth = self.0.max_depth;
+        match msg {
+            Message::List(ref mut ms) => ms.iter_mut().for_each(|m| add_tags(m, max_depth)),
+            Message::Single(ref mut m) => add_tags(m, max_depth),
+            Message::Flush => {}
+        }
+
+        self.1.do_send(msg).unwrap();
+    }
+}
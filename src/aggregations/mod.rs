@@ -1,13 +1,29 @@
 pub mod buffer;
+mod classify;
 mod container;
+mod correlate;
+mod redact;
 mod regex;
+mod rename;
+#[cfg(feature = "scripting")]
+mod script;
 mod systemdetails;
 mod whitelist;
 mod exec;
+mod lineage;
+mod netscan;
 
 pub use self::buffer::*;
+pub use self::classify::*;
 pub use self::exec::*;
+pub use self::lineage::*;
+pub use self::netscan::*;
 pub use self::container::*;
+pub use self::correlate::*;
+pub use self::redact::*;
 pub use self::regex::*;
+pub use self::rename::*;
+#[cfg(feature = "scripting")]
+pub use self::script::*;
 pub use self::systemdetails::*;
 pub use self::whitelist::*;
@@ -0,0 +1,290 @@
+//! Detects port scans and bursts of connections.
+//!
+//! Connections are grouped by the process that made them, or by their source
+//! address when the process isn't known. A `netscan.detected` event is sent
+//! along with the connections when, within a window, a process connects to
+//! more than `port_threshold` distinct ports of one host (`scan_type` is
+//! `ports`), or to more than `host_threshold` distinct hosts (`hosts`). Each
+//! is reported once per window.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+
+use crate::backends::Message;
+use crate::metrics::kind::{COUNTER, METER};
+use crate::metrics::{Measurement, Tags, Unit};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NetScanConfig {
+    /// The names of the measurements of connections
+    #[serde(default = "default_measurements")]
+    pub measurements: Vec<String>,
+    #[serde(default = "default_window_s")]
+    pub window_s: u64,
+    #[serde(default = "default_port_threshold")]
+    pub port_threshold: usize,
+    #[serde(default = "default_host_threshold")]
+    pub host_threshold: usize,
+    /// The most processes tracked at once
+    #[serde(default = "default_max_sources")]
+    pub max_sources: usize,
+}
+
+fn default_measurements() -> Vec<String> {
+    vec!["connection.out".to_string()]
+}
+
+fn default_window_s() -> u64 {
+    60
+}
+
+fn default_port_threshold() -> usize {
+    100
+}
+
+fn default_host_threshold() -> usize {
+    50
+}
+
+fn default_max_sources() -> usize {
+    10_000
+}
+
+struct Source {
+    started: Instant,
+    ports: HashMap<String, HashSet<String>>,
+    port_scans: HashSet<String>,
+    hosts_reported: bool,
+}
+
+impl Source {
+    fn new(now: Instant) -> Self {
+        Source {
+            started: now,
+            ports: HashMap::new(),
+            port_scans: HashSet::new(),
+            hosts_reported: false,
+        }
+    }
+}
+
+struct Detector {
+    config: NetScanConfig,
+    window: Duration,
+    sources: HashMap<String, Source>,
+}
+
+impl Detector {
+    fn new(config: NetScanConfig) -> Self {
+        Detector {
+            window: Duration::from_secs(config.window_s),
+            config,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Records the connection `m`, pushing the events it triggers to `out`
+    fn process(&mut self, m: &Measurement, now: Instant, out: &mut Vec<Measurement>) {
+        if !self.config.measurements.iter().any(|n| n == &m.name) {
+            return;
+        }
+
+        let (key, host, port) = match (
+            source_key(&m.tags),
+            m.tags.get("d_ip"),
+            m.tags.get("d_port"),
+        ) {
+            (Some(key), Some(host), Some(port)) => (key, host, port),
+            _ => return,
+        };
+
+        if self.sources.len() >= self.config.max_sources && !self.sources.contains_key(key) {
+            self.expire(now);
+            if self.sources.len() >= self.config.max_sources {
+                return;
+            }
+        }
+
+        let window = self.window;
+        let source = self
+            .sources
+            .entry(key.to_string())
+            .or_insert_with(|| Source::new(now));
+        if now.duration_since(source.started) >= window {
+            *source = Source::new(now);
+        }
+
+        let port_threshold = self.config.port_threshold;
+        let ports = source.ports.entry(host.to_string()).or_default();
+        // a host is only reported once per window, so stop counting its ports
+        if ports.len() <= port_threshold {
+            ports.insert(port.to_string());
+        }
+        if ports.len() > port_threshold && source.port_scans.insert(host.to_string()) {
+            let mut tags = detection_tags(&m.tags, "ports", self.config.window_s);
+            tags.insert("d_ip", host);
+            tags.insert("distinct_ports", ports.len().to_string());
+            out.push(detection(tags, ports.len()));
+        }
+
+        let hosts = source.ports.len();
+        if hosts > self.config.host_threshold && !source.hosts_reported {
+            source.hosts_reported = true;
+            let mut tags = detection_tags(&m.tags, "hosts", self.config.window_s);
+            tags.insert("distinct_hosts", hosts.to_string());
+            out.push(detection(tags, hosts));
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let window = self.window;
+        self.sources
+            .retain(|_, source| now.duration_since(source.started) < window);
+    }
+}
+
+fn source_key(tags: &Tags) -> Option<&str> {
+    tags.get("process_id").or_else(|| tags.get("s_ip"))
+}
+
+fn detection_tags(connection: &Tags, scan_type: &str, window_s: u64) -> Tags {
+    let mut tags = Tags::new();
+    for key in &["process_id", "process_str", "s_ip", "netns"] {
+        if let Some(value) = connection.get(key) {
+            tags.insert(*key, value);
+        }
+    }
+    tags.insert("scan_type", scan_type);
+    tags.insert("window_s", window_s.to_string());
+
+    tags
+}
+
+fn detection(tags: Tags, count: usize) -> Measurement {
+    Measurement::new(
+        COUNTER | METER,
+        "netscan.detected".to_string(),
+        Unit::Count(count as u64),
+        tags,
+    )
+}
+
+pub struct NetScan {
+    detector: Detector,
+    upstream: Recipient<Message>,
+}
+
+impl NetScan {
+    pub fn launch(config: NetScanConfig, upstream: Recipient<Message>) -> Recipient<Message> {
+        NetScan {
+            detector: Detector::new(config),
+            upstream,
+        }
+        .start()
+        .recipient()
+    }
+}
+
+impl Actor for NetScan {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.detector.window, |act, _ctx| {
+            act.detector.expire(Instant::now())
+        });
+    }
+}
+
+impl Handler<Message> for NetScan {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let now = Instant::now();
+        let mut detections = vec![];
+        let msg = match msg {
+            Message::List(mut ms) => {
+                for m in ms.iter() {
+                    self.detector.process(m, now, &mut detections);
+                }
+                ms.append(&mut detections);
+                Message::List(ms)
+            }
+            Message::Single(m) => {
+                self.detector.process(&m, now, &mut detections);
+                if detections.is_empty() {
+                    Message::Single(m)
+                } else {
+                    detections.insert(0, m);
+                    Message::List(detections)
+                }
+            }
+            Message::Flush => Message::Flush,
+        };
+
+        self.upstream.do_send(msg).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(pid: &str, host: &str, port: u16) -> Measurement {
+        let mut tags = Tags::new();
+        tags.insert("process_id", pid);
+        tags.insert("d_ip", host);
+        tags.insert("d_port", port);
+        Measurement::new(COUNTER, "connection.out".to_string(), Unit::Count(1), tags)
+    }
+
+    fn detector() -> Detector {
+        Detector::new(NetScanConfig {
+            measurements: default_measurements(),
+            window_s: 60,
+            port_threshold: 3,
+            host_threshold: 2,
+            max_sources: 10,
+        })
+    }
+
+    #[test]
+    fn port_scan() {
+        let mut detector = detector();
+        let now = Instant::now();
+        let mut out = vec![];
+
+        for port in &[22, 22, 80, 443, 8080, 8443] {
+            detector.process(&connection("1", "10.0.0.1", *port), now, &mut out);
+        }
+        detector.process(&connection("2", "10.0.0.1", 25), now, &mut out);
+
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].name, "netscan.detected");
+        assert_eq!(out[0].tags.get("scan_type"), Some("ports"));
+        assert_eq!(out[0].tags.get("process_id"), Some("1"));
+        assert_eq!(out[0].tags.get("distinct_ports"), Some("4"));
+
+        // a new window
+        out.clear();
+        let later = now + Duration::from_secs(60);
+        detector.process(&connection("1", "10.0.0.1", 1), later, &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn host_sweep() {
+        let mut detector = detector();
+        let now = Instant::now();
+        let mut out = vec![];
+
+        for host in &["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"] {
+            detector.process(&connection("1", host, 22), now, &mut out);
+        }
+
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].tags.get("scan_type"), Some("hosts"));
+        assert_eq!(out[0].tags.get("distinct_hosts"), Some("3"));
+    }
+}
@@ -0,0 +1,252 @@
+//! Scrubs personal data from the values of tags before measurements leave
+//! the host.
+//!
+//! Every rule applies an `action` to the tags it lists: `MaskIp` zeroes the
+//! host part of addresses, keeping `ipv4_prefix` and `ipv6_prefix` bits,
+//! `Hash` replaces values with an HMAC-SHA256 keyed with `key`, so they can
+//! still be correlated without being reversed, and `StripQuery` cuts the
+//! query string and fragment off paths and URLs. Values an action doesn't
+//! apply to, like a hostname in an IP tag, are kept.
+//!
+//! The step only scrubs the pipeline it's a step of, so a local `Console` or
+//! `Pcap` pipeline can keep full fidelity while remote backends get the
+//! redacted data.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use actix::prelude::*;
+use failure::{bail, Error};
+use rayon::prelude::*;
+use ring::hmac;
+
+use crate::backends::Message;
+use crate::metrics::Measurement;
+use crate::secrets::Secret;
+
+/// The length of hashed values, in hex digits
+const HASH_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RedactConfig {
+    /// The key of `Hash` rules
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+    pub rules: Vec<RedactRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RedactRule {
+    pub tags: Vec<String>,
+    #[serde(flatten)]
+    pub action: RedactAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "action")]
+pub enum RedactAction {
+    MaskIp {
+        #[serde(default = "default_ipv4_prefix")]
+        ipv4_prefix: u8,
+        #[serde(default = "default_ipv6_prefix")]
+        ipv6_prefix: u8,
+    },
+    Hash,
+    StripQuery,
+}
+
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    48
+}
+
+#[derive(Clone)]
+enum Action {
+    MaskIp { ipv4_prefix: u8, ipv6_prefix: u8 },
+    Hash(hmac::Key),
+    StripQuery,
+}
+
+impl Action {
+    fn apply(&self, value: &str) -> Option<String> {
+        match self {
+            Action::MaskIp {
+                ipv4_prefix,
+                ipv6_prefix,
+            } => match value.parse::<IpAddr>().ok()? {
+                IpAddr::V4(ip) => {
+                    let mask = u32::MAX
+                        .checked_shl(32 - u32::from(*ipv4_prefix))
+                        .unwrap_or(0);
+                    Some(Ipv4Addr::from(u32::from(ip) & mask).to_string())
+                }
+                IpAddr::V6(ip) => {
+                    let mask = u128::MAX
+                        .checked_shl(128 - u32::from(*ipv6_prefix))
+                        .unwrap_or(0);
+                    Some(Ipv6Addr::from(u128::from(ip) & mask).to_string())
+                }
+            },
+            Action::Hash(key) => {
+                let tag = hmac::sign(key, value.as_bytes());
+                let hex = tag
+                    .as_ref()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                Some(hex[..HASH_LEN].to_string())
+            }
+            Action::StripQuery => {
+                let end = value.find(&['?', '#'][..])?;
+                Some(value[..end].to_string())
+            }
+        }
+    }
+}
+
+/// The actions of every tag, in the order of the rules
+type Rules = HashMap<String, Vec<Action>>;
+
+fn load_rules(config: RedactConfig) -> Result<Rules, Error> {
+    let key = Secret::from_config("key", config.key.as_ref(), config.key_file.as_ref())?
+        .map(|key| hmac::Key::new(hmac::HMAC_SHA256, key.get().as_bytes()));
+
+    let mut rules = Rules::new();
+    for rule in config.rules {
+        let action = match rule.action {
+            RedactAction::MaskIp {
+                ipv4_prefix,
+                ipv6_prefix,
+            } => {
+                if ipv4_prefix > 32 || ipv6_prefix > 128 {
+                    bail!("MaskIp prefixes are at most 32 bits for IPv4 and 128 for IPv6");
+                }
+                Action::MaskIp {
+                    ipv4_prefix,
+                    ipv6_prefix,
+                }
+            }
+            RedactAction::Hash => match key {
+                Some(ref key) => Action::Hash(key.clone()),
+                None => bail!("Hash rules need a key or key_file"),
+            },
+            RedactAction::StripQuery => Action::StripQuery,
+        };
+
+        for tag in rule.tags {
+            rules
+                .entry(tag)
+                .or_insert_with(Vec::new)
+                .push(action.clone());
+        }
+    }
+
+    Ok(rules)
+}
+
+fn redact(msg: &mut Measurement, rules: &Rules) {
+    for (key, value) in msg.tags.iter_mut() {
+        if let Some(actions) = rules.get(key.as_str()) {
+            for action in actions {
+                if let Some(redacted) = action.apply(value.as_str()) {
+                    *value = redacted.into();
+                }
+            }
+        }
+    }
+}
+
+pub struct Redact(Arc<Rules>, Recipient<Message>);
+
+impl Redact {
+    pub fn launch(config: RedactConfig, upstream: Recipient<Message>) -> Recipient<Message> {
+        let rules = load_rules(config).expect("Invalid redaction rules");
+
+        Redact(Arc::new(rules), upstream).start().recipient()
+    }
+}
+
+impl Actor for Redact {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for Redact {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let rules = self.0.clone();
+        match msg {
+            Message::List(ref mut ms) => ms.par_iter_mut().for_each(|m| redact(m, &rules)),
+            Message::Single(ref mut m) => redact(m, &rules),
+            Message::Flush => {}
+        }
+
+        self.1.do_send(msg).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::kind::COUNTER;
+    use crate::metrics::{Tags, Unit};
+
+    #[test]
+    fn redacts() {
+        let config: RedactConfig = toml::from_str(
+            r#"
+key = "salt"
+
+[[rules]]
+tags = ["s_ip", "d_ip"]
+action = "MaskIp"
+
+[[rules]]
+tags = ["username"]
+action = "Hash"
+
+[[rules]]
+tags = ["path_str", "url"]
+action = "StripQuery"
+"#,
+        )
+        .unwrap();
+        let rules = load_rules(config).unwrap();
+
+        let mut tags = Tags::new();
+        tags.insert("s_ip", "192.168.10.34");
+        tags.insert("d_ip", "2001:db8:85a3:8d3:1319:8a2e:370:7348");
+        tags.insert("username", "alice");
+        tags.insert("url", "https://example.com/login?user=alice#top");
+        tags.insert("path_str", "var/log/syslog");
+        tags.insert("process_str", "curl");
+        let mut m = Measurement::new(COUNTER, "connection.out".to_string(), Unit::Count(1), tags);
+        redact(&mut m, &rules);
+
+        assert_eq!(m.tags.get("s_ip"), Some("192.168.10.0"));
+        assert_eq!(m.tags.get("d_ip"), Some("2001:db8:85a3::"));
+        assert_eq!(m.tags.get("url"), Some("https://example.com/login"));
+        assert_eq!(m.tags.get("path_str"), Some("var/log/syslog"));
+        assert_eq!(m.tags.get("process_str"), Some("curl"));
+
+        let hashed = m.tags.get("username").unwrap();
+        assert_eq!(hashed.len(), HASH_LEN);
+        assert_ne!(hashed, "alice");
+        let again = Action::Hash(hmac::Key::new(hmac::HMAC_SHA256, b"salt"));
+        assert_eq!(again.apply("alice").as_deref(), Some(hashed));
+
+        let unkeyed: RedactConfig = toml::from_str(
+            r#"
+[[rules]]
+tags = ["username"]
+action = "Hash"
+"#,
+        )
+        .unwrap();
+        assert!(load_rules(unkeyed).is_err());
+    }
+}
@@ -6,9 +6,9 @@ use rayon::prelude::*;
 use regex::Regex as RegexMatcher;
 
 use crate::backends::Message;
-use crate::metrics::Measurement;
+use crate::metrics::{Measurement, TagStr};
 
-type Rules = Arc<HashMap<String, (RegexMatcher, String)>>;
+type Rules = Arc<HashMap<String, (RegexMatcher, TagStr)>>;
 pub struct Regex(Rules, Recipient<Message>);
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RegexPattern {
@@ -30,7 +30,7 @@ impl Regex {
             .map(|p| {
                 (
                     p.key,
-                    (RegexMatcher::new(&p.regex).unwrap(), p.replace_with),
+                    (RegexMatcher::new(&p.regex).unwrap(), p.replace_with.into()),
                 )
             })
             .collect();
@@ -45,9 +45,9 @@ impl Actor for Regex {
 
 fn filter_tags(msg: &mut Measurement, rules: Rules) {
     for (key, value) in msg.tags.iter_mut() {
-        if let Some((regex, replace)) = rules.get(key) {
+        if let Some((regex, replace)) = rules.get(key.as_str()) {
             if regex.is_match(value) {
-                *value = replace.clone();
+                *value = replace.into();
             }
         }
     }
@@ -63,6 +63,7 @@ impl Handler<Message> for Regex {
                 .par_iter_mut()
                 .for_each(move |m| filter_tags(m, rules.clone())),
             Message::Single(ref mut m) => filter_tags(m, rules),
+            Message::Flush => {}
         }
 
         self.1.do_send(msg).unwrap();
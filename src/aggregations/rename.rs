@@ -0,0 +1,177 @@
+use actix::prelude::*;
+use failure::{format_err, Error};
+use rayon::prelude::*;
+use std::sync::Arc;
+
+use crate::backends::Message;
+use crate::metrics::Measurement;
+
+/// A metric name like `prod.edge.{name}`.
+///
+/// `{name}` is the name of the measurement, `{hostname}` the name of the
+/// host, and any other placeholder the value of the tag it names, or
+/// `unknown`.
+#[derive(Debug, PartialEq)]
+pub struct NameTemplate(Vec<Part>);
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Name,
+    Hostname,
+    Tag(String),
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<NameTemplate, Error> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format_err!("unclosed placeholder in name template {}", template))?
+                + start;
+            parts.push(match &rest[start + 1..end] {
+                "name" => Part::Name,
+                "hostname" => Part::Hostname,
+                "" => {
+                    return Err(format_err!(
+                        "empty placeholder in name template {}",
+                        template
+                    ))
+                }
+                tag => Part::Tag(tag.to_string()),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(NameTemplate(parts))
+    }
+
+    /// The template of a pipeline. `{namespace}` in `template` is replaced
+    /// with `namespace`, which is otherwise a prefix, unless it has a
+    /// `{name}` placeholder itself.
+    pub fn from_config(
+        namespace: Option<&str>,
+        template: Option<&str>,
+    ) -> Result<Option<NameTemplate>, Error> {
+        let template = match (namespace, template) {
+            (Some(namespace), Some(template)) => template.replace("{namespace}", namespace),
+            (None, Some(template)) => template
+                .replace("{namespace}.", "")
+                .replace("{namespace}", ""),
+            (Some(namespace), None) if namespace.contains("{name}") => namespace.to_string(),
+            (Some(namespace), None) => format!("{}.{{name}}", namespace),
+            (None, None) => return Ok(None),
+        };
+
+        NameTemplate::parse(&template).map(Some)
+    }
+
+    pub fn render(&self, hostname: &str, m: &Measurement) -> String {
+        let mut name = String::with_capacity(m.name.len() * 2);
+        for part in self.0.iter() {
+            match part {
+                Part::Literal(s) => name.push_str(s),
+                Part::Name => name.push_str(&m.name),
+                Part::Hostname => name.push_str(hostname),
+                Part::Tag(tag) => name.push_str(m.tags.get(tag).unwrap_or("unknown")),
+            }
+        }
+
+        name
+    }
+}
+
+/// Renames every measurement from a template before it reaches the backend
+pub struct Rename {
+    template: Arc<NameTemplate>,
+    hostname: Arc<String>,
+    upstream: Recipient<Message>,
+}
+
+impl Rename {
+    pub fn launch(template: NameTemplate, upstream: Recipient<Message>) -> Recipient<Message> {
+        use redbpf::uname::*;
+
+        Rename {
+            template: Arc::new(template),
+            hostname: Arc::new(get_fqdn().unwrap()),
+            upstream,
+        }
+        .start()
+        .recipient()
+    }
+}
+
+impl Actor for Rename {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for Rename {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let template = self.template.clone();
+        let hostname = self.hostname.clone();
+        match msg {
+            Message::List(ref mut ms) => ms
+                .par_iter_mut()
+                .for_each(move |m| m.name = template.render(&hostname, m)),
+            Message::Single(ref mut m) => m.name = template.render(&hostname, m),
+            Message::Flush => {}
+        }
+
+        self.upstream.do_send(msg).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{kind, Tags, Unit};
+
+    fn measurement() -> Measurement {
+        let mut tags = Tags::new();
+        tags.insert("process_str", "nginx");
+        Measurement::new(
+            kind::COUNTER,
+            "connection.out".to_string(),
+            Unit::Count(1),
+            tags,
+        )
+    }
+
+    fn render(namespace: Option<&str>, template: Option<&str>) -> String {
+        NameTemplate::from_config(namespace, template)
+            .unwrap()
+            .unwrap()
+            .render("host1", &measurement())
+    }
+
+    #[test]
+    fn templates() {
+        assert_eq!(render(Some("prod.edge"), None), "prod.edge.connection.out");
+        assert_eq!(
+            render(Some("prod.{name}.edge"), None),
+            "prod.connection.out.edge"
+        );
+        assert_eq!(
+            render(Some("prod"), Some("{namespace}.{hostname}.{name}")),
+            "prod.host1.connection.out"
+        );
+        assert_eq!(
+            render(None, Some("{namespace}.{name}.{process_str}.{pid}")),
+            "connection.out.nginx.unknown"
+        );
+        assert!(NameTemplate::from_config(None, None).unwrap().is_none());
+        assert!(NameTemplate::parse("prod.{name").is_err());
+        assert!(NameTemplate::parse("prod.{}").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//! Transforms measurements with a user supplied Rhai script.
+//!
+//! The script defines a `transform` function that is called with each
+//! measurement as an object map:
+//!
+//! ```text
+//! fn transform(m) {
+//!     if m.tags.process_str == "sshd" {
+//!         return ();
+//!     }
+//!     m.name = "custom." + m.name;
+//!     m.tags.team = "infra";
+//!     m
+//! }
+//! ```
+//!
+//! Returning the map keeps the measurement with any changes to its `name`,
+//! `value` and `tags`, while returning `()` drops it. The `timestamp` is
+//! read-only. Numeric and boolean tags are numbers and booleans, and other
+//! tags are strings. Scripts have no access to the file system or the
+//! network, and are stopped after `max_operations`.
+
+use std::fs;
+use std::path::PathBuf;
+
+use actix::prelude::*;
+use rhai::{Dynamic, Engine, ImmutableString, Map, Scope, AST};
+
+use crate::backends::Message;
+use crate::metrics::{Measurement, TagValue, Tags, Typed, Unit};
+
+const ENTRY_POINT: &str = "transform";
+
+pub struct Script {
+    program: Program,
+    upstream: Recipient<Message>,
+}
+
+/// A compiled script, and the engine that runs it
+struct Program {
+    engine: Engine,
+    ast: AST,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScriptConfig {
+    /// The script itself
+    pub script: Option<String>,
+    /// A file to read the script from, if `script` is not set
+    pub path: Option<PathBuf>,
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+}
+
+fn default_max_operations() -> u64 {
+    100_000
+}
+
+impl Script {
+    pub fn launch(config: ScriptConfig, upstream: Recipient<Message>) -> Recipient<Message> {
+        let source = match (config.script, config.path) {
+            (Some(script), _) => script,
+            (None, Some(path)) => fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read script {:?}: {}", path, e)),
+            (None, None) => panic!("Invalid configuration: script or path must be set"),
+        };
+
+        let program = Program::compile(&source, config.max_operations)
+            .unwrap_or_else(|e| panic!("Invalid script: {}", e));
+
+        Script { program, upstream }.start().recipient()
+    }
+}
+
+impl Program {
+    fn compile(source: &str, max_operations: u64) -> Result<Program, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(max_operations);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+
+        Ok(Program { engine, ast })
+    }
+
+    /// Runs the script on `m`, returning `None` if it is dropped. The
+    /// measurement is kept as is if the script fails.
+    fn transform(&self, m: Measurement) -> Option<Measurement> {
+        let result: Result<Dynamic, _> = self.engine.call_fn(
+            &mut Scope::new(),
+            &self.ast,
+            ENTRY_POINT,
+            (Dynamic::from(to_map(&m)),),
+        );
+
+        match result {
+            Ok(ret) if ret.is::<()>() => None,
+            Ok(ret) => match ret.try_cast::<Map>() {
+                Some(map) => Some(from_map(m, map)),
+                None => {
+                    warn!("Script returned neither a measurement nor ()");
+                    Some(m)
+                }
+            },
+            Err(e) => {
+                warn!("Script failed on {}: {}", m.name, e);
+                Some(m)
+            }
+        }
+    }
+}
+
+impl Actor for Script {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for Script {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let msg = match msg {
+            Message::List(ms) => {
+                let ms = ms
+                    .into_iter()
+                    .filter_map(|m| self.program.transform(m))
+                    .collect::<Vec<_>>();
+                if ms.is_empty() {
+                    return;
+                }
+                Message::List(ms)
+            }
+            Message::Single(m) => match self.program.transform(m) {
+                Some(m) => Message::Single(m),
+                None => return,
+            },
+            Message::Flush => Message::Flush,
+        };
+
+        self.upstream.do_send(msg).unwrap();
+    }
+}
+
+fn to_map(m: &Measurement) -> Map {
+    let tags = m
+        .tags
+        .iter()
+        .map(|(k, v)| (k.as_str().into(), tag_to_dynamic(v)))
+        .collect::<Map>();
+
+    let value = match m.value {
+        Unit::Byte(x) | Unit::Count(x) | Unit::Duration(x) => Dynamic::from(x as i64),
+        Unit::Gauge(x) => Dynamic::from(x),
+        Unit::Str(ref s) => Dynamic::from(s.clone()),
+        Unit::Raw(_) | Unit::Histogram(_) => Dynamic::from(()),
+    };
+
+    let mut map = Map::new();
+    map.insert("name".into(), Dynamic::from(m.name.clone()));
+    map.insert("value".into(), value);
+    map.insert("timestamp".into(), Dynamic::from(m.timestamp as i64));
+    map.insert("tags".into(), Dynamic::from(tags));
+
+    map
+}
+
+/// Applies the changes of the script to `m`. Values keep the unit of the
+/// measurement, and values of the wrong type are ignored. Tags keep their
+/// order, and those added by the script follow them, sorted by name.
+fn from_map(mut m: Measurement, mut map: Map) -> Measurement {
+    if let Some(name) = map.remove("name").and_then(to_string) {
+        m.name = name;
+    }
+
+    if let Some(value) = map.remove("value") {
+        m.value = match m.value {
+            Unit::Byte(x) => Unit::Byte(to_u64(value).unwrap_or(x)),
+            Unit::Count(x) => Unit::Count(to_u64(value).unwrap_or(x)),
+            Unit::Duration(x) => Unit::Duration(to_u64(value).unwrap_or(x)),
+            Unit::Gauge(x) => Unit::Gauge(to_f64(value).unwrap_or(x)),
+            Unit::Str(s) => Unit::Str(to_string(value).unwrap_or(s)),
+            unit => unit,
+        };
+    }
+
+    if let Some(mut changed) = map.remove("tags").and_then(|t| t.try_cast::<Map>()) {
+        let mut tags = Tags::new();
+        for (k, _) in m.tags.iter() {
+            if let Some(v) = changed.remove(k.as_str()).and_then(to_tag_value) {
+                tags.insert(k.clone(), v);
+            }
+        }
+
+        let mut added = changed
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.to_string(), to_tag_value(v)?)))
+            .collect::<Vec<_>>();
+        added.sort_by(|a, b| a.0.cmp(&b.0));
+        for (k, v) in added {
+            tags.insert(k, v);
+        }
+
+        m.tags = tags;
+    }
+
+    m
+}
+
+fn to_string(value: Dynamic) -> Option<String> {
+    if value.is::<ImmutableString>() {
+        return value.try_cast::<ImmutableString>().map(|s| s.to_string());
+    }
+    if value.is::<()>() {
+        return None;
+    }
+
+    Some(value.to_string())
+}
+
+/// Numeric and boolean tags are numbers and booleans in scripts
+fn tag_to_dynamic(value: &TagValue) -> Dynamic {
+    match value.typed() {
+        Typed::Int(x) => Dynamic::from(x),
+        Typed::Float(x) => Dynamic::from(x),
+        Typed::Bool(x) => Dynamic::from(x),
+        Typed::Str(_) | Typed::Ip(_) => Dynamic::from(value.to_string()),
+    }
+}
+
+fn to_tag_value(value: Dynamic) -> Option<TagValue> {
+    if value.is::<i64>() {
+        return value.try_cast::<i64>().map(TagValue::from);
+    }
+    if value.is::<f64>() {
+        return value.try_cast::<f64>().map(TagValue::from);
+    }
+    if value.is::<bool>() {
+        return value.try_cast::<bool>().map(TagValue::from);
+    }
+
+    to_string(value).map(TagValue::from)
+}
+
+fn to_u64(value: Dynamic) -> Option<u64> {
+    match value.try_cast::<i64>() {
+        Some(x) if x >= 0 => Some(x as u64),
+        _ => None,
+    }
+}
+
+fn to_f64(value: Dynamic) -> Option<f64> {
+    if value.is::<i64>() {
+        return value.try_cast::<i64>().map(|x| x as f64);
+    }
+
+    value.try_cast::<f64>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::kind::COUNTER;
+
+    fn run(script: &str, m: Measurement) -> Option<Measurement> {
+        Program::compile(script, default_max_operations())
+            .unwrap()
+            .transform(m)
+    }
+
+    fn measurement() -> Measurement {
+        let mut tags = Tags::new();
+        tags.insert("process_str", "curl");
+        tags.insert("d_port", 443);
+        tags.insert("conn_str", "out");
+        Measurement::new(COUNTER, "volume.out".to_string(), Unit::Byte(100), tags)
+    }
+
+    #[test]
+    fn transform_measurement() {
+        let m = run(
+            r#"
+fn transform(m) {
+    m.name = "net." + m.name;
+    m.value = m.value * 2;
+    m.tags.team = "infra";
+    m
+}
+"#,
+            measurement(),
+        )
+        .unwrap();
+
+        assert_eq!(m.name, "net.volume.out");
+        assert_eq!(m.value, Unit::Byte(200));
+        assert_eq!(m.tags.get("team"), Some("infra"));
+        assert_eq!(m.tags.get("process_str"), Some("curl"));
+    }
+
+    #[test]
+    fn keep_tag_order() {
+        let m = run(
+            r#"
+fn transform(m) {
+    m.tags.zone = "a";
+    m.tags.app = "web";
+    m.tags.d_port = 8443;
+    m
+}
+"#,
+            measurement(),
+        )
+        .unwrap();
+
+        let keys = m.tags.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec!["process_str", "d_port", "conn_str", "app", "zone"]
+        );
+        assert_eq!(m.tags.get("d_port"), Some("8443"));
+    }
+
+    #[test]
+    fn keep_measurement_on_failure() {
+        let m = run("fn transform(m) { m.missing() }", measurement()).unwrap();
+        assert_eq!(m.name, "volume.out");
+        assert_eq!(m.value, Unit::Byte(100));
+        assert_eq!(m.tags.len(), 3);
+    }
+
+    #[test]
+    fn drop_measurement() {
+        let script = r#"
+fn transform(m) {
+    if m.tags.process_str == "curl" { return (); }
+    m
+}
+"#;
+        assert!(run(script, measurement()).is_none());
+    }
+}
@@ -2,11 +2,11 @@ use actix::prelude::*;
 use rayon::prelude::*;
 
 use crate::backends::Message;
-use crate::metrics::Measurement;
+use crate::metrics::{Measurement, TagStr};
 
 pub struct AddSystemDetails {
-    host: String,
-    kernel: String,
+    host: TagStr,
+    kernel: TagStr,
     upstream: Recipient<Message>,
 }
 
@@ -19,10 +19,10 @@ impl AddSystemDetails {
         use redbpf::uname::*;
 
         let uts = uname().unwrap();
-        let kernel = to_str(&uts.release).to_string();
+        let kernel = to_str(&uts.release).into();
 
         AddSystemDetails {
-            host: get_fqdn().unwrap(),
+            host: get_fqdn().unwrap().into(),
             kernel,
             upstream,
         }
@@ -31,9 +31,9 @@ impl AddSystemDetails {
     }
 }
 
-fn add_tags(msg: &mut Measurement, host: String, kernel: String) {
-    msg.tags.insert("host".to_string(), host);
-    msg.tags.insert("kernel".to_string(), kernel);
+fn add_tags(msg: &mut Measurement, host: TagStr, kernel: TagStr) {
+    msg.tags.insert("host", host);
+    msg.tags.insert("kernel", kernel);
 }
 
 impl Handler<Message> for AddSystemDetails {
@@ -47,6 +47,7 @@ impl Handler<Message> for AddSystemDetails {
                 .par_iter_mut()
                 .for_each(move |m| add_tags(m, host.clone(), kernel.clone())),
             Message::Single(ref mut m) => add_tags(m, host, kernel),
+            Message::Flush => {}
         }
 
         self.upstream.do_send(msg).unwrap();
@@ -25,7 +25,7 @@ impl Actor for Whitelist {
 }
 
 fn filter_tags(msg: &mut Measurement, whitelist: Arc<HashSet<String>>) {
-    msg.tags.0.retain(|(k, _v)| whitelist.contains(k));
+    msg.tags.retain(|k, _v| whitelist.contains(k.as_str()));
 }
 
 impl Handler<Message> for Whitelist {
@@ -38,6 +38,7 @@ impl Handler<Message> for Whitelist {
                 .par_iter_mut()
                 .for_each(move |m| filter_tags(m, whitelist.clone())),
             Message::Single(ref mut m) => filter_tags(m, whitelist.clone()),
+            Message::Flush => {}
         }
 
         self.1.do_send(msg).unwrap();
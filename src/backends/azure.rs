@@ -0,0 +1,375 @@
+//! Sends measurements to Azure Blob Storage and Event Hubs over HTTPS.
+//!
+//! Messages are appended to an append blob per host and hour, named
+//! `<prefix><yyyy>/<mm>/<dd>/<hh>/<hostname>`. The blob is created when the
+//! first append of the hour finds it missing, and messages larger than a
+//! block are appended in as many blocks as they take. Blobs are written
+//! with a SAS token of the container.
+//!
+//! Every message is published to Event Hubs as a single event, signed with a
+//! SAS token derived from a shared access key, and partitioned by hostname.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix::prelude::*;
+use futures::future::Either;
+use futures::{finished, stream, Future, Stream};
+use hyper::{header, Body, Client, Method, Request, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use ring::hmac;
+
+use crate::backends::encoders::{Encoder, Encoding, Fields};
+use crate::backends::proxy::{ProxyConfig, ProxyConnector};
+use crate::backends::tls::https_connector;
+use crate::backends::{url_encode, Message};
+use crate::metrics::timestamp_now;
+use crate::secrets::Secret;
+use crate::telemetry::BackendStats;
+
+const STORAGE_VERSION: &str = "2019-12-12";
+/// The largest block that can be appended to a blob
+const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+/// How long the signatures of Event Hubs requests are valid
+const SAS_TTL_S: u64 = 3600;
+
+type HttpsClient = Client<HttpsConnector<ProxyConnector>>;
+/// Whether a block was appended
+type AppendFuture = Box<dyn Future<Item = bool, Error = hyper::Error>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlobConfig {
+    account: String,
+    container: String,
+    /// A SAS token of the container, without the leading `?`
+    sas_token: Option<String>,
+    sas_token_file: Option<String>,
+    #[serde(default)]
+    prefix: String,
+    /// Replaces `https://<account>.blob.core.windows.net`
+    endpoint: Option<String>,
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default)]
+    fields: Fields,
+    proxy: Option<ProxyConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EventHubsConfig {
+    namespace: String,
+    event_hub: String,
+    key_name: String,
+    key: Option<String>,
+    key_file: Option<String>,
+    /// Replaces `https://<namespace>.servicebus.windows.net`
+    endpoint: Option<String>,
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default)]
+    fields: Fields,
+    proxy: Option<ProxyConfig>,
+}
+
+fn https_client(proxy: Option<&ProxyConfig>) -> HttpsClient {
+    let connector = https_connector(4, None, proxy)
+        .unwrap_or_else(|e| panic!("Invalid Azure proxy settings: {}", e));
+    Client::builder().build(connector)
+}
+
+fn hostname() -> String {
+    redbpf::uname::get_fqdn().unwrap()
+}
+
+fn encode(encoder: &Encoder, msg: Message) -> Option<Vec<u8>> {
+    match msg {
+        Message::Single(m) => Some(encoder.encode(&[m])),
+        Message::List(ref ms) => Some(encoder.encode(ms)),
+        Message::Flush => None,
+    }
+}
+
+pub struct AzureBlob {
+    config: BlobConfig,
+    base: String,
+    sas_token: Secret,
+    hostname: String,
+    client: HttpsClient,
+    encoder: Encoder,
+    stats: Arc<BackendStats>,
+}
+
+impl AzureBlob {
+    pub fn new(config: BlobConfig, stats: Arc<BackendStats>) -> AzureBlob {
+        let base = match config.endpoint {
+            Some(ref endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{}.blob.core.windows.net", config.account),
+        };
+        let sas_token = Secret::required(
+            "sas_token",
+            config.sas_token.as_ref(),
+            config.sas_token_file.as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("Invalid Azure Blob settings: {}", e));
+
+        AzureBlob {
+            base,
+            sas_token,
+            hostname: hostname(),
+            client: https_client(config.proxy.as_ref()),
+            encoder: Encoder::new(config.encoding, config.fields.clone()),
+            config,
+            stats,
+        }
+    }
+
+    /// The blob of the hour of `timestamp`, without the SAS token
+    fn blob(&self, timestamp: u64) -> String {
+        format!(
+            "{}/{}/{}{}{}",
+            self.base,
+            self.config.container,
+            self.config.prefix,
+            partition(timestamp / 1_000_000_000),
+            self.hostname,
+        )
+    }
+}
+
+/// Appends `block` to the append blob at `blob`, creating the blob if it
+/// doesn't exist yet
+fn append_block(
+    client: HttpsClient,
+    blob: String,
+    sas_token: String,
+    content_type: &'static str,
+    block: Vec<u8>,
+) -> AppendFuture {
+    let append = format!("{}?comp=appendblock&{}", blob, sas_token);
+    let request = azure_request(&append, content_type, block.clone());
+    let retry = azure_request(&append, content_type, block);
+
+    let appended = client
+        .request(request)
+        .and_then(move |res| -> AppendFuture {
+            if res.status() != StatusCode::NOT_FOUND {
+                return Box::new(finished(res.status().is_success()));
+            }
+
+            let create = create_request(&format!("{}?{}", blob, sas_token), content_type);
+            Box::new(client.request(create).and_then(move |res| {
+                // another append may have created it since
+                if !res.status().is_success() && res.status() != StatusCode::CONFLICT {
+                    return Either::B(finished(false));
+                }
+
+                Either::A(client.request(retry).map(|res| res.status().is_success()))
+            }))
+        });
+
+    Box::new(appended)
+}
+
+/// Creates an empty append blob, unless it exists
+fn create_request(uri: &str, content_type: &'static str) -> Request<Body> {
+    let mut req = azure_request(uri, content_type, vec![]);
+    let headers = req.headers_mut();
+    headers.insert("x-ms-blob-type", "AppendBlob".parse().unwrap());
+    headers.insert(header::IF_NONE_MATCH, "*".parse().unwrap());
+
+    req
+}
+
+fn azure_request(uri: &str, content_type: &'static str, body: Vec<u8>) -> Request<Body> {
+    let mut req = Request::new(Body::from(body));
+    *req.method_mut() = Method::PUT;
+    *req.uri_mut() = uri.parse::<Uri>().unwrap();
+    let headers = req.headers_mut();
+    headers.insert("x-ms-version", STORAGE_VERSION.parse().unwrap());
+    headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+
+    req
+}
+
+impl Actor for AzureBlob {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for AzureBlob {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let body = match encode(&self.encoder, msg) {
+            Some(body) => body,
+            None => return self.stats.flushed(),
+        };
+        self.stats.received();
+
+        let blob = self.blob(timestamp_now());
+        let sas_token = self.sas_token.get().trim_start_matches('?').to_string();
+        let client = self.client.clone();
+        let content_type = self.encoder.content_type();
+        let blocks = body
+            .chunks(MAX_BLOCK_SIZE)
+            .map(<[u8]>::to_vec)
+            .collect::<Vec<_>>();
+
+        // blocks are appended in order, one after the other
+        let request = self.stats.request();
+        let appended = stream::iter_ok::<_, hyper::Error>(blocks).fold(true, move |ok, block| {
+            append_block(
+                client.clone(),
+                blob.clone(),
+                sas_token.clone(),
+                content_type,
+                block,
+            )
+            .map(move |appended| ok && appended)
+        });
+        actix::spawn(appended.then(move |res| {
+            request.finish(res.unwrap_or(false));
+            finished(())
+        }));
+    }
+}
+
+pub struct EventHubs {
+    config: EventHubsConfig,
+    key: Secret,
+    uri: String,
+    broker_properties: String,
+    client: HttpsClient,
+    encoder: Encoder,
+    stats: Arc<BackendStats>,
+}
+
+impl EventHubs {
+    pub fn new(config: EventHubsConfig, stats: Arc<BackendStats>) -> EventHubs {
+        let base = match config.endpoint {
+            Some(ref endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None => format!("https://{}.servicebus.windows.net", config.namespace),
+        };
+        let broker_properties = format!(r#"{{"PartitionKey":"{}"}}"#, hostname());
+        let key = Secret::required("key", config.key.as_ref(), config.key_file.as_ref())
+            .unwrap_or_else(|e| panic!("Invalid Event Hubs settings: {}", e));
+
+        EventHubs {
+            key,
+            uri: format!("{}/{}", base, config.event_hub),
+            broker_properties,
+            client: https_client(config.proxy.as_ref()),
+            encoder: Encoder::new(config.encoding, config.fields.clone()),
+            config,
+            stats,
+        }
+    }
+}
+
+impl Actor for EventHubs {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for EventHubs {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let body = match encode(&self.encoder, msg) {
+            Some(body) => body,
+            None => return self.stats.flushed(),
+        };
+        self.stats.received();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let token = sas_token(
+            &self.uri,
+            &self.config.key_name,
+            &self.key.get(),
+            now + SAS_TTL_S,
+        );
+
+        let mut req = Request::new(Body::from(body));
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = format!("{}/messages?api-version=2014-01", self.uri)
+            .parse()
+            .unwrap();
+        let headers = req.headers_mut();
+        headers.insert(header::AUTHORIZATION, token.parse().unwrap());
+        headers.insert("BrokerProperties", self.broker_properties.parse().unwrap());
+        headers.insert(
+            header::CONTENT_TYPE,
+            self.encoder.content_type().parse().unwrap(),
+        );
+
+        let request = self.stats.request();
+        actix::spawn(self.client.request(req).then(move |res| {
+            request.finish(res.map(|r| r.status().is_success()).unwrap_or(false));
+            finished(())
+        }));
+    }
+}
+
+/// Signs access to `uri` until `expiry`, in seconds since the UNIX epoch
+fn sas_token(uri: &str, key_name: &str, key: &str, expiry: u64) -> String {
+    let resource = url_encode(uri);
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    let signature = hmac::sign(&key, format!("{}\n{}", resource, expiry).as_bytes());
+
+    format!(
+        "SharedAccessSignature sr={}&sig={}&se={}&skn={}",
+        resource,
+        url_encode(&base64::encode(signature.as_ref())),
+        expiry,
+        key_name
+    )
+}
+
+/// The `yyyy/mm/dd/hh/` folder of a time, in seconds since the UNIX epoch
+fn partition(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let hour = (secs % 86_400) / 3600;
+
+    // from days since the epoch to the proleptic Gregorian calendar, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}/{:02}/{:02}/{:02}/", year, month, day, hour)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions() {
+        assert_eq!(partition(0), "1970/01/01/00/");
+        assert_eq!(partition(951_782_400 + 13 * 3600), "2000/02/29/13/");
+        assert_eq!(partition(1_609_459_199), "2020/12/31/23/");
+    }
+
+    #[test]
+    fn signature() {
+        let token = sas_token(
+            "https://ns.servicebus.windows.net/hub",
+            "send",
+            "secret",
+            1_600_000_000,
+        );
+        assert_eq!(
+            token,
+            "SharedAccessSignature sr=https%3A%2F%2Fns.servicebus.windows.net%2Fhub\
+             &sig=tPaAtWHWgK%2BeH5KQZ%2FGQqBWBTsgJ%2BBY7tHYv1EcHiO8%3D\
+             &se=1600000000&skn=send"
+        );
+    }
+}
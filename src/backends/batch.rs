@@ -0,0 +1,210 @@
+//! Collects measurements into batches in front of a backend, and sends them
+//! on a schedule.
+//!
+//! Batches are sent every `interval_ms`, plus a delay of up to `jitter_ms`
+//! that's different on every host and every flush, so a fleet started at
+//! the same time doesn't flush at the same time. A batch is sent as soon as
+//! it has `max_batch_size` measurements.
+//!
+//! With `max_in_flight`, batches are held back while as many wait for the
+//! backend to acknowledge them, and at most `max_queued` batches are held.
+//! Batches the backend fails to deliver are dropped.
+
+use std::collections::VecDeque;
+use std::hash::Hasher;
+use std::mem;
+use std::time::Duration;
+
+use actix::fut;
+use actix::prelude::*;
+use metrohash::MetroHash64;
+
+use crate::backends::{Deliver, Message};
+use crate::metrics::Measurement;
+use crate::shedding;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FlushConfig {
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default)]
+    pub jitter_ms: u64,
+    pub max_batch_size: Option<usize>,
+    /// Only for the backends that acknowledge deliveries
+    pub max_in_flight: Option<usize>,
+    #[serde(default = "default_max_queued")]
+    pub max_queued: usize,
+}
+
+fn default_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_max_queued() -> usize {
+    1000
+}
+
+pub struct Batcher {
+    config: FlushConfig,
+    seed: u64,
+    flushes: u64,
+    buffer: Vec<Measurement>,
+    queue: VecDeque<Vec<Measurement>>,
+    in_flight: usize,
+    backend: Recipient<Message>,
+    deliver: Option<Recipient<Deliver>>,
+}
+
+impl Batcher {
+    /// `deliver` is the same backend as `backend`, if it acknowledges
+    /// deliveries
+    pub fn launch(
+        config: FlushConfig,
+        pipeline: &str,
+        backend: Recipient<Message>,
+        deliver: Option<Recipient<Deliver>>,
+    ) -> Recipient<Message> {
+        let mut hasher = MetroHash64::new();
+        hasher.write(redbpf::uname::get_fqdn().unwrap_or_default().as_bytes());
+        hasher.write(pipeline.as_bytes());
+        let deliver = deliver.filter(|_| config.max_in_flight.is_some());
+
+        Batcher {
+            config,
+            seed: hasher.finish(),
+            flushes: 0,
+            buffer: vec![],
+            queue: VecDeque::new(),
+            in_flight: 0,
+            backend,
+            deliver,
+        }
+        .start()
+        .recipient()
+    }
+
+    fn schedule(&mut self, ctx: &mut Context<Self>) {
+        let delay = Duration::from_millis(self.config.interval_ms * shedding::flush_factor())
+            + jitter(self.seed, self.flushes, self.config.jitter_ms);
+        self.flushes += 1;
+        ctx.run_later(delay, |act, ctx| {
+            act.flush(ctx);
+            act.schedule(ctx);
+        });
+    }
+
+    fn flush(&mut self, ctx: &mut Context<Self>) {
+        if !self.buffer.is_empty() {
+            let batch = mem::replace(&mut self.buffer, vec![]);
+            self.enqueue(batch);
+        }
+        self.send(ctx);
+    }
+
+    fn enqueue(&mut self, batch: Vec<Measurement>) {
+        self.queue.push_back(batch);
+        if self.queue.len() > self.config.max_queued.max(1) {
+            self.queue.pop_front();
+            warn!("Dropped a batch waiting for the backend");
+        }
+    }
+
+    fn send(&mut self, ctx: &mut Context<Self>) {
+        let deliver = match self.deliver {
+            Some(ref deliver) => deliver.clone(),
+            None => {
+                for batch in self.queue.drain(..) {
+                    let _ = self.backend.do_send(Message::List(batch));
+                }
+                return;
+            }
+        };
+
+        let max_in_flight = self.config.max_in_flight.unwrap_or(1).max(1);
+        while self.in_flight < max_in_flight {
+            let batch = match self.queue.pop_front() {
+                Some(batch) => batch,
+                None => return,
+            };
+
+            self.in_flight += 1;
+            ctx.spawn(
+                deliver
+                    .send(Deliver(batch))
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        act.in_flight -= 1;
+                        if let Ok(Err(())) = res {
+                            warn!("The backend failed to deliver a batch");
+                        }
+                        act.send(ctx);
+
+                        fut::ok(())
+                    }),
+            );
+        }
+    }
+}
+
+impl Actor for Batcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.schedule(ctx);
+    }
+}
+
+impl Handler<Message> for Batcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, ctx: &mut Context<Self>) -> Self::Result {
+        match msg {
+            Message::Single(m) => self.buffer.push(m),
+            Message::List(ms) => self.buffer.extend(ms),
+            Message::Flush => {
+                self.flush(ctx);
+                let _ = self.backend.do_send(Message::Flush);
+                return;
+            }
+        }
+
+        if let Some(max) = self.config.max_batch_size {
+            let max = max.max(1);
+            while self.buffer.len() >= max {
+                let rest = self.buffer.split_off(max);
+                let batch = mem::replace(&mut self.buffer, rest);
+                self.enqueue(batch);
+            }
+            self.send(ctx);
+        }
+    }
+}
+
+/// A delay of up to `max_ms`, different for every seed and flush
+fn jitter(seed: u64, flush: u64, max_ms: u64) -> Duration {
+    if max_ms == 0 {
+        return Duration::from_millis(0);
+    }
+
+    let mut hasher = MetroHash64::with_seed(seed);
+    hasher.write_u64(flush);
+    Duration::from_millis(hasher.finish() % max_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitters() {
+        assert_eq!(jitter(1, 0, 0), Duration::from_millis(0));
+
+        let delays = (0..100).map(|f| jitter(1, f, 1000)).collect::<Vec<_>>();
+        assert!(delays.iter().all(|d| *d < Duration::from_millis(1000)));
+        assert!(delays.windows(2).any(|w| w[0] != w[1]));
+        assert_ne!(
+            (0..10).map(|f| jitter(2, f, 1000)).collect::<Vec<_>>(),
+            delays[..10].to_vec()
+        );
+    }
+}
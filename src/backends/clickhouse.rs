@@ -0,0 +1,366 @@
+//! Inserts measurements into a ClickHouse table over its HTTP interface.
+//!
+//! Every message is inserted as one batch, in the `JSONEachRow` or
+//! `RowBinary` format, into the columns:
+//!
+//! * `timestamp`: `DateTime64(9)`
+//! * `name`: `String`
+//! * `kind`: `UInt16`
+//! * `value`: `Float64`
+//! * a `String` column for every tag in `columns`, empty when the tag is
+//!   missing
+//! * `tags_column`, if set: the other tags, as a `Map(String, String)`
+//!
+//! Failed inserts are retried up to `max_retries` times, waiting twice as
+//! long before every retry.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use bytes::Bytes;
+use futures::{finished, Future};
+use hyper::{header, Body, Client, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use serde_json::{json, Map, Value};
+
+use crate::backends::proxy::{ProxyConfig, ProxyConnector};
+use crate::backends::tls::{check_scheme, https_connector, TlsConfig};
+use crate::backends::{url_encode, Message};
+use crate::metrics::Measurement;
+use crate::secrets::Secret;
+use crate::telemetry::BackendStats;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClickHouseConfig {
+    /// Like `http://localhost:8123`
+    url: String,
+    table: String,
+    database: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+    password_file: Option<String>,
+    #[serde(default)]
+    format: RowFormat,
+    /// The column each tag is stored in
+    #[serde(default)]
+    columns: BTreeMap<String, String>,
+    tags_column: Option<String>,
+    /// Lets the server buffer inserts, and acknowledge them before they're
+    /// written
+    #[serde(default)]
+    async_insert: bool,
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    #[serde(default = "default_retry_interval_ms")]
+    retry_interval_ms: u64,
+    tls: Option<TlsConfig>,
+    proxy: Option<ProxyConfig>,
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_interval_ms() -> u64 {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RowFormat {
+    JSONEachRow,
+    RowBinary,
+}
+
+impl Default for RowFormat {
+    fn default() -> RowFormat {
+        RowFormat::JSONEachRow
+    }
+}
+
+pub struct ClickHouse {
+    config: ClickHouseConfig,
+    uri: Uri,
+    password: Option<Secret>,
+    client: Client<HttpsConnector<ProxyConnector>>,
+    stats: Arc<BackendStats>,
+}
+
+impl ClickHouse {
+    pub fn new(config: ClickHouseConfig, stats: Arc<BackendStats>) -> ClickHouse {
+        let uri = insert_uri(&config)
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid ClickHouse URL {}: {}", config.url, e));
+        let password = Secret::from_config(
+            "password",
+            config.password.as_ref(),
+            config.password_file.as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("Invalid ClickHouse password: {}", e));
+        check_scheme(&config.url, config.tls.as_ref())
+            .unwrap_or_else(|e| panic!("Invalid ClickHouse settings: {}", e));
+        let connector = https_connector(4, config.tls.as_ref(), config.proxy.as_ref())
+            .unwrap_or_else(|e| panic!("Invalid ClickHouse TLS or proxy settings: {}", e));
+
+        ClickHouse {
+            config,
+            uri,
+            password,
+            client: Client::builder().build(connector),
+            stats,
+        }
+    }
+
+    fn insert(&mut self, body: Bytes, attempt: u32, ctx: &mut Context<Self>) {
+        let mut req = Request::new(Body::from(body.clone()));
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = self.uri.clone();
+        let headers = req.headers_mut();
+        if let Some(ref user) = self.config.user {
+            headers.insert("X-ClickHouse-User", user.parse().unwrap());
+        }
+        if let Some(ref password) = self.password {
+            headers.insert("X-ClickHouse-Key", password.get().parse().unwrap());
+        }
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/octet-stream".parse().unwrap(),
+        );
+
+        let request = self.stats.request();
+        let response = self
+            .client
+            .request(req)
+            .then(|res| finished::<_, ()>(res.map(|r| r.status().is_success()).unwrap_or(false)));
+        ctx.spawn(response.into_actor(self).map(move |success, act, ctx| {
+            request.finish(success);
+            if success {
+                return;
+            }
+
+            if attempt < act.config.max_retries {
+                let delay = Duration::from_millis(act.config.retry_interval_ms << attempt);
+                ctx.run_later(delay, move |act, ctx| act.insert(body, attempt + 1, ctx));
+            } else {
+                error!(
+                    "Failed to insert into {} after {} attempts",
+                    act.config.table,
+                    attempt + 1
+                );
+            }
+        }));
+    }
+}
+
+impl Actor for ClickHouse {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for ClickHouse {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, ctx: &mut Context<Self>) -> Self::Result {
+        let measurements = match msg {
+            Message::Single(m) => vec![m],
+            Message::List(ms) => ms,
+            Message::Flush => return self.stats.flushed(),
+        };
+        self.stats.received();
+
+        let body = match self.config.format {
+            RowFormat::JSONEachRow => json_rows(&self.config, &measurements),
+            RowFormat::RowBinary => binary_rows(&self.config, &measurements),
+        };
+        self.insert(body.into(), 0, ctx);
+    }
+}
+
+fn insert_uri(config: &ClickHouseConfig) -> String {
+    let mut columns = vec!["timestamp", "name", "kind", "value"];
+    columns.extend(config.columns.values().map(String::as_str));
+    columns.extend(config.tags_column.as_ref().map(String::as_str));
+
+    let table = match config.database {
+        Some(ref database) => format!("{}.{}", database, config.table),
+        None => config.table.clone(),
+    };
+    let query = format!(
+        "INSERT INTO {} ({}) FORMAT {:?}",
+        table,
+        columns.join(", "),
+        config.format
+    );
+
+    let mut uri = format!(
+        "{}/?query={}",
+        config.url.trim_end_matches('/'),
+        url_encode(&query)
+    );
+    if config.async_insert {
+        uri.push_str("&async_insert=1&wait_for_async_insert=0");
+    }
+
+    uri
+}
+
+/// The values of the mapped tags, in the order of the columns, and the
+/// other tags
+fn split_tags<'a>(
+    config: &'a ClickHouseConfig,
+    m: &'a Measurement,
+) -> (Vec<&'a str>, Vec<(&'a str, &'a str)>) {
+    let mapped = config
+        .columns
+        .keys()
+        .map(|tag| m.tags.get(tag).unwrap_or_default())
+        .collect();
+    let rest = m
+        .tags
+        .iter()
+        .filter(|(k, _)| !config.columns.contains_key(k.as_str()))
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+
+    (mapped, rest)
+}
+
+fn json_rows(config: &ClickHouseConfig, measurements: &[Measurement]) -> Vec<u8> {
+    let mut body = vec![];
+    for m in measurements {
+        let mut row = Map::new();
+        row.insert("timestamp".to_string(), m.timestamp.into());
+        row.insert("name".to_string(), m.name.clone().into());
+        row.insert("kind".to_string(), m.kind.into());
+        row.insert("value".to_string(), json!(m.value.get_f64()));
+
+        let (mapped, rest) = split_tags(config, m);
+        for (column, value) in config.columns.values().zip(mapped) {
+            row.insert(column.clone(), value.into());
+        }
+        if let Some(ref column) = config.tags_column {
+            let tags = rest
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), Value::from(v)))
+                .collect::<Map<_, _>>();
+            row.insert(column.clone(), tags.into());
+        }
+
+        serde_json::to_writer(&mut body, &Value::Object(row)).unwrap();
+        body.push(b'\n');
+    }
+
+    body
+}
+
+fn binary_rows(config: &ClickHouseConfig, measurements: &[Measurement]) -> Vec<u8> {
+    let mut body = vec![];
+    for m in measurements {
+        body.extend_from_slice(&(m.timestamp as i64).to_le_bytes());
+        write_string(&mut body, &m.name);
+        body.extend_from_slice(&m.kind.to_le_bytes());
+        body.extend_from_slice(&m.value.get_f64().to_bits().to_le_bytes());
+
+        let (mapped, rest) = split_tags(config, m);
+        for value in mapped {
+            write_string(&mut body, value);
+        }
+        if config.tags_column.is_some() {
+            write_varint(&mut body, rest.len() as u64);
+            for (k, v) in rest {
+                write_string(&mut body, k);
+                write_string(&mut body, v);
+            }
+        }
+    }
+
+    body
+}
+
+fn write_string(body: &mut Vec<u8>, s: &str) {
+    write_varint(body, s.len() as u64);
+    body.extend_from_slice(s.as_bytes());
+}
+
+/// Writes a LEB128 encoded integer
+fn write_varint(body: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            body.push(byte);
+            return;
+        }
+        body.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::kind::COUNTER;
+    use crate::metrics::{Tags, Unit};
+
+    fn config(format: &str) -> ClickHouseConfig {
+        toml::from_str(&format!(
+            r#"
+url = "http://localhost:8123/"
+database = "ingraind"
+table = "measurements"
+format = "{}"
+tags_column = "tags"
+async_insert = true
+
+[columns]
+process_id = "pid"
+"#,
+            format
+        ))
+        .unwrap()
+    }
+
+    fn measurement() -> Measurement {
+        let mut tags = Tags::new();
+        tags.insert("process_id", "42");
+        tags.insert("d_ip", "1.1.1.1");
+        Measurement::with_timestamp(
+            1,
+            COUNTER,
+            "connection.out".to_string(),
+            Unit::Count(3),
+            tags,
+        )
+    }
+
+    #[test]
+    fn query() {
+        assert_eq!(
+            insert_uri(&config("RowBinary")),
+            "http://localhost:8123/?query=INSERT%20INTO%20ingraind.measurements%20\
+             %28timestamp%2C%20name%2C%20kind%2C%20value%2C%20pid%2C%20tags%29%20\
+             FORMAT%20RowBinary&async_insert=1&wait_for_async_insert=0"
+        );
+    }
+
+    #[test]
+    fn rows() {
+        let json = json_rows(&config("JSONEachRow"), &[measurement()]);
+        let row: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(row["pid"], "42");
+        assert_eq!(row["value"], 3.0);
+        assert_eq!(row["tags"], json!({"d_ip": "1.1.1.1"}));
+        assert_eq!(json.last(), Some(&b'\n'));
+
+        let binary = binary_rows(&config("RowBinary"), &[measurement()]);
+        let mut expected = vec![1, 0, 0, 0, 0, 0, 0, 0, 14];
+        expected.extend_from_slice(b"connection.out");
+        expected.extend_from_slice(&[COUNTER as u8, 0]);
+        expected.extend_from_slice(&3f64.to_bits().to_le_bytes());
+        expected.extend_from_slice(b"\x0242\x01\x04d_ip\x071.1.1.1");
+        assert_eq!(binary, expected);
+
+        let mut varint = vec![];
+        write_varint(&mut varint, 300);
+        assert_eq!(varint, vec![0xac, 0x02]);
+    }
+}
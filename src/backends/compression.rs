@@ -0,0 +1,117 @@
+//! Compresses encoded batches before a backend sends them.
+//!
+//! Set in the config of a backend as
+//! `compression = { algorithm = "gzip", level = 9 }`. The level defaults to
+//! 6 for gzip, and 3 for zstd.
+
+#[cfg(feature = "gzip-compression")]
+use std::io::Write;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    None,
+    #[cfg(feature = "gzip-compression")]
+    Gzip,
+    #[cfg(feature = "zstd-compression")]
+    Zstd,
+}
+
+impl Default for Algorithm {
+    fn default() -> Algorithm {
+        Algorithm::None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Compression {
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    pub level: Option<i32>,
+}
+
+impl Compression {
+    pub fn compress(&self, data: Vec<u8>) -> Vec<u8> {
+        match self.algorithm {
+            Algorithm::None => data,
+            #[cfg(feature = "gzip-compression")]
+            Algorithm::Gzip => {
+                let level = flate2::Compression::new(self.level.unwrap_or(6) as u32);
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap()
+            }
+            #[cfg(feature = "zstd-compression")]
+            Algorithm::Zstd => {
+                zstd::stream::encode_all(&data[..], self.level.unwrap_or(3)).unwrap()
+            }
+        }
+    }
+
+    /// The `Content-Encoding` of compressed batches
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self.algorithm {
+            Algorithm::None => None,
+            #[cfg(feature = "gzip-compression")]
+            Algorithm::Gzip => Some("gzip"),
+            #[cfg(feature = "zstd-compression")]
+            Algorithm::Zstd => Some("zstd"),
+        }
+    }
+
+    /// The file extension of compressed batches, with the leading `.`
+    pub fn extension(&self) -> &'static str {
+        match self.algorithm {
+            Algorithm::None => "",
+            #[cfg(feature = "gzip-compression")]
+            Algorithm::Gzip => ".gz",
+            #[cfg(feature = "zstd-compression")]
+            Algorithm::Zstd => ".zst",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn none() {
+        let compression = Compression::default();
+        assert_eq!(compression.compress(b"abc".to_vec()), b"abc");
+        assert_eq!(compression.content_encoding(), None);
+    }
+
+    #[cfg(feature = "gzip-compression")]
+    #[test]
+    fn gzip() {
+        use std::io::Read;
+
+        let compression = Compression {
+            algorithm: Algorithm::Gzip,
+            level: None,
+        };
+        let data = br#"{"name":"connection.out","value":1}"#.repeat(100);
+        let compressed = compression.compress(data.clone());
+        assert!(compressed.len() * 10 < data.len());
+
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[cfg(feature = "zstd-compression")]
+    #[test]
+    fn zstd() {
+        let compression = Compression {
+            algorithm: Algorithm::Zstd,
+            level: Some(19),
+        };
+        let data = br#"{"name":"connection.out","value":1}"#.repeat(100);
+        let compressed = compression.compress(data.clone());
+        assert!(compressed.len() * 10 < data.len());
+        assert_eq!(zstd::stream::decode_all(&compressed[..]).unwrap(), data);
+    }
+}
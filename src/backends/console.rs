@@ -1,9 +1,18 @@
 use ::actix::prelude::*;
 use crate::backends::Message;
 use crate::backends::encoders::measurement_to_json;
+use crate::telemetry::BackendStats;
+use std::sync::Arc;
 
-#[derive(Default)]
-pub struct Console;
+pub struct Console {
+    stats: Arc<BackendStats>,
+}
+
+impl Console {
+    pub fn new(stats: Arc<BackendStats>) -> Console {
+        Console { stats }
+    }
+}
 
 impl Actor for Console {
     type Context = Context<Self>;
@@ -16,10 +25,14 @@ impl Handler<Message> for Console {
         let mut measurements = match msg {
             Message::Single(m) => vec![m],
             Message::List(ms) => ms,
+            Message::Flush => return self.stats.flushed(),
         };
+        self.stats.received();
 
+	let request = self.stats.request();
 	for m in measurements.drain(..) {
             println!("{}", String::from_utf8(measurement_to_json(m)).unwrap());
 	}
+	request.finish(true);
     }
 }
@@ -0,0 +1,407 @@
+//! Drops measurements that a backend was already sent, like the batches of a
+//! write-ahead log that are sent again after a restart.
+//!
+//! Measurements are identified by a hash of their name, their tags, their
+//! value and their timestamp. The hashes of the last `capacity` measurements sent in the
+//! last `window_s` seconds are kept in a ring, which is saved to `path`
+//! every `sync_interval_s` and when the pipeline is flushed, and loaded
+//! again on startup.
+//!
+//! Deliveries of backends that acknowledge them only remember the
+//! measurements once the backend accepted them, so a failed batch can be
+//! sent again.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use actix::fut;
+use actix::prelude::*;
+use failure::{format_err, Error};
+use metrohash::MetroHash64;
+
+use crate::backends::{Deliver, Message};
+use crate::metrics::{clock, Measurement, Unit};
+
+/// The hash and the time it was sent at
+const ENTRY_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DedupConfig {
+    path: String,
+    #[serde(default = "default_window_s")]
+    window_s: u64,
+    #[serde(default = "default_capacity")]
+    capacity: usize,
+    #[serde(default = "default_sync_interval_s")]
+    sync_interval_s: u64,
+}
+
+fn default_window_s() -> u64 {
+    3600
+}
+
+fn default_capacity() -> usize {
+    100_000
+}
+
+fn default_sync_interval_s() -> u64 {
+    5
+}
+
+/// A hash of the name, tags, value and timestamp of `m`, that's the same
+/// across restarts
+pub fn content_hash(m: &Measurement) -> u64 {
+    let mut tags: Vec<_> = m
+        .tags
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    tags.sort();
+
+    let mut hasher = MetroHash64::new();
+    hasher.write(m.name.as_bytes());
+    hasher.write_u8(0);
+    for (k, v) in tags {
+        hasher.write(k.as_bytes());
+        hasher.write_u8(0);
+        hasher.write(v.as_bytes());
+        hasher.write_u8(0);
+    }
+    hash_value(&mut hasher, &m.value);
+    hasher.write_u64(m.timestamp);
+    hasher.finish()
+}
+
+fn hash_value(hasher: &mut MetroHash64, value: &Unit) {
+    match value {
+        Unit::Byte(x) => {
+            hasher.write_u8(1);
+            hasher.write_u64(*x);
+        }
+        Unit::Count(x) => {
+            hasher.write_u8(2);
+            hasher.write_u64(*x);
+        }
+        Unit::Gauge(x) => {
+            hasher.write_u8(3);
+            hasher.write_u64(x.to_bits());
+        }
+        Unit::Duration(x) => {
+            hasher.write_u8(4);
+            hasher.write_u64(*x);
+        }
+        Unit::Str(s) => {
+            hasher.write_u8(5);
+            hasher.write_u64(s.len() as u64);
+            hasher.write(s.as_bytes());
+        }
+        Unit::Raw(bytes) => {
+            hasher.write_u8(6);
+            hasher.write_u64(bytes.len() as u64);
+            hasher.write(bytes);
+        }
+        Unit::Histogram(buckets) => {
+            hasher.write_u8(7);
+            hasher.write_u64(buckets.bounds.len() as u64);
+            for (bound, count) in buckets.bounds.iter().zip(buckets.counts.iter()) {
+                hasher.write_u64(*bound);
+                hasher.write_u64(*count);
+            }
+            hasher.write_u64(buckets.sum);
+        }
+    }
+}
+
+/// The hashes sent recently, oldest first
+pub struct Ring {
+    capacity: usize,
+    window_ns: u64,
+    entries: VecDeque<(u64, u64)>,
+    sent: HashMap<u64, u64>,
+}
+
+impl Ring {
+    pub fn new(capacity: usize, window_s: u64) -> Ring {
+        Ring {
+            capacity,
+            window_ns: window_s * 1_000_000_000,
+            entries: VecDeque::new(),
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Loads the ring saved at `path`, or an empty one if there's none
+    pub fn load(path: &Path, capacity: usize, window_s: u64, now: u64) -> Result<Ring, Error> {
+        let mut ring = Ring::new(capacity, window_s);
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ring),
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in data.chunks_exact(ENTRY_LEN) {
+            let mut hash = [0u8; 8];
+            let mut sent_at = [0u8; 8];
+            hash.copy_from_slice(&entry[..8]);
+            sent_at.copy_from_slice(&entry[8..]);
+            ring.push(u64::from_le_bytes(hash), u64::from_le_bytes(sent_at));
+        }
+        ring.expire(now);
+
+        Ok(ring)
+    }
+
+    /// Writes the ring to `path`, through a temporary file
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut data = Vec::with_capacity(self.entries.len() * ENTRY_LEN);
+        for (hash, sent_at) in self.entries.iter() {
+            data.extend_from_slice(&hash.to_le_bytes());
+            data.extend_from_slice(&sent_at.to_le_bytes());
+        }
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether `hash` was sent within the window before `now`
+    pub fn contains(&self, hash: u64, now: u64) -> bool {
+        match self.sent.get(&hash) {
+            Some(sent_at) => now.saturating_sub(*sent_at) <= self.window_ns,
+            None => false,
+        }
+    }
+
+    /// Remembers that `hash` was sent at `now`
+    pub fn push(&mut self, hash: u64, now: u64) {
+        self.entries.push_back((hash, now));
+        self.sent.insert(hash, now);
+        while self.entries.len() > self.capacity {
+            self.pop();
+        }
+    }
+
+    /// Forgets the hashes sent before the window
+    pub fn expire(&mut self, now: u64) {
+        while let Some(&(_, sent_at)) = self.entries.front() {
+            if now.saturating_sub(sent_at) <= self.window_ns {
+                break;
+            }
+            self.pop();
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some((hash, sent_at)) = self.entries.pop_front() {
+            // only if it wasn't sent again since
+            if self.sent.get(&hash) == Some(&sent_at) {
+                self.sent.remove(&hash);
+            }
+        }
+    }
+}
+
+/// Sits in front of a backend, and drops the measurements it was sent
+pub struct Dedup {
+    path: PathBuf,
+    ring: Ring,
+    sync_interval: Duration,
+    backend: Recipient<Message>,
+    deliver: Option<Recipient<Deliver>>,
+}
+
+impl Dedup {
+    /// `deliver` is the same backend as `backend`, if it acknowledges
+    /// deliveries
+    pub fn launch(
+        config: DedupConfig,
+        backend: Recipient<Message>,
+        deliver: Option<Recipient<Deliver>>,
+    ) -> Result<(Recipient<Message>, Option<Recipient<Deliver>>), Error> {
+        let path = PathBuf::from(&config.path);
+        let ring = Ring::load(&path, config.capacity, config.window_s, clock::wall_now())
+            .map_err(|e| format_err!("Failed to load deduplication ring {}: {}", config.path, e))?;
+        let acknowledges = deliver.is_some();
+
+        let addr = Dedup {
+            path,
+            ring,
+            sync_interval: Duration::from_secs(config.sync_interval_s),
+            backend,
+            deliver,
+        }
+        .start();
+        let deliver = if acknowledges {
+            Some(addr.clone().recipient())
+        } else {
+            None
+        };
+
+        Ok((addr.recipient(), deliver))
+    }
+
+    /// The measurements that weren't sent yet, and their hashes
+    fn unsent(&mut self, measurements: Vec<Measurement>) -> (Vec<Measurement>, HashSet<u64>) {
+        let now = clock::wall_now();
+        self.ring.expire(now);
+
+        let mut hashes = HashSet::with_capacity(measurements.len());
+        let ring = &self.ring;
+        let unsent = measurements
+            .into_iter()
+            // duplicates in the same batch too
+            .filter(|m| {
+                let hash = content_hash(m);
+                !ring.contains(hash, now) && hashes.insert(hash)
+            })
+            .collect::<Vec<_>>();
+
+        (unsent, hashes)
+    }
+
+    fn remember(&mut self, hashes: HashSet<u64>) {
+        let now = clock::wall_now();
+        for hash in hashes {
+            self.ring.push(hash, now);
+        }
+    }
+
+    fn save(&self) {
+        if let Err(e) = self.ring.save(&self.path) {
+            error!(
+                "Failed to save deduplication ring {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl Actor for Dedup {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.sync_interval, |act, _ctx| act.save());
+    }
+}
+
+impl Handler<Message> for Dedup {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let measurements = match msg {
+            Message::Single(m) => vec![m],
+            Message::List(ms) => ms,
+            Message::Flush => {
+                self.save();
+                return self.backend.do_send(Message::Flush).unwrap();
+            }
+        };
+
+        let total = measurements.len();
+        let (unsent, hashes) = self.unsent(measurements);
+        if unsent.len() < total {
+            debug!("Dropped {} duplicate measurements", total - unsent.len());
+        }
+        if unsent.is_empty() {
+            return;
+        }
+
+        self.remember(hashes);
+        self.backend.do_send(Message::List(unsent)).unwrap();
+    }
+}
+
+impl Handler<Deliver> for Dedup {
+    type Result = ResponseActFuture<Self, (), ()>;
+
+    fn handle(&mut self, msg: Deliver, _ctx: &mut Context<Self>) -> Self::Result {
+        let deliver = match self.deliver {
+            Some(ref deliver) => deliver.clone(),
+            None => return Box::new(fut::err(())),
+        };
+        let (unsent, hashes) = self.unsent(msg.0);
+        if unsent.is_empty() {
+            return Box::new(fut::ok(()));
+        }
+
+        Box::new(deliver.send(Deliver(unsent)).into_actor(self).then(
+            move |res, act, _ctx| match res {
+                Ok(Ok(())) => {
+                    act.remember(hashes);
+                    fut::ok(())
+                }
+                _ => fut::err(()),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{kind, Tags};
+    use std::env;
+    use std::process;
+
+    fn measurement(timestamp: u64, tags: &[(&str, &str)]) -> Measurement {
+        Measurement::with_timestamp(
+            timestamp,
+            kind::COUNTER,
+            "connection.out".to_string(),
+            Unit::Count(1),
+            tags.iter().cloned().collect::<Tags>(),
+        )
+    }
+
+    #[test]
+    fn hashes() {
+        let m = measurement(1, &[("d_ip", "1.1.1.1"), ("d_port", "443")]);
+        let reordered = measurement(1, &[("d_port", "443"), ("d_ip", "1.1.1.1")]);
+        assert_eq!(content_hash(&m), content_hash(&reordered));
+        assert_ne!(content_hash(&m), content_hash(&measurement(2, &[])));
+
+        let mut other_value = m.clone();
+        other_value.value = Unit::Count(2);
+        assert_ne!(content_hash(&m), content_hash(&other_value));
+        assert_ne!(
+            content_hash(&measurement(1, &[("a", "bc")])),
+            content_hash(&measurement(1, &[("ab", "c")]))
+        );
+    }
+
+    #[test]
+    fn ring() {
+        let second = 1_000_000_000;
+        let mut ring = Ring::new(3, 10);
+        for hash in 1..=4 {
+            ring.push(hash, hash * second);
+        }
+        assert_eq!(ring.len(), 3);
+        assert!(!ring.contains(1, 4 * second));
+        assert!(ring.contains(2, 4 * second));
+        assert!(!ring.contains(2, 13 * second));
+
+        let path = env::temp_dir().join(format!("ingraind-dedup-{}", process::id()));
+        ring.save(&path).unwrap();
+        let loaded = Ring::load(&path, 3, 10, 13 * second).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.contains(3, 13 * second));
+        assert!(loaded.contains(4, 13 * second));
+        fs::remove_file(&path).unwrap();
+
+        assert!(Ring::load(&path, 3, 10, 0).unwrap().is_empty());
+    }
+}
@@ -1,14 +1,27 @@
 use std::collections::HashMap;
 
-use serde_json;
+use serde_json::{self, Map};
 
 use super::{Kind, Measurement, Unit};
+use crate::metrics::{Buckets, TagValue, Tags};
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug)]
 pub enum Encoding {
     JSON,
     #[cfg(feature = "capnp")]
     Capnp,
+    #[cfg(feature = "msgpack-encoding")]
+    MsgPack,
+    #[cfg(feature = "cbor-encoding")]
+    CBOR,
+    #[cfg(feature = "protobuf-encoding")]
+    Protobuf,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::JSON
+    }
 }
 
 impl Encoding {
@@ -16,8 +29,251 @@ impl Encoding {
         match self {
             Encoding::JSON => to_json(measurements),
             #[cfg(feature = "capnp")]
-            Encoding::Capnp => to_capnp(measurements)
+            Encoding::Capnp => to_capnp(measurements),
+            #[cfg(feature = "msgpack-encoding")]
+            Encoding::MsgPack => to_msgpack(measurements),
+            #[cfg(feature = "cbor-encoding")]
+            Encoding::CBOR => to_cbor(measurements),
+            #[cfg(feature = "protobuf-encoding")]
+            Encoding::Protobuf => to_protobuf(measurements),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::JSON => "application/json",
+            #[cfg(feature = "capnp")]
+            Encoding::Capnp => "application/octet-stream",
+            #[cfg(feature = "msgpack-encoding")]
+            Encoding::MsgPack => "application/msgpack",
+            #[cfg(feature = "cbor-encoding")]
+            Encoding::CBOR => "application/cbor",
+            #[cfg(feature = "protobuf-encoding")]
+            Encoding::Protobuf => "application/x-protobuf",
+        }
+    }
+}
+
+/// Selects, renames and lays out the fields of encoded measurements.
+///
+/// The schemas of Cap'n Proto and Protobuf are fixed, so only the selection
+/// of fields and tags, and the names of tags apply to them. The fields left
+/// out are sent empty.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Fields {
+    /// Fields to include, all of them if empty
+    #[serde(default)]
+    include: Vec<String>,
+    /// Tags to include, all of them if empty
+    #[serde(default)]
+    include_tags: Vec<String>,
+    /// New names of fields and tags
+    #[serde(default)]
+    rename: HashMap<String, String>,
+    #[serde(default)]
+    tags: TagLayout,
+    /// Turns dotted names into nested objects, so `destination.ip` becomes
+    /// `{"destination": {"ip": ...}}`
+    #[serde(default)]
+    expand_dots: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TagLayout {
+    /// Tags are an object in the `tags` field
+    Nested,
+    /// Tags are fields of the measurement
+    Flat,
+}
+
+impl Default for TagLayout {
+    fn default() -> TagLayout {
+        TagLayout::Nested
+    }
+}
+
+impl Fields {
+    fn is_default(&self) -> bool {
+        self.include.is_empty()
+            && self.include_tags.is_empty()
+            && self.rename.is_empty()
+            && self.tags == TagLayout::Nested
+            && !self.expand_dots
+    }
+
+    fn included(&self, name: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|i| i == name)
+    }
+
+    fn tag_included(&self, name: &str) -> bool {
+        self.include_tags.is_empty() || self.include_tags.iter().any(|i| i == name)
+    }
+
+    fn name(&self, name: &str) -> String {
+        self.rename
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    fn apply(&self, m: SerializedMeasurement) -> serde_json::Value {
+        let mut object = Map::new();
+        let mut fields = vec![
+            ("timestamp", m.timestamp.into()),
+            ("kind", m.kind.into()),
+            ("name", m.name.into()),
+            ("measurement", serde_json::to_value(m.measurement).unwrap()),
+        ];
+        if let Some(sequence) = m.sequence {
+            fields.push(("sequence", sequence.into()));
+        }
+        for (name, value) in fields {
+            if self.included(name) {
+                object.insert(self.name(name), value);
+            }
+        }
+
+        if self.included("tags") {
+            let tags = m
+                .tags
+                .into_iter()
+                .filter(|(k, _)| self.tag_included(k))
+                .map(|(k, v)| (self.name(&k), serde_json::to_value(v).unwrap()));
+            match self.tags {
+                TagLayout::Nested => {
+                    object.insert(self.name("tags"), tags.collect::<Map<_, _>>().into());
+                }
+                TagLayout::Flat => object.extend(tags),
+            }
+        }
+
+        let value = object.into();
+        if self.expand_dots {
+            expand_dots(value)
+        } else {
+            value
+        }
+    }
+
+    fn serialize(&self, measurements: &[Measurement]) -> Vec<serde_json::Value> {
+        measurements
+            .iter()
+            .map(|m| self.apply(SerializedMeasurement::from(m)))
+            .collect()
+    }
+
+    /// A copy of the measurement with the fields left out emptied, and the
+    /// selected tags renamed, for encodings with fixed schemas
+    fn select(&self, m: &Measurement) -> Measurement {
+        let mut tags = Tags::new();
+        if self.included("tags") {
+            for (k, v) in m.tags.iter() {
+                if self.tag_included(k) {
+                    tags.insert(self.name(k), v.clone());
+                }
+            }
+        }
+
+        Measurement {
+            timestamp: if self.included("timestamp") {
+                m.timestamp
+            } else {
+                0
+            },
+            kind: if self.included("kind") { m.kind } else { 0 },
+            name: if self.included("name") {
+                m.name.clone()
+            } else {
+                String::new()
+            },
+            value: if self.included("measurement") {
+                m.value.clone()
+            } else {
+                Unit::Count(0)
+            },
+            sequence: m.sequence.filter(|_| self.included("sequence")),
+            tags,
+            ..m.clone()
+        }
+    }
+}
+
+fn expand_dots(value: serde_json::Value) -> serde_json::Value {
+    let object = match value {
+        serde_json::Value::Object(object) => object,
+        value => return value,
+    };
+
+    let mut expanded = Map::new();
+    for (key, value) in object {
+        let value = expand_dots(value);
+        let mut path = key.split('.').rev();
+        let last = path.next().unwrap().to_string();
+        let value = path.fold((last, value), |(key, value), parent| {
+            let mut object = Map::new();
+            object.insert(key, value);
+            (parent.to_string(), object.into())
+        });
+        merge(&mut expanded, value.0, value.1);
+    }
+
+    expanded.into()
+}
+
+/// Inserts `value` at `key`, merging it into the object already there
+fn merge(object: &mut Map<String, serde_json::Value>, key: String, value: serde_json::Value) {
+    use serde_json::Value::Object;
+
+    match (object.get_mut(&key), value) {
+        (Some(Object(existing)), Object(value)) => {
+            for (k, v) in value {
+                merge(existing, k, v);
+            }
+        }
+        (_, value) => {
+            object.insert(key, value);
+        }
+    }
+}
+
+/// Encodes measurements with the selected fields
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    pub encoding: Encoding,
+    pub fields: Fields,
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding, fields: Fields) -> Encoder {
+        Encoder { encoding, fields }
+    }
+
+    pub fn encode(&self, measurements: &[Measurement]) -> Vec<u8> {
+        if self.fields.is_default() {
+            return self.encoding.encode(measurements);
         }
+
+        let fields = || self.fields.serialize(measurements);
+        match self.encoding {
+            Encoding::JSON => serde_json::to_vec(&fields()).unwrap(),
+            #[cfg(feature = "msgpack-encoding")]
+            Encoding::MsgPack => rmp_serde::to_vec_named(&fields()).unwrap(),
+            #[cfg(feature = "cbor-encoding")]
+            Encoding::CBOR => serde_cbor::to_vec(&fields()).unwrap(),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let selected = measurements
+                    .iter()
+                    .map(|m| self.fields.select(m))
+                    .collect::<Vec<_>>();
+                self.encoding.encode(&selected)
+            }
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.encoding.content_type()
     }
 }
 
@@ -36,14 +292,27 @@ pub fn to_capnp(src: &[Measurement]) -> Vec<u8> {
         m.set_timestamp(source.timestamp);
         m.set_kind(source.kind);
         m.set_name(&serialized_name(&source));
-        m.set_measurement(source.value.get() as f64);
+        m.set_measurement(source.value.get_f64());
+        m.set_sequence(source.sequence.unwrap_or(0));
 
-        let mut tags = m.init_tags(source.tags.0.len() as u32);
-        for (i, source) in source.tags.0.iter().enumerate() {
+        let mut tags = m.reborrow().init_tags(source.tags.len() as u32);
+        for (i, source) in source.tags.iter().enumerate() {
             let mut tag = tags.reborrow().get(i as u32);
             tag.set_key(&source.0);
             tag.set_value(&source.1);
         }
+
+        if let Unit::Histogram(ref source) = source.value {
+            let mut histogram = m.init_histogram();
+            histogram.set_sum(source.sum);
+
+            let mut buckets = histogram.init_buckets(source.iter().count() as u32);
+            for (i, (bound, count)) in source.iter().enumerate() {
+                let mut bucket = buckets.reborrow().get(i as u32);
+                bucket.set_bound(bound);
+                bucket.set_count(count);
+            }
+        }
     }
 
     let mut buffer = Cursor::new(Vec::new());
@@ -56,17 +325,95 @@ pub fn measurement_to_json(measurement: Measurement) -> Vec<u8> {
 }
 
 pub fn to_json(measurements: &[Measurement]) -> Vec<u8> {
-    serde_json::to_vec(&measurements.iter().map(SerializedMeasurement::from).collect::<Vec<_>>()).unwrap()
+    serde_json::to_vec(&serialized(measurements)).unwrap()
 }
 
-fn serialized_name(msg: &Measurement) -> String {
-    let type_str = match msg.value {
+/// Encodes measurements as a MessagePack array of maps, with the same fields
+/// as JSON
+#[cfg(feature = "msgpack-encoding")]
+pub fn to_msgpack(measurements: &[Measurement]) -> Vec<u8> {
+    rmp_serde::to_vec_named(&serialized(measurements)).unwrap()
+}
+
+#[cfg(feature = "cbor-encoding")]
+pub fn to_cbor(measurements: &[Measurement]) -> Vec<u8> {
+    serde_cbor::to_vec(&serialized(measurements)).unwrap()
+}
+
+/// Encodes measurements as a `Payload` message of `schema/ingraind.proto`.
+///
+/// Unlike the other encodings, the name and the unit of measurements are
+/// separate fields.
+#[cfg(feature = "protobuf-encoding")]
+pub fn to_protobuf(src: &[Measurement]) -> Vec<u8> {
+    use prost::Message;
+
+    let payload = protobuf_payload(src);
+    let mut buffer = Vec::with_capacity(payload.encoded_len());
+    payload.encode(&mut buffer).unwrap();
+    buffer
+}
+
+#[cfg(feature = "protobuf-encoding")]
+pub fn protobuf_payload(src: &[Measurement]) -> crate::ingraind_proto::Payload {
+    use crate::ingraind_proto as proto;
+    use crate::ingraind_proto::measurement::Value;
+
+    let data = src
+        .iter()
+        .map(|source| {
+            let value = match source.value {
+                Unit::Gauge(x) => Value::Float(x),
+                Unit::Histogram(ref buckets) => Value::Histogram(proto::Histogram {
+                    buckets: buckets
+                        .iter()
+                        .map(|(bound, count)| proto::Bucket { bound, count })
+                        .collect(),
+                    sum: buckets.sum,
+                }),
+                ref unit => Value::Integer(unit.get()),
+            };
+
+            proto::Measurement {
+                timestamp: source.timestamp,
+                kind: u32::from(source.kind),
+                name: source.name.clone(),
+                unit: unit_name(&source.value).to_string(),
+                value: Some(value),
+                tags: source
+                    .tags
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                sequence: source.sequence.unwrap_or(0),
+            }
+        })
+        .collect();
+
+    proto::Payload { data }
+}
+
+fn serialized(measurements: &[Measurement]) -> Vec<SerializedMeasurement> {
+    measurements
+        .iter()
+        .map(SerializedMeasurement::from)
+        .collect()
+}
+
+fn unit_name(unit: &Unit) -> &'static str {
+    match unit {
         Unit::Byte(_) => "byte",
         Unit::Count(_) => "count",
+        Unit::Gauge(_) => "gauge",
+        Unit::Duration(_) => "duration",
+        Unit::Histogram(_) => "histogram",
         Unit::Str(_) => "string",
-    };
+        Unit::Raw(_) => "raw",
+    }
+}
 
-    format!("{}_{}", &msg.name, type_str)
+fn serialized_name(msg: &Measurement) -> String {
+    format!("{}_{}", &msg.name, unit_name(&msg.value))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -74,8 +421,29 @@ struct SerializedMeasurement {
     timestamp: u64,
     pub kind: Kind,
     pub name: String,
-    pub measurement: u64,
-    pub tags: HashMap<String, String>,
+    pub measurement: Value,
+    pub tags: HashMap<String, TagValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+}
+
+/// Keeps integer measurements serialized as integers
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum Value {
+    Integer(u64),
+    Float(f64),
+    Histogram(Buckets),
+}
+
+impl From<&Unit> for Value {
+    fn from(unit: &Unit) -> Value {
+        match *unit {
+            Unit::Gauge(x) => Value::Float(x),
+            Unit::Histogram(ref buckets) => Value::Histogram(buckets.clone()),
+            _ => Value::Integer(unit.get()),
+        }
+    }
 }
 
 impl From<&Measurement> for SerializedMeasurement {
@@ -85,9 +453,91 @@ impl From<&Measurement> for SerializedMeasurement {
         SerializedMeasurement {
             timestamp: msg.timestamp,
             kind: msg.kind,
-            measurement: msg.value.get(),
-            tags: msg.tags.iter().cloned().collect(),
+            measurement: Value::from(&msg.value),
+            tags: msg
+                .tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
             name,
+            sequence: msg.sequence,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{kind, Tags};
+
+    fn measurement() -> Measurement {
+        let mut tags = Tags::new();
+        tags.insert("d_ip", "10.0.0.1");
+        tags.insert("process_str", "curl");
+
+        Measurement::with_timestamp(
+            1,
+            kind::COUNTER,
+            "connection".to_string(),
+            Unit::Count(1),
+            tags,
+        )
+    }
+
+    #[test]
+    fn fields() {
+        let fields: Fields = toml::from_str(
+            r#"
+include = ["name", "measurement", "tags"]
+include_tags = ["d_ip"]
+rename = { d_ip = "destination.ip", measurement = "value" }
+tags = "flat"
+expand_dots = true
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fields.serialize(&[measurement()])[0],
+            serde_json::json!({
+                "name": "connection_count",
+                "value": 1,
+                "destination": { "ip": "10.0.0.1" },
+            })
+        );
+    }
+
+    #[test]
+    fn nested_tags() {
+        let fields = Fields {
+            include: vec!["tags".to_string()],
+            ..Fields::default()
+        };
+
+        assert_eq!(
+            fields.serialize(&[measurement()])[0],
+            serde_json::json!({
+                "tags": { "d_ip": "10.0.0.1", "process_str": "curl" },
+            })
+        );
+    }
+
+    #[test]
+    fn select() {
+        let fields = Fields {
+            include: vec!["name".to_string(), "tags".to_string()],
+            include_tags: vec!["d_ip".to_string()],
+            rename: vec![("d_ip".to_string(), "destination.ip".to_string())]
+                .into_iter()
+                .collect(),
+            ..Fields::default()
+        };
+
+        let selected = fields.select(&measurement());
+        assert_eq!(selected.timestamp, 0);
+        assert_eq!(selected.name, "connection");
+        assert_eq!(selected.value, Unit::Count(0));
+        assert_eq!(selected.tags.len(), 1);
+        assert_eq!(selected.tags.get("destination.ip"), Some("10.0.0.1"));
+    }
+}
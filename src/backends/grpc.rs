@@ -0,0 +1,388 @@
+//! Ships measurements to a collector that implements the `IngraindIngest`
+//! service of `schema/ingraind.proto`, over a GRPC stream that stays open.
+//!
+//! Every message is sent as a batch with a sequence number, and is kept
+//! until the collector acknowledges it. At most `window` batches are sent
+//! without being acknowledged, or as many as the collector last asked for,
+//! and the others wait. When more than `max_pending` batches wait, the
+//! oldest are dropped.
+//!
+//! When the stream ends or fails, the backend connects again after
+//! `reconnect_interval_s`, and sends the batches that weren't acknowledged
+//! again.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use bytes::Bytes;
+use failure::{format_err, Error};
+use futures::sync::mpsc::{self, UnboundedSender};
+use futures::{Future, Stream};
+use hyper::{header, Body, Chunk, Client, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use prost::Message as ProtoMessage;
+
+use crate::backends::compression::Compression;
+use crate::backends::encoders::protobuf_payload;
+use crate::backends::proxy::{ProxyConfig, ProxyConnector};
+use crate::backends::tls::{check_scheme, https_connector, TlsConfig};
+use crate::backends::Message;
+use crate::ingraind_proto::{IngestAck, IngestRequest};
+use crate::telemetry::{BackendStats, Request as StatsRequest};
+
+const INGEST_PATH: &str = "/ingraind.IngraindIngest/Ingest";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GrpcConfig {
+    /// Like `https://collector:50051`. `http` URLs use HTTP/2 without TLS.
+    endpoint: String,
+    #[serde(default = "default_window")]
+    window: usize,
+    #[serde(default = "default_max_pending")]
+    max_pending: usize,
+    #[serde(default = "default_reconnect_interval_s")]
+    reconnect_interval_s: u64,
+    /// Compresses every batch, which the collector has to accept as the
+    /// `grpc-encoding`
+    #[serde(default)]
+    compression: Compression,
+    tls: Option<TlsConfig>,
+    proxy: Option<ProxyConfig>,
+}
+
+fn default_window() -> usize {
+    16
+}
+
+fn default_max_pending() -> usize {
+    1024
+}
+
+fn default_reconnect_interval_s() -> u64 {
+    5
+}
+
+/// The batches that weren't acknowledged yet, in the order they're sent
+struct Outbox<T> {
+    pending: VecDeque<(u64, T)>,
+    /// How many of the pending batches were sent
+    in_flight: usize,
+    window: usize,
+    max_pending: usize,
+    sequence: u64,
+}
+
+impl<T> Outbox<T> {
+    fn new(window: usize, max_pending: usize) -> Outbox<T> {
+        Outbox {
+            pending: VecDeque::new(),
+            in_flight: 0,
+            window: window.max(1),
+            max_pending: max_pending.max(1),
+            sequence: 0,
+        }
+    }
+
+    /// Adds a batch with the next sequence number, and returns the batches
+    /// dropped to make room for it
+    fn push(&mut self, batch: impl FnOnce(u64) -> T) -> Vec<T> {
+        self.sequence += 1;
+        self.pending
+            .push_back((self.sequence, batch(self.sequence)));
+
+        let mut dropped = vec![];
+        while self.pending.len() > self.max_pending {
+            let (_, batch) = self.pending.pop_front().unwrap();
+            self.in_flight = self.in_flight.saturating_sub(1);
+            dropped.push(batch);
+        }
+
+        dropped
+    }
+
+    /// The batches that can be sent now, and marks them as sent
+    fn ready(&mut self) -> impl Iterator<Item = &T> {
+        let start = self.in_flight;
+        self.in_flight = self.pending.len().min(self.window.max(start));
+
+        self.pending
+            .iter()
+            .skip(start)
+            .take(self.in_flight - start)
+            .map(|(_, batch)| batch)
+    }
+
+    /// Removes the batches up to and including `sequence`, and returns them
+    fn ack(&mut self, sequence: u64, window: u32) -> Vec<T> {
+        if window > 0 {
+            self.window = window as usize;
+        }
+
+        let mut acked = vec![];
+        while self.pending.front().map_or(false, |(s, _)| *s <= sequence) {
+            let (_, batch) = self.pending.pop_front().unwrap();
+            self.in_flight = self.in_flight.saturating_sub(1);
+            acked.push(batch);
+        }
+
+        acked
+    }
+
+    /// Marks every pending batch as not sent, to send them on a new stream
+    fn reset(&mut self) {
+        self.in_flight = 0;
+    }
+}
+
+pub struct Grpc {
+    config: GrpcConfig,
+    uri: Uri,
+    hostname: String,
+    client: Client<HttpsConnector<ProxyConnector>>,
+    sender: Option<UnboundedSender<Bytes>>,
+    responses: Option<SpawnHandle>,
+    received: Vec<u8>,
+    outbox: Outbox<(Bytes, StatsRequest)>,
+    stats: Arc<BackendStats>,
+}
+
+impl Grpc {
+    pub fn new(config: GrpcConfig, stats: Arc<BackendStats>) -> Grpc {
+        let uri = format!("{}{}", config.endpoint.trim_end_matches('/'), INGEST_PATH)
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid GRPC endpoint {}: {}", config.endpoint, e));
+
+        check_scheme(&config.endpoint, config.tls.as_ref())
+            .unwrap_or_else(|e| panic!("Invalid GRPC settings: {}", e));
+        let connector = https_connector(2, config.tls.as_ref(), config.proxy.as_ref())
+            .unwrap_or_else(|e| panic!("Invalid GRPC TLS or proxy settings: {}", e));
+
+        Grpc {
+            uri,
+            hostname: redbpf::uname::get_fqdn().unwrap(),
+            client: Client::builder().http2_only(true).build(connector),
+            sender: None,
+            responses: None,
+            received: vec![],
+            outbox: Outbox::new(config.window, config.max_pending),
+            config,
+            stats,
+        }
+    }
+
+    fn connect(&mut self, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.responses.take() {
+            ctx.cancel_future(handle);
+        }
+
+        let (sender, receiver) = mpsc::unbounded();
+        let body = receiver.map_err(|_| io::Error::new(io::ErrorKind::Other, "stream closed"));
+        let mut req = Request::new(Body::wrap_stream(body));
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = self.uri.clone();
+        let headers = req.headers_mut();
+        headers.insert(header::CONTENT_TYPE, "application/grpc".parse().unwrap());
+        headers.insert(header::TE, "trailers".parse().unwrap());
+        if let Some(encoding) = self.config.compression.content_encoding() {
+            headers.insert("grpc-encoding", encoding.parse().unwrap());
+        }
+
+        let responses = self
+            .client
+            .request(req)
+            .map_err(Error::from)
+            .and_then(|res| {
+                let status = res
+                    .headers()
+                    .get("grpc-status")
+                    .and_then(|s| s.to_str().ok())
+                    .unwrap_or("0");
+                if !res.status().is_success() || status != "0" {
+                    return Err(format_err!("HTTP {}, GRPC status {}", res.status(), status));
+                }
+
+                Ok(res.into_body().map_err(Error::from))
+            })
+            .flatten_stream();
+
+        self.received.clear();
+        self.sender = Some(sender);
+        self.responses = Some(ctx.add_stream(responses));
+        self.outbox.reset();
+        self.send_ready();
+    }
+
+    fn send_ready(&mut self) {
+        let sender = match self.sender {
+            Some(ref sender) => sender,
+            None => return,
+        };
+
+        for (frame, _) in self.outbox.ready() {
+            // a closed stream ends the responses too, and reconnects
+            let _ = sender.unbounded_send(frame.clone());
+        }
+    }
+
+    fn reconnect_later(&mut self, ctx: &mut Context<Self>) {
+        self.sender = None;
+        let interval = Duration::from_secs(self.config.reconnect_interval_s);
+        ctx.run_later(interval, |act, ctx| act.connect(ctx));
+    }
+}
+
+impl Actor for Grpc {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+    }
+}
+
+impl Handler<Message> for Grpc {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let measurements = match msg {
+            Message::Single(m) => vec![m],
+            Message::List(ms) => ms,
+            Message::Flush => return self.stats.flushed(),
+        };
+        self.stats.received();
+
+        let hostname = self.hostname.clone();
+        let compression = self.config.compression;
+        let request = self.stats.request();
+        let dropped = self.outbox.push(|sequence| {
+            let message = IngestRequest {
+                sequence,
+                hostname,
+                payload: Some(protobuf_payload(&measurements)),
+            };
+            (frame(&message, compression), request)
+        });
+        if !dropped.is_empty() {
+            warn!(
+                "Dropped {} batches waiting for the collector",
+                dropped.len()
+            );
+        }
+        for (_, request) in dropped {
+            request.finish(false);
+        }
+
+        self.send_ready();
+    }
+}
+
+impl StreamHandler<Chunk, Error> for Grpc {
+    fn handle(&mut self, chunk: Chunk, _ctx: &mut Context<Self>) {
+        self.received.extend_from_slice(&chunk);
+        for message in decode_frames(&mut self.received) {
+            let ack = match IngestAck::decode(&message[..]) {
+                Ok(ack) => ack,
+                Err(e) => {
+                    warn!("Invalid acknowledgement from the collector: {}", e);
+                    continue;
+                }
+            };
+
+            for (_, request) in self.outbox.ack(ack.sequence, ack.window) {
+                request.finish(true);
+            }
+        }
+
+        self.send_ready();
+    }
+
+    fn error(&mut self, err: Error, _ctx: &mut Context<Self>) -> Running {
+        warn!("GRPC stream to {} failed: {}", self.config.endpoint, err);
+        Running::Continue
+    }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        self.responses = None;
+        self.reconnect_later(ctx);
+    }
+}
+
+/// Prefixes a message with the GRPC frame header: whether it's compressed,
+/// and the length
+fn frame(message: &impl ProtoMessage, compression: Compression) -> Bytes {
+    let mut encoded = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut encoded).unwrap();
+    let compressed = compression.content_encoding().is_some();
+    let encoded = compression.compress(encoded);
+
+    let mut buf = Vec::with_capacity(5 + encoded.len());
+    buf.push(compressed as u8);
+    buf.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&encoded);
+
+    buf.into()
+}
+
+/// Takes the complete messages off the front of `buf`
+fn decode_frames(buf: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut start = 0;
+    while buf.len() - start >= 5 {
+        let header = &buf[start..start + 5];
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if buf.len() - start - 5 < len {
+            break;
+        }
+        messages.push(buf[start + 5..start + 5 + len].to_vec());
+        start += 5 + len;
+    }
+    buf.drain(..start);
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outbox() {
+        let mut outbox = Outbox::new(2, 3);
+        for _ in 0..3 {
+            assert!(outbox.push(|s| s).is_empty());
+        }
+        assert_eq!(outbox.ready().cloned().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(outbox.ready().count(), 0);
+
+        assert_eq!(outbox.ack(1, 0), vec![1]);
+        assert_eq!(outbox.ready().cloned().collect::<Vec<_>>(), vec![3]);
+
+        // full, the oldest is dropped even if it was sent
+        assert!(outbox.push(|s| s).is_empty());
+        assert_eq!(outbox.push(|s| s), vec![2]);
+        assert_eq!(outbox.ack(3, 4), vec![3]);
+        assert_eq!(outbox.ready().cloned().collect::<Vec<_>>(), vec![4, 5]);
+
+        outbox.reset();
+        assert_eq!(outbox.ready().cloned().collect::<Vec<_>>(), vec![4, 5]);
+    }
+
+    #[test]
+    fn frames() {
+        let ack = IngestAck {
+            sequence: 300,
+            window: 8,
+        };
+        let mut buf = frame(&ack, Compression::default()).to_vec();
+        assert_eq!(buf[..5], [0, 0, 0, 0, 5]);
+        buf.extend_from_slice(&buf.clone());
+        buf.extend_from_slice(&[0, 0, 0]);
+
+        let messages = decode_frames(&mut buf);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(IngestAck::decode(&messages[1][..]).unwrap(), ack);
+        assert_eq!(buf, vec![0, 0, 0]);
+    }
+}
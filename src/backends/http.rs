@@ -1,37 +1,62 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use actix::prelude::*;
+use futures::future::Either;
 use futures::{finished, Future};
-use hyper::{client::HttpConnector, header, Body, Client, HeaderMap, Method, Request, Uri};
+use hyper::{header, Body, Client, HeaderMap, Method, Request, Uri};
 use hyper_rustls::HttpsConnector;
 use rayon::prelude::*;
 
-use crate::backends::encoders::Encoding;
-use crate::backends::Message;
+use crate::backends::compression::Compression;
+use crate::backends::encoders::{Encoder, Encoding, Fields};
+use crate::backends::proxy::{ProxyConfig, ProxyConnector};
+use crate::backends::tls::{check_scheme, https_connector, TlsConfig};
+use crate::backends::{Deliver, Message};
+use crate::secrets::Secret;
+use crate::telemetry::BackendStats;
 
 pub struct HTTP {
     headers: HeaderMap,
+    secret_headers: Vec<(header::HeaderName, Secret)>,
     uri: Uri,
-    client: Client<HttpsConnector<HttpConnector>>,
-    encoding: Encoding,
+    client: Client<HttpsConnector<ProxyConnector>>,
+    encoder: Encoder,
+    compression: Compression,
     content_type: String,
-    parallel_chunk_size: usize
+    parallel_chunk_size: usize,
+    stats: Arc<BackendStats>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct HTTPConfig {
     uri: String,
     headers: HashMap<String, String>,
+    /// Headers whose values are read from files, like `Authorization`
+    #[serde(default)]
+    header_files: HashMap<String, String>,
     threads: Option<usize>,
     encoding: Option<Encoding>,
+    #[serde(default)]
+    fields: Fields,
+    #[serde(default)]
+    compression: Compression,
     parallel_chunk_size: Option<usize>,
+    tls: Option<TlsConfig>,
+    proxy: Option<ProxyConfig>,
 }
 
 impl HTTP {
-    pub fn new(config: HTTPConfig) -> HTTP {
-        let client = Client::builder()
-            .keep_alive(false)
-            .build(HttpsConnector::new(config.threads.unwrap_or(4)));
+    pub fn new(config: HTTPConfig, stats: Arc<BackendStats>) -> HTTP {
+        check_scheme(&config.uri, config.tls.as_ref())
+            .unwrap_or_else(|e| panic!("Invalid HTTP settings: {}", e));
+        let connector = https_connector(
+            config.threads.unwrap_or(4),
+            config.tls.as_ref(),
+            config.proxy.as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("Invalid HTTP TLS or proxy settings: {}", e));
+        let client = Client::builder().keep_alive(false).build(connector);
         let uri = config.uri.parse().unwrap();
 
         let headers = {
@@ -45,25 +70,69 @@ impl HTTP {
 
             headers
         };
+        let secret_headers = config
+            .header_files
+            .iter()
+            .map(|(h, file)| {
+                let secret = Secret::required(h, None, Some(file))
+                    .unwrap_or_else(|e| panic!("Invalid HTTP header: {}", e));
+                (
+                    header::HeaderName::from_bytes(h.as_bytes()).unwrap(),
+                    secret,
+                )
+            })
+            .collect();
 
-        let encoding = config.encoding.unwrap_or(Encoding::JSON);
-        let content_type = match &encoding {
-            Encoding::JSON => "application/json",
-            #[cfg(feature = "capnp-encoding")]
-            Encoding::Capnp => "application/octet-stream",
-        }
-        .to_string();
+        let encoder = Encoder::new(config.encoding.unwrap_or_default(), config.fields);
+        let content_type = encoder.content_type().to_string();
 
         let parallel_chunk_size = config.parallel_chunk_size.unwrap_or(0);
 
         HTTP {
             headers,
+            secret_headers,
             client,
             uri,
-            encoding,
+            encoder,
+            compression: config.compression,
             content_type,
-            parallel_chunk_size
+            parallel_chunk_size,
+            stats,
+        }
+    }
+
+    /// Posts a payload, and resolves to whether the server accepted it
+    fn post(&self, payload: Vec<u8>) -> impl Future<Item = bool, Error = ()> {
+        let mut req = Request::new(Body::from(payload));
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = self.uri.clone();
+        req.headers_mut().clone_from(&self.headers);
+        for (name, secret) in self.secret_headers.iter() {
+            // a rotated secret may not be a valid header value
+            match secret.get().parse() {
+                Ok(value) => {
+                    req.headers_mut().insert(name.clone(), value);
+                }
+                Err(e) => {
+                    error!("Invalid value of HTTP header {}: {}", name, e);
+                    self.stats.request().finish(false);
+                    return Either::B(finished(false));
+                }
+            }
+        }
+        req.headers_mut()
+            .insert(header::CONTENT_TYPE, self.content_type.parse().unwrap());
+        if let Some(encoding) = self.compression.content_encoding() {
+            req.headers_mut()
+                .insert(header::CONTENT_ENCODING, encoding.parse().unwrap());
         }
+
+        let request = self.stats.request();
+        Either::A(self.client.request(req).then(move |res| {
+            let success = res.map(|r| r.status().is_success()).unwrap_or(false);
+            request.finish(success);
+            finished(success)
+        }))
     }
 }
 
@@ -78,33 +147,36 @@ impl Handler<Message> for HTTP {
         let measurements = match msg {
             Message::Single(m) => vec![m],
             Message::List(ms) => ms,
+            Message::Flush => return self.stats.flushed(),
         };
+        self.stats.received();
 
-        let encoding = self.encoding;
+        let encoder = &self.encoder;
+        let compression = &self.compression;
         let payloads: Vec<_> = if self.parallel_chunk_size > 0 {
             measurements
                 .into_par_iter()
                 .chunks(self.parallel_chunk_size)
-                .map(|chunks| encoding.encode(&chunks))
+                .map(|chunks| compression.compress(encoder.encode(&chunks)))
                 .collect()
         } else {
-            vec![encoding.encode(&measurements)]
+            vec![compression.compress(encoder.encode(&measurements))]
         };
 
         for payload in payloads {
-            let mut req = Request::new(Body::from(payload));
-            *req.method_mut() = Method::POST;
-            *req.uri_mut() = self.uri.clone();
-            req.headers_mut().clone_from(&self.headers);
-            req.headers_mut()
-                .insert(header::CONTENT_TYPE, self.content_type.parse().unwrap());
-
-            actix::spawn(
-                self.client
-                    .request(req)
-                    .and_then(|_| finished(()))
-                    .or_else(|_| finished(())),
-            );
+            actix::spawn(self.post(payload).map(|_| ()));
         }
     }
 }
+
+impl Handler<Deliver> for HTTP {
+    type Result = ResponseFuture<(), ()>;
+
+    fn handle(&mut self, msg: Deliver, _ctx: &mut Context<Self>) -> Self::Result {
+        let payload = self.compression.compress(self.encoder.encode(&msg.0));
+        Box::new(
+            self.post(payload)
+                .and_then(|success| if success { Ok(()) } else { Err(()) }),
+        )
+    }
+}
@@ -0,0 +1,508 @@
+//! Exports connections and traffic volumes as IPFIX flow records.
+//!
+//! The `connection.in`, `connection.out`, `volume.in` and `volume.out`
+//! measurements of the Network grain are collected into flows by protocol,
+//! addresses and ports, and the flows seen in every `active_timeout_s` are
+//! sent to `collector` over UDP, as described in RFC 7011. Addresses and
+//! ports are oriented the way the packets went, so the source of an inbound
+//! flow is the remote end. All other measurements are ignored.
+//!
+//! Collectors can't ask for templates over UDP, so every message carries the
+//! templates of its records.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ::actix::prelude::*;
+
+use crate::backends::Message;
+use crate::metrics::{Measurement, Unit};
+use crate::telemetry::BackendStats;
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET: u16 = 2;
+const IPV4_TEMPLATE: u16 = 256;
+const IPV6_TEMPLATE: u16 = 257;
+/// Keeps messages within the MTU of most links
+const MAX_MESSAGE_SIZE: usize = 1400;
+const HEADER_SIZE: usize = 16;
+
+const OCTET_DELTA_COUNT: u16 = 1;
+const PROTOCOL_IDENTIFIER: u16 = 4;
+const SOURCE_TRANSPORT_PORT: u16 = 7;
+const SOURCE_IPV4_ADDRESS: u16 = 8;
+const DESTINATION_TRANSPORT_PORT: u16 = 11;
+const DESTINATION_IPV4_ADDRESS: u16 = 12;
+const SOURCE_IPV6_ADDRESS: u16 = 27;
+const DESTINATION_IPV6_ADDRESS: u16 = 28;
+const FLOW_DIRECTION: u16 = 61;
+const FLOW_START_MILLISECONDS: u16 = 152;
+const FLOW_END_MILLISECONDS: u16 = 153;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpfixConfig {
+    /// The `host:port` of the collector
+    collector: String,
+    #[serde(default)]
+    observation_domain_id: u32,
+    #[serde(default = "default_active_timeout_s")]
+    active_timeout_s: u64,
+    /// Flows are exported early when there are more than this
+    #[serde(default = "default_max_flows")]
+    max_flows: usize,
+}
+
+fn default_active_timeout_s() -> u64 {
+    60
+}
+
+fn default_max_flows() -> usize {
+    65_536
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Direction {
+    Ingress = 0,
+    Egress = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    direction: Direction,
+    protocol: u8,
+    source: IpAddr,
+    source_port: u16,
+    destination: IpAddr,
+    destination_port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Flow {
+    octets: u64,
+    start_ms: u64,
+    end_ms: u64,
+}
+
+impl FlowKey {
+    fn from_measurement(m: &Measurement) -> Option<FlowKey> {
+        let direction = match m.name.as_str() {
+            "connection.in" | "volume.in" => Direction::Ingress,
+            "connection.out" | "volume.out" => Direction::Egress,
+            _ => return None,
+        };
+        // connections are only traced for TCP
+        let protocol = match m.tags.get("proto").unwrap_or("tcp") {
+            "tcp" => IPPROTO_TCP,
+            "udp" => IPPROTO_UDP,
+            _ => return None,
+        };
+
+        let local: IpAddr = m.tags.get("s_ip")?.parse().ok()?;
+        let local_port = m.tags.get("s_port")?.parse().ok()?;
+        let remote: IpAddr = m.tags.get("d_ip")?.parse().ok()?;
+        let remote_port = m.tags.get("d_port")?.parse().ok()?;
+
+        let key = match direction {
+            Direction::Ingress => FlowKey {
+                direction,
+                protocol,
+                source: remote,
+                source_port: remote_port,
+                destination: local,
+                destination_port: local_port,
+            },
+            Direction::Egress => FlowKey {
+                direction,
+                protocol,
+                source: local,
+                source_port: local_port,
+                destination: remote,
+                destination_port: remote_port,
+            },
+        };
+
+        Some(key)
+    }
+
+    fn addresses(&self) -> Addresses {
+        match (self.source, self.destination) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => Addresses::V4(s, d),
+            (s, d) => Addresses::V6(to_ipv6(s), to_ipv6(d)),
+        }
+    }
+}
+
+enum Addresses {
+    V4(Ipv4Addr, Ipv4Addr),
+    V6(Ipv6Addr, Ipv6Addr),
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+pub struct Ipfix {
+    config: IpfixConfig,
+    socket: UdpSocket,
+    flows: HashMap<FlowKey, Flow>,
+    sequence: u32,
+    stats: Arc<BackendStats>,
+}
+
+impl Ipfix {
+    pub fn new(config: IpfixConfig, stats: Arc<BackendStats>) -> Ipfix {
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        socket
+            .connect(config.collector.as_str())
+            .unwrap_or_else(|_| panic!("Invalid IPFIX collector: {}", config.collector));
+
+        Ipfix {
+            config,
+            socket,
+            flows: HashMap::new(),
+            sequence: 0,
+            stats,
+        }
+    }
+
+    fn record(&mut self, m: &Measurement) {
+        let key = match FlowKey::from_measurement(m) {
+            Some(key) => key,
+            None => return,
+        };
+        let octets = match m.value {
+            Unit::Byte(bytes) => bytes,
+            _ => 0,
+        };
+        let ms = m.timestamp / 1_000_000;
+
+        let flow = self.flows.entry(key).or_insert(Flow {
+            octets: 0,
+            start_ms: ms,
+            end_ms: ms,
+        });
+        flow.octets += octets;
+        flow.start_ms = flow.start_ms.min(ms);
+        flow.end_ms = flow.end_ms.max(ms);
+    }
+
+    /// Sends every flow to the collector, and starts over
+    fn export(&mut self) {
+        if self.flows.is_empty() {
+            return;
+        }
+
+        let flows = self.flows.drain().collect::<Vec<_>>();
+        let export_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let messages = encode(
+            &flows,
+            export_time,
+            &mut self.sequence,
+            self.config.observation_domain_id,
+        );
+
+        let request = self.stats.request();
+        let result = messages
+            .iter()
+            .map(|message| self.socket.send(message).map(|_| ()))
+            .collect::<io::Result<()>>();
+        if let Err(ref e) = result {
+            error!("Failed to export flows to {}: {}", self.config.collector, e);
+        }
+        request.finish(result.is_ok());
+    }
+}
+
+impl Actor for Ipfix {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let timeout = Duration::from_secs(self.config.active_timeout_s);
+        ctx.run_interval(timeout, |act, _ctx| act.export());
+    }
+}
+
+impl Handler<Message> for Ipfix {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        match msg {
+            Message::Single(m) => self.record(&m),
+            Message::List(ms) => ms.iter().for_each(|m| self.record(m)),
+            Message::Flush => {
+                self.export();
+                return self.stats.flushed();
+            }
+        }
+        self.stats.received();
+
+        if self.flows.len() > self.config.max_flows {
+            self.export();
+        }
+    }
+}
+
+/// Builds the messages that carry `flows`, numbering their records from
+/// `sequence`
+fn encode(
+    flows: &[(FlowKey, Flow)],
+    export_time: u32,
+    sequence: &mut u32,
+    domain: u32,
+) -> Vec<Vec<u8>> {
+    let templates = templates();
+    let mut messages = vec![];
+    let mut message = vec![];
+    let mut set = (0, vec![]);
+    let mut records = 0;
+
+    for (key, flow) in flows {
+        let (template, record) = data_record(key, flow);
+
+        if !message.is_empty()
+            && HEADER_SIZE + message.len() + 4 + set.1.len() + record.len() > MAX_MESSAGE_SIZE
+        {
+            close_set(&mut message, &mut set);
+            messages.push(header(&message, export_time, *sequence, domain));
+            *sequence = sequence.wrapping_add(records);
+            message.clear();
+            records = 0;
+        }
+        if message.is_empty() {
+            message.extend_from_slice(&templates);
+        }
+        if set.0 != template {
+            close_set(&mut message, &mut set);
+            set.0 = template;
+        }
+
+        set.1.extend_from_slice(&record);
+        records += 1;
+    }
+
+    close_set(&mut message, &mut set);
+    messages.push(header(&message, export_time, *sequence, domain));
+    *sequence = sequence.wrapping_add(records);
+
+    messages
+}
+
+/// Appends the data set being built to `message`
+fn close_set(message: &mut Vec<u8>, set: &mut (u16, Vec<u8>)) {
+    if set.1.is_empty() {
+        return;
+    }
+
+    message.extend_from_slice(&set.0.to_be_bytes());
+    message.extend_from_slice(&((4 + set.1.len()) as u16).to_be_bytes());
+    message.append(&mut set.1);
+}
+
+fn header(sets: &[u8], export_time: u32, sequence: u32, domain: u32) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + sets.len());
+    message.extend_from_slice(&VERSION.to_be_bytes());
+    message.extend_from_slice(&((HEADER_SIZE + sets.len()) as u16).to_be_bytes());
+    message.extend_from_slice(&export_time.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(&domain.to_be_bytes());
+    message.extend_from_slice(sets);
+
+    message
+}
+
+fn template_fields(source: u16, destination: u16, address_len: u16) -> Vec<(u16, u16)> {
+    vec![
+        (source, address_len),
+        (destination, address_len),
+        (SOURCE_TRANSPORT_PORT, 2),
+        (DESTINATION_TRANSPORT_PORT, 2),
+        (PROTOCOL_IDENTIFIER, 1),
+        (FLOW_DIRECTION, 1),
+        (OCTET_DELTA_COUNT, 8),
+        (FLOW_START_MILLISECONDS, 8),
+        (FLOW_END_MILLISECONDS, 8),
+    ]
+}
+
+/// The template set describing the IPv4 and IPv6 records
+fn templates() -> Vec<u8> {
+    let mut body = vec![];
+    for (id, fields) in &[
+        (
+            IPV4_TEMPLATE,
+            template_fields(SOURCE_IPV4_ADDRESS, DESTINATION_IPV4_ADDRESS, 4),
+        ),
+        (
+            IPV6_TEMPLATE,
+            template_fields(SOURCE_IPV6_ADDRESS, DESTINATION_IPV6_ADDRESS, 16),
+        ),
+    ] {
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (element, len) in fields {
+            body.extend_from_slice(&element.to_be_bytes());
+            body.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    let mut set = Vec::with_capacity(4 + body.len());
+    set.extend_from_slice(&TEMPLATE_SET.to_be_bytes());
+    set.extend_from_slice(&((4 + body.len()) as u16).to_be_bytes());
+    set.append(&mut body);
+
+    set
+}
+
+fn data_record(key: &FlowKey, flow: &Flow) -> (u16, Vec<u8>) {
+    let mut record = Vec::with_capacity(64);
+    let template = match key.addresses() {
+        Addresses::V4(source, destination) => {
+            record.extend_from_slice(&source.octets());
+            record.extend_from_slice(&destination.octets());
+            IPV4_TEMPLATE
+        }
+        Addresses::V6(source, destination) => {
+            record.extend_from_slice(&source.octets());
+            record.extend_from_slice(&destination.octets());
+            IPV6_TEMPLATE
+        }
+    };
+    record.extend_from_slice(&key.source_port.to_be_bytes());
+    record.extend_from_slice(&key.destination_port.to_be_bytes());
+    record.push(key.protocol);
+    record.push(key.direction as u8);
+    record.extend_from_slice(&flow.octets.to_be_bytes());
+    record.extend_from_slice(&flow.start_ms.to_be_bytes());
+    record.extend_from_slice(&flow.end_ms.to_be_bytes());
+
+    (template, record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::kind::COUNTER;
+    use crate::metrics::Tags;
+
+    fn volume(name: &str, d_ip: &str, bytes: u64, timestamp: u64) -> Measurement {
+        let mut tags = Tags::new();
+        tags.insert("s_ip", "10.0.0.1");
+        tags.insert("s_port", "50000");
+        tags.insert("d_ip", d_ip);
+        tags.insert("d_port", "443");
+        tags.insert("proto", "tcp");
+        Measurement::with_timestamp(
+            timestamp,
+            COUNTER,
+            name.to_string(),
+            Unit::Byte(bytes),
+            tags,
+        )
+    }
+
+    #[test]
+    fn flow_keys() {
+        let out = FlowKey::from_measurement(&volume("volume.out", "1.1.1.1", 10, 0)).unwrap();
+        assert_eq!(out.source, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(out.destination_port, 443);
+
+        let inbound = FlowKey::from_measurement(&volume("volume.in", "1.1.1.1", 10, 0)).unwrap();
+        assert_eq!(inbound.source, "1.1.1.1".parse::<IpAddr>().unwrap());
+        assert_eq!(inbound.source_port, 443);
+        assert_eq!(inbound.direction, Direction::Ingress);
+
+        assert!(FlowKey::from_measurement(&volume("dns.answer", "1.1.1.1", 10, 0)).is_none());
+    }
+
+    #[test]
+    fn messages() {
+        let mut flows = HashMap::new();
+        for m in &[
+            volume("volume.out", "1.1.1.1", 10, 2_000_000),
+            volume("volume.out", "1.1.1.1", 20, 5_000_000),
+            volume("volume.out", "::2", 30, 5_000_000),
+        ] {
+            let flow = flows
+                .entry(FlowKey::from_measurement(m).unwrap())
+                .or_insert(Flow {
+                    octets: 0,
+                    start_ms: m.timestamp / 1_000_000,
+                    end_ms: 0,
+                });
+            flow.octets += match m.value {
+                Unit::Byte(b) => b,
+                _ => 0,
+            };
+            flow.end_ms = m.timestamp / 1_000_000;
+        }
+        let mut flows = flows.into_iter().collect::<Vec<_>>();
+        flows.sort_by_key(|(key, _)| key.destination.is_ipv6());
+
+        let mut sequence = 7;
+        let messages = encode(&flows, 1, &mut sequence, 3);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(sequence, 9);
+
+        let message = &messages[0];
+        assert_eq!(&message[0..2], &VERSION.to_be_bytes());
+        assert_eq!(&message[2..4], &(message.len() as u16).to_be_bytes());
+        assert_eq!(&message[8..12], &7u32.to_be_bytes());
+        assert_eq!(&message[12..16], &3u32.to_be_bytes());
+
+        let templates = templates();
+        assert_eq!(&message[16..16 + templates.len()], &templates[..]);
+
+        let v4 = &message[16 + templates.len()..];
+        assert_eq!(&v4[0..2], &IPV4_TEMPLATE.to_be_bytes());
+        assert_eq!(&v4[4..8], &[10, 0, 0, 1]);
+        assert_eq!(&v4[8..12], &[1, 1, 1, 1]);
+        // octets, start and end
+        assert_eq!(&v4[18..26], &30u64.to_be_bytes());
+        assert_eq!(&v4[26..34], &2u64.to_be_bytes());
+        assert_eq!(&v4[34..42], &5u64.to_be_bytes());
+
+        let v6 = &v4[42..];
+        assert_eq!(&v6[0..2], &IPV6_TEMPLATE.to_be_bytes());
+        assert_eq!(
+            &v6[4..20],
+            &"::ffff:10.0.0.1".parse::<Ipv6Addr>().unwrap().octets()
+        );
+    }
+
+    #[test]
+    fn split_messages() {
+        let key = FlowKey::from_measurement(&volume("volume.out", "1.1.1.1", 1, 0)).unwrap();
+        let flow = Flow {
+            octets: 1,
+            start_ms: 0,
+            end_ms: 0,
+        };
+        let flows = (0..100)
+            .map(|port| {
+                let key = FlowKey {
+                    source_port: port,
+                    ..key
+                };
+                (key, flow)
+            })
+            .collect::<Vec<_>>();
+
+        let mut sequence = 0;
+        let messages = encode(&flows, 1, &mut sequence, 0);
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|m| m.len() <= MAX_MESSAGE_SIZE));
+        assert_eq!(sequence, 100);
+    }
+}
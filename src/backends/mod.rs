@@ -1,16 +1,43 @@
 use actix;
 
+#[cfg(feature = "azure-backend")]
+pub mod azure;
+pub mod batch;
+#[cfg(feature = "clickhouse-backend")]
+pub mod clickhouse;
+pub mod compression;
 pub mod console;
+pub mod dedup;
+#[cfg(feature = "grpc-backend")]
+pub mod grpc;
 #[cfg(feature = "http-backend")]
 pub mod http;
+pub mod ipfix;
+#[cfg(feature = "mqtt-backend")]
+pub mod mqtt;
+pub mod pcap;
+pub mod proxy;
+#[cfg(feature = "redis-backend")]
+pub mod redis_stream;
 #[cfg(feature = "s3-backend")]
 pub mod s3;
 #[cfg(feature = "statsd-backend")]
 pub mod statsd;
+pub mod supervisor;
+#[cfg(any(
+    feature = "http-backend",
+    feature = "grpc-backend",
+    feature = "clickhouse-backend",
+    feature = "azure-backend",
+    feature = "s3-backend",
+    feature = "mqtt-backend"
+))]
+pub mod tls;
+pub mod wal;
 
 mod encoders;
 
-use crate::metrics::{kind::Kind, Measurement, Unit};
+use crate::metrics::{kind::Kind, Measurement, Unit, UnitType};
 
 impl actix::Message for Measurement {
     type Result = ();
@@ -20,4 +47,36 @@ impl actix::Message for Measurement {
 pub enum Message {
     Single(Measurement),
     List(Vec<Measurement>),
+    /// Asks the pipeline to deliver everything it holds. Sent on shutdown.
+    Flush,
+}
+
+/// Sends a batch, and resolves once the backend delivered it. The backends
+/// that handle it can be used behind a write-ahead log.
+pub struct Deliver(pub Vec<Measurement>);
+
+impl actix::Message for Deliver {
+    type Result = Result<(), ()>;
+}
+
+/// Drops the raw measurements, for the backends that don't handle them
+pub(crate) fn without_raw(mut measurements: Vec<Measurement>) -> Vec<Measurement> {
+    measurements.retain(|m| m.value.get_type() != UnitType::Raw);
+    measurements
+}
+
+/// Percent-encodes everything but the unreserved characters of URIs
+#[cfg(any(feature = "azure-backend", feature = "clickhouse-backend"))]
+pub(crate) fn url_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len() * 3);
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
 }
@@ -0,0 +1,490 @@
+//! Publishes measurements to an MQTT broker, with MQTT 3.1.1 or 5.
+//!
+//! Every message is published as one batch to `topic`, after replacing
+//! `{hostname}` in it. When the topic also contains `{name}`, the batch is
+//! split by measurement name, and each part is published to its own topic.
+//!
+//! Messages are published with QoS 0, 1 or 2, and at QoS 1 and 2 the backend
+//! waits for the broker to acknowledge every message before publishing the
+//! next. With `tls`, the connection to the broker is encrypted, with the
+//! settings of `backends::tls`.
+//!
+//! After an error, the connection is opened again on the next message, and
+//! the message that failed is dropped.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use backend_rustls as rustls;
+use failure::{bail, format_err, Error};
+
+use crate::backends::encoders::{Encoder, Encoding, Fields};
+use crate::backends::tls::TlsConfig;
+use crate::backends::Message;
+use crate::metrics::Measurement;
+use crate::secrets::Secret;
+use crate::telemetry::BackendStats;
+
+/// How long to wait for the broker to acknowledge a packet
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+const PUBREL: u8 = 0x62;
+const PUBCOMP: u8 = 0x70;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttConfig {
+    /// `host:port` of the broker
+    broker: String,
+    #[serde(default = "default_topic")]
+    topic: String,
+    /// Defaults to `ingraind-<hostname>`
+    client_id: Option<String>,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    protocol: Protocol,
+    #[serde(default = "default_keep_alive_s")]
+    keep_alive_s: u16,
+    username: Option<String>,
+    password: Option<String>,
+    password_file: Option<String>,
+    tls: Option<TlsConfig>,
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default)]
+    fields: Fields,
+}
+
+fn default_topic() -> String {
+    "ingraind/{hostname}".to_string()
+}
+
+fn default_keep_alive_s() -> u16 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+impl Default for Protocol {
+    fn default() -> Protocol {
+        Protocol::V311
+    }
+}
+
+impl Protocol {
+    fn level(self) -> u8 {
+        match self {
+            Protocol::V311 => 4,
+            Protocol::V5 => 5,
+        }
+    }
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// A connection to the broker, after it accepted the `CONNECT`
+struct Session {
+    stream: Box<dyn Stream>,
+    protocol: Protocol,
+    packet_id: u16,
+}
+
+impl Session {
+    fn open(
+        mut stream: Box<dyn Stream>,
+        config: &MqttConfig,
+        client_id: &str,
+        password: Option<&str>,
+    ) -> Result<Session, Error> {
+        stream.write_all(&connect(config, client_id, password))?;
+        let (kind, body) = read_packet(&mut stream)?;
+        if kind != CONNACK || body.len() < 2 {
+            bail!("Expected CONNACK from {}, got {:#x}", config.broker, kind);
+        }
+        if body[1] != 0 {
+            bail!("{} refused the connection: {:#x}", config.broker, body[1]);
+        }
+
+        Ok(Session {
+            stream,
+            protocol: config.protocol,
+            packet_id: 0,
+        })
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], qos: u8) -> Result<(), Error> {
+        self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+        let packet_id = self.packet_id;
+        self.stream
+            .write_all(&publish(self.protocol, topic, payload, qos, packet_id))?;
+
+        match qos {
+            0 => Ok(()),
+            1 => self.expect(PUBACK, packet_id),
+            _ => {
+                self.expect(PUBREC, packet_id)?;
+                self.stream.write_all(&ack(PUBREL, packet_id))?;
+                self.expect(PUBCOMP, packet_id)
+            }
+        }
+    }
+
+    fn ping(&mut self) -> Result<(), Error> {
+        self.stream.write_all(&[PINGREQ, 0])?;
+        self.expect(PINGRESP, 0)
+    }
+
+    /// Reads packets until one of `kind` arrives, and checks that it
+    /// acknowledges `packet_id`
+    fn expect(&mut self, kind: u8, packet_id: u16) -> Result<(), Error> {
+        loop {
+            let (k, body) = read_packet(&mut self.stream)?;
+            if k != kind {
+                continue;
+            }
+            if kind == PINGRESP {
+                return Ok(());
+            }
+            if body.len() < 2 || u16::from_be_bytes([body[0], body[1]]) != packet_id {
+                bail!("Unexpected acknowledgement {:#x}", kind);
+            }
+            // MQTT 5 adds a reason code, where 0x80 and above are failures
+            if let Some(&reason) = body.get(2) {
+                if reason >= 0x80 {
+                    bail!("Broker rejected message {}: {:#x}", packet_id, reason);
+                }
+            }
+
+            return Ok(());
+        }
+    }
+}
+
+pub struct Mqtt {
+    config: MqttConfig,
+    client_id: String,
+    hostname: String,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    password: Option<Secret>,
+    session: Option<Session>,
+    encoder: Encoder,
+    stats: Arc<BackendStats>,
+}
+
+impl Mqtt {
+    pub fn new(config: MqttConfig, stats: Arc<BackendStats>) -> Mqtt {
+        if config.qos > 2 {
+            panic!("Invalid MQTT QoS {}", config.qos);
+        }
+        let tls = config.tls.as_ref().map(|tls| match tls.client_config() {
+            Ok(tls) => Arc::new(tls),
+            Err(e) => panic!("Invalid MQTT TLS settings: {}", e),
+        });
+        let password = Secret::from_config(
+            "password",
+            config.password.as_ref(),
+            config.password_file.as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("Invalid MQTT password: {}", e));
+        let hostname = redbpf::uname::get_fqdn().unwrap();
+
+        Mqtt {
+            client_id: config
+                .client_id
+                .clone()
+                .unwrap_or_else(|| format!("ingraind-{}", hostname)),
+            hostname,
+            tls,
+            password,
+            session: None,
+            encoder: Encoder::new(config.encoding, config.fields.clone()),
+            config,
+            stats,
+        }
+    }
+
+    fn connect(&self) -> Result<Session, Error> {
+        let tcp = TcpStream::connect(&self.config.broker)?;
+        tcp.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+
+        let stream: Box<dyn Stream> = match self.tls {
+            Some(ref tls) => {
+                // like the other backends, the server name indication is
+                // the host, and `server_name` only changes verification
+                let name = self.config.broker.rsplitn(2, ':').last().unwrap();
+                let name = webpki::DNSNameRef::try_from_ascii_str(name)
+                    .map_err(|_| format_err!("Invalid server name {}", name))?;
+                let session = rustls::ClientSession::new(tls, name);
+                Box::new(rustls::StreamOwned::new(session, tcp))
+            }
+            None => Box::new(tcp),
+        };
+
+        let password = self.password.as_ref().map(Secret::get);
+        Session::open(
+            stream,
+            &self.config,
+            &self.client_id,
+            password.as_ref().map(String::as_str),
+        )
+    }
+
+    fn send(&mut self, topic: &str, payload: &[u8]) -> Result<(), Error> {
+        if self.session.is_none() {
+            self.session = Some(self.connect()?);
+        }
+
+        let result = self
+            .session
+            .as_mut()
+            .unwrap()
+            .publish(topic, payload, self.config.qos);
+        if result.is_err() {
+            self.session = None;
+        }
+
+        result
+    }
+
+    /// The topic of each measurement name, and the measurements to publish
+    /// to it
+    fn topics(&self, measurements: Vec<Measurement>) -> BTreeMap<String, Vec<Measurement>> {
+        let topic = self.config.topic.replace("{hostname}", &self.hostname);
+        let mut topics = BTreeMap::new();
+        if !topic.contains("{name}") {
+            topics.insert(topic, measurements);
+            return topics;
+        }
+
+        for m in measurements {
+            topics
+                .entry(topic.replace("{name}", &m.name))
+                .or_insert_with(Vec::new)
+                .push(m);
+        }
+
+        topics
+    }
+}
+
+impl Actor for Mqtt {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = Duration::from_secs(u64::from(self.config.keep_alive_s.max(2) / 2));
+        ctx.run_interval(interval, |act, _ctx| {
+            let failed = match act.session {
+                Some(ref mut session) => session.ping().is_err(),
+                None => false,
+            };
+            if failed {
+                warn!("Lost connection to MQTT broker {}", act.config.broker);
+                act.session = None;
+            }
+        });
+    }
+}
+
+impl Handler<Message> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let measurements = match msg {
+            Message::Single(m) => vec![m],
+            Message::List(ms) => ms,
+            Message::Flush => return self.stats.flushed(),
+        };
+        self.stats.received();
+
+        for (topic, measurements) in self.topics(measurements) {
+            let payload = self.encoder.encode(&measurements);
+            let request = self.stats.request();
+            let result = self.send(&topic, &payload);
+            if let Err(ref e) = result {
+                warn!("Failed to publish to {}: {}", topic, e);
+            }
+            request.finish(result.is_ok());
+        }
+    }
+}
+
+fn connect(config: &MqttConfig, client_id: &str, password: Option<&str>) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    let mut body = vec![];
+    write_string(&mut body, "MQTT");
+    body.push(config.protocol.level());
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&config.keep_alive_s.to_be_bytes());
+    if config.protocol == Protocol::V5 {
+        body.push(0); // no properties
+    }
+
+    write_string(&mut body, client_id);
+    if let Some(ref username) = config.username {
+        write_string(&mut body, username);
+    }
+    if let Some(password) = password {
+        write_string(&mut body, password);
+    }
+
+    packet(CONNECT, &body)
+}
+
+fn publish(protocol: Protocol, topic: &str, payload: &[u8], qos: u8, packet_id: u16) -> Vec<u8> {
+    let mut body = vec![];
+    write_string(&mut body, topic);
+    if qos > 0 {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    if protocol == Protocol::V5 {
+        body.push(0); // no properties
+    }
+    body.extend_from_slice(payload);
+
+    packet(PUBLISH | (qos << 1), &body)
+}
+
+fn ack(kind: u8, packet_id: u16) -> Vec<u8> {
+    packet(kind, &packet_id.to_be_bytes())
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+
+    packet
+}
+
+fn write_string(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    body.extend_from_slice(s.as_bytes());
+}
+
+/// Reads the type and the body of the next packet
+fn read_packet(stream: &mut dyn Read) -> Result<(u8, Vec<u8>), Error> {
+    let mut byte = [0u8];
+    stream.read_exact(&mut byte)?;
+    let kind = byte[0] & 0xf0;
+
+    let mut len = 0usize;
+    for shift in (0..28).step_by(7) {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+
+    Ok((kind, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+
+    /// Replies with a canned response, and records what's written to it
+    struct Broker {
+        response: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for Broker {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    impl Write for Broker {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn config(protocol: &str) -> MqttConfig {
+        toml::from_str(&format!(
+            r#"
+broker = "localhost:1883"
+protocol = "{}"
+username = "u"
+"#,
+            protocol
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn packets() {
+        assert_eq!(
+            connect(&config("3.1.1"), "id", None),
+            b"\x10\x11\x00\x04MQTT\x04\x82\x00\x3c\x00\x02id\x00\x01u".to_vec()
+        );
+        assert_eq!(
+            publish(Protocol::V5, "t", b"xy", 1, 7),
+            b"\x32\x08\x00\x01t\x00\x07\x00xy".to_vec()
+        );
+        assert_eq!(packet(PUBLISH, &[0; 200])[..3], [0x30, 0xc8, 0x01]);
+
+        let mut long = Cursor::new(packet(PUBLISH, &[1; 321]));
+        assert_eq!(read_packet(&mut long).unwrap(), (PUBLISH, vec![1; 321]));
+    }
+
+    #[test]
+    fn session() {
+        let mut response = vec![CONNACK, 2, 0, 0];
+        response.extend_from_slice(&ack(PUBREC, 1));
+        response.extend_from_slice(&ack(PUBCOMP, 1));
+        response.extend_from_slice(&[PUBACK, 3, 0, 2, 0x87]);
+        let broker = Broker {
+            response: Cursor::new(response),
+            written: vec![],
+        };
+
+        let mut session = Session::open(Box::new(broker), &config("5"), "id", None).unwrap();
+        session.publish("t", b"x", 2).unwrap();
+        assert!(session.publish("t", b"x", 1).is_err());
+    }
+}
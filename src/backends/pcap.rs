@@ -0,0 +1,289 @@
+//! Writes the packets captured by grains to rolling pcapng files.
+//!
+//! Grains that capture packets emit them as `<grain>.packet` measurements
+//! with a `Unit::Raw` value, tagged with the `interface` and the
+//! `link_type` of the capture. Each grain and interface pair is described
+//! by its own interface description block, and every packet is written with
+//! the timestamp of its measurement. All other measurements are ignored.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use ::actix::prelude::*;
+
+use crate::backends::Message;
+use crate::metrics::{Measurement, Tags, Unit};
+use crate::telemetry::BackendStats;
+
+pub const LINKTYPE_ETHERNET: u16 = 1;
+pub const LINKTYPE_RAW: u16 = 101;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+/// Timestamps are in nanoseconds
+const TSRESOL_NANOS: u8 = 9;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PcapConfig {
+    path: String,
+    #[serde(default = "default_max_size_mb")]
+    max_size_mb: u64,
+    #[serde(default = "default_max_files")]
+    max_files: usize,
+}
+
+fn default_max_size_mb() -> u64 {
+    100
+}
+
+fn default_max_files() -> usize {
+    10
+}
+
+/// Builds the measurement of a packet captured by `grain`
+pub fn packet(
+    grain: &str,
+    timestamp: u64,
+    interface: &str,
+    link_type: u16,
+    data: Vec<u8>,
+) -> Measurement {
+    let mut tags = Tags::new();
+    tags.insert("interface", interface);
+    tags.insert("link_type", link_type.to_string());
+
+    Measurement::with_timestamp(
+        timestamp,
+        0,
+        format!("{}.packet", grain),
+        Unit::Raw(data),
+        tags,
+    )
+}
+
+pub struct Pcap {
+    config: PcapConfig,
+    file: Option<PcapFile>,
+    stats: Arc<BackendStats>,
+}
+
+struct PcapFile {
+    writer: BufWriter<File>,
+    size: u64,
+    interfaces: HashMap<(String, String, u16), u32>,
+}
+
+impl PcapFile {
+    fn create(path: &str) -> io::Result<PcapFile> {
+        let mut file = PcapFile {
+            writer: BufWriter::new(File::create(path)?),
+            size: 0,
+            interfaces: HashMap::new(),
+        };
+        file.write(&section_header())?;
+
+        Ok(file)
+    }
+
+    fn write(&mut self, block: &[u8]) -> io::Result<()> {
+        self.writer.write_all(block)?;
+        self.size += block.len() as u64;
+
+        Ok(())
+    }
+
+    fn write_packet(&mut self, m: &Measurement, data: &[u8]) -> io::Result<()> {
+        let grain = m.name.trim_end_matches(".packet");
+        let interface = m.tags.get("interface").unwrap_or_default();
+        let link_type = m
+            .tags
+            .get("link_type")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(LINKTYPE_ETHERNET);
+
+        let key = (grain.to_string(), interface.to_string(), link_type);
+        let id = match self.interfaces.get(&key) {
+            Some(id) => *id,
+            None => {
+                let id = self.interfaces.len() as u32;
+                let name = format!("{}:{}", grain, interface);
+                self.write(&interface_description(link_type, &name))?;
+                self.interfaces.insert(key, id);
+                id
+            }
+        };
+
+        self.write(&enhanced_packet(id, m.timestamp, data))
+    }
+}
+
+impl Pcap {
+    pub fn new(config: PcapConfig, stats: Arc<BackendStats>) -> Pcap {
+        Pcap {
+            config,
+            file: None,
+            stats,
+        }
+    }
+
+    fn write_all(&mut self, measurements: &[Measurement]) -> io::Result<()> {
+        for m in measurements.iter() {
+            let data = match m.value {
+                Unit::Raw(ref data) => data,
+                _ => continue,
+            };
+
+            if self
+                .file
+                .as_ref()
+                .map_or(true, |f| f.size >= self.config.max_size_mb << 20)
+            {
+                self.rotate()?;
+            }
+            self.file.as_mut().unwrap().write_packet(m, data)?;
+        }
+
+        match self.file {
+            Some(ref mut file) => file.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves `path` to `path.1`, `path.1` to `path.2` and so on, dropping
+    /// the oldest file, then starts a new file at `path`
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        if let Some(mut file) = self.file.take() {
+            file.writer.flush()?;
+        }
+
+        if Path::new(path).exists() && self.config.max_files > 0 {
+            let rotated = |i: usize| format!("{}.{}", path, i);
+            let _ = fs::remove_file(rotated(self.config.max_files));
+            for i in (1..self.config.max_files).rev() {
+                let _ = fs::rename(rotated(i), rotated(i + 1));
+            }
+            fs::rename(path, rotated(1))?;
+        }
+
+        self.file = Some(PcapFile::create(path)?);
+        Ok(())
+    }
+}
+
+impl Actor for Pcap {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for Pcap {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let measurements = match msg {
+            Message::Single(m) => vec![m],
+            Message::List(ms) => ms,
+            Message::Flush => return self.stats.flushed(),
+        };
+        self.stats.received();
+
+        let request = self.stats.request();
+        let result = self.write_all(&measurements);
+        if let Err(ref e) = result {
+            error!("Failed to write packets to {}: {}", self.config.path, e);
+            self.file = None;
+        }
+        request.finish(result.is_ok());
+    }
+}
+
+/// Frames `body` as a pcapng block, padding it to 32 bits
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let len = (12 + body.len() + padding) as u32;
+
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(block.len() + padding, 0);
+    block.extend_from_slice(&len.to_le_bytes());
+
+    block
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // the section length is not known in advance
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+
+    block(SECTION_HEADER_BLOCK, &body)
+}
+
+fn interface_description(link_type: u16, name: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(32);
+    body.extend_from_slice(&link_type.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // no snapshot length limit
+    body.extend_from_slice(&0u32.to_le_bytes());
+    option(&mut body, IF_NAME, name.as_bytes());
+    option(&mut body, IF_TSRESOL, &[TSRESOL_NANOS]);
+    option(&mut body, OPT_ENDOFOPT, &[]);
+
+    block(INTERFACE_DESCRIPTION_BLOCK, &body)
+}
+
+fn enhanced_packet(interface: u32, timestamp: u64, data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(20 + data.len());
+    body.extend_from_slice(&interface.to_le_bytes());
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(data);
+
+    block(ENHANCED_PACKET_BLOCK, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        let shb = section_header();
+        assert_eq!(shb.len(), 28);
+        assert_eq!(&shb[8..12], &BYTE_ORDER_MAGIC.to_le_bytes());
+
+        let idb = interface_description(LINKTYPE_RAW, "dns:eth0");
+        assert_eq!(idb.len(), 44);
+        assert_eq!(&idb[4..8], &(idb.len() as u32).to_le_bytes());
+        assert_eq!(&idb[8..10], &LINKTYPE_RAW.to_le_bytes());
+        assert_eq!(&idb[20..28], b"dns:eth0");
+
+        let epb = enhanced_packet(1, 0x1_0000_0002, &[1, 2, 3]);
+        assert_eq!(epb.len(), 32 + 4);
+        assert_eq!(&epb[8..12], &1u32.to_le_bytes());
+        assert_eq!(&epb[12..20], &[1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(&epb[24..31], &[3, 0, 0, 0, 1, 2, 3]);
+        assert_eq!(&epb[epb.len() - 4..], &(epb.len() as u32).to_le_bytes());
+    }
+}
@@ -0,0 +1,209 @@
+//! Adds measurements to a Redis stream.
+//!
+//! Every measurement is added as its own entry, with the fields `host` and
+//! `measurement`, which holds the measurement encoded as a list of one. The
+//! entries of a message are sent in pipelines of `batch_size` commands, and
+//! every command trims the stream to about `max_len` entries.
+//!
+//! Connecting, and every read and write, time out after `timeout_ms`, so an
+//! unresponsive server doesn't hold up the pipeline. The connection is
+//! opened again on the next message after an error. The entries that failed
+//! are dropped.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use redis::{Cmd, Connection, IntoConnectionInfo, Pipeline, RedisResult};
+
+use crate::backends::encoders::{Encoder, Encoding, Fields};
+use crate::backends::Message;
+use crate::metrics::Measurement;
+use crate::secrets::Secret;
+use crate::telemetry::BackendStats;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedisStreamConfig {
+    /// Like `redis://localhost:6379/0`
+    url: String,
+    /// Replaces the password of `url`
+    password: Option<String>,
+    password_file: Option<String>,
+    stream: String,
+    #[serde(default = "default_max_len")]
+    max_len: u64,
+    /// Trims to exactly `max_len` entries, which is slower
+    #[serde(default)]
+    exact_trim: bool,
+    #[serde(default = "default_batch_size")]
+    batch_size: usize,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default)]
+    fields: Fields,
+}
+
+fn default_max_len() -> u64 {
+    100_000
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+pub struct RedisStream {
+    config: RedisStreamConfig,
+    client: redis::Client,
+    password: Option<Secret>,
+    connection: Option<Connection>,
+    hostname: String,
+    encoder: Encoder,
+    stats: Arc<BackendStats>,
+}
+
+impl RedisStream {
+    pub fn new(config: RedisStreamConfig, stats: Arc<BackendStats>) -> RedisStream {
+        let client = redis::Client::open(config.url.as_str())
+            .unwrap_or_else(|e| panic!("Invalid Redis URL {}: {}", config.url, e));
+        let password = Secret::from_config(
+            "password",
+            config.password.as_ref(),
+            config.password_file.as_ref(),
+        )
+        .unwrap_or_else(|e| panic!("Invalid Redis password: {}", e));
+
+        RedisStream {
+            client,
+            password,
+            connection: None,
+            hostname: redbpf::uname::get_fqdn().unwrap(),
+            encoder: Encoder::new(config.encoding, config.fields.clone()),
+            config,
+            stats,
+        }
+    }
+
+    fn xadd(&self, m: &Measurement) -> Cmd {
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(&self.config.stream).arg("MAXLEN");
+        if !self.config.exact_trim {
+            cmd.arg("~");
+        }
+        cmd.arg(self.config.max_len)
+            .arg("*")
+            .arg("host")
+            .arg(&self.hostname)
+            .arg("measurement")
+            .arg(self.encoder.encode(&[m.clone()]));
+
+        cmd
+    }
+
+    fn send(&mut self, pipeline: &Pipeline) -> RedisResult<()> {
+        if self.connection.is_none() {
+            let timeout = Duration::from_millis(self.config.timeout_ms.max(1));
+            let connection = match self.password {
+                Some(ref password) => {
+                    let mut info = self.config.url.as_str().into_connection_info()?;
+                    info.passwd = Some(password.get());
+                    redis::Client::open(info)?.get_connection_with_timeout(timeout)?
+                }
+                None => self.client.get_connection_with_timeout(timeout)?,
+            };
+            connection.set_read_timeout(Some(timeout))?;
+            connection.set_write_timeout(Some(timeout))?;
+            self.connection = Some(connection);
+        }
+
+        let result = pipeline.query(self.connection.as_mut().unwrap());
+        if result.is_err() {
+            self.connection = None;
+        }
+
+        result
+    }
+}
+
+impl Actor for RedisStream {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for RedisStream {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let measurements = match msg {
+            Message::Single(m) => vec![m],
+            Message::List(ms) => ms,
+            Message::Flush => return self.stats.flushed(),
+        };
+        self.stats.received();
+
+        for batch in measurements.chunks(self.config.batch_size.max(1)) {
+            let mut pipeline = redis::pipe();
+            for m in batch {
+                pipeline.add_command(self.xadd(m)).ignore();
+            }
+
+            let request = self.stats.request();
+            let result = self.send(&pipeline);
+            if let Err(ref e) = result {
+                warn!("Failed to add to stream {}: {}", self.config.stream, e);
+            }
+            request.finish(result.is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::kind::COUNTER;
+    use crate::metrics::{Tags, Unit};
+
+    #[test]
+    fn command() {
+        let config: RedisStreamConfig = toml::from_str(
+            r#"
+url = "redis://localhost"
+stream = "ingraind"
+max_len = 1000
+"#,
+        )
+        .unwrap();
+        let redis = RedisStream {
+            client: redis::Client::open("redis://localhost").unwrap(),
+            password: None,
+            connection: None,
+            hostname: "host".to_string(),
+            encoder: Encoder::new(config.encoding, config.fields.clone()),
+            config,
+            stats: Arc::new(BackendStats::default()),
+        };
+        let m = Measurement::with_timestamp(
+            1,
+            COUNTER,
+            "connection.out".to_string(),
+            Unit::Count(1),
+            Tags::new(),
+        );
+        let payload = redis.encoder.encode(&[m.clone()]);
+
+        let mut expected = format!(
+            "*10\r\n$4\r\nXADD\r\n$8\r\ningraind\r\n$6\r\nMAXLEN\r\n$1\r\n~\r\n\
+             $4\r\n1000\r\n$1\r\n*\r\n$4\r\nhost\r\n$4\r\nhost\r\n\
+             $11\r\nmeasurement\r\n${}\r\n",
+            payload.len()
+        )
+        .into_bytes();
+        expected.extend_from_slice(&payload);
+        expected.extend_from_slice(b"\r\n");
+        assert_eq!(redis.xadd(&m).get_packed_command(), expected);
+    }
+}
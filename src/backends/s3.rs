@@ -1,32 +1,170 @@
 use std::env;
+use std::str::FromStr;
 
 use ::actix::prelude::*;
+use failure::{format_err, Error};
 use futures::Future;
+use rusoto_core::credential::{AutoRefreshingProvider, ChainProvider, DefaultCredentialsProvider};
 pub use rusoto_core::region::Region;
+use rusoto_core::HttpClient;
 use rusoto_s3::{PutObjectRequest, S3Client, S3 as RusotoS3};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
 
-use crate::backends::Message;
+use crate::backends::compression::Compression;
+use crate::backends::encoders::{Encoder, Encoding, Fields};
+use crate::backends::proxy::ProxyConfig;
+use crate::backends::tls::https_connector;
+use crate::backends::{Deliver, Message};
 use crate::metrics::timestamp_now;
+use crate::telemetry::BackendStats;
+use std::sync::Arc;
 
 pub struct S3 {
     hostname: String,
     client: S3Client,
     bucket: String,
+    encoder: Encoder,
+    compression: Compression,
+    server_side_encryption: Option<String>,
+    sse_kms_key_id: Option<String>,
+    stats: Arc<BackendStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct S3Config {
+    #[serde(default)]
+    encoding: Encoding,
+    #[serde(default)]
+    fields: Fields,
+    #[serde(default)]
+    compression: Compression,
+    /// Defaults to the `AWS_S3_BUCKET` environment variable
+    bucket: Option<String>,
+    /// The name of the region, like `eu-west-1`. Defaults to the
+    /// `AWS_DEFAULT_REGION` or `AWS_REGION` environment variables.
+    region: Option<String>,
+    /// The URL of an S3-compatible store, like MinIO or Ceph
+    endpoint: Option<String>,
+    /// Writes with the credentials of a role
+    assume_role: Option<AssumeRoleConfig>,
+    /// `AES256` or `aws:kms`
+    server_side_encryption: Option<String>,
+    sse_kms_key_id: Option<String>,
+    proxy: Option<ProxyConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssumeRoleConfig {
+    role_arn: String,
+    #[serde(default = "default_session_name")]
+    session_name: String,
+    external_id: Option<String>,
+}
+
+fn default_session_name() -> String {
+    "ingraind".to_string()
+}
+
+/// Requests are always addressed path-style, like
+/// `https://endpoint/bucket/key`, which S3-compatible stores expect
+fn region(config: &S3Config) -> Result<Region, Error> {
+    match (&config.region, &config.endpoint) {
+        (name, Some(endpoint)) => Ok(Region::Custom {
+            name: name.clone().unwrap_or_else(|| "us-east-1".to_string()),
+            endpoint: endpoint.clone(),
+        }),
+        (Some(name), None) => {
+            Region::from_str(name).map_err(|_| format_err!("Invalid S3 region: {}", name))
+        }
+        (None, None) => Ok(Region::default()),
+    }
+}
+
+fn client(config: &S3Config) -> Result<S3Client, Error> {
+    let region = region(config)?;
+    let http_client = || -> Result<_, Error> {
+        Ok(HttpClient::from_connector(https_connector(
+            4,
+            None,
+            config.proxy.as_ref(),
+        )?))
+    };
+    let role = match config.assume_role {
+        Some(ref role) => role,
+        None => {
+            return Ok(S3Client::new_with(
+                http_client()?,
+                DefaultCredentialsProvider::new()?,
+                region,
+            ))
+        }
+    };
+
+    let sts = StsClient::new_with(http_client()?, ChainProvider::new(), region.clone());
+    let provider = StsAssumeRoleSessionCredentialsProvider::new(
+        sts,
+        role.role_arn.clone(),
+        role.session_name.clone(),
+        role.external_id.clone(),
+        None,
+        None,
+        None,
+    );
+
+    Ok(S3Client::new_with(
+        http_client()?,
+        AutoRefreshingProvider::new(provider)?,
+        region,
+    ))
 }
 
 impl S3 {
-    pub fn new() -> S3 {
+    pub fn new(config: S3Config, stats: Arc<BackendStats>) -> S3 {
         use redbpf::uname::*;
 
-        let bucket = env::var("AWS_S3_BUCKET")
-            .expect("The AWS_S3_BUCKET environment variable has to be specified!");
+        let bucket = config.bucket.clone().unwrap_or_else(|| {
+            env::var("AWS_S3_BUCKET")
+                .expect("The AWS_S3_BUCKET environment variable has to be specified!")
+        });
+        let client = client(&config).unwrap_or_else(|e| panic!("Invalid S3 configuration: {}", e));
 
         S3 {
             hostname: get_fqdn().unwrap(),
-            client: S3Client::new(Region::default()),
-            bucket: bucket.into(),
+            client,
+            bucket,
+            encoder: Encoder::new(config.encoding, config.fields),
+            compression: config.compression,
+            server_side_encryption: config.server_side_encryption,
+            sse_kms_key_id: config.sse_kms_key_id,
+            stats,
         }
     }
+
+    /// Writes an object, and resolves to whether it was written
+    fn put(&self, body: Vec<u8>) -> impl Future<Item = bool, Error = ()> {
+        let body = self.compression.compress(body);
+        let request = self.stats.request();
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: format!(
+                    "{}_{}{}",
+                    &self.hostname,
+                    timestamp_now(),
+                    self.compression.extension()
+                ),
+                body: Some(body.into()),
+                content_type: Some(self.encoder.content_type().to_string()),
+                content_encoding: self.compression.content_encoding().map(String::from),
+                server_side_encryption: self.server_side_encryption.clone(),
+                ssekms_key_id: self.sse_kms_key_id.clone(),
+                ..Default::default()
+            })
+            .then(move |res| {
+                request.finish(res.is_ok());
+                Ok(res.is_ok())
+            })
+    }
 }
 
 impl Actor for S3 {
@@ -38,19 +176,24 @@ impl Handler<Message> for S3 {
 
     fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
         let body = match msg {
-	    Message::Single(m) => super::encoders::to_json(&vec![m]).into(),
-	    Message::List(ref ms) => super::encoders::to_json(ms).into(),
+	    Message::Single(m) => self.encoder.encode(&[m]),
+	    Message::List(ref ms) => self.encoder.encode(ms),
+	    Message::Flush => return self.stats.flushed(),
 	};
+        self.stats.received();
+
+        ::actix::spawn(self.put(body).map(|_| ()));
+    }
+}
+
+impl Handler<Deliver> for S3 {
+    type Result = ResponseFuture<(), ()>;
 
-        ::actix::spawn(
-            self.client
-                .put_object(PutObjectRequest {
-                    bucket: self.bucket.clone(),
-                    key: format!("{}_{}", &self.hostname, timestamp_now()),
-                    body: Some(body),
-                    ..Default::default()
-                }).and_then(|_| Ok(()))
-                .or_else(|_| Ok(())),
-        );
+    fn handle(&mut self, msg: Deliver, _ctx: &mut Context<Self>) -> Self::Result {
+        let body = self.encoder.encode(&msg.0);
+        Box::new(
+            self.put(body)
+                .and_then(|success| if success { Ok(()) } else { Err(()) }),
+        )
     }
 }
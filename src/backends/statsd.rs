@@ -1,55 +1,406 @@
+//! Sends measurements to one or more StatsD servers over UDP.
+//!
+//! With several `targets`, each metric name is always sent to the same
+//! server, picked on a consistent hash ring, so adding or removing a server
+//! only moves the metrics of its neighbours on the ring.
+//!
+//! The address of every server is resolved again every `resolve_interval_s`,
+//! and its sockets are replaced when the address changed, or when sending to
+//! it failed since the last check. The number of lines sent to each server,
+//! the errors, and the times it was resolved again are reported to it at the
+//! same interval, as `statsd.sent`, `statsd.errors` and `statsd.reconnects`,
+//! tagged with the `target`.
+
 use std::env;
-use std::net::UdpSocket;
+use std::hash::Hasher;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use ::actix::prelude::*;
-use cadence::{BufferedUdpMetricSink, Counted, QueuingMetricSink, StatsdClient};
+use cadence::{
+    BufferedUdpMetricSink, Counted, Gauged, Histogrammed, MetricSink, QueuingMetricSink,
+    StatsdClient, Timed,
+};
+use metrohash::MetroHash64;
 
 use crate::backends::Message;
-use crate::metrics::Measurement;
+use crate::metrics::kind::{self, Semantics};
+use crate::metrics::{Buckets, Deltas, Measurement, Unit, UnitType};
+use crate::telemetry::BackendStats;
 
-pub struct Statsd {
-    client: StatsdClient,
-}
-#[derive(Serialize, Deserialize, Debug)]
+/// The points each server has on the hash ring
+const RING_POINTS: usize = 160;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StatsdConfig {
     pub use_tags: bool,
+    /// The `host:port` of each server. If empty, the server is read from
+    /// the `STATSD_HOST` and `STATSD_PORT` environment variables.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    #[serde(default = "default_resolve_interval_s")]
+    pub resolve_interval_s: u64,
+    /// Prepended to the name of every metric, followed by a dot. Left out
+    /// when empty.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+fn default_resolve_interval_s() -> u64 {
+    60
+}
+
+fn default_prefix() -> String {
+    "ingraind.metrics".to_string()
+}
+
+#[derive(Default)]
+struct Health {
+    sent: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// Counts the lines sent by a sink, and the errors
+struct CountingSink<T> {
+    sink: T,
+    health: Arc<Health>,
+}
+
+impl<T: MetricSink> MetricSink for CountingSink<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let result = self.sink.emit(metric);
+        match result {
+            Ok(_) => self.health.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.health.errors.fetch_add(1, Ordering::Relaxed),
+        };
+
+        result
+    }
+}
+
+struct Target {
+    address: String,
+    prefix: String,
+    resolved: SocketAddr,
+    client: StatsdClient,
+    /// Sends the lines cadence can't build
+    lines: UdpSocket,
+    health: Arc<Health>,
+    /// The errors at the last check
+    errors: u64,
+    reconnects: u64,
+}
+
+impl Target {
+    fn connect(address: &str, prefix: &str, health: Arc<Health>) -> io::Result<Target> {
+        let resolved = resolve(address)?;
+
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        let sink = CountingSink {
+            sink: BufferedUdpMetricSink::from(resolved, socket)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+            health: health.clone(),
+        };
+        let client = StatsdClient::from_sink(prefix, QueuingMetricSink::from(sink));
+
+        let lines = UdpSocket::bind("0.0.0.0:0")?;
+        lines.set_nonblocking(true)?;
+        lines.connect(resolved)?;
+
+        Ok(Target {
+            address: address.to_string(),
+            prefix: prefix.to_string(),
+            resolved,
+            client,
+            lines,
+            health,
+            errors: 0,
+            reconnects: 0,
+        })
+    }
+
+    /// Resolves the address again, and reconnects if it changed, or if
+    /// sending failed since the last check
+    fn check(&mut self) {
+        let errors = self.health.errors.load(Ordering::Relaxed);
+        let failed = errors > self.errors;
+        self.errors = errors;
+
+        let resolved = match resolve(&self.address) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                warn!("Failed to resolve statsd server {}: {}", self.address, e);
+                return;
+            }
+        };
+        if resolved == self.resolved && !failed {
+            return;
+        }
+
+        match Target::connect(&self.address, &self.prefix, self.health.clone()) {
+            Ok(target) => {
+                info!(
+                    "Reconnecting to statsd server {} at {}",
+                    self.address, resolved
+                );
+                let reconnects = self.reconnects + 1;
+                *self = Target {
+                    errors,
+                    reconnects,
+                    ..target
+                };
+            }
+            Err(e) => warn!(
+                "Failed to reconnect to statsd server {}: {}",
+                self.address, e
+            ),
+        }
+    }
+
+    /// Reports the lines sent, the errors and the reconnects since the last
+    /// report
+    fn report(&mut self) {
+        let sent = self.health.sent.swap(0, Ordering::Relaxed);
+        let errors = self.health.errors.swap(0, Ordering::Relaxed);
+        let reconnects = self.reconnects;
+        self.errors = 0;
+        self.reconnects = 0;
+
+        for (name, value) in &[
+            ("statsd.sent", sent),
+            ("statsd.errors", errors),
+            ("statsd.reconnects", reconnects),
+        ] {
+            let _ = self
+                .client
+                .count_with_tags(name, *value as i64)
+                .with_tag("target", &self.address)
+                .try_send();
+        }
+    }
+
+    /// Sends every bucket as a single timing at its upper bound, with a
+    /// sample rate that makes the server count it as many times as the
+    /// bucket holds values
+    fn send_histogram(&self, msg: &Measurement, buckets: &Buckets) -> io::Result<()> {
+        for (bound, count) in buckets.iter() {
+            self.send_line(msg, &format!("{}|ms|@{}", bound, 1.0 / count as f64))?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a gauge as is, instead of rounded to an unsigned integer.
+    ///
+    /// A signed value updates the gauge by that much, so a negative one is
+    /// sent as a delta from zero.
+    fn send_gauge(&self, msg: &Measurement, value: f64) -> io::Result<()> {
+        if value < 0.0 {
+            self.send_line(msg, "0|g")?;
+        }
+
+        self.send_line(msg, &format!("{}|g", value))
+    }
+
+    /// Sends `value`, made of the value and the type of the metric, with
+    /// the name and the tags of `msg`
+    fn send_line(&self, msg: &Measurement, value: &str) -> io::Result<()> {
+        let mut line = format!("{}:{}", prefixed(&self.prefix, &msg.name), value);
+        let tags = msg
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{}:{}", k, v))
+            .collect::<Vec<_>>()
+            .join(",");
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags);
+        }
+
+        let result = self.lines.send(line.as_bytes());
+        let counter = match result {
+            Ok(_) => &self.health.sent,
+            Err(_) => &self.health.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        result.map(|_| ())
+    }
+}
+
+/// Joins the prefix and the name the way cadence does
+fn prefixed(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", prefix.trim_end_matches('.'), name)
+    }
+}
+
+fn resolve(address: &str) -> io::Result<SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no addresses", address),
+        )
+    })
+}
+
+fn hash(key: &str) -> u64 {
+    let mut hasher = MetroHash64::new();
+    hasher.write(key.as_bytes());
+    hasher.finish()
+}
+
+/// Places `RING_POINTS` points of each target on a ring, sorted by hash
+fn ring(addresses: &[String]) -> Vec<(u64, usize)> {
+    let mut ring = addresses
+        .iter()
+        .enumerate()
+        .flat_map(|(i, address)| {
+            (0..RING_POINTS).map(move |p| (hash(&format!("{}-{}", address, p)), i))
+        })
+        .collect::<Vec<_>>();
+    ring.sort();
+
+    ring
+}
+
+/// The target that follows the hash of `name` on the ring
+fn shard(ring: &[(u64, usize)], name: &str) -> usize {
+    let h = hash(name);
+    let i = match ring.binary_search_by_key(&h, |&(point, _)| point) {
+        Ok(i) | Err(i) => i,
+    };
+
+    ring.get(i)
+        .or_else(|| ring.first())
+        .map_or(0, |&(_, target)| target)
+}
+
+pub struct Statsd {
+    targets: Vec<Target>,
+    ring: Vec<(u64, usize)>,
+    resolve_interval: Duration,
+    deltas: Deltas,
+    stats: Arc<BackendStats>,
 }
 
 impl Statsd {
-    pub fn new(_config: StatsdConfig) -> Statsd {
-        let helper_socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        helper_socket.set_nonblocking(true).unwrap();
-
-        let host =
-            env::var("STATSD_HOST").expect("The STATSD_HOST environment variable has to be set!");
-        let port =
-            env::var("STATSD_PORT").expect("The STATSD_PORT environment variable has to be set!");
-        let port = u16::from_str(&port).expect("STATSD_PORT has to be a valid port number");
-
-        let udp_sink = BufferedUdpMetricSink::from((host.as_str(), port), helper_socket).unwrap_or_else(|_|
-            panic!("Invalid statsd server settings: {}:{}", host, port)
-        );
-        let queuing_sink = QueuingMetricSink::from(udp_sink);
-        let client = StatsdClient::from_sink("ingraind.metrics", queuing_sink);
-
-        Statsd { client }
+    pub fn new(config: StatsdConfig, stats: Arc<BackendStats>) -> Statsd {
+        let addresses = if config.targets.is_empty() {
+            let host = env::var("STATSD_HOST")
+                .expect("The STATSD_HOST environment variable has to be set!");
+            let port = env::var("STATSD_PORT")
+                .expect("The STATSD_PORT environment variable has to be set!");
+            let port = u16::from_str(&port).expect("STATSD_PORT has to be a valid port number");
+            vec![format!("{}:{}", host, port)]
+        } else {
+            config.targets
+        };
+
+        let targets = addresses
+            .iter()
+            .map(|address| {
+                Target::connect(address, &config.prefix, Arc::new(Health::default()))
+                    .unwrap_or_else(|e| {
+                        panic!("Invalid statsd server settings: {}: {}", address, e)
+                    })
+            })
+            .collect();
+
+        Statsd {
+            targets,
+            ring: ring(&addresses),
+            resolve_interval: Duration::from_secs(config.resolve_interval_s),
+            deltas: Deltas::new(),
+            stats,
+        }
     }
 
-    fn count_with_tags(&mut self, msg: &Measurement) {
-        let mut builder = self
-            .client
-            .count_with_tags(&msg.name, msg.value.get() as i64);
-        for (key, value) in msg.tags.iter() {
-            builder = builder.with_tag(key, value);
+    /// Sends the measurements of a message, as a single request
+    fn send_all(&mut self, ms: &[Measurement]) {
+        let request = self.stats.request();
+        let mut success = true;
+        for m in ms {
+            success &= self.send(m).is_ok();
         }
+        request.finish(success);
+    }
 
-        builder.try_send().unwrap();
+    fn send(&mut self, msg: &Measurement) -> Result<(), String> {
+        // statsd counters are deltas
+        let delta;
+        let msg = match kind::semantics(msg.kind) {
+            Semantics::Cumulative => match self.deltas.apply(msg.clone()) {
+                Some(m) => {
+                    delta = m;
+                    &delta
+                }
+                None => return Ok(()),
+            },
+            _ => msg,
+        };
+
+        macro_rules! send_with_tags {
+            ($builder:expr) => {{
+                let mut builder = $builder;
+                for (key, value) in msg.tags.iter() {
+                    builder = builder.with_tag(key, value);
+                }
+                builder.try_send().map(|_| ()).map_err(|e| e.to_string())
+            }};
+        }
+
+        let target = &self.targets[shard(&self.ring, &msg.name)];
+        let semantics = kind::semantics(msg.kind);
+        let gauge = semantics == Semantics::Gauge || msg.value.get_type() == UnitType::Gauge;
+        let result = match msg.value {
+            Unit::Histogram(ref buckets) => target
+                .send_histogram(msg, buckets)
+                .map_err(|e| e.to_string()),
+            _ if gauge => target
+                .send_gauge(msg, msg.value.get_f64())
+                .map_err(|e| e.to_string()),
+            // statsd timers are in milliseconds
+            Unit::Duration(ns) => {
+                send_with_tags!(target.client.time_with_tags(&msg.name, ns / 1_000_000))
+            }
+            _ if semantics == Semantics::Distribution => send_with_tags!(target
+                .client
+                .histogram_with_tags(&msg.name, msg.value.get())),
+            // the number of distinct elements
+            _ if semantics == Semantics::Set => {
+                send_with_tags!(target.client.gauge_with_tags(&msg.name, msg.value.get()))
+            }
+            _ => send_with_tags!(target
+                .client
+                .count_with_tags(&msg.name, msg.value.get() as i64)),
+        };
+        if let Err(ref e) = result {
+            warn!("Failed to send {} to statsd: {}", msg.name, e);
+        }
+
+        result
     }
 }
 
 impl Actor for Statsd {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.resolve_interval, |act, _ctx| {
+            for target in act.targets.iter_mut() {
+                target.check();
+                target.report();
+            }
+        });
+    }
 }
 
 impl Handler<Message> for Statsd {
@@ -57,10 +408,59 @@ impl Handler<Message> for Statsd {
 
     fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
         match msg {
-            Message::List(ref ms) => for m in ms {
-                self.count_with_tags(&m);
-            },
-            Message::Single(ref m) => self.count_with_tags(m),
+            Message::List(ref ms) => {
+                self.stats.received();
+                self.send_all(ms);
+            }
+            Message::Single(ref m) => {
+                self.stats.received();
+                self.send_all(std::slice::from_ref(m))
+            }
+            Message::Flush => self.stats.flushed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharding() {
+        let addresses = vec![
+            "a:8125".to_string(),
+            "b:8125".to_string(),
+            "c:8125".to_string(),
+        ];
+        let three = ring(&addresses);
+        let two = ring(&addresses[..2]);
+        assert_eq!(three.len(), 3 * RING_POINTS);
+
+        let names = (0..1000)
+            .map(|i| format!("metric.{}", i))
+            .collect::<Vec<_>>();
+        let mut counts = [0; 3];
+        let mut moved = 0;
+        for name in names.iter() {
+            let target = shard(&three, name);
+            counts[target] += 1;
+            // only the metrics of the removed server move
+            if target != 2 {
+                assert_eq!(shard(&two, name), target);
+            } else {
+                moved += 1;
+            }
         }
+        assert_eq!(moved, counts[2]);
+        assert!(counts.iter().all(|&c| c > 200));
+
+        assert_eq!(shard(&ring(&addresses[..1]), "anything"), 0);
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(prefixed("ingraind.metrics", "a.b"), "ingraind.metrics.a.b");
+        assert_eq!(prefixed("app.", "a.b"), "app.a.b");
+        assert_eq!(prefixed("", "a.b"), "a.b");
     }
 }
@@ -0,0 +1,254 @@
+//! Restarts the backends that panic.
+//!
+//! Every backend runs on its own arbiter, behind a supervisor that forwards
+//! it the pipeline's messages. A panic only stops the backend's arbiter,
+//! instead of ingraind, and once the supervisor finds the backend gone, it
+//! starts it again after a backoff that doubles with every restart, from
+//! `backoff_ms` up to `max_backoff_ms`. The message the backend panicked
+//! on is lost, and the ones arriving during the backoff are dropped.
+//!
+//! Raw measurements, such as captured packets, are only forwarded to the
+//! backends that accept them.
+//!
+//! A backend that has to restart more than `max_restarts` times within
+//! `window_s` seconds stops ingraind, as any other panic does. Restarts are
+//! reported by telemetry as `ingraind.self.backend.restarts`.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix::fut;
+use actix::prelude::*;
+
+use crate::backends::{without_raw, Deliver, Message};
+use crate::telemetry::BackendStats;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SupervisorConfig {
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: usize,
+    #[serde(default = "default_window_s")]
+    pub window_s: u64,
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn default_max_restarts() -> usize {
+    5
+}
+
+fn default_window_s() -> u64 {
+    300
+}
+
+fn default_backoff_ms() -> u64 {
+    100
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            max_restarts: default_max_restarts(),
+            window_s: default_window_s(),
+            backoff_ms: default_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+        }
+    }
+}
+
+thread_local! {
+    static SUPERVISED: Cell<bool> = Cell::new(false);
+}
+
+/// Whether the current thread runs a supervised backend, so a panic only
+/// stops its arbiter
+pub fn is_supervised() -> bool {
+    SUPERVISED.with(Cell::get)
+}
+
+/// A started backend, and the same backend as a recipient of deliveries if
+/// it acknowledges them
+pub type Started = (Recipient<Message>, Option<Recipient<Deliver>>);
+
+/// Starts a backend on an arbiter, reporting to the statistics of its
+/// pipeline
+pub type Start = Box<dyn Fn(&Arbiter, Arc<BackendStats>) -> Started>;
+
+pub struct Supervisor {
+    pipeline: String,
+    config: SupervisorConfig,
+    start: Start,
+    stats: Arc<BackendStats>,
+    /// Whether the backend accepts raw measurements
+    raw: bool,
+    /// The running backend, if it's not waiting to restart
+    backend: Option<Started>,
+    /// Tells the backends started by every restart apart
+    generation: u64,
+    /// When the backend restarted, within the window
+    restarts: VecDeque<Instant>,
+}
+
+impl Supervisor {
+    /// Starts the backend of `pipeline` behind a supervisor, which is
+    /// returned as the backend
+    pub fn launch(
+        pipeline: &str,
+        config: SupervisorConfig,
+        stats: Arc<BackendStats>,
+        acknowledges: bool,
+        raw: bool,
+        start: Start,
+    ) -> Started {
+        let addr = Supervisor {
+            pipeline: pipeline.to_string(),
+            config,
+            start,
+            stats,
+            raw,
+            backend: None,
+            generation: 0,
+            restarts: VecDeque::new(),
+        }
+        .start();
+
+        let deliver = if acknowledges {
+            Some(addr.clone().recipient())
+        } else {
+            None
+        };
+        (addr.recipient(), deliver)
+    }
+
+    fn spawn(&mut self) {
+        let arbiter = Arbiter::new();
+        arbiter.exec_fn(|| SUPERVISED.with(|supervised| supervised.set(true)));
+
+        self.generation += 1;
+        self.backend = Some((self.start)(&arbiter, self.stats.clone()));
+    }
+
+    /// Restarts the backend of `generation` after a backoff, unless it
+    /// already was
+    fn restart(&mut self, generation: u64, ctx: &mut Context<Self>) {
+        if generation != self.generation || self.backend.is_none() {
+            return;
+        }
+        self.backend = None;
+
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window_s);
+        while let Some(at) = self.restarts.front() {
+            if now.duration_since(*at) <= window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.config.max_restarts {
+            panic!(
+                "The backend of pipeline {} stopped more than {} times within {}s",
+                self.pipeline, self.config.max_restarts, self.config.window_s
+            );
+        }
+
+        let delay = backoff(&self.config, self.restarts.len());
+        self.restarts.push_back(now);
+        warn!(
+            "The backend of pipeline {} stopped, restarting it in {:?}",
+            self.pipeline, delay
+        );
+        ctx.run_later(delay, |act, _ctx| {
+            act.stats.restarted();
+            act.spawn();
+        });
+    }
+}
+
+/// The delay before the restart following `restarts` recent ones
+fn backoff(config: &SupervisorConfig, restarts: usize) -> Duration {
+    let factor = 1u64.checked_shl(restarts as u32).unwrap_or(u64::MAX);
+    let delay = config.backoff_ms.saturating_mul(factor);
+    Duration::from_millis(delay.min(config.max_backoff_ms))
+}
+
+impl Actor for Supervisor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        self.spawn();
+    }
+}
+
+impl Handler<Message> for Supervisor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, ctx: &mut Context<Self>) -> Self::Result {
+        let msg = match msg {
+            Message::Single(m) if !self.raw => match without_raw(vec![m]).pop() {
+                Some(m) => Message::Single(m),
+                None => return,
+            },
+            Message::List(ms) if !self.raw => Message::List(without_raw(ms)),
+            msg => msg,
+        };
+        let sent = match self.backend {
+            Some((ref backend, _)) => backend.do_send(msg),
+            None => return,
+        };
+
+        if sent.is_err() {
+            self.restart(self.generation, ctx);
+        }
+    }
+}
+
+impl Handler<Deliver> for Supervisor {
+    type Result = ResponseActFuture<Self, (), ()>;
+
+    fn handle(&mut self, msg: Deliver, _ctx: &mut Context<Self>) -> Self::Result {
+        let msg = if self.raw {
+            msg
+        } else {
+            Deliver(without_raw(msg.0))
+        };
+        let delivery = match self.backend {
+            Some((_, Some(ref deliver))) => deliver.send(msg),
+            _ => return Box::new(fut::err(())),
+        };
+
+        let generation = self.generation;
+        Box::new(
+            delivery
+                .into_actor(self)
+                .then(move |res, act, ctx| match res {
+                    Ok(res) => fut::result(res),
+                    Err(_) => {
+                        act.restart(generation, ctx);
+                        fut::err(())
+                    }
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off() {
+        let config = SupervisorConfig::default();
+        assert_eq!(backoff(&config, 0), Duration::from_millis(100));
+        assert_eq!(backoff(&config, 3), Duration::from_millis(800));
+        assert_eq!(backoff(&config, 10), Duration::from_secs(30));
+        assert_eq!(backoff(&config, 100), Duration::from_secs(30));
+    }
+}
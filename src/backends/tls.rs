@@ -0,0 +1,275 @@
+//! TLS settings shared by the network backends.
+//!
+//! The server is verified with the certificates in `ca_file`, or with the
+//! system's certificate bundle if it's not set, or the Mozilla roots bundled
+//! with webpki-roots if the system has none. The backend authenticates
+//! with `client_cert` and `client_key` if they're set. `server_name` checks
+//! the server's certificate against another name than the host it's reached
+//! at, and `min_version` is the oldest TLS version that's accepted. Every
+//! backend sends the host it's reached at as the server name indication,
+//! whether `server_name` is set or not.
+//!
+//! Backends with a `tls` block refuse to send measurements in cleartext.
+//!
+//! The settings are built with the rustls that hyper-rustls uses.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use backend_rustls as rustls;
+use failure::{bail, format_err, Error};
+use rustls::internal::pemfile;
+use rustls::{Certificate, ProtocolVersion, RootCertStore, ServerCertVerified, TLSError};
+
+#[cfg(any(
+    feature = "http-backend",
+    feature = "grpc-backend",
+    feature = "clickhouse-backend",
+    feature = "azure-backend",
+    feature = "s3-backend"
+))]
+use crate::backends::proxy::{ProxyConfig, ProxyConnector};
+
+/// Where distributions keep the bundle of trusted certificates
+const SYSTEM_CA_FILES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Defaults to the system's certificate bundle, or the bundled roots
+    pub ca_file: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// The name the server's certificate is checked against. Defaults to the
+    /// host the server is reached at.
+    pub server_name: Option<String>,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    V12,
+    #[serde(rename = "1.3")]
+    V13,
+}
+
+impl Default for TlsVersion {
+    fn default() -> TlsVersion {
+        TlsVersion::V12
+    }
+}
+
+impl TlsVersion {
+    fn versions(self) -> Vec<ProtocolVersion> {
+        match self {
+            TlsVersion::V12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            TlsVersion::V13 => vec![ProtocolVersion::TLSv1_3],
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn client_config(&self) -> Result<rustls::ClientConfig, Error> {
+        let mut config = rustls::ClientConfig::new();
+        config.versions = self.min_version.versions();
+
+        let ca_file = self.ca_file.as_ref().map(String::as_str).or_else(|| {
+            SYSTEM_CA_FILES
+                .iter()
+                .copied()
+                .find(|file| Path::new(file).exists())
+        });
+        match ca_file {
+            Some(ca_file) => add_ca_file(&mut config.root_store, ca_file)?,
+            // containers often ship without a bundle
+            None => config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+                    .map_err(|_| format_err!("Invalid certificates in {}", cert))?;
+                let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+                    .map_err(|_| format_err!("Invalid private key in {}", key))?;
+                if keys.is_empty() {
+                    keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key)?))
+                        .map_err(|_| format_err!("Invalid private key in {}", key))?;
+                }
+                let private_key = keys
+                    .pop()
+                    .ok_or_else(|| format_err!("No private key in {}", key))?;
+                // rustls panics on keys it can't sign with
+                rustls::sign::any_supported_type(&private_key)
+                    .map_err(|_| format_err!("Unsupported private key in {}", key))?;
+                config.set_single_client_cert(certs, private_key);
+            }
+            (None, None) => (),
+            _ => bail!("client_cert and client_key have to be set together"),
+        }
+
+        if let Some(ref name) = self.server_name {
+            let verifier = ServerName::new(name)?;
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(verifier));
+        }
+
+        Ok(config)
+    }
+}
+
+fn add_ca_file(roots: &mut RootCertStore, ca_file: &str) -> Result<(), Error> {
+    let mut ca = BufReader::new(File::open(ca_file)?);
+    let (added, _) = roots
+        .add_pem_file(&mut ca)
+        .map_err(|_| format_err!("Invalid certificates in {}", ca_file))?;
+    if added == 0 {
+        bail!("No certificates in {}", ca_file);
+    }
+
+    Ok(())
+}
+
+/// The signature algorithms rustls accepts in certificates
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Verifies certificates against a fixed name, whatever host was connected.
+/// The host is still the server name indication.
+///
+/// rustls 0.16 doesn't export its verifier, so this checks the chain the
+/// same way it does.
+struct ServerName {
+    name: webpki::DNSName,
+}
+
+impl ServerName {
+    fn new(name: &str) -> Result<ServerName, Error> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| format_err!("Invalid server name {}", name))?;
+
+        Ok(ServerName {
+            name: name.to_owned(),
+        })
+    }
+}
+
+impl rustls::ServerCertVerifier for ServerName {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let (cert, chain) = presented_certs
+            .split_first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let cert = webpki::EndEntityCert::from(&cert.0).map_err(TLSError::WebPKIError)?;
+        let chain: Vec<&[u8]> = chain.iter().map(|cert| cert.0.as_slice()).collect();
+        let anchors: Vec<_> = roots
+            .roots
+            .iter()
+            .map(|root| root.to_trust_anchor())
+            .collect();
+        let now = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+
+        cert.verify_is_valid_tls_server_cert(
+            SIGNATURE_ALGORITHMS,
+            &webpki::TLSServerTrustAnchors(&anchors),
+            &chain,
+            now,
+        )
+        .and_then(|_| cert.verify_is_valid_for_dns_name(self.name.as_ref()))
+        .map(|_| ServerCertVerified::assertion())
+        .map_err(TLSError::WebPKIError)
+    }
+}
+
+/// A connector for the HTTP backends, that uses `tls` for `https` URIs, and
+/// connects through `proxy`
+#[cfg(any(
+    feature = "http-backend",
+    feature = "grpc-backend",
+    feature = "clickhouse-backend",
+    feature = "azure-backend",
+    feature = "s3-backend"
+))]
+pub fn https_connector(
+    threads: usize,
+    tls: Option<&TlsConfig>,
+    proxy: Option<&ProxyConfig>,
+) -> Result<hyper_rustls::HttpsConnector<ProxyConnector>, Error> {
+    let mut config = match tls {
+        Some(tls) => tls.client_config()?,
+        None => TlsConfig::default().client_config()?,
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let connector = ProxyConnector::new(threads, proxy)?;
+
+    Ok((connector, config).into())
+}
+
+/// Fails if `uri` would send measurements in cleartext while `tls` is set
+pub fn check_scheme(uri: &str, tls: Option<&TlsConfig>) -> Result<(), Error> {
+    if tls.is_some() && !uri.starts_with("https://") {
+        bail!("{} is not an https URI, but tls is set", uri);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings() {
+        let config: TlsConfig = toml::from_str("").unwrap();
+        assert_eq!(config.min_version, TlsVersion::V12);
+        // with the system's bundle or the bundled roots
+        assert!(config.client_config().is_ok());
+
+        let config: TlsConfig = toml::from_str(
+            r#"
+ca_file = "/nonexistent/ca.pem"
+min_version = "1.3"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.min_version.versions(),
+            vec![ProtocolVersion::TLSv1_3]
+        );
+        assert!(config.client_config().is_err());
+
+        let config: TlsConfig =
+            toml::from_str(r#"client_cert = "/etc/ingraind/cert.pem""#).unwrap();
+        assert!(check_scheme("https://collector", Some(&config)).is_ok());
+        assert!(check_scheme("http://collector", Some(&config)).is_err());
+        assert!(check_scheme("http://collector", None).is_ok());
+    }
+}
@@ -0,0 +1,477 @@
+//! A write-ahead log in front of a backend, for at-least-once delivery.
+//!
+//! Every batch is appended to a segment file in `dir` before it's sent, and
+//! batches are sent one at a time, in order, until the backend acknowledges
+//! them. A batch that fails is retried every `retry_interval_s`. Segments are
+//! deleted once every batch in them is acknowledged, so batches survive both
+//! restarts and long outages of the backend.
+//!
+//! Records are laid out as the length of the payload, its CRC-32 and its
+//! sequence number, followed by the batch encoded as JSON. A record that's
+//! cut short or doesn't match its CRC, like after a crash, ends its segment.
+//!
+//! When the segments grow beyond `max_bytes`, the oldest is dropped.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::fut;
+use actix::prelude::*;
+use failure::{bail, format_err, Error};
+
+use crate::backends::{Deliver, Message};
+use crate::metrics::Measurement;
+use crate::telemetry::BackendStats;
+
+/// The length, the CRC and the sequence of a record
+const HEADER_LEN: usize = 16;
+const ACKED_FILE: &str = "acked";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalConfig {
+    dir: String,
+    #[serde(default = "default_segment_bytes")]
+    segment_bytes: u64,
+    #[serde(default = "default_max_bytes")]
+    max_bytes: u64,
+    /// Syncs every record to disk before sending it
+    #[serde(default = "default_fsync")]
+    fsync: bool,
+    #[serde(default = "default_retry_interval_s")]
+    retry_interval_s: u64,
+}
+
+fn default_segment_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_fsync() -> bool {
+    true
+}
+
+fn default_retry_interval_s() -> u64 {
+    5
+}
+
+struct Segment {
+    first: u64,
+    size: u64,
+}
+
+/// The segment files of a log, and where the records that weren't
+/// acknowledged are
+pub struct Log {
+    dir: PathBuf,
+    segment_bytes: u64,
+    max_bytes: u64,
+    fsync: bool,
+    segments: VecDeque<Segment>,
+    writer: Option<File>,
+    /// The sequence, the segment and the offset of every pending record
+    pending: VecDeque<(u64, u64, u64)>,
+    next: u64,
+    acked: u64,
+}
+
+impl Log {
+    pub fn open(config: &WalConfig) -> Result<Log, Error> {
+        let dir = PathBuf::from(&config.dir);
+        fs::create_dir_all(&dir)?;
+        let acked = match fs::read_to_string(dir.join(ACKED_FILE)) {
+            Ok(acked) => acked.trim().parse()?,
+            Err(_) => 0,
+        };
+
+        let mut firsts = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                if !name.ends_with(".wal") {
+                    return None;
+                }
+                name.trim_end_matches(".wal").parse::<u64>().ok()
+            })
+            .collect::<Vec<_>>();
+        firsts.sort();
+
+        let mut log = Log {
+            dir,
+            segment_bytes: config.segment_bytes,
+            max_bytes: config.max_bytes,
+            fsync: config.fsync,
+            segments: VecDeque::new(),
+            writer: None,
+            pending: VecDeque::new(),
+            next: acked + 1,
+            acked,
+        };
+        for first in firsts {
+            log.recover(first)?;
+        }
+
+        Ok(log)
+    }
+
+    fn path(&self, first: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.wal", first))
+    }
+
+    /// Reads the records of a segment, and cuts it at the first invalid one
+    fn recover(&mut self, first: u64) -> Result<(), Error> {
+        let path = self.path(first);
+        let data = fs::read(&path)?;
+        let mut offset = 0;
+        while let Some((sequence, len)) = parse_record(&data[offset..]) {
+            if sequence > self.acked {
+                self.pending.push_back((sequence, first, offset as u64));
+            }
+            self.next = self.next.max(sequence + 1);
+            offset += HEADER_LEN + len;
+        }
+        if offset < data.len() {
+            warn!("Truncating {} at an invalid record", path.display());
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(offset as u64)?;
+        }
+
+        self.segments.push_back(Segment {
+            first,
+            size: offset as u64,
+        });
+        Ok(())
+    }
+
+    /// Appends a record, and returns its sequence
+    pub fn append(&mut self, payload: &[u8]) -> Result<u64, Error> {
+        let full = self
+            .segments
+            .back()
+            .map_or(true, |s| s.size >= self.segment_bytes);
+        if full {
+            self.segments.push_back(Segment {
+                first: self.next,
+                size: 0,
+            });
+            self.writer = None;
+        }
+        if self.writer.is_none() {
+            let first = self.segments.back().unwrap().first;
+            let writer = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(first))?;
+            self.writer = Some(writer);
+        }
+
+        let sequence = self.next;
+        let record = record(sequence, payload);
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(&record)?;
+        if self.fsync {
+            writer.sync_data()?;
+        }
+
+        let segment = self.segments.back_mut().unwrap();
+        self.pending
+            .push_back((sequence, segment.first, segment.size));
+        segment.size += record.len() as u64;
+        self.next += 1;
+        self.enforce_max_bytes()?;
+
+        Ok(sequence)
+    }
+
+    fn enforce_max_bytes(&mut self) -> Result<(), Error> {
+        while self.segments.len() > 1
+            && self.segments.iter().map(|s| s.size).sum::<u64>() > self.max_bytes
+        {
+            let segment = self.segments.pop_front().unwrap();
+            let before = self.pending.len();
+            self.pending.retain(|&(_, first, _)| first != segment.first);
+            warn!(
+                "Write-ahead log is full, dropped {} batches",
+                before - self.pending.len()
+            );
+            fs::remove_file(self.path(segment.first))?;
+        }
+
+        Ok(())
+    }
+
+    /// The oldest record that wasn't acknowledged
+    pub fn first_pending(&self) -> Option<u64> {
+        self.pending.front().map(|&(sequence, _, _)| sequence)
+    }
+
+    pub fn read(&self, sequence: u64) -> Result<Vec<u8>, Error> {
+        let &(_, first, offset) = self
+            .pending
+            .iter()
+            .find(|&&(s, _, _)| s == sequence)
+            .ok_or_else(|| format_err!("Record {} is not pending", sequence))?;
+
+        let mut file = File::open(self.path(first))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; HEADER_LEN];
+        file.read_exact(&mut header)?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let mut record = header.to_vec();
+        record.resize(HEADER_LEN + len, 0);
+        file.read_exact(&mut record[HEADER_LEN..])?;
+
+        match parse_record(&record) {
+            Some((s, _)) if s == sequence => Ok(record.split_off(HEADER_LEN)),
+            _ => bail!("Record {} is corrupt", sequence),
+        }
+    }
+
+    /// Acknowledges every record up to and including `sequence`, and
+    /// deletes the segments that only hold acknowledged records
+    pub fn ack(&mut self, sequence: u64) -> Result<(), Error> {
+        self.acked = self.acked.max(sequence);
+        while self
+            .pending
+            .front()
+            .map_or(false, |&(s, _, _)| s <= self.acked)
+        {
+            self.pending.pop_front();
+        }
+
+        let tmp = self.dir.join(format!("{}.tmp", ACKED_FILE));
+        fs::write(&tmp, self.acked.to_string())?;
+        fs::rename(&tmp, self.dir.join(ACKED_FILE))?;
+
+        while self.segments.len() > 1 && self.segments[1].first <= self.acked + 1 {
+            let segment = self.segments.pop_front().unwrap();
+            fs::remove_file(self.path(segment.first))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+fn record(sequence: u64, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&[0; 4]);
+    record.extend_from_slice(&sequence.to_le_bytes());
+    record.extend_from_slice(payload);
+    let crc = crc32(&record[8..]);
+    record[4..8].copy_from_slice(&crc.to_le_bytes());
+
+    record
+}
+
+/// The sequence and the payload length of a valid record at the start of
+/// `data`
+fn parse_record(data: &[u8]) -> Option<(u64, usize)> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let crc = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+    let record = data.get(8..HEADER_LEN + len)?;
+    if crc32(record) != crc {
+        return None;
+    }
+
+    let mut sequence = [0u8; 8];
+    sequence.copy_from_slice(&record[..8]);
+    Some((u64::from_le_bytes(sequence), len))
+}
+
+/// CRC-32 (IEEE)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+pub struct Wal {
+    log: Log,
+    backend: Recipient<Deliver>,
+    delivering: bool,
+    retry_interval: Duration,
+    stats: Arc<BackendStats>,
+}
+
+impl Wal {
+    pub fn launch(
+        config: WalConfig,
+        backend: Recipient<Deliver>,
+        stats: Arc<BackendStats>,
+    ) -> Result<Recipient<Message>, Error> {
+        let log = Log::open(&config)
+            .map_err(|e| format_err!("Failed to open write-ahead log {}: {}", config.dir, e))?;
+        if log.pending() > 0 {
+            info!(
+                "Sending {} batches left in write-ahead log {}",
+                log.pending(),
+                config.dir
+            );
+        }
+
+        Ok(Wal {
+            log,
+            backend,
+            delivering: false,
+            retry_interval: Duration::from_secs(config.retry_interval_s),
+            stats,
+        }
+        .start()
+        .recipient())
+    }
+
+    /// Sends the oldest pending batch, unless one is on its way
+    fn deliver(&mut self, ctx: &mut Context<Self>) {
+        if self.delivering {
+            return;
+        }
+        let sequence = match self.log.first_pending() {
+            Some(sequence) => sequence,
+            None => return,
+        };
+
+        let batch = self.log.read(sequence).and_then(|payload| {
+            serde_json::from_slice::<Vec<Measurement>>(&payload).map_err(Error::from)
+        });
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                error!("Skipping batch {} of write-ahead log: {}", sequence, e);
+                if let Err(e) = self.log.ack(sequence) {
+                    error!("Failed to update write-ahead log: {}", e);
+                }
+                return self.deliver(ctx);
+            }
+        };
+
+        self.delivering = true;
+        ctx.spawn(
+            self.backend
+                .send(Deliver(batch))
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    act.delivering = false;
+                    match res {
+                        Ok(Ok(())) => {
+                            if let Err(e) = act.log.ack(sequence) {
+                                error!("Failed to update write-ahead log: {}", e);
+                            }
+                            act.deliver(ctx);
+                        }
+                        _ => {
+                            ctx.run_later(act.retry_interval, |act, ctx| act.deliver(ctx));
+                        }
+                    }
+
+                    fut::ok(())
+                }),
+        );
+    }
+}
+
+impl Actor for Wal {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.deliver(ctx);
+    }
+}
+
+impl Handler<Message> for Wal {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, ctx: &mut Context<Self>) -> Self::Result {
+        let payload = match msg {
+            Message::Single(m) => serde_json::to_vec(&[m]),
+            Message::List(ref ms) => serde_json::to_vec(ms),
+            // everything received is on disk already
+            Message::Flush => return self.stats.flushed(),
+        };
+        self.stats.received();
+
+        match payload
+            .map_err(Error::from)
+            .and_then(|p| self.log.append(&p))
+        {
+            Ok(_) => self.deliver(ctx),
+            Err(e) => error!("Failed to write to write-ahead log: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn config(dir: &Path) -> WalConfig {
+        WalConfig {
+            dir: dir.to_string_lossy().to_string(),
+            segment_bytes: 64,
+            max_bytes: 1024,
+            fsync: false,
+            retry_interval_s: 1,
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn log() {
+        let dir = env::temp_dir().join(format!("ingraind-wal-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut log = Log::open(&config(&dir)).unwrap();
+
+        // every other record fills a segment
+        for i in 1..=4u8 {
+            assert_eq!(log.append(&[i; 40]).unwrap(), u64::from(i));
+        }
+        assert_eq!(log.segments.len(), 2);
+        log.ack(2).unwrap();
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(log.first_pending(), Some(3));
+
+        // a torn write is cut off, and the rest is sent after a restart
+        let last = log.path(3);
+        let mut file = OpenOptions::new().append(true).open(&last).unwrap();
+        file.write_all(&record(5, &[5; 40])[..20]).unwrap();
+        drop(log);
+
+        let mut log = Log::open(&config(&dir)).unwrap();
+        assert_eq!(log.pending(), 2);
+        assert_eq!(log.read(4).unwrap(), vec![4; 40]);
+        assert_eq!(fs::metadata(&last).unwrap().len(), 2 * 56);
+        assert_eq!(log.append(&[5; 8]).unwrap(), 5);
+
+        log.ack(5).unwrap();
+        assert_eq!(log.first_pending(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
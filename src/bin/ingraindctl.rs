@@ -0,0 +1,68 @@
+#![deny(clippy::all)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+
+use clap::{crate_version, App, AppSettings, Arg, SubCommand};
+use serde_json::Value;
+
+const DEFAULT_SOCKET: &str = "/run/ingraind.sock";
+
+fn query(socket: &str, request: &str) -> Result<Value, String> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| format!("{}: {}", socket, e))?;
+    writeln!(stream, "{}", request).map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(|e| e.to_string())?;
+    let response: Value = serde_json::from_str(&response).map_err(|e| e.to_string())?;
+
+    match response.get("error").and_then(|e| e.as_str()) {
+        Some(error) => Err(error.to_string()),
+        None => Ok(response),
+    }
+}
+
+fn main() {
+    let matches = App::new("ingraindctl")
+        .version(crate_version!())
+        .about("Queries a running ingraind through its control socket")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .short("s")
+                .value_name("PATH")
+                .default_value(DEFAULT_SOCKET)
+                .help("Path to the control socket"),
+        )
+        .subcommand(SubCommand::with_name("status").about("Shows statistics of the loaded grains"))
+        .subcommand(SubCommand::with_name("programs").about("Lists the programs of each grain"))
+        .subcommand(SubCommand::with_name("maps").about("Lists the maps of each grain"))
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Dumps the contents of a map as JSON")
+                .arg(Arg::with_name("grain").required(true).index(1))
+                .arg(Arg::with_name("map").required(true).index(2)),
+        )
+        .get_matches();
+
+    let request = match matches.subcommand() {
+        ("dump", Some(args)) => format!(
+            "dump {} {}",
+            args.value_of("grain").unwrap(),
+            args.value_of("map").unwrap()
+        ),
+        (command, _) => command.to_string(),
+    };
+
+    match query(matches.value_of("socket").unwrap(), &request) {
+        Ok(response) => println!("{}", serde_json::to_string_pretty(&response).unwrap()),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
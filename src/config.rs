@@ -1,25 +1,426 @@
 use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix::{Actor, Arbiter, Recipient};
+use failure::{format_err, Error};
 use log::LevelFilter;
+use serde::Serialize;
 
 use crate::aggregations::*;
+use crate::backends::supervisor::{Started, Supervisor, SupervisorConfig};
 use crate::backends::*;
-use crate::grains::{self, dns, file, network, osquery, syscalls, tls};
-use crate::grains::{EBPFActor, EBPFGrain, EBPFProbe};
+use crate::containers::ContainersConfig;
+use crate::grains::{
+    self, arp, cgroup, cpu, creds, db, dhcp_ntp, dns, file, generic, icmp, kernel_load, listen,
+    memory, meta, network, osquery, plugin, prebuilt, process_tree, ptrace, sched, snapshot, ssh,
+    ssl, synthetic, syscalls, tcp_latency, tls, tty, udp, unix, usdt,
+};
+use crate::grains::limits::{LimitsConfig, RateLimiter};
+use crate::grains::workers::{Workers, WorkersConfig};
+use crate::grains::meta::Meta;
+use crate::grains::{AttachPolicy, DecodeErrorSink, EBPFActor, EBPFGrain, EBPFProbe};
+use crate::memlock::MemlockConfig;
+use crate::metrics::clock::ClockConfig;
+use crate::privileges::PrivilegesConfig;
+use crate::record::Handlers;
+use crate::shedding::LoadSheddingConfig;
+use crate::telemetry::{BackendStats, TelemetryConfig};
+use crate::tenancy::{TenancyConfig, Tenants};
+
+/// The grains that can be used as a probe's `type`, and their options
+pub const GRAINS: &[(&str, &[&str])] = &[
+    ("Files", &["monitor_dirs", "aggregate_interval_ms", "filters", "disk_usage"]),
+    ("Network", &["egress_policy", "aggregate_interval_ms", "filters"]),
+    ("TcpLatency", &["rtt_sample_interval_ms"]),
+    ("UDP", &[]),
+    ("ICMP", &[]),
+    ("Listen", &[]),
+    ("Profile", &["frequency_hz", "interval_s"]),
+    ("Memory", &["mmap_threshold", "major_faults"]),
+    ("Scheduler", &["min_latency_us", "histogram_interval_ms"]),
+    ("Cpu", &["interval_ms", "top", "per_cpu"]),
+    ("Credentials", &[]),
+    ("KernelLoad", &[]),
+    ("ProcessTree", &[]),
+    ("Ptrace", &[]),
+    ("Unix", &[]),
+    ("Ssh", &[]),
+    ("Tty", &["shells", "redact"]),
+    (
+        "Usdt",
+        &[
+            "binary",
+            "provider",
+            "probe",
+            "pid",
+            "metric",
+            "string_args",
+        ],
+    ),
+    ("Ssl", &["library", "pid"]),
+    ("Db", &["libpq", "postgres", "libmysqlclient", "pid"]),
+    ("Generic", &["function", "metric", "args", "ordered"]),
+    ("Cgroup", &["flush_interval_ms"]),
+    (
+        "DNS",
+        &["interface", "xdp_mode", "capture_packets", "exfiltration"],
+    ),
+    ("DhcpNtp", &["interface", "xdp_mode"]),
+    (
+        "Arp",
+        &["interface", "xdp_mode", "gratuitous_flood_threshold"],
+    ),
+    ("TLS", &["interface", "capture_packets"]),
+    ("Syscall", &["monitor_syscalls", "sampling"]),
+    ("StatsD", &["bind_address"]),
+    ("Plugin", &["name", "command", "socket"]),
+    (
+        "Osquery",
+        &[
+            "queries",
+            "osqueryi",
+            "osqueryi_args",
+            "config_path",
+            "interval_ms",
+            "run_at_start",
+        ],
+    ),
+    (
+        "Snapshot",
+        &["interval_ms", "processes", "sockets", "mounts"],
+    ),
+    (
+        "Synthetic",
+        &["rate", "interval_ms", "seed", "measurements"],
+    ),
+    (
+        "Test",
+        &[
+            "name",
+            "measurement",
+            "measurement_type",
+            "aggregation_type",
+            "tags",
+            "measurements_per_second",
+            "message_len",
+        ],
+    ),
+];
+
+/// The prefix of environment variables that override the configuration, like
+/// `INGRAIND__PIPELINE__S3__CONFIG__BUCKET` for `pipeline.s3.config.bucket`
+pub const ENV_PREFIX: &str = "INGRAIND__";
+
+fn default_shutdown_timeout_s() -> u64 {
+    5
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub log: Option<Logging>,
+    #[serde(default = "default_shutdown_timeout_s")]
+    pub shutdown_timeout_s: u64,
+    #[serde(flatten)]
+    pub privileges: PrivilegesConfig,
+    #[serde(default)]
+    pub memlock: MemlockConfig,
+    pub telemetry: Option<TelemetryConfig>,
+    pub load_shedding: Option<LoadSheddingConfig>,
+    pub clock: Option<ClockConfig>,
+    pub containers: Option<ContainersConfig>,
+    /// The proxy of the backends that don't set their own
+    pub proxy: Option<crate::backends::proxy::ProxyConfig>,
+    pub workers: Option<WorkersConfig>,
+    #[cfg(feature = "admin-endpoint")]
+    pub admin: Option<crate::admin::AdminConfig>,
+    pub control: Option<crate::control::ControlConfig>,
+    #[cfg(feature = "kubernetes")]
+    pub kubernetes: Option<crate::kubernetes::KubernetesConfig>,
+    /// Probes to load from files instead of the built-in ones
+    pub prebuilt_probes: Option<prebuilt::PrebuiltConfig>,
+    /// Prefixes the names of all measurements, like `prod.edge`, unless a
+    /// pipeline sets its own
+    pub namespace: Option<String>,
+    pub tenancy: Option<TenancyConfig>,
     pub probe: Vec<Probe>,
     pub pipeline: HashMap<String, Pipeline>,
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            log: None,
+            shutdown_timeout_s: default_shutdown_timeout_s(),
+            privileges: PrivilegesConfig::default(),
+            memlock: MemlockConfig::default(),
+            telemetry: None,
+            load_shedding: None,
+            clock: None,
+            containers: None,
+            proxy: None,
+            workers: None,
+            #[cfg(feature = "admin-endpoint")]
+            admin: None,
+            control: None,
+            #[cfg(feature = "kubernetes")]
+            kubernetes: None,
+            prebuilt_probes: None,
+            namespace: None,
+            tenancy: None,
+            probe: vec![],
+            pipeline: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let content = fs::read(path)?;
+        Ok(toml::from_slice(content.as_slice())?)
+    }
+
+    /// Loads the configuration at `path`, overridden by the `INGRAIND__*`
+    /// environment variables, then by `overrides` of `key=value`
+    pub fn load_layered(path: &str, overrides: &[&str]) -> Result<Config, Error> {
+        let content = fs::read_to_string(path)?;
+        Config::layered(&content, env::vars(), overrides)
+    }
+
+    fn layered(
+        content: &str,
+        vars: impl Iterator<Item = (String, String)>,
+        overrides: &[&str],
+    ) -> Result<Config, Error> {
+        let mut config: toml::Value = toml::from_str(content)?;
+
+        for (var, value) in vars.filter(|(var, _)| var.starts_with(ENV_PREFIX)) {
+            let key = var[ENV_PREFIX.len()..]
+                .split("__")
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>();
+            override_value(&mut config, &key, &value).map_err(|e| format_err!("{}: {}", var, e))?;
+        }
+        for o in overrides {
+            let eq = o
+                .find('=')
+                .ok_or_else(|| format_err!("{}: expected key=value", o))?;
+            let key = o[..eq].split('.').map(String::from).collect::<Vec<_>>();
+            override_value(&mut config, &key, &o[eq + 1..])
+                .map_err(|e| format_err!("{}: {}", o, e))?;
+        }
+
+        Ok(config.try_into()?)
+    }
+
+    /// The template the measurements of `pipeline` are renamed with
+    pub fn name_template(&self, pipeline: &Pipeline) -> Result<Option<NameTemplate>, Error> {
+        let namespace = pipeline
+            .namespace
+            .as_ref()
+            .or_else(|| self.namespace.as_ref());
+        NameTemplate::from_config(
+            namespace.map(String::as_str),
+            pipeline.name_template.as_ref().map(String::as_str),
+        )
+    }
+
+    /// Validates the configuration without loading anything, and returns
+    /// the programs of each probe
+    pub fn check(&self) -> Result<Vec<Vec<String>>, Error> {
+        self.check_with(&[])
+    }
+
+    /// Like `check`, with `pipelines` started outside the configuration
+    pub fn check_with(&self, pipelines: &[&str]) -> Result<Vec<Vec<String>>, Error> {
+        let telemetry = self.telemetry.iter().flat_map(|t| t.pipelines.iter());
+        let probes = self.probe.iter().flat_map(|p| p.pipelines.iter());
+        let tenants = match self.tenancy {
+            Some(ref tenancy) => Some(Tenants::new(tenancy)?),
+            None => None,
+        };
+        let routes = tenants
+            .iter()
+            .flat_map(|t| t.pipelines().values().flatten());
+        if let Some(p) = telemetry
+            .chain(probes)
+            .chain(routes)
+            .find(|p| !self.pipeline.contains_key(*p) && !pipelines.contains(&p.as_str()))
+        {
+            return Err(format_err!("pipeline {} not found", p));
+        }
+        if let Some((name, _)) = self
+            .pipeline
+            .iter()
+            .find(|(_, p)| p.wal.is_some() && !p.backend.acknowledges())
+        {
+            return Err(format_err!(
+                "pipeline {}: the backend can't be used with a write-ahead log",
+                name
+            ));
+        }
+        if let Some((name, _)) = self.pipeline.iter().find(|(_, p)| {
+            let in_flight = p.flush.as_ref().and_then(|f| f.max_in_flight).is_some();
+            in_flight && (p.wal.is_some() || !p.backend.acknowledges())
+        }) {
+            return Err(format_err!(
+                "pipeline {}: max_in_flight needs a backend that acknowledges deliveries, without a write-ahead log",
+                name
+            ));
+        }
+        if let Some(ref telemetry) = self.telemetry {
+            telemetry.check()?;
+        }
+        if let Some(ref load_shedding) = self.load_shedding {
+            load_shedding.check()?;
+        }
+        #[cfg(feature = "kubernetes")]
+        {
+            if let Some(ref kubernetes) = self.kubernetes {
+                kubernetes.check()?;
+            }
+        }
+        for (name, pipeline) in self.pipeline.iter() {
+            self.name_template(pipeline)
+                .map_err(|e| format_err!("pipeline {}: {}", name, e))?;
+        }
+        for probe in self.probe.iter() {
+            probe
+                .grain
+                .check()
+                .map_err(|e| format_err!("{} probe: {}", probe.grain.name(), e))?;
+            if !probe.required && probe.retry_interval_s == 0 {
+                return Err(format_err!(
+                    "{} probe: retry_interval_s must be greater than 0",
+                    probe.grain.name()
+                ));
+            }
+        }
+        // prebuilt probes are only loaded when ingraind starts
+        let prebuilt = match self.prebuilt_probes {
+            Some(ref prebuilt) => prebuilt::read(prebuilt)?,
+            None => vec![],
+        };
+        let replacement = |probe: &Probe| prebuilt.iter().find(|p| p.grain == probe.grain.name());
+        let code = |probe: &Probe| match replacement(probe) {
+            Some(replacement) => Some(replacement.code.as_slice()),
+            None => probe.grain.code(),
+        };
+        for probe in self.probe.iter() {
+            probe.check_limits(code(probe))?;
+            let metas = match replacement(probe) {
+                Some(replacement) => {
+                    if let Some(bundled) = probe.grain.code() {
+                        prebuilt::check_replaces(bundled, &replacement.code).map_err(|e| {
+                            format_err!("{} prebuilt probe: {}", replacement.grain, e)
+                        })?;
+                    }
+                    replacement.metas.clone()
+                }
+                None => probe.metas()?,
+            };
+            probe.check_kernel(&metas)?;
+        }
+
+        self.probe
+            .iter()
+            .map(|probe| match code(probe) {
+                Some(code) => grains::program_names(code)
+                    .map_err(|e| format_err!("{} probe: {}", probe.grain.name(), e)),
+                None => Ok(vec![]),
+            })
+            .collect()
+    }
+
+    /// Describes the probes, and the pipelines their measurements go through
+    pub fn describe(&self) -> String {
+        let mut out = String::new();
+        for probe in self.probe.iter() {
+            let _ = writeln!(
+                out,
+                "probe {} -> {}",
+                probe.grain.name(),
+                probe.pipelines.join(", ")
+            );
+        }
+
+        let mut pipelines = self.pipeline.iter().collect::<Vec<_>>();
+        pipelines.sort_by_key(|(name, _)| name.as_str());
+        for (name, pipeline) in pipelines {
+            let steps = pipeline.steps.iter().flatten().map(|s| tag(s, "type"));
+            let stages = steps
+                .chain(Some(tag(&pipeline.backend, "backend")))
+                .collect::<Vec<_>>();
+            let _ = writeln!(out, "pipeline {}: {}", name, stages.join(" -> "));
+        }
+
+        out
+    }
+}
+
+/// Sets the value at `key`, a path of table keys and array indices, and
+/// adds the tables missing on the way. The value is parsed as TOML, or taken
+/// as a string if it isn't valid TOML.
+fn override_value(config: &mut toml::Value, key: &[String], value: &str) -> Result<(), Error> {
+    if key.iter().any(String::is_empty) {
+        return Err(format_err!("empty key"));
+    }
+    let (last, parents) = key.split_last().unwrap();
+
+    let mut current = config;
+    for k in parents {
+        current = match current {
+            toml::Value::Table(table) => table
+                .entry(k.clone())
+                .or_insert(toml::Value::Table(Default::default())),
+            toml::Value::Array(array) => k
+                .parse::<usize>()
+                .ok()
+                .and_then(move |i| array.get_mut(i))
+                .ok_or_else(|| format_err!("no element {}", k))?,
+            _ => return Err(format_err!("{} is not a table", k)),
+        };
+    }
+
+    let value = toml::from_str::<toml::Value>(&format!("v = {}", value))
+        .ok()
+        .and_then(|mut v| v.as_table_mut()?.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()));
+    match current {
+        toml::Value::Table(table) => {
+            table.insert(last.clone(), value);
+        }
+        toml::Value::Array(array) => {
+            let element = last
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| array.get_mut(i))
+                .ok_or_else(|| format_err!("no element {}", last))?;
+            *element = value;
+        }
+        _ => return Err(format_err!("{} is not in a table", last)),
+    }
+
+    Ok(())
+}
+
+/// The value of the `key` field of an internally tagged enum
+fn tag(value: &impl Serialize, key: &str) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|v| v.get(key).and_then(|t| t.as_str()).map(String::from))
+        .unwrap_or_default()
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum Logging {
     EnvLogger,
     Syslog(SyslogConfig),
+    Tracing(crate::logging::TracingConfig),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,6 +433,85 @@ pub struct Probe {
     pub pipelines: Vec<String>,
     #[serde(rename = "config")]
     pub grain: Grain,
+    /// Whether ingraind stops if the grain's programs fail to attach
+    #[serde(default = "default_required")]
+    pub required: bool,
+    #[serde(default = "default_retry_interval_s")]
+    pub retry_interval_s: u64,
+    /// Hands the events of all CPUs to the grain in the order the kernel
+    /// saw them, holding them back for this long
+    pub reorder_ms: Option<u64>,
+    pub decode_errors: Option<DecodeErrorsConfig>,
+    pub limits: Option<LimitsConfig>,
+}
+
+/// Where a sample of the events of a grain that fail to decode is sent
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DecodeErrorsConfig {
+    pub pipelines: Vec<String>,
+    #[serde(default = "default_sample_every")]
+    pub sample_every: u64,
+}
+
+fn default_sample_every() -> u64 {
+    100
+}
+
+fn default_required() -> bool {
+    true
+}
+
+fn default_retry_interval_s() -> u64 {
+    60
+}
+
+impl Probe {
+    pub fn attach_policy(&self) -> AttachPolicy {
+        AttachPolicy {
+            required: self.required,
+            retry_interval: Duration::from_secs(self.retry_interval_s),
+        }
+    }
+
+    pub fn reorder_horizon(&self) -> Option<Duration> {
+        self.reorder_ms.map(Duration::from_millis)
+    }
+
+    /// Fails if the maps of the grain, built into `code`, take more memory
+    /// than its limits
+    pub fn check_limits(&self, code: Option<&[u8]>) -> Result<(), Error> {
+        let (limits, code) = match (&self.limits, code) {
+            (Some(limits), Some(code)) => (limits, code),
+            _ => return Ok(()),
+        };
+        let cpus = redbpf::cpus::get_online()
+            .map_err(|_| format_err!("Unable to list the online CPUs"))?;
+
+        limits
+            .check_maps(code, cpus.len())
+            .map_err(|e| format_err!("{} probe: {}", self.grain.name(), e))
+    }
+
+    /// The requirements of the grain's probe, if it has one
+    pub fn metas(&self) -> Result<Vec<Meta>, Error> {
+        match self.grain.code() {
+            Some(code) => {
+                meta::of(code).map_err(|e| format_err!("{} probe: {}", self.grain.name(), e))
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Fails with what's missing if the running kernel can't meet the
+    /// requirements of the grain's probe
+    pub fn check_kernel(&self, metas: &[Meta]) -> Result<(), Error> {
+        let kernel = match redbpf::uname::get_kernel_internal_version() {
+            Some(kernel) => kernel,
+            None => return Ok(()),
+        };
+
+        meta::check(metas, kernel).map_err(|e| format_err!("{} probe: {}", self.grain.name(), e))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -39,30 +519,85 @@ pub struct Pipeline {
     #[serde(rename = "config")]
     pub backend: Backend,
     pub steps: Option<Vec<Aggregator>>,
+    /// Sends batches through a write-ahead log. Only for the backends that
+    /// acknowledge deliveries.
+    pub wal: Option<wal::WalConfig>,
+    /// Sends batches on a schedule, instead of as they arrive
+    pub flush: Option<batch::FlushConfig>,
+    /// Drops the measurements the backend was already sent
+    pub dedup: Option<dedup::DedupConfig>,
+    pub namespace: Option<String>,
+    /// Names measurements from a template, like `{namespace}.{name}`
+    pub name_template: Option<String>,
+    /// Restarts the backend when it panics
+    #[serde(default)]
+    pub supervisor: SupervisorConfig,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum Grain {
     Files(file::FilesConfig),
-    Network,
+    Network(network::NetworkConfig),
+    TcpLatency(tcp_latency::TcpLatencyConfig),
+    UDP,
+    ICMP,
+    Listen,
+    Profile(grains::profile::ProfileConfig),
+    Memory(memory::MemoryConfig),
+    Scheduler(sched::SchedulerConfig),
+    Cpu(cpu::CpuConfig),
+    Credentials,
+    KernelLoad,
+    ProcessTree,
+    Ptrace,
+    Unix,
+    Ssh,
+    Tty(tty::TtyConfig),
+    Usdt(usdt::UsdtConfig),
+    Ssl(ssl::SslConfig),
+    Db(db::DbConfig),
+    Generic(generic::GenericConfig),
+    Cgroup(cgroup::CgroupConfig),
     DNS(dns::DnsConfig),
+    DhcpNtp(dhcp_ntp::DhcpNtpConfig),
+    Arp(arp::ArpConfig),
     TLS(tls::TlsConfig),
     Syscall(syscalls::SyscallConfig),
     StatsD(grains::statsd::StatsdConfig),
     Osquery(osquery::OsqueryConfig),
+    Plugin(plugin::PluginConfig),
+    Snapshot(snapshot::SnapshotConfig),
+    Synthetic(synthetic::SyntheticConfig),
     Test(grains::test::TestProbeConfig),
+    /// The minimal probe of the end-to-end tests
+    #[cfg(feature = "privileged-tests")]
+    Harness,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "backend")]
 pub enum Backend {
     #[cfg(feature = "s3-backend")]
-    S3,
+    S3(s3::S3Config),
     #[cfg(feature = "statsd-backend")]
     StatsD(statsd::StatsdConfig),
     #[cfg(feature = "http-backend")]
     HTTP(http::HTTPConfig),
+    #[cfg(feature = "azure-backend")]
+    AzureBlob(azure::BlobConfig),
+    #[cfg(feature = "azure-backend")]
+    EventHubs(azure::EventHubsConfig),
+    #[cfg(feature = "clickhouse-backend")]
+    ClickHouse(clickhouse::ClickHouseConfig),
+    #[cfg(feature = "grpc-backend")]
+    GRPC(grpc::GrpcConfig),
+    IPFIX(ipfix::IpfixConfig),
+    #[cfg(feature = "mqtt-backend")]
+    MQTT(mqtt::MqttConfig),
+    Pcap(pcap::PcapConfig),
+    #[cfg(feature = "redis-backend")]
+    RedisStream(redis_stream::RedisStreamConfig),
     Console,
 }
 
@@ -71,9 +606,16 @@ pub enum Backend {
 pub enum Aggregator {
     AddSystemDetails,
     Buffer(BufferConfig),
+    Classify(ClassifyConfig),
     Container(ContainerConfig),
+    Correlate(CorrelateConfig),
     Exec(ExecConfig),
+    Lineage(LineageConfig),
+    NetScan(NetScanConfig),
+    Redact(RedactConfig),
     Regex(RegexConfig),
+    #[cfg(feature = "scripting")]
+    Script(ScriptConfig),
     Whitelist(WhitelistConfig),
 }
 
@@ -82,33 +624,171 @@ impl Aggregator {
         match self {
             Aggregator::AddSystemDetails => AddSystemDetails::launch(upstream),
             Aggregator::Buffer(config) => Buffer::launch(config, upstream),
+            Aggregator::Classify(config) => Classify::launch(config, upstream),
             Aggregator::Container(config) => Container::launch(config, upstream),
+            Aggregator::Correlate(config) => Correlate::launch(config, upstream),
             Aggregator::Exec(config) => Exec::launch(config, upstream),
+            Aggregator::Lineage(config) => Lineage::launch(config, upstream),
+            Aggregator::NetScan(config) => NetScan::launch(config, upstream),
+            Aggregator::Redact(config) => Redact::launch(config, upstream),
             Aggregator::Regex(config) => Regex::launch(config, upstream),
+            #[cfg(feature = "scripting")]
+            Aggregator::Script(config) => Script::launch(config, upstream),
             Aggregator::Whitelist(config) => Whitelist::launch(config, upstream),
         }
     }
 }
 
 impl Backend {
-    pub fn into_recipient(self) -> Recipient<Message> {
+    /// Whether the backend acknowledges deliveries, and can be used behind
+    /// a write-ahead log
+    pub fn acknowledges(&self) -> bool {
         match self {
             #[cfg(feature = "s3-backend")]
-            Backend::S3 => {
-                Actor::start_in_arbiter(&actix::Arbiter::new(), |_| s3::S3::new()).recipient()
+            Backend::S3(_) => true,
+            #[cfg(feature = "http-backend")]
+            Backend::HTTP(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the backend handles raw measurements, such as captured
+    /// packets. The others never receive them.
+    pub fn accepts_raw(&self) -> bool {
+        match self {
+            Backend::Pcap(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Starts the backend on `arbiter`, and returns it as a recipient of
+    /// deliveries too if it acknowledges them
+    fn start_in_arbiter(self, arbiter: &Arbiter, stats: Arc<BackendStats>) -> Started {
+        match self {
+            #[cfg(feature = "s3-backend")]
+            Backend::S3(config) => {
+                let addr = Actor::start_in_arbiter(arbiter, |_| s3::S3::new(config, stats));
+                (addr.clone().recipient(), Some(addr.recipient()))
             }
             #[cfg(feature = "statsd-backend")]
             Backend::StatsD(config) => {
-                Actor::start_in_arbiter(&actix::Arbiter::new(), |_| statsd::Statsd::new(config))
-                    .recipient()
+                let addr = Actor::start_in_arbiter(arbiter, |_| statsd::Statsd::new(config, stats));
+                (addr.recipient(), None)
             }
             #[cfg(feature = "http-backend")]
             Backend::HTTP(config) => {
-                Actor::start_in_arbiter(&actix::Arbiter::new(), |_| http::HTTP::new(config))
-                    .recipient()
+                let addr = Actor::start_in_arbiter(arbiter, |_| http::HTTP::new(config, stats));
+                (addr.clone().recipient(), Some(addr.recipient()))
+            }
+            #[cfg(feature = "azure-backend")]
+            Backend::AzureBlob(config) => {
+                let addr =
+                    Actor::start_in_arbiter(arbiter, |_| azure::AzureBlob::new(config, stats));
+                (addr.recipient(), None)
+            }
+            #[cfg(feature = "azure-backend")]
+            Backend::EventHubs(config) => {
+                let addr =
+                    Actor::start_in_arbiter(arbiter, |_| azure::EventHubs::new(config, stats));
+                (addr.recipient(), None)
+            }
+            #[cfg(feature = "clickhouse-backend")]
+            Backend::ClickHouse(config) => {
+                let addr = Actor::start_in_arbiter(arbiter, |_| {
+                    clickhouse::ClickHouse::new(config, stats)
+                });
+                (addr.recipient(), None)
+            }
+            #[cfg(feature = "grpc-backend")]
+            Backend::GRPC(config) => {
+                let addr = Actor::start_in_arbiter(arbiter, |_| grpc::Grpc::new(config, stats));
+                (addr.recipient(), None)
+            }
+            Backend::IPFIX(config) => {
+                let addr = Actor::start_in_arbiter(arbiter, |_| ipfix::Ipfix::new(config, stats));
+                (addr.recipient(), None)
+            }
+            #[cfg(feature = "mqtt-backend")]
+            Backend::MQTT(config) => {
+                let addr = Actor::start_in_arbiter(arbiter, |_| mqtt::Mqtt::new(config, stats));
+                (addr.recipient(), None)
             }
-            Backend::Console => console::Console.start().recipient(),
+            Backend::Pcap(config) => {
+                let addr = Actor::start_in_arbiter(arbiter, |_| pcap::Pcap::new(config, stats));
+                (addr.recipient(), None)
+            }
+            #[cfg(feature = "redis-backend")]
+            Backend::RedisStream(config) => {
+                let addr = Actor::start_in_arbiter(arbiter, |_| {
+                    redis_stream::RedisStream::new(config, stats)
+                });
+                (addr.recipient(), None)
+            }
+            Backend::Console => {
+                let addr = Actor::start_in_arbiter(arbiter, |_| console::Console::new(stats));
+                (addr.recipient(), None)
+            }
+        }
+    }
+
+    /// Starts the backend behind a supervisor that restarts it when it
+    /// panics
+    fn start_supervised(
+        self,
+        pipeline: &str,
+        supervisor: SupervisorConfig,
+        stats: Arc<BackendStats>,
+    ) -> Started {
+        let acknowledges = self.acknowledges();
+        let raw = self.accepts_raw();
+        Supervisor::launch(
+            pipeline,
+            supervisor,
+            stats,
+            acknowledges,
+            raw,
+            Box::new(move |arbiter: &Arbiter, stats| self.clone().start_in_arbiter(arbiter, stats)),
+        )
+    }
+
+    /// Starts the backend behind a write-ahead log, and behind `dedup` if
+    /// it's set, so batches sent again from the log are deduplicated
+    pub fn into_wal_recipient(
+        self,
+        pipeline: &str,
+        wal: wal::WalConfig,
+        dedup: Option<dedup::DedupConfig>,
+        supervisor: SupervisorConfig,
+    ) -> Result<Recipient<Message>, Error> {
+        if !self.acknowledges() {
+            return Err(format_err!(
+                "the {} backend can't be used with a write-ahead log",
+                tag(&self, "backend")
+            ));
         }
+
+        let stats = BackendStats::register(pipeline);
+        let (backend, deliver) = self.start_supervised(pipeline, supervisor, stats.clone());
+        let deliver = deliver.expect("acknowledging backends receive deliveries");
+        let deliver = match dedup {
+            Some(dedup) => dedup::Dedup::launch(dedup, backend, Some(deliver))?
+                .1
+                .unwrap(),
+            None => deliver,
+        };
+        wal::Wal::launch(wal, deliver, stats)
+    }
+
+    /// Starts the backend, and returns it as a recipient of deliveries too
+    /// if it acknowledges them
+    pub fn into_recipients(self, pipeline: &str, supervisor: SupervisorConfig) -> Started {
+        let stats = BackendStats::register(pipeline);
+        self.start_supervised(pipeline, supervisor, stats)
+    }
+
+    pub fn into_recipient(self, pipeline: &str) -> Recipient<Message> {
+        self.into_recipients(pipeline, SupervisorConfig::default())
+            .0
     }
 }
 
@@ -116,10 +796,55 @@ pub enum ProbeActor {
     EBPF(EBPFActor),
     StatsD(grains::statsd::Statsd),
     Osquery(osquery::Osquery),
+    Profile(grains::profile::Profile),
+    Cpu(cpu::Cpu),
+    Plugin(plugin::Plugin),
+    Snapshot(snapshot::Snapshot),
+    Synthetic(synthetic::Synthetic),
     Test(grains::test::TestProbe)
 }
 
 impl ProbeActor {
+    /// Reads the perf buffers of eBPF probes on `workers`
+    pub fn with_workers(self, workers: &Workers) -> Self {
+        match self {
+            ProbeActor::EBPF(a) => ProbeActor::EBPF(a.with_workers(workers.clone())),
+            a => a,
+        }
+    }
+
+    /// Sets what happens when programs of eBPF probes fail to attach
+    pub fn with_policy(self, policy: AttachPolicy) -> Self {
+        match self {
+            ProbeActor::EBPF(a) => ProbeActor::EBPF(a.with_policy(policy)),
+            a => a,
+        }
+    }
+
+    /// Merges the events eBPF probes read on every CPU in kernel time order
+    pub fn with_reorder(self, horizon: Duration) -> Self {
+        match self {
+            ProbeActor::EBPF(a) => ProbeActor::EBPF(a.with_reorder(horizon)),
+            a => a,
+        }
+    }
+
+    /// Sends a sample of the events eBPF probes fail to decode to `sink`
+    pub fn with_decode_errors(self, sink: DecodeErrorSink) -> Self {
+        match self {
+            ProbeActor::EBPF(a) => ProbeActor::EBPF(a.with_decode_errors(sink)),
+            a => a,
+        }
+    }
+
+    /// Caps the rate of the events of eBPF probes
+    pub fn with_limiter(self, limiter: RateLimiter) -> Self {
+        match self {
+            ProbeActor::EBPF(a) => ProbeActor::EBPF(a.with_limiter(limiter)),
+            a => a,
+        }
+    }
+
     pub fn start(self, io: &Arbiter) {
         match self {
             ProbeActor::EBPF(a) => {
@@ -128,9 +853,24 @@ impl ProbeActor {
             ProbeActor::StatsD(a) => {
                 Actor::start_in_arbiter(io, |_| a);
             }
+            ProbeActor::Plugin(a) => {
+                Actor::start_in_arbiter(io, |_| a);
+            }
+            ProbeActor::Snapshot(a) => {
+                Actor::start_in_arbiter(io, |_| a);
+            }
+            ProbeActor::Synthetic(a) => {
+                Actor::start_in_arbiter(io, |_| a);
+            }
             ProbeActor::Test(a) => {
                 Actor::start_in_arbiter(io, |_| a);
             }
+            ProbeActor::Profile(a) => {
+                Actor::start_in_arbiter(io, |_| a);
+            }
+            ProbeActor::Cpu(a) => {
+                Actor::start_in_arbiter(io, |_| a);
+            }
             ProbeActor::Osquery(a) => {
                 a.start();
             }
@@ -139,6 +879,114 @@ impl ProbeActor {
 }
 
 impl Grain {
+    pub fn name(&self) -> String {
+        tag(self, "type")
+    }
+
+    /// Validates the configuration of the grain, without loading it
+    pub fn check(&self) -> Result<(), Error> {
+        match self {
+            Grain::Network(config) => config.check(),
+            Grain::Files(config) => config.check(),
+            Grain::Usdt(config) => config.check(),
+            Grain::Ssl(config) => config.check(),
+            Grain::Generic(config) => config.check(),
+            Grain::Cgroup(config) => config.check(),
+            Grain::Syscall(config) => config.check(),
+            Grain::Plugin(config) => config.check(),
+            Grain::Synthetic(config) => synthetic::Generator::new(config).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The eBPF object of the grain, if it has one
+    pub fn code(&self) -> Option<&'static [u8]> {
+        let code = match self {
+            Grain::Network(_) => network::Network::code(),
+            Grain::TcpLatency(_) => tcp_latency::TcpLatency::code(),
+            Grain::Files(_) => file::Files::code(),
+            Grain::UDP => udp::UDP::code(),
+            Grain::ICMP => icmp::ICMP::code(),
+            Grain::Listen => listen::Listen::code(),
+            Grain::Memory(_) => memory::Memory::code(),
+            Grain::Scheduler(_) => sched::Scheduler::code(),
+            Grain::Credentials => creds::Credentials::code(),
+            Grain::KernelLoad => kernel_load::KernelLoad::code(),
+            Grain::ProcessTree => process_tree::ProcessTree::code(),
+            Grain::Ptrace => ptrace::Ptrace::code(),
+            Grain::Unix => unix::Unix::code(),
+            Grain::Ssh => ssh::Ssh::code(),
+            Grain::Tty(_) => tty::Tty::code(),
+            Grain::Usdt(_) => usdt::Usdt::code(),
+            Grain::Ssl(_) => ssl::Ssl::code(),
+            Grain::Db(_) => db::Db::code(),
+            Grain::Generic(_) => generic::Generic::code(),
+            Grain::Cgroup(_) => cgroup::Cgroup::code(),
+            Grain::DNS(_) => dns::DNS::code(),
+            Grain::DhcpNtp(_) => dhcp_ntp::DhcpNtp::code(),
+            Grain::Arp(_) => arp::Arp::code(),
+            Grain::TLS(_) => tls::TLS::code(),
+            Grain::Syscall(_) => syscalls::Syscall::code(),
+            #[cfg(feature = "privileged-tests")]
+            Grain::Harness => grains::harness::Harness::code(),
+            Grain::StatsD(_)
+            | Grain::Osquery(_)
+            | Grain::Plugin(_)
+            | Grain::Profile(_)
+            | Grain::Cpu(_)
+            | Grain::Snapshot(_)
+            | Grain::Synthetic(_)
+            | Grain::Test(_) => return None,
+        };
+
+        Some(prebuilt::code(&self.name()).unwrap_or(code))
+    }
+
+    /// The event handlers of the grain, without loading it
+    pub fn into_handlers(self) -> Option<Handlers> {
+        fn handlers<T: EBPFGrain<'static> + 'static>(grain: T) -> Option<Handlers> {
+            Some(Box::new(move |map| grain.handler(map)))
+        }
+
+        match self {
+            Grain::Network(config) => handlers(network::Network::new(config).ok()?),
+            Grain::TcpLatency(config) => handlers(tcp_latency::TcpLatency(config)),
+            Grain::Files(config) => handlers(file::Files::new(config)),
+            Grain::UDP => handlers(udp::UDP),
+            Grain::ICMP => handlers(icmp::ICMP),
+            Grain::Listen => handlers(listen::Listen),
+            Grain::Memory(config) => handlers(memory::Memory(config)),
+            Grain::Scheduler(config) => handlers(sched::Scheduler::new(config)),
+            Grain::Credentials => handlers(creds::Credentials),
+            Grain::KernelLoad => handlers(kernel_load::KernelLoad),
+            Grain::ProcessTree => handlers(process_tree::ProcessTree),
+            Grain::Ptrace => handlers(ptrace::Ptrace),
+            Grain::Unix => handlers(unix::Unix),
+            Grain::Ssh => handlers(ssh::Ssh::default()),
+            Grain::Tty(config) => handlers(tty::Tty::new(config).ok()?),
+            Grain::Usdt(config) => handlers(usdt::Usdt::new(config).ok()?),
+            Grain::Ssl(config) => handlers(ssl::Ssl::new(config).ok()?),
+            Grain::Db(config) => handlers(db::Db(config)),
+            Grain::Generic(config) => handlers(generic::Generic(config)),
+            Grain::Cgroup(config) => handlers(cgroup::Cgroup::new(config)),
+            Grain::DNS(config) => handlers(dns::DNS::new(config)),
+            Grain::DhcpNtp(config) => handlers(dhcp_ntp::DhcpNtp::new(config)),
+            Grain::Arp(config) => handlers(arp::Arp::new(config)),
+            Grain::TLS(config) => handlers(tls::TLS(config)),
+            Grain::Syscall(config) => handlers(syscalls::Syscall(config)),
+            #[cfg(feature = "privileged-tests")]
+            Grain::Harness => handlers(grains::harness::Harness),
+            Grain::StatsD(_)
+            | Grain::Osquery(_)
+            | Grain::Plugin(_)
+            | Grain::Profile(_)
+            | Grain::Cpu(_)
+            | Grain::Snapshot(_)
+            | Grain::Synthetic(_)
+            | Grain::Test(_) => None,
+        }
+    }
+
     pub fn into_probe_actor(self, recipients: Vec<Recipient<Message>>) -> ProbeActor {
         match self {
             Grain::StatsD(config) => {
@@ -147,16 +995,63 @@ impl Grain {
             Grain::Osquery(config) => {
                 ProbeActor::Osquery(osquery::Osquery::with_config(config, recipients))
             }
+            Grain::Profile(config) => ProbeActor::Profile(
+                grains::profile::Profile::with_config(config, recipients).unwrap(),
+            ),
+            Grain::Cpu(config) => {
+                ProbeActor::Cpu(cpu::Cpu::with_config(config, recipients).unwrap())
+            }
+            Grain::Plugin(config) => {
+                ProbeActor::Plugin(plugin::Plugin::with_config(config, recipients))
+            }
+            Grain::Snapshot(config) => {
+                ProbeActor::Snapshot(snapshot::Snapshot::with_config(config, recipients))
+            }
+            Grain::Synthetic(config) => {
+                ProbeActor::Synthetic(synthetic::Synthetic::with_config(config, recipients))
+            }
             Grain::Test(config) => {
                 ProbeActor::Test(grains::test::TestProbe::with_config(config, recipients))
             }
             _ => {
                 let probe: Box<dyn EBPFProbe> = match self {
-                    Grain::Network => Box::new(network::Network.load().unwrap()),
-                    Grain::Files(config) => Box::new(file::Files(config).load().unwrap()),
-                    Grain::DNS(config) => Box::new(dns::DNS(config).load().unwrap()),
+                    Grain::Network(config) => {
+                        Box::new(network::Network::new(config).unwrap().load().unwrap())
+                    }
+                    Grain::TcpLatency(config) => {
+                        Box::new(tcp_latency::TcpLatency(config).load().unwrap())
+                    }
+                    Grain::Files(config) => Box::new(file::Files::new(config).load().unwrap()),
+                    Grain::UDP => Box::new(udp::UDP.load().unwrap()),
+                    Grain::ICMP => Box::new(icmp::ICMP.load().unwrap()),
+                    Grain::Listen => Box::new(listen::Listen.load().unwrap()),
+                    Grain::Memory(config) => Box::new(memory::Memory(config).load().unwrap()),
+                    Grain::Scheduler(config) => {
+                        Box::new(sched::Scheduler::new(config).load().unwrap())
+                    }
+                    Grain::Credentials => Box::new(creds::Credentials.load().unwrap()),
+                    Grain::KernelLoad => Box::new(kernel_load::KernelLoad.load().unwrap()),
+                    Grain::ProcessTree => Box::new(process_tree::ProcessTree.load().unwrap()),
+                    Grain::Ptrace => Box::new(ptrace::Ptrace.load().unwrap()),
+                    Grain::Unix => Box::new(unix::Unix.load().unwrap()),
+                    Grain::Ssh => Box::new(ssh::Ssh::default().load().unwrap()),
+                    Grain::Tty(config) => Box::new(tty::Tty::new(config).unwrap().load().unwrap()),
+                    Grain::Usdt(config) => {
+                        Box::new(usdt::Usdt::new(config).unwrap().load().unwrap())
+                    }
+                    Grain::Ssl(config) => Box::new(ssl::Ssl::new(config).unwrap().load().unwrap()),
+                    Grain::Db(config) => Box::new(db::Db(config).load().unwrap()),
+                    Grain::Generic(config) => Box::new(generic::Generic(config).load().unwrap()),
+                    Grain::Cgroup(config) => Box::new(cgroup::Cgroup::new(config).load().unwrap()),
+                    Grain::DNS(config) => Box::new(dns::DNS::new(config).load().unwrap()),
+                    Grain::DhcpNtp(config) => {
+                        Box::new(dhcp_ntp::DhcpNtp::new(config).load().unwrap())
+                    }
+                    Grain::Arp(config) => Box::new(arp::Arp::new(config).load().unwrap()),
                     Grain::TLS(config) => Box::new(tls::TLS(config).load().unwrap()),
                     Grain::Syscall(config) => Box::new(syscalls::Syscall(config).load().unwrap()),
+                    #[cfg(feature = "privileged-tests")]
+                    Grain::Harness => Box::new(grains::harness::Harness.load().unwrap()),
                     _ => unreachable!(),
                 };
                 ProbeActor::EBPF(EBPFActor::new(probe, recipients))
@@ -177,6 +1072,18 @@ mod tests {
 type = "Syslog"
 log_level = "DEBUG"
 
+[telemetry]
+pipelines = ["statsd"]
+
+[workers]
+threads = 4
+
+[admin]
+bind = "127.0.0.1:9150"
+
+[control]
+path = "/run/ingraind.sock"
+
 [[probe]]
 pipelines = ["statsd"]
 [probe.config]
@@ -188,12 +1095,39 @@ pipelines = ["statsd", "http"]
 [probe.config]
 type = "Network"
 
+[[probe]]
+pipelines = ["statsd"]
+[probe.config]
+type = "Network"
+[probe.config.egress_policy]
+default_action = "deny"
+rules = [
+  { action = "allow", cidr = "10.0.0.0/8" },
+  { action = "allow", ports = [53, 443] },
+]
+
+[[probe]]
+pipelines = ["statsd"]
+[probe.config]
+type = "TcpLatency"
+
 [[probe]]
 pipelines = ["statsd"]
 [probe.config]
 type = "Syscall"
 monitor_syscalls = ["read"]
 
+[[probe]]
+pipelines = ["statsd"]
+[probe.config]
+type = "Generic"
+function = "do_sys_open"
+metric = "file.open"
+args = [
+  { index = 2, name = "path_str", type = "string" },
+  { index = 3, name = "flags" },
+]
+
 [pipeline.http.config]
 backend = "HTTP"
 encoding = "JSON"
@@ -228,4 +1162,121 @@ interval_s = 30
         )
         .unwrap();
     }
+
+    #[test]
+    fn check_and_describe() {
+        use crate::config::Config;
+
+        let mut config: Config = toml::from_str(
+            r#"
+[[probe]]
+pipelines = ["console"]
+[probe.config]
+type = "StatsD"
+
+[pipeline.console.config]
+backend = "Console"
+
+[[pipeline.console.steps]]
+type = "Buffer"
+interval_s = 30
+"#,
+        )
+        .unwrap();
+
+        assert!(config.check().is_ok());
+        assert_eq!(
+            config.describe(),
+            "probe StatsD -> console\npipeline console: Buffer -> Console\n"
+        );
+
+        config.probe[0].pipelines.push("missing".to_string());
+        assert!(config.check().is_err());
+        config.probe[0].pipelines.pop();
+        config.probe[0].required = false;
+        config.probe[0].retry_interval_s = 0;
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn check_grains() {
+        use crate::config::Grain;
+
+        let grain = |config: &str| toml::from_str::<Grain>(config).unwrap();
+        assert!(grain("type = \"Network\"").check().is_ok());
+        assert!(grain(
+            r#"
+type = "Network"
+[filters]
+ports = { action = "allow", values = ["9000-8000"] }
+"#
+        )
+        .check()
+        .is_err());
+    }
+
+    #[test]
+    fn layered() {
+        use crate::config::Config;
+
+        let base = r#"
+shutdown_timeout_s = 10
+
+[[probe]]
+pipelines = ["console"]
+[probe.config]
+type = "StatsD"
+
+[pipeline.console.config]
+backend = "Console"
+"#;
+        let vars = vec![
+            ("INGRAIND__SHUTDOWN_TIMEOUT_S".to_string(), "20".to_string()),
+            ("INGRAIND__NAMESPACE".to_string(), "staging".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let config = Config::layered(
+            base,
+            vars.into_iter(),
+            &[
+                "namespace=prod.edge",
+                "probe.0.pipelines=[\"console\", \"s3\"]",
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.shutdown_timeout_s, 20);
+        assert_eq!(config.namespace.as_ref().unwrap(), "prod.edge");
+        assert_eq!(config.probe[0].pipelines, vec!["console", "s3"]);
+
+        assert!(Config::layered(base, vec![].into_iter(), &["probe.1.pipelines=[]"]).is_err());
+        assert!(Config::layered(base, vec![].into_iter(), &["namespace"]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn grains_listed() {
+        // options may be missing, but every listed type must be known
+        for (name, _) in GRAINS {
+            if let Err(e) = serde_json::from_value::<Grain>(json!({ "type": name })) {
+                assert!(!e.to_string().contains("unknown variant"), "{}", e);
+            }
+        }
+
+        let e = serde_json::from_value::<Grain>(json!({ "type": "-" })).unwrap_err();
+        let e = e.to_string();
+        let variants = e.splitn(2, "expected one of ").nth(1).unwrap();
+        for variant in variants.split(", ").map(|v| v.trim_matches('`')) {
+            // the probe of the end-to-end tests isn't for users
+            if variant != "Harness" {
+                let listed = GRAINS.iter().any(|(name, _)| *name == variant);
+                assert!(listed, "{} isn't listed", variant);
+            }
+        }
+    }
 }
@@ -0,0 +1,529 @@
+//! Keeps the metadata of the containers on the host, by watching the events
+//! of their runtimes.
+//!
+//! The `Container` aggregation looks up the container of a measurement's
+//! process here, instead of asking the runtime, and tags it with the
+//! container's name and image. A watcher lists the running containers every
+//! time it connects to its runtime, then follows the runtime's events:
+//! started containers are added, and stopped ones are kept for `expire_s`,
+//! so measurements that arrive late are still tagged.
+//!
+//! Docker is watched through its API socket, and containerd, including the
+//! containers it runs for Kubernetes through CRI, through `ctr`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::{format_err, Error};
+use lazy_static::lazy_static;
+use serde_json::Value;
+
+use crate::metrics::kind::*;
+use crate::metrics::{Measurement, Tags, Unit};
+
+pub const DOCKER: &str = "docker";
+pub const CONTAINERD: &str = "containerd";
+
+const DOCKER_EVENTS: &str = "/v1.24/events?filters=%7B%22type%22%3A%5B%22container%22%5D%7D";
+const DOCKER_CONTAINERS: &str = "/v1.24/containers/json";
+
+lazy_static! {
+    static ref CACHE: RwLock<Cache> = RwLock::new(Cache::default());
+}
+
+static WATCHING: AtomicBool = AtomicBool::new(false);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContainersConfig {
+    pub docker: Option<DockerConfig>,
+    pub containerd: Option<ContainerdConfig>,
+    /// How long stopped containers are kept
+    #[serde(default = "default_expire_s")]
+    pub expire_s: u64,
+    /// How long to wait before reconnecting to a runtime
+    #[serde(default = "default_retry_s")]
+    pub retry_s: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DockerConfig {
+    #[serde(default = "default_docker_socket")]
+    pub socket: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContainerdConfig {
+    #[serde(default = "default_ctr")]
+    pub ctr: String,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    pub address: Option<String>,
+}
+
+fn default_expire_s() -> u64 {
+    60
+}
+
+fn default_retry_s() -> u64 {
+    10
+}
+
+fn default_docker_socket() -> String {
+    "/var/run/docker.sock".to_string()
+}
+
+fn default_ctr() -> String {
+    "ctr".to_string()
+}
+
+fn default_namespace() -> String {
+    "k8s.io".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerInfo {
+    pub id: String,
+    pub name: Option<String>,
+    pub image: Option<String>,
+    pub runtime: &'static str,
+    pub labels: BTreeMap<String, String>,
+}
+
+enum Event {
+    Started(ContainerInfo),
+    Stopped(String),
+}
+
+struct Entry {
+    info: Arc<ContainerInfo>,
+    /// When a stopped container is removed
+    expires: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Cache {
+    containers: HashMap<String, Entry>,
+}
+
+impl Cache {
+    fn started(&mut self, info: ContainerInfo) {
+        let entry = Entry {
+            info: Arc::new(info),
+            expires: None,
+        };
+        self.containers.insert(entry.info.id.clone(), entry);
+    }
+
+    fn stopped(&mut self, id: &str, expires: Instant) {
+        if let Some(entry) = self.containers.get_mut(id) {
+            entry.expires = Some(entry.expires.unwrap_or(expires));
+        }
+    }
+
+    /// Replaces the containers of `runtime` with the `running` ones. The
+    /// others have stopped while the runtime wasn't watched.
+    fn sync(&mut self, runtime: &str, running: Vec<ContainerInfo>, expires: Instant) {
+        let stopped: Vec<String> = self
+            .containers
+            .values()
+            .filter(|e| e.info.runtime == runtime && !running.iter().any(|c| c.id == e.info.id))
+            .map(|e| e.info.id.clone())
+            .collect();
+        for id in stopped {
+            self.stopped(&id, expires);
+        }
+        for info in running {
+            self.started(info);
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.containers
+            .retain(|_, e| e.expires.map_or(true, |expires| expires > now));
+    }
+}
+
+/// The metadata of container `id`, if it's known
+pub fn lookup(id: &str) -> Option<Arc<ContainerInfo>> {
+    if !WATCHING.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let info = CACHE
+        .read()
+        .unwrap()
+        .containers
+        .get(id)
+        .map(|e| e.info.clone());
+    match info {
+        Some(_) => HITS.fetch_add(1, Ordering::Relaxed),
+        None => MISSES.fetch_add(1, Ordering::Relaxed),
+    };
+
+    info
+}
+
+/// Lookups of the cache, and how many containers it holds
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+}
+
+impl CacheStats {
+    pub fn current() -> CacheStats {
+        CacheStats {
+            hits: HITS.load(Ordering::Relaxed),
+            misses: MISSES.load(Ordering::Relaxed),
+            entries: CACHE.read().unwrap().containers.len() as u64,
+        }
+    }
+
+    pub fn to_measurements(&self, previous: &CacheStats) -> Vec<Measurement> {
+        let measurement = |kind, name: &str, value| {
+            Measurement::new(
+                kind,
+                format!("ingraind.self.containers.{}", name),
+                value,
+                Tags::new(),
+            )
+        };
+
+        vec![
+            measurement(COUNTER, "hits", Unit::Count(self.hits - previous.hits)),
+            measurement(
+                COUNTER,
+                "misses",
+                Unit::Count(self.misses - previous.misses),
+            ),
+            measurement(GAUGE, "entries", Unit::Count(self.entries)),
+        ]
+    }
+}
+
+/// Whether any runtime is watched
+pub fn watching() -> bool {
+    WATCHING.load(Ordering::Relaxed)
+}
+
+/// Starts a thread watching each configured runtime
+pub fn watch(config: ContainersConfig) {
+    WATCHING.store(true, Ordering::Relaxed);
+    let expire = Duration::from_secs(config.expire_s);
+    let retry = Duration::from_secs(config.retry_s);
+
+    if let Some(docker) = config.docker {
+        spawn(DOCKER, retry, move || watch_docker(&docker, expire));
+    }
+    if let Some(containerd) = config.containerd {
+        spawn(CONTAINERD, retry, move || {
+            watch_containerd(&containerd, expire)
+        });
+    }
+}
+
+fn spawn<F>(runtime: &'static str, retry: Duration, watch: F)
+where
+    F: Fn() -> Result<(), Error> + Send + 'static,
+{
+    thread::Builder::new()
+        .name(format!("{}-watcher", runtime))
+        .spawn(move || loop {
+            if let Err(e) = watch() {
+                warn!("lost the {} events: {}", runtime, e);
+            }
+            thread::sleep(retry);
+        })
+        .unwrap();
+}
+
+fn handle(event: Event, expire: Duration) {
+    let now = Instant::now();
+    let mut cache = CACHE.write().unwrap();
+    match event {
+        Event::Started(info) => cache.started(info),
+        Event::Stopped(id) => cache.stopped(&id, now + expire),
+    }
+    cache.expire(now);
+}
+
+fn watch_docker(config: &DockerConfig, expire: Duration) -> Result<(), Error> {
+    // subscribe first, so the containers started while listing aren't missed
+    let events = docker_get(&config.socket, DOCKER_EVENTS)?;
+
+    let containers: Value =
+        serde_json::from_reader(docker_get(&config.socket, DOCKER_CONTAINERS)?)?;
+    let running = containers
+        .as_array()
+        .ok_or_else(|| format_err!("unexpected container list"))?
+        .iter()
+        .filter_map(docker_container)
+        .collect::<Vec<_>>();
+    info!("watching {} docker containers", running.len());
+    CACHE
+        .write()
+        .unwrap()
+        .sync(DOCKER, running, Instant::now() + expire);
+
+    for event in serde_json::Deserializer::from_reader(events).into_iter::<Value>() {
+        if let Some(event) = docker_event(&event?) {
+            handle(event, expire);
+        }
+    }
+
+    Err(format_err!("connection closed"))
+}
+
+/// Sends a GET request to the Docker API, and returns the body of the
+/// response
+fn docker_get(socket: &str, path: &str) -> Result<BufReader<UnixStream>, Error> {
+    let mut stream = UnixStream::connect(socket)?;
+    // HTTP/1.0 responses aren't chunked, and end when the connection closes
+    write!(stream, "GET {} HTTP/1.0\r\nHost: docker\r\n\r\n", path)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format_err!("{}: {}", path, status.trim()));
+    }
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    Ok(reader)
+}
+
+/// A container of the Docker container list
+fn docker_container(container: &Value) -> Option<ContainerInfo> {
+    Some(ContainerInfo {
+        id: container["Id"].as_str()?.to_string(),
+        name: container["Names"][0]
+            .as_str()
+            .map(|name| name.trim_start_matches('/').to_string()),
+        image: container["Image"].as_str().map(String::from),
+        runtime: DOCKER,
+        labels: strings(&container["Labels"]),
+    })
+}
+
+fn docker_event(event: &Value) -> Option<Event> {
+    if event["Type"].as_str() != Some("container") {
+        return None;
+    }
+
+    let actor = &event["Actor"];
+    let id = actor["ID"].as_str()?.to_string();
+    match event["Action"].as_str()? {
+        "start" => {
+            // the labels of the container are mixed with its attributes
+            let mut labels = strings(&actor["Attributes"]);
+            let name = labels.remove("name");
+            let image = labels.remove("image");
+            Some(Event::Started(ContainerInfo {
+                id,
+                name,
+                image,
+                runtime: DOCKER,
+                labels,
+            }))
+        }
+        "die" => Some(Event::Stopped(id)),
+        _ => None,
+    }
+}
+
+fn watch_containerd(config: &ContainerdConfig, expire: Duration) -> Result<(), Error> {
+    let mut events = ctr(config)
+        .arg("events")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdout = events.stdout.take().unwrap();
+
+    let list = ctr(config).args(&["containers", "list", "-q"]).output()?;
+    if !list.status.success() {
+        let _ = events.kill();
+        return Err(format_err!("ctr containers list: {}", list.status));
+    }
+    let running = String::from_utf8_lossy(&list.stdout)
+        .lines()
+        .filter_map(|id| containerd_info(config, id.trim()).ok())
+        .collect::<Vec<_>>();
+    info!("watching {} containerd containers", running.len());
+    CACHE
+        .write()
+        .unwrap()
+        .sync(CONTAINERD, running, Instant::now() + expire);
+
+    for line in BufReader::new(stdout).lines() {
+        let line = line?;
+        let event = match containerd_event(&line) {
+            Some(("/tasks/start", payload)) => payload["container_id"]
+                .as_str()
+                .and_then(|id| containerd_info(config, id).ok())
+                .map(Event::Started),
+            Some(("/containers/delete", payload)) => payload["id"]
+                .as_str()
+                .map(|id| Event::Stopped(id.to_string())),
+            _ => None,
+        };
+        if let Some(event) = event {
+            handle(event, expire);
+        }
+    }
+
+    let _ = events.wait();
+    Err(format_err!("ctr events exited"))
+}
+
+fn ctr(config: &ContainerdConfig) -> Command {
+    let mut command = Command::new(&config.ctr);
+    if let Some(address) = &config.address {
+        command.args(&["--address", address]);
+    }
+    command.args(&["--namespace", &config.namespace]);
+
+    command
+}
+
+fn containerd_info(config: &ContainerdConfig, id: &str) -> Result<ContainerInfo, Error> {
+    let output = ctr(config).args(&["containers", "info", id]).output()?;
+    if !output.status.success() {
+        return Err(format_err!("ctr containers info {}: {}", id, output.status));
+    }
+
+    containerd_container(&serde_json::from_slice(&output.stdout)?)
+        .ok_or_else(|| format_err!("unexpected info of container {}", id))
+}
+
+/// A container from `ctr containers info`. The name is only known for
+/// containers created by Kubernetes.
+fn containerd_container(container: &Value) -> Option<ContainerInfo> {
+    let labels = strings(&container["Labels"]);
+    Some(ContainerInfo {
+        id: container["ID"].as_str()?.to_string(),
+        name: labels.get("io.kubernetes.container.name").cloned(),
+        image: container["Image"].as_str().map(String::from),
+        runtime: CONTAINERD,
+        labels,
+    })
+}
+
+/// The topic and payload of a line of `ctr events`, like
+/// `2019-04-12 11:24:32.1 +0000 UTC k8s.io /tasks/start {"container_id":"..."}`
+fn containerd_event(line: &str) -> Option<(&str, Value)> {
+    let topic = &line[line.find(" /")? + 1..];
+    let (topic, payload) = topic.split_at(topic.find(' ')?);
+
+    Some((topic, serde_json::from_str(payload).ok()?))
+}
+
+fn strings(map: &Value) -> BTreeMap<String, String> {
+    map.as_object()
+        .map(|map| {
+            map.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(id: &str, runtime: &'static str) -> ContainerInfo {
+        ContainerInfo {
+            id: id.to_string(),
+            name: None,
+            image: None,
+            runtime,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn cache_churn() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+
+        cache.sync(
+            DOCKER,
+            vec![container("a", DOCKER), container("b", DOCKER)],
+            later,
+        );
+        cache.started(container("c", CONTAINERD));
+        cache.stopped("a", later);
+        cache.expire(now);
+        assert_eq!(cache.containers.len(), 3);
+
+        // b stopped while disconnected, and a was restarted
+        cache.sync(DOCKER, vec![container("a", DOCKER)], later);
+        assert!(cache.containers["a"].expires.is_none());
+        assert!(cache.containers["b"].expires.is_some());
+        assert!(cache.containers["c"].expires.is_none());
+
+        cache.expire(later);
+        let mut ids: Vec<_> = cache.containers.keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn docker_events() {
+        let event = serde_json::json!({
+            "Type": "container",
+            "Action": "start",
+            "Actor": {
+                "ID": "a844b859",
+                "Attributes": { "image": "nginx:1.15", "name": "web", "team": "edge" }
+            }
+        });
+        match docker_event(&event) {
+            Some(Event::Started(info)) => {
+                assert_eq!(info.id, "a844b859");
+                assert_eq!(info.name.as_ref().map(String::as_str), Some("web"));
+                assert_eq!(info.image.as_ref().map(String::as_str), Some("nginx:1.15"));
+                assert_eq!(info.labels.len(), 1);
+            }
+            _ => panic!("not a start event"),
+        }
+
+        let event = serde_json::json!({ "Type": "network", "Action": "connect" });
+        assert!(docker_event(&event).is_none());
+
+        let list = serde_json::json!({ "Id": "a844b859", "Names": ["/web"], "Labels": {} });
+        assert_eq!(docker_container(&list).unwrap().name.unwrap(), "web");
+    }
+
+    #[test]
+    fn containerd_events() {
+        let line = r#"2019-04-12 11:24:32.142 +0000 UTC k8s.io /tasks/start {"container_id":"a844b859","pid":4242}"#;
+        let (topic, payload) = containerd_event(line).unwrap();
+        assert_eq!(topic, "/tasks/start");
+        assert_eq!(payload["container_id"], "a844b859");
+
+        let info = serde_json::json!({
+            "ID": "a844b859",
+            "Image": "docker.io/library/nginx:1.15",
+            "Labels": { "io.kubernetes.container.name": "web" }
+        });
+        let info = containerd_container(&info).unwrap();
+        assert_eq!(info.name.unwrap(), "web");
+        assert_eq!(info.runtime, CONTAINERD);
+    }
+}
@@ -0,0 +1,182 @@
+//! A local control socket for querying a running ingraind.
+//!
+//! Clients, like `ingraindctl`, connect to the unix socket, send a single
+//! line with a command and its arguments, and receive a JSON document in
+//! response:
+//!
+//!  * `status`: the statistics of the loaded grains
+//!  * `programs`: the programs of each grain
+//!  * `maps`: the maps of each grain
+//!  * `dump <grain> <map>`: the contents of a map of a grain
+//!
+//! Errors are returned as `{"error": "..."}`. Only the user ingraind runs
+//! as can connect, and clients are served on threads of their own.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use actix::Recipient;
+use futures::Future;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+
+use crate::grains::stats;
+
+/// How long clients have to send their command, and read the response
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref DUMPERS: Mutex<Vec<(String, Recipient<DumpMap>)>> = Mutex::new(Vec::new());
+}
+
+/// Asks a grain for the contents of one of its maps
+pub struct DumpMap(pub String);
+
+impl actix::Message for DumpMap {
+    type Result = Option<Value>;
+}
+
+/// Registers the grain named `grain` so its maps can be dumped
+pub fn register(grain: &str, recipient: Recipient<DumpMap>) {
+    DUMPERS.lock().unwrap().push((grain.to_string(), recipient));
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlConfig {
+    pub path: PathBuf,
+}
+
+pub struct Control {
+    path: PathBuf,
+}
+
+impl Control {
+    pub fn with_config(config: ControlConfig) -> Self {
+        Control { path: config.path }
+    }
+
+    /// Starts serving clients on a thread of its own
+    pub fn start(self) {
+        // a previous instance may have left its socket behind
+        let _ = fs::remove_file(&self.path);
+        // the socket is only ever accessible to the owner, as it's created
+        // with mode 0600 under the umask
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(&self.path);
+        unsafe { libc::umask(umask) };
+        let listener = match listener {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to start control socket {:?}: {}", self.path, e);
+                return;
+            }
+        };
+
+        info!("Control socket listening on {:?}", self.path);
+        thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    // a client that doesn't send its command doesn't hold
+                    // up the others
+                    let client = thread::Builder::new()
+                        .name("control-client".to_string())
+                        .spawn(move || {
+                            if let Err(e) = stream.and_then(serve) {
+                                warn!("Control socket error: {}", e);
+                            }
+                        });
+                    if let Err(e) = client {
+                        warn!("Control socket error: {}", e);
+                    }
+                }
+            })
+            .unwrap();
+    }
+}
+
+fn serve(stream: UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+
+    let mut stream = stream;
+    serde_json::to_writer(&mut stream, &respond(&request))?;
+    stream.write_all(b"\n")
+}
+
+fn respond(request: &str) -> Value {
+    let words = request.split_whitespace().collect::<Vec<_>>();
+    match words.as_slice() {
+        ["status"] => json!({ "grains": stats::all() }),
+        ["programs"] => json!(stats::all()
+            .into_iter()
+            .map(|s| json!({ "grain": s.name, "programs": s.programs }))
+            .collect::<Vec<_>>()),
+        ["maps"] => json!(stats::all()
+            .into_iter()
+            .map(|s| json!({ "grain": s.name, "maps": s.maps }))
+            .collect::<Vec<_>>()),
+        ["dump", grain, map] => dump(grain, map),
+        _ => error(format!("invalid command: {}", request.trim())),
+    }
+}
+
+/// Dumps the map of every loaded grain named `grain` that has it
+fn dump(grain: &str, map: &str) -> Value {
+    let recipients = DUMPERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| name == grain)
+        .map(|(_, recipient)| recipient.clone())
+        .collect::<Vec<_>>();
+    if recipients.is_empty() {
+        return error(format!("grain {} not found", grain));
+    }
+
+    let dumps = recipients
+        .iter()
+        .filter_map(|r| r.send(DumpMap(map.to_string())).wait().ok())
+        .flatten()
+        .collect::<Vec<_>>();
+    if dumps.is_empty() {
+        return error(format!("map {} of {} can't be dumped", map, grain));
+    }
+
+    let entries = dumps
+        .into_iter()
+        .flat_map(|dump| match dump {
+            Value::Array(entries) => entries,
+            entry => vec![entry],
+        })
+        .collect::<Vec<_>>();
+    json!({ "grain": grain, "map": map, "entries": entries })
+}
+
+fn error(message: String) -> Value {
+    json!({ "error": message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_commands() {
+        assert!(respond("bogus\n")["error"].is_string());
+        assert!(respond("dump Network\n")["error"].is_string());
+        assert_eq!(
+            respond("dump Nonexistent map\n")["error"],
+            json!("grain Nonexistent not found")
+        );
+        assert!(respond("maps\n").is_array());
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::grains::protocol::arp::{self, MacAddr, ARPOP_REPLY};
+use crate::grains::*;
+
+use ingraind_probes::arp::Event;
+use redbpf::xdp::MapData;
+
+unsafe impl FromRawEvent for Event {}
+
+pub struct Arp {
+    config: ArpConfig,
+    /// Shared by the handlers of all CPUs, so conflicts are detected
+    /// whichever CPU the replies arrive on
+    neighbors: Arc<Mutex<Neighbors>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ArpConfig {
+    interface: String,
+    #[serde(default = "default_xdp_mode")]
+    xdp_mode: XdpMode,
+    #[serde(default = "default_gratuitous_flood_threshold")]
+    gratuitous_flood_threshold: u64,
+}
+
+fn default_gratuitous_flood_threshold() -> u64 {
+    10
+}
+
+/// IP to MAC bindings, seeded from the kernel's neighbor table
+struct Neighbors {
+    bindings: HashMap<Ipv4Addr, MacAddr>,
+    window_start: Instant,
+    gratuitous: u64,
+}
+
+impl Neighbors {
+    fn new() -> Neighbors {
+        Neighbors {
+            bindings: read_neighbor_table().unwrap_or_default(),
+            window_start: Instant::now(),
+            gratuitous: 0,
+        }
+    }
+
+    /// Records the sender's binding, and returns the MAC address that was
+    /// previously bound to the IP, if different
+    fn update(&mut self, ip: Ipv4Addr, mac: MacAddr) -> Option<MacAddr> {
+        if ip.is_unspecified() {
+            return None;
+        }
+
+        self.bindings
+            .insert(ip, mac)
+            .filter(|&previous| previous != mac)
+    }
+
+    /// Counts gratuitous ARP in one second windows, and returns the count
+    /// the first time it reaches `threshold` in a window
+    fn count_gratuitous(&mut self, threshold: u64) -> Option<u64> {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.gratuitous = 0;
+        }
+
+        self.gratuitous += 1;
+        if self.gratuitous == threshold {
+            Some(self.gratuitous)
+        } else {
+            None
+        }
+    }
+}
+
+impl Arp {
+    pub fn new(config: ArpConfig) -> Self {
+        Arp {
+            config,
+            neighbors: Arc::new(Mutex::new(Neighbors::new())),
+        }
+    }
+}
+
+impl EBPFProbe for Grain<Arp> {
+    fn attach(&mut self) -> MessageStreams {
+        let conf = &self.native.config;
+        let interface = conf.interface.clone();
+        let flags = conf.xdp_mode.into();
+        self.attach_xdps(&interface, flags)
+    }
+}
+
+impl EBPFGrain<'static> for Arp {
+    fn code() -> &'static [u8] {
+        probes::arp::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        let interface = self.config.interface.clone();
+        let threshold = self.config.gratuitous_flood_threshold;
+        let netns = netns::current();
+        let neighbors = self.neighbors.clone();
+
+        parsed_ref(move |data: &MapData<Event>| {
+            let event = data.data();
+            let packet = match arp::parse(data.payload())? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+
+            let mut tags = Tags::new();
+            tags.insert("interface", interface.as_str());
+            if let Some(netns) = netns {
+                tags.insert("netns", netns);
+            }
+            tags.insert("ip", packet.sender_ip);
+            tags.insert("mac", packet.sender_mac.to_string());
+
+            let eth_source = MacAddr(event.h_source);
+            if eth_source != packet.sender_mac {
+                tags.insert("eth_source_mac", eth_source.to_string());
+            }
+
+            let mut measurements = vec![];
+            let mut neighbors = neighbors.lock().unwrap();
+            if let Some(previous) = neighbors.update(packet.sender_ip, packet.sender_mac) {
+                let mut tags = tags.clone();
+                tags.insert("previous_mac", previous.to_string());
+                measurements.push(Measurement::new(
+                    COUNTER | METER,
+                    "arp.conflict".to_string(),
+                    Unit::Count(1),
+                    tags,
+                ));
+            }
+
+            if packet.is_gratuitous() {
+                if let Some(count) = neighbors.count_gratuitous(threshold) {
+                    measurements.push(Measurement::new(
+                        COUNTER | METER,
+                        "arp.gratuitous_flood".to_string(),
+                        Unit::Count(count),
+                        tags.clone(),
+                    ));
+                }
+
+                measurements.push(Measurement::new(
+                    COUNTER | METER,
+                    "arp.gratuitous".to_string(),
+                    Unit::Count(1),
+                    tags,
+                ));
+            } else if packet.oper == ARPOP_REPLY {
+                measurements.push(Measurement::new(
+                    COUNTER | METER,
+                    "arp.reply".to_string(),
+                    Unit::Count(1),
+                    tags,
+                ));
+            }
+
+            if measurements.is_empty() {
+                return Ok(None);
+            }
+
+            Ok(Some(Message::List(measurements)))
+        })
+    }
+}
+
+/// Parses complete entries of `/proc/net/arp`
+fn read_neighbor_table() -> Option<HashMap<Ipv4Addr, MacAddr>> {
+    const ATF_COM: u32 = 0x2;
+
+    let table = fs::read_to_string("/proc/net/arp").ok()?;
+    let bindings = table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let flags = u32::from_str_radix(fields.get(2)?.trim_start_matches("0x"), 16).ok()?;
+            if flags & ATF_COM == 0 {
+                return None;
+            }
+
+            Some((fields.get(0)?.parse().ok()?, fields.get(3)?.parse().ok()?))
+        })
+        .collect();
+
+    Some(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neighbor_conflicts() {
+        let mut neighbors = Neighbors {
+            bindings: HashMap::new(),
+            window_start: Instant::now(),
+            gratuitous: 0,
+        };
+        let ip = Ipv4Addr::new(192, 168, 1, 1);
+        let mac = MacAddr([0xde, 0xad, 0xbe, 0xef, 0, 1]);
+        let spoofed = MacAddr([0xde, 0xad, 0xbe, 0xef, 0, 2]);
+
+        assert_eq!(neighbors.update(ip, mac), None);
+        assert_eq!(neighbors.update(ip, mac), None);
+        assert_eq!(neighbors.update(ip, spoofed), Some(mac));
+        assert_eq!(neighbors.update(Ipv4Addr::UNSPECIFIED, mac), None);
+
+        assert_eq!(neighbors.count_gratuitous(2), None);
+        assert_eq!(neighbors.count_gratuitous(2), Some(2));
+        assert_eq!(neighbors.count_gratuitous(2), None);
+    }
+}
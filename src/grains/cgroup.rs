@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Duration;
+
+use failure::{bail, Error};
+use redbpf::{HashMap as BPFHashMap, Module};
+use serde_json::Value;
+
+use crate::grains::probes::cgroup::maps;
+use crate::grains::*;
+
+use ingraind_probes::cgroup::{CgroupCounter, FILE_READ, FILE_WRITE, NET_RX, NET_TX};
+
+pub struct Cgroup {
+    config: CgroupConfig,
+    /// The bytes reported so far of each counter in the kernel
+    reported: HashMap<CgroupCounter, u64>,
+    /// The paths of the cgroups seen so far, by id
+    paths: HashMap<u64, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CgroupConfig {
+    #[serde(default = "default_flush_interval_ms")]
+    flush_interval_ms: u64,
+}
+
+fn default_flush_interval_ms() -> u64 {
+    10_000
+}
+
+impl CgroupConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if self.flush_interval_ms == 0 {
+            bail!("Cgroup: flush_interval_ms must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+impl Cgroup {
+    pub fn new(config: CgroupConfig) -> Self {
+        Cgroup {
+            config,
+            reported: HashMap::new(),
+            paths: HashMap::new(),
+        }
+    }
+}
+
+impl EBPFProbe for Grain<Cgroup> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for Cgroup {
+    fn code() -> &'static [u8] {
+        probes::cgroup::CODE
+    }
+
+    fn dump_map(&self, module: &Module, name: &str) -> Option<Value> {
+        match name {
+            maps::STATS => dump_hash_map::<CgroupCounter, u64>(module, name),
+            _ => dump_common_map(module, name),
+        }
+    }
+
+    fn scrape_interval(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.config.flush_interval_ms))
+    }
+
+    fn scrape(&mut self, module: &Module) -> Vec<Measurement> {
+        let map =
+            BPFHashMap::<CgroupCounter, u64>::new(find_map_by_name(module, maps::STATS)).unwrap();
+        let counters = map.iter().collect::<Vec<_>>();
+
+        // the hierarchy is only read again when new cgroups show up
+        if counters
+            .iter()
+            .any(|(key, _)| !self.paths.contains_key(&key.cgroup_id))
+        {
+            self.paths = read_cgroup_paths();
+        }
+
+        let mut measurements = vec![];
+        for (key, bytes) in counters {
+            let reported = self.reported.insert(key, bytes).unwrap_or(0);
+            let path = self.paths.get(&key.cgroup_id);
+
+            // the counters of removed cgroups are reported one last time
+            if path.is_none() {
+                map.delete(key);
+                self.reported.remove(&key);
+            }
+
+            let name = match key.counter {
+                NET_TX => "cgroup.net.tx",
+                NET_RX => "cgroup.net.rx",
+                FILE_READ => "cgroup.file.read",
+                FILE_WRITE => "cgroup.file.write",
+                _ => continue,
+            };
+            if bytes > reported {
+                measurements.push(Measurement::new(
+                    COUNTER,
+                    name.to_string(),
+                    Unit::Byte(bytes - reported),
+                    cgroup_tags(key.cgroup_id, path),
+                ));
+            }
+        }
+
+        measurements
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        // everything is scraped, there are no events
+        Box::new(|_, _| Ok(None))
+    }
+}
+
+fn cgroup_tags(cgroup_id: u64, path: Option<&String>) -> Tags {
+    let mut tags = Tags::new();
+    let path = match path {
+        Some(path) => path,
+        None => {
+            tags.insert("cgroup_id", cgroup_id.to_string());
+            return tags;
+        }
+    };
+
+    if let Some(unit) = systemd_unit(path) {
+        tags.insert("systemd_unit", unit);
+    }
+    if let Some(id) = docker_id(path) {
+        tags.insert("docker_id", id);
+    }
+    tags.insert("cgroup_path", path.as_str());
+
+    tags
+}
+
+/// Where the cgroup v2 hierarchy is mounted
+pub(crate) fn cgroup2_root() -> &'static Path {
+    // hybrid hierarchies mount cgroup v2 separately
+    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        Path::new("/sys/fs/cgroup")
+    } else {
+        Path::new("/sys/fs/cgroup/unified")
+    }
+}
+
+/// The paths of the cgroups in the cgroup v2 hierarchy, by the inode of
+/// their directory, which is their id
+fn read_cgroup_paths() -> HashMap<u64, String> {
+    let root = cgroup2_root();
+
+    let mut paths = HashMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if let Ok(meta) = fs::metadata(&dir) {
+            let path = dir.strip_prefix(root).unwrap_or(&dir);
+            paths.insert(meta.ino(), format!("/{}", path.display()));
+        }
+
+        if let Ok(entries) = fs::read_dir(&dir) {
+            dirs.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+                    .map(|e| e.path()),
+            );
+        }
+    }
+
+    paths
+}
+
+/// The innermost systemd service or scope the cgroup belongs to
+fn systemd_unit(path: &str) -> Option<&str> {
+    path.rsplit('/')
+        .find(|c| c.ends_with(".service") || c.ends_with(".scope"))
+}
+
+/// Docker names cgroups after the container id, either directly or as
+/// `docker-<id>.scope` with the systemd driver
+fn docker_id(path: &str) -> Option<&str> {
+    path.rsplit('/')
+        .map(|c| c.trim_start_matches("docker-").trim_end_matches(".scope"))
+        .find(|c| c.len() == 64 && c.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup_path_tags() {
+        let id = "a844b8599d5e23c620c646b69c6d93c4014247cd0be9ec142c44219b6467e07f";
+
+        let path = "/system.slice/nginx.service";
+        assert_eq!(systemd_unit(path), Some("nginx.service"));
+        assert_eq!(docker_id(path), None);
+
+        let path = format!("/system.slice/docker-{}.scope", id);
+        assert_eq!(systemd_unit(&path), Some(&path[14..]));
+        assert_eq!(docker_id(&path), Some(id));
+
+        let path = format!("/kubepods/besteffort/pod1234/{}", id);
+        assert_eq!(systemd_unit(&path), None);
+        assert_eq!(docker_id(&path), Some(id));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use actix::{Actor, AsyncContext, Context, Recipient};
+use failure::{bail, format_err, Error};
+use redbpf::uname::get_kernel_internal_version;
+
+use ingraind_probes::cpu::CpuTime;
+
+use crate::backends::Message;
+use crate::grains::loader::{possible_cpus, Module};
+use crate::grains::meta;
+use crate::grains::probes::cpu::maps;
+use crate::grains::{probes, to_string, SendToManyRecipients};
+use crate::metrics::{kind, Measurement, Tags, Unit};
+
+/// Accumulates the time processes spend on each CPU at every context switch,
+/// and reports it every interval.
+///
+/// The totals are kept in a per-CPU map that's read without deleting
+/// anything, which redbpf can't read, so the probe is loaded by the
+/// grain's own loader.
+pub struct Cpu {
+    config: CpuConfig,
+    recipients: Vec<Recipient<Message>>,
+    module: Module,
+    /// The CPUs of the values of the per-CPU map, in order
+    cpus: Vec<u32>,
+    /// The on-CPU time of every process and CPU at the last scrape
+    reported: HashMap<(u32, u32), u64>,
+    last_scrape: Instant,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CpuConfig {
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+    /// Only report the processes that used the most CPU time in an interval
+    top: Option<usize>,
+    /// Report the time spent on every CPU separately, tagged with `cpu`
+    #[serde(default)]
+    per_cpu: bool,
+}
+
+fn default_interval_ms() -> u64 {
+    10_000
+}
+
+/// The on-CPU time of a process on a CPU since it was first seen
+struct Sample {
+    pid: u32,
+    cpu: u32,
+    comm: String,
+    ns: u64,
+}
+
+impl Cpu {
+    pub fn with_config(
+        config: CpuConfig,
+        recipients: Vec<Recipient<Message>>,
+    ) -> Result<Self, Error> {
+        if config.interval_ms == 0 {
+            bail!("Cpu: interval_ms must be greater than 0");
+        }
+
+        if let Some(kernel) = get_kernel_internal_version() {
+            meta::check(&meta::of(probes::cpu::CODE)?, kernel)
+                .map_err(|e| format_err!("Cpu: {}", e))?;
+        }
+
+        let mut module = Module::load(probes::cpu::CODE)?;
+        module.attach_tracepoints("sched")?;
+
+        Ok(Cpu {
+            config,
+            recipients,
+            module,
+            cpus: possible_cpus()?,
+            reported: HashMap::new(),
+            last_scrape: Instant::now(),
+        })
+    }
+
+    fn scrape(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_scrape);
+        self.last_scrape = now;
+
+        let map = match self.module.map(maps::CPU_TIME) {
+            Some(map) => map,
+            None => return,
+        };
+
+        let mut samples = vec![];
+        let mut exited = HashSet::new();
+        for key in map.keys() {
+            let values = match map.get_per_cpu(&key, self.cpus.len()) {
+                Some(values) => values,
+                None => continue,
+            };
+            let pid = unsafe { ptr::read_unaligned(key.as_ptr() as *const u32) };
+            for (&cpu, value) in self.cpus.iter().zip(values) {
+                let time = unsafe { ptr::read_unaligned(value.as_ptr() as *const CpuTime) };
+                if time.ns > 0 {
+                    samples.push(Sample {
+                        pid,
+                        cpu,
+                        comm: to_string(&time.comm),
+                        ns: time.ns,
+                    });
+                }
+            }
+
+            // processes that exited are reported one last time
+            if !Path::new(&format!("/proc/{}", pid)).exists() {
+                map.delete(&key);
+                exited.insert(pid);
+            }
+        }
+
+        let measurements = usage(
+            samples,
+            &mut self.reported,
+            elapsed,
+            self.config.per_cpu,
+            self.config.top,
+        );
+        self.reported.retain(|(pid, _), _| !exited.contains(pid));
+
+        if !measurements.is_empty() {
+            self.recipients.do_send(Message::List(measurements));
+        }
+    }
+}
+
+impl Actor for Cpu {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("cpu started, reporting every {}ms", self.config.interval_ms);
+        ctx.run_interval(Duration::from_millis(self.config.interval_ms), |act, _| {
+            act.scrape()
+        });
+    }
+}
+
+/// `cpu.time`, the total on-CPU time in nanoseconds, and `cpu.usage` in
+/// percent of a CPU since the `reported` totals, for every process, the
+/// busiest first
+fn usage(
+    samples: Vec<Sample>,
+    reported: &mut HashMap<(u32, u32), u64>,
+    elapsed: Duration,
+    per_cpu: bool,
+    top: Option<usize>,
+) -> Vec<Measurement> {
+    let mut totals: HashMap<(u32, Option<u32>), (String, u64, u64)> = HashMap::new();
+    for sample in samples {
+        // a total below the last one belongs to a new process with a
+        // reused pid
+        let last = reported
+            .insert((sample.pid, sample.cpu), sample.ns)
+            .filter(|&last| last <= sample.ns)
+            .unwrap_or(0);
+        let cpu = if per_cpu { Some(sample.cpu) } else { None };
+        let total = totals
+            .entry((sample.pid, cpu))
+            .or_insert_with(|| (sample.comm, 0, 0));
+        total.1 += sample.ns;
+        total.2 += sample.ns - last;
+    }
+
+    let mut totals = totals.into_iter().collect::<Vec<_>>();
+    totals.sort_by(|a, b| (b.1).2.cmp(&(a.1).2));
+    totals.truncate(top.unwrap_or(usize::MAX));
+
+    let elapsed_ns = elapsed.as_nanos().max(1) as f64;
+    let mut measurements = vec![];
+    for ((pid, cpu), (comm, ns, delta)) in totals {
+        let mut tags = Tags::new();
+        tags.insert("process_id", pid);
+        tags.insert("process_str", comm);
+        if let Some(cpu) = cpu {
+            tags.insert("cpu", cpu);
+        }
+
+        measurements.push(Measurement::new(
+            kind::COUNTER | kind::CUMULATIVE,
+            "cpu.time".to_string(),
+            Unit::Duration(ns),
+            tags.clone(),
+        ));
+        measurements.push(Measurement::new(
+            kind::GAUGE,
+            "cpu.usage".to_string(),
+            Unit::Gauge(delta as f64 / elapsed_ns * 100.0),
+            tags,
+        ));
+    }
+
+    measurements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(pid: u32, cpu: u32, comm: &str, ns: u64) -> Sample {
+        Sample {
+            pid,
+            cpu,
+            comm: comm.to_string(),
+            ns,
+        }
+    }
+
+    #[test]
+    fn aggregates() {
+        let samples = |scale: u64| {
+            vec![
+                sample(1, 0, "init", 100_000_000 * scale),
+                sample(42, 0, "cc1", 500_000_000 * scale),
+                sample(42, 1, "cc1", 1_000_000_000 * scale),
+            ]
+        };
+        let elapsed = Duration::from_secs(1);
+
+        let mut reported = HashMap::new();
+        let measurements = usage(samples(1), &mut reported, elapsed, false, None);
+        assert_eq!(measurements.len(), 4);
+        assert_eq!(measurements[0].tags.get("process_str").unwrap(), "cc1");
+        assert_eq!(measurements[0].value, Unit::Duration(1_500_000_000));
+        assert_eq!(measurements[1].value, Unit::Gauge(150.0));
+        assert_eq!(measurements[3].value, Unit::Gauge(10.0));
+
+        // the totals keep growing, and the usage is of the interval
+        let measurements = usage(samples(2), &mut reported, elapsed, true, Some(2));
+        assert_eq!(measurements.len(), 4);
+        assert_eq!(measurements[0].tags.get("cpu").unwrap(), "1");
+        assert_eq!(measurements[0].value, Unit::Duration(2_000_000_000));
+        assert_eq!(measurements[1].value, Unit::Gauge(100.0));
+        assert_eq!(measurements[3].value, Unit::Gauge(50.0));
+    }
+}
@@ -0,0 +1,118 @@
+use crate::grains::*;
+
+use ingraind_probes::creds::CredChange;
+
+unsafe impl FromRawEvent for CredChange {}
+
+const CAPABILITIES: [&str; 38] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+];
+
+pub struct Credentials;
+
+impl EBPFProbe for Grain<Credentials> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for Credentials {
+    fn code() -> &'static [u8] {
+        probes::creds::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: CredChange| {
+            Some(Message::Single(Measurement::new(
+                COUNTER | METER,
+                "creds.escalation".to_string(),
+                Unit::Count(1),
+                cred_tags(&event),
+            )))
+        })
+    }
+}
+
+fn cred_tags(event: &CredChange) -> Tags {
+    let mut tags = Tags::new();
+
+    tags.insert("process_id", event.pid);
+    tags.insert("process_str", to_string(&event.comm));
+    tags.insert("parent_process_id", event.ppid);
+    tags.insert("parent_process_str", to_string(&event.parent_comm));
+    tags.insert("old_uid", event.old_uid.to_string());
+    tags.insert("new_uid", event.new_uid.to_string());
+    tags.insert("old_euid", event.old_euid.to_string());
+    tags.insert("new_euid", event.new_euid.to_string());
+    tags.insert("old_caps", format!("{:#x}", event.old_caps));
+    tags.insert("new_caps", format!("{:#x}", event.new_caps));
+    tags.insert(
+        "gained_caps_list",
+        capability_names(event.new_caps & !event.old_caps),
+    );
+
+    tags
+}
+
+fn capability_names(caps: u64) -> String {
+    (0..64)
+        .filter(|bit| caps & (1 << bit) != 0)
+        .map(|bit| match CAPABILITIES.get(bit) {
+            Some(name) => name.to_string(),
+            None => format!("CAP_{}", bit),
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_names() {
+        assert_eq!(capability_names(0), "");
+        assert_eq!(capability_names(1 << 21), "CAP_SYS_ADMIN");
+        assert_eq!(
+            capability_names(1 | 1 << 7 | 1 << 40),
+            "CAP_CHOWN,CAP_SETUID,CAP_40"
+        );
+    }
+}
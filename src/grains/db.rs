@@ -0,0 +1,154 @@
+use crate::grains::*;
+
+use ingraind_probes::db::{Database, QueryLatency};
+
+unsafe impl FromRawEvent for QueryLatency {
+    fn valid(raw: &[u8]) -> bool {
+        // `database` follows the `u32` pid
+        known_variant(raw, 4, 3)
+    }
+}
+
+pub struct Db(pub DbConfig);
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct DbConfig {
+    /// Path to libpq, for Postgres clients
+    libpq: Option<String>,
+    /// Path to the postgres server binary
+    postgres: Option<String>,
+    /// Path to libmysqlclient, for MySQL clients
+    libmysqlclient: Option<String>,
+    pid: Option<i32>,
+}
+
+impl EBPFProbe for Grain<Db> {
+    fn attach(&mut self) -> MessageStreams {
+        let conf = &self.native.0;
+        let targets = [
+            ("pq_exec", conf.libpq.clone(), "PQexec"),
+            (
+                "exec_simple_query",
+                conf.postgres.clone(),
+                "exec_simple_query",
+            ),
+            (
+                "mysql_real_query",
+                conf.libmysqlclient.clone(),
+                "mysql_real_query",
+            ),
+        ];
+        let pid = conf.pid;
+
+        self.attach_uprobes(pid, |program| {
+            targets
+                .iter()
+                .filter(|(prefix, _, _)| program.starts_with(prefix))
+                .filter_map(|(_, target, symbol)| {
+                    Some(UprobeLocation {
+                        target: target.clone()?,
+                        symbol: Some(symbol.to_string()),
+                        offset: 0,
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+impl EBPFGrain<'static> for Db {
+    fn code() -> &'static [u8] {
+        probes::db::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: QueryLatency| {
+            let end = event
+                .query
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(event.query.len());
+            let query = String::from_utf8_lossy(&event.query[..end]);
+
+            let mut tags = Tags::new();
+            tags.insert("process_id", event.pid);
+            tags.insert("process_str", to_string(&event.comm));
+            tags.insert("database", database_name(event.database));
+            tags.insert("statement_str", fingerprint(&query));
+
+            Some(Message::Single(Measurement::new(
+                TIMER | HISTOGRAM,
+                "db.query_latency".to_string(),
+                Unit::Duration(event.duration),
+                tags,
+            )))
+        })
+    }
+}
+
+fn database_name(database: Database) -> &'static str {
+    use Database::*;
+
+    match database {
+        PostgresClient | PostgresServer => "postgres",
+        MysqlClient => "mysql",
+    }
+}
+
+/// Normalizes a statement to its first keyword and the table it operates
+/// on, e.g. `SELECT users`, so that latencies can be aggregated
+fn fingerprint(query: &str) -> String {
+    let mut words = query.split_whitespace();
+    let keyword = match words.next() {
+        Some(keyword) => keyword.to_uppercase(),
+        None => return String::new(),
+    };
+
+    let table = match keyword.as_str() {
+        "SELECT" | "DELETE" => words
+            .find(|w| w.eq_ignore_ascii_case("from"))
+            .and(words.next()),
+        "INSERT" | "REPLACE" => words
+            .find(|w| w.eq_ignore_ascii_case("into"))
+            .and(words.next()),
+        "UPDATE" => words.next(),
+        _ => None,
+    };
+
+    match table {
+        Some(table) => {
+            let table = table
+                .split(|c| c == '(' || c == ',' || c == ';')
+                .next()
+                .unwrap_or_default()
+                .trim_matches(|c| c == '"' || c == '`');
+            format!("{} {}", keyword, table)
+        }
+        None => keyword,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(
+            fingerprint("select * from users where id = 1"),
+            "SELECT users"
+        );
+        assert_eq!(fingerprint("SELECT 1"), "SELECT");
+        assert_eq!(
+            fingerprint("INSERT INTO `orders`(id) VALUES (1)"),
+            "INSERT orders"
+        );
+        assert_eq!(
+            fingerprint("update \"accounts\" set x = 1"),
+            "UPDATE accounts"
+        );
+        assert_eq!(fingerprint("DELETE FROM sessions;"), "DELETE sessions");
+        assert_eq!(fingerprint("BEGIN"), "BEGIN");
+        assert_eq!(fingerprint(""), "");
+    }
+}
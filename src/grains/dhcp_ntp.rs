@@ -0,0 +1,168 @@
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+use crate::grains::protocol::ip::to_ipv4;
+use crate::grains::protocol::{dhcp, ntp};
+use crate::grains::*;
+
+use ingraind_probes::dhcp_ntp::{Event, DHCP_SERVER_PORT, NTP_PORT};
+use redbpf::xdp::MapData;
+
+unsafe impl FromRawEvent for Event {}
+
+pub struct DhcpNtp {
+    config: DhcpNtpConfig,
+    /// The servers seen on the interface, shared by the handlers of all
+    /// CPUs
+    sources: Arc<Mutex<Sources>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DhcpNtpConfig {
+    interface: String,
+    #[serde(default = "default_xdp_mode")]
+    xdp_mode: XdpMode,
+}
+
+#[derive(Default)]
+struct Sources {
+    dhcp_server: Option<Ipv4Addr>,
+    ntp_servers: HashSet<Ipv4Addr>,
+}
+
+impl DhcpNtp {
+    pub fn new(config: DhcpNtpConfig) -> Self {
+        DhcpNtp {
+            config,
+            sources: Arc::new(Mutex::new(Sources::default())),
+        }
+    }
+}
+
+impl EBPFProbe for Grain<DhcpNtp> {
+    fn attach(&mut self) -> MessageStreams {
+        let conf = &self.native.config;
+        let interface = conf.interface.clone();
+        let flags = conf.xdp_mode.into();
+        self.attach_xdps(&interface, flags)
+    }
+}
+
+impl EBPFGrain<'static> for DhcpNtp {
+    fn code() -> &'static [u8] {
+        probes::dhcp_ntp::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        let interface = self.config.interface.clone();
+        let netns = netns::current();
+        let sources = self.sources.clone();
+
+        parsed_ref(move |data: &MapData<Event>| {
+            let event = data.data();
+            let source = to_ipv4(event.saddr);
+
+            let mut tags = Tags::new();
+            tags.insert("interface", interface.as_str());
+            if let Some(netns) = netns {
+                tags.insert("netns", netns);
+            }
+
+            let mut sources = sources.lock().unwrap();
+            let measurements = match event.sport {
+                DHCP_SERVER_PORT => dhcp_measurements(&mut sources, source, data.payload(), tags)?,
+                NTP_PORT => ntp_measurements(&mut sources, source, data.payload(), tags)?,
+                _ => return Ok(None),
+            };
+
+            Ok(measurements.map(Message::List))
+        })
+    }
+}
+
+fn dhcp_measurements(
+    sources: &mut Sources,
+    source: Ipv4Addr,
+    payload: &[u8],
+    mut tags: Tags,
+) -> Result<Option<Vec<Measurement>>, DecodeError> {
+    let reply = match dhcp::parse_reply(payload)? {
+        Some(reply) => reply,
+        None => return Ok(None),
+    };
+    let server = reply.server_id.unwrap_or(source);
+
+    tags.insert("server_ip", server);
+    tags.insert("s_ip", source);
+    tags.insert("your_ip", reply.your_ip);
+    if let Some(router) = reply.router {
+        tags.insert("router_ip", router);
+    }
+    if !reply.dns.is_empty() {
+        let dns = reply
+            .dns
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect::<Vec<_>>();
+        tags.insert("dns_servers", dns.join(","));
+    }
+
+    let mut measurements = vec![];
+    let previous = sources.dhcp_server.replace(server);
+    if let Some(previous) = previous.filter(|&p| p != server) {
+        let mut tags = tags.clone();
+        tags.insert("previous_server_ip", previous);
+        measurements.push(Measurement::new(
+            COUNTER | METER,
+            "dhcp.server_change".to_string(),
+            Unit::Count(1),
+            tags,
+        ));
+    }
+
+    measurements.push(Measurement::new(
+        COUNTER | METER,
+        format!("dhcp.{}", reply.message_type.as_str()),
+        Unit::Count(1),
+        tags,
+    ));
+
+    Ok(Some(measurements))
+}
+
+fn ntp_measurements(
+    sources: &mut Sources,
+    source: Ipv4Addr,
+    payload: &[u8],
+    mut tags: Tags,
+) -> Result<Option<Vec<Measurement>>, DecodeError> {
+    let response = match ntp::parse_response(payload)? {
+        Some(response) => response,
+        None => return Ok(None),
+    };
+
+    tags.insert("s_ip", source);
+    tags.insert("stratum", response.stratum);
+    tags.insert("reference_id", response.reference_id);
+
+    let mut measurements = vec![];
+    let first = sources.ntp_servers.is_empty();
+    if sources.ntp_servers.insert(source) && !first {
+        measurements.push(Measurement::new(
+            COUNTER | METER,
+            "ntp.source_change".to_string(),
+            Unit::Count(1),
+            tags.clone(),
+        ));
+    }
+
+    measurements.push(Measurement::new(
+        COUNTER | METER,
+        "ntp.response".to_string(),
+        Unit::Count(1),
+        tags,
+    ));
+
+    Ok(Some(measurements))
+}
@@ -0,0 +1,409 @@
+//! Scans the directories the Files grain monitors, to report how much space
+//! they use and how many of their files change.
+//!
+//! Every `interval_ms`, a thread walks every directory, without following
+//! symlinks or crossing into other filesystems, and the grain reports the
+//! total size of its files as `file.dir.size`, its inodes as
+//! `file.dir.inodes`, and how many of them were created or changed since the
+//! previous scan as `file.dir.changed`. From the second scan on, the growth
+//! since the previous one is reported as `file.dir.growth`, next to the
+//! bytes processes were seen writing under the directory in the meantime as
+//! `file.dir.written`.
+//!
+//! A directory growing by more than `growth_threshold_bytes`, or with more
+//! than `churn_threshold` changed files, between two scans is reported as
+//! `file.dir.alert`, tagged with the `reason`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::metrics::clock::wall_now;
+use crate::metrics::kind::{COUNTER, GAUGE};
+use crate::metrics::{Measurement, Tags, Unit};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiskUsageConfig {
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    pub growth_threshold_bytes: Option<u64>,
+    pub churn_threshold: Option<u64>,
+}
+
+fn default_interval_ms() -> u64 {
+    60_000
+}
+
+/// The usage of a directory at a scan
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub size: u64,
+    pub inodes: u64,
+    /// Inodes whose status changed since the previous scan
+    pub changed: u64,
+}
+
+/// Walks `dir` without leaving its filesystem, counting the inodes whose
+/// status changed since `since`, in nanoseconds since the UNIX epoch
+pub fn scan(dir: &Path, since: u64) -> io::Result<Usage> {
+    let root = fs::symlink_metadata(dir)?;
+    let mut usage = Usage {
+        size: 0,
+        inodes: 0,
+        changed: 0,
+    };
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        // directories can vanish while they're walked
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            if meta.dev() != root.dev() {
+                continue;
+            }
+
+            usage.inodes += 1;
+            if meta.is_file() {
+                usage.size += meta.len();
+            }
+            let ctime = meta.ctime() as u64 * 1_000_000_000 + meta.ctime_nsec() as u64;
+            if ctime >= since {
+                usage.changed += 1;
+            }
+            if meta.is_dir() {
+                pending.push(entry.path());
+            }
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Bytes written under each monitored directory since the last scan, with
+/// the path of the directory relative to the root of its filesystem
+#[derive(Clone)]
+pub struct Written(Arc<Mutex<HashMap<String, (String, u64)>>>);
+
+impl Written {
+    fn new(dirs: &[String]) -> Written {
+        let written = dirs
+            .iter()
+            .map(|dir| (dir.clone(), (fs_relative(Path::new(dir)), 0)))
+            .collect();
+        Written(Arc::new(Mutex::new(written)))
+    }
+
+    /// Counts `bytes` written to `path`, which is relative to the root of
+    /// its filesystem, like the paths of file events
+    pub fn record(&self, path: &str, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+
+        for (dir, written) in self.0.lock().unwrap().values_mut() {
+            if dir.is_empty()
+                || path == dir
+                || (path.starts_with(dir.as_str()) && path[dir.len()..].starts_with('/'))
+            {
+                *written += bytes;
+            }
+        }
+    }
+
+    fn take(&self, dir: &str) -> u64 {
+        self.0
+            .lock()
+            .unwrap()
+            .get_mut(dir)
+            .map(|(_, written)| std::mem::replace(written, 0))
+            .unwrap_or(0)
+    }
+}
+
+/// The path of `dir` relative to the mount point of its filesystem, found
+/// by walking up until the device changes
+fn fs_relative(dir: &Path) -> String {
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    let mut root = Path::new("/");
+    if let Ok(meta) = fs::metadata(&dir) {
+        root = dir.as_path();
+        while let Some(parent) = root.parent() {
+            match fs::metadata(parent) {
+                Ok(parent_meta) if parent_meta.dev() == meta.dev() => root = parent,
+                _ => break,
+            }
+        }
+    }
+
+    dir.strip_prefix(root)
+        .unwrap_or(&dir)
+        .to_string_lossy()
+        .trim_matches('/')
+        .to_string()
+}
+
+type ScanResult = Vec<(String, io::Result<Usage>)>;
+
+pub struct DiskUsage {
+    config: DiskUsageConfig,
+    dirs: Vec<String>,
+    written: Written,
+    previous: HashMap<String, Usage>,
+    /// When the previous scan started, in nanoseconds since the UNIX epoch
+    since: u64,
+    scanning: Option<Receiver<ScanResult>>,
+}
+
+impl DiskUsage {
+    pub fn new(config: DiskUsageConfig, dirs: &[String]) -> DiskUsage {
+        DiskUsage {
+            config,
+            dirs: dirs.to_vec(),
+            written: Written::new(dirs),
+            previous: HashMap::new(),
+            since: wall_now(),
+            scanning: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.config.interval_ms)
+    }
+
+    pub fn written(&self) -> Written {
+        self.written.clone()
+    }
+
+    /// The results of the scan that finished since the last call, if any,
+    /// and starts the next one if `due` and the previous one finished
+    pub fn poll(&mut self, due: bool) -> Vec<Measurement> {
+        let mut measurements = vec![];
+        if let Some(ref scanning) = self.scanning {
+            match scanning.try_recv() {
+                Ok(results) => {
+                    self.scanning = None;
+                    for (dir, result) in results {
+                        match result {
+                            Ok(usage) => measurements.extend(self.report(&dir, usage)),
+                            Err(e) => warn!("Failed to scan {}: {}", dir, e),
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => return measurements,
+                Err(TryRecvError::Disconnected) => self.scanning = None,
+            }
+        }
+
+        if due {
+            self.start_scan();
+        }
+
+        measurements
+    }
+
+    fn start_scan(&mut self) {
+        let (tx, rx) = channel();
+        let dirs = self.dirs.clone();
+        let since = self.since;
+        self.since = wall_now();
+        self.scanning = Some(rx);
+
+        thread::spawn(move || {
+            let results = dirs
+                .into_iter()
+                .map(|dir| {
+                    let usage = scan(&PathBuf::from(&dir), since);
+                    (dir, usage)
+                })
+                .collect::<ScanResult>();
+            let _ = tx.send(results);
+        });
+    }
+
+    fn report(&mut self, dir: &str, usage: Usage) -> Vec<Measurement> {
+        let written = self.written.take(dir);
+        let previous = self.previous.insert(dir.to_string(), usage);
+        measurements(&self.config, dir, usage, previous, written)
+    }
+}
+
+fn measurements(
+    config: &DiskUsageConfig,
+    dir: &str,
+    usage: Usage,
+    previous: Option<Usage>,
+    written: u64,
+) -> Vec<Measurement> {
+    let mut tags = Tags::new();
+    tags.insert("path_str", dir);
+
+    let mut measurements = vec![
+        Measurement::new(
+            GAUGE,
+            "file.dir.size".to_string(),
+            Unit::Byte(usage.size),
+            tags.clone(),
+        ),
+        Measurement::new(
+            GAUGE,
+            "file.dir.inodes".to_string(),
+            Unit::Count(usage.inodes),
+            tags.clone(),
+        ),
+        Measurement::new(
+            COUNTER,
+            "file.dir.changed".to_string(),
+            Unit::Count(usage.changed),
+            tags.clone(),
+        ),
+    ];
+
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return measurements,
+    };
+
+    let growth = usage.size as i64 - previous.size as i64;
+    measurements.push(Measurement::new(
+        GAUGE,
+        "file.dir.growth".to_string(),
+        Unit::Gauge(growth as f64),
+        tags.clone(),
+    ));
+    measurements.push(Measurement::new(
+        COUNTER,
+        "file.dir.written".to_string(),
+        Unit::Byte(written),
+        tags.clone(),
+    ));
+
+    let mut alert = |reason: &str| {
+        warn!(
+            "{} in {}: {} bytes, {} changed files",
+            reason, dir, growth, usage.changed
+        );
+        let mut tags = tags.clone();
+        tags.insert("reason", reason);
+        measurements.push(Measurement::new(
+            COUNTER,
+            "file.dir.alert".to_string(),
+            Unit::Count(1),
+            tags,
+        ));
+    };
+    match config.growth_threshold_bytes {
+        Some(threshold) if growth > threshold as i64 => alert("growth"),
+        _ => (),
+    }
+    match config.churn_threshold {
+        Some(threshold) if usage.changed > threshold => alert("churn"),
+        _ => (),
+    }
+
+    measurements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn scans() {
+        let dir = env::temp_dir().join(format!("ingraind-disk-usage-{}", process::id()));
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        fs::write(dir.join("a"), b"12345").unwrap();
+        fs::write(dir.join("logs/b"), b"123").unwrap();
+
+        let usage = scan(&dir, 0).unwrap();
+        assert_eq!(usage.size, 8);
+        assert_eq!(usage.inodes, 3);
+        assert_eq!(usage.changed, 3);
+        assert_eq!(scan(&dir, u64::MAX).unwrap().changed, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(scan(&dir, 0).is_err());
+    }
+
+    #[test]
+    fn written() {
+        let written = Written::new(&["/var/log".to_string(), "/".to_string()]);
+        // the paths are relative to the filesystem of the directory
+        written.0.lock().unwrap().get_mut("/var/log").unwrap().0 = "var/log".to_string();
+        written.record("var/log/syslog", 10);
+        written.record("var/logs/other", 5);
+        written.record("etc/passwd", 1);
+
+        assert_eq!(written.take("/var/log"), 10);
+        assert_eq!(written.take("/var/log"), 0);
+        assert_eq!(written.take("/"), 16);
+    }
+
+    #[test]
+    fn relative_to_filesystem() {
+        assert_eq!(fs_relative(Path::new("/")), "");
+        assert_eq!(
+            fs_relative(Path::new("/nonexistent/dir")),
+            "nonexistent/dir"
+        );
+    }
+
+    #[test]
+    fn alerts() {
+        let config: DiskUsageConfig = toml::from_str(
+            r#"
+growth_threshold_bytes = 1000
+churn_threshold = 10
+"#,
+        )
+        .unwrap();
+        let usage = |size, changed| Usage {
+            size,
+            inodes: 20,
+            changed,
+        };
+
+        let first = measurements(&config, "/var/log", usage(5000, 20), None, 0);
+        assert_eq!(first.len(), 3);
+
+        let steady = measurements(
+            &config,
+            "/var/log",
+            usage(5500, 5),
+            Some(usage(5000, 20)),
+            500,
+        );
+        assert_eq!(steady.len(), 5);
+        assert_eq!(steady[3].value, Unit::Gauge(500.0));
+        assert_eq!(steady[4].value, Unit::Byte(500));
+
+        let runaway = measurements(
+            &config,
+            "/var/log",
+            usage(9000, 50),
+            Some(usage(5500, 5)),
+            0,
+        );
+        let reasons = runaway
+            .iter()
+            .filter(|m| m.name == "file.dir.alert")
+            .map(|m| m.tags.get("reason").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons, vec!["growth", "churn"]);
+    }
+}
@@ -1,25 +1,54 @@
+use crate::backends::pcap::{self, LINKTYPE_RAW};
+use crate::grains::dns_exfil::{Analyzer, ExfiltrationConfig, Suspicion};
 use crate::grains::protocol::ip::to_ipv4;
+use crate::grains::sockets;
 use crate::grains::*;
 use crate::metrics::timestamp_now;
 
-use dns_parser::{rdata::RData, Packet, ResourceRecord};
+use dns_parser::{rdata::RData, Packet, ResourceRecord, ResponseCode};
 use metrohash::MetroHash64;
 use std::hash::Hasher;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use ingraind_probes::dns::Event;
 use redbpf::xdp::MapData;
 
-pub struct DNS(pub DnsConfig);
+unsafe impl FromRawEvent for Event {}
+
+pub struct DNS {
+    config: DnsConfig,
+    /// Shared by the handlers of all CPUs, so answers read on any of them
+    /// count towards the same windows
+    analyzer: Option<Arc<Mutex<Analyzer>>>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DnsConfig {
     interface: String,
     #[serde(default = "default_xdp_mode")]
     xdp_mode: XdpMode,
+    #[serde(default)]
+    capture_packets: bool,
+    /// Looks for data being smuggled out in queries
+    exfiltration: Option<ExfiltrationConfig>,
+}
+
+impl DNS {
+    pub fn new(config: DnsConfig) -> Self {
+        let analyzer = config
+            .exfiltration
+            .clone()
+            .map(|config| Arc::new(Mutex::new(Analyzer::new(config))));
+
+        DNS { config, analyzer }
+    }
 }
 
 impl EBPFProbe for Grain<DNS> {
     fn attach(&mut self) -> MessageStreams {
-        let conf = &self.native.0;
+        let conf = &self.native.config;
         let interface = conf.interface.clone();
         let flags = conf.xdp_mode.into();
         self.attach_xdps(&interface, flags)
@@ -28,21 +57,50 @@ impl EBPFProbe for Grain<DNS> {
 
 impl EBPFGrain<'static> for DNS {
     fn code() -> &'static [u8] {
-        include_bytes!(concat!(env!("OUT_DIR"), "/target/bpf/programs/dns/dns.elf"))
+        probes::dns::CODE
     }
 
     fn get_handler(&self, _id: &str) -> EventCallback {
-        Box::new(|raw| {
-            let data = unsafe { &*(raw.as_ptr() as *const MapData<Event>) };
+        let interface = self.config.interface.clone();
+        let capture_packets = self.config.capture_packets;
+        let netns = netns::current();
+        let analyzer = self.analyzer.clone();
+        parsed_ref(move |data: &MapData<Event>| {
             let event = data.data();
             if let Ok(packet) = Packet::parse(data.payload()) {
                 let timestamp = timestamp_now();
                 let query = DNSQuery::from(event);
 
+                let source = SocketAddr::new(query.source_ip.into(), query.source_port);
+                let destination =
+                    SocketAddr::new(query.destination_ip.into(), query.destination_port);
+                let owner = sockets::by_flow("udp", source, destination);
+
+                // answers are inbound, so the client is the destination
+                let client = match owner {
+                    Some(ref owner) => owner.pid.to_string(),
+                    None => query.destination_ip.to_string(),
+                };
+
                 let mut tags = query.to_tags();
                 let id = hash_event(event, timestamp);
+                if let Some(owner) = owner {
+                    owner.tag(&mut tags);
+                }
 
                 tags.insert("id", &id);
+                tags.insert("interface", interface.as_str());
+                if let Some(netns) = netns {
+                    tags.insert("netns", netns);
+                }
+
+                let analysis = match analyzer {
+                    Some(ref analyzer) => {
+                        let mut analyzer = analyzer.lock().unwrap();
+                        analyze(&mut analyzer, &packet, &client, &tags, timestamp)
+                    }
+                    None => vec![],
+                };
 
                 let mut measurements = vec![Measurement::with_timestamp(
                     timestamp,
@@ -62,10 +120,9 @@ impl EBPFGrain<'static> for DNS {
                                 COUNTER | HISTOGRAM | METER,
                                 "dns.answer_address".to_string(),
                                 Unit::Count(1),
-                                Tags(vec![
-                                    ("q_address_str".to_string(), v.qname.to_string()),
-                                    ("id".to_string(), id.clone()),
-                                ]),
+                                vec![("q_address_str", v.qname.to_string()), ("id", id.clone())]
+                                    .into_iter()
+                                    .collect(),
                             )
                         })
                         .collect::<Vec<Measurement>>(),
@@ -91,14 +148,92 @@ impl EBPFGrain<'static> for DNS {
                         .collect::<Vec<Measurement>>(),
                 );
 
-                Some(Message::List(measurements))
+                measurements.extend(analysis);
+
+                if capture_packets {
+                    measurements.push(pcap::packet(
+                        "dns",
+                        timestamp,
+                        &interface,
+                        LINKTYPE_RAW,
+                        to_ip_packet(event, data.payload()),
+                    ));
+                }
+
+                Ok(Some(Message::List(measurements)))
             } else {
-                None
+                Err(DecodeError::malformed("DNS packet", data.payload().len()))
             }
         })
     }
 }
 
+/// Runs the exfiltration heuristics over an answer to `client`, the process
+/// or host that sent the query. Along with the `dns.suspicious` events, the
+/// longest label of each query is reported as `dns.label_length`.
+fn analyze(
+    analyzer: &mut Analyzer,
+    packet: &Packet,
+    client: &str,
+    tags: &Tags,
+    timestamp: u64,
+) -> Vec<Measurement> {
+    let now = Instant::now();
+    let mut measurements = vec![];
+    let mut suspicions = vec![];
+
+    for question in packet.questions.iter() {
+        let qname = question.qname.to_string();
+        let mut label_tags = tags.clone();
+        label_tags.insert("q_address_str", qname.as_str());
+        measurements.push(Measurement::with_timestamp(
+            timestamp,
+            HISTOGRAM,
+            "dns.label_length".to_string(),
+            Unit::Count(analyzer.longest_label(&qname) as u64),
+            label_tags,
+        ));
+
+        for suspicion in analyzer.name(&qname) {
+            suspicions.push((suspicion, Some(qname.clone())));
+        }
+    }
+
+    if packet.header.response_code == ResponseCode::NameError {
+        suspicions.extend(analyzer.nxdomain(client, now).map(|s| (s, None)));
+    }
+
+    let txt_bytes = packet
+        .answers
+        .iter()
+        .map(|answer| match answer.data {
+            RData::TXT(ref txt) => txt.iter().map(|s| s.len() as u64).sum(),
+            _ => 0,
+        })
+        .sum::<u64>();
+    if txt_bytes > 0 {
+        suspicions.extend(analyzer.txt(client, txt_bytes, now).map(|s| (s, None)));
+    }
+
+    for (suspicion, qname) in suspicions {
+        let mut tags = tags.clone();
+        tags.insert("reason", suspicion.reason());
+        tags.insert("value", suspicion.value());
+        if let Some(qname) = qname {
+            tags.insert("q_address_str", qname);
+        }
+        measurements.push(Measurement::with_timestamp(
+            timestamp,
+            COUNTER | METER,
+            "dns.suspicious".to_string(),
+            Unit::Count(1),
+            tags,
+        ));
+    }
+
+    measurements
+}
+
 fn hash_event(event: &Event, timestamp: u64) -> String {
     let mut hasher = MetroHash64::new();
 
@@ -111,6 +246,36 @@ fn hash_event(event: &Event, timestamp: u64) -> String {
     hasher.finish().to_string()
 }
 
+/// Rebuilds the IPv4 and UDP headers around a DNS message. Only the
+/// addresses and ports are known, every other header field is synthetic.
+fn to_ip_packet(event: &Event, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len() as u16;
+    let total_len = 20 + udp_len;
+
+    let mut packet = Vec::with_capacity(total_len as usize);
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    // id, don't fragment, ttl, UDP, checksum
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+    packet.extend_from_slice(&to_ipv4(event.saddr).octets());
+    packet.extend_from_slice(&to_ipv4(event.daddr).octets());
+
+    let checksum = !packet.chunks(2).fold(0u32, |sum, word| {
+        let sum = sum + u32::from(u16::from_be_bytes([word[0], word[1]]));
+        (sum & 0xFFFF) + (sum >> 16)
+    }) as u16;
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(&event.sport.to_be_bytes());
+    packet.extend_from_slice(&event.dport.to_be_bytes());
+    packet.extend_from_slice(&udp_len.to_be_bytes());
+    // the UDP checksum is optional over IPv4
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+
+    packet
+}
+
 fn ip_to_tags(v: &ResourceRecord, id: &str) -> Tags {
     use RData::*;
 
@@ -120,11 +285,11 @@ fn ip_to_tags(v: &ResourceRecord, id: &str) -> Tags {
     match &v.data {
         A(a) => {
             tags.insert("record_type", "A");
-            tags.insert("address", a.0.to_string());
+            tags.insert("address", a.0);
         }
         AAAA(aaaa) => {
             tags.insert("record_type", "AAAA");
-            tags.insert("address", aaaa.0.to_string());
+            tags.insert("address", aaaa.0);
         }
         CNAME(cname) => {
             tags.insert("record_type", "CNAME");
@@ -132,7 +297,7 @@ fn ip_to_tags(v: &ResourceRecord, id: &str) -> Tags {
         }
         MX(mx) => {
             tags.insert("record_type", "MX");
-            tags.insert("mx_preference", format!("{}", mx.preference));
+            tags.insert("mx_preference", mx.preference);
             tags.insert("address", mx.exchange.to_string());
         }
         NS(ns) => {
@@ -194,11 +359,11 @@ impl ToTags for DNSQuery {
     fn to_tags(self) -> Tags {
         let mut tags = Tags::new();
 
-        tags.insert("d_ip", self.destination_ip.to_string());
-        tags.insert("d_port", self.destination_port.to_string());
+        tags.insert("d_ip", self.destination_ip);
+        tags.insert("d_port", self.destination_port);
 
-        tags.insert("s_ip", self.source_ip.to_string());
-        tags.insert("s_port", self.source_port.to_string());
+        tags.insert("s_ip", self.source_ip);
+        tags.insert("s_port", self.source_port);
 
         tags
     }
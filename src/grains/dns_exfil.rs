@@ -0,0 +1,305 @@
+//! Heuristics for spotting data smuggled out through DNS queries.
+//!
+//! Names are scored on the Shannon entropy and length of the labels below
+//! the registered domain, which encoded data inflates. Registered domains
+//! are found with the public suffix list, so the labels of names like
+//! `www.example.co.uk` are only `www`. Processes, or the
+//! hosts they run on when the process isn't known, are also checked for
+//! bursts of NXDOMAIN answers and large volumes of TXT records within a
+//! window, the hallmarks of tunnels that answer with data.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use publicsuffix::List;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExfiltrationConfig {
+    /// Bits per character above which a name is suspicious
+    #[serde(default = "default_entropy_threshold")]
+    pub entropy_threshold: f64,
+    /// Names shorter than this aren't scored on entropy
+    #[serde(default = "default_min_entropy_length")]
+    pub min_entropy_length: usize,
+    #[serde(default = "default_max_label_length")]
+    pub max_label_length: usize,
+    /// NXDOMAIN answers per window
+    #[serde(default = "default_nxdomain_threshold")]
+    pub nxdomain_threshold: u64,
+    /// Bytes of TXT records per window
+    #[serde(default = "default_txt_bytes_threshold")]
+    pub txt_bytes_threshold: u64,
+    #[serde(default = "default_window_s")]
+    pub window_s: u64,
+    /// The public suffix list, as published at publicsuffix.org
+    #[serde(default = "default_public_suffix_list")]
+    pub public_suffix_list: PathBuf,
+}
+
+fn default_entropy_threshold() -> f64 {
+    4.0
+}
+
+fn default_min_entropy_length() -> usize {
+    20
+}
+
+fn default_max_label_length() -> usize {
+    40
+}
+
+fn default_nxdomain_threshold() -> u64 {
+    50
+}
+
+fn default_txt_bytes_threshold() -> u64 {
+    16 * 1024
+}
+
+fn default_window_s() -> u64 {
+    60
+}
+
+fn default_public_suffix_list() -> PathBuf {
+    PathBuf::from("/usr/share/publicsuffix/public_suffix_list.dat")
+}
+
+/// Why a name or a source looks suspicious, and the value that crossed the
+/// threshold
+#[derive(Debug, Clone, PartialEq)]
+pub enum Suspicion {
+    Entropy(f64),
+    LabelLength(usize),
+    NxdomainRate(u64),
+    TxtVolume(u64),
+}
+
+impl Suspicion {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Suspicion::Entropy(_) => "entropy",
+            Suspicion::LabelLength(_) => "label_length",
+            Suspicion::NxdomainRate(_) => "nxdomain_rate",
+            Suspicion::TxtVolume(_) => "txt_volume",
+        }
+    }
+
+    pub fn value(&self) -> String {
+        match self {
+            Suspicion::Entropy(x) => format!("{:.2}", x),
+            Suspicion::LabelLength(x) => x.to_string(),
+            Suspicion::NxdomainRate(x) | Suspicion::TxtVolume(x) => x.to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Window {
+    started: Option<Instant>,
+    nxdomains: u64,
+    txt_bytes: u64,
+    nxdomain_reported: bool,
+    txt_reported: bool,
+}
+
+pub struct Analyzer {
+    config: ExfiltrationConfig,
+    suffixes: Option<List>,
+    windows: HashMap<String, Window>,
+}
+
+impl Analyzer {
+    /// Without a public suffix list, registered domains are assumed to be
+    /// made of the last two labels of names
+    pub fn new(config: ExfiltrationConfig) -> Self {
+        let suffixes = match List::from_path(&config.public_suffix_list) {
+            Ok(list) => Some(list),
+            Err(e) => {
+                warn!(
+                    "Can't read the public suffix list {:?}, assuming domains are registered under TLDs: {}",
+                    config.public_suffix_list, e
+                );
+                None
+            }
+        };
+
+        Analyzer::with_suffixes(config, suffixes)
+    }
+
+    fn with_suffixes(config: ExfiltrationConfig, suffixes: Option<List>) -> Self {
+        Analyzer {
+            config,
+            suffixes,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Scores a queried name
+    pub fn name(&self, name: &str) -> Vec<Suspicion> {
+        let mut suspicions = vec![];
+        let longest = self.longest_label(name);
+        if longest > self.config.max_label_length {
+            suspicions.push(Suspicion::LabelLength(longest));
+        }
+
+        let subdomain = self.subdomain_labels(name).concat();
+        if subdomain.len() >= self.config.min_entropy_length {
+            let entropy = entropy(&subdomain);
+            if entropy > self.config.entropy_threshold {
+                suspicions.push(Suspicion::Entropy(entropy));
+            }
+        }
+
+        suspicions
+    }
+
+    /// Counts an NXDOMAIN answer to `source`, which is reported once per
+    /// window when the threshold is crossed
+    pub fn nxdomain(&mut self, source: &str, now: Instant) -> Option<Suspicion> {
+        let threshold = self.config.nxdomain_threshold;
+        let window = self.window(source, now);
+        window.nxdomains += 1;
+        if window.nxdomains > threshold && !window.nxdomain_reported {
+            window.nxdomain_reported = true;
+            return Some(Suspicion::NxdomainRate(window.nxdomains));
+        }
+
+        None
+    }
+
+    /// Adds the size of TXT records sent to `source`
+    pub fn txt(&mut self, source: &str, bytes: u64, now: Instant) -> Option<Suspicion> {
+        let threshold = self.config.txt_bytes_threshold;
+        let window = self.window(source, now);
+        window.txt_bytes += bytes;
+        if window.txt_bytes > threshold && !window.txt_reported {
+            window.txt_reported = true;
+            return Some(Suspicion::TxtVolume(window.txt_bytes));
+        }
+
+        None
+    }
+
+    fn window(&mut self, source: &str, now: Instant) -> &mut Window {
+        let length = Duration::from_secs(self.config.window_s);
+        // sources are only ever added by traffic, so drop the quiet ones
+        // whenever a window rolls over
+        if self.windows.len() > 4096 {
+            self.windows.retain(|_, w| match w.started {
+                Some(started) => now.duration_since(started) < length,
+                None => false,
+            });
+        }
+
+        let window = self.windows.entry(source.to_string()).or_default();
+        match window.started {
+            Some(started) if now.duration_since(started) < length => {}
+            _ => {
+                *window = Window {
+                    started: Some(now),
+                    ..Window::default()
+                }
+            }
+        }
+
+        window
+    }
+
+    /// The length of the longest label below the registered domain of
+    /// `name`
+    pub fn longest_label(&self, name: &str) -> usize {
+        self.subdomain_labels(name)
+            .iter()
+            .map(|l| l.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The labels below the registered domain
+    fn subdomain_labels<'a>(&self, name: &'a str) -> Vec<&'a str> {
+        let name = name.trim_end_matches('.');
+        let labels = name
+            .split('.')
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<_>>();
+
+        let registered = self
+            .suffixes
+            .as_ref()
+            .and_then(|list| list.parse_domain(name).ok())
+            .and_then(|domain| Some(domain.root()?.split('.').count()))
+            .unwrap_or(2);
+        let end = labels.len().saturating_sub(registered);
+        labels[..end].to_vec()
+    }
+}
+
+/// Shannon entropy in bits per character
+fn entropy(s: &str) -> f64 {
+    let mut counts = [0usize; 256];
+    for b in s.bytes() {
+        counts[b.to_ascii_lowercase() as usize] += 1;
+    }
+
+    let len = s.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ExfiltrationConfig {
+        toml::from_str("nxdomain_threshold = 2\ntxt_bytes_threshold = 100").unwrap()
+    }
+
+    #[test]
+    fn names() {
+        let analyzer = Analyzer::with_suffixes(config(), None);
+        assert!(analyzer.name("www.example.com").is_empty());
+        assert!(analyzer.name("mail.google.com.").is_empty());
+        assert_eq!(analyzer.longest_label("example.com"), 0);
+        assert_eq!(analyzer.longest_label("www.example.co.uk"), 7);
+
+        let suffixes = List::from_str("// ===BEGIN ICANN DOMAINS===\ncom\nuk\nco.uk\n").unwrap();
+        let analyzer = Analyzer::with_suffixes(config(), Some(suffixes));
+        assert_eq!(analyzer.longest_label("www.example.co.uk"), 3);
+        assert_eq!(analyzer.longest_label("example.co.uk"), 0);
+
+        let encoded = "mzxw6ytboi4dkmzqgq2tmnzyhe3dcmrvgu4tiobqgm2dc.evil.com";
+        let suspicions = analyzer.name(encoded);
+        assert_eq!(suspicions[0], Suspicion::LabelLength(45));
+        assert_eq!(suspicions[1].reason(), "entropy");
+    }
+
+    #[test]
+    fn rates() {
+        let mut analyzer = Analyzer::with_suffixes(config(), None);
+        let now = Instant::now();
+
+        assert_eq!(analyzer.nxdomain("42", now), None);
+        assert_eq!(analyzer.nxdomain("42", now), None);
+        assert_eq!(
+            analyzer.nxdomain("42", now),
+            Some(Suspicion::NxdomainRate(3))
+        );
+        // reported once per window
+        assert_eq!(analyzer.nxdomain("42", now), None);
+        assert_eq!(analyzer.nxdomain("43", now), None);
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(analyzer.nxdomain("42", later), None);
+
+        assert_eq!(analyzer.txt("42", 80, now), None);
+        assert_eq!(analyzer.txt("42", 80, now), Some(Suspicion::TxtVolume(160)));
+    }
+}
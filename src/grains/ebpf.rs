@@ -1,46 +1,319 @@
 use crate::backends::Message;
-use crate::grains::SendToManyRecipients;
+use crate::grains::event::{DecodeError, DecodeErrorSink, FromRawEvent};
+use crate::control::{self, DumpMap};
+use crate::grains::{dump_common_map, SendToManyRecipients};
 use crate::grains::ebpf_io::{
-    MessageStream, MessageStreams, PerfMessageStream, SocketMessageStream
+    GrainStream, MessageStreams, OrderedPerfMessageStream, PerfMessageStream,
+    SocketMessageStream
 };
+use crate::grains::limits::{Breach, RateLimiter, PERF_BUFFER_PAGES};
+use crate::grains::meta;
+use crate::grains::prebuilt;
+use crate::grains::probes;
+use crate::grains::sampling::{Sampler, SamplingConfig};
+use crate::grains::stats::GrainStats;
+use crate::grains::workers::Workers;
+use crate::metrics::{from_kernel_time, Measurement};
 
-use redbpf::{cpus, xdp, Module, PerfMap, Result};
+use redbpf::uname::get_kernel_internal_version;
+use redbpf::{cpus, xdp, HashMap as BPFHashMap, Module, PerfMap, Program};
 
-use actix::{Actor, AsyncContext, Context, Recipient, Running, StreamHandler};
+use actix::{Actor, AsyncContext, Context, Handler, Recipient, Running, StreamHandler};
+use serde_json::Value;
 use lazy_socket::raw::Socket;
 use std::io;
 use std::os::unix::io::FromRawFd;
 use std::convert::Into;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+const LIMIT_INTERVAL: Duration = Duration::from_secs(1);
+const TOGGLE_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Grain<T> {
     module: Module,
     pub native: T,
+    stats: Arc<GrainStats>,
+    failed: Vec<(String, Target)>,
+    sampler: Option<Sampler>,
+    reorder: Option<Duration>,
+    decode_errors: Option<DecodeErrorSink>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+/// Where and when an event handed to an `EventCallback` was seen
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EventContext {
+    /// The CPU whose perf buffer the event was read from
+    pub cpu: Option<usize>,
+    /// The time the kernel saw the event at, in nanoseconds since boot, if
+    /// it's sent as `Timestamped`
+    pub ktime: Option<u64>,
+    /// The time the monotonic clock started at, if the event was seen on
+    /// another boot, such as a replayed one
+    pub boot_time: Option<u64>,
+}
+
+/// Handles a raw event, and returns the measurements it makes, or why it
+/// couldn't be decoded
+pub type EventCallback =
+    Box<dyn Fn(&EventContext, &[u8]) -> std::result::Result<Option<Message>, DecodeError> + Send>;
+
+/// A function or an offset in an executable or shared library to attach
+/// uprobes to
+#[derive(Clone, Debug)]
+pub struct UprobeLocation {
+    pub target: String,
+    pub symbol: Option<String>,
+    pub offset: u64,
 }
 
-pub type EventCallback = Box<dyn Fn(&[u8]) -> Option<Message> + Send>;
+/// What a program is attached to
+#[derive(Debug)]
+enum Target {
+    Kprobe,
+    Function(String),
+    Uprobe {
+        location: UprobeLocation,
+        pid: Option<i32>,
+    },
+    Xdp {
+        iface: String,
+        flags: xdp::Flags,
+    },
+    Tracepoint {
+        category: String,
+        name: String,
+    },
+}
+
+/// Attaches `prog` to `target`, and records it in `failed` if the kernel
+/// refuses, unless it's `optional`
+fn attach(
+    stats: &GrainStats,
+    failed: &mut Vec<(String, Target)>,
+    prog: &mut Program,
+    target: Target,
+    optional: bool,
+) {
+    let result = match target {
+        Target::Kprobe => prog.attach_probe(),
+        Target::Function(ref name) => prog.attach_probe_to_name(name),
+        Target::Uprobe { ref location, pid } => {
+            let symbol = location.symbol.as_ref().map(String::as_str);
+            prog.attach_uprobe(symbol, location.offset, &location.target, pid)
+        }
+        Target::Xdp { ref iface, flags } => prog.attach_xdp(iface, flags),
+        Target::Tracepoint {
+            ref category,
+            ref name,
+        } => prog.attach_tracepoint(category, name),
+    };
+
+    match result {
+        Ok(_) => {
+            stats.attached();
+            info!(
+                grain = stats.name(),
+                program = %prog.name,
+                kind = ?prog.kind,
+                target = ?target,
+                "attached"
+            );
+        }
+        Err(e) if optional => {
+            warn!(
+                grain = stats.name(),
+                program = %prog.name,
+                target = ?target,
+                error = ?e,
+                "failed to attach optional program, running without it"
+            );
+        }
+        Err(e) => {
+            warn!(
+                grain = stats.name(),
+                program = %prog.name,
+                target = ?target,
+                error = ?e,
+                "failed to attach"
+            );
+            failed.push((prog.name.clone(), target));
+        }
+    }
+}
 
 pub trait EBPFGrain<'code>: Sized {
     fn code() -> &'code [u8];
     fn get_handler(&self, id: &str) -> EventCallback;
-    fn loaded(&mut self, _module: &mut Module) {}
+    /// Sets up the maps of the probe once it's loaded
+    fn loaded(&mut self, _module: &mut Module) -> std::result::Result<(), failure::Error> {
+        Ok(())
+    }
+
+    /// How often counters aggregated in the kernel are scraped, if the
+    /// grain aggregates any
+    fn scrape_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Reads and resets the counters aggregated in the kernel
+    fn scrape(&mut self, _module: &Module) -> Vec<Measurement> {
+        vec![]
+    }
+
+    /// Reads the contents of map `name` as JSON, if the grain knows the
+    /// types of its keys and values
+    fn dump_map(&self, module: &Module, name: &str) -> Option<Value> {
+        dump_common_map(module, name)
+    }
+
+    /// Whether the grain works without program `name`, which is then only
+    /// warned about if it fails to attach
+    fn optional(&self, _name: &str) -> bool {
+        false
+    }
 
-    fn load(mut self) -> Result<Grain<Self>>
+    /// Whether the events of map `id` are sent as `Timestamped`, as declared
+    /// by the type of the perf map in the probe
+    fn kernel_timestamps(&self, id: &str) -> bool {
+        probes::events(Self::code())
+            .iter()
+            .any(|(map, event)| *map == id && event.starts_with("Timestamped<"))
+    }
+
+    /// Whether the events of map `id` can be put in order by their kernel
+    /// timestamp when the probe sets `reorder_ms`
+    fn ordered(&self, id: &str) -> bool {
+        self.kernel_timestamps(id)
+    }
+
+    /// Whether the events of map `id` are sent as `Sampled`, inside the
+    /// timestamp if they have one
+    fn sampled(&self, _id: &str) -> bool {
+        false
+    }
+
+    /// How the probe samples events in the kernel during bursts, if it can
+    fn sampling(&self) -> Option<SamplingConfig> {
+        None
+    }
+
+    /// The handler for the events of map `id`, stamping measurements with
+    /// the time the kernel saw the event at if it's known, and the rate it
+    /// was sampled at
+    fn handler(&self, id: &str) -> EventCallback {
+        let mut handler = self.get_handler(id);
+        if self.sampled(id) {
+            handler = sampled(handler);
+        }
+        if self.kernel_timestamps(id) {
+            handler = timestamped(handler);
+        }
+
+        handler
+    }
+
+    fn load(mut self) -> std::result::Result<Grain<Self>, failure::Error>
     where
         Self: Sized,
     {
-        let mut module = Module::parse(Self::code())?;
+        let code = match prebuilt::code(grain_name::<Self>()) {
+            Some(code) => code,
+            None => Self::code(),
+        };
+        let mut module = Module::parse(code)
+            .map_err(|e| failure::format_err!("couldn't parse the probe: {:?}", e))?;
+        if let Some(kernel) = get_kernel_internal_version() {
+            let metas = meta::of(code)?;
+            let unsupported = meta::unsupported(&metas, kernel);
+            for (program, reason) in unsupported.iter() {
+                warn!(
+                    grain = grain_name::<Self>(),
+                    program = %program,
+                    reason = %reason,
+                    "skipping unsupported program"
+                );
+            }
+            module
+                .programs
+                .retain(|p| !unsupported.iter().any(|(program, _)| *program == p.name));
+        }
         for prog in module.programs.iter_mut() {
-            prog.load(module.version, module.license.clone()).unwrap();
+            prog.load(module.version, module.license.clone())
+                .map_err(|e| failure::format_err!("couldn't load {}: {:?}", prog.name, e))?;
         }
 
-        self.loaded(&mut module);
+        self.loaded(&mut module)?;
+        let sampler = self.sampling().map(Sampler::new);
+        let programs = module.programs.iter().map(|p| p.name.clone()).collect();
+        let maps = module.maps.iter().map(|m| m.name.clone()).collect();
         Ok(Grain {
             module,
             native: self,
+            stats: GrainStats::register(grain_name::<Self>(), programs, maps),
+            failed: vec![],
+            sampler,
+            reorder: None,
+            decode_errors: None,
+            limiter: None,
         })
     }
 }
 
+/// Strips the kernel time from events, hands it to the handler in the
+/// context, and stamps their measurements with it
+fn timestamped(handler: EventCallback) -> EventCallback {
+    Box::new(move |ctx, raw| {
+        let ktime = u64::from_raw(raw)?;
+        let ctx = EventContext {
+            ktime: Some(ktime),
+            ..*ctx
+        };
+        let mut message = match handler(&ctx, &raw[8..])? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        let timestamp = match ctx.boot_time {
+            Some(boot_time) => boot_time.saturating_add(ktime),
+            None => from_kernel_time(ktime),
+        };
+        match message {
+            Message::Single(ref mut m) => m.timestamp = timestamp,
+            Message::List(ref mut ms) => ms.iter_mut().for_each(|m| m.timestamp = timestamp),
+            Message::Flush => {}
+        }
+
+        Ok(Some(message))
+    })
+}
+
+/// Strips the divisor from sampled events, and sets the sample rate of
+/// their measurements
+fn sampled(handler: EventCallback) -> EventCallback {
+    Box::new(move |ctx, raw| {
+        let divisor = u64::from_raw(raw)?.max(1);
+        let mut message = match handler(ctx, &raw[8..])? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        let rate = 1.0 / divisor as f64;
+        let sample =
+            |m: &mut Measurement| m.sample_rate = Some(m.sample_rate.unwrap_or(1.0) * rate);
+        match message {
+            Message::Single(ref mut m) => sample(m),
+            Message::List(ref mut ms) => ms.iter_mut().for_each(sample),
+            Message::Flush => {}
+        }
+
+        Ok(Some(message))
+    })
+}
+
 impl<'code, 'module, T> Grain<T>
 where
     T: EBPFGrain<'code>,
@@ -53,9 +326,9 @@ where
             .iter_mut()
             .filter(|p| p.kind == Kprobe || p.kind == Kretprobe)
         {
-            prog.attach_probe()
-                .expect(&format!("Failed to attach kprobe {}", prog.name));
-            info!("Loaded: {}, {:?}", prog.name, prog.kind);
+            let optional = self.native.optional(&prog.name);
+            let target = Target::Kprobe;
+            attach(&self.stats, &mut self.failed, prog, target, optional);
         }
 
         self.bind_perf()
@@ -69,8 +342,33 @@ where
             .iter_mut()
             .filter(|p| p.kind == Kprobe || p.kind == Kretprobe)
         {
-            info!("Loaded: {}, {:?}", name.as_ref(), prog.kind);
-            prog.attach_probe_to_name(name.as_ref()).unwrap();
+            let target = Target::Function(name.as_ref().to_string());
+            let optional = self.native.optional(&prog.name);
+            attach(&self.stats, &mut self.failed, prog, target, optional);
+        }
+
+        self.bind_perf()
+    }
+
+    /// Attaches all uprobes in the module to the locations returned by
+    /// `locations` for each program
+    pub fn attach_uprobes(
+        &mut self,
+        pid: Option<i32>,
+        locations: impl Fn(&str) -> Vec<UprobeLocation>,
+    ) -> MessageStreams {
+        use redbpf::ProgramKind::*;
+        for prog in self
+            .module
+            .programs
+            .iter_mut()
+            .filter(|p| p.kind == UProbe || p.kind == URetProbe)
+        {
+            let optional = self.native.optional(&prog.name);
+            for location in locations(&prog.name) {
+                let target = Target::Uprobe { location, pid };
+                attach(&self.stats, &mut self.failed, prog, target, optional);
+            }
         }
 
         self.bind_perf()
@@ -79,8 +377,12 @@ where
     pub fn attach_xdps(&mut self, iface: &str, flags: xdp::Flags) -> MessageStreams {
         use redbpf::ProgramKind::*;
         for prog in self.module.programs.iter_mut().filter(|p| p.kind == XDP) {
-            info!("Loaded: {}, {:?}", prog.name, prog.kind);
-            prog.attach_xdp(iface, flags).unwrap();
+            let target = Target::Xdp {
+                iface: iface.to_string(),
+                flags,
+            };
+            let optional = self.native.optional(&prog.name);
+            attach(&self.stats, &mut self.failed, prog, target, optional);
         }
 
         self.bind_perf()
@@ -94,8 +396,33 @@ where
             .iter_mut()
             .filter(|p| p.kind == Tracepoint)
         {
-            info!("Attached: {}, {:?}", prog.name, prog.kind);
-            prog.attach_tracepoint(category, name).unwrap();
+            let target = Target::Tracepoint {
+                category: category.to_string(),
+                name: name.to_string(),
+            };
+            let optional = self.native.optional(&prog.name);
+            attach(&self.stats, &mut self.failed, prog, target, optional);
+        }
+
+        self.bind_perf()
+    }
+
+    /// Attaches each tracepoint program in the module to the tracepoint of
+    /// `category` it is named after
+    pub fn attach_tracepoints_to_names(&mut self, category: &str) -> MessageStreams {
+        use redbpf::ProgramKind::*;
+        for prog in self
+            .module
+            .programs
+            .iter_mut()
+            .filter(|p| p.kind == Tracepoint)
+        {
+            let target = Target::Tracepoint {
+                category: category.to_string(),
+                name: prog.name.clone(),
+            };
+            let optional = self.native.optional(&prog.name);
+            attach(&self.stats, &mut self.failed, prog, target, optional);
         }
 
         self.bind_perf()
@@ -105,14 +432,44 @@ where
         let online_cpus = cpus::get_online().unwrap();
         let mut streams: MessageStreams = vec![];
         for m in self.module.maps.iter_mut().filter(|m| m.kind == 4) {
-            for cpuid in online_cpus.iter() {
-                let map = PerfMap::bind(m, -1, *cpuid, 16, -1, 0).unwrap();
-                let stream = Box::new(PerfMessageStream::new(
-                    m.name.clone(),
-                    map,
-                    self.native.get_handler(m.name.as_str()),
-                ));
-                streams.push(stream);
+            let maps = online_cpus
+                .iter()
+                .map(|cpuid| {
+                    (
+                        *cpuid as usize,
+                        PerfMap::bind(m, -1, *cpuid, PERF_BUFFER_PAGES, -1, 0).unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            // only events with a kernel timestamp can be put in order
+            match self.reorder {
+                Some(horizon) if self.native.ordered(&m.name) => {
+                    let stream = Box::new(OrderedPerfMessageStream::new(
+                        m.name.clone(),
+                        maps,
+                        horizon,
+                        self.native.handler(m.name.as_str()),
+                        self.stats.clone(),
+                        self.decode_errors.clone(),
+                        self.limiter.clone(),
+                    ));
+                    streams.push(GrainStream::Local(stream));
+                }
+                _ => {
+                    for (cpuid, map) in maps {
+                        let stream = PerfMessageStream::new(
+                            m.name.clone(),
+                            cpuid,
+                            map,
+                            self.native.handler(m.name.as_str()),
+                            self.stats.clone(),
+                            self.decode_errors.clone(),
+                            self.limiter.clone(),
+                        );
+                        streams.push(GrainStream::Cpu(cpuid, stream));
+                    }
+                }
             }
         }
 
@@ -121,15 +478,24 @@ where
 
     pub fn attach_socketfilters(&mut self, iface: &str) -> MessageStreams {
         use redbpf::ProgramKind::*;
+        let stats = self.stats.clone();
         let socket_fds = self
             .module
             .programs
             .iter_mut()
             .filter(|p| p.kind == SocketFilter)
             .map(|prog| {
-                info!("Attached: {}, {:?}", prog.name, prog.kind);
-                prog.attach_socketfilter(iface).unwrap()
+                let fd = prog.attach_socketfilter(iface).unwrap();
+                info!(
+                    grain = stats.name(),
+                    program = %prog.name,
+                    kind = ?prog.kind,
+                    iface,
+                    "attached"
+                );
+                fd
             })
+            .inspect(|_| stats.attached())
             .collect::<Vec<_>>();
 
         // we need to get out of mutable borrow land to continue.
@@ -143,28 +509,262 @@ where
             .filter(|p| p.kind == SocketFilter)
             .zip(&socket_fds)
             .map(|(prog, fd)| {
-                Box::new(SocketMessageStream::new(
+                let stream = Box::new(SocketMessageStream::new(
                     prog.name.clone(),
                     unsafe { Socket::from_raw_fd(*fd) },
                     self.native.get_handler(prog.name.as_str()),
-                )) as Box<MessageStream>
+                    self.stats.clone(),
+                    self.decode_errors.clone(),
+                    self.limiter.clone(),
+                ));
+                GrainStream::Local(stream)
             })
             .collect()
     }
 }
 
-pub trait EBPFProbe: Send {
+pub trait EBPFProbe:
+    Send
+    + Introspect
+    + Scrape
+    + Sample
+    + Dump
+    + Reattach
+    + Reorder
+    + ReportDecodeErrors
+    + Limit
+    + Toggle
+{
     fn attach(&mut self) -> MessageStreams;
 }
 
+pub trait Introspect {
+    fn stats(&self) -> Arc<GrainStats>;
+}
+
+impl<T> Introspect for Grain<T> {
+    fn stats(&self) -> Arc<GrainStats> {
+        self.stats.clone()
+    }
+}
+
+pub trait Scrape {
+    fn scrape_interval(&self) -> Option<Duration>;
+    fn scrape(&mut self) -> Vec<Measurement>;
+}
+
+impl<'code, T: EBPFGrain<'code>> Scrape for Grain<T> {
+    fn scrape_interval(&self) -> Option<Duration> {
+        self.native.scrape_interval()
+    }
+
+    fn scrape(&mut self) -> Vec<Measurement> {
+        self.native.scrape(&self.module)
+    }
+}
+
+pub trait Sample {
+    /// How often the sampling divisor is adjusted, if the probe samples
+    /// events in the kernel
+    fn sampling_interval(&self) -> Option<Duration>;
+
+    /// Adjusts the sampling divisor to the rate of events since the last
+    /// adjustment
+    fn adjust_sampling(&mut self);
+}
+
+impl<T> Sample for Grain<T> {
+    fn sampling_interval(&self) -> Option<Duration> {
+        self.sampler.as_ref().map(Sampler::interval)
+    }
+
+    fn adjust_sampling(&mut self) {
+        if let Some(ref mut sampler) = self.sampler {
+            sampler.adjust(&self.module, &self.stats);
+        }
+    }
+}
+
+pub trait Dump {
+    fn dump_map(&self, name: &str) -> Option<Value>;
+}
+
+impl<'code, T: EBPFGrain<'code>> Dump for Grain<T> {
+    fn dump_map(&self, name: &str) -> Option<Value> {
+        self.native.dump_map(&self.module, name)
+    }
+}
+
+pub trait Reattach {
+    /// The programs that failed to attach
+    fn failed(&self) -> Vec<String>;
+
+    /// Retries attaching the programs that failed to, and returns how many
+    /// still fail
+    fn reattach(&mut self) -> usize;
+}
+
+impl<T> Reattach for Grain<T> {
+    fn failed(&self) -> Vec<String> {
+        self.failed.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn reattach(&mut self) -> usize {
+        let mut failed = vec![];
+        for (name, target) in self.failed.drain(..) {
+            match self.module.programs.iter_mut().find(|p| p.name == name) {
+                Some(prog) => attach(&self.stats, &mut failed, prog, target, false),
+                None => continue,
+            }
+        }
+        self.failed = failed;
+
+        self.failed.len()
+    }
+}
+
+pub trait Reorder {
+    /// Merges the `Timestamped` events of all CPUs in the order the kernel
+    /// saw them, holding them back for `horizon`. Only applies to the perf
+    /// maps bound after it's set.
+    fn reorder(&mut self, horizon: Duration);
+}
+
+impl<T> Reorder for Grain<T> {
+    fn reorder(&mut self, horizon: Duration) {
+        self.reorder = Some(horizon);
+    }
+}
+
+pub trait ReportDecodeErrors {
+    /// Sends a sample of the events that fail to decode to `sink`. Only
+    /// applies to the streams bound after it's set.
+    fn report_decode_errors(&mut self, sink: DecodeErrorSink);
+}
+
+impl<T> ReportDecodeErrors for Grain<T> {
+    fn report_decode_errors(&mut self, sink: DecodeErrorSink) {
+        self.decode_errors = Some(sink);
+    }
+}
+
+pub trait Limit {
+    /// Admits the events of the grain through `limiter`. Only applies to
+    /// the streams bound after it's set.
+    fn limit(&mut self, limiter: Arc<RateLimiter>);
+}
+
+impl<T> Limit for Grain<T> {
+    fn limit(&mut self, limiter: Arc<RateLimiter>) {
+        self.limiter = Some(limiter);
+    }
+}
+
+pub trait Toggle {
+    /// Sets the `DISABLED` flag in the probe's `settings` map, if it has
+    /// one, so its programs stop doing work in the kernel. Events of probes
+    /// without it are still dropped as they are read.
+    fn set_disabled(&mut self, disabled: bool);
+}
+
+impl<T> Toggle for Grain<T> {
+    fn set_disabled(&mut self, disabled: bool) {
+        let name = probes::common::maps::SETTINGS;
+        let map = match self.module.maps.iter().find(|m| m.name == name) {
+            Some(map) => map,
+            None => return,
+        };
+
+        if let Ok(settings) = BPFHashMap::<u8, u64>::new(map) {
+            settings.set(ingraind_probes::DISABLED, disabled as u64);
+        }
+    }
+}
+
+/// The name of the grain's type, without the module path
+fn grain_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+/// What to do when programs of a grain fail to attach
+#[derive(Clone, Copy, Debug)]
+pub struct AttachPolicy {
+    /// Stop ingraind, instead of running without the programs
+    pub required: bool,
+    /// How often to retry attaching programs that aren't required
+    pub retry_interval: Duration,
+}
+
+impl Default for AttachPolicy {
+    fn default() -> Self {
+        AttachPolicy {
+            required: true,
+            retry_interval: Duration::from_secs(60),
+        }
+    }
+}
+
 pub struct EBPFActor {
     probe: Box<dyn EBPFProbe>,
     recipients: Vec<Recipient<Message>>,
+    workers: Option<Workers>,
+    policy: AttachPolicy,
+    limiter: Option<Arc<RateLimiter>>,
 }
 
 impl EBPFActor {
     pub fn new(probe: Box<dyn EBPFProbe>, recipients: Vec<Recipient<Message>>) -> Self {
-        EBPFActor { probe, recipients }
+        EBPFActor {
+            probe,
+            recipients,
+            workers: None,
+            policy: AttachPolicy::default(),
+            limiter: None,
+        }
+    }
+
+    /// Reads the perf buffers of the probe on `workers`
+    pub fn with_workers(mut self, workers: Workers) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: AttachPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Merges the events of all CPUs in the order the kernel saw them,
+    /// holding them back for `horizon`
+    pub fn with_reorder(mut self, horizon: Duration) -> Self {
+        self.probe.reorder(horizon);
+        self
+    }
+
+    /// Sends a sample of the events that fail to decode to `sink`
+    pub fn with_decode_errors(mut self, sink: DecodeErrorSink) -> Self {
+        self.probe.report_decode_errors(sink);
+        self
+    }
+
+    /// Caps the rate of the probe's events at the limit of `limiter`
+    pub fn with_limiter(mut self, limiter: RateLimiter) -> Self {
+        let limiter = Arc::new(limiter);
+        self.probe.limit(limiter.clone());
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Retries attaching the programs that failed to until all are attached
+    fn reattach_later(&self, ctx: &mut Context<Self>) {
+        ctx.run_later(self.policy.retry_interval, |act, ctx| {
+            if act.probe.reattach() > 0 {
+                act.reattach_later(ctx);
+            } else {
+                act.probe.stats().ready();
+            }
+        });
     }
 }
 
@@ -172,10 +772,97 @@ impl Actor for EBPFActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let mut streams = self.probe.attach();
-        for stream in streams.drain(..) {
+        let streams = self.probe.attach();
+        let streams = match self.workers {
+            Some(ref workers) => workers.spawn(streams, &self.recipients),
+            None => streams.into_iter().map(GrainStream::into_local).collect(),
+        };
+        for stream in streams {
             ctx.add_stream(stream);
         }
+
+        let stats = self.probe.stats();
+        let failed = self.probe.failed();
+        if !failed.is_empty() {
+            if self.policy.required {
+                panic!(
+                    "Failed to attach programs of {}: {}",
+                    stats.name(),
+                    failed.join(", ")
+                );
+            }
+
+            warn!(
+                grain = stats.name(),
+                programs = %failed.join(", "),
+                "running without programs that failed to attach"
+            );
+            self.reattach_later(ctx);
+        }
+
+        control::register(stats.name(), ctx.address().recipient());
+        if self.policy.required {
+            stats.require();
+        }
+        if failed.is_empty() {
+            stats.ready();
+        }
+        ctx.run_interval(STATS_INTERVAL, move |act, _ctx| {
+            let measurements = stats.snapshot().to_measurements();
+            act.recipients.do_send(Message::List(measurements));
+        });
+
+        let mut enabled = true;
+        ctx.run_interval(TOGGLE_INTERVAL, move |act, _ctx| {
+            let current = act.probe.stats().is_enabled();
+            if current != enabled {
+                act.probe.set_disabled(!current);
+                enabled = current;
+            }
+        });
+
+        if let Some(interval) = self.probe.scrape_interval() {
+            ctx.run_interval(interval, |act, _ctx| {
+                let measurements = act.probe.scrape();
+                if !measurements.is_empty() && act.probe.stats().is_enabled() {
+                    act.recipients.do_send(Message::List(measurements));
+                }
+            });
+        }
+
+        if let Some(interval) = self.probe.sampling_interval() {
+            ctx.run_interval(interval, |act, _ctx| act.probe.adjust_sampling());
+        }
+
+        if let Some(limiter) = self.limiter.clone() {
+            let name = self.probe.stats().name().to_string();
+            ctx.run_interval(LIMIT_INTERVAL, move |act, _ctx| {
+                let events = match limiter.take_exceeded() {
+                    Some(events) => events,
+                    None => return,
+                };
+
+                warn!(
+                    grain = %name,
+                    events,
+                    limit = limiter.max(),
+                    action = ?limiter.on_breach(),
+                    "over the event rate limit"
+                );
+                if limiter.on_breach() == Breach::Alert {
+                    let alert = limiter.to_measurement(&name, events);
+                    act.recipients.do_send(Message::Single(alert));
+                }
+            });
+        }
+    }
+}
+
+impl Handler<DumpMap> for EBPFActor {
+    type Result = Option<Value>;
+
+    fn handle(&mut self, msg: DumpMap, _ctx: &mut Context<Self>) -> Self::Result {
+        self.probe.dump_map(&msg.0)
     }
 }
 
@@ -187,7 +874,7 @@ impl StreamHandler<Vec<Message>, io::Error> for EBPFActor {
     }
 
     fn error(&mut self, err: io::Error, _ctx: &mut Self::Context) -> Running {
-        error!("probe error: {}", err);
+        error!(error = %err, "probe error");
         Running::Continue
     }
 }
@@ -216,3 +903,39 @@ impl Into<xdp::Flags> for XdpMode {
 pub fn default_xdp_mode() -> XdpMode {
     XdpMode::Auto
 }
+
+const PROGRAM_PREFIXES: [&str; 7] = [
+    "kprobe/",
+    "kretprobe/",
+    "uprobe/",
+    "uretprobe/",
+    "tracepoint/",
+    "socketfilter/",
+    "xdp/",
+];
+
+/// Checks that `code` is a well-formed eBPF object without loading it, and
+/// returns the names of the programs it contains
+pub fn program_names(code: &[u8]) -> std::result::Result<Vec<String>, failure::Error> {
+    let elf = goblin::elf::Elf::parse(code)?;
+    let sections = elf
+        .section_headers
+        .iter()
+        .filter_map(|sh| elf.shdr_strtab.get(sh.sh_name).and_then(|name| name.ok()))
+        .collect::<Vec<_>>();
+
+    if !sections.contains(&"license") {
+        return Err(failure::format_err!("missing license section"));
+    }
+
+    let programs = sections
+        .iter()
+        .filter(|name| PROGRAM_PREFIXES.iter().any(|p| name.starts_with(p)))
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    if programs.is_empty() {
+        return Err(failure::format_err!("no programs found"));
+    }
+
+    Ok(programs)
+}
@@ -1,6 +1,12 @@
 use crate::backends::Message;
+use crate::grains::limits::RateLimiter;
 use crate::grains::protocol::*;
-use crate::grains::EventCallback;
+use crate::grains::reorder::ReorderBuffer;
+use crate::grains::stats::GrainStats;
+use crate::grains::{DecodeError, DecodeErrorSink, EventCallback, EventContext};
+use crate::metrics::clock::monotonic_now;
+use crate::record;
+use crate::shedding;
 
 use futures::{Async, Poll, Stream};
 use lazy_socket::raw::Socket;
@@ -10,7 +16,11 @@ use redbpf::PerfMap;
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::reactor::{Handle, PollEvented2};
+use tokio_timer::Interval;
+use tracing::{debug, warn};
 
 pub struct GrainIo(RawFd);
 
@@ -41,46 +51,104 @@ impl Evented for GrainIo {
 }
 
 pub type MessageStream = dyn Stream<Item = Vec<Message>, Error = io::Error>;
-pub type MessageStreams = Vec<Box<MessageStream>>;
+pub type MessageStreams = Vec<GrainStream>;
 
+pub enum GrainStream {
+    /// Read on the thread of the grain
+    Local(Box<MessageStream>),
+    /// Reads the perf buffer of a CPU, and can be moved to the worker of
+    /// that CPU
+    Cpu(usize, PerfMessageStream),
+}
+
+impl GrainStream {
+    /// The stream, to be read on the thread of the grain
+    pub fn into_local(self) -> Box<MessageStream> {
+        match self {
+            GrainStream::Local(stream) => stream,
+            GrainStream::Cpu(_, stream) => Box::new(stream),
+        }
+    }
+}
+
+/// A perf buffer that can be moved to the thread that reads it.
+///
+/// `PerfMap` isn't `Send` because it keeps raw pointers to the ring buffer
+/// it mmaps. The mapping and the perf event behind it are owned by the
+/// `PerfMap` alone, nothing else points into them, and they aren't tied
+/// to the thread that opened them, so the map can be moved. It isn't
+/// `Sync`, so it's still only read from one thread at a time.
+struct OwnedPerfMap(PerfMap);
+
+unsafe impl Send for OwnedPerfMap {}
+
+/// Reads the perf buffer of a CPU.
+///
+/// It's `Send` so it can be read by a worker: the buffer is registered with
+/// the reactor of the thread that first polls the stream, not the one that
+/// creates it.
 pub struct PerfMessageStream {
     poll: PollEvented2<GrainIo>,
-    map: PerfMap,
+    map: OwnedPerfMap,
     name: String,
+    /// Handed to the callback with every event
+    context: EventContext,
     callback: EventCallback,
+    stats: Arc<GrainStats>,
+    decode_errors: Option<DecodeErrorSink>,
+    limiter: Option<Arc<RateLimiter>>,
 }
 
 impl PerfMessageStream {
-    pub fn new(name: String, map: PerfMap, callback: EventCallback) -> Self {
+    pub fn new(
+        name: String,
+        cpu: usize,
+        map: PerfMap,
+        callback: EventCallback,
+        stats: Arc<GrainStats>,
+        decode_errors: Option<DecodeErrorSink>,
+        limiter: Option<Arc<RateLimiter>>,
+    ) -> Self {
         let io = GrainIo(map.fd);
         let poll = PollEvented2::new_with_handle(io, &Handle::default()).unwrap();
         PerfMessageStream {
             poll,
-            map,
+            map: OwnedPerfMap(map),
             name,
+            context: EventContext {
+                cpu: Some(cpu),
+                ..Default::default()
+            },
             callback,
+            stats,
+            decode_errors,
+            limiter,
         }
     }
 
     fn read_messages(&mut self) -> Vec<Message> {
         use redbpf::Event;
 
+        let enabled = self.stats.is_map_enabled(&self.name);
         let mut ret = Vec::new();
-        while let Some(ev) = self.map.read() {
+        while let Some(ev) = self.map.0.read() {
             match ev {
-                Event::Lost(lost) => {
-                    warn!("Possibly lost {} samples for {}", lost.count, &self.name);
-                }
+                Event::Lost(lost) => report_lost(&self.stats, &self.name, lost.count),
+                Event::Sample(_) if !enabled => self.stats.dropped(1),
                 Event::Sample(sample) => {
-                    let msg = unsafe {
-                        (self.callback)(slice::from_raw_parts(
-                            sample.data.as_ptr(),
-                            sample.size as usize,
-                        ))
+                    let data = unsafe {
+                        slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize)
                     };
-                    if let Some(msg) = msg {
-                        ret.push(msg);
-                    }
+                    let msg = handle_sample(
+                        &self.stats,
+                        &self.name,
+                        &self.callback,
+                        &self.context,
+                        self.decode_errors.as_ref(),
+                        self.limiter.as_ref(),
+                        data,
+                    );
+                    ret.extend(msg);
                 }
             };
         }
@@ -89,6 +157,80 @@ impl PerfMessageStream {
     }
 }
 
+fn report_lost(stats: &GrainStats, map: &str, count: u64) {
+    warn!(
+        grain = stats.name(),
+        map = %map,
+        count,
+        "possibly lost samples"
+    );
+    stats.lost(count);
+}
+
+/// Hands a sample to `callback` unless load is being shed, or the grain is
+/// over its rate limit
+fn handle_sample(
+    stats: &GrainStats,
+    map: &str,
+    callback: &EventCallback,
+    context: &EventContext,
+    decode_errors: Option<&DecodeErrorSink>,
+    limiter: Option<&Arc<RateLimiter>>,
+    data: &[u8],
+) -> Option<Message> {
+    let rate = match admit(stats, limiter) {
+        Some(rate) => rate,
+        None => {
+            stats.dropped(1);
+            return None;
+        }
+    };
+    record::record(stats.name(), map, data);
+    let msg = checked(stats, map, decode_errors, data, callback(context, data));
+
+    msg.map(|msg| shedding::sampled(msg, rate))
+}
+
+/// The rate the next event is sampled at, by load shedding and the rate
+/// limit, or `None` if it's dropped
+fn admit(stats: &GrainStats, limiter: Option<&Arc<RateLimiter>>) -> Option<f64> {
+    let rate = shedding::sample(stats.sampled_events())?;
+    match limiter {
+        Some(limiter) => Some(rate * limiter.admit()?),
+        None => Some(rate),
+    }
+}
+
+/// Counts the events the callback handled, and the ones it failed to
+/// decode, a sample of which is sent to `decode_errors`
+fn checked(
+    stats: &GrainStats,
+    map: &str,
+    decode_errors: Option<&DecodeErrorSink>,
+    data: &[u8],
+    result: Result<Option<Message>, DecodeError>,
+) -> Option<Message> {
+    match result {
+        Ok(msg) => {
+            stats.received(msg.is_some());
+            msg
+        }
+        Err(e) => {
+            let count = stats.malformed();
+            debug!(
+                grain = stats.name(),
+                map = %map,
+                error = %e,
+                "failed to decode event"
+            );
+            if let Some(sink) = decode_errors {
+                sink.report(count, stats.name(), map, &e, data);
+            }
+            None
+        }
+    }
+}
+
 impl Stream for PerfMessageStream {
     type Item = Vec<Message>;
     type Error = io::Error;
@@ -105,26 +247,173 @@ impl Stream for PerfMessageStream {
     }
 }
 
+/// Reads the perf buffers of a map on every CPU, and hands their
+/// `Timestamped` events to a single callback in the order the kernel saw
+/// them, once they are as old as the reorder horizon
+pub struct OrderedPerfMessageStream {
+    buffers: Vec<(usize, PollEvented2<GrainIo>, PerfMap)>,
+    name: String,
+    callback: EventCallback,
+    stats: Arc<GrainStats>,
+    decode_errors: Option<DecodeErrorSink>,
+    limiter: Option<Arc<RateLimiter>>,
+    reorder: ReorderBuffer,
+    tick: Interval,
+}
+
+impl OrderedPerfMessageStream {
+    pub fn new(
+        name: String,
+        maps: Vec<(usize, PerfMap)>,
+        horizon: Duration,
+        callback: EventCallback,
+        stats: Arc<GrainStats>,
+        decode_errors: Option<DecodeErrorSink>,
+        limiter: Option<Arc<RateLimiter>>,
+    ) -> Self {
+        let buffers = maps
+            .into_iter()
+            .map(|(cpu, map)| {
+                let io = GrainIo(map.fd);
+                let poll = PollEvented2::new_with_handle(io, &Handle::default()).unwrap();
+                (cpu, poll, map)
+            })
+            .collect();
+        OrderedPerfMessageStream {
+            buffers,
+            name,
+            callback,
+            stats,
+            decode_errors,
+            limiter,
+            reorder: ReorderBuffer::new(horizon),
+            tick: Interval::new_interval(horizon.max(Duration::from_millis(1))),
+        }
+    }
+
+    fn read_events(&mut self) -> io::Result<()> {
+        use redbpf::Event;
+
+        let ready = Ready::readable();
+        let enabled = self.stats.is_map_enabled(&self.name);
+        for (cpu, poll, map) in self.buffers.iter_mut() {
+            if poll.poll_read_ready(ready)? == Async::NotReady {
+                continue;
+            }
+
+            while let Some(ev) = map.read() {
+                match ev {
+                    Event::Lost(lost) => report_lost(&self.stats, &self.name, lost.count),
+                    Event::Sample(_) if !enabled => self.stats.dropped(1),
+                    Event::Sample(sample) => {
+                        let data = unsafe {
+                            slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize)
+                        };
+                        self.reorder.push(*cpu, data);
+                    }
+                };
+            }
+            poll.clear_read_ready(ready).unwrap();
+        }
+
+        let late = self.reorder.take_late();
+        if late > 0 {
+            warn!(
+                grain = self.stats.name(),
+                map = %self.name,
+                count = late,
+                horizon = ?self.reorder.horizon(),
+                "events arrived after the reorder horizon"
+            );
+        }
+
+        Ok(())
+    }
+
+    fn release_messages(&mut self) -> Vec<Message> {
+        let now = monotonic_now();
+        let mut ret = Vec::new();
+        while let Some((cpu, ktime, data)) = self.reorder.pop(now) {
+            let context = EventContext {
+                cpu: Some(cpu),
+                ktime: Some(ktime),
+                ..Default::default()
+            };
+            let msg = handle_sample(
+                &self.stats,
+                &self.name,
+                &self.callback,
+                &context,
+                self.decode_errors.as_ref(),
+                self.limiter.as_ref(),
+                &data,
+            );
+            ret.extend(msg);
+        }
+
+        ret
+    }
+}
+
+impl Stream for OrderedPerfMessageStream {
+    type Item = Vec<Message>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.read_events()?;
+
+        // wakes the stream up to release the events held back
+        while let Async::Ready(Some(_)) = self
+            .tick
+            .poll()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        {}
+
+        let messages = self.release_messages();
+        if messages.is_empty() {
+            return Ok(Async::NotReady);
+        }
+
+        Ok(Async::Ready(Some(messages)))
+    }
+}
+
 pub struct SocketMessageStream {
+    name: String,
     poll: PollEvented2<GrainIo>,
     socket: Socket,
     callback: EventCallback,
+    stats: Arc<GrainStats>,
+    decode_errors: Option<DecodeErrorSink>,
+    limiter: Option<Arc<RateLimiter>>,
 }
 
 impl SocketMessageStream {
-    pub fn new(_name: String, socket: Socket, callback: EventCallback) -> Self {
+    pub fn new(
+        name: String,
+        socket: Socket,
+        callback: EventCallback,
+        stats: Arc<GrainStats>,
+        decode_errors: Option<DecodeErrorSink>,
+        limiter: Option<Arc<RateLimiter>>,
+    ) -> Self {
         let io = GrainIo(socket.as_raw_fd());
         let poll = PollEvented2::new_with_handle(io, &Handle::default()).unwrap();
         SocketMessageStream {
+            name,
             poll,
             socket,
             callback,
+            stats,
+            decode_errors,
+            limiter,
         }
     }
 
     fn read_messages(&self) -> Vec<Message> {
         let mut buf = [0u8; 64 * 1024];
         let mut headbuf = [0u8; ETH_HLEN + 4];
+        let enabled = self.stats.is_map_enabled(&self.name);
 
         let mut ret = Vec::new();
         while self.socket.recv(&mut headbuf, 0x02 /* MSG_PEEK */).is_ok() {
@@ -133,8 +422,29 @@ impl SocketMessageStream {
             if read <= ETH_HLEN {
                 break;
             }
-            if let Some(msg) = (self.callback)(&buf[..plen]) {
-                ret.push(msg);
+            let admitted = if enabled {
+                admit(&self.stats, self.limiter.as_ref())
+            } else {
+                None
+            };
+            let rate = match admitted {
+                Some(rate) => rate,
+                None => {
+                    self.stats.dropped(1);
+                    continue;
+                }
+            };
+            record::record(self.stats.name(), &self.name, &buf[..plen]);
+            let result = (self.callback)(&EventContext::default(), &buf[..plen]);
+            let msg = checked(
+                &self.stats,
+                &self.name,
+                self.decode_errors.as_ref(),
+                &buf[..plen],
+                result,
+            );
+            if let Some(msg) = msg {
+                ret.push(shedding::sampled(msg, rate));
             }
         }
 
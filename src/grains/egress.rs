@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::str::FromStr;
+
+use failure::{format_err, Error};
+use redbpf::uname::get_kernel_internal_version;
+
+use crate::grains::cgroup::cgroup2_root;
+use crate::grains::filter::words;
+use crate::grains::loader::Module;
+use crate::grains::meta;
+use crate::grains::probes::egress::{maps, CODE};
+
+use ingraind_probes::egress::{
+    Destination, Policy, Rule as RawRule, ACTION_ALLOW, ACTION_DENY, MAX_RULES, POLICY,
+};
+use ingraind_probes::filter::Cidr as RawCidr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+fn default_action() -> Action {
+    Action::Allow
+}
+
+fn default_cgroup() -> String {
+    "/".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EgressRule {
+    pub action: Action,
+    pub cidr: Option<String>,
+    pub ports: Option<Vec<u16>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EgressPolicyConfig {
+    #[serde(default = "default_action")]
+    pub default_action: Action,
+    pub rules: Vec<EgressRule>,
+    /// Rejects the connections the policy denies in the kernel, instead of
+    /// only reporting them
+    #[serde(default)]
+    pub enforce: bool,
+    /// The cgroup whose processes the policy is enforced on, with the ones
+    /// below it, as a path in the cgroup v2 hierarchy
+    #[serde(default = "default_cgroup")]
+    pub cgroup: String,
+}
+
+impl EgressPolicyConfig {
+    /// Where the cgroup the policy is enforced on is in the filesystem
+    pub fn cgroup_path(&self) -> PathBuf {
+        cgroup2_root().join(self.cgroup.trim_start_matches('/'))
+    }
+}
+
+/// A network in the IPv6 address space, with IPv4 networks mapped into it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    pub(crate) addr: u128,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, addr: u128) -> bool {
+        addr & self.mask() == self.addr & self.mask()
+    }
+
+    pub(crate) fn mask(&self) -> u128 {
+        if self.prefix == 0 {
+            return 0;
+        }
+
+        !0u128 << (128 - u32::from(self.prefix))
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr, Error> {
+        let mut parts = s.splitn(2, '/');
+        let addr = IpAddr::from_str(parts.next().unwrap())?;
+        let (addr, max_prefix, offset) = match addr {
+            IpAddr::V4(v4) => (v4.to_ipv6_mapped(), 32, 96),
+            IpAddr::V6(v6) => (v6, 128, 0),
+        };
+        let prefix = match parts.next() {
+            Some(p) => u8::from_str(p)?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(format_err!("invalid prefix length in {}", s));
+        }
+
+        Ok(Cidr {
+            addr: u128::from(addr),
+            prefix: prefix + offset,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    action: Action,
+    net: Option<Cidr>,
+    ports: Option<Vec<u16>>,
+}
+
+impl Rule {
+    fn matches(&self, addr: u128, port: u16) -> bool {
+        self.net.map(|n| n.contains(addr)).unwrap_or(true)
+            && self
+                .ports
+                .as_ref()
+                .map(|p| p.contains(&port))
+                .unwrap_or(true)
+    }
+}
+
+/// Ordered list of allow/deny rules for outbound connections.
+///
+/// Rules are evaluated in the order of definition, and the first match
+/// decides. If no rule matches, `default_action` applies.
+#[derive(Debug)]
+pub struct EgressPolicy {
+    default_action: Action,
+    rules: Vec<Rule>,
+}
+
+impl EgressPolicy {
+    pub fn new(config: &EgressPolicyConfig) -> Result<Self, Error> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|r| {
+                Ok(Rule {
+                    action: r.action,
+                    net: r.cidr.as_ref().map(|c| c.parse()).transpose()?,
+                    ports: r.ports.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(EgressPolicy {
+            default_action: config.default_action,
+            rules,
+        })
+    }
+
+    pub fn check(&self, addr: IpAddr, port: u16) -> Action {
+        let addr = u128::from(match addr {
+            IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+            IpAddr::V6(v6) => v6,
+        });
+
+        self.rules
+            .iter()
+            .find(|r| r.matches(addr, port))
+            .map(|r| r.action)
+            .unwrap_or(self.default_action)
+    }
+
+    /// The policy as the `egress` probe evaluates it. Every port of a rule
+    /// becomes a rule of its own, and at most `MAX_RULES` fit.
+    pub fn to_raw(&self) -> Result<Policy, Error> {
+        let mut rules = vec![];
+        for rule in self.rules.iter() {
+            let net = rule
+                .net
+                .map(|net| RawCidr {
+                    addr: words(net.addr & net.mask()),
+                    mask: words(net.mask()),
+                })
+                .unwrap_or_default();
+            let ports = match rule.ports {
+                Some(ref ports) => ports.clone(),
+                None => vec![0],
+            };
+            rules.extend(ports.into_iter().map(|port| RawRule {
+                action: raw_action(rule.action),
+                port,
+                net,
+            }));
+        }
+        if rules.len() > MAX_RULES {
+            return Err(format_err!(
+                "at most {} rules, counting every port as one, can be enforced",
+                MAX_RULES
+            ));
+        }
+
+        let mut policy = Policy {
+            default_action: raw_action(self.default_action),
+            rules_len: rules.len() as u32,
+            ..Default::default()
+        };
+        policy.rules[..rules.len()].copy_from_slice(&rules);
+
+        Ok(policy)
+    }
+}
+
+fn raw_action(action: Action) -> u8 {
+    match action {
+        Action::Allow => ACTION_ALLOW,
+        Action::Deny => ACTION_DENY,
+    }
+}
+
+/// Rejects the connections an `EgressPolicy` denies in the kernel, with the
+/// programs of the `egress` probe attached to a cgroup
+pub struct Enforcer {
+    module: Module,
+    /// The connections rejected so far to each destination
+    counted: HashMap<Vec<u8>, u64>,
+}
+
+impl Enforcer {
+    pub fn attach(policy: &EgressPolicy, cgroup: &Path) -> Result<Enforcer, Error> {
+        let policy = policy.to_raw()?;
+        if let Some(kernel) = get_kernel_internal_version() {
+            meta::check(&meta::of(CODE)?, kernel)
+                .map_err(|e| format_err!("can't enforce the egress policy: {}", e))?;
+        }
+
+        let mut module = Module::load(CODE)?;
+        let value = unsafe {
+            slice::from_raw_parts(
+                &policy as *const Policy as *const u8,
+                mem::size_of::<Policy>(),
+            )
+        };
+        module
+            .map(maps::POLICY)
+            .ok_or_else(|| format_err!("the egress probe has no {} map", maps::POLICY))?
+            .set(&[POLICY], value)?;
+        module.attach_cgroup(cgroup)?;
+
+        Ok(Enforcer {
+            module,
+            counted: HashMap::new(),
+        })
+    }
+
+    /// The connections rejected to each destination since the last call
+    pub fn denied(&mut self) -> Vec<(IpAddr, u16, u64)> {
+        let map = match self.module.map(maps::DENIED) {
+            Some(map) => map,
+            None => return vec![],
+        };
+
+        let mut denied = vec![];
+        for key in map.keys() {
+            let count = match map.get(&key) {
+                Some(value) => {
+                    let mut count = [0u8; 8];
+                    count.copy_from_slice(&value[..8]);
+                    u64::from_ne_bytes(count)
+                }
+                None => continue,
+            };
+            let counted = self.counted.insert(key.clone(), count).unwrap_or(0);
+            if count > counted {
+                let dest = unsafe { ptr::read_unaligned(key.as_ptr() as *const Destination) };
+                denied.push((addr(&dest), dest.port as u16, count - counted));
+            }
+        }
+
+        denied
+    }
+}
+
+fn addr(dest: &Destination) -> IpAddr {
+    let mut bytes = [0u8; 16];
+    for (i, word) in dest.addr.iter().enumerate() {
+        bytes[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+    }
+
+    to_ip_addr(Ipv6Addr::from(bytes))
+}
+
+/// Unmaps IPv4-mapped addresses (`::ffff:a.b.c.d`). Every other address,
+/// including `::1` and the deprecated IPv4-compatible ones, is IPv6.
+pub fn to_ip_addr(v6: Ipv6Addr) -> IpAddr {
+    match v6.segments() {
+        [0, 0, 0, 0, 0, 0xFFFF, hi, lo] => {
+            IpAddr::V4(Ipv4Addr::from(u32::from(hi) << 16 | u32::from(lo)))
+        }
+        _ => IpAddr::V6(v6),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(toml: &str) -> EgressPolicy {
+        EgressPolicy::new(&toml::from_str(toml).unwrap()).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(u128::from(Ipv6Addr::from_str("::ffff:10.1.2.3").unwrap())));
+        assert!(!net.contains(u128::from(Ipv6Addr::from_str("::ffff:11.1.2.3").unwrap())));
+
+        let any: Cidr = "::/0".parse().unwrap();
+        assert!(any.contains(u128::from(Ipv6Addr::from_str("::ffff:11.1.2.3").unwrap())));
+    }
+
+    #[test]
+    fn test_to_ip_addr() {
+        let addr = |s: &str| to_ip_addr(Ipv6Addr::from_str(s).unwrap());
+        assert_eq!(addr("::ffff:10.1.2.3"), ip("10.1.2.3"));
+        assert_eq!(addr("::1"), ip("::1"));
+        assert_eq!(addr("::"), ip("::"));
+        assert_eq!(addr("2001:db8::1"), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn test_cidr_invalid() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let p = policy(
+            r#"
+default_action = "deny"
+rules = [
+  { action = "deny", cidr = "10.0.0.1/32" },
+  { action = "allow", cidr = "10.0.0.0/8", ports = [443] },
+  { action = "allow", cidr = "2001:db8::/32" },
+]
+"#,
+        );
+
+        assert_eq!(p.check(ip("10.0.0.1"), 443), Action::Deny);
+        assert_eq!(p.check(ip("10.0.0.2"), 443), Action::Allow);
+        assert_eq!(p.check(ip("10.0.0.2"), 80), Action::Deny);
+        assert_eq!(p.check(ip("2001:db8::1"), 80), Action::Allow);
+        assert_eq!(p.check(ip("8.8.8.8"), 53), Action::Deny);
+    }
+
+    #[test]
+    fn test_default_allow() {
+        let p = policy(
+            r#"
+rules = [{ action = "deny", ports = [25] }]
+"#,
+        );
+
+        assert_eq!(p.check(ip("8.8.8.8"), 25), Action::Deny);
+        assert_eq!(p.check(ip("8.8.8.8"), 53), Action::Allow);
+    }
+
+    #[test]
+    fn test_to_raw() {
+        let p = policy(
+            r#"
+default_action = "deny"
+rules = [
+  { action = "allow", cidr = "10.0.0.0/8", ports = [53, 443] },
+  { action = "deny" },
+]
+"#,
+        );
+        let raw = p.to_raw().unwrap();
+
+        assert_eq!(raw.default_action, ACTION_DENY);
+        assert_eq!(raw.rules_len, 3);
+        assert_eq!(
+            raw.rules[..3].iter().map(|r| r.port).collect::<Vec<_>>(),
+            vec![53, 443, 0]
+        );
+        assert_eq!(raw.rules[0].action, ACTION_ALLOW);
+        assert_eq!(raw.rules[0].net.addr[3], u32::from_ne_bytes([10, 0, 0, 0]));
+        assert_eq!(raw.rules[2].net.mask, [0; 4]);
+
+        let ports = (1..=MAX_RULES as u16 + 1)
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        let p = policy(&format!(
+            "rules = [{{ action = \"deny\", ports = [{}] }}]",
+            ports.join(", ")
+        ));
+        assert!(p.to_raw().is_err());
+    }
+}
@@ -0,0 +1,441 @@
+//! Decoding of the raw events sent by eBPF programs.
+//!
+//! Events are checked to be large enough for their type before they are
+//! read, and the ones that aren't are handed back as a `DecodeError`. They
+//! are counted in the grain's `events_malformed`, and a sample of them can
+//! be sent to a `DecodeErrorSink` as `ingraind.grain.decode_error`
+//! measurements, with the raw event in the `payload` tag.
+//!
+//! Events with variable-length data are sent as
+//! `ingraind_probes::WithPayload`, and handed to grains as the event and
+//! the bytes of its payload by `decoded_with_payload`. Handlers that need
+//! the CPU an event was read on, or the time the kernel saw it at, get its
+//! `EventContext` from `decoded_with_context`. Handlers that parse the
+//! packets or fields events carry are wrapped with `parsed` or
+//! `parsed_ref`, and fail with `DecodeError::malformed` on the ones they
+//! can't parse, rather than skipping them like events of no interest.
+
+use std::any::type_name;
+use std::fmt;
+use std::mem::{align_of, size_of};
+use std::ptr;
+
+use actix::Recipient;
+
+use crate::backends::Message;
+use crate::grains::{EventCallback, EventContext, SendToManyRecipients};
+use crate::metrics::kind::COUNTER;
+use crate::metrics::{Measurement, Tags, Unit};
+
+use ingraind_probes::{WithPayload, PAYLOAD_SIZE};
+use redbpf::xdp::MapData;
+
+/// The most bytes of an event sent to a `DecodeErrorSink`
+const MAX_REPORTED: usize = 512;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError {
+    pub event: &'static str,
+    pub expected: usize,
+    pub received: usize,
+    pub misaligned: bool,
+    /// The event holds an unknown enum variant
+    pub invalid: bool,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.misaligned {
+            return write!(f, "misaligned {} event", self.event);
+        }
+        if self.invalid {
+            return write!(f, "invalid {} event", self.event);
+        }
+
+        write!(
+            f,
+            "short {} event: expected {} bytes, received {}",
+            self.event, self.expected, self.received
+        )
+    }
+}
+
+impl DecodeError {
+    fn new<T>(expected: usize, received: usize) -> DecodeError {
+        DecodeError {
+            event: type_name::<T>(),
+            expected,
+            received,
+            misaligned: false,
+            invalid: false,
+        }
+    }
+
+    /// A payload of `received` bytes that should be a `event`, but can't be
+    /// parsed as one
+    pub fn malformed(event: &'static str, received: usize) -> DecodeError {
+        DecodeError {
+            event,
+            expected: 0,
+            received,
+            misaligned: false,
+            invalid: true,
+        }
+    }
+
+    pub fn to_measurement(&self) -> Measurement {
+        let mut tags = Tags::new();
+        tags.insert("event", self.event);
+        tags.insert("expected", self.expected.to_string());
+        tags.insert("received", self.received.to_string());
+
+        Measurement::new(
+            COUNTER,
+            "ingraind.grain.decode_error".to_string(),
+            Unit::Count(1),
+            tags,
+        )
+    }
+}
+
+/// An event that can be decoded from the bytes sent by an eBPF program.
+///
+/// # Safety
+///
+/// Implementors must be `repr(C)` types, or enums with a `repr(u32)` tag,
+/// that are valid for any bytes the eBPF programs send for them that pass
+/// `valid`.
+pub unsafe trait FromRawEvent: Sized {
+    /// The size of the event, including any data following the type, read
+    /// from `raw`, which holds at least the type
+    fn size(_raw: &[u8]) -> usize {
+        size_of::<Self>()
+    }
+
+    /// Whether `raw`, which holds at least the type, is a valid event, such
+    /// as one of a known enum variant
+    fn valid(_raw: &[u8]) -> bool {
+        true
+    }
+
+    /// Copies the event out of `raw`
+    fn from_raw(raw: &[u8]) -> Result<Self, DecodeError> {
+        check::<Self>(raw)?;
+
+        Ok(unsafe { ptr::read_unaligned(raw.as_ptr() as *const Self) })
+    }
+
+    /// Borrows the event from `raw` without copying it
+    fn ref_from_raw(raw: &[u8]) -> Result<&Self, DecodeError> {
+        check::<Self>(raw)?;
+        if raw.as_ptr() as usize % align_of::<Self>() != 0 {
+            return Err(DecodeError {
+                misaligned: true,
+                ..DecodeError::new::<Self>(size_of::<Self>(), raw.len())
+            });
+        }
+
+        Ok(unsafe { &*(raw.as_ptr() as *const Self) })
+    }
+}
+
+/// The kernel timestamp of `Timestamped` events
+unsafe impl FromRawEvent for u64 {}
+
+/// Packets captured by XDP programs follow their event
+unsafe impl<T: FromRawEvent> FromRawEvent for MapData<T> {
+    fn size(raw: &[u8]) -> usize {
+        let (payload_at, _, size) = map_data_payload::<T>(raw);
+        payload_at + size as usize
+    }
+
+    fn valid(raw: &[u8]) -> bool {
+        let (_, offset, size) = map_data_payload::<T>(raw);
+        offset <= size && T::valid(raw)
+    }
+}
+
+/// Where the payload of a `MapData<T>` starts, and its `offset` and `size`
+fn map_data_payload<T>(raw: &[u8]) -> (usize, u32, u32) {
+    // the offsets of the `repr(C)` fields of `MapData<T>`
+    let offset_at = offset_after::<T, u32>();
+    let size_at = offset_at + size_of::<u32>();
+    let payload_at = size_at + size_of::<u32>();
+
+    (payload_at, read_u32(raw, offset_at), read_u32(raw, size_at))
+}
+
+/// Whether the `repr(u32)` tag of the enum at `offset` in `raw` is one of
+/// its `variants`
+pub fn known_variant(raw: &[u8], offset: usize, variants: u32) -> bool {
+    raw.len() >= offset + size_of::<u32>() && read_u32(raw, offset) < variants
+}
+
+/// The offset of a `repr(C)` field of type `F` following the bytes of a `T`
+fn offset_after<T, F>() -> usize {
+    (size_of::<T>() + align_of::<F>() - 1) / align_of::<F>() * align_of::<F>()
+}
+
+fn read_u32(raw: &[u8], offset: usize) -> u32 {
+    unsafe { ptr::read_unaligned(raw[offset..offset + size_of::<u32>()].as_ptr() as *const u32) }
+}
+
+/// Checks that `raw` holds a whole, valid `T`
+fn check<T: FromRawEvent>(raw: &[u8]) -> Result<(), DecodeError> {
+    check_size::<T>(raw)?;
+    if !T::valid(raw) {
+        return Err(DecodeError {
+            invalid: true,
+            ..DecodeError::new::<T>(size_of::<T>(), raw.len())
+        });
+    }
+
+    let size = T::size(raw);
+    if size > raw.len() {
+        return Err(DecodeError::new::<T>(size, raw.len()));
+    }
+
+    Ok(())
+}
+
+fn check_size<T>(raw: &[u8]) -> Result<(), DecodeError> {
+    if raw.len() < size_of::<T>() {
+        return Err(DecodeError::new::<T>(size_of::<T>(), raw.len()));
+    }
+
+    Ok(())
+}
+
+/// Splits a raw `WithPayload<T>` event into the event and its payload
+pub fn split_payload<T: FromRawEvent>(raw: &[u8]) -> Result<(T, &[u8]), DecodeError> {
+    // the offsets of the `repr(C)` fields of `WithPayload<T>`
+    let len_at = offset_after::<T, u32>();
+    let payload_at = len_at + size_of::<u32>();
+    if raw.len() < payload_at {
+        return Err(DecodeError::new::<WithPayload<T>>(payload_at, raw.len()));
+    }
+
+    let event = T::from_raw(&raw[..len_at])?;
+    let len = read_u32(raw, len_at) as usize;
+    if len > PAYLOAD_SIZE || raw.len() < payload_at + len {
+        return Err(DecodeError::new::<WithPayload<T>>(
+            payload_at + len,
+            raw.len(),
+        ));
+    }
+
+    Ok((event, &raw[payload_at..payload_at + len]))
+}
+
+/// Decodes raw events as `T` before handing them to `handler`
+pub fn decoded<T, F>(handler: F) -> EventCallback
+where
+    T: FromRawEvent,
+    F: Fn(T) -> Option<Message> + Send + 'static,
+{
+    Box::new(move |_ctx, raw| T::from_raw(raw).map(&handler))
+}
+
+/// Decodes raw events as `T` before handing them to `handler`, with the CPU
+/// and the kernel time they were seen at
+pub fn decoded_with_context<T, F>(handler: F) -> EventCallback
+where
+    T: FromRawEvent,
+    F: Fn(&EventContext, T) -> Option<Message> + Send + 'static,
+{
+    Box::new(move |ctx, raw| T::from_raw(raw).map(|event| handler(ctx, event)))
+}
+
+/// Borrows raw events as `T` without copying them before handing them to
+/// `handler`
+pub fn decoded_ref<T, F>(handler: F) -> EventCallback
+where
+    T: FromRawEvent,
+    F: Fn(&T) -> Option<Message> + Send + 'static,
+{
+    Box::new(move |_ctx, raw| T::ref_from_raw(raw).map(&handler))
+}
+
+/// Decodes raw events as `T` before handing them to `handler`, which fails
+/// with a `DecodeError` when it can't make sense of them
+pub fn parsed<T, F>(handler: F) -> EventCallback
+where
+    T: FromRawEvent,
+    F: Fn(T) -> Result<Option<Message>, DecodeError> + Send + 'static,
+{
+    Box::new(move |_ctx, raw| T::from_raw(raw).and_then(&handler))
+}
+
+/// Borrows raw events as `T` before handing them to `handler`, which fails
+/// with a `DecodeError` when it can't parse the data they carry
+pub fn parsed_ref<T, F>(handler: F) -> EventCallback
+where
+    T: FromRawEvent,
+    F: Fn(&T) -> Result<Option<Message>, DecodeError> + Send + 'static,
+{
+    Box::new(move |_ctx, raw| T::ref_from_raw(raw).and_then(&handler))
+}
+
+/// Decodes raw `WithPayload<T>` events before handing the event and its
+/// payload to `handler`
+pub fn decoded_with_payload<T, F>(handler: F) -> EventCallback
+where
+    T: FromRawEvent,
+    F: Fn(T, &[u8]) -> Option<Message> + Send + 'static,
+{
+    Box::new(move |_ctx, raw| {
+        split_payload::<T>(raw).map(|(event, payload)| handler(event, payload))
+    })
+}
+
+/// Where a sample of the events that fail to decode is sent, to debug the
+/// probes sending them
+#[derive(Clone)]
+pub struct DecodeErrorSink {
+    pub recipients: Vec<Recipient<Message>>,
+    /// Only one in `sample_every` events that fail to decode is sent
+    pub sample_every: u64,
+}
+
+impl DecodeErrorSink {
+    /// Sends the `count`th event of the grain that failed to decode, with
+    /// its raw bytes, if it's sampled
+    pub fn report(&self, count: u64, grain: &str, map: &str, error: &DecodeError, raw: &[u8]) {
+        if (count - 1) % self.sample_every.max(1) != 0 {
+            return;
+        }
+
+        let payload = hex(&raw[..raw.len().min(MAX_REPORTED)]);
+        let mut measurement = error.to_measurement();
+        measurement.tags.insert("grain", grain);
+        measurement.tags.insert("map", map);
+        measurement.tags.insert("payload", payload);
+        self.recipients.do_send(Message::Single(measurement));
+    }
+}
+
+fn hex(raw: &[u8]) -> String {
+    raw.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Event {
+        pid: u32,
+        len: u32,
+    }
+
+    unsafe impl FromRawEvent for Event {
+        fn size(raw: &[u8]) -> usize {
+            size_of::<Self>() + read_u32(raw, 4) as usize
+        }
+    }
+
+    #[repr(u32)]
+    #[derive(Debug, PartialEq)]
+    enum Tagged {
+        A(u32),
+        B(u32),
+    }
+
+    unsafe impl FromRawEvent for Tagged {
+        fn valid(raw: &[u8]) -> bool {
+            known_variant(raw, 0, 2)
+        }
+    }
+
+    #[test]
+    fn decode() {
+        let mut raw = vec![];
+        raw.extend_from_slice(&1u32.to_ne_bytes());
+        raw.extend_from_slice(&2u32.to_ne_bytes());
+
+        let short = Event::from_raw(&raw[..4]).unwrap_err();
+        assert_eq!((short.expected, short.received), (8, 4));
+        assert_eq!(short.to_measurement().tags.get("expected"), Some("8"));
+
+        let truncated = Event::from_raw(&raw).unwrap_err();
+        assert_eq!((truncated.expected, truncated.received), (10, 8));
+
+        raw.extend_from_slice(&[0, 0]);
+        assert_eq!(Event::from_raw(&raw).unwrap(), Event { pid: 1, len: 2 });
+    }
+
+    #[test]
+    fn variants() {
+        let mut raw = vec![];
+        raw.extend_from_slice(&1u32.to_ne_bytes());
+        raw.extend_from_slice(&7u32.to_ne_bytes());
+        assert_eq!(Tagged::from_raw(&raw).unwrap(), Tagged::B(7));
+
+        raw[..4].copy_from_slice(&2u32.to_ne_bytes());
+        assert!(Tagged::from_raw(&raw).unwrap_err().invalid);
+    }
+
+    #[test]
+    fn payload() {
+        let mut event = WithPayload {
+            event: 7u64,
+            len: 3,
+            payload: [0u8; PAYLOAD_SIZE],
+        };
+        event.payload[..4].copy_from_slice(b"abcd");
+        let raw = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const _ as *const u8,
+                size_of::<WithPayload<u64>>(),
+            )
+        };
+
+        assert_eq!(split_payload::<u64>(raw).unwrap(), (7, &b"abc"[..]));
+        assert_eq!(split_payload::<u64>(&raw[..15]).unwrap(), (7, &b"abc"[..]));
+
+        let truncated = split_payload::<u64>(&raw[..14]).unwrap_err();
+        assert_eq!((truncated.expected, truncated.received), (15, 14));
+
+        event.len = PAYLOAD_SIZE as u32 + 1;
+        let raw = unsafe {
+            std::slice::from_raw_parts(
+                &event as *const _ as *const u8,
+                size_of::<WithPayload<u64>>(),
+            )
+        };
+        assert!(split_payload::<u64>(raw).is_err());
+    }
+
+    #[test]
+    fn context() {
+        let handler = decoded_with_context(|ctx: &EventContext, pid: u64| {
+            let mut tags = Tags::new();
+            tags.insert("cpu", ctx.cpu.unwrap());
+            tags.insert("process_id", pid);
+            Some(Message::Single(Measurement::new(
+                COUNTER,
+                "event".to_string(),
+                Unit::Count(ctx.ktime.unwrap()),
+                tags,
+            )))
+        });
+        let ctx = EventContext {
+            cpu: Some(3),
+            ktime: Some(1000),
+            ..Default::default()
+        };
+
+        match handler(&ctx, &42u64.to_ne_bytes()) {
+            Ok(Some(Message::Single(m))) => {
+                assert_eq!(m.tags.get("cpu"), Some("3"));
+                assert_eq!(m.tags.get("process_id"), Some("42"));
+                assert_eq!(m.value, Unit::Count(1000));
+            }
+            _ => panic!("no measurement"),
+        }
+
+        let short = handler(&ctx, &[1, 2]).unwrap_err();
+        assert_eq!((short.expected, short.received), (8, 2));
+    }
+}
@@ -2,24 +2,134 @@
 
 use std::ffi::CStr;
 use std::fs::metadata;
+use std::mem;
 use std::os::raw::c_char;
 use std::os::unix::fs::MetadataExt;
+use std::time::Duration;
 
+use failure::Error;
 use redbpf::{HashMap, Module};
+use serde_json::Value;
 
+use crate::grains::disk_usage::{DiskUsage, DiskUsageConfig, Written};
+use crate::grains::filter::FiltersConfig;
+use crate::grains::probes::file::maps;
 use crate::grains::*;
 
-use ingraind_probes::file::{Access, FileAccess as RawFileAccess};
+use ingraind_probes::file::{
+    Access, FileAccess as RawFileAccess, FileVolume, FileVolumeKey, PathList, AGGREGATE,
+};
+
+unsafe impl FromRawEvent for RawFileAccess {
+    fn valid(raw: &[u8]) -> bool {
+        // `access` follows the `u32` tid, aligned for its `usize`
+        known_variant(raw, mem::align_of::<Access>(), 2)
+    }
+}
 
 type ino_t = u64;
 
 //const ACTION_IGNORE: u8 = 0;
 const ACTION_RECORD: u8 = 1;
 
-pub struct Files(pub FilesConfig);
+pub struct Files {
+    config: FilesConfig,
+    disk_usage: Option<DiskUsage>,
+    /// How many times counters were scraped
+    scrapes: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FilesConfig {
     pub monitor_dirs: Vec<String>,
+    /// Aggregate volumes per process and file in the kernel, and scrape
+    /// them this often instead of sending every read and write
+    #[serde(default)]
+    pub aggregate_interval_ms: Option<u64>,
+    /// Filters on the pid and uid of processes, applied in the kernel
+    #[serde(default)]
+    pub filters: Option<FiltersConfig>,
+    /// Scans `monitor_dirs` for their size, inodes and changed files
+    #[serde(default)]
+    pub disk_usage: Option<DiskUsageConfig>,
+}
+
+impl FilesConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if let Some(ref filters) = self.filters {
+            filters.check()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Files {
+    pub fn new(config: FilesConfig) -> Self {
+        let disk_usage = config
+            .disk_usage
+            .clone()
+            .map(|disk_usage| DiskUsage::new(disk_usage, &config.monitor_dirs));
+
+        Files {
+            config,
+            disk_usage,
+            scrapes: 0,
+        }
+    }
+
+    /// Whether something done every `interval` is due at this scrape
+    fn due(&self, interval: Duration) -> bool {
+        let scrape_interval = self.scrape_interval().unwrap();
+        let every = (interval.as_millis() / scrape_interval.as_millis()).max(1) as u64;
+        self.scrapes % every == 0
+    }
+
+    fn written(&self) -> Option<Written> {
+        self.disk_usage.as_ref().map(DiskUsage::written)
+    }
+
+    /// Reads and resets the volumes aggregated in the kernel
+    fn scrape_volumes(&self, module: &Module) -> Vec<Measurement> {
+        let written = self.written();
+
+        // paths are recorded before the volumes they belong to, so draining
+        // the volumes first leaves no volume without its path
+        let volumes = drain_map::<FileVolumeKey, FileVolume>(module, maps::FILE_VOLUMES);
+        let paths = drain_map::<u64, PathList>(module, maps::FILE_PATHS)
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>();
+
+        let mut measurements = vec![];
+        for (key, volume) in volumes {
+            let path = paths.get(&key.inode).map(path_of).unwrap_or_default();
+            if let Some(ref written) = written {
+                written.record(&path, volume.write);
+            }
+            let tags = FileAccess {
+                id: key.pid,
+                process: to_string(&volume.comm),
+                path,
+                ino: key.inode,
+                read: volume.read as usize,
+                write: volume.write as usize,
+            }
+            .to_tags();
+
+            for (name, bytes) in [("file.read", volume.read), ("file.write", volume.write)].iter() {
+                if *bytes > 0 {
+                    measurements.push(Measurement::new(
+                        COUNTER,
+                        name.to_string(),
+                        Unit::Byte(*bytes),
+                        tags.clone(),
+                    ));
+                }
+            }
+        }
+
+        measurements
+    }
 }
 
 #[derive(Debug)]
@@ -40,26 +150,78 @@ impl EBPFProbe for Grain<Files> {
 
 impl EBPFGrain<'static> for Files {
     fn code() -> &'static [u8] {
-        include_bytes!(concat!(
-            env!("OUT_DIR"),
-            "/target/bpf/programs/file/file.elf"
-        ))
+        probes::file::CODE
     }
 
-    fn loaded(&mut self, module: &mut Module) {
-        let actionlist = HashMap::<u64, u8>::new(find_map_by_name(module, "actionlist")).unwrap();
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        let actionlist =
+            HashMap::<u64, u8>::new(find_map_by_name(module, maps::ACTIONLIST)).unwrap();
 
         let record = ACTION_RECORD;
-        for dir in self.0.monitor_dirs.iter() {
-            let ino = metadata(dir).unwrap().ino();
+        for dir in self.config.monitor_dirs.iter() {
+            let ino = metadata(dir)?.ino();
             actionlist.set(ino, record);
         }
+
+        if let Some(ref filters) = self.config.filters {
+            filters.load(module)?;
+        }
+
+        if self.config.aggregate_interval_ms.is_some() {
+            let settings =
+                HashMap::<u8, u64>::new(find_map_by_name(module, maps::SETTINGS)).unwrap();
+            settings.set(AGGREGATE, 1);
+        }
+
+        Ok(())
+    }
+
+    fn dump_map(&self, module: &Module, name: &str) -> Option<Value> {
+        match name {
+            maps::ACTIONLIST => dump_hash_map::<u64, u8>(module, name),
+            maps::FILE_VOLUMES => dump_hash_map::<FileVolumeKey, FileVolume>(module, name),
+            _ => dump_common_map(module, name),
+        }
+    }
+
+    fn scrape_interval(&self) -> Option<Duration> {
+        let aggregate = self.config.aggregate_interval_ms.map(Duration::from_millis);
+        let disk_usage = self.disk_usage.as_ref().map(DiskUsage::interval);
+
+        match (aggregate, disk_usage) {
+            (Some(aggregate), Some(disk_usage)) => Some(aggregate.min(disk_usage)),
+            (aggregate, disk_usage) => aggregate.or(disk_usage),
+        }
+    }
+
+    fn scrape(&mut self, module: &Module) -> Vec<Measurement> {
+        let mut measurements = vec![];
+        if let Some(interval) = self.config.aggregate_interval_ms {
+            if self.due(Duration::from_millis(interval)) {
+                measurements.extend(self.scrape_volumes(module));
+            }
+        }
+
+        let due = self
+            .disk_usage
+            .as_ref()
+            .map(|disk_usage| self.due(disk_usage.interval()))
+            .unwrap_or(false);
+        if let Some(ref mut disk_usage) = self.disk_usage {
+            measurements.extend(disk_usage.poll(due));
+        }
+
+        self.scrapes += 1;
+        measurements
     }
 
     fn get_handler(&self, _id: &str) -> EventCallback {
-        Box::new(move |raw| {
-            let raw_access = unsafe { std::ptr::read(raw.as_ptr() as *const RawFileAccess) };
+        let written = self.written();
+        decoded(move |raw_access: RawFileAccess| {
             let file = FileAccess::from(raw_access);
+            if let Some(ref written) = written {
+                written.record(&file.path, file.write as u64);
+            }
             let name = format!("file.{}", if file.write > 0 { "write" } else { "read" });
             let vol = if file.write > 0 {
                 file.write
@@ -79,19 +241,7 @@ impl EBPFGrain<'static> for Files {
 
 impl From<RawFileAccess> for FileAccess {
     fn from(raw: RawFileAccess) -> FileAccess {
-        let segments = raw.paths.0.to_vec();
-        let path = segments
-            .iter()
-            .rev()
-            .map(|s| unsafe {
-                CStr::from_ptr(s.name.as_ptr() as *const c_char)
-                    .to_string_lossy()
-                    .into_owned()
-            })
-            .collect::<Vec<String>>()
-            .join("/")
-            .trim_start_matches('/')
-            .to_string();
+        let path = path_of(&raw.paths);
 
         let (read, write) = match raw.access {
             Access::Read(s) => (s, 0),
@@ -109,11 +259,27 @@ impl From<RawFileAccess> for FileAccess {
     }
 }
 
+fn path_of(paths: &PathList) -> String {
+    paths
+        .0
+        .iter()
+        .rev()
+        .map(|s| unsafe {
+            CStr::from_ptr(s.name.as_ptr() as *const c_char)
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+        .trim_start_matches('/')
+        .to_string()
+}
+
 impl ToTags for FileAccess {
     fn to_tags(self) -> Tags {
         let mut tags = Tags::new();
 
-        tags.insert("process_id", self.id.to_string());
+        tags.insert("process_id", self.id);
         tags.insert("process_str", self.process);
         tags.insert("path_str", self.path);
         tags.insert("ino_id", self.ino.to_string());
@@ -0,0 +1,186 @@
+//! Filters that probes apply in the kernel, before events cross the perf
+//! buffer.
+//!
+//! Grains that support them take a `filters` section in their config, and
+//! load it into the probe's `filters` and `filter_pids` maps.
+
+use std::str::FromStr;
+
+use failure::{format_err, Error};
+use redbpf::{HashMap, Module};
+
+use crate::grains::egress::{Action, Cidr};
+use crate::grains::find_map_by_name;
+use crate::grains::probes::common::maps;
+
+use ingraind_probes::filter::{
+    Cidr as RawCidr, Filters, Range, FILTERS, FILTER_ALLOW, FILTER_DENY, FILTER_NONE, MAX_CIDRS,
+    MAX_RANGES,
+};
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct FiltersConfig {
+    pub pids: Option<FilterConfig<u32>>,
+    /// Single uids, or ranges like `"1000-1999"`
+    pub uids: Option<FilterConfig<String>>,
+    /// Single ports, or ranges like `"8000-8999"`
+    pub ports: Option<FilterConfig<String>>,
+    /// Networks, like `"10.0.0.0/8"`
+    pub addresses: Option<FilterConfig<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FilterConfig<T> {
+    pub action: Action,
+    pub values: Vec<T>,
+}
+
+impl FiltersConfig {
+    /// Fails if the filters can't be loaded into a probe
+    pub fn check(&self) -> Result<(), Error> {
+        self.to_filters().map(|_| ())
+    }
+
+    /// Loads the filters into the maps of `module`
+    pub fn load(&self, module: &Module) -> Result<(), Error> {
+        let filters = self.to_filters()?;
+        HashMap::<u8, Filters>::new(find_map_by_name(module, maps::FILTERS))?.set(FILTERS, filters);
+
+        if let Some(ref pids) = self.pids {
+            let map = HashMap::<u32, u8>::new(find_map_by_name(module, maps::FILTER_PIDS))?;
+            for pid in pids.values.iter() {
+                map.set(*pid, 1);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn to_filters(&self) -> Result<Filters, Error> {
+        let mut filters = Filters {
+            pids: action(&self.pids),
+            uids: action(&self.uids),
+            ports: action(&self.ports),
+            addrs: action(&self.addresses),
+            ..Default::default()
+        };
+
+        if let Some(ref uids) = self.uids {
+            filters.uid_ranges_len = ranges(&uids.values, &mut filters.uid_ranges)?;
+        }
+        if let Some(ref ports) = self.ports {
+            filters.port_ranges_len = ranges(&ports.values, &mut filters.port_ranges)?;
+        }
+        if let Some(ref addresses) = self.addresses {
+            if addresses.values.len() > MAX_CIDRS {
+                return Err(format_err!(
+                    "at most {} addresses can be filtered",
+                    MAX_CIDRS
+                ));
+            }
+            for (i, value) in addresses.values.iter().enumerate() {
+                let cidr = Cidr::from_str(value)?;
+                filters.cidrs[i] = RawCidr {
+                    addr: words(cidr.addr & cidr.mask()),
+                    mask: words(cidr.mask()),
+                };
+            }
+            filters.cidrs_len = addresses.values.len() as u32;
+        }
+
+        Ok(filters)
+    }
+}
+
+fn action<T>(filter: &Option<FilterConfig<T>>) -> u8 {
+    match filter {
+        Some(FilterConfig {
+            action: Action::Allow,
+            ..
+        }) => FILTER_ALLOW,
+        Some(FilterConfig {
+            action: Action::Deny,
+            ..
+        }) => FILTER_DENY,
+        None => FILTER_NONE,
+    }
+}
+
+fn ranges(values: &[String], ranges: &mut [Range; MAX_RANGES]) -> Result<u32, Error> {
+    if values.len() > MAX_RANGES {
+        return Err(format_err!("at most {} ranges can be filtered", MAX_RANGES));
+    }
+
+    for (i, value) in values.iter().enumerate() {
+        let mut parts = value.splitn(2, '-');
+        let start = u32::from_str(parts.next().unwrap().trim())?;
+        let end = match parts.next() {
+            Some(end) => u32::from_str(end.trim())?,
+            None => start,
+        };
+        if end < start {
+            return Err(format_err!("invalid range {}", value));
+        }
+
+        ranges[i] = Range { start, end };
+    }
+
+    Ok(values.len() as u32)
+}
+
+/// The address as 32 bit words in network byte order, like `in6_addr`
+pub(crate) fn words(addr: u128) -> [u32; 4] {
+    let bytes = addr.to_be_bytes();
+    let mut words = [0u32; 4];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = u32::from_ne_bytes([
+            bytes[i * 4],
+            bytes[i * 4 + 1],
+            bytes[i * 4 + 2],
+            bytes[i * 4 + 3],
+        ]);
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_filters() {
+        let config: FiltersConfig = toml::from_str(
+            r#"
+uids = { action = "deny", values = ["0", "1000-1999"] }
+addresses = { action = "allow", values = ["10.0.0.0/8"] }
+"#,
+        )
+        .unwrap();
+        let filters = config.to_filters().unwrap();
+
+        assert_eq!(filters.pids, FILTER_NONE);
+        assert_eq!(filters.uids, FILTER_DENY);
+        assert_eq!(filters.uid_ranges_len, 2);
+        assert_eq!(
+            (filters.uid_ranges[1].start, filters.uid_ranges[1].end),
+            (1000, 1999)
+        );
+
+        assert_eq!(filters.addrs, FILTER_ALLOW);
+        assert_eq!(filters.cidrs_len, 1);
+        let cidr = filters.cidrs[0];
+        assert_eq!(cidr.mask[..3], [!0, !0, !0]);
+        assert_eq!(cidr.addr[2], u32::from_ne_bytes([0, 0, 0xff, 0xff]));
+        assert_eq!(cidr.addr[3], u32::from_ne_bytes([10, 0, 0, 0]));
+        assert_eq!(cidr.mask[3], u32::from_ne_bytes([0xff, 0, 0, 0]));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let mut ranges = [Range::default(); MAX_RANGES];
+        assert!(super::ranges(&["2000-1000".to_string()], &mut ranges).is_err());
+        assert!(super::ranges(&["x".to_string()], &mut ranges).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use failure::{bail, Error};
+use redbpf::{HashMap as BPFHashMap, Module};
+
+use crate::grains::probes::generic::maps;
+use crate::grains::*;
+
+use ingraind_probes::generic::{Call, MAX_ARGS, STRING_ARGS};
+
+unsafe impl FromRawEvent for Call {}
+
+pub struct Generic(pub GenericConfig);
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GenericConfig {
+    function: String,
+    metric: String,
+    #[serde(default)]
+    args: Vec<GenericArg>,
+    /// Put calls in order with the probe's `reorder_ms`
+    #[serde(default)]
+    ordered: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenericArg {
+    /// The position of the argument, starting from 1
+    index: usize,
+    name: String,
+    #[serde(rename = "type", default)]
+    typ: ArgType,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgType {
+    Int,
+    String,
+}
+
+impl Default for ArgType {
+    fn default() -> ArgType {
+        ArgType::Int
+    }
+}
+
+impl GenericConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if let Some(arg) = self.args.iter().find(|arg| !valid_index(arg)) {
+            bail!(
+                "{}: argument index {} is out of range, the first {} arguments can be read",
+                self.metric,
+                arg.index,
+                MAX_ARGS
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl EBPFProbe for Grain<Generic> {
+    fn attach(&mut self) -> MessageStreams {
+        let function = self.native.0.function.clone();
+        self.attach_kprobes_to_names(function)
+    }
+}
+
+impl EBPFGrain<'static> for Generic {
+    fn code() -> &'static [u8] {
+        probes::generic::CODE
+    }
+
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        let string_args = self
+            .0
+            .args
+            .iter()
+            .filter(|arg| arg.typ == ArgType::String && valid_index(arg))
+            .fold(0, |mask, arg| mask | 1 << (arg.index - 1));
+
+        let settings =
+            BPFHashMap::<u8, u64>::new(find_map_by_name(module, maps::SETTINGS)).unwrap();
+        settings.set(STRING_ARGS, string_args);
+
+        Ok(())
+    }
+
+    // the probe may be replaced by any prebuilt one, so the first bytes of
+    // its events are only taken for a timestamp to order by when asked to
+    fn ordered(&self, id: &str) -> bool {
+        self.0.ordered && self.kernel_timestamps(id)
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        let metric = self.0.metric.clone();
+        let args = self
+            .0
+            .args
+            .iter()
+            .filter(|arg| valid_index(arg))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        decoded(move |call: Call| {
+            let mut tags = Tags::new();
+            tags.insert("process_id", call.pid);
+            tags.insert("process_str", to_string(&call.comm));
+
+            for arg in args.iter() {
+                let i = arg.index - 1;
+                let value = match arg.typ {
+                    ArgType::Int => call.args[i].to_string(),
+                    ArgType::String => to_string(&call.strings[i]),
+                };
+                tags.insert(arg.name.as_str(), value);
+            }
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | METER,
+                metric.clone(),
+                Unit::Count(1),
+                tags,
+            )))
+        })
+    }
+}
+
+fn valid_index(arg: &GenericArg) -> bool {
+    arg.index >= 1 && arg.index <= MAX_ARGS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_arg_indices() {
+        let config = |index| GenericConfig {
+            function: "do_sys_open".to_string(),
+            metric: "file.open".to_string(),
+            args: vec![GenericArg {
+                index,
+                name: "flags".to_string(),
+                typ: ArgType::Int,
+            }],
+        };
+
+        assert!(config(1).check().is_ok());
+        assert!(config(MAX_ARGS).check().is_ok());
+        assert!(config(0).check().is_err());
+        assert!(config(MAX_ARGS + 1).check().is_err());
+    }
+}
@@ -0,0 +1,36 @@
+//! The smallest eBPF grain there is, for the end-to-end tests in `tests/`.
+//! It reports every process that goes to sleep as `harness.sleep`.
+use crate::grains::*;
+
+use ingraind_probes::harness::Sleep;
+
+unsafe impl FromRawEvent for Sleep {}
+
+pub struct Harness;
+
+impl EBPFProbe for Grain<Harness> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for Harness {
+    fn code() -> &'static [u8] {
+        probes::harness::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|sleep: Sleep| {
+            let mut tags = Tags::new();
+            tags.insert("process_id", sleep.pid);
+            tags.insert("process_str", to_string(&sleep.comm));
+
+            Some(Message::Single(Measurement::new(
+                COUNTER,
+                "harness.sleep".to_string(),
+                Unit::Count(1),
+                tags,
+            )))
+        })
+    }
+}
@@ -0,0 +1,87 @@
+use crate::grains::protocol::ip::to_ipv4;
+use crate::grains::*;
+
+use ingraind_probes::icmp::{Icmp, IcmpEvent};
+
+unsafe impl FromRawEvent for IcmpEvent {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+
+pub struct ICMP;
+
+impl EBPFProbe for Grain<ICMP> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for ICMP {
+    fn code() -> &'static [u8] {
+        probes::icmp::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: IcmpEvent| {
+            let (name, icmp) = match event {
+                IcmpEvent::Send(icmp) => ("icmp.out", icmp),
+                IcmpEvent::Receive(icmp) => ("icmp.in", icmp),
+            };
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | METER,
+                name.to_string(),
+                Unit::Count(1),
+                icmp_tags(&icmp),
+            )))
+        })
+    }
+}
+
+fn icmp_tags(icmp: &Icmp) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("d_ip", to_ipv4(icmp.daddr));
+    tags.insert("s_ip", to_ipv4(icmp.saddr));
+    tags.insert("icmp_type", icmp.typ);
+    tags.insert("icmp_type_str", type_name(icmp.typ));
+    tags.insert("icmp_code", icmp.code);
+
+    tags
+}
+
+fn type_name(typ: u8) -> &'static str {
+    match typ {
+        0 => "echo_reply",
+        3 => "dest_unreachable",
+        4 => "source_quench",
+        5 => "redirect",
+        8 => "echo_request",
+        11 => "time_exceeded",
+        12 => "parameter_problem",
+        13 => "timestamp",
+        14 => "timestamp_reply",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icmp_tags() {
+        let icmp = Icmp {
+            saddr: 0x0100_000a,
+            daddr: 0x0101_a8c0,
+            typ: 3,
+            code: 1,
+        };
+        let tags = icmp_tags(&icmp);
+
+        assert_eq!(tags.get("s_ip"), Some("10.0.0.1"));
+        assert_eq!(tags.get("d_ip"), Some("192.168.1.1"));
+        assert_eq!(tags.get("icmp_type_str"), Some("dest_unreachable"));
+        assert_eq!(tags.get("icmp_code"), Some("1"));
+    }
+}
@@ -0,0 +1,104 @@
+use failure::Error;
+use redbpf::{HashMap as BPFHashMap, Module};
+
+use crate::grains::probes::kernel_load::maps;
+use crate::grains::*;
+
+use ingraind_probes::kernel_load::{KernelLoad as RawKernelLoad, Process};
+
+unsafe impl FromRawEvent for RawKernelLoad {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+
+const BPF_PROG_TYPES: [&str; 27] = [
+    "unspec",
+    "socket_filter",
+    "kprobe",
+    "sched_cls",
+    "sched_act",
+    "tracepoint",
+    "xdp",
+    "perf_event",
+    "cgroup_skb",
+    "cgroup_sock",
+    "lwt_in",
+    "lwt_out",
+    "lwt_xmit",
+    "sock_ops",
+    "sk_skb",
+    "cgroup_device",
+    "sk_msg",
+    "raw_tracepoint",
+    "cgroup_sock_addr",
+    "lwt_seg6local",
+    "lirc_mode2",
+    "sk_reuseport",
+    "flow_dissector",
+    "cgroup_sysctl",
+    "raw_tracepoint_writable",
+    "cgroup_sockopt",
+    "tracing",
+];
+
+pub struct KernelLoad;
+
+impl EBPFProbe for Grain<KernelLoad> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for KernelLoad {
+    fn code() -> &'static [u8] {
+        probes::kernel_load::CODE
+    }
+
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        let map = BPFHashMap::<u8, u64>::new(find_map_by_name(module, maps::HOST_PID)).unwrap();
+        map.set(1u8, std::process::id() as u64);
+
+        Ok(())
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: RawKernelLoad| {
+            let (name, tags) = match event {
+                RawKernelLoad::Module(process, module) => {
+                    let mut tags = process_tags(&process);
+                    tags.insert("module_str", to_string(&module));
+                    ("kernel.module_load", tags)
+                }
+                RawKernelLoad::Bpf(process, prog_type, prog_name) => {
+                    let mut tags = process_tags(&process);
+                    tags.insert("bpf_prog_type", prog_type_name(prog_type));
+                    tags.insert("bpf_prog_str", to_string(&prog_name));
+                    ("kernel.bpf_load", tags)
+                }
+            };
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | METER,
+                name.to_string(),
+                Unit::Count(1),
+                tags,
+            )))
+        })
+    }
+}
+
+fn process_tags(process: &Process) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("process_id", process.pid);
+    tags.insert("process_str", to_string(&process.comm));
+
+    tags
+}
+
+fn prog_type_name(prog_type: u32) -> String {
+    match BPF_PROG_TYPES.get(prog_type as usize) {
+        Some(name) => name.to_string(),
+        None => prog_type.to_string(),
+    }
+}
@@ -0,0 +1,336 @@
+//! Caps on the resources of a grain, protecting the host from misconfigured
+//! probes.
+//!
+//! The maps a grain declares may take at most `max_map_bytes`, as the
+//! kernel charges them: all their entries with the bookkeeping of every one,
+//! rounded up to pages, along with the perf buffers of perf maps on every
+//! online CPU, or the grain isn't loaded. At most `max_events_per_s` of its events
+//! are admitted into the pipelines every second, and beyond that, depending
+//! on `on_breach`:
+//!
+//!  * `Sample`: only one in every n events is admitted, n growing with the
+//!    rate, and its measurements are marked as sampled
+//!  * `Pause`: the events of the grain are dropped for `pause_s`
+//!  * `Alert`: every event is still admitted, and the grain's pipelines are
+//!    sent `ingraind.grain.limit_exceeded` every second over the cap, with
+//!    the events of that second
+//!
+//! Breaches are logged whatever the action.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use failure::{bail, Error};
+
+use crate::metrics::clock::monotonic_now;
+use crate::metrics::kind::GAUGE;
+use crate::metrics::{Measurement, Tags, Unit};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+const BPF_MAP_TYPE_HASH: u64 = 1;
+const BPF_MAP_TYPE_ARRAY: u64 = 2;
+const BPF_MAP_TYPE_PERF_EVENT_ARRAY: u64 = 4;
+const BPF_MAP_TYPE_PERCPU_HASH: u64 = 5;
+const BPF_MAP_TYPE_PERCPU_ARRAY: u64 = 6;
+const BPF_MAP_TYPE_LRU_HASH: u64 = 9;
+const BPF_MAP_TYPE_LRU_PERCPU_HASH: u64 = 10;
+
+/// The pages of the perf buffer read on every CPU for a perf map, after
+/// its metadata page
+pub const PERF_BUFFER_PAGES: usize = 16;
+
+/// `struct bpf_map` and the rest of the header of every map, about
+const MAP_HEADER_BYTES: u64 = 512;
+/// `struct htab_elem`, ahead of the key and value of every hash map entry
+const HASH_ELEM_BYTES: u64 = 48;
+/// `struct bucket`, of which hash maps have a power of two above their
+/// entries
+const HASH_BUCKET_BYTES: u64 = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LimitsConfig {
+    pub max_map_bytes: Option<u64>,
+    pub max_events_per_s: Option<u64>,
+    #[serde(default = "default_on_breach")]
+    pub on_breach: Breach,
+    #[serde(default = "default_pause_s")]
+    pub pause_s: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Breach {
+    Sample,
+    Pause,
+    Alert,
+}
+
+fn default_on_breach() -> Breach {
+    Breach::Sample
+}
+
+fn default_pause_s() -> u64 {
+    60
+}
+
+impl LimitsConfig {
+    /// Fails if the maps declared in `code` take more than `max_map_bytes`
+    pub fn check_maps(&self, code: &[u8], cpus: usize) -> Result<(), Error> {
+        let max = match self.max_map_bytes {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+
+        let bytes = map_bytes(code, cpus)?;
+        let total = bytes.iter().map(|(_, b)| b).sum::<u64>();
+        if total > max {
+            let mut maps = bytes
+                .iter()
+                .map(|(name, b)| format!("{} {}", name, b))
+                .collect::<Vec<_>>();
+            maps.sort();
+            bail!(
+                "maps take {} bytes, over max_map_bytes {}: {}",
+                total,
+                max,
+                maps.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        let max = self.max_events_per_s?;
+        Some(RateLimiter {
+            max: max.max(1),
+            on_breach: self.on_breach,
+            pause: self.pause_s.saturating_mul(NANOS_PER_SEC),
+            second: AtomicU64::new(0),
+            events: AtomicU64::new(0),
+            paused_until: AtomicU64::new(0),
+            exceeded: AtomicU64::new(0),
+        })
+    }
+}
+
+/// The bytes every map declared in `code` takes, by name
+pub fn map_bytes(code: &[u8], cpus: usize) -> Result<Vec<(String, u64)>, Error> {
+    let elf = goblin::elf::Elf::parse(code)?;
+    let mut maps = vec![];
+    for sh in elf.section_headers.iter() {
+        let name = match elf.shdr_strtab.get(sh.sh_name).and_then(|name| name.ok()) {
+            Some(name) if name.starts_with("maps/") => name,
+            _ => continue,
+        };
+        let start = sh.sh_offset as usize;
+        let def = match code.get(start..start + sh.sh_size as usize) {
+            Some(def) if def.len() >= 16 => def,
+            _ => bail!("invalid definition of map {}", name),
+        };
+
+        maps.push((
+            name["maps/".len()..].to_string(),
+            def_bytes(def, cpus, page_size()),
+        ));
+    }
+
+    Ok(maps)
+}
+
+/// The bytes of locked memory the kernel charges for a `bpf_map_def`, with
+/// pages of `page_size`
+fn def_bytes(def: &[u8], cpus: usize, page_size: u64) -> u64 {
+    let field = |i: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&def[i * 4..i * 4 + 4]);
+        u64::from(u32::from_ne_bytes(bytes))
+    };
+    let (kind, key_size, value_size, max_entries) = (field(0), field(1), field(2), field(3));
+    let cpus = cpus as u64;
+    // keys and values are 8-byte aligned, and per-CPU values are allocated
+    // separately, behind a pointer
+    let (key, value) = (round_up(key_size, 8), round_up(value_size, 8));
+    let buckets = max_entries.next_power_of_two() * HASH_BUCKET_BYTES;
+
+    let entries = match kind {
+        BPF_MAP_TYPE_HASH | BPF_MAP_TYPE_LRU_HASH => {
+            buckets + max_entries * (HASH_ELEM_BYTES + key + value)
+        }
+        BPF_MAP_TYPE_PERCPU_HASH | BPF_MAP_TYPE_LRU_PERCPU_HASH => {
+            buckets + max_entries * (HASH_ELEM_BYTES + key + 8 + value * cpus)
+        }
+        BPF_MAP_TYPE_ARRAY => max_entries * value,
+        BPF_MAP_TYPE_PERCPU_ARRAY => max_entries * (8 + value * cpus),
+        // pointers to the perf events
+        BPF_MAP_TYPE_PERF_EVENT_ARRAY => max_entries * 8,
+        _ => max_entries * (key + value),
+    };
+    let map = round_up(MAP_HEADER_BYTES + entries, page_size);
+
+    // the perf buffers are mapped by ingraind, and charged to it too
+    if kind == BPF_MAP_TYPE_PERF_EVENT_ARRAY {
+        return map + cpus * (PERF_BUFFER_PAGES as u64 + 1) * page_size;
+    }
+    map
+}
+
+fn round_up(n: u64, to: u64) -> u64 {
+    (n + to - 1) / to * to
+}
+
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+/// Admits at most `max_events_per_s` events of a grain every second. Shared
+/// by the streams of all its maps and CPUs.
+pub struct RateLimiter {
+    max: u64,
+    on_breach: Breach,
+    pause: u64,
+    /// The second events are counted in, on the monotonic clock
+    second: AtomicU64,
+    events: AtomicU64,
+    paused_until: AtomicU64,
+    /// The most events of a second over the cap since the last report
+    exceeded: AtomicU64,
+}
+
+impl RateLimiter {
+    /// The rate the next event is sampled at, or `None` if it's dropped
+    pub fn admit(&self) -> Option<f64> {
+        self.admit_at(monotonic_now())
+    }
+
+    fn admit_at(&self, now: u64) -> Option<f64> {
+        if now < self.paused_until.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let second = now / NANOS_PER_SEC;
+        if self.second.swap(second, Ordering::Relaxed) != second {
+            self.events.store(0, Ordering::Relaxed);
+        }
+        let events = self.events.fetch_add(1, Ordering::Relaxed) + 1;
+        if events <= self.max {
+            return Some(1.0);
+        }
+        self.exceeded.fetch_max(events, Ordering::Relaxed);
+
+        match self.on_breach {
+            Breach::Sample => {
+                let every = (events - 1) / self.max + 1;
+                if events % every == 0 {
+                    Some(1.0 / every as f64)
+                } else {
+                    None
+                }
+            }
+            Breach::Pause => {
+                self.paused_until
+                    .store(now.saturating_add(self.pause), Ordering::Relaxed);
+                None
+            }
+            Breach::Alert => Some(1.0),
+        }
+    }
+
+    pub fn on_breach(&self) -> Breach {
+        self.on_breach
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The most events of a second over the cap since the last call, if any
+    pub fn take_exceeded(&self) -> Option<u64> {
+        match self.exceeded.swap(0, Ordering::Relaxed) {
+            0 => None,
+            events => Some(events),
+        }
+    }
+
+    pub fn to_measurement(&self, grain: &str, events: u64) -> Measurement {
+        let mut tags = Tags::new();
+        tags.insert("grain", grain);
+        tags.insert("limit", self.max.to_string());
+
+        Measurement::new(
+            GAUGE,
+            "ingraind.grain.limit_exceeded".to_string(),
+            Unit::Count(events),
+            tags,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(on_breach: &str) -> RateLimiter {
+        let config: LimitsConfig = toml::from_str(&format!(
+            "max_events_per_s = 2\non_breach = \"{}\"\npause_s = 5",
+            on_breach
+        ))
+        .unwrap();
+        config.rate_limiter().unwrap()
+    }
+
+    #[test]
+    fn limits_events() {
+        let second = NANOS_PER_SEC;
+
+        let sample = limiter("Sample");
+        let admitted = (0..8).map(|_| sample.admit_at(second)).collect::<Vec<_>>();
+        assert_eq!(
+            admitted,
+            vec![
+                Some(1.0),
+                Some(1.0),
+                None,
+                Some(0.5),
+                None,
+                Some(1.0 / 3.0),
+                None,
+                Some(0.25)
+            ]
+        );
+        assert_eq!(sample.take_exceeded(), Some(8));
+        assert_eq!(sample.take_exceeded(), None);
+        assert_eq!(sample.admit_at(2 * second), Some(1.0));
+
+        let pause = limiter("Pause");
+        assert_eq!(pause.admit_at(second), Some(1.0));
+        assert_eq!(pause.admit_at(second), Some(1.0));
+        assert_eq!(pause.admit_at(second), None);
+        assert_eq!(pause.admit_at(5 * second), None);
+        assert_eq!(pause.admit_at(6 * second), Some(1.0));
+
+        let alert = limiter("Alert");
+        assert!((0..4).all(|_| alert.admit_at(second) == Some(1.0)));
+        assert_eq!(alert.take_exceeded(), Some(4));
+    }
+
+    #[test]
+    fn map_sizes() {
+        let def = |fields: [u32; 5]| {
+            fields
+                .iter()
+                .flat_map(|f| f.to_ne_bytes().to_vec())
+                .collect::<Vec<_>>()
+        };
+        let page = 4096;
+        // a hash of u32 to u64, a per-CPU array of u64, and a perf map
+        assert_eq!(
+            def_bytes(&def([1, 4, 8, 1000, 0]), 4, page),
+            round_up(512 + 1024 * 16 + 1000 * (48 + 8 + 8), page)
+        );
+        assert_eq!(def_bytes(&def([6, 4, 8, 16, 0]), 4, page), page);
+        assert_eq!(
+            def_bytes(&def([4, 4, 4, 1024, 0]), 4, page),
+            3 * page + 4 * 17 * page
+        );
+    }
+}
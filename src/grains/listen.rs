@@ -0,0 +1,60 @@
+use crate::grains::network::{ip_addr, ip_version, netns_tags, proto_name};
+use crate::grains::*;
+
+use ingraind_probes::listen::{ListenEvent, Listener};
+
+unsafe impl FromRawEvent for ListenEvent {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+
+pub struct Listen;
+
+impl EBPFProbe for Grain<Listen> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for Listen {
+    fn code() -> &'static [u8] {
+        probes::listen::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: ListenEvent| {
+            let (name, listener) = match event {
+                ListenEvent::Bind(listener) => ("socket.bind", listener),
+                ListenEvent::Listen(listener) => ("socket.listen", listener),
+            };
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | METER,
+                name.to_string(),
+                Unit::Count(1),
+                listener_tags(&listener),
+            )))
+        })
+    }
+}
+
+fn listener_tags(listener: &Listener) -> Tags {
+    let conn = &listener.conn;
+    let mut tags = Tags::new();
+
+    tags.insert("process_str", to_string(&conn.comm));
+    tags.insert("process_id", conn.pid);
+    tags.insert("uid", listener.uid);
+    tags.insert("address", ip_addr(&conn.saddr));
+    tags.insert("ip_version", ip_version(&conn.saddr));
+    // skc_num is kept in host byte order by the kernel
+    tags.insert("port", conn.sport);
+    match proto_name(conn.typ) {
+        Some(proto) => tags.insert("proto", proto),
+        None => tags.insert("proto", conn.typ.to_string()),
+    }
+    netns_tags(conn, &mut tags);
+
+    tags
+}
@@ -0,0 +1,664 @@
+//! Loads the programs of a probe that redbpf can't load yet:
+//!
+//!  * `perf_event/` programs, attached to a sampling event on every CPU
+//!  * `cgroup/connect4` and `cgroup/connect6` programs, attached to a cgroup
+//!    to allow or reject the `connect()` calls of its processes
+//!  * `tracepoint/` programs of probes with per-CPU maps, whose values
+//!    redbpf can't read
+//!
+//! The maps of the probe are created, and the programs relocated against
+//! them, the same way redbpf does for the programs it knows about.
+//!
+//! Only the Profile and Cpu grains and the egress `Enforcer` load their
+//! probes with it. Everything redbpf can load goes through redbpf, and the
+//! loader can go once redbpf supports these programs.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use failure::{bail, format_err, Error};
+use goblin::elf::Elf;
+use libc::{c_int, c_long, c_ulong};
+use redbpf::cpus;
+use redbpf::uname::get_kernel_internal_version;
+
+const BPF_MAP_CREATE: c_int = 0;
+const BPF_MAP_LOOKUP_ELEM: c_int = 1;
+const BPF_MAP_UPDATE_ELEM: c_int = 2;
+const BPF_MAP_DELETE_ELEM: c_int = 3;
+const BPF_MAP_GET_NEXT_KEY: c_int = 4;
+const BPF_PROG_LOAD: c_int = 5;
+const BPF_PROG_ATTACH: c_int = 8;
+const BPF_PROG_DETACH: c_int = 9;
+
+const BPF_PROG_TYPE_TRACEPOINT: u32 = 5;
+const BPF_PROG_TYPE_PERF_EVENT: u32 = 7;
+const BPF_PROG_TYPE_CGROUP_SOCK_ADDR: u32 = 18;
+const BPF_CGROUP_INET4_CONNECT: u32 = 10;
+const BPF_CGROUP_INET6_CONNECT: u32 = 11;
+/// Lets the programs of other tools attached to the same cgroup run too
+const BPF_F_ALLOW_MULTI: u32 = 2;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_INSN_SIZE: usize = 8;
+
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+const PERF_ATTR_FLAG_FREQ: u64 = 1 << 10;
+const PERF_FLAG_FD_CLOEXEC: c_ulong = 8;
+const PERF_EVENT_IOC_ENABLE: c_ulong = 0x2400;
+const PERF_EVENT_IOC_SET_BPF: c_ulong = 0x4004_2408;
+
+const LOG_SIZE: usize = 64 * 1024;
+
+const POSSIBLE_CPUS: &str = "/sys/devices/system/cpu/possible";
+/// Where tracefs is mounted, depending on the distribution
+const TRACEFS: &[&str] = &["/sys/kernel/debug/tracing", "/sys/kernel/tracing"];
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+/// The fields of `struct perf_event_attr` up to `config2`
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_freq: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+}
+
+fn bpf<T>(cmd: c_int, attr: &T) -> io::Result<c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            mem::size_of::<T>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret)
+}
+
+/// A map created for a probe
+pub struct Map {
+    pub name: String,
+    fd: RawFd,
+    key_size: usize,
+    value_size: usize,
+}
+
+impl Map {
+    /// Creates the map `def`, a `struct bpf_map_def`, describes
+    fn create(name: &str, def: &[u8]) -> Result<Map, Error> {
+        let field = |i: usize| -> Result<u32, Error> {
+            let bytes = def
+                .get(i * 4..i * 4 + 4)
+                .ok_or_else(|| format_err!("invalid definition of map {}", name))?;
+            Ok(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        let attr = MapCreateAttr {
+            map_type: field(0)?,
+            key_size: field(1)?,
+            value_size: field(2)?,
+            max_entries: field(3)?,
+            map_flags: field(4)?,
+        };
+        let fd = bpf(BPF_MAP_CREATE, &attr)
+            .map_err(|e| format_err!("couldn't create map {}: {}", name, e))?;
+
+        Ok(Map {
+            name: name.to_string(),
+            fd: fd as RawFd,
+            key_size: attr.key_size as usize,
+            value_size: attr.value_size as usize,
+        })
+    }
+
+    /// The keys in the map
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        let mut keys: Vec<Vec<u8>> = vec![];
+        loop {
+            let mut next = vec![0u8; self.key_size];
+            let attr = MapElemAttr {
+                map_fd: self.fd as u32,
+                // no key gets the first one
+                key: keys.last().map_or(0, |key| key.as_ptr() as u64),
+                value: next.as_mut_ptr() as u64,
+                flags: 0,
+            };
+            if bpf(BPF_MAP_GET_NEXT_KEY, &attr).is_err() {
+                return keys;
+            }
+            keys.push(next);
+        }
+    }
+
+    /// The values of `key` on each of the `cpus` possible CPUs, in the order
+    /// of `possible_cpus()`, for per-CPU maps
+    pub fn get_per_cpu(&self, key: &[u8], cpus: usize) -> Option<Vec<Vec<u8>>> {
+        // the value of every CPU is padded to 8 bytes
+        let stride = (self.value_size + 7) & !7;
+        let mut values = vec![0u8; stride * cpus];
+        let attr = MapElemAttr {
+            map_fd: self.fd as u32,
+            key: key.as_ptr() as u64,
+            value: values.as_mut_ptr() as u64,
+            flags: 0,
+        };
+        bpf(BPF_MAP_LOOKUP_ELEM, &attr).ok()?;
+
+        Some(
+            values
+                .chunks(stride)
+                .map(|value| value[..self.value_size].to_vec())
+                .collect(),
+        )
+    }
+
+    /// The value of `key`, if it's in the map
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut value = vec![0u8; self.value_size];
+        let attr = MapElemAttr {
+            map_fd: self.fd as u32,
+            key: key.as_ptr() as u64,
+            value: value.as_mut_ptr() as u64,
+            flags: 0,
+        };
+        bpf(BPF_MAP_LOOKUP_ELEM, &attr).ok()?;
+
+        Some(value)
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if key.len() != self.key_size || value.len() != self.value_size {
+            bail!("invalid key or value for map {}", self.name);
+        }
+        let attr = MapElemAttr {
+            map_fd: self.fd as u32,
+            key: key.as_ptr() as u64,
+            value: value.as_ptr() as u64,
+            flags: 0,
+        };
+        bpf(BPF_MAP_UPDATE_ELEM, &attr)
+            .map_err(|e| format_err!("couldn't update map {}: {}", self.name, e))?;
+
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &[u8]) {
+        let attr = MapElemAttr {
+            map_fd: self.fd as u32,
+            key: key.as_ptr() as u64,
+            value: 0,
+            flags: 0,
+        };
+        let _ = bpf(BPF_MAP_DELETE_ELEM, &attr);
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// The kind of a program, from the prefix of its section
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    PerfEvent,
+    Tracepoint,
+    /// Attached to cgroups, with the attach type it's loaded for
+    Cgroup(u32),
+}
+
+impl Kind {
+    /// The kind and the name of the program in section `name`, if it's one
+    /// the loader knows
+    fn from_section(name: &str) -> Option<(Kind, &str)> {
+        if name.starts_with("perf_event/") {
+            return Some((Kind::PerfEvent, &name["perf_event/".len()..]));
+        }
+        if name.starts_with("tracepoint/") {
+            return Some((Kind::Tracepoint, &name["tracepoint/".len()..]));
+        }
+
+        match name {
+            "cgroup/connect4" => Some((Kind::Cgroup(BPF_CGROUP_INET4_CONNECT), "connect4")),
+            "cgroup/connect6" => Some((Kind::Cgroup(BPF_CGROUP_INET6_CONNECT), "connect6")),
+            _ => None,
+        }
+    }
+
+    fn prog_type(self) -> u32 {
+        match self {
+            Kind::PerfEvent => BPF_PROG_TYPE_PERF_EVENT,
+            Kind::Tracepoint => BPF_PROG_TYPE_TRACEPOINT,
+            Kind::Cgroup(_) => BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+        }
+    }
+
+    fn attach_type(self) -> u32 {
+        match self {
+            Kind::PerfEvent | Kind::Tracepoint => 0,
+            Kind::Cgroup(attach_type) => attach_type,
+        }
+    }
+}
+
+/// A program loaded in the kernel
+struct Program {
+    name: String,
+    kind: Kind,
+    fd: RawFd,
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// The maps and the programs of a probe
+pub struct Module {
+    pub maps: Vec<Map>,
+    programs: Vec<Program>,
+    events: Vec<RawFd>,
+    cgroups: Vec<RawFd>,
+    attached: Vec<ProgAttachAttr>,
+}
+
+impl Module {
+    /// Creates the maps of the probe in `code`, and loads the programs the
+    /// loader knows
+    pub fn load(code: &[u8]) -> Result<Module, Error> {
+        let elf = Elf::parse(code)?;
+        let section = |shndx: usize| {
+            let sh = &elf.section_headers[shndx];
+            &code[sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize]
+        };
+
+        let mut license = String::new();
+        let mut maps = HashMap::new();
+        let mut programs = HashMap::new();
+        for (shndx, sh) in elf.section_headers.iter().enumerate() {
+            let name = match elf.shdr_strtab.get(sh.sh_name).and_then(|name| name.ok()) {
+                Some(name) => name,
+                None => continue,
+            };
+            if name == "license" {
+                license = String::from_utf8_lossy(section(shndx))
+                    .trim_end_matches('\0')
+                    .to_string();
+            } else if name.starts_with("maps/") {
+                maps.insert(shndx, Map::create(&name["maps/".len()..], section(shndx))?);
+            } else if let Some((kind, name)) = Kind::from_section(name) {
+                programs.insert(shndx, (name.to_string(), kind, section(shndx).to_vec()));
+            }
+        }
+
+        for (shndx, relocs) in elf.shdr_relocs.iter() {
+            let target = elf.section_headers[*shndx].sh_info as usize;
+            let (_, _, insns) = match programs.get_mut(&target) {
+                Some(program) => program,
+                None => continue,
+            };
+
+            for reloc in relocs.iter() {
+                let map = elf
+                    .syms
+                    .get(reloc.r_sym)
+                    .and_then(|sym| maps.get(&sym.st_shndx))
+                    .ok_or_else(|| format_err!("relocation to an unknown map"))?;
+                let insn = reloc.r_offset as usize;
+                if insn + BPF_INSN_SIZE > insns.len() {
+                    bail!("relocation outside of the program");
+                }
+                insns[insn + 1] = (insns[insn + 1] & 0x0f) | (BPF_PSEUDO_MAP_FD << 4);
+                insns[insn + 4..insn + 8].copy_from_slice(&map.fd.to_ne_bytes());
+            }
+        }
+
+        let license = CString::new(license)?;
+        let version = get_kernel_internal_version().unwrap_or(0);
+        let programs = programs
+            .drain()
+            .map(|(_, (name, kind, insns))| Program::load(name, kind, &insns, &license, version))
+            .collect::<Result<Vec<_>, _>>()?;
+        if programs.is_empty() {
+            bail!("no programs to load in the probe");
+        }
+
+        Ok(Module {
+            maps: maps.drain().map(|(_, map)| map).collect(),
+            programs,
+            events: vec![],
+            cgroups: vec![],
+            attached: vec![],
+        })
+    }
+
+    pub fn map(&self, name: &str) -> Option<&Map> {
+        self.maps.iter().find(|map| map.name == name)
+    }
+
+    /// Runs the programs `frequency` times a second on every CPU
+    pub fn attach_sampling(&mut self, frequency: u64) -> Result<(), Error> {
+        let attr = PerfEventAttr {
+            type_: PERF_TYPE_SOFTWARE,
+            size: mem::size_of::<PerfEventAttr>() as u32,
+            config: PERF_COUNT_SW_CPU_CLOCK,
+            sample_freq: frequency,
+            flags: PERF_ATTR_FLAG_FREQ,
+            ..Default::default()
+        };
+
+        let online =
+            cpus::get_online().map_err(|_| format_err!("Unable to list the online CPUs"))?;
+        for cpu in online {
+            let fd = perf_event_open(&attr, cpu).map_err(|e| {
+                format_err!("couldn't open the sampling event of CPU {}: {}", cpu, e)
+            })?;
+            self.events.push(fd);
+
+            for program in self.programs.iter().filter(|p| p.kind == Kind::PerfEvent) {
+                if unsafe { libc::ioctl(fd, PERF_EVENT_IOC_SET_BPF, program.fd) } < 0 {
+                    bail!(
+                        "couldn't attach {} to CPU {}: {}",
+                        program.name,
+                        cpu,
+                        io::Error::last_os_error()
+                    );
+                }
+            }
+            if unsafe { libc::ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) } < 0 {
+                bail!(
+                    "couldn't enable the sampling event of CPU {}: {}",
+                    cpu,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Attaches the `tracepoint/` programs to the tracepoints of the same
+    /// name in `category`, like `sched`
+    pub fn attach_tracepoints(&mut self, category: &str) -> Result<(), Error> {
+        for program in self.programs.iter().filter(|p| p.kind == Kind::Tracepoint) {
+            let id = tracepoint_id(category, &program.name)?;
+            let attr = PerfEventAttr {
+                type_: PERF_TYPE_TRACEPOINT,
+                size: mem::size_of::<PerfEventAttr>() as u32,
+                config: id,
+                // the sample period
+                sample_freq: 1,
+                wakeup_events: 1,
+                ..Default::default()
+            };
+
+            // programs attached to a tracepoint run on every CPU
+            let fd = perf_event_open(&attr, 0).map_err(|e| {
+                format_err!(
+                    "couldn't open tracepoint {}/{}: {}",
+                    category,
+                    program.name,
+                    e
+                )
+            })?;
+            self.events.push(fd);
+
+            if unsafe { libc::ioctl(fd, PERF_EVENT_IOC_SET_BPF, program.fd) } < 0
+                || unsafe { libc::ioctl(fd, PERF_EVENT_IOC_ENABLE, 0) } < 0
+            {
+                bail!(
+                    "couldn't attach {} to tracepoint {}/{}: {}",
+                    program.name,
+                    category,
+                    program.name,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Attaches the `cgroup/` programs to the cgroup v2 at `path`, so they
+    /// run for its processes and the ones of the cgroups below it
+    pub fn attach_cgroup(&mut self, path: &Path) -> Result<(), Error> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe {
+            libc::open(
+                c_path.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            bail!(
+                "couldn't open cgroup {}: {}",
+                path.display(),
+                io::Error::last_os_error()
+            );
+        }
+        self.cgroups.push(fd);
+
+        for program in self.programs.iter() {
+            if let Kind::Cgroup(attach_type) = program.kind {
+                let attr = ProgAttachAttr {
+                    target_fd: fd as u32,
+                    attach_bpf_fd: program.fd as u32,
+                    attach_type,
+                    attach_flags: BPF_F_ALLOW_MULTI,
+                };
+                bpf(BPF_PROG_ATTACH, &attr).map_err(|e| {
+                    format_err!(
+                        "couldn't attach {} to cgroup {}: {}",
+                        program.name,
+                        path.display(),
+                        e
+                    )
+                })?;
+                self.attached.push(attr);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        for fd in self.events.drain(..) {
+            unsafe { libc::close(fd) };
+        }
+        // the cgroups keep the programs attached to them, even once closed
+        for attr in self.attached.drain(..) {
+            let _ = bpf(BPF_PROG_DETACH, &attr);
+        }
+        for fd in self.cgroups.drain(..) {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// Opens a perf event of every process on `cpu`
+fn perf_event_open(attr: &PerfEventAttr, cpu: c_int) -> io::Result<RawFd> {
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            -1,
+            cpu,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(fd as RawFd)
+}
+
+/// The id of tracepoint `category/name`, as tracefs lists it
+fn tracepoint_id(category: &str, name: &str) -> Result<u64, Error> {
+    let id = TRACEFS
+        .iter()
+        .find_map(|tracefs| {
+            fs::read_to_string(format!("{}/events/{}/{}/id", tracefs, category, name)).ok()
+        })
+        .ok_or_else(|| format_err!("tracepoint {}/{} not found", category, name))?;
+
+    id.trim()
+        .parse()
+        .map_err(|_| format_err!("invalid id of tracepoint {}/{}", category, name))
+}
+
+/// The ids of the CPUs the kernel keeps per-CPU values for, which include
+/// the ones that are offline
+pub fn possible_cpus() -> Result<Vec<u32>, Error> {
+    let cpus = fs::read_to_string(POSSIBLE_CPUS)?;
+    parse_cpu_list(cpus.trim())
+        .ok_or_else(|| format_err!("invalid list of CPUs in {}", POSSIBLE_CPUS))
+}
+
+/// Parses lists of CPUs like `0-3,5`
+fn parse_cpu_list(list: &str) -> Option<Vec<u32>> {
+    let mut cpus = vec![];
+    for range in list.split(',') {
+        let mut bounds = range.splitn(2, '-');
+        let start = bounds.next()?.parse().ok()?;
+        let end = match bounds.next() {
+            Some(end) => end.parse().ok()?,
+            None => start,
+        };
+        cpus.extend(start..=end);
+    }
+
+    Some(cpus)
+}
+
+impl Program {
+    fn load(
+        name: String,
+        kind: Kind,
+        insns: &[u8],
+        license: &CString,
+        version: u32,
+    ) -> Result<Program, Error> {
+        let mut attr = ProgLoadAttr {
+            prog_type: kind.prog_type(),
+            insn_cnt: (insns.len() / BPF_INSN_SIZE) as u32,
+            insns: insns.as_ptr() as u64,
+            license: license.as_ptr() as u64,
+            log_level: 0,
+            log_size: 0,
+            log_buf: 0,
+            kern_version: version,
+            prog_flags: 0,
+            prog_name: [0; 16],
+            prog_ifindex: 0,
+            expected_attach_type: kind.attach_type(),
+        };
+        if let Ok(fd) = bpf(BPF_PROG_LOAD, &attr) {
+            return Ok(Program {
+                name,
+                kind,
+                fd: fd as RawFd,
+            });
+        }
+
+        // load it again for the verifier log
+        let mut log = vec![0u8; LOG_SIZE];
+        attr.log_level = 1;
+        attr.log_size = LOG_SIZE as u32;
+        attr.log_buf = log.as_mut_ptr() as u64;
+        let error = match bpf(BPF_PROG_LOAD, &attr) {
+            Ok(fd) => {
+                return Ok(Program {
+                    name,
+                    kind,
+                    fd: fd as RawFd,
+                })
+            }
+            Err(e) => e,
+        };
+        let log = String::from_utf8_lossy(&log);
+        bail!(
+            "couldn't load {}: {}\n{}",
+            name,
+            error,
+            log.trim_end_matches('\0')
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0"), Some(vec![0]));
+        assert_eq!(parse_cpu_list("0-3,6"), Some(vec![0, 1, 2, 3, 6]));
+        assert_eq!(parse_cpu_list("0-"), None);
+    }
+}
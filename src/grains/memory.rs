@@ -0,0 +1,92 @@
+use failure::Error;
+use redbpf::{HashMap as BPFHashMap, Module};
+
+use crate::grains::probes::memory::maps;
+use crate::grains::*;
+
+use ingraind_probes::memory::{MemoryEvent, Process, MMAP_THRESHOLD, REPORT_MAJOR_FAULTS};
+
+unsafe impl FromRawEvent for MemoryEvent {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 3)
+    }
+}
+
+pub struct Memory(pub MemoryConfig);
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MemoryConfig {
+    #[serde(default = "default_mmap_threshold")]
+    mmap_threshold: u64,
+    #[serde(default = "default_major_faults")]
+    major_faults: bool,
+}
+
+fn default_mmap_threshold() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_major_faults() -> bool {
+    true
+}
+
+impl EBPFProbe for Grain<Memory> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for Memory {
+    fn code() -> &'static [u8] {
+        probes::memory::CODE
+    }
+
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        let settings =
+            BPFHashMap::<u8, u64>::new(find_map_by_name(module, maps::SETTINGS)).unwrap();
+        settings.set(MMAP_THRESHOLD, self.0.mmap_threshold);
+        settings.set(REPORT_MAJOR_FAULTS, self.0.major_faults as u64);
+
+        Ok(())
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: MemoryEvent| {
+            let measurement = match event {
+                MemoryEvent::OomKill(victim, trigger) => {
+                    let mut tags = process_tags(&trigger);
+                    tags.insert("victim_process_id", victim.pid);
+                    tags.insert("victim_process_str", to_string(&victim.comm));
+
+                    Measurement::new(
+                        COUNTER | METER,
+                        "memory.oom_kill".to_string(),
+                        Unit::Count(1),
+                        tags,
+                    )
+                }
+                MemoryEvent::MajorFault(process) => Measurement::new(
+                    COUNTER | METER,
+                    "memory.major_fault".to_string(),
+                    Unit::Count(1),
+                    process_tags(&process),
+                ),
+                MemoryEvent::LargeMmap(process, len) => Measurement::new(
+                    COUNTER | HISTOGRAM,
+                    "memory.mmap".to_string(),
+                    Unit::Byte(len),
+                    process_tags(&process),
+                ),
+            };
+
+            Some(Message::Single(measurement))
+        })
+    }
+}
+
+fn process_tags(process: &Process) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("process_id", process.pid);
+    tags.insert("process_str", to_string(&process.comm));
+
+    tags
+}
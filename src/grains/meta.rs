@@ -0,0 +1,216 @@
+//! The requirements probes embed in `meta/` sections of their ELF, so
+//! ingraind can tell what's missing on kernels that can't run them, instead
+//! of failing to load them.
+//!
+//!  * `meta/min_kernel` holds the oldest kernel the probe runs on, as a
+//!    little-endian `u32` like the `version` section
+//!  * `meta/needs=<helper>` names a BPF helper the probe calls. Only the
+//!    helpers in `HELPERS` are checked, as the kernel they appeared in is
+//!    known.
+//!
+//! Either may be scoped to a program by appending its name, like
+//! `meta/min_kernel/udp_rcv`. On kernels the probe doesn't support, it
+//! isn't loaded, and the programs the kernel doesn't support are skipped.
+//!
+//! Probes declare them with the `meta!` macro of `ingraind-probes`. The
+//! requirements of the probes ingraind is built with are read from their
+//! sources into the probe registry, and those of prebuilt probes from their
+//! ELF as it's checked, so no probe is parsed for them again when loaded.
+
+use failure::{bail, format_err, Error};
+use goblin::elf::Elf;
+
+use crate::grains::{prebuilt, probes};
+
+const PREFIX: &str = "meta/";
+
+/// The kernels BPF helpers appeared in
+const HELPERS: &[(&str, u32)] = &[
+    ("bpf_get_stackid", 0x040600),
+    ("bpf_get_current_task", 0x040800),
+    ("bpf_probe_read_str", 0x040b00),
+    ("bpf_get_current_cgroup_id", 0x041200),
+    ("bpf_send_signal", 0x050300),
+    ("bpf_probe_read_user", 0x050500),
+    ("bpf_probe_read_kernel", 0x050500),
+    ("bpf_probe_read_user_str", 0x050500),
+    ("bpf_probe_read_kernel_str", 0x050500),
+    ("bpf_get_ns_current_pid_tgid", 0x050700),
+    ("bpf_ktime_get_boot_ns", 0x050800),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Requirement {
+    Kernel(u32),
+    Helper(String),
+}
+
+/// A requirement of a probe, or of one of its programs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Meta {
+    pub program: Option<String>,
+    pub requirement: Requirement,
+}
+
+impl Meta {
+    /// Why `kernel` doesn't meet the requirement, if it doesn't
+    fn unmet(&self, kernel: u32) -> Option<String> {
+        match self.requirement {
+            Requirement::Kernel(version) if kernel < version => {
+                Some(format!("needs kernel >= {}", version_string(version)))
+            }
+            Requirement::Helper(ref helper) => {
+                let (_, version) = HELPERS.iter().find(|(name, _)| name == helper)?;
+                if kernel >= *version {
+                    return None;
+                }
+                Some(format!(
+                    "needs {}, from kernel {}",
+                    helper,
+                    version_string(*version)
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The requirements of the probe built into, or loaded from, `code`
+pub fn of(code: &[u8]) -> Result<Vec<Meta>, Error> {
+    if let Some(probe) = probes::find(code) {
+        return probe
+            .meta
+            .iter()
+            .filter_map(|(name, value)| parse_section(name, Some(*value)).transpose())
+            .collect();
+    }
+
+    prebuilt::metas(code).ok_or_else(|| format_err!("unknown probe"))
+}
+
+/// The requirements in the `meta/` sections of `elf`, parsed from `code`
+pub fn read(elf: &Elf, code: &[u8]) -> Result<Vec<Meta>, Error> {
+    let mut metas = vec![];
+    for sh in elf.section_headers.iter() {
+        let name = match elf.shdr_strtab.get(sh.sh_name).and_then(|name| name.ok()) {
+            Some(name) if name.starts_with(PREFIX) => &name[PREFIX.len()..],
+            _ => continue,
+        };
+        let start = sh.sh_offset as usize;
+        let value = match code.get(start..start + sh.sh_size as usize) {
+            Some(&[a, b, c, d]) => Some(u32::from_le_bytes([a, b, c, d])),
+            _ => None,
+        };
+        metas.extend(parse_section(name, value)?);
+    }
+
+    Ok(metas)
+}
+
+/// The requirement of section `meta/<name>`, holding `value` if it's a
+/// `u32`. Keys of newer versions of ingraind-probes are ignored.
+fn parse_section(name: &str, value: Option<u32>) -> Result<Option<Meta>, Error> {
+    let mut parts = name.splitn(2, '/');
+    let key = parts.next().unwrap_or_default();
+    let program = parts.next().map(String::from);
+
+    let requirement = if key == "min_kernel" {
+        match value {
+            Some(version) => Requirement::Kernel(version),
+            None => bail!("invalid {}{}", PREFIX, name),
+        }
+    } else if key.starts_with("needs=") {
+        Requirement::Helper(key["needs=".len()..].to_string())
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(Meta {
+        program,
+        requirement,
+    }))
+}
+
+/// Fails with what's missing if `kernel` can't run the probe
+pub fn check(metas: &[Meta], kernel: u32) -> Result<(), Error> {
+    let unmet = metas
+        .iter()
+        .filter(|m| m.program.is_none())
+        .filter_map(|m| m.unmet(kernel))
+        .collect::<Vec<_>>();
+    if !unmet.is_empty() {
+        bail!(
+            "the probe {}, this is kernel {}",
+            unmet.join(" and "),
+            version_string(kernel)
+        );
+    }
+
+    Ok(())
+}
+
+/// The programs `kernel` can't run, and why
+pub fn unsupported(metas: &[Meta], kernel: u32) -> Vec<(String, String)> {
+    metas
+        .iter()
+        .filter_map(|m| Some((m.program.clone()?, m.unmet(kernel)?)))
+        .collect()
+}
+
+/// A kernel version like `0x041200`, as `4.18`
+fn version_string(version: u32) -> String {
+    format!("{}.{}", version >> 16, (version >> 8) & 0xff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(program: Option<&str>, requirement: Requirement) -> Meta {
+        Meta {
+            program: program.map(String::from),
+            requirement,
+        }
+    }
+
+    #[test]
+    fn requirements() {
+        let metas = vec![
+            meta(None, Requirement::Kernel(0x041200)),
+            meta(None, Requirement::Helper("bpf_probe_read_user".to_string())),
+            meta(Some("udp_rcv"), Requirement::Kernel(0x050a00)),
+            meta(None, Requirement::Helper("bpf_unknown".to_string())),
+        ];
+
+        assert!(check(&metas, 0x050800).is_ok());
+        assert_eq!(
+            check(&metas, 0x040f12).unwrap_err().to_string(),
+            "the probe needs kernel >= 4.18 and needs bpf_probe_read_user, \
+             from kernel 5.5, this is kernel 4.15"
+        );
+        assert_eq!(
+            unsupported(&metas, 0x050800),
+            vec![("udp_rcv".to_string(), "needs kernel >= 5.10".to_string())]
+        );
+        assert!(unsupported(&metas, 0x050a00).is_empty());
+    }
+
+    #[test]
+    fn sections() {
+        assert_eq!(
+            parse_section("min_kernel/udp_rcv", Some(0x050a00)).unwrap(),
+            Some(meta(Some("udp_rcv"), Requirement::Kernel(0x050a00)))
+        );
+        let helper = Requirement::Helper("bpf_probe_read_str".to_string());
+        assert_eq!(
+            parse_section("needs=bpf_probe_read_str", Some(1)).unwrap(),
+            Some(meta(None, helper))
+        );
+        assert!(parse_section("min_kernel", None).is_err());
+        assert_eq!(parse_section("newer_key", None).unwrap(), None);
+
+        // the probes built in declare theirs in the registry
+        let metas = of(probes::sched::CODE).unwrap();
+        assert_eq!(metas, vec![meta(None, Requirement::Kernel(0x040e00))]);
+    }
+}
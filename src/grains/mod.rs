@@ -1,29 +1,78 @@
 mod ebpf;
 mod ebpf_io;
+mod event;
+mod loader;
 mod protocol;
+mod reorder;
+mod symbols;
 
+pub mod arp;
+pub mod dhcp_ntp;
+pub mod disk_usage;
 pub mod dns;
+pub mod dns_exfil;
+pub mod egress;
 pub mod file;
+pub mod filter;
+pub mod limits;
+pub mod meta;
 pub mod osquery;
+pub mod plugin;
+pub mod prebuilt;
+pub mod probes;
+pub mod process_tree;
+pub mod sampling;
+pub mod snapshot;
+pub mod sockets;
 pub mod statsd;
+pub mod synthetic;
+pub mod syscall_table;
 pub mod syscalls;
 pub mod tls;
+pub mod netns;
 pub mod network;
+pub mod tcp_latency;
+pub mod udp;
+pub mod icmp;
+pub mod listen;
+pub mod profile;
+pub mod memory;
+pub mod sched;
+pub mod cpu;
+pub mod creds;
+pub mod kernel_load;
+pub mod ptrace;
+pub mod unix;
+pub mod ssh;
+pub mod tty;
+pub mod usdt;
+pub mod ssl;
+pub mod stats;
+pub mod db;
+pub mod generic;
+pub mod cgroup;
 pub mod test;
+#[cfg(feature = "privileged-tests")]
+pub mod harness;
+pub mod workers;
 
 use actix::Recipient;
 
 pub use crate::grains::ebpf::*;
 pub use crate::grains::ebpf_io::*;
+pub use crate::grains::event::*;
 
 pub use crate::backends::Message;
 pub use crate::metrics::kind::*;
 pub use crate::metrics::{Measurement, Tags, ToTags, Unit};
 pub use std::net::Ipv4Addr;
 
-use redbpf::{Map, Module};
+use ingraind_probes::filter::Filters;
+use redbpf::{HashMap as BPFHashMap, Map, Module};
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::{os::raw::c_char, mem::transmute };
-trait SendToManyRecipients {
+pub(crate) trait SendToManyRecipients {
     fn do_send(&self, message: Message) {
         let recipients = self.recipients();
         for (i, r) in recipients.iter().enumerate() {
@@ -61,3 +110,63 @@ pub fn to_string(buf: &[c_char]) -> String {
 pub fn find_map_by_name<'a>(module: &'a Module, needle: &str) -> &'a Map {
     module.maps.iter().find(|v| v.name == needle).unwrap()
 }
+
+/// Reads and deletes the entries of hash map `name`, for counters
+/// aggregated in the kernel.
+///
+/// Updates made by the kernel between the read and the delete are lost.
+pub fn drain_map<K: Clone, V: Clone>(module: &Module, name: &str) -> Vec<(K, V)> {
+    let map = BPFHashMap::<K, V>::new(find_map_by_name(module, name)).unwrap();
+    let entries = map.iter().collect::<Vec<_>>();
+    for (key, _) in entries.iter() {
+        map.delete(key.clone());
+    }
+
+    entries
+}
+
+/// Reads the entries of hash map `name` as JSON. Keys and values that
+/// can't be serialized, like maps keyed by structs, are formatted as the hex
+/// of their bytes.
+pub fn dump_hash_map<K, V>(module: &Module, name: &str) -> Option<Value>
+where
+    K: Clone + Serialize,
+    V: Clone + Serialize,
+{
+    let map = module.maps.iter().find(|m| m.name == name)?;
+    let map = BPFHashMap::<K, V>::new(map).ok()?;
+    let entries = map
+        .iter()
+        .map(|(key, value)| {
+            json!({
+                "key": to_json(&key),
+                "value": to_json(&value),
+            })
+        })
+        .collect();
+
+    Some(Value::Array(entries))
+}
+
+fn to_json<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or_else(|_| Value::String(to_hex(value)))
+}
+
+/// The bytes of `value` as they are in the map, in hex
+fn to_hex<T>(value: &T) -> String {
+    let bytes =
+        unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) };
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads the maps that follow the conventions shared by probes, like
+/// `settings` and `filters`, as JSON
+pub fn dump_common_map(module: &Module, name: &str) -> Option<Value> {
+    match name {
+        probes::common::maps::SETTINGS => dump_hash_map::<u8, u64>(module, name),
+        probes::common::maps::FILTERS => dump_hash_map::<u8, Filters>(module, name),
+        probes::common::maps::FILTER_PIDS => dump_hash_map::<u32, u8>(module, name),
+        probes::common::maps::SAMPLING => dump_hash_map::<u8, u64>(module, name),
+        _ => None,
+    }
+}
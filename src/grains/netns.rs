@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+use std::sync::{Once, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+/// How often the interfaces and the network namespaces of processes are
+/// re-read
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+static RESCANS: Once = Once::new();
+
+lazy_static! {
+    static ref INTERFACES: RwLock<HashMap<u32, String>> = RwLock::new(HashMap::new());
+    static ref NETNS_PIDS: RwLock<HashMap<u32, u32>> = RwLock::new(HashMap::new());
+}
+
+/// Reads the tables on the first lookup, and re-reads them every
+/// `RESCAN_INTERVAL` from then on, outside of the event path
+fn start_rescans() {
+    RESCANS.call_once(|| {
+        rescan();
+        thread::spawn(|| loop {
+            thread::sleep(RESCAN_INTERVAL);
+            rescan();
+        });
+    });
+}
+
+fn rescan() {
+    let interfaces = read_interfaces();
+    *INTERFACES.write().unwrap() = interfaces;
+    let pids = read_netns_pids();
+    *NETNS_PIDS.write().unwrap() = pids;
+}
+
+/// Inode of the network namespace `ingraind` is running in.
+pub fn current() -> Option<u32> {
+    netns_of("self")
+}
+
+/// Resolve an interface index to its name in the host network namespace.
+///
+/// The table is re-read from `/sys/class/net` every `RESCAN_INTERVAL`, so
+/// interfaces created after startup are picked up.
+pub fn interface_name(ifindex: u32) -> Option<String> {
+    if ifindex == 0 {
+        return None;
+    }
+
+    start_rescans();
+    INTERFACES.read().unwrap().get(&ifindex).cloned()
+}
+
+/// Find a process that lives in the network namespace with the given inode.
+///
+/// This is used to attribute traffic to containers when the event itself
+/// does not carry a usable process id.
+pub fn pid_for_netns(netns: u32) -> Option<u32> {
+    start_rescans();
+    let pid = *NETNS_PIDS.read().unwrap().get(&netns)?;
+
+    // the process may have exited since the last rescan
+    if netns_of(&pid.to_string()) == Some(netns) {
+        Some(pid)
+    } else {
+        None
+    }
+}
+
+fn netns_of(pid: &str) -> Option<u32> {
+    let link = fs::read_link(format!("/proc/{}/ns/net", pid)).ok()?;
+    parse_ns_link(link.to_str()?)
+}
+
+fn parse_ns_link(link: &str) -> Option<u32> {
+    let inode = link.trim_start_matches("net:[").trim_end_matches(']');
+    u32::from_str(inode).ok()
+}
+
+fn read_interfaces() -> HashMap<u32, String> {
+    let mut interfaces = HashMap::new();
+    let entries = match fs::read_dir("/sys/class/net") {
+        Ok(entries) => entries,
+        Err(_) => return interfaces,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Ok(index) = fs::read_to_string(entry.path().join("ifindex")) {
+            if let Ok(index) = u32::from_str(index.trim()) {
+                interfaces.insert(index, name);
+            }
+        }
+    }
+
+    interfaces
+}
+
+fn read_netns_pids() -> HashMap<u32, u32> {
+    let mut pids = HashMap::new();
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return pids,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Ok(pid) = u32::from_str(&name) {
+            if let Some(netns) = netns_of(&name) {
+                pids.entry(netns).or_insert(pid);
+            }
+        }
+    }
+
+    pids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ns_link() {
+        assert_eq!(parse_ns_link("net:[4026531993]"), Some(4026531993));
+        assert_eq!(parse_ns_link("mnt:[4026531993]"), None);
+    }
+}
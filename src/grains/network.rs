@@ -1,11 +1,125 @@
 #![allow(non_camel_case_types)]
 
-use crate::grains::{self, *};
+use std::mem::size_of;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use ingraind_probes::network::{Connection, Ipv6Addr, Message};
+use crate::grains::egress::{to_ip_addr, Action, EgressPolicy, EgressPolicyConfig, Enforcer};
+use crate::grains::filter::FiltersConfig;
+use crate::grains::probes::network::{maps, programs};
+use crate::grains::sockets::{self, SocketOwner, SocketTable};
+use crate::grains::{self, netns, *};
+
+use failure::Error;
+use ingraind_probes::network::{
+    Connection, Flow, FlowVolume, Ipv6Addr, Message, TcpEvent, AGGREGATE,
+};
+use redbpf::{HashMap, Module};
+use serde_json::Value;
 use redbpf_probes::bindings::{IPPROTO_TCP, IPPROTO_UDP};
 
-pub struct Network;
+unsafe impl FromRawEvent for Connection {}
+unsafe impl FromRawEvent for Message {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+unsafe impl FromRawEvent for TcpEvent {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 4)
+    }
+}
+
+/// How often the owners of sockets are copied from the kernel, and the
+/// connections the kernel rejected are reported
+const SCRAPE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Network {
+    config: NetworkConfig,
+    policy: Option<Arc<EgressPolicy>>,
+    enforcer: Option<Enforcer>,
+    scrapes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct NetworkConfig {
+    egress_policy: Option<EgressPolicyConfig>,
+    /// Aggregate volumes per flow in the kernel, and scrape them this
+    /// often instead of sending every message
+    aggregate_interval_ms: Option<u64>,
+    /// Filters applied in the kernel, before events are sent
+    filters: Option<FiltersConfig>,
+}
+
+impl NetworkConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if let Some(ref filters) = self.filters {
+            filters.check()?;
+        }
+        if let Some(ref config) = self.egress_policy {
+            let policy = EgressPolicy::new(config)?;
+            if config.enforce {
+                policy.to_raw()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Network {
+    pub fn new(config: NetworkConfig) -> Result<Self, Error> {
+        let policy = match config.egress_policy {
+            Some(ref policy) => Some(Arc::new(EgressPolicy::new(policy)?)),
+            None => None,
+        };
+
+        Ok(Network {
+            config,
+            policy,
+            enforcer: None,
+            scrapes: 0,
+        })
+    }
+
+    /// Whether something done every `interval` is due at this scrape
+    fn due(&self, interval: Duration) -> bool {
+        let scrape_interval = self.scrape_interval().unwrap();
+        let every = (interval.as_millis() / scrape_interval.as_millis()).max(1) as u64;
+        self.scrapes % every == 0
+    }
+
+    /// Reads and resets the volumes aggregated in the kernel
+    fn scrape_volumes(&self, module: &Module) -> Vec<Measurement> {
+        let mut measurements = vec![];
+        for (_, volume) in drain_map::<Flow, FlowVolume>(module, maps::FLOW_VOLUMES) {
+            let proto = match proto_name(volume.conn.typ) {
+                Some(proto) => proto,
+                None => continue,
+            };
+
+            let mut tags = conn_tags(&volume.conn);
+            tags.insert("proto", proto);
+
+            for (name, bytes) in
+                [("volume.out", volume.sent), ("volume.in", volume.received)].iter()
+            {
+                if *bytes > 0 {
+                    measurements.push(Measurement::new(
+                        COUNTER,
+                        name.to_string(),
+                        Unit::Byte(*bytes),
+                        tags.clone(),
+                    ));
+                }
+            }
+        }
+
+        measurements
+    }
+}
 
 impl EBPFProbe for Grain<Network> {
     fn attach(&mut self) -> MessageStreams {
@@ -15,70 +129,246 @@ impl EBPFProbe for Grain<Network> {
 
 impl EBPFGrain<'static> for Network {
     fn code() -> &'static [u8] {
-        include_bytes!(concat!(
-            env!("OUT_DIR"),
-            "/target/bpf/programs/network/network.elf"
-        ))
+        probes::network::CODE
+    }
+
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        if let Some(ref filters) = self.config.filters {
+            filters.load(module)?;
+        }
+
+        if let (Some(config), Some(policy)) = (&self.config.egress_policy, &self.policy) {
+            if config.enforce {
+                self.enforcer = Some(Enforcer::attach(policy, &config.cgroup_path())?);
+            }
+        }
+
+        if self.config.aggregate_interval_ms.is_some() {
+            let settings =
+                HashMap::<u8, u64>::new(find_map_by_name(module, maps::SETTINGS)).unwrap();
+            settings.set(AGGREGATE, 1);
+        }
+
+        Ok(())
+    }
+
+    /// `tcp_reset` is often inlined, so there may be nothing to attach to
+    fn optional(&self, name: &str) -> bool {
+        name == programs::TCP_RESET
+    }
+
+    fn dump_map(&self, module: &Module, name: &str) -> Option<Value> {
+        match name {
+            maps::ESTABLISHED_AT => dump_hash_map::<u64, u64>(module, name),
+            maps::FLOW_VOLUMES => dump_hash_map::<Flow, FlowVolume>(module, name),
+            maps::SOCKETS => dump_hash_map::<u64, Connection>(module, name),
+            _ => dump_common_map(module, name),
+        }
+    }
+
+    fn scrape_interval(&self) -> Option<Duration> {
+        let aggregate = self.config.aggregate_interval_ms.map(Duration::from_millis);
+        Some(aggregate.map_or(SCRAPE_INTERVAL, |a| a.min(SCRAPE_INTERVAL)))
+    }
+
+    fn scrape(&mut self, module: &Module) -> Vec<Measurement> {
+        let mut measurements = vec![];
+        if let Some(interval) = self.config.aggregate_interval_ms {
+            if self.due(Duration::from_millis(interval)) {
+                measurements.extend(self.scrape_volumes(module));
+            }
+        }
+
+        // copied in one go, so lookups by other grains aren't held up by
+        // every socket
+        let owners =
+            HashMap::<u64, Connection>::new(find_map_by_name(module, maps::SOCKETS)).unwrap();
+        let mut table = SocketTable::default();
+        for owner in owners.iter().filter_map(|(_, conn)| socket_owner(&conn)) {
+            table.insert(owner);
+        }
+        sockets::replace(table);
+
+        // the kernel rejects the connections before they're made, so they
+        // are never seen by the probe
+        if let Some(ref mut enforcer) = self.enforcer {
+            for (addr, port, count) in enforcer.denied() {
+                let mut tags = Tags::new();
+                tags.insert("d_ip", addr);
+                tags.insert("d_port", port);
+                tags.insert("ip_version", if addr.is_ipv4() { "4" } else { "6" });
+                measurements.push(Measurement::new(
+                    COUNTER | METER,
+                    "connection.denied".to_string(),
+                    Unit::Count(count),
+                    tags,
+                ));
+            }
+        }
+
+        self.scrapes += 1;
+        measurements
     }
 
     fn get_handler(&self, id: &str) -> EventCallback {
         match id {
-            "ip_connections" => Box::new(|raw| {
-                let event = unsafe { std::ptr::read(raw.as_ptr() as *const Connection) };
-
-                Some(grains::Message::Single(Measurement::new(
-                    COUNTER | HISTOGRAM | METER,
-                    "connection.out".to_string(),
-                    Unit::Count(1),
-                    conn_tags(&event),
-                )))
-            }),
+            maps::IP_CONNECTIONS => {
+                // only connections the kernel let through reach the probe
+                let policy = match self.enforcer {
+                    Some(_) => None,
+                    None => self.policy.clone(),
+                };
 
-            "ip_volume" => Box::new(|raw| {
-                let event = unsafe { std::ptr::read(raw.as_ptr() as *const Message) };
+                decoded(move |event: Connection| {
+                    let tags = conn_tags(&event);
+                    let connection = Measurement::new(
+                        COUNTER | HISTOGRAM | METER,
+                        "connection.out".to_string(),
+                        Unit::Count(1),
+                        tags.clone(),
+                    );
+
+                    let port = to_le(event.dport as u16);
+                    match &policy {
+                        Some(p) if p.check(ip_addr(&event.daddr), port) == Action::Deny => {
+                            Some(grains::Message::List(vec![
+                                connection,
+                                Measurement::new(
+                                    COUNTER | METER,
+                                    "connection.denied".to_string(),
+                                    Unit::Count(1),
+                                    tags,
+                                ),
+                            ]))
+                        }
+                        _ => Some(grains::Message::Single(connection)),
+                    }
+                })
+            }
+
+            maps::IP_VOLUME => parsed(|event: Message| {
                 let (name, conn, vol) = match event {
                     Message::Send(conn, size) => ("volume.out", conn, size),
                     Message::Receive(conn, size) => ("volume.in", conn, size),
                 };
 
-                let proto = match conn.typ {
-                    IPPROTO_TCP => "tcp",
-                    IPPROTO_UDP => "udp",
-                    _ => return None,
-                };
+                // the probe only sends TCP and UDP volumes
+                let proto = proto_name(conn.typ).ok_or_else(|| {
+                    DecodeError::malformed("connection protocol", size_of::<Message>())
+                })?;
 
                 let mut tags = conn_tags(&conn);
                 tags.insert("proto", proto);
 
-                Some(grains::Message::Single(Measurement::new(
+                Ok(Some(grains::Message::Single(Measurement::new(
                     COUNTER | HISTOGRAM,
                     name.to_string(),
                     Unit::Byte(vol as u64),
                     tags,
-                )))
+                ))))
+            }),
+
+            maps::TCP_LIFECYCLE => decoded(|event: TcpEvent| {
+                let measurement = tcp_measurement(event);
+                Some(grains::Message::Single(measurement))
             }),
             _ => unreachable!(),
         }
     }
 }
 
-fn conn_tags(event: &Connection) -> Tags {
+fn tcp_measurement(event: TcpEvent) -> Measurement {
+    let (kind, name, conn, value) = match event {
+        TcpEvent::Accept(conn) => (
+            COUNTER | HISTOGRAM | METER,
+            "connection.in",
+            conn,
+            Unit::Count(1),
+        ),
+        TcpEvent::Close(conn, duration) => (
+            TIMER | HISTOGRAM,
+            "connection.duration",
+            conn,
+            Unit::Duration(duration),
+        ),
+        TcpEvent::Retransmit(conn) => (COUNTER | METER, "tcp.retransmit", conn, Unit::Count(1)),
+        TcpEvent::Reset(conn) => (COUNTER | METER, "tcp.reset", conn, Unit::Count(1)),
+    };
+
+    Measurement::new(kind, name.to_string(), value, conn_tags(&conn))
+}
+
+pub(crate) fn conn_tags(event: &Connection) -> Tags {
     let mut tags = Tags::new();
     tags.insert("process_str", to_string(&event.comm));
-    tags.insert("process_id", event.pid.to_string());
-    tags.insert("d_ip", ip_to_string(&event.daddr));
-    tags.insert("s_ip", ip_to_string(&event.saddr));
-    tags.insert("d_port", to_le(event.dport as u16).to_string());
-    tags.insert("s_port", to_le(event.sport as u16).to_string());
+    tags.insert("process_id", event.pid);
+    tags.insert("d_ip", ip_addr(&event.daddr));
+    tags.insert("s_ip", ip_addr(&event.saddr));
+    tags.insert("ip_version", ip_version(&event.daddr));
+    tags.insert("d_port", to_le(event.dport as u16));
+    // skc_num is kept in host byte order by the kernel
+    tags.insert("s_port", event.sport);
+    if event.cookie != 0 {
+        tags.insert("socket_cookie", event.cookie);
+    }
+    netns_tags(event, &mut tags);
 
     tags
 }
 
-fn ip_to_string(addr: &Ipv6Addr) -> String {
+fn socket_owner(conn: &Connection) -> Option<SocketOwner> {
+    Some(SocketOwner {
+        cookie: conn.cookie,
+        pid: conn.pid,
+        comm: to_string(&conn.comm),
+        proto: proto_name(conn.typ)?,
+        // skc_num is kept in host byte order, skc_dport in network order
+        local: SocketAddr::new(ip_addr(&conn.saddr), conn.sport as u16),
+        remote: SocketAddr::new(ip_addr(&conn.daddr), to_le(conn.dport as u16)),
+        seen: conn.ts,
+    })
+}
+
+pub(crate) fn netns_tags(event: &Connection, tags: &mut Tags) {
+    if event.netns != 0 {
+        tags.insert("netns", event.netns);
+    }
+    if let Some(iface) = netns::interface_name(event.ifindex) {
+        tags.insert("interface", iface);
+    }
+}
+
+pub(crate) fn proto_name(typ: u32) -> Option<&'static str> {
+    match typ {
+        IPPROTO_TCP => Some("tcp"),
+        IPPROTO_UDP => Some("udp"),
+        _ => None,
+    }
+}
+
+/// Sockets of either family keep IPv4 addresses mapped into IPv6 ones
+pub(crate) fn ip_version(addr: &Ipv6Addr) -> &'static str {
+    match ip_addr(addr) {
+        std::net::IpAddr::V4(_) => "4",
+        std::net::IpAddr::V6(_) => "6",
+    }
+}
+
+pub(crate) fn ip_addr(addr: &Ipv6Addr) -> std::net::IpAddr {
     let v6: &std::net::Ipv6Addr = unsafe { std::mem::transmute(addr) };
 
-    match v6.to_ipv4() {
-        Some(v4) => v4.to_string(),
-        None => v6.to_string(),
+    to_ip_addr(*v6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_duration() {
+        let conn: Connection = unsafe { std::mem::zeroed() };
+        let measurement = tcp_measurement(TcpEvent::Close(conn, 1_500_000));
+        assert_eq!(measurement.name, "connection.duration");
+        assert_eq!(measurement.value, Unit::Duration(1_500_000));
     }
 }
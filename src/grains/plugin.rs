@@ -0,0 +1,298 @@
+//! Grains implemented by external processes.
+//!
+//! Plugins send measurements to ingraind as JSON objects, one per line:
+//!
+//! ```text
+//! {"name": "queue.depth", "value": 12, "unit": "gauge", "kind": "gauge", "tags": {"queue": "jobs"}}
+//! ```
+//!
+//! * `value` is an integer, a float for gauges, or a string
+//! * `unit` is one of `count` (default), `byte`, `gauge`, `duration` or `string`
+//! * `kind` is one or more of the aggregation kinds, separated by `|`, and
+//!   defaults to `counter`
+//! * `timestamp`, in nanoseconds since the UNIX epoch, defaults to the time
+//!   the measurement is received
+//!
+//! A plugin is either started by ingraind with `command` and writes to its
+//! standard output, in which case it is restarted if it exits, or connects
+//! to the unix socket at `socket`. Measurements are tagged with the name of
+//! the plugin as `plugin`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use actix::{Actor, AsyncContext, Context, Recipient, StreamHandler};
+use failure::{bail, format_err, Error};
+use futures::sync::mpsc::{self, UnboundedSender};
+
+use crate::backends::Message;
+use crate::grains::SendToManyRecipients;
+use crate::metrics::{kind, timestamp_now, Measurement, Tags, Unit};
+
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PluginConfig {
+    pub name: String,
+    /// The plugin's command and arguments, if ingraind starts it
+    pub command: Option<Vec<String>>,
+    /// The unix socket plugins connect to
+    pub socket: Option<PathBuf>,
+}
+
+impl PluginConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        match (&self.command, &self.socket) {
+            (None, None) => bail!("Plugin {}: command or socket must be set", self.name),
+            (Some(command), _) if command.is_empty() => {
+                bail!("Plugin {}: command must not be empty", self.name)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum PluginValue {
+    Integer(u64),
+    Float(f64),
+    Str(String),
+}
+
+/// A measurement as sent by a plugin
+#[derive(Deserialize, Debug)]
+struct PluginMeasurement {
+    name: String,
+    value: PluginValue,
+    #[serde(default = "default_unit")]
+    unit: String,
+    #[serde(default = "default_kind")]
+    kind: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+    timestamp: Option<u64>,
+}
+
+fn default_unit() -> String {
+    "count".to_string()
+}
+
+fn default_kind() -> String {
+    "counter".to_string()
+}
+
+/// Parses a line sent by plugin `plugin`
+pub fn parse_measurement(plugin: &str, line: &str) -> Result<Measurement, Error> {
+    let PluginMeasurement {
+        name,
+        value,
+        unit,
+        kind: kinds,
+        tags: plugin_tags,
+        timestamp,
+    } = serde_json::from_str(line)?;
+
+    let mut kind = 0;
+    for k in kinds.split('|') {
+        kind |= kind::try_from_str(k.trim()).map_err(|_| format_err!("invalid kind: {}", k))?;
+    }
+
+    let value = match (unit.to_lowercase().as_str(), value) {
+        ("gauge", PluginValue::Float(x)) => Unit::Gauge(x),
+        ("gauge", PluginValue::Integer(x)) => Unit::Gauge(x as f64),
+        ("string", PluginValue::Str(s)) => Unit::Str(s),
+        (u, PluginValue::Integer(x)) => Unit::try_from_str(u, x)
+            .map_err(|_| format_err!("invalid unit for {}: {}", name, unit))?,
+        _ => return Err(format_err!("invalid {} value for {}", unit, name)),
+    };
+
+    let mut tags = Tags::new();
+    let mut plugin_tags = plugin_tags.into_iter().collect::<Vec<_>>();
+    plugin_tags.sort();
+    for (k, v) in plugin_tags {
+        tags.insert(k, v);
+    }
+    tags.insert("plugin", plugin);
+
+    Ok(Measurement::with_timestamp(
+        timestamp.unwrap_or_else(timestamp_now),
+        kind,
+        name,
+        value,
+        tags,
+    ))
+}
+
+pub struct Plugin {
+    config: PluginConfig,
+    recipients: Vec<Recipient<Message>>,
+}
+
+impl Plugin {
+    pub fn with_config(config: PluginConfig, recipients: Vec<Recipient<Message>>) -> Self {
+        Plugin { config, recipients }
+    }
+}
+
+impl Actor for Plugin {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let (tx, rx) = mpsc::unbounded();
+        ctx.add_stream(rx);
+
+        if let Some(ref command) = self.config.command {
+            let name = self.config.name.clone();
+            let command = command.clone();
+            let tx = tx.clone();
+            thread::spawn(move || run_command(&name, &command, &tx));
+        }
+
+        if let Some(ref path) = self.config.socket {
+            let _ = fs::remove_file(path);
+            let listener = match UnixListener::bind(path) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Failed to listen for plugins on {:?}: {}", path, e);
+                    return;
+                }
+            };
+            if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+                warn!("Failed to restrict access to {:?}: {}", path, e);
+            }
+
+            let name = self.config.name.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let name = name.clone();
+                            let tx = tx.clone();
+                            thread::spawn(move || read_measurements(&name, stream, &tx));
+                        }
+                        Err(e) => warn!("Plugin {} connection failed: {}", name, e),
+                    }
+                }
+            });
+        }
+    }
+}
+
+impl StreamHandler<Measurement, ()> for Plugin {
+    fn handle(&mut self, measurement: Measurement, _ctx: &mut Context<Self>) {
+        self.recipients.do_send(Message::Single(measurement));
+    }
+}
+
+/// Runs the plugin's command, and restarts it whenever it exits
+fn run_command(name: &str, command: &[String], tx: &UnboundedSender<Measurement>) {
+    loop {
+        let child = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn();
+
+        match child {
+            Ok(mut child) => {
+                info!("Started plugin {}", name);
+                read_measurements(name, child.stdout.take().unwrap(), tx);
+                let status = child.wait();
+                warn!("Plugin {} exited: {:?}", name, status);
+            }
+            Err(e) => error!("Failed to start plugin {}: {}", name, e),
+        }
+
+        if tx.is_closed() {
+            return;
+        }
+        thread::sleep(RESTART_DELAY);
+    }
+}
+
+fn read_measurements(name: &str, input: impl Read, tx: &UnboundedSender<Measurement>) {
+    for line in BufReader::new(input).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Plugin {} read error: {}", name, e);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_measurement(name, &line) {
+            Ok(m) => {
+                if tx.unbounded_send(m).is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!("Invalid measurement from plugin {}: {}", name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::kind::{COUNTER, GAUGE, HISTOGRAM};
+
+    #[test]
+    fn parse() {
+        let m = parse_measurement(
+            "queues",
+            r#"{"name": "queue.depth", "value": 1.5, "unit": "gauge", "kind": "gauge", "tags": {"queue": "jobs"}}"#,
+        )
+        .unwrap();
+        assert_eq!(m.name, "queue.depth");
+        assert_eq!(m.kind, GAUGE);
+        assert_eq!(m.value, Unit::Gauge(1.5));
+        assert_eq!(m.tags.get("queue"), Some("jobs"));
+        assert_eq!(m.tags.get("plugin"), Some("queues"));
+
+        let m = parse_measurement(
+            "queues",
+            r#"{"name": "jobs", "value": 3, "kind": "counter|histogram", "timestamp": 10}"#,
+        )
+        .unwrap();
+        assert_eq!(m.kind, COUNTER | HISTOGRAM);
+        assert_eq!(m.value, Unit::Count(3));
+        assert_eq!(m.timestamp, 10);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse_measurement("p", r#"{"name": "x", "value": "a"}"#).is_err());
+        assert!(parse_measurement("p", r#"{"name": "x", "value": 1, "kind": "bogus"}"#).is_err());
+        assert!(parse_measurement("p", r#"{"name": "x", "value": 1, "unit": "parsec"}"#).is_err());
+        assert!(parse_measurement("p", "not json").is_err());
+    }
+
+    #[test]
+    fn check() {
+        let config = |command: Option<Vec<String>>, socket: Option<PathBuf>| PluginConfig {
+            name: "queues".to_string(),
+            command,
+            socket,
+        };
+
+        assert!(config(None, None).check().is_err());
+        assert!(config(Some(vec![]), None).check().is_err());
+        assert!(config(Some(vec!["queues".to_string()]), None)
+            .check()
+            .is_ok());
+        assert!(config(None, Some(PathBuf::from("/run/queues.sock")))
+            .check()
+            .is_ok());
+    }
+}
@@ -0,0 +1,241 @@
+//! Probes loaded from files at runtime, instead of the ones built into
+//! ingraind.
+//!
+//! The directory holds a `manifest.toml` listing the file of each grain it
+//! replaces:
+//!
+//! ```text
+//! ingraind_version = "1.0.0"
+//!
+//! [[probe]]
+//! grain = "Network"
+//! file = "network.elf"
+//! ```
+//!
+//! The probes must be built from the same version of `ingraind-probes` as
+//! ingraind, carry a GPL compatible license, and target either any kernel
+//! or the running one. They must also have every program and map of the
+//! probe they replace, which is checked with the config.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use failure::{format_err, Error};
+use goblin::elf::Elf;
+use lazy_static::lazy_static;
+use redbpf::uname::get_kernel_internal_version;
+
+use crate::grains::meta::{self, Meta};
+use crate::grains::{probes, program_names};
+
+const MANIFEST: &str = "manifest.toml";
+
+/// The version of probes that can be loaded on any kernel
+const ANY_KERNEL: u32 = 0xFFFF_FFFE;
+
+/// The licenses the kernel considers GPL compatible
+const GPL_COMPATIBLE: &[&str] = &[
+    "GPL",
+    "GPL v2",
+    "GPL and additional rights",
+    "Dual BSD/GPL",
+    "Dual MIT/GPL",
+    "Dual MPL/GPL",
+];
+
+lazy_static! {
+    static ref PROBES: RwLock<HashMap<String, Prebuilt<&'static [u8]>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// A probe replacing the one built in for `grain`, with the requirements
+/// in its `meta/` sections
+pub struct Prebuilt<C> {
+    pub grain: String,
+    pub code: C,
+    pub metas: Vec<Meta>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrebuiltConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    ingraind_version: String,
+    #[serde(rename = "probe", default)]
+    probes: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ManifestEntry {
+    grain: String,
+    file: PathBuf,
+}
+
+/// The code loaded for `grain`, if any
+pub fn code(grain: &str) -> Option<&'static [u8]> {
+    PROBES.read().unwrap().get(grain).map(|probe| probe.code)
+}
+
+/// The requirements of the probe loaded from `code`, if it's one
+pub fn metas(code: &[u8]) -> Option<Vec<Meta>> {
+    let probes = PROBES.read().unwrap();
+    let probe = probes.values().find(|p| std::ptr::eq(p.code, code))?;
+    Some(probe.metas.clone())
+}
+
+/// Reads and checks the probes listed in the manifest of `config.path`,
+/// without loading them, and returns the probe of each grain they replace
+pub fn read(config: &PrebuiltConfig) -> Result<Vec<Prebuilt<Vec<u8>>>, Error> {
+    let manifest = fs::read_to_string(config.path.join(MANIFEST))?;
+    let manifest = parse_manifest(&manifest)?;
+    let kernel = get_kernel_internal_version().unwrap_or(0);
+
+    manifest
+        .probes
+        .into_iter()
+        .map(|entry| {
+            let (code, metas) = read_probe(&config.path.join(&entry.file), kernel)
+                .map_err(|e| format_err!("{} probe {:?}: {}", entry.grain, entry.file, e))?;
+            Ok(Prebuilt {
+                grain: entry.grain,
+                code,
+                metas,
+            })
+        })
+        .collect()
+}
+
+/// Reads the probes like `read`, and loads them so grains use them instead
+/// of the built in ones. Returns the grains they replace.
+pub fn load(config: &PrebuiltConfig) -> Result<Vec<String>, Error> {
+    let mut grains = vec![];
+    for Prebuilt { grain, code, metas } in read(config)? {
+        info!("Using a prebuilt probe for {}", grain);
+        // probes are loaded for the lifetime of ingraind
+        let code: &'static [u8] = Box::leak(code.into_boxed_slice());
+        let probe = Prebuilt {
+            grain: grain.clone(),
+            code,
+            metas,
+        };
+        PROBES.write().unwrap().insert(grain.clone(), probe);
+        grains.push(grain);
+    }
+
+    Ok(grains)
+}
+
+/// Fails if `code` lacks a program or map of the probe built into
+/// `bundled`, which the grain would look for
+pub fn check_replaces(bundled: &[u8], code: &[u8]) -> Result<(), Error> {
+    let probe = match probes::find(bundled) {
+        Some(probe) => probe,
+        None => return Ok(()),
+    };
+
+    let elf = Elf::parse(code)?;
+    let sections = elf
+        .section_headers
+        .iter()
+        .filter_map(|sh| elf.shdr_strtab.get(sh.sh_name).and_then(|n| n.ok()))
+        .collect::<Vec<_>>();
+
+    for (kind, name) in probe.programs.iter() {
+        let section = format!("{}/{}", kind, name);
+        if !sections.contains(&section.as_str()) {
+            return Err(format_err!("missing the program {}", section));
+        }
+    }
+    for (name, _) in probe.maps.iter() {
+        let section = format!("maps/{}", name);
+        if !sections.contains(&section.as_str()) {
+            return Err(format_err!("missing the map {}", name));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_manifest(manifest: &str) -> Result<Manifest, Error> {
+    let manifest: Manifest = toml::from_str(manifest)?;
+    if manifest.ingraind_version != env!("CARGO_PKG_VERSION") {
+        return Err(format_err!(
+            "probes are built for ingraind {}, this is {}",
+            manifest.ingraind_version,
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+
+    Ok(manifest)
+}
+
+fn read_probe(path: &Path, kernel: u32) -> Result<(Vec<u8>, Vec<Meta>), Error> {
+    let code = fs::read(path)?;
+    program_names(&code)?;
+
+    let elf = Elf::parse(&code)?;
+    let section = |name: &str| {
+        elf.section_headers
+            .iter()
+            .find(|sh| elf.shdr_strtab.get(sh.sh_name).and_then(|n| n.ok()) == Some(name))
+            .and_then(|sh| code.get(sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize))
+    };
+
+    let license = section("license").unwrap_or_default();
+    let license = String::from_utf8_lossy(license);
+    let license = license.trim_end_matches('\0');
+    if !GPL_COMPATIBLE.contains(&license) {
+        return Err(format_err!("license {:?} is not GPL compatible", license));
+    }
+
+    let version = match section("version") {
+        Some(v) if v.len() == 4 => u32::from_le_bytes([v[0], v[1], v[2], v[3]]),
+        _ => return Err(format_err!("missing version section")),
+    };
+    if version != ANY_KERNEL && version != kernel {
+        return Err(format_err!(
+            "built for kernel {:#x}, running {:#x}",
+            version,
+            kernel
+        ));
+    }
+
+    let metas = meta::read(&elf, &code)?;
+    Ok((code, metas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let manifest = parse_manifest(&format!(
+            r#"
+ingraind_version = "{}"
+
+[[probe]]
+grain = "Network"
+file = "network.elf"
+"#,
+            env!("CARGO_PKG_VERSION")
+        ))
+        .unwrap();
+        assert_eq!(manifest.probes.len(), 1);
+        assert_eq!(manifest.probes[0].grain, "Network");
+
+        assert!(parse_manifest("ingraind_version = \"0.0.1\"").is_err());
+    }
+
+    #[test]
+    fn replaces() {
+        let tty = probes::tty::CODE;
+        assert!(check_replaces(tty, tty).is_ok());
+        assert!(check_replaces(tty, probes::tcp_latency::CODE).is_err());
+    }
+}
@@ -0,0 +1,15 @@
+//! The probes ingraind is built with.
+//!
+//! Generated by `build.rs` from the sources of `ingraind-probes`, with a
+//! module for every probe holding its `CODE`, its `PROGRAMS`, its `MAPS`
+//! along with the `EVENTS` of the perf maps, and the `META` requirements it
+//! declares. Grains refer to the programs and maps of their probe through
+//! the constants of `programs` and `maps`, and to the maps shared by probes
+//! through `common::maps`, so renaming or removing one in a probe fails the
+//! build of the grain. So does an event type that isn't in
+//! `ingraind_probes`.
+//!
+//! `PROBES` lists them all, and `find` the one built into some code, to
+//! check the prebuilt probes replacing it have its programs and maps.
+
+include!(concat!(env!("OUT_DIR"), "/probes.rs"));
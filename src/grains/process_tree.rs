@@ -0,0 +1,471 @@
+//! A table of the processes running on the host, and their ancestors.
+//!
+//! The table is seeded from `/proc` when the `ProcessTree` grain is loaded,
+//! and kept up to date by its fork, exec and exit probes. Processes that
+//! aren't in the table are looked up in `/proc` when they're asked for, so
+//! lookups work without the grain, only less reliably for short-lived
+//! processes.
+//!
+//! Exited processes are kept for a while, so the events they sent before
+//! exiting can still be attributed. The table holds at most
+//! `MAX_PROCESSES`, and once it's full, new processes are only looked up in
+//! `/proc`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use lazy_static::lazy_static;
+use redbpf::Module;
+
+use crate::aggregations::docker_id;
+use crate::grains::*;
+
+use ingraind_probes::process_tree::ProcessEvent;
+
+unsafe impl FromRawEvent for ProcessEvent {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 3)
+    }
+}
+
+const EXITED_TTL: Duration = Duration::from_secs(30);
+const MAX_PROCESSES: usize = 65_536;
+
+lazy_static! {
+    static ref TABLE: RwLock<ProcessTable> = RwLock::new(ProcessTable::default());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    pub comm: String,
+    /// The executable the process was started from
+    pub exe: Option<String>,
+    /// The real uid of the process
+    pub uid: Option<u32>,
+    /// The Docker container the process runs in
+    pub container: Option<String>,
+    /// The paths of the cgroups of the process
+    pub cgroups: Vec<String>,
+}
+
+#[derive(Default)]
+struct ProcessTable {
+    processes: HashMap<u32, ProcessInfo>,
+    exited: VecDeque<(Instant, u32)>,
+}
+
+impl ProcessTable {
+    /// Adds a process, making room by expiring the oldest exited one if the
+    /// table is full, or dropping it if none has exited
+    fn insert(&mut self, info: ProcessInfo) {
+        if self.processes.len() >= MAX_PROCESSES && !self.processes.contains_key(&info.pid) {
+            match self.exited.pop_front() {
+                Some((_, pid)) => {
+                    self.processes.remove(&pid);
+                }
+                None => return,
+            }
+        }
+
+        // the pid of an exited process may be reused before it's removed
+        self.exited.retain(|&(_, exited)| exited != info.pid);
+        self.processes.insert(info.pid, info);
+    }
+
+    /// Adds a forked process like its parent, and returns whether the
+    /// parent is known
+    fn fork(&mut self, pid: u32, ppid: u32) -> bool {
+        let info = match self.processes.get(&ppid) {
+            Some(parent) => ProcessInfo {
+                pid,
+                ppid,
+                ..parent.clone()
+            },
+            None => return false,
+        };
+        self.insert(info);
+
+        true
+    }
+
+    /// Updates the program of a process, along with the uid and cgroups it
+    /// may have changed before, and returns whether it's known
+    fn exec(&mut self, pid: u32, comm: String, exe: Option<String>, owner: Owner) -> bool {
+        match self.processes.get_mut(&pid) {
+            Some(info) => {
+                info.comm = comm;
+                info.exe = exe;
+                // unless the process exited before /proc was read
+                if owner.uid.is_some() {
+                    info.uid = owner.uid;
+                    info.cgroups = owner.cgroups;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn exit(&mut self, pid: u32, now: Instant) {
+        self.exited.push_back((now, pid));
+        while let Some(&(exited, pid)) = self.exited.front() {
+            if now.duration_since(exited) < EXITED_TTL {
+                break;
+            }
+            self.exited.pop_front();
+            self.processes.remove(&pid);
+        }
+    }
+
+    /// Adds the `running` processes missing from the table, and expires the
+    /// ones that aren't running anymore, as if they had just exited.
+    /// Returns how many were added and expired.
+    ///
+    /// `running` is read before the table is locked, so the processes
+    /// forked since are missing from it, and are only expired if they're
+    /// not `alive`.
+    fn reconcile(
+        &mut self,
+        running: Vec<ProcessInfo>,
+        alive: impl Fn(u32) -> bool,
+        now: Instant,
+    ) -> (usize, usize) {
+        let pids = running.iter().map(|p| p.pid).collect::<HashSet<_>>();
+        let exited = self
+            .exited
+            .iter()
+            .map(|&(_, pid)| pid)
+            .collect::<HashSet<_>>();
+        let stale = self
+            .processes
+            .keys()
+            .filter(|pid| !pids.contains(pid) && !exited.contains(pid))
+            .cloned()
+            .filter(|&pid| !alive(pid))
+            .collect::<Vec<_>>();
+        for pid in stale.iter() {
+            self.exit(*pid, now);
+        }
+
+        let mut added = 0;
+        for info in running {
+            if !self.processes.contains_key(&info.pid) {
+                self.insert(info);
+                added += 1;
+            }
+        }
+
+        (added, stale.len())
+    }
+
+    fn ancestors(&self, pid: u32, max_depth: usize) -> Vec<ProcessInfo> {
+        let mut ancestors = vec![];
+        let mut pid = pid;
+        while ancestors.len() < max_depth {
+            let parent = match self.processes.get(&pid) {
+                Some(info) if info.ppid != 0 && info.ppid != pid => info.ppid,
+                _ => break,
+            };
+            match self.processes.get(&parent) {
+                Some(info) => ancestors.push(info.clone()),
+                None => break,
+            }
+            pid = parent;
+        }
+
+        ancestors
+    }
+}
+
+/// Adds every process in `/proc` to the table
+pub fn seed() {
+    let running = running();
+    let mut table = TABLE.write().unwrap();
+    for info in running {
+        table.insert(info);
+    }
+}
+
+/// The processes in `/proc`
+pub fn running() -> Vec<ProcessInfo> {
+    match fs::read_dir("/proc") {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse::<u32>().ok())
+            .filter_map(read_proc)
+            .collect(),
+        Err(e) => {
+            warn!("Failed to read /proc: {}", e);
+            vec![]
+        }
+    }
+}
+
+/// Makes the table match the `running` processes, healing forks and exits
+/// that were missed. Returns how many processes were added and expired.
+pub fn reconcile(running: Vec<ProcessInfo>) -> (usize, usize) {
+    let alive = |pid| Path::new(&format!("/proc/{}", pid)).exists();
+    TABLE
+        .write()
+        .unwrap()
+        .reconcile(running, alive, Instant::now())
+}
+
+/// The process `pid`
+pub fn get(pid: u32) -> Option<ProcessInfo> {
+    if let Some(info) = TABLE.read().unwrap().processes.get(&pid) {
+        return Some(info.clone());
+    }
+
+    let info = read_proc(pid)?;
+    TABLE.write().unwrap().insert(info.clone());
+    Some(info)
+}
+
+/// The parent of `pid`, its parent, and so on, up to `max_depth` processes
+pub fn ancestors(pid: u32, max_depth: usize) -> Vec<ProcessInfo> {
+    // make sure the chain is in the table
+    let mut next = get(pid);
+    for _ in 0..max_depth {
+        next = match next {
+            Some(ref info) if info.ppid != 0 => get(info.ppid),
+            _ => break,
+        };
+    }
+
+    TABLE.read().unwrap().ancestors(pid, max_depth)
+}
+
+fn read_proc(pid: u32) -> Option<ProcessInfo> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (comm, ppid) = parse_stat(&stat)?;
+    let owner = read_owner(pid);
+
+    Some(ProcessInfo {
+        pid,
+        ppid,
+        comm,
+        exe: read_exe(pid),
+        uid: owner.uid,
+        container: docker_id(pid),
+        cgroups: owner.cgroups,
+    })
+}
+
+/// Who a process runs as, and where
+#[derive(Default)]
+struct Owner {
+    uid: Option<u32>,
+    cgroups: Vec<String>,
+}
+
+fn read_owner(pid: u32) -> Owner {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid)).unwrap_or_default();
+
+    Owner {
+        uid: status_uid(&status),
+        cgroups: cgroup_paths(&cgroup),
+    }
+}
+
+/// The real uid in a `/proc/<pid>/status` file
+pub fn status_uid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find(|line| line.starts_with("Uid:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// The paths in a `/proc/<pid>/cgroup` file
+pub fn cgroup_paths(cgroup: &str) -> Vec<String> {
+    cgroup
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .map(String::from)
+        .collect()
+}
+
+fn read_exe(pid: u32) -> Option<String> {
+    fs::read_link(format!("/proc/{}/exe", pid))
+        .ok()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+/// The `comm` and parent pid from the contents of `/proc/<pid>/stat`
+fn parse_stat(stat: &str) -> Option<(String, u32)> {
+    // the comm is in parentheses, and may contain both spaces and parentheses
+    let start = stat.find('(')?;
+    let end = stat.rfind(')')?;
+    let comm = stat.get(start + 1..end)?.to_string();
+    let ppid = stat
+        .get(end + 1..)?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()?;
+
+    Some((comm, ppid))
+}
+
+pub struct ProcessTree;
+
+impl EBPFProbe for Grain<ProcessTree> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for ProcessTree {
+    fn code() -> &'static [u8] {
+        probes::process_tree::CODE
+    }
+
+    fn loaded(&mut self, _module: &mut Module) -> Result<(), Error> {
+        seed();
+
+        Ok(())
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        // `/proc` is only read without holding the table
+        decoded(|event: ProcessEvent| {
+            let pid = match event {
+                ProcessEvent::Fork { pid, ppid } => {
+                    if TABLE.write().unwrap().fork(pid, ppid) {
+                        return None;
+                    }
+                    pid
+                }
+                ProcessEvent::Exec { pid, comm } => {
+                    let exe = read_exe(pid);
+                    let owner = read_owner(pid);
+                    let comm = to_string(&comm);
+                    if TABLE.write().unwrap().exec(pid, comm, exe, owner) {
+                        return None;
+                    }
+                    pid
+                }
+                ProcessEvent::Exit { pid } => {
+                    TABLE.write().unwrap().exit(pid, Instant::now());
+                    return None;
+                }
+            };
+
+            if let Some(info) = read_proc(pid) {
+                TABLE.write().unwrap().insert(info);
+            }
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, ppid: u32, comm: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid,
+            comm: comm.to_string(),
+            exe: None,
+            uid: None,
+            container: None,
+            cgroups: vec![],
+        }
+    }
+
+    #[test]
+    fn stat() {
+        assert_eq!(
+            parse_stat("42 (a (b) c) S 1 42 42 0 -1"),
+            Some(("a (b) c".to_string(), 1))
+        );
+        assert_eq!(parse_stat("42 (sh"), None);
+        assert_eq!(
+            status_uid("Name:\tbash\nUid:\t1000\t1000\t1000\t1000\n"),
+            Some(1000)
+        );
+    }
+
+    #[test]
+    fn lineage() {
+        let mut table = ProcessTable::default();
+        table.processes.insert(1, process(1, 0, "systemd"));
+        table.processes.insert(10, process(10, 1, "sshd"));
+
+        assert!(table.fork(20, 10));
+        let owner = Owner {
+            uid: Some(1000),
+            cgroups: vec![],
+        };
+        assert!(table.exec(20, "bash".to_string(), Some("/bin/bash".to_string()), owner));
+        assert!(table.fork(30, 20));
+        assert!(!table.fork(40, 99));
+
+        let ancestors = table.ancestors(30, 8);
+        let comms = ancestors
+            .iter()
+            .map(|p| p.comm.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(comms, vec!["bash", "sshd", "systemd"]);
+        assert_eq!(ancestors[0].exe, Some("/bin/bash".to_string()));
+        assert_eq!(ancestors[0].uid, Some(1000));
+        assert_eq!(table.ancestors(30, 1).len(), 1);
+
+        let now = Instant::now();
+        table.exit(20, now);
+        assert_eq!(table.ancestors(30, 8).len(), 3);
+        table.exit(30, now + EXITED_TTL);
+        assert!(table.processes.get(&20).is_none());
+        assert!(table.processes.get(&30).is_some());
+    }
+
+    #[test]
+    fn reconcile() {
+        let mut table = ProcessTable::default();
+        table.processes.insert(1, process(1, 0, "systemd"));
+        table.processes.insert(10, process(10, 1, "sshd"));
+
+        let now = Instant::now();
+        let running = vec![process(1, 0, "systemd"), process(20, 1, "cron")];
+        assert_eq!(table.reconcile(running.clone(), |_| false, now), (1, 1));
+        assert_eq!(table.processes.get(&20), Some(&running[1]));
+        // expired processes are kept for a while, and not expired again
+        assert!(table.processes.get(&10).is_some());
+        assert_eq!(table.reconcile(running.clone(), |_| false, now), (0, 0));
+
+        // forked after /proc was read
+        table.fork(30, 20);
+        assert_eq!(table.reconcile(running, |pid| pid == 30, now), (0, 0));
+        assert!(table.exited.iter().all(|&(_, pid)| pid != 30));
+
+        table.exit(1, now + EXITED_TTL);
+        assert!(table.processes.get(&10).is_none());
+    }
+
+    #[test]
+    fn bounded() {
+        let mut table = ProcessTable::default();
+        for pid in 0..MAX_PROCESSES as u32 {
+            table.insert(process(pid, 0, "sh"));
+        }
+        table.insert(process(u32::MAX, 0, "sh"));
+        assert!(table.processes.get(&u32::MAX).is_none());
+
+        table.exit(1, Instant::now());
+        table.insert(process(u32::MAX, 0, "sh"));
+        assert!(table.processes.get(&u32::MAX).is_some());
+        assert!(table.processes.get(&1).is_none());
+        assert_eq!(table.processes.len(), MAX_PROCESSES);
+    }
+}
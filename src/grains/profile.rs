@@ -0,0 +1,184 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+use actix::{Actor, AsyncContext, Context, Recipient};
+use failure::{bail, format_err, Error};
+use redbpf::uname::get_kernel_internal_version;
+
+use ingraind_probes::profile::{StackKey, MAX_STACK_DEPTH};
+
+use crate::backends::Message;
+use crate::grains::loader::{Map, Module};
+use crate::grains::meta;
+use crate::grains::probes::profile::maps;
+use crate::grains::symbols::Symbolizer;
+use crate::grains::{probes, to_string, SendToManyRecipients};
+use crate::metrics::{kind, Measurement, Tags, Unit};
+
+fn default_frequency_hz() -> u64 {
+    49
+}
+
+fn default_interval_s() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileConfig {
+    #[serde(default = "default_frequency_hz")]
+    frequency_hz: u64,
+    #[serde(default = "default_interval_s")]
+    interval_s: u64,
+}
+
+/// Samples the stacks running on every CPU, and reports how many samples
+/// were taken of each as folded stacks, ready for flamegraphs.
+pub struct Profile {
+    config: ProfileConfig,
+    recipients: Vec<Recipient<Message>>,
+    module: Module,
+    symbolizer: Symbolizer,
+    /// The counts reported so far of the stacks in the `counts` map
+    reported: HashMap<Vec<u8>, u64>,
+}
+
+impl Profile {
+    pub fn with_config(
+        config: ProfileConfig,
+        recipients: Vec<Recipient<Message>>,
+    ) -> Result<Self, Error> {
+        if config.frequency_hz == 0 || config.interval_s == 0 {
+            bail!("Profile: frequency_hz and interval_s must be greater than 0");
+        }
+
+        if let Some(kernel) = get_kernel_internal_version() {
+            meta::check(&meta::of(probes::profile::CODE)?, kernel)
+                .map_err(|e| format_err!("Profile: {}", e))?;
+        }
+
+        let mut module = Module::load(probes::profile::CODE)?;
+        module.attach_sampling(config.frequency_hz)?;
+
+        Ok(Profile {
+            config,
+            recipients,
+            module,
+            symbolizer: Symbolizer::load(),
+            reported: HashMap::new(),
+        })
+    }
+
+    fn scrape(&mut self) {
+        let counts = self.module.map(maps::COUNTS);
+        let stacks = self.module.map(maps::STACKS);
+        let (counts, stacks) = match (counts, stacks) {
+            (Some(counts), Some(stacks)) => (counts, stacks),
+            _ => return,
+        };
+
+        let mut measurements = vec![];
+        let mut live = HashSet::new();
+        let mut dead = HashSet::new();
+        for key in counts.keys() {
+            let count = match counts.get(&key) {
+                Some(count) => read_u64(&count, 0),
+                None => continue,
+            };
+            let stack = unsafe { ptr::read_unaligned(key.as_ptr() as *const StackKey) };
+            let reported = self.reported.insert(key.clone(), count).unwrap_or(0);
+
+            // the kernel has stopped counting the stacks of processes that
+            // exited, and they're reported one last time
+            let exited = !Path::new(&format!("/proc/{}", stack.pid)).exists();
+            if exited {
+                counts.delete(&key);
+                self.reported.remove(&key);
+                dead.extend(&[stack.user_stack, stack.kernel_stack]);
+            } else {
+                live.extend(&[stack.user_stack, stack.kernel_stack]);
+            }
+
+            if count > reported {
+                let folded = fold(&mut self.symbolizer, stacks, &stack);
+                let mut tags = Tags::new();
+                tags.insert("process_id", stack.pid);
+                tags.insert("process_str", to_string(&stack.comm));
+                tags.insert("stack", folded);
+
+                measurements.push(Measurement::new(
+                    kind::COUNTER,
+                    "profile.samples".to_string(),
+                    Unit::Count(count - reported),
+                    tags,
+                ));
+            }
+        }
+
+        // stack ids are shared by every process that ran the same stack
+        for id in dead.difference(&live).filter(|&&id| id >= 0) {
+            stacks.delete(&id.to_ne_bytes());
+        }
+        self.symbolizer.scraped();
+
+        if !measurements.is_empty() {
+            self.recipients.do_send(Message::List(measurements));
+        }
+    }
+}
+
+impl Actor for Profile {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(
+            "profile started, sampling at {}Hz",
+            self.config.frequency_hz
+        );
+        ctx.run_interval(Duration::from_secs(self.config.interval_s), |act, _| {
+            act.scrape()
+        });
+    }
+}
+
+fn read_u64(buf: &[u8], i: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[i * 8..i * 8 + 8]);
+    u64::from_ne_bytes(bytes)
+}
+
+/// The instruction pointers of stack `id`, from the outermost frame in
+fn frames(stacks: &Map, id: i32) -> Vec<u64> {
+    if id < 0 {
+        return vec![];
+    }
+    let stack = match stacks.get(&id.to_ne_bytes()) {
+        Some(stack) => stack,
+        None => return vec![],
+    };
+
+    let mut ips = (0..MAX_STACK_DEPTH)
+        .map(|i| read_u64(&stack, i))
+        .take_while(|&ip| ip != 0)
+        .collect::<Vec<_>>();
+    ips.reverse();
+    ips
+}
+
+/// `comm;user frames;kernel frames`, from the outermost frame in, with
+/// kernel functions suffixed with `_[k]` the way flamegraph.pl colours them
+fn fold(symbolizer: &mut Symbolizer, stacks: &Map, stack: &StackKey) -> String {
+    let mut folded = to_string(&stack.comm);
+    for ip in frames(stacks, stack.user_stack) {
+        folded.push(';');
+        folded.push_str(symbolizer.user(stack.pid, ip).unwrap_or("[unknown]"));
+    }
+    for ip in frames(stacks, stack.kernel_stack) {
+        folded.push(';');
+        folded.push_str(symbolizer.kernel(ip).unwrap_or("[unknown]"));
+        folded.push_str("_[k]");
+    }
+
+    folded
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use crate::grains::DecodeError;
+
+const ARP_LEN: usize = 28;
+
+const HTYPE_ETHER: u16 = 1;
+const PTYPE_IPV4: u16 = 0x0800;
+
+pub const ARPOP_REPLY: u16 = 2;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+impl std::str::FromStr for MacAddr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<MacAddr, ()> {
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(':');
+        for b in mac.iter_mut() {
+            *b = u8::from_str_radix(parts.next().ok_or(())?, 16).map_err(|_| ())?;
+        }
+
+        if parts.next().is_some() {
+            return Err(());
+        }
+
+        Ok(MacAddr(mac))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Arp {
+    pub oper: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Arp {
+    /// Gratuitous ARP announces the sender's own address, either as a
+    /// request or as an unsolicited reply
+    pub fn is_gratuitous(&self) -> bool {
+        self.sender_ip == self.target_ip && !self.sender_ip.is_unspecified()
+    }
+}
+
+/// Parses an ARP packet, if it's for Ethernet and IPv4
+pub fn parse(buf: &[u8]) -> Result<Option<Arp>, DecodeError> {
+    if buf.len() < ARP_LEN {
+        return Err(DecodeError::malformed("ARP packet", buf.len()));
+    }
+
+    let u16_at = |i: usize| (buf[i] as u16) << 8 | buf[i + 1] as u16;
+    if u16_at(0) != HTYPE_ETHER || u16_at(2) != PTYPE_IPV4 || buf[4] != 6 || buf[5] != 4 {
+        return Ok(None);
+    }
+
+    let mac = |i: usize| {
+        let mut m = [0u8; 6];
+        m.copy_from_slice(&buf[i..i + 6]);
+        MacAddr(m)
+    };
+    let ip = |i: usize| Ipv4Addr::new(buf[i], buf[i + 1], buf[i + 2], buf[i + 3]);
+
+    Ok(Some(Arp {
+        oper: u16_at(6),
+        sender_mac: mac(8),
+        sender_ip: ip(14),
+        target_mac: mac(18),
+        target_ip: ip(24),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reply() {
+        let buf = [
+            0, 1, 8, 0, 6, 4, 0, 2, // header
+            0xde, 0xad, 0xbe, 0xef, 0, 1, 192, 168, 1, 1, // sender
+            0xde, 0xad, 0xbe, 0xef, 0, 2, 192, 168, 1, 2, // target
+        ];
+
+        let arp = parse(&buf).unwrap().unwrap();
+        assert_eq!(arp.oper, ARPOP_REPLY);
+        assert_eq!(arp.sender_mac.to_string(), "de:ad:be:ef:00:01");
+        assert_eq!(arp.sender_ip, Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(arp.target_ip, Ipv4Addr::new(192, 168, 1, 2));
+        assert!(!arp.is_gratuitous());
+
+        assert_eq!("de:ad:be:ef:00:01".parse(), Ok(arp.sender_mac));
+        assert!(parse(&buf[..20]).is_err());
+    }
+}
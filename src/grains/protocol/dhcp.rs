@@ -0,0 +1,141 @@
+use std::net::Ipv4Addr;
+
+use crate::grains::DecodeError;
+
+const OPTIONS_OFFSET: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const BOOTREPLY: u8 = 2;
+
+const OPT_PAD: u8 = 0;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MessageType {
+    Offer,
+    Ack,
+    Nak,
+    Other(u8),
+}
+
+impl MessageType {
+    pub fn as_str(self) -> &'static str {
+        use MessageType::*;
+
+        match self {
+            Offer => "offer",
+            Ack => "ack",
+            Nak => "nak",
+            Other(_) => "other",
+        }
+    }
+}
+
+impl From<u8> for MessageType {
+    fn from(t: u8) -> MessageType {
+        match t {
+            2 => MessageType::Offer,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            t => MessageType::Other(t),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    pub message_type: MessageType,
+    pub your_ip: Ipv4Addr,
+    pub server_id: Option<Ipv4Addr>,
+    pub router: Option<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+}
+
+/// Parses a BOOTP reply carrying DHCP options, if `buf` is one
+pub fn parse_reply(buf: &[u8]) -> Result<Option<Reply>, DecodeError> {
+    let malformed = || DecodeError::malformed("DHCP reply", buf.len());
+    if buf.len() < OPTIONS_OFFSET {
+        return Err(malformed());
+    }
+    if buf[0] != BOOTREPLY || buf[236..240] != MAGIC_COOKIE {
+        return Ok(None);
+    }
+
+    let mut reply = Reply {
+        message_type: MessageType::Other(0),
+        your_ip: ipv4(&buf[16..20]),
+        server_id: None,
+        router: None,
+        dns: vec![],
+    };
+
+    let mut options = &buf[OPTIONS_OFFSET..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPT_PAD => {
+                options = rest;
+                continue;
+            }
+            OPT_END => break,
+            _ => {}
+        }
+
+        let len = *rest.first().ok_or_else(malformed)? as usize;
+        let value = rest.get(1..1 + len).ok_or_else(malformed)?;
+        options = &rest[1 + len..];
+
+        match code {
+            OPT_MESSAGE_TYPE if len == 1 => reply.message_type = value[0].into(),
+            OPT_SERVER_ID if len == 4 => reply.server_id = Some(ipv4(value)),
+            OPT_ROUTER if len >= 4 => reply.router = Some(ipv4(&value[..4])),
+            OPT_DNS => reply.dns = value.chunks_exact(4).map(ipv4).collect(),
+            _ => {}
+        }
+    }
+
+    Ok(Some(reply))
+}
+
+fn ipv4(b: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(b[0], b[1], b[2], b[3])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_offer() {
+        let mut buf = vec![0u8; OPTIONS_OFFSET];
+        buf[0] = BOOTREPLY;
+        buf[16..20].copy_from_slice(&[192, 168, 1, 100]);
+        buf[236..240].copy_from_slice(&MAGIC_COOKIE);
+        buf.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, 2, OPT_PAD]);
+        buf.extend_from_slice(&[OPT_SERVER_ID, 4, 192, 168, 1, 1]);
+        buf.extend_from_slice(&[OPT_ROUTER, 4, 192, 168, 1, 254]);
+        buf.extend_from_slice(&[OPT_DNS, 8, 1, 1, 1, 1, 8, 8, 8, 8, OPT_END]);
+
+        assert_eq!(
+            parse_reply(&buf),
+            Ok(Some(Reply {
+                message_type: MessageType::Offer,
+                your_ip: Ipv4Addr::new(192, 168, 1, 100),
+                server_id: Some(Ipv4Addr::new(192, 168, 1, 1)),
+                router: Some(Ipv4Addr::new(192, 168, 1, 254)),
+                dns: vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(8, 8, 8, 8)],
+            }))
+        );
+
+        // truncated options
+        buf.truncate(buf.len() - 5);
+        assert!(parse_reply(&buf).is_err());
+
+        // request
+        buf[0] = 1;
+        assert_eq!(parse_reply(&buf), Ok(None));
+    }
+}
@@ -1,3 +1,6 @@
+pub mod arp;
+pub mod dhcp;
 pub mod ip;
+pub mod ntp;
 
 pub const ETH_HLEN: usize = 14;
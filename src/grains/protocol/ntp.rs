@@ -0,0 +1,78 @@
+use std::net::Ipv4Addr;
+
+use crate::grains::DecodeError;
+
+const NTP_HEADER_LEN: usize = 48;
+
+const MODE_SERVER: u8 = 4;
+const MODE_BROADCAST: u8 = 5;
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub stratum: u8,
+    pub reference_id: String,
+}
+
+/// Parses server and broadcast mode NTP packets, and skips the others
+pub fn parse_response(buf: &[u8]) -> Result<Option<Response>, DecodeError> {
+    if buf.len() < NTP_HEADER_LEN {
+        return Err(DecodeError::malformed("NTP packet", buf.len()));
+    }
+
+    let mode = buf[0] & 0x7;
+    if mode != MODE_SERVER && mode != MODE_BROADCAST {
+        return Ok(None);
+    }
+
+    let stratum = buf[1];
+    let refid = &buf[12..16];
+
+    // primary servers identify their clock source with a short ASCII
+    // string, everything else with the address of their upstream
+    let reference_id = if stratum <= 1 {
+        refid
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as char)
+            .collect()
+    } else {
+        Ipv4Addr::new(refid[0], refid[1], refid[2], refid[3]).to_string()
+    };
+
+    Ok(Some(Response {
+        stratum,
+        reference_id,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_responses() {
+        let mut buf = [0u8; NTP_HEADER_LEN];
+        buf[0] = 0x24;
+        buf[1] = 1;
+        buf[12..15].copy_from_slice(b"GPS");
+        assert_eq!(
+            parse_response(&buf),
+            Ok(Some(Response {
+                stratum: 1,
+                reference_id: "GPS".to_string()
+            }))
+        );
+
+        buf[1] = 3;
+        buf[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        assert_eq!(
+            parse_response(&buf).unwrap().unwrap().reference_id,
+            "10.0.0.1"
+        );
+
+        // client request
+        buf[0] = 0x23;
+        assert_eq!(parse_response(&buf), Ok(None));
+        assert!(parse_response(&buf[..12]).is_err());
+    }
+}
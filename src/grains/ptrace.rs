@@ -0,0 +1,83 @@
+use std::fs;
+
+use crate::grains::*;
+
+use ingraind_probes::ptrace::{Injection, Process};
+
+unsafe impl FromRawEvent for Injection {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 3)
+    }
+}
+
+const PTRACE_ATTACH: u64 = 16;
+const PTRACE_SEIZE: u64 = 0x4206;
+
+pub struct Ptrace;
+
+impl EBPFProbe for Grain<Ptrace> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for Ptrace {
+    fn code() -> &'static [u8] {
+        probes::ptrace::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded_with_context(|ctx: &EventContext, event: Injection| {
+            let (name, mut tags) = match event {
+                Injection::Attach(tracer, tracee, request) => {
+                    let mut tags = injection_tags(&tracer, &tracee);
+                    tags.insert("request", request_name(request));
+                    ("ptrace.attach", tags)
+                }
+                Injection::Poke(tracer, tracee, addr) => {
+                    let mut tags = injection_tags(&tracer, &tracee);
+                    tags.insert("address", format!("{:#x}", addr));
+                    ("ptrace.poke", tags)
+                }
+                Injection::VmWrite(writer, pid) => {
+                    let mut tags = Tags::new();
+                    tags.insert("process_id", writer.pid);
+                    tags.insert("process_str", to_string(&writer.comm));
+                    tags.insert("tracee_process_id", pid);
+                    if let Ok(comm) = fs::read_to_string(format!("/proc/{}/comm", pid)) {
+                        tags.insert("tracee_process_str", comm.trim_end());
+                    }
+                    ("process.vm_write", tags)
+                }
+            };
+            if let Some(cpu) = ctx.cpu {
+                tags.insert("cpu", cpu);
+            }
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | METER,
+                name.to_string(),
+                Unit::Count(1),
+                tags,
+            )))
+        })
+    }
+}
+
+fn injection_tags(tracer: &Process, tracee: &Process) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("process_id", tracer.pid);
+    tags.insert("process_str", to_string(&tracer.comm));
+    tags.insert("tracee_process_id", tracee.pid);
+    tags.insert("tracee_process_str", to_string(&tracee.comm));
+
+    tags
+}
+
+fn request_name(request: u64) -> String {
+    match request {
+        PTRACE_ATTACH => "PTRACE_ATTACH".to_string(),
+        PTRACE_SEIZE => "PTRACE_SEIZE".to_string(),
+        r => r.to_string(),
+    }
+}
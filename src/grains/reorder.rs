@@ -0,0 +1,142 @@
+//! Merging the events of per-CPU perf buffers in the order the kernel saw
+//! them.
+//!
+//! Every CPU has its own perf buffer, and they are read in whatever order
+//! they wake the reader up, so the response to a query, or the close of a
+//! file, can be handled before the event it pairs with when the two ran on
+//! different CPUs. A `ReorderBuffer` holds `Timestamped` events back until
+//! they are `horizon` old, and releases them ordered by their kernel time.
+//! Events arriving later than that are released right away, and counted as
+//! late.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::mem;
+use std::time::Duration;
+
+use crate::grains::event::FromRawEvent;
+
+/// The most events held back, beyond which the oldest are released early
+const MAX_PENDING: usize = 65_536;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Pending {
+    ktime: u64,
+    /// Keeps events with the same kernel time in the order they were read
+    seq: u64,
+    cpu: usize,
+    data: Vec<u8>,
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.ktime, self.seq).cmp(&(other.ktime, other.seq))
+    }
+}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub struct ReorderBuffer {
+    horizon: u64,
+    pending: BinaryHeap<Reverse<Pending>>,
+    seq: u64,
+    /// The kernel time of the newest event released
+    released: u64,
+    late: u64,
+}
+
+impl ReorderBuffer {
+    pub fn new(horizon: Duration) -> ReorderBuffer {
+        ReorderBuffer {
+            horizon: horizon.as_nanos() as u64,
+            pending: BinaryHeap::new(),
+            seq: 0,
+            released: 0,
+            late: 0,
+        }
+    }
+
+    pub fn horizon(&self) -> Duration {
+        Duration::from_nanos(self.horizon)
+    }
+
+    /// Holds back a raw `Timestamped` event read on `cpu`
+    pub fn push(&mut self, cpu: usize, data: &[u8]) {
+        // events too short for a timestamp go first, for the handler to
+        // report them
+        let ktime = u64::from_raw(data).unwrap_or(0);
+        if ktime < self.released {
+            self.late += 1;
+        }
+
+        self.seq += 1;
+        self.pending.push(Reverse(Pending {
+            ktime,
+            seq: self.seq,
+            cpu,
+            data: data.to_vec(),
+        }));
+    }
+
+    /// The CPU, kernel timestamp and data of the oldest event held back if
+    /// it's at least `horizon` old at `now`, on the monotonic clock, or if
+    /// too many are held back
+    pub fn pop(&mut self, now: u64) -> Option<(usize, u64, Vec<u8>)> {
+        let due = match self.pending.peek() {
+            Some(Reverse(next)) => {
+                next.ktime.saturating_add(self.horizon) <= now || self.pending.len() > MAX_PENDING
+            }
+            None => false,
+        };
+        if !due {
+            return None;
+        }
+
+        let Reverse(next) = self.pending.pop()?;
+        self.released = self.released.max(next.ktime);
+        Some((next.cpu, next.ktime, next.data))
+    }
+
+    /// How many events arrived after a newer one was released since the
+    /// last call
+    pub fn take_late(&mut self) -> u64 {
+        mem::replace(&mut self.late, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(ktime: u64, id: u8) -> Vec<u8> {
+        let mut data = ktime.to_ne_bytes().to_vec();
+        data.push(id);
+        data
+    }
+
+    #[test]
+    fn reorders() {
+        let mut buffer = ReorderBuffer::new(Duration::from_nanos(100));
+        buffer.push(1, &event(1050, 3));
+        buffer.push(0, &event(1000, 1));
+        buffer.push(1, &event(1000, 2));
+
+        assert_eq!(buffer.pop(1099), None);
+        assert_eq!(buffer.pop(1100), Some((0, 1000, event(1000, 1))));
+        assert_eq!(buffer.pop(1100), Some((1, 1000, event(1000, 2))));
+        assert_eq!(buffer.pop(1100), None);
+        assert_eq!(buffer.pop(1150), Some((1, 1050, event(1050, 3))));
+
+        buffer.push(0, &event(1020, 4));
+        assert_eq!(buffer.take_late(), 1);
+        assert_eq!(buffer.take_late(), 0);
+        assert_eq!(buffer.pop(1150), Some((0, 1020, event(1020, 4))));
+
+        buffer.push(2, &[1, 2]);
+        assert_eq!(buffer.pop(1150), Some((2, 0, vec![1, 2])));
+    }
+}
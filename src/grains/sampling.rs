@@ -0,0 +1,142 @@
+//! Adjusts the divisor probes sample their events with in the kernel, to
+//! the rate events arrive at.
+//!
+//! Every `interval_ms`, the rate of events read from the perf buffers of a
+//! grain, including lost ones, is scaled up by the current divisor to
+//! estimate the rate the probe sees. The divisor is then set so at most
+//! `max_events_per_second` are sent, up to `max_divisor`.
+
+use std::time::{Duration, Instant};
+
+use failure::{bail, Error};
+use ingraind_probes::sampling::SAMPLE_DIVISOR;
+use redbpf::{HashMap as BPFHashMap, Module};
+use tracing::info;
+
+use crate::grains::probes::common::maps;
+use crate::grains::stats::GrainStats;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SamplingConfig {
+    pub max_events_per_second: u64,
+    #[serde(default = "default_max_divisor")]
+    pub max_divisor: u64,
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_max_divisor() -> u64 {
+    1000
+}
+
+fn default_interval_ms() -> u64 {
+    1000
+}
+
+impl SamplingConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if self.interval_ms == 0 {
+            bail!("sampling: interval_ms must be greater than 0");
+        }
+        if self.max_events_per_second == 0 || self.max_divisor == 0 {
+            bail!("sampling: max_events_per_second and max_divisor must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Sampler {
+    config: SamplingConfig,
+    divisor: u64,
+    events: u64,
+    checked: Instant,
+}
+
+impl Sampler {
+    pub fn new(config: SamplingConfig) -> Sampler {
+        Sampler {
+            config,
+            divisor: 1,
+            events: 0,
+            checked: Instant::now(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.config.interval_ms)
+    }
+
+    /// Sets the divisor in the `sampling` map of `module` from the events
+    /// of the grain since the last adjustment
+    pub fn adjust(&mut self, module: &Module, stats: &GrainStats) {
+        let snapshot = stats.snapshot();
+        let events = snapshot.events_received + snapshot.events_lost + snapshot.events_disabled;
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.checked);
+        let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        let rate = events.saturating_sub(self.events) * 1000 / elapsed_ms.max(1);
+        self.events = events;
+        self.checked = now;
+
+        let divisor = next_divisor(self.divisor, rate, &self.config);
+        if divisor == self.divisor {
+            return;
+        }
+
+        let map = match module.maps.iter().find(|m| m.name == maps::SAMPLING) {
+            Some(map) => BPFHashMap::<u8, u64>::new(map).unwrap(),
+            None => return,
+        };
+        map.set(SAMPLE_DIVISOR, divisor);
+        info!(
+            grain = stats.name(),
+            rate = rate * self.divisor,
+            divisor,
+            "sampling events"
+        );
+        self.divisor = divisor;
+    }
+}
+
+/// The divisor to sample with, given the rate of events sent with `divisor`
+fn next_divisor(divisor: u64, rate: u64, config: &SamplingConfig) -> u64 {
+    let max = config.max_events_per_second.max(1);
+    let total = rate * divisor;
+
+    ((total + max - 1) / max)
+        .max(1)
+        .min(config.max_divisor.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisors() {
+        let config = SamplingConfig {
+            max_events_per_second: 1000,
+            max_divisor: 100,
+            interval_ms: 1000,
+        };
+
+        assert_eq!(next_divisor(1, 500, &config), 1);
+        assert_eq!(next_divisor(1, 2500, &config), 3);
+        assert_eq!(next_divisor(3, 900, &config), 3);
+        assert_eq!(next_divisor(3, 100, &config), 1);
+        assert_eq!(next_divisor(1, 1_000_000, &config), 100);
+    }
+
+    #[test]
+    fn check() {
+        let config = |interval_ms| SamplingConfig {
+            max_events_per_second: 1000,
+            max_divisor: 100,
+            interval_ms,
+        };
+
+        assert!(config(1000).check().is_ok());
+        assert!(config(0).check().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use failure::Error;
+use redbpf::{HashMap as BPFHashMap, Module};
+use serde_json::Value;
+
+use crate::grains::probes::sched::maps;
+use crate::grains::*;
+
+use crate::metrics::Buckets;
+
+use ingraind_probes::sched::{
+    RunqLatency, HISTOGRAM_BUCKETS, HISTOGRAM_INTERVAL, HISTOGRAM_SUM, MIN_LATENCY,
+};
+
+unsafe impl FromRawEvent for RunqLatency {}
+
+pub struct Scheduler {
+    config: SchedulerConfig,
+    /// The counts and the sum of the histogram reported so far, in the
+    /// layout of the map
+    reported: [u64; HISTOGRAM_BUCKETS + 1],
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SchedulerConfig {
+    #[serde(default)]
+    min_latency_us: u64,
+    #[serde(default)]
+    histogram_interval_ms: u64,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Scheduler {
+            config,
+            reported: [0; HISTOGRAM_BUCKETS + 1],
+        }
+    }
+}
+
+impl EBPFProbe for Grain<Scheduler> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_tracepoints_to_names("sched")
+    }
+}
+
+impl EBPFGrain<'static> for Scheduler {
+    fn code() -> &'static [u8] {
+        probes::sched::CODE
+    }
+
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        let settings =
+            BPFHashMap::<u8, u64>::new(find_map_by_name(module, maps::SETTINGS)).unwrap();
+        settings.set(MIN_LATENCY, self.config.min_latency_us * 1000);
+        settings.set(
+            HISTOGRAM_INTERVAL,
+            self.config.histogram_interval_ms * 1_000_000,
+        );
+
+        Ok(())
+    }
+
+    fn dump_map(&self, module: &Module, name: &str) -> Option<Value> {
+        match name {
+            maps::HISTOGRAM => dump_hash_map::<u8, u64>(module, name),
+            _ => dump_common_map(module, name),
+        }
+    }
+
+    fn scrape_interval(&self) -> Option<Duration> {
+        match self.config.histogram_interval_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    fn scrape(&mut self, module: &Module) -> Vec<Measurement> {
+        let map = BPFHashMap::<u8, u64>::new(find_map_by_name(module, maps::HISTOGRAM)).unwrap();
+
+        // the counters only grow, so every CPU can count without locking
+        let mut counts = [0u64; HISTOGRAM_BUCKETS];
+        for (i, count) in counts.iter_mut().enumerate() {
+            let total = map.get(i as u8).unwrap_or(0);
+            *count = total.saturating_sub(self.reported[i]);
+            self.reported[i] = total;
+        }
+        let total = map.get(HISTOGRAM_SUM).unwrap_or(0);
+        let sum = total.saturating_sub(self.reported[HISTOGRAM_BUCKETS]);
+        self.reported[HISTOGRAM_BUCKETS] = total;
+
+        let buckets = Buckets::log2(&counts, sum);
+        let p99 = match buckets.quantile(0.99) {
+            Some(p99) => p99,
+            None => return vec![],
+        };
+
+        vec![
+            Measurement::new(
+                GAUGE,
+                "sched.runq_latency.p99".to_string(),
+                // the buckets are in microseconds
+                Unit::Duration(p99.saturating_mul(1000)),
+                Tags::new(),
+            ),
+            Measurement::new(
+                HISTOGRAM,
+                "sched.runq_latency".to_string(),
+                Unit::Histogram(buckets),
+                Tags::new(),
+            ),
+        ]
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: RunqLatency| {
+            let mut tags = Tags::new();
+            tags.insert("process_id", event.pid);
+            tags.insert("process_str", to_string(&event.comm));
+            tags.insert("cpu", event.cpu);
+
+            Some(Message::Single(Measurement::new(
+                TIMER | HISTOGRAM,
+                "sched.runq_latency".to_string(),
+                Unit::Duration(event.latency),
+                tags,
+            )))
+        })
+    }
+}
@@ -0,0 +1,349 @@
+//! Periodically takes an inventory of the host from `/proc`, without any
+//! eBPF programs.
+//!
+//! Every `interval_ms`, and once at start, a gauge is sent for every running
+//! process as `snapshot.process`, every listening TCP and bound UDP socket
+//! as `snapshot.listen`, and every mount as `snapshot.mount`. Backends can
+//! reconcile what the eBPF grains reported against them, for example after
+//! ingraind was restarted.
+//!
+//! The process table other grains look processes up in is reconciled too,
+//! healing forks and exits its probes missed. How many processes were added
+//! and expired is sent as `snapshot.reconciled`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use actix::{Actor, AsyncContext, Context, Recipient};
+
+use crate::backends::Message;
+use crate::grains::process_tree::{self, ProcessInfo};
+use crate::grains::SendToManyRecipients;
+use crate::metrics::{kind, Measurement, Tags, Unit};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotConfig {
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_true")]
+    pub processes: bool,
+    #[serde(default = "default_true")]
+    pub sockets: bool,
+    #[serde(default = "default_true")]
+    pub mounts: bool,
+}
+
+fn default_interval_ms() -> u64 {
+    60_000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A listening socket in `/proc/net`
+#[derive(Debug, PartialEq)]
+struct Socket {
+    proto: &'static str,
+    address: IpAddr,
+    port: u16,
+    uid: u32,
+    inode: u64,
+}
+
+/// A mount in `/proc/self/mounts`
+#[derive(Debug, PartialEq)]
+struct Mount {
+    device: String,
+    mount_point: String,
+    fs_type: String,
+    read_only: bool,
+}
+
+const TCP_LISTEN: &str = "0A";
+const UDP_UNCONNECTED: &str = "07";
+
+pub struct Snapshot {
+    config: SnapshotConfig,
+    recipients: Vec<Recipient<Message>>,
+}
+
+impl Snapshot {
+    pub fn with_config(config: SnapshotConfig, recipients: Vec<Recipient<Message>>) -> Self {
+        Snapshot { config, recipients }
+    }
+
+    fn snapshot(&mut self) {
+        let mut measurements = vec![];
+        let running = process_tree::running();
+
+        if self.config.processes {
+            measurements.extend(
+                running
+                    .iter()
+                    .map(|p| gauge("snapshot.process", process_tags(p))),
+            );
+
+            let (added, expired) = process_tree::reconcile(running.clone());
+            for (change, count) in [("added", added), ("expired", expired)].iter() {
+                let mut tags = Tags::new();
+                tags.insert("change", *change);
+                measurements.push(Measurement::new(
+                    kind::COUNTER,
+                    "snapshot.reconciled".to_string(),
+                    Unit::Count(*count as u64),
+                    tags,
+                ));
+            }
+        }
+
+        if self.config.sockets {
+            let owners = socket_owners(&running);
+            for (file, proto, state) in [
+                ("/proc/net/tcp", "tcp", TCP_LISTEN),
+                ("/proc/net/tcp6", "tcp", TCP_LISTEN),
+                ("/proc/net/udp", "udp", UDP_UNCONNECTED),
+                ("/proc/net/udp6", "udp", UDP_UNCONNECTED),
+            ]
+            .iter()
+            {
+                let table = fs::read_to_string(file).unwrap_or_default();
+                measurements.extend(
+                    table
+                        .lines()
+                        .skip(1)
+                        .filter_map(|line| parse_socket(line, proto, state))
+                        .map(|socket| {
+                            let owner = owners.get(&socket.inode).cloned();
+                            gauge("snapshot.listen", socket_tags(&socket, owner))
+                        }),
+                );
+            }
+        }
+
+        if self.config.mounts {
+            let mounts = fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+            measurements.extend(
+                mounts
+                    .lines()
+                    .filter_map(parse_mount)
+                    .map(|mount| gauge("snapshot.mount", mount_tags(&mount))),
+            );
+        }
+
+        self.recipients.do_send(Message::List(measurements));
+    }
+}
+
+impl SendToManyRecipients for Snapshot {
+    fn recipients(&self) -> &Vec<Recipient<Message>> {
+        &self.recipients
+    }
+}
+
+impl Actor for Snapshot {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.snapshot();
+        ctx.run_interval(
+            Duration::from_millis(self.config.interval_ms),
+            |act, _ctx| act.snapshot(),
+        );
+    }
+}
+
+fn gauge(name: &str, tags: Tags) -> Measurement {
+    Measurement::new(kind::GAUGE, name.to_string(), Unit::Count(1), tags)
+}
+
+fn process_tags(process: &ProcessInfo) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("process_id", process.pid);
+    tags.insert("process_str", process.comm.as_str());
+    tags.insert("parent_process_id", process.ppid);
+    if let Some(ref exe) = process.exe {
+        tags.insert("exe", exe.as_str());
+    }
+    if let Some(ref container) = process.container {
+        tags.insert("docker_id", container.as_str());
+    }
+
+    tags
+}
+
+fn socket_tags(socket: &Socket, owner: Option<&ProcessInfo>) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("proto", socket.proto);
+    tags.insert("address", socket.address);
+    tags.insert(
+        "ip_version",
+        if socket.address.is_ipv4() { "4" } else { "6" },
+    );
+    tags.insert("port", socket.port);
+    tags.insert("uid", socket.uid);
+    if let Some(owner) = owner {
+        tags.insert("process_id", owner.pid);
+        tags.insert("process_str", owner.comm.as_str());
+    }
+
+    tags
+}
+
+fn mount_tags(mount: &Mount) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("device", mount.device.as_str());
+    tags.insert("mount_point", mount.mount_point.as_str());
+    tags.insert("fs_type", mount.fs_type.as_str());
+    tags.insert("read_only", mount.read_only);
+
+    tags
+}
+
+/// The processes holding each socket inode, from their file descriptors
+fn socket_owners(running: &[ProcessInfo]) -> HashMap<u64, &ProcessInfo> {
+    let mut owners = HashMap::new();
+    for process in running {
+        let fds = match fs::read_dir(format!("/proc/{}/fd", process.pid)) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.filter_map(Result::ok) {
+            let link = match fs::read_link(fd.path()) {
+                Ok(link) => link,
+                Err(_) => continue,
+            };
+            let inode = link
+                .to_str()
+                .filter(|l| l.starts_with("socket:[") && l.ends_with(']'))
+                .and_then(|l| l["socket:[".len()..l.len() - 1].parse().ok());
+            if let Some(inode) = inode {
+                owners.insert(inode, process);
+            }
+        }
+    }
+
+    owners
+}
+
+/// Parses a line of `/proc/net/{tcp,udp}{,6}`, if the socket is in `state`
+fn parse_socket(line: &str, proto: &'static str, state: &str) -> Option<Socket> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    if fields.get(3)? != &state {
+        return None;
+    }
+
+    let mut local = fields.get(1)?.split(':');
+    let address = parse_address(local.next()?)?;
+    let port = u16::from_str_radix(local.next()?, 16).ok()?;
+
+    Some(Socket {
+        proto,
+        address,
+        port,
+        uid: fields.get(7)?.parse().ok()?,
+        inode: fields.get(9)?.parse().ok()?,
+    })
+}
+
+/// Addresses are printed as 32 bit words in host byte order, holding bytes
+/// in network byte order
+fn parse_address(hex: &str) -> Option<IpAddr> {
+    let mut bytes = vec![];
+    for i in (0..hex.len()).step_by(8) {
+        let word = u32::from_str_radix(hex.get(i..i + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+
+    match bytes.len() {
+        4 => Some(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).into()),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&bytes);
+            let v6 = Ipv6Addr::from(octets);
+            Some(
+                v6.to_ipv4()
+                    .filter(|_| is_mapped(&v6))
+                    .map_or(v6.into(), IpAddr::V4),
+            )
+        }
+        _ => None,
+    }
+}
+
+fn is_mapped(addr: &Ipv6Addr) -> bool {
+    addr.segments()[..6] == [0, 0, 0, 0, 0, 0xffff]
+}
+
+/// Parses a line of `/proc/self/mounts`
+fn parse_mount(line: &str) -> Option<Mount> {
+    let mut fields = line.split_whitespace();
+    let device = unescape(fields.next()?);
+    let mount_point = unescape(fields.next()?);
+    let fs_type = fields.next()?.to_string();
+    let read_only = fields.next()?.split(',').any(|o| o == "ro");
+
+    Some(Mount {
+        device,
+        mount_point,
+        fs_type,
+        read_only,
+    })
+}
+
+/// Replaces the octal escapes of spaces, tabs, newlines and backslashes
+fn unescape(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sockets() {
+        let tcp = "   0: 0100007F:0CEA 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 23456 1 0000000000000000 100 0 0 10 0";
+        let socket = parse_socket(tcp, "tcp", TCP_LISTEN).unwrap();
+        assert_eq!(
+            socket,
+            Socket {
+                proto: "tcp",
+                address: Ipv4Addr::new(127, 0, 0, 1).into(),
+                port: 3306,
+                uid: 1000,
+                inode: 23456,
+            }
+        );
+        assert_eq!(parse_socket(tcp, "tcp", UDP_UNCONNECTED), None);
+
+        let tcp6 = "   1: 00000000000000000000000001000000:0016 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1234 1";
+        assert_eq!(
+            parse_socket(tcp6, "tcp", TCP_LISTEN).unwrap().address,
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+        assert_eq!(
+            parse_address("0000000000000000FFFF00000100007F"),
+            Some(Ipv4Addr::new(127, 0, 0, 1).into())
+        );
+    }
+
+    #[test]
+    fn mounts() {
+        assert_eq!(
+            parse_mount("/dev/sda1 /mnt/my\\040disk ext4 ro,relatime 0 0"),
+            Some(Mount {
+                device: "/dev/sda1".to_string(),
+                mount_point: "/mnt/my disk".to_string(),
+                fs_type: "ext4".to_string(),
+                read_only: true,
+            })
+        );
+        assert_eq!(parse_mount("proc"), None);
+    }
+}
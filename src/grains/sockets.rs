@@ -0,0 +1,128 @@
+//! The processes that own sockets, as seen by the Network grain.
+//!
+//! The network probe records the process that connects, accepts or sends
+//! from a socket in its `sockets` map, keyed by the cookie the kernel
+//! assigns the socket, or by the socket's address until it has one. The
+//! Network grain copies the map to this table every second, so grains that
+//! only see packets, like the XDP and socket filter based ones, can
+//! attribute traffic to processes by the addresses of the packets.
+//!
+//! eBPF maps can't be shared between the separately loaded modules of
+//! grains, so the Network grain must be loaded for lookups to succeed.
+//! Packets read from `AF_PACKET` sockets carry the cookie of the capturing
+//! socket, not of the one that sent them, so they can't be looked up by
+//! cookie.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+
+use crate::metrics::Tags;
+
+lazy_static! {
+    static ref SOCKETS: RwLock<SocketTable> = RwLock::new(SocketTable::default());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocketOwner {
+    /// The socket's cookie, or 0 if it hasn't been assigned one
+    pub cookie: u64,
+    pub pid: u32,
+    pub comm: String,
+    pub proto: &'static str,
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    /// The kernel time the owner was last recorded at
+    pub seen: u64,
+}
+
+impl SocketOwner {
+    /// Adds the `process_id`, `process_str` and `socket_cookie` tags
+    pub fn tag(&self, tags: &mut Tags) {
+        tags.insert("process_id", self.pid);
+        tags.insert("process_str", self.comm.as_str());
+        if self.cookie != 0 {
+            tags.insert("socket_cookie", self.cookie);
+        }
+    }
+}
+
+type FlowKey = (&'static str, SocketAddr, SocketAddr);
+
+#[derive(Default)]
+pub struct SocketTable {
+    flows: HashMap<FlowKey, SocketOwner>,
+}
+
+impl SocketTable {
+    /// Adds the owner of a socket. Of sockets with the same addresses, the
+    /// most recently recorded one is kept.
+    pub fn insert(&mut self, owner: SocketOwner) {
+        let key = (owner.proto, owner.local, owner.remote);
+        match self.flows.get(&key) {
+            Some(newer) if newer.seen > owner.seen => {}
+            _ => {
+                self.flows.insert(key, owner);
+            }
+        }
+    }
+
+    fn by_flow(&self, proto: &str, a: SocketAddr, b: SocketAddr) -> Option<&SocketOwner> {
+        let proto = match proto {
+            "tcp" => "tcp",
+            "udp" => "udp",
+            _ => return None,
+        };
+
+        self.flows
+            .get(&(proto, a, b))
+            .or_else(|| self.flows.get(&(proto, b, a)))
+    }
+}
+
+/// Replaces the owners of all sockets
+pub fn replace(table: SocketTable) {
+    *SOCKETS.write().unwrap() = table;
+}
+
+/// The owner of the socket a packet between `a` and `b` belongs to, in
+/// either direction
+pub fn by_flow(proto: &str, a: SocketAddr, b: SocketAddr) -> Option<SocketOwner> {
+    SOCKETS.read().unwrap().by_flow(proto, a, b).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(cookie: u64, local: &str, seen: u64) -> SocketOwner {
+        SocketOwner {
+            cookie,
+            pid: 42 + cookie as u32,
+            comm: "curl".to_string(),
+            proto: "tcp",
+            local: local.parse().unwrap(),
+            remote: "1.1.1.1:443".parse().unwrap(),
+            seen,
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let mut table = SocketTable::default();
+        table.insert(owner(7, "10.0.0.1:50000", 2));
+        table.insert(owner(8, "10.0.0.1:50000", 1));
+        table.insert(owner(9, "10.0.0.1:50001", 1));
+
+        let local = "10.0.0.1:50000".parse().unwrap();
+        let remote = "1.1.1.1:443".parse().unwrap();
+        assert_eq!(table.by_flow("tcp", local, remote).unwrap().cookie, 7);
+        assert_eq!(table.by_flow("tcp", remote, local).unwrap().pid, 49);
+        assert!(table.by_flow("udp", local, remote).is_none());
+
+        let other = "10.0.0.1:50001".parse().unwrap();
+        assert_eq!(table.by_flow("tcp", remote, other).unwrap().cookie, 9);
+    }
+}
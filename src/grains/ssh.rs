@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::grains::*;
+
+use ingraind_probes::ssh::{Session, SshSession};
+
+unsafe impl FromRawEvent for SshSession {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+
+#[derive(Default)]
+pub struct Ssh {
+    /// The tags of open sessions by pid, as the session's environment is
+    /// gone by the time it ends
+    sessions: Arc<Mutex<HashMap<u32, Tags>>>,
+}
+
+impl EBPFProbe for Grain<Ssh> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for Ssh {
+    fn code() -> &'static [u8] {
+        probes::ssh::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        let sessions = self.sessions.clone();
+
+        decoded(move |event: SshSession| {
+            let mut sessions = sessions.lock().unwrap();
+            let measurement = match event {
+                SshSession::Start(session) => {
+                    let tags = session_tags(&session);
+                    sessions.insert(session.pid, tags.clone());
+
+                    Measurement::new(
+                        COUNTER | METER,
+                        "ssh.session.start".to_string(),
+                        Unit::Count(1),
+                        tags,
+                    )
+                }
+                SshSession::End(session, duration) => {
+                    let tags = sessions
+                        .remove(&session.pid)
+                        .unwrap_or_else(|| process_tags(&session));
+
+                    Measurement::new(
+                        TIMER | HISTOGRAM,
+                        "ssh.session.end".to_string(),
+                        Unit::Duration(duration),
+                        tags,
+                    )
+                }
+            };
+
+            Some(Message::Single(measurement))
+        })
+    }
+}
+
+fn process_tags(session: &Session) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("process_id", session.pid);
+    tags.insert("process_str", to_string(&session.comm));
+
+    tags
+}
+
+fn session_tags(session: &Session) -> Tags {
+    let mut tags = process_tags(session);
+    let pid = session.pid;
+
+    let env = SessionEnv::parse(&fs::read(format!("/proc/{}/environ", pid)).unwrap_or_default());
+    if let Some((ip, port)) = env.remote {
+        tags.insert("remote_ip", ip);
+        tags.insert("remote_port", port);
+    }
+
+    if let Some(user) = env.user.or_else(|| username(pid)) {
+        tags.insert("username", user);
+    }
+
+    let tty = fs::read_link(format!("/proc/{}/fd/0", pid))
+        .map(|path| path.starts_with("/dev/pts"))
+        .unwrap_or(false);
+    tags.insert("tty", tty.to_string());
+
+    tags
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SessionEnv {
+    pub remote: Option<(String, String)>,
+    pub user: Option<String>,
+}
+
+impl SessionEnv {
+    /// Parses the NUL separated contents of `/proc/<pid>/environ`
+    pub fn parse(environ: &[u8]) -> SessionEnv {
+        let mut env = SessionEnv::default();
+
+        for var in environ.split(|&c| c == 0) {
+            let var = String::from_utf8_lossy(var);
+            let mut kv = var.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("SSH_CONNECTION"), Some(conn)) => {
+                    let mut parts = conn.split_whitespace();
+                    if let (Some(ip), Some(port)) = (parts.next(), parts.next()) {
+                        env.remote = Some((ip.to_string(), port.to_string()));
+                    }
+                }
+                (Some("USER"), Some(user)) => env.user = Some(user.to_string()),
+                _ => {}
+            }
+        }
+
+        env
+    }
+}
+
+/// Looks up the real uid of the process in `/etc/passwd`
+fn username(pid: u32) -> Option<String> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let uid = status
+        .lines()
+        .find(|l| l.starts_with("Uid:"))?
+        .split_whitespace()
+        .nth(1)?
+        .to_string();
+
+    user_name(&uid)
+}
+
+/// Looks up `uid` in `/etc/passwd`
+pub(crate) fn user_name(uid: &str) -> Option<String> {
+    fs::read_to_string("/etc/passwd")
+        .ok()?
+        .lines()
+        .map(|l| l.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.get(2) == Some(&uid))
+        .map(|fields| fields[0].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_session_env() {
+        let environ = b"LANG=C\0USER=alice\0SSH_CONNECTION=10.0.0.1 51234 10.0.0.2 22\0";
+        assert_eq!(
+            SessionEnv::parse(environ),
+            SessionEnv {
+                remote: Some(("10.0.0.1".to_string(), "51234".to_string())),
+                user: Some("alice".to_string()),
+            }
+        );
+
+        assert_eq!(SessionEnv::parse(b""), SessionEnv::default());
+    }
+}
@@ -0,0 +1,180 @@
+use std::process::Command;
+
+use failure::{format_err, Error};
+
+use crate::grains::probes::ssl::programs;
+use crate::grains::*;
+
+use ingraind_probes::ssl::{SslData, SslEvent};
+
+unsafe impl FromRawEvent for SslEvent {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+
+/// How `ldconfig -p` tags the libraries of the architecture ingraind runs on
+#[cfg(target_arch = "x86_64")]
+const LDCONFIG_ARCH: &str = "x86-64";
+#[cfg(target_arch = "aarch64")]
+const LDCONFIG_ARCH: &str = "AArch64";
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const LDCONFIG_ARCH: &str = "";
+
+pub struct Ssl {
+    config: SslConfig,
+    library: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct SslConfig {
+    library: Option<String>,
+    pid: Option<i32>,
+}
+
+impl SslConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        self.library().map(|_| ())
+    }
+
+    /// The configured library, or the system's libssl
+    fn library(&self) -> Result<String, Error> {
+        match self.library {
+            Some(ref library) => Ok(library.clone()),
+            None => find_library("libssl.so."),
+        }
+    }
+}
+
+impl Ssl {
+    pub fn new(config: SslConfig) -> Result<Self, Error> {
+        let library = config.library()?;
+
+        Ok(Ssl { config, library })
+    }
+}
+
+impl EBPFProbe for Grain<Ssl> {
+    fn attach(&mut self) -> MessageStreams {
+        let library = self.native.library.clone();
+        let pid = self.native.config.pid;
+
+        self.attach_uprobes(pid, |program| {
+            symbol(program)
+                .map(|symbol| UprobeLocation {
+                    target: library.clone(),
+                    symbol: Some(symbol.to_string()),
+                    offset: 0,
+                })
+                .into_iter()
+                .collect()
+        })
+    }
+}
+
+impl EBPFGrain<'static> for Ssl {
+    fn code() -> &'static [u8] {
+        probes::ssl::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: SslEvent| {
+            let (name, data) = match event {
+                SslEvent::Read(data) => ("ssl.in", data),
+                SslEvent::Write(data) => ("ssl.out", data),
+            };
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | HISTOGRAM | METER,
+                name.to_string(),
+                Unit::Byte(data.len as u64),
+                ssl_tags(&data),
+            )))
+        })
+    }
+}
+
+fn ssl_tags(data: &SslData) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("process_id", data.pid);
+    tags.insert("process_str", to_string(&data.comm));
+
+    let end = data
+        .sni
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(data.sni.len());
+    if end > 0 {
+        tags.insert("sni_str", String::from_utf8_lossy(&data.sni[..end]));
+    }
+
+    tags
+}
+
+/// Looks up the newest version of a shared library in the linker cache
+fn find_library(prefix: &str) -> Result<String, Error> {
+    let output = Command::new("ldconfig")
+        .arg("-p")
+        .output()
+        .or_else(|_| Command::new("/sbin/ldconfig").arg("-p").output())
+        .map_err(|e| format_err!("can't run ldconfig to find {}: {}", prefix, e))?;
+
+    let cache = String::from_utf8_lossy(&output.stdout);
+    parse_ldconfig(&cache, prefix, LDCONFIG_ARCH)
+        .ok_or_else(|| format_err!("{}* not found by ldconfig, set `library`", prefix))
+}
+
+/// Finds the first library starting with `prefix` for `arch` in the output
+/// of `ldconfig -p`, which lists the newest versions first
+fn parse_ldconfig(cache: &str, prefix: &str, arch: &str) -> Option<String> {
+    cache.lines().find_map(|line| {
+        let mut parts = line.trim().splitn(2, " => ");
+        let (name, path) = (parts.next()?, parts.next()?);
+        if name.starts_with(prefix) && name.contains(arch) {
+            Some(path.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Maps the probe's programs to the functions they instrument
+fn symbol(program: &str) -> Option<&'static str> {
+    let symbol = match program {
+        programs::SSL_READ_ENTER | programs::SSL_READ_EXIT => "SSL_read",
+        programs::SSL_WRITE_ENTER | programs::SSL_WRITE_EXIT => "SSL_write",
+        programs::SSL_CTRL => "SSL_ctrl",
+        programs::GET_SERVERNAME_ENTER | programs::GET_SERVERNAME_EXIT => "SSL_get_servername",
+        programs::SSL_FREE => "SSL_free",
+        _ => return None,
+    };
+
+    Some(symbol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ldconfig_libraries() {
+        let cache = "\
+3 libs found in cache `/etc/ld.so.cache'
+\tlibssl3.so (libc6,x86-64) => /lib/x86_64-linux-gnu/libssl3.so
+\tlibssl.so.3 (libc6,AArch64) => /lib/aarch64-linux-gnu/libssl.so.3
+\tlibssl.so.1.1 (libc6,x86-64) => /lib/x86_64-linux-gnu/libssl.so.1.1
+\tlibssl.so (libc6,x86-64) => /lib/x86_64-linux-gnu/libssl.so
+";
+
+        assert_eq!(
+            parse_ldconfig(cache, "libssl.so.", "x86-64").as_deref(),
+            Some("/lib/x86_64-linux-gnu/libssl.so.1.1")
+        );
+        assert_eq!(
+            parse_ldconfig(cache, "libssl.so.", "AArch64").as_deref(),
+            Some("/lib/aarch64-linux-gnu/libssl.so.3")
+        );
+        assert_eq!(parse_ldconfig(cache, "libcrypto.so.", ""), None);
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+
+use crate::metrics::kind::*;
+use crate::metrics::{Measurement, Tags, Unit};
+
+lazy_static! {
+    static ref GRAINS: RwLock<Vec<Arc<GrainStats>>> = RwLock::new(Vec::new());
+}
+
+/// The grains that have to attach all their programs for ingraind to be
+/// ready
+static REQUIRED: AtomicUsize = AtomicUsize::new(0);
+
+/// Runtime statistics of a loaded grain, shared between its message
+/// streams and the actor reporting them.
+///
+/// They also hold whether the grain, or some of its maps, were disabled at
+/// runtime, in which case their events are dropped as they are read. The
+/// grain's actor also tells the probe to stop in the kernel.
+#[derive(Debug, Default)]
+pub struct GrainStats {
+    name: String,
+    programs: Vec<String>,
+    maps: Vec<String>,
+    ready: AtomicBool,
+    required: AtomicBool,
+    /// Disabled through the admin endpoint
+    disabled: AtomicBool,
+    /// Disabled to shed load, which is kept apart so restoring load
+    /// doesn't enable grains disabled on purpose
+    shed: AtomicBool,
+    disabled_maps: RwLock<HashSet<String>>,
+    /// The events seen while load shedding samples them
+    sampled_events: AtomicU64,
+    events_received: AtomicU64,
+    events_ignored: AtomicU64,
+    events_disabled: AtomicU64,
+    events_lost: AtomicU64,
+    events_malformed: AtomicU64,
+    messages: AtomicU64,
+    programs_attached: AtomicU64,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct GrainStatsSnapshot {
+    pub name: String,
+    pub programs: Vec<String>,
+    pub maps: Vec<String>,
+    pub ready: bool,
+    pub enabled: bool,
+    pub shed: bool,
+    pub disabled_maps: Vec<String>,
+    pub events_received: u64,
+    pub events_ignored: u64,
+    pub events_disabled: u64,
+    pub events_lost: u64,
+    pub events_malformed: u64,
+    pub messages: u64,
+    pub programs_attached: u64,
+}
+
+impl GrainStats {
+    /// Creates the statistics for a grain, and registers them so they can
+    /// be listed with `all()`
+    pub fn register(name: &str, programs: Vec<String>, maps: Vec<String>) -> Arc<GrainStats> {
+        let stats = Arc::new(GrainStats {
+            name: name.to_string(),
+            programs,
+            maps,
+            ..Default::default()
+        });
+        GRAINS.write().unwrap().push(stats.clone());
+
+        stats
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn received(&self, message: bool) {
+        self.events_received.fetch_add(1, Ordering::Relaxed);
+        if message {
+            self.messages.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.events_ignored.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts an event that failed to decode, and returns how many did so
+    /// far
+    pub fn malformed(&self) -> u64 {
+        self.events_received.fetch_add(1, Ordering::Relaxed);
+        self.events_malformed.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Counts events dropped because the grain or their map is disabled,
+    /// to shed load, or over the grain's rate limit
+    pub fn dropped(&self, count: u64) {
+        self.events_disabled.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn lost(&self, count: u64) {
+        self.events_lost.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn attached(&self) {
+        self.programs_attached.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks the grain as having all its programs attached
+    pub fn ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    /// Marks the grain as one of those ingraind waits for to be ready
+    pub fn require(&self) {
+        self.required.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.disabled.load(Ordering::Relaxed) && !self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the events of map `map` are handled
+    pub fn is_map_enabled(&self, map: &str) -> bool {
+        self.is_enabled() && !self.disabled_maps.read().unwrap().contains(map)
+    }
+
+    /// Enables or disables the grain, or only map `map` of it. Returns
+    /// `false` if the grain has no such map.
+    pub fn set_enabled(&self, map: Option<&str>, enabled: bool) -> bool {
+        let map = match map {
+            Some(map) => map,
+            None => {
+                self.disabled.store(!enabled, Ordering::Relaxed);
+                return true;
+            }
+        };
+
+        if !self.maps.iter().any(|m| m == map) {
+            return false;
+        }
+
+        let mut disabled = self.disabled_maps.write().unwrap();
+        if enabled {
+            disabled.remove(map);
+        } else {
+            disabled.insert(map.to_string());
+        }
+
+        true
+    }
+
+    /// Disables the grain to shed load, or enables it again if it wasn't
+    /// disabled otherwise
+    pub fn set_shed(&self, shed: bool) {
+        self.shed.store(shed, Ordering::Relaxed);
+    }
+
+    pub fn sampled_events(&self) -> &AtomicU64 {
+        &self.sampled_events
+    }
+
+    pub fn snapshot(&self) -> GrainStatsSnapshot {
+        let mut disabled_maps = self
+            .disabled_maps
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        disabled_maps.sort();
+
+        GrainStatsSnapshot {
+            name: self.name.clone(),
+            programs: self.programs.clone(),
+            maps: self.maps.clone(),
+            ready: self.ready.load(Ordering::Relaxed),
+            enabled: self.is_enabled(),
+            shed: self.shed.load(Ordering::Relaxed),
+            disabled_maps,
+            events_received: self.events_received.load(Ordering::Relaxed),
+            events_ignored: self.events_ignored.load(Ordering::Relaxed),
+            events_disabled: self.events_disabled.load(Ordering::Relaxed),
+            events_lost: self.events_lost.load(Ordering::Relaxed),
+            events_malformed: self.events_malformed.load(Ordering::Relaxed),
+            messages: self.messages.load(Ordering::Relaxed),
+            programs_attached: self.programs_attached.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl GrainStatsSnapshot {
+    /// Reports the counters as running totals, and the attached programs
+    /// as a gauge
+    pub fn to_measurements(&self) -> Vec<Measurement> {
+        let mut tags = Tags::new();
+        tags.insert("grain", self.name.as_str());
+
+        let counter = |name: &str, total: u64| {
+            Measurement::new(
+                COUNTER | CUMULATIVE,
+                format!("ingraind.grain.{}", name),
+                Unit::Count(total),
+                tags.clone(),
+            )
+        };
+
+        vec![
+            counter("events_received", self.events_received),
+            counter("events_ignored", self.events_ignored),
+            counter("events_disabled", self.events_disabled),
+            counter("events_lost", self.events_lost),
+            counter("events_malformed", self.events_malformed),
+            counter("messages", self.messages),
+            Measurement::new(
+                GAUGE,
+                "ingraind.grain.programs_attached".to_string(),
+                Unit::Count(self.programs_attached),
+                tags.clone(),
+            ),
+        ]
+    }
+}
+
+/// Statistics of all grains loaded so far
+pub fn all() -> Vec<GrainStatsSnapshot> {
+    GRAINS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|s| s.snapshot())
+        .collect()
+}
+
+/// Enables or disables all loaded grains named `grain`, or only their map
+/// `map`. Returns how many grains were changed.
+pub fn set_enabled(grain: &str, map: Option<&str>, enabled: bool) -> usize {
+    GRAINS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|s| s.name == grain)
+        .filter(|s| s.set_enabled(map, enabled))
+        .count()
+}
+
+/// Disables all loaded grains named `grain` to shed load, or enables them
+/// again
+pub fn set_shed(grain: &str, shed: bool) {
+    for stats in GRAINS.read().unwrap().iter().filter(|s| s.name == grain) {
+        stats.set_shed(shed);
+    }
+}
+
+/// Sets how many required grains are started, before they are loaded
+pub fn expect_required(count: usize) {
+    REQUIRED.store(count, Ordering::Relaxed);
+}
+
+/// Whether all required grains are loaded, and attached all their programs
+pub fn ready() -> bool {
+    let attached = GRAINS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|s| s.required.load(Ordering::Relaxed) && s.ready.load(Ordering::Relaxed))
+        .count();
+
+    attached >= REQUIRED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_totals() {
+        let stats = GrainStats::register("Test", vec![], vec!["events".to_string()]);
+        stats.attached();
+        stats.received(true);
+        stats.received(false);
+        stats.lost(3);
+        assert_eq!(stats.malformed(), 1);
+
+        let first = stats.snapshot();
+        assert_eq!(first.events_received, 3);
+        assert_eq!(first.events_malformed, 1);
+        assert_eq!(first.events_ignored, 1);
+        assert!(all().contains(&first));
+
+        expect_required(1);
+        stats.require();
+        assert!(!ready());
+
+        stats.ready();
+        assert!(ready());
+        assert!(all().iter().find(|s| s.name == "Test").unwrap().ready);
+
+        stats.received(true);
+        let measurements = stats.snapshot().to_measurements();
+        let value = |name: &str| {
+            measurements
+                .iter()
+                .find(|m| m.name == format!("ingraind.grain.{}", name))
+                .map(|m| m.value.clone())
+        };
+
+        assert_eq!(value("events_received"), Some(Unit::Count(4)));
+        assert_eq!(value("events_lost"), Some(Unit::Count(3)));
+        assert_eq!(value("events_malformed"), Some(Unit::Count(1)));
+        assert_eq!(value("programs_attached"), Some(Unit::Count(1)));
+    }
+
+    #[test]
+    fn disable_at_runtime() {
+        let stats = GrainStats::register("Disable", vec![], vec!["events".to_string()]);
+        assert!(stats.is_map_enabled("events"));
+
+        assert_eq!(set_enabled("Disable", Some("events"), false), 1);
+        assert_eq!(set_enabled("Disable", Some("other"), false), 0);
+        assert!(stats.is_enabled());
+        assert!(!stats.is_map_enabled("events"));
+        assert_eq!(stats.snapshot().disabled_maps, vec!["events".to_string()]);
+
+        set_enabled("Disable", Some("events"), true);
+        set_enabled("Disable", None, false);
+        assert!(!stats.is_map_enabled("events"));
+        assert!(!stats.snapshot().enabled);
+
+        // restoring load keeps grains disabled on purpose disabled
+        set_shed("Disable", true);
+        set_shed("Disable", false);
+        assert!(!stats.is_enabled());
+        set_enabled("Disable", None, true);
+        set_shed("Disable", true);
+        assert!(!stats.is_enabled());
+        assert!(stats.snapshot().shed);
+        set_shed("Disable", false);
+        assert!(stats.is_enabled());
+    }
+}
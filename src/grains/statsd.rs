@@ -220,18 +220,17 @@ impl Into<Measurement> for Metric {
     fn into(self) -> Measurement {
         use MetricValue::*;
         use kind::*;
-        use Unit::*;
 
         let mut reset = false;
         let (k, v) = match self.value {
-            Counter(v) => (COUNTER, Count(v as u64)),
+            Counter(v) => (COUNTER, Unit::Count(v as u64)),
             Gauge(v, rst) => {
                 reset = rst;
-                (GAUGE, Count(v as u64))
+                (GAUGE, Unit::Gauge(v))
             }
-            Timing(t) => (TIMER, Count(t as u64)),
-            Set(v) => (SET, Str(v)),
-            Histogram(v) => (HISTOGRAM, Count(v))
+            Timing(t) => (TIMER, Unit::Duration((t * 1e6) as u64)),
+            Set(v) => (SET, Unit::Str(v)),
+            Histogram(v) => (HISTOGRAM, Unit::Count(v))
         };
 
         let mut m = Measurement::new(k, self.key, v, self.tags);
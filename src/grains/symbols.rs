@@ -0,0 +1,255 @@
+//! Resolves the instruction pointers of sampled stacks to the functions
+//! they're in, using `/proc/kallsyms` for the kernel, and the symbol tables
+//! of the executables and libraries processes have mapped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::sym::STT_FUNC;
+use goblin::elf::Elf;
+
+/// Functions sorted by address, with their size if it's known
+#[derive(Default)]
+struct Functions(Vec<(u64, u64, String)>);
+
+impl Functions {
+    fn new(mut functions: Vec<(u64, u64, String)>) -> Self {
+        functions.sort_by_key(|&(addr, _, _)| addr);
+        Functions(functions)
+    }
+
+    fn resolve(&self, addr: u64) -> Option<&str> {
+        let i = match self.0.binary_search_by_key(&addr, |&(start, _, _)| start) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let (start, size, ref name) = self.0[i];
+        if size != 0 && addr >= start + size {
+            return None;
+        }
+
+        Some(name)
+    }
+}
+
+/// The functions of the kernel and its modules
+#[derive(Default)]
+pub struct KernelSymbols(Functions);
+
+impl KernelSymbols {
+    pub fn load() -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string("/proc/kallsyms")?))
+    }
+
+    fn parse(kallsyms: &str) -> Self {
+        let functions = kallsyms
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+                let kind = fields.next()?;
+                let name = fields.next()?;
+                match kind {
+                    "t" | "T" | "w" | "W" if addr != 0 => Some((addr, 0, name.to_string())),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        KernelSymbols(Functions::new(functions))
+    }
+
+    pub fn resolve(&self, addr: u64) -> Option<&str> {
+        self.0.resolve(addr)
+    }
+}
+
+/// The functions of an ELF file, and where its segments are in the file
+struct ElfSymbols {
+    functions: Functions,
+    /// The file offset, the address and the size of loaded segments
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl ElfSymbols {
+    fn parse(code: &[u8]) -> Option<ElfSymbols> {
+        let elf = Elf::parse(code).ok()?;
+        let symbols = elf
+            .syms
+            .iter()
+            .map(|sym| (sym, &elf.strtab))
+            .chain(elf.dynsyms.iter().map(|sym| (sym, &elf.dynstrtab)));
+        let functions = symbols
+            .filter(|(sym, _)| sym.st_type() == STT_FUNC && sym.st_value != 0)
+            .filter_map(|(sym, strtab)| {
+                let name = strtab.get(sym.st_name)?.ok()?;
+                Some((sym.st_value, sym.st_size, name.to_string()))
+            })
+            .collect();
+        let segments = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .map(|ph| (ph.p_offset, ph.p_vaddr, ph.p_filesz))
+            .collect();
+
+        Some(ElfSymbols {
+            functions: Functions::new(functions),
+            segments,
+        })
+    }
+
+    /// The function at `offset` in the file
+    fn resolve(&self, offset: u64) -> Option<&str> {
+        let &(start, vaddr, _) = self
+            .segments
+            .iter()
+            .find(|&&(start, _, size)| offset >= start && offset < start + size)?;
+        self.functions.resolve(offset - start + vaddr)
+    }
+}
+
+/// A file mapped executable in a process
+struct Mapping {
+    start: u64,
+    end: u64,
+    offset: u64,
+    path: String,
+}
+
+fn mappings(pid: u32) -> Vec<Mapping> {
+    let maps = match fs::read_to_string(format!("/proc/{}/maps", pid)) {
+        Ok(maps) => maps,
+        Err(_) => return vec![],
+    };
+
+    maps.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mut range = fields.next()?.splitn(2, '-');
+            let perms = fields.next()?;
+            let offset = fields.next()?;
+            let path = fields.nth(2)?;
+            if !perms.contains('x') || !path.starts_with('/') {
+                return None;
+            }
+
+            Some(Mapping {
+                start: u64::from_str_radix(range.next()?, 16).ok()?,
+                end: u64::from_str_radix(range.next()?, 16).ok()?,
+                offset: u64::from_str_radix(offset, 16).ok()?,
+                path: path.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Resolves addresses in the kernel and in processes.
+///
+/// Files are read through the root of the process, so the right ones are
+/// used for processes in containers, and are cached by inode until they
+/// aren't used for a whole scrape.
+pub struct Symbolizer {
+    kernel: KernelSymbols,
+    processes: HashMap<u32, Vec<Mapping>>,
+    files: HashMap<(u64, u64), (Option<ElfSymbols>, bool)>,
+}
+
+impl Symbolizer {
+    pub fn load() -> Self {
+        let kernel = KernelSymbols::load().unwrap_or_else(|e| {
+            warn!("Unable to read kernel symbols: {}", e);
+            KernelSymbols::default()
+        });
+
+        Symbolizer {
+            kernel,
+            processes: HashMap::new(),
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn kernel(&self, addr: u64) -> Option<&str> {
+        self.kernel.resolve(addr)
+    }
+
+    pub fn user(&mut self, pid: u32, addr: u64) -> Option<&str> {
+        let mappings = self.processes.entry(pid).or_insert_with(|| mappings(pid));
+        let mapping = mappings.iter().find(|m| addr >= m.start && addr < m.end)?;
+        let path = format!("/proc/{}/root{}", pid, mapping.path);
+        let offset = addr - mapping.start + mapping.offset;
+
+        let meta = fs::metadata(&path).ok()?;
+        let (symbols, used) = self
+            .files
+            .entry((meta.dev(), meta.ino()))
+            .or_insert_with(|| {
+                (
+                    fs::read(&path)
+                        .ok()
+                        .and_then(|code| ElfSymbols::parse(&code)),
+                    true,
+                )
+            });
+        *used = true;
+
+        symbols.as_ref()?.resolve(offset)
+    }
+
+    /// Forgets the mappings of processes, which can change by the next
+    /// scrape, and the files that weren't used since the last one
+    pub fn scraped(&mut self) {
+        self.processes.clear();
+        self.files.retain(|_, (_, used)| *used);
+        for (_, used) in self.files.values_mut() {
+            *used = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_kernel_symbols() {
+        let symbols = KernelSymbols::parse(
+            "ffffffff81000000 T _stext\n\
+             ffffffff81001000 t do_one_initcall\n\
+             ffffffff81002000 D some_data\n\
+             ffffffffc0000000 t ext4_readpage\t[ext4]\n",
+        );
+
+        assert_eq!(symbols.resolve(0xffff_ffff_8100_0010), Some("_stext"));
+        assert_eq!(
+            symbols.resolve(0xffff_ffff_8100_1fff),
+            Some("do_one_initcall")
+        );
+        assert_eq!(
+            symbols.resolve(0xffff_ffff_8100_2010),
+            Some("do_one_initcall")
+        );
+        assert_eq!(
+            symbols.resolve(0xffff_ffff_c000_0100),
+            Some("ext4_readpage")
+        );
+        assert_eq!(symbols.resolve(0x1000), None);
+    }
+
+    #[test]
+    fn resolves_sized_functions() {
+        let functions = Functions::new(vec![
+            (0x2000, 0x10, "b".to_string()),
+            (0x1000, 0x100, "a".to_string()),
+        ]);
+
+        assert_eq!(functions.resolve(0x1080), Some("a"));
+        assert_eq!(functions.resolve(0x1100), None);
+        assert_eq!(functions.resolve(0x2000), Some("b"));
+        assert_eq!(functions.resolve(0x2010), None);
+    }
+}
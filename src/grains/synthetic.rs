@@ -0,0 +1,386 @@
+//! Emits fake measurements, without loading any eBPF programs.
+//!
+//! Backends and aggregations can be developed and load tested with it on
+//! hosts without root, or without a kernel the probes support. Every
+//! measurement is picked from `measurements` by weight, with a value between
+//! `min` and `max`, and tags drawn from their distributions.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix::{Actor, AsyncContext, Context, Recipient};
+use failure::{format_err, Error};
+
+use crate::backends::Message;
+use crate::grains::SendToManyRecipients;
+use crate::metrics::kind::{self, Kind};
+use crate::metrics::{timestamp_now, Measurement, TagValue, Tags, Unit, UnitType};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyntheticConfig {
+    /// Measurements per second
+    #[serde(default = "default_rate")]
+    pub rate: u64,
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// The same seed generates the same measurements
+    pub seed: Option<u64>,
+    pub measurements: Vec<SyntheticMeasurement>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyntheticMeasurement {
+    pub name: String,
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    #[serde(default = "default_unit")]
+    pub unit: String,
+    #[serde(default)]
+    pub min: u64,
+    #[serde(default = "default_max")]
+    pub max: u64,
+    #[serde(default = "default_weight")]
+    pub weight: u64,
+    #[serde(default)]
+    pub tags: BTreeMap<String, TagDistribution>,
+}
+
+/// How the values of a tag are drawn
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum TagDistribution {
+    /// One of the values, uniformly
+    Values(Vec<TagValue>),
+    /// One of the values, by weight
+    Weighted {
+        values: Vec<TagValue>,
+        weights: Vec<u64>,
+    },
+    /// An integer between `min` and `max`
+    Range { min: i64, max: i64 },
+    /// One of `cardinality` strings starting with `prefix`
+    Cardinality {
+        cardinality: u64,
+        #[serde(default)]
+        prefix: String,
+    },
+}
+
+fn default_rate() -> u64 {
+    100
+}
+
+fn default_interval_ms() -> u64 {
+    100
+}
+
+fn default_kind() -> String {
+    "counter".to_string()
+}
+
+fn default_unit() -> String {
+    "count".to_string()
+}
+
+fn default_max() -> u64 {
+    1
+}
+
+fn default_weight() -> u64 {
+    1
+}
+
+impl TagDistribution {
+    fn check(&self) -> Result<(), Error> {
+        match self {
+            TagDistribution::Values(values) if values.is_empty() => Err(format_err!("no values")),
+            TagDistribution::Weighted { values, weights }
+                if values.is_empty()
+                    || values.len() != weights.len()
+                    || weights.iter().sum::<u64>() == 0 =>
+            {
+                Err(format_err!("values need as many weights, not all 0"))
+            }
+            TagDistribution::Range { min, max } if min > max => {
+                Err(format_err!("min is greater than max"))
+            }
+            TagDistribution::Cardinality { cardinality: 0, .. } => {
+                Err(format_err!("cardinality is 0"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn sample(&self, rng: &mut Rng) -> TagValue {
+        match self {
+            TagDistribution::Values(values) => {
+                values[rng.range(0, values.len() as u64 - 1) as usize].clone()
+            }
+            TagDistribution::Weighted { values, weights } => values[rng.pick(weights)].clone(),
+            TagDistribution::Range { min, max } => {
+                let offset = rng.range(0, max.wrapping_sub(*min) as u64);
+                TagValue::from(min.wrapping_add(offset as i64))
+            }
+            TagDistribution::Cardinality {
+                cardinality,
+                prefix,
+            } => TagValue::from(format!("{}{}", prefix, rng.range(0, cardinality - 1))),
+        }
+    }
+}
+
+struct Template {
+    name: String,
+    kind: Kind,
+    unit: UnitType,
+    min: u64,
+    max: u64,
+    tags: Vec<(String, TagDistribution)>,
+}
+
+/// Generates measurements from a configuration
+pub struct Generator {
+    rng: Rng,
+    templates: Vec<Template>,
+    weights: Vec<u64>,
+}
+
+impl Generator {
+    pub fn new(config: &SyntheticConfig) -> Result<Generator, Error> {
+        if config.measurements.is_empty() {
+            return Err(format_err!("no measurements"));
+        }
+        if config.interval_ms == 0 {
+            return Err(format_err!("interval_ms is 0"));
+        }
+        if config.measurements.iter().all(|m| m.weight == 0) {
+            return Err(format_err!("the weights of the measurements are all 0"));
+        }
+
+        let mut templates = vec![];
+        for m in config.measurements.iter() {
+            let kind = kind::try_from_str(&m.kind)
+                .map_err(|_| format_err!("{}: unknown kind {}", m.name, m.kind))?;
+            let unit = Unit::try_from_str(&m.unit, 0)
+                .map_err(|_| format_err!("{}: unknown unit {}", m.name, m.unit))?
+                .get_type();
+            if m.min > m.max {
+                return Err(format_err!("{}: min is greater than max", m.name));
+            }
+            for (tag, distribution) in m.tags.iter() {
+                distribution
+                    .check()
+                    .map_err(|e| format_err!("{}: tag {}: {}", m.name, tag, e))?;
+            }
+
+            templates.push(Template {
+                name: m.name.clone(),
+                kind,
+                unit,
+                min: m.min,
+                max: m.max,
+                tags: m.tags.clone().into_iter().collect(),
+            });
+        }
+
+        Ok(Generator {
+            rng: Rng::new(config.seed.unwrap_or_else(timestamp_now)),
+            templates,
+            weights: config.measurements.iter().map(|m| m.weight).collect(),
+        })
+    }
+
+    pub fn generate(&mut self) -> Measurement {
+        let rng = &mut self.rng;
+        let template = &self.templates[rng.pick(&self.weights)];
+        let value = match template.unit {
+            UnitType::Gauge => {
+                let span = (template.max - template.min) as f64;
+                Unit::Gauge(template.min as f64 + rng.float() * span)
+            }
+            unit => unit
+                .to_unit(rng.range(template.min, template.max))
+                .expect("templates only have numeric units"),
+        };
+        let tags = template
+            .tags
+            .iter()
+            .map(|(tag, distribution)| (tag.as_str(), distribution.sample(rng)))
+            .collect::<Tags>();
+
+        Measurement::new(template.kind, template.name.clone(), value, tags)
+    }
+}
+
+/// A xorshift64* generator, which is plenty for fake data
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        match seed.wrapping_add(0x9E37_79B9_7F4A_7C15) {
+            0 => Rng(1),
+            state => Rng(state),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Between `min` and `max`, inclusive
+    fn range(&mut self, min: u64, max: u64) -> u64 {
+        match max - min {
+            std::u64::MAX => self.next_u64(),
+            span => min + self.next_u64() % (span + 1),
+        }
+    }
+
+    /// Between 0 and 1
+    fn float(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// The index of a weight, picked by weight
+    fn pick(&mut self, weights: &[u64]) -> usize {
+        let total = weights.iter().sum::<u64>();
+        let mut n = self.range(0, total - 1);
+        for (i, weight) in weights.iter().enumerate() {
+            if n < *weight {
+                return i;
+            }
+            n -= weight;
+        }
+
+        unreachable!()
+    }
+}
+
+pub struct Synthetic {
+    generator: Generator,
+    rate: u64,
+    interval_ms: u64,
+    /// Thousandths of a measurement that are due, but not sent yet
+    remainder: u64,
+    recipients: Vec<Recipient<Message>>,
+}
+
+impl Synthetic {
+    pub fn with_config(config: SyntheticConfig, recipients: Vec<Recipient<Message>>) -> Self {
+        Synthetic {
+            generator: Generator::new(&config).unwrap(),
+            rate: config.rate,
+            interval_ms: config.interval_ms,
+            remainder: 0,
+            recipients,
+        }
+    }
+
+    fn emit(&mut self) {
+        let due = self.rate * self.interval_ms + self.remainder;
+        self.remainder = due % 1000;
+        let count = due / 1000;
+        if count == 0 {
+            return;
+        }
+
+        let measurements = (0..count).map(|_| self.generator.generate()).collect();
+        self.recipients.do_send(Message::List(measurements));
+    }
+}
+
+impl SendToManyRecipients for Synthetic {
+    fn recipients(&self) -> &Vec<Recipient<Message>> {
+        &self.recipients
+    }
+}
+
+impl Actor for Synthetic {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("synthetic probe started, {} measurements/s", self.rate);
+        ctx.run_interval(Duration::from_millis(self.interval_ms), |act, _ctx| {
+            act.emit()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SyntheticConfig {
+        toml::from_str(
+            r#"
+seed = 42
+
+[[measurements]]
+name = "connection.out"
+unit = "byte"
+min = 100
+max = 200
+weight = 3
+[measurements.tags]
+process_str = ["nginx", "curl"]
+d_port = { min = 1, max = 1024 }
+d_ip = { cardinality = 4, prefix = "10.0.0." }
+
+[[measurements]]
+name = "load"
+kind = "gauge"
+unit = "gauge"
+max = 10
+[measurements.tags]
+proto = { values = ["tcp", "udp"], weights = [1, 0] }
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn generates() {
+        let mut generator = Generator::new(&config()).unwrap();
+        let ms = (0..1000).map(|_| generator.generate()).collect::<Vec<_>>();
+
+        let (out, load): (Vec<_>, Vec<_>) = ms.iter().partition(|m| m.name == "connection.out");
+        assert!((650..850).contains(&out.len()));
+        for m in out {
+            assert!((100..=200).contains(&m.value.get()));
+            assert!(["nginx", "curl"].contains(&m.tags.get("process_str").unwrap()));
+            let port = m.tags.get("d_port").unwrap().parse::<u16>().unwrap();
+            assert!((1..=1024).contains(&port));
+            let ip = m.tags.get("d_ip").unwrap();
+            assert!(["10.0.0.0", "10.0.0.1", "10.0.0.2", "10.0.0.3"].contains(&ip));
+        }
+        for m in load {
+            assert_eq!(m.kind, kind::GAUGE);
+            assert!((0.0..=10.0).contains(&m.value.get_f64()));
+            assert_eq!(m.tags.get("proto"), Some("tcp"));
+        }
+
+        let mut again = Generator::new(&config()).unwrap();
+        assert_eq!(again.generate().tags, ms[0].tags);
+    }
+
+    #[test]
+    fn rejects() {
+        let mut c = config();
+        c.measurements[0].unit = "furlong".to_string();
+        assert!(Generator::new(&c).is_err());
+
+        let mut c = config();
+        c.measurements[1]
+            .tags
+            .insert("empty".to_string(), TagDistribution::Values(vec![]));
+        assert!(Generator::new(&c).is_err());
+
+        let mut c = config();
+        c.measurements.clear();
+        assert!(Generator::new(&c).is_err());
+    }
+}
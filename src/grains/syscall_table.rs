@@ -0,0 +1,46 @@
+//! The system calls of the architecture ingraind is built for.
+//!
+//! The table is generated by `build.rs` from `syscalls/<arch>.tbl`, and is
+//! empty on architectures without one, where system calls are only reported
+//! by number.
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/syscall_table.rs"));
+}
+
+pub use self::generated::SYSCALLS;
+
+/// The name of system call `nr`
+pub fn name(nr: u64) -> Option<&'static str> {
+    SYSCALLS
+        .iter()
+        .find(|(number, _)| *number == nr)
+        .map(|(_, name)| *name)
+}
+
+/// The number of system call `name`
+pub fn number(name: &str) -> Option<u64> {
+    SYSCALLS
+        .iter()
+        .find(|(_, syscall)| *syscall == name)
+        .map(|(nr, _)| *nr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        for (nr, syscall) in SYSCALLS.iter() {
+            assert_eq!(name(*nr), Some(*syscall));
+            assert_eq!(number(syscall), Some(*nr));
+        }
+        assert_eq!(number("bogus"), None);
+
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(name(59), Some("execve"));
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(name(221), Some("execve"));
+    }
+}
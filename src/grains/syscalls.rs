@@ -1,75 +1,79 @@
-use std::collections::HashMap;
-use std::fs;
 use std::os::raw::c_char;
 
+use crate::grains::probes::syscalls::maps;
+use crate::grains::sampling::SamplingConfig;
+use crate::grains::syscall_table;
 use crate::grains::*;
 
 use failure::Error;
-use redbpf::uname::get_kernel_internal_version;
 use redbpf::{HashMap as BPFHashMap, Module};
 
 use ingraind_probes::syscalls::SyscallTracepoint;
 
-type KSyms = HashMap<u64, String>;
+unsafe impl FromRawEvent for SyscallTracepoint {}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SyscallConfig {
-    symbol_map: Option<String>,
     monitor_syscalls: Vec<String>,
-    ksyms: Option<KSyms>,
+    sampling: Option<SamplingConfig>,
 }
 pub struct Syscall(pub SyscallConfig);
 
-#[cfg(target_arch = "x86_64")]
-const SYSCALL_PREFIX: &str = "__x64_sys_";
+impl SyscallConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if let Some(ref sampling) = self.sampling {
+            sampling.check()?;
+        }
 
-#[cfg(target_arch = "aarch64")]
-const SYSCALL_PREFIX: &str = "__arm64_sys_";
+        Ok(())
+    }
+}
 
 impl EBPFProbe for Grain<Syscall> {
     fn attach(&mut self) -> MessageStreams {
-        let prefix = if get_kernel_internal_version().unwrap() >= 0x041100 {
-            SYSCALL_PREFIX
-        } else {
-            "sys_"
-        };
-        let bind_to = self.native.0.monitor_syscalls.clone();
-        bind_to
-            .iter()
-            .flat_map(|syscall| self.attach_kprobes_to_names(&format!("{}{}", prefix, syscall)))
-            .collect()
+        self.attach_tracepoints("raw_syscalls", "sys_enter")
     }
 }
 
 impl EBPFGrain<'static> for Syscall {
     fn code() -> &'static [u8] {
-        include_bytes!(concat!(
-            env!("OUT_DIR"),
-            "/target/bpf/programs/syscalls/syscalls.elf"
-        ))
+        probes::syscalls::CODE
     }
 
-    fn loaded(&mut self, module: &mut Module) {
-        let symfile = match self.0.symbol_map {
-            Some(ref path) => path.clone(),
-            None => "/proc/kallsyms".to_string(),
-        };
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        let map = BPFHashMap::<u8, u64>::new(find_map_by_name(module, maps::HOST_PID)).unwrap();
+        map.set(1u8, std::process::id() as u64);
 
-        self.0.ksyms = Some(parse_symbol_map(&symfile).unwrap());
+        let monitored =
+            BPFHashMap::<u64, u8>::new(find_map_by_name(module, maps::MONITORED)).unwrap();
+        for syscall in self.0.monitor_syscalls.iter() {
+            match syscall_table::number(syscall) {
+                Some(nr) => monitored.set(nr, 1),
+                None => warn!("Unknown system call: {}", syscall),
+            }
+        }
 
-        let map = BPFHashMap::<u8, u64>::new(find_map_by_name(module, "host_pid")).unwrap();
-        map.set(1u8, std::process::id() as u64);
+        Ok(())
+    }
+
+    fn sampled(&self, _id: &str) -> bool {
+        true
+    }
+
+    fn sampling(&self) -> Option<SamplingConfig> {
+        self.0.sampling.clone()
     }
 
     fn get_handler(&self, _id: &str) -> EventCallback {
-        let ksyms = self.0.ksyms.clone().unwrap();
-        Box::new(move |raw| {
-            let data = unsafe { std::ptr::read(raw.as_ptr() as *const SyscallTracepoint) };
+        decoded(move |data: SyscallTracepoint| {
             let mut tags = Tags::new();
 
-            let syscall_name = ksyms[&data.syscall_nr].clone();
+            let syscall_name = syscall_table::name(data.syscall_nr)
+                .map(str::to_string)
+                .unwrap_or_else(|| data.syscall_nr.to_string());
             tags.insert("syscall_str", syscall_name);
 
-            tags.insert("process_id", data.id.to_string());
+            tags.insert("process_id", data.id);
             tags.insert(
                 "process_str",
                 crate::grains::to_string(unsafe { &*(&data.comm as *const [c_char]) }),
@@ -84,17 +88,3 @@ impl EBPFGrain<'static> for Syscall {
         })
     }
 }
-
-fn parse_symbol_map(path: &str) -> Result<KSyms, Error> {
-    let symmap = fs::read_to_string(path)?;
-    Ok(symmap
-        .lines()
-        .map(|l| l.splitn(4, ' ').collect::<Vec<&str>>())
-        .map(|tokens| {
-            (
-                u64::from_str_radix(&tokens[0], 16).unwrap(),
-                tokens[2].to_string(),
-            )
-        })
-        .collect())
-}
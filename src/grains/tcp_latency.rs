@@ -0,0 +1,63 @@
+use failure::Error;
+use redbpf::{HashMap as BPFHashMap, Module};
+
+use crate::grains::network::conn_tags;
+use crate::grains::*;
+
+use ingraind_probes::tcp_latency::{LatencyEvent, RTT_SAMPLE_INTERVAL};
+
+unsafe impl FromRawEvent for LatencyEvent {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+
+pub struct TcpLatency(pub TcpLatencyConfig);
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TcpLatencyConfig {
+    #[serde(default = "default_rtt_sample_interval_ms")]
+    rtt_sample_interval_ms: u64,
+}
+
+fn default_rtt_sample_interval_ms() -> u64 {
+    1000
+}
+
+impl EBPFProbe for Grain<TcpLatency> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for TcpLatency {
+    fn code() -> &'static [u8] {
+        probes::tcp_latency::CODE
+    }
+
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        let settings = find_map_by_name(module, probes::tcp_latency::maps::SETTINGS);
+        let settings = BPFHashMap::<u8, u64>::new(settings).unwrap();
+        settings.set(
+            RTT_SAMPLE_INTERVAL,
+            self.0.rtt_sample_interval_ms * 1_000_000,
+        );
+
+        Ok(())
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: LatencyEvent| {
+            let (name, conn, ns) = match event {
+                LatencyEvent::Rtt(conn, srtt_us) => ("tcp.rtt", conn, u64::from(srtt_us) * 1000),
+                LatencyEvent::Connect(conn, ns) => ("tcp.connect_latency", conn, ns),
+            };
+
+            Some(Message::Single(Measurement::new(
+                TIMER | HISTOGRAM,
+                name.to_string(),
+                Unit::Duration(ns),
+                conn_tags(&conn),
+            )))
+        })
+    }
+}
@@ -1,8 +1,10 @@
 #![allow(non_camel_case_types)]
 
+use crate::backends::pcap::{self, LINKTYPE_ETHERNET};
 use crate::grains::protocol::ETH_HLEN;
+use crate::grains::sockets;
 use crate::grains::*;
-use crate::metrics::Tags;
+use crate::metrics::{timestamp_now, Tags};
 
 use rustls::internal::msgs::{
     codec::Codec, enums::ContentType, enums::ServerNameType, handshake::ClientHelloPayload,
@@ -11,12 +13,14 @@ use rustls::internal::msgs::{
 };
 use rustls::CipherSuite;
 
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
 
 pub struct TLS(pub TlsConfig);
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TlsConfig {
     interface: String,
+    #[serde(default)]
+    capture_packets: bool,
 }
 
 impl EBPFProbe for Grain<TLS> {
@@ -28,38 +32,69 @@ impl EBPFProbe for Grain<TLS> {
 
 impl EBPFGrain<'static> for TLS {
     fn code() -> &'static [u8] {
-        include_bytes!(concat!(env!("OUT_DIR"), "/target/bpf/programs/tls/tls.elf"))
+        probes::tls::CODE
     }
 
     fn get_handler(&self, _id: &str) -> EventCallback {
-        Box::new(tls_to_message)
+        let interface = self.0.interface.clone();
+        let capture_packets = self.0.capture_packets;
+        let netns = netns::current();
+        Box::new(move |_ctx, buf| {
+            let message = tls_to_message(buf, &interface, netns);
+            if !capture_packets {
+                return Ok(message);
+            }
+
+            let packet = pcap::packet(
+                "tls",
+                timestamp_now(),
+                &interface,
+                LINKTYPE_ETHERNET,
+                buf.to_vec(),
+            );
+            match message? {
+                Some(Message::Single(m)) => Ok(Some(Message::List(vec![m, packet]))),
+                _ => Ok(Some(Message::Single(packet))),
+            }
+        })
     }
 }
 
-fn tls_to_message(buf: &[u8]) -> Option<Message> {
+fn tls_to_message(
+    buf: &[u8],
+    interface: &str,
+    netns: Option<u32>,
+) -> Result<Option<Message>, DecodeError> {
+    let malformed = || DecodeError::malformed("TLS handshake", buf.len());
     let (handshake, version) = {
-        let offset = tcp_payload_offset(buf);
-        let mut packet = TLSMessage::read_bytes(&buf[offset..])?;
-
-        if packet.typ == ContentType::Handshake && packet.decode_payload() {
-            if let MessagePayload::Handshake(x) = packet.payload {
-                (x, packet.version)
-            } else {
-                return None;
-            }
+        let offset = tcp_payload_offset(buf).ok_or_else(malformed)?;
+        let mut packet = TLSMessage::read_bytes(&buf[offset..]).ok_or_else(malformed)?;
+
+        if packet.typ != ContentType::Handshake {
+            return Ok(None);
+        }
+        if !packet.decode_payload() {
+            return Err(malformed());
+        }
+        if let MessagePayload::Handshake(x) = packet.payload {
+            (x, packet.version)
         } else {
-            return None;
+            return Ok(None);
         }
     };
 
     let mut tags = tag_ip_and_ports(buf);
     tags.insert("tls_version", format!("{:?}", &version));
+    tags.insert("interface", interface);
+    if let Some(netns) = netns {
+        tags.insert("netns", netns);
+    }
 
     use self::HandshakePayload::*;
     match handshake.payload {
-        ClientHello(payload) => parse_clienthello(payload, tags),
-        ServerHello(payload) => parse_serverhello(payload, tags),
-        _ => None,
+        ClientHello(payload) => Ok(parse_clienthello(payload, tags)),
+        ServerHello(payload) => Ok(parse_serverhello(payload, tags)),
+        _ => Ok(None),
     }
 }
 
@@ -116,13 +151,19 @@ fn tag_ip_and_ports(buf: &[u8]) -> Tags {
 
     tags.insert("d_ip", d_ip);
     tags.insert("s_ip", s_ip);
-    tags.insert("d_port", d_port.to_string());
-    tags.insert("s_port", s_port.to_string());
+    tags.insert("d_port", d_port);
+    tags.insert("s_port", s_port);
+
+    let source = SocketAddr::new(s_ip.into(), s_port);
+    let destination = SocketAddr::new(d_ip.into(), d_port);
+    if let Some(owner) = sockets::by_flow("tcp", source, destination) {
+        owner.tag(&mut tags);
+    }
 
     tags
 }
 
-fn parse_ips(buf: &[u8]) -> (String, String) {
+fn parse_ips(buf: &[u8]) -> (Ipv4Addr, Ipv4Addr) {
     let s = Ipv4Addr::new(
         buf[ETH_HLEN + 12],
         buf[ETH_HLEN + 13],
@@ -137,7 +178,7 @@ fn parse_ips(buf: &[u8]) -> (String, String) {
         buf[ETH_HLEN + 19],
     );
 
-    (d.to_string(), s.to_string())
+    (d, s)
 }
 
 fn parse_tcp_ports(buf: &[u8]) -> (u16, u16) {
@@ -158,9 +199,18 @@ fn tcp_len(buf: &[u8]) -> usize {
     ((buf[ETH_HLEN + iph_len(buf) + 12] as usize) >> 4) << 2
 }
 
-#[inline]
-fn tcp_payload_offset(buf: &[u8]) -> usize {
-    ETH_HLEN + iph_len(buf) + tcp_len(buf)
+/// Where the TCP payload starts, if `buf` holds the IP and TCP headers
+fn tcp_payload_offset(buf: &[u8]) -> Option<usize> {
+    const MIN_HEADER_LEN: usize = 20;
+
+    if buf.len() < ETH_HLEN + MIN_HEADER_LEN {
+        return None;
+    }
+    if buf.len() < ETH_HLEN + iph_len(buf) + MIN_HEADER_LEN {
+        return None;
+    }
+
+    Some(ETH_HLEN + iph_len(buf) + tcp_len(buf)).filter(|&offset| offset <= buf.len())
 }
 
 #[inline]
@@ -172,3 +222,33 @@ fn msg(name: &str, tags: Tags) -> Option<Message> {
         tags,
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grains::network::conn_tags;
+    use ingraind_probes::network::Connection;
+
+    #[test]
+    fn same_tags_as_tcp() {
+        // 10.0.0.1:51234 -> 10.0.0.2:443
+        let mut buf = vec![0; ETH_HLEN + 40];
+        buf[ETH_HLEN] = 0x45;
+        buf[ETH_HLEN + 12..ETH_HLEN + 16].copy_from_slice(&[10, 0, 0, 1]);
+        buf[ETH_HLEN + 16..ETH_HLEN + 20].copy_from_slice(&[10, 0, 0, 2]);
+        buf[ETH_HLEN + 20..ETH_HLEN + 22].copy_from_slice(&51234u16.to_be_bytes());
+        buf[ETH_HLEN + 22..ETH_HLEN + 24].copy_from_slice(&443u16.to_be_bytes());
+        let tls = tag_ip_and_ports(&buf);
+
+        let mut conn: Connection = unsafe { std::mem::zeroed() };
+        conn.saddr = unsafe { std::mem::transmute(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped()) };
+        conn.daddr = unsafe { std::mem::transmute(Ipv4Addr::new(10, 0, 0, 2).to_ipv6_mapped()) };
+        conn.sport = 51234;
+        conn.dport = u32::from(to_le(443));
+        let tcp = conn_tags(&conn);
+
+        for key in &["d_ip", "s_ip", "d_port", "s_port"] {
+            assert_eq!(tls.get_value(key), tcp.get_value(key), "{}", key);
+        }
+    }
+}
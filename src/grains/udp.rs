@@ -0,0 +1,40 @@
+use crate::grains::network::conn_tags;
+use crate::grains::*;
+
+use ingraind_probes::udp::Message as UdpMessage;
+
+unsafe impl FromRawEvent for UdpMessage {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+
+pub struct UDP;
+
+impl EBPFProbe for Grain<UDP> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for UDP {
+    fn code() -> &'static [u8] {
+        probes::udp::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: UdpMessage| {
+            let (name, conn, vol) = match event {
+                UdpMessage::Send(conn, size) => ("udp.out", conn, size),
+                UdpMessage::Receive(conn, size) => ("udp.in", conn, size),
+            };
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | HISTOGRAM,
+                name.to_string(),
+                Unit::Byte(u64::from(vol)),
+                conn_tags(&conn),
+            )))
+        })
+    }
+}
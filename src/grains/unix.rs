@@ -0,0 +1,81 @@
+use crate::grains::*;
+
+use ingraind_probes::unix::{UnixMessage, UnixSend};
+
+unsafe impl FromRawEvent for UnixMessage {
+    fn valid(raw: &[u8]) -> bool {
+        known_variant(raw, 0, 2)
+    }
+}
+
+pub struct Unix;
+
+impl EBPFProbe for Grain<Unix> {
+    fn attach(&mut self) -> MessageStreams {
+        self.attach_kprobes()
+    }
+}
+
+impl EBPFGrain<'static> for Unix {
+    fn code() -> &'static [u8] {
+        probes::unix::CODE
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        decoded(|event: UnixMessage| {
+            let (send, socket_type) = match event {
+                UnixMessage::Stream(send) => (send, "stream"),
+                UnixMessage::Dgram(send) => (send, "dgram"),
+            };
+
+            let mut tags = unix_tags(&send);
+            tags.insert("socket_type", socket_type);
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | HISTOGRAM | METER,
+                "unix.out".to_string(),
+                Unit::Byte(send.len as u64),
+                tags,
+            )))
+        })
+    }
+}
+
+fn unix_tags(send: &UnixSend) -> Tags {
+    let mut tags = Tags::new();
+    tags.insert("process_id", send.pid);
+    tags.insert("process_str", to_string(&send.comm));
+    tags.insert("path_str", path_name(&send.path));
+
+    tags
+}
+
+/// Abstract socket names start with a NUL byte, and are shown with a
+/// leading `@` like `ss` and `netstat` do.
+fn path_name(path: &[u8]) -> String {
+    let (prefix, name) = match path.split_first() {
+        Some((0, rest)) if rest.first().map_or(false, |&c| c != 0) => ("@", rest),
+        _ => ("", path),
+    };
+
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    format!("{}{}", prefix, String::from_utf8_lossy(&name[..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_names() {
+        let mut path = [0u8; 108];
+        assert_eq!(path_name(&path), "");
+
+        path[..20].copy_from_slice(b"/var/run/docker.sock");
+        assert_eq!(path_name(&path), "/var/run/docker.sock");
+
+        let mut path = [0u8; 108];
+        path[1..10].copy_from_slice(b"/tmp/.X11");
+        assert_eq!(path_name(&path), "@/tmp/.X11");
+    }
+}
@@ -0,0 +1,570 @@
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::grains::probes::usdt::maps;
+use crate::grains::*;
+
+use failure::{bail, format_err, Error};
+use ingraind_probes::usdt::{
+    ArgSpec, UsdtEvent, ARG_MEMORY, ARG_STRING, MAX_ARGS, MAX_STRING_ARGS, NO_REG,
+};
+use redbpf::{HashMap as BPFHashMap, Module};
+
+unsafe impl FromRawEvent for UsdtEvent {}
+
+const NT_STAPSDT: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UsdtConfig {
+    binary: String,
+    provider: Option<String>,
+    probe: String,
+    pid: Option<i32>,
+    metric: Option<String>,
+    #[serde(default)]
+    string_args: Vec<usize>,
+}
+
+impl UsdtConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if !cfg!(target_arch = "x86_64") {
+            bail!("USDT probes are only supported on x86_64");
+        }
+
+        if self.string_args.len() > MAX_STRING_ARGS {
+            bail!(
+                "USDT probe {} can read at most {} string arguments",
+                self.probe,
+                MAX_STRING_ARGS
+            );
+        }
+
+        if let Some(i) = self.string_args.iter().find(|&&i| i >= MAX_ARGS) {
+            bail!(
+                "USDT probe {} can't read string argument {}, only the first {}",
+                self.probe,
+                i,
+                MAX_ARGS
+            );
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Usdt {
+    config: UsdtConfig,
+    notes: Vec<Note>,
+}
+
+impl Usdt {
+    /// Looks up the probe in the binary's `.note.stapsdt` section
+    pub fn new(config: UsdtConfig) -> Result<Usdt, Error> {
+        config.check()?;
+
+        let elf = fs::read(&config.binary)?;
+        let notes = read_notes(&elf)?
+            .into_iter()
+            .filter(|n| n.name == config.probe)
+            .filter(|n| config.provider.as_ref().map_or(true, |p| &n.provider == p))
+            .collect::<Vec<_>>();
+
+        if notes.is_empty() {
+            return Err(format_err!(
+                "no USDT probe {} in {}",
+                config.probe,
+                config.binary
+            ));
+        }
+
+        if notes.iter().any(|n| n.args != notes[0].args) {
+            warn!(
+                "USDT probe {} has different argument locations at each site, using the first",
+                config.probe
+            );
+        }
+
+        Ok(Usdt { config, notes })
+    }
+}
+
+impl EBPFProbe for Grain<Usdt> {
+    fn attach(&mut self) -> MessageStreams {
+        let binary = self.native.config.binary.clone();
+        let pid = self.native.config.pid;
+        let sites = self
+            .native
+            .notes
+            .iter()
+            .map(|n| UprobeLocation {
+                target: binary.clone(),
+                symbol: None,
+                offset: n.pc,
+            })
+            .collect::<Vec<_>>();
+        self.attach_uprobes(pid, |_| sites.clone())
+    }
+}
+
+impl EBPFGrain<'static> for Usdt {
+    fn code() -> &'static [u8] {
+        probes::usdt::CODE
+    }
+
+    fn loaded(&mut self, module: &mut Module) -> Result<(), Error> {
+        let specs = BPFHashMap::<u8, ArgSpec>::new(find_map_by_name(module, maps::ARGS)).unwrap();
+        for (i, arg) in self.notes[0].args.iter().enumerate().take(MAX_ARGS) {
+            let slot = self.config.string_args.iter().position(|&s| s == i);
+            if let Some(spec) = arg.spec(slot) {
+                specs.set(i as u8, spec);
+            }
+        }
+
+        for note in self.notes.iter().filter(|n| n.semaphore != 0) {
+            match self.config.pid {
+                Some(pid) => enable_semaphore(Path::new(&self.config.binary), note.semaphore, pid)
+                    .map_err(|e| {
+                        format_err!(
+                            "can't enable the semaphore of USDT probe {}:{}: {}",
+                            note.provider,
+                            note.name,
+                            e
+                        )
+                    })?,
+                None => warn!(
+                    "USDT probe {}:{} is guarded by a semaphore, set `pid` to enable it",
+                    note.provider, note.name
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_handler(&self, _id: &str) -> EventCallback {
+        let note = &self.notes[0];
+        let args = note.args.clone();
+        let string_args = self.config.string_args.clone();
+        let metric = self
+            .config
+            .metric
+            .clone()
+            .unwrap_or_else(|| format!("usdt.{}.{}", note.provider, note.name));
+
+        decoded(move |event: UsdtEvent| {
+            let mut tags = Tags::new();
+            tags.insert("process_id", event.pid);
+            tags.insert("process_str", to_string(&event.comm));
+
+            for (i, arg) in args.iter().enumerate() {
+                if let Some(slot) = string_args.iter().position(|&s| s == i) {
+                    tags.insert(format!("arg{}_str", i), to_string(&event.strings[slot]));
+                    continue;
+                }
+
+                if let Some(value) = arg.value(&event.regs, event.values.get(i).cloned()) {
+                    tags.insert(format!("arg{}", i), value.to_string());
+                }
+            }
+
+            Some(Message::Single(Measurement::new(
+                COUNTER | METER,
+                metric.clone(),
+                Unit::Count(1),
+                tags,
+            )))
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Note {
+    provider: String,
+    name: String,
+    /// File offset of the probe site
+    pc: u64,
+    /// File offset of the semaphore, or 0 if there is none
+    semaphore: u64,
+    args: Vec<Arg>,
+}
+
+struct Section {
+    name: String,
+    typ: u32,
+    addr: u64,
+    offset: u64,
+    size: u64,
+}
+
+/// Parses the USDT probes of a 64-bit little endian ELF binary, with
+/// addresses converted to file offsets for attaching
+fn read_notes(elf: &[u8]) -> Result<Vec<Note>, Error> {
+    let sections = read_sections(elf).ok_or_else(|| format_err!("unsupported ELF binary"))?;
+    let notes = match sections.iter().find(|s| s.name == ".note.stapsdt") {
+        Some(notes) => notes,
+        None => return Ok(vec![]),
+    };
+
+    // prelinking moves the binary, which is detected through the actual
+    // address of `.stapsdt.base`
+    let base = sections.iter().find(|s| s.name == ".stapsdt.base");
+    let to_offset = |addr: u64, note_base: u64| {
+        let addr = match base {
+            Some(base) => addr.wrapping_add(base.addr).wrapping_sub(note_base),
+            None => addr,
+        };
+
+        sections
+            .iter()
+            .filter(|s| s.typ != SHT_NOBITS && s.addr != 0)
+            .find(|s| s.addr <= addr && addr < s.addr + s.size)
+            .map(|s| addr - s.addr + s.offset)
+    };
+
+    let raw_notes = elf
+        .get(notes.offset as usize..(notes.offset + notes.size) as usize)
+        .and_then(parse_notes)
+        .ok_or_else(|| format_err!("malformed .note.stapsdt section"))?;
+
+    raw_notes
+        .into_iter()
+        .map(|raw| {
+            let pc = to_offset(raw.pc, raw.base).ok_or_else(|| {
+                format_err!(
+                    "USDT probe {}:{} is outside the binary",
+                    raw.provider,
+                    raw.name
+                )
+            })?;
+            let semaphore = match raw.semaphore {
+                0 => 0,
+                addr => to_offset(addr, raw.base).unwrap_or(0),
+            };
+
+            Ok(Note {
+                args: raw.args.split_whitespace().map(Arg::parse).collect(),
+                provider: raw.provider,
+                name: raw.name,
+                pc,
+                semaphore,
+            })
+        })
+        .collect()
+}
+
+/// A `stapsdt` note with link time addresses
+struct RawNote {
+    pc: u64,
+    base: u64,
+    semaphore: u64,
+    provider: String,
+    name: String,
+    args: String,
+}
+
+fn parse_notes(data: &[u8]) -> Option<Vec<RawNote>> {
+    let mut notes = vec![];
+    let mut pos = 0;
+    while pos + 12 <= data.len() {
+        let namesz = read_u32(data, pos)? as usize;
+        let descsz = read_u32(data, pos + 4)? as usize;
+        let typ = read_u32(data, pos + 8)?;
+        let name_start = pos + 12;
+        let desc_start = name_start + align4(namesz);
+        pos = desc_start + align4(descsz);
+
+        if typ != NT_STAPSDT || data.get(name_start..name_start + namesz) != Some(b"stapsdt\0") {
+            continue;
+        }
+
+        let desc = data.get(desc_start..desc_start + descsz)?;
+        let mut strings = desc
+            .get(24..)?
+            .split(|&c| c == 0)
+            .map(|s| String::from_utf8_lossy(s).to_string());
+
+        notes.push(RawNote {
+            pc: read_u64(desc, 0)?,
+            base: read_u64(desc, 8)?,
+            semaphore: read_u64(desc, 16)?,
+            provider: strings.next()?,
+            name: strings.next()?,
+            args: strings.next().unwrap_or_default(),
+        });
+    }
+
+    Some(notes)
+}
+
+fn read_sections(elf: &[u8]) -> Option<Vec<Section>> {
+    // 64-bit, little endian
+    if elf.get(..6)? != b"\x7fELF\x02\x01" {
+        return None;
+    }
+
+    let shoff = read_u64(elf, 0x28)? as usize;
+    let shentsize = read_u16(elf, 0x3a)? as usize;
+    let shnum = read_u16(elf, 0x3c)? as usize;
+    let shstrndx = read_u16(elf, 0x3e)? as usize;
+
+    let headers = (0..shnum)
+        .map(|i| {
+            let h = elf.get(shoff + i * shentsize..shoff + (i + 1) * shentsize)?;
+            Some((
+                read_u32(h, 0)? as usize,
+                Section {
+                    name: String::new(),
+                    typ: read_u32(h, 4)?,
+                    addr: read_u64(h, 16)?,
+                    offset: read_u64(h, 24)?,
+                    size: read_u64(h, 32)?,
+                },
+            ))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let strtab = &headers.get(shstrndx)?.1;
+    let strtab = elf.get(strtab.offset as usize..(strtab.offset + strtab.size) as usize)?;
+
+    Some(
+        headers
+            .into_iter()
+            .map(|(name, mut section)| {
+                let name = strtab.get(name..).unwrap_or_default();
+                let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                section.name = String::from_utf8_lossy(&name[..end]).to_string();
+                section
+            })
+            .collect(),
+    )
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    read_le(buf, pos, 2).map(|v| v as u16)
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    read_le(buf, pos, 4).map(|v| v as u32)
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> Option<u64> {
+    read_le(buf, pos, 8)
+}
+
+fn read_le(buf: &[u8], pos: usize, len: usize) -> Option<u64> {
+    let b = buf.get(pos..pos + len)?;
+    Some(b.iter().rev().fold(0, |acc, &b| acc << 8 | u64::from(b)))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Location {
+    Register(usize),
+    Constant(i64),
+    Memory(usize, i64),
+    Unsupported,
+}
+
+/// An argument specification like `-4@%edi` or `8@-16(%rbp)`
+#[derive(Debug, PartialEq, Clone)]
+struct Arg {
+    /// Negative for signed arguments
+    size: i8,
+    location: Location,
+}
+
+impl Arg {
+    fn parse(spec: &str) -> Arg {
+        let (size, operand) = match spec.find('@') {
+            Some(at) => (spec[..at].parse().unwrap_or(8), &spec[at + 1..]),
+            None => (8, spec),
+        };
+
+        let location = if operand.starts_with('$') {
+            operand[1..]
+                .parse()
+                .map(Location::Constant)
+                .unwrap_or(Location::Unsupported)
+        } else if operand.starts_with('%') {
+            register_index(operand)
+                .map(Location::Register)
+                .unwrap_or(Location::Unsupported)
+        } else if let (Some(open), true) = (operand.find('('), operand.ends_with(')')) {
+            let disp = &operand[..open];
+            let disp = if disp.is_empty() {
+                Some(0)
+            } else {
+                disp.parse().ok()
+            };
+            match (disp, register_index(&operand[open + 1..operand.len() - 1])) {
+                (Some(disp), Some(reg)) => Location::Memory(reg, disp),
+                _ => Location::Unsupported,
+            }
+        } else {
+            Location::Unsupported
+        };
+
+        Arg { size, location }
+    }
+
+    /// How the probe reads the argument if it's in memory, or a string
+    fn spec(&self, string_slot: Option<usize>) -> Option<ArgSpec> {
+        let string = string_slot.map_or(0, |_| ARG_STRING);
+        let (kind, reg, disp) = match self.location {
+            Location::Register(reg) if string != 0 => (string, reg as u8, 0),
+            Location::Constant(value) if string != 0 => (string, NO_REG, value),
+            Location::Memory(reg, disp) => (ARG_MEMORY | string, reg as u8, disp),
+            _ => return None,
+        };
+
+        Some(ArgSpec {
+            kind,
+            reg,
+            slot: string_slot.unwrap_or(0) as u8,
+            disp,
+        })
+    }
+
+    /// The value of the argument given the registers and, for arguments in
+    /// memory, the 8 bytes the probe read
+    fn value(&self, regs: &[u64], memory: Option<u64>) -> Option<i64> {
+        let len = match self.size.abs() {
+            len @ 1..=8 => len as usize,
+            _ => 8,
+        };
+
+        let raw = match self.location {
+            Location::Register(reg) => regs[reg],
+            Location::Constant(value) => value as u64,
+            Location::Memory(..) => memory?,
+            Location::Unsupported => return None,
+        };
+
+        let shift = 64 - 8 * len as u32;
+        let value = if self.size < 0 {
+            ((raw << shift) as i64) >> shift
+        } else {
+            ((raw << shift) >> shift) as i64
+        };
+
+        Some(value)
+    }
+}
+
+/// Maps x86_64 register names to their index in `struct pt_regs`
+fn register_index(name: &str) -> Option<usize> {
+    let name = name.trim_start_matches('%');
+    let index = match name {
+        "rax" | "eax" | "ax" | "al" => 10,
+        "rbx" | "ebx" | "bx" | "bl" => 5,
+        "rcx" | "ecx" | "cx" | "cl" => 11,
+        "rdx" | "edx" | "dx" | "dl" => 12,
+        "rsi" | "esi" | "si" | "sil" => 13,
+        "rdi" | "edi" | "di" | "dil" => 14,
+        "rbp" | "ebp" | "bp" | "bpl" => 4,
+        "rsp" | "esp" | "sp" | "spl" => 19,
+        "rip" => 16,
+        _ if name.starts_with('r') => {
+            let n = name[1..].trim_end_matches(|c| c == 'd' || c == 'w' || c == 'b');
+            match n.parse::<u8>().ok()? {
+                8 => 9,
+                9 => 8,
+                10 => 7,
+                11 => 6,
+                n @ 12..=15 => 15 - n as usize,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    Some(index)
+}
+
+/// Probes guarded by a semaphore only fire while it's non-zero, which
+/// needs to be incremented in the running process
+fn enable_semaphore(binary: &Path, offset: u64, pid: i32) -> Result<(), Error> {
+    let binary = fs::canonicalize(binary)?;
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    let addr = maps
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if Path::new(fields.get(5)?) != binary {
+                return None;
+            }
+
+            let mut range = fields[0].split('-');
+            let start = u64::from_str_radix(range.next()?, 16).ok()?;
+            let end = u64::from_str_radix(range.next()?, 16).ok()?;
+            let map_offset = u64::from_str_radix(fields.get(2)?, 16).ok()?;
+
+            if map_offset <= offset && offset < map_offset + (end - start) {
+                Some(start + offset - map_offset)
+            } else {
+                None
+            }
+        })
+        .next()
+        .ok_or_else(|| format_err!("{} is not mapped in process {}", binary.display(), pid))?;
+
+    let mem = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(format!("/proc/{}/mem", pid))?;
+    let mut count = [0u8; 2];
+    mem.read_exact_at(&mut count, addr)?;
+    let count = u16::from_le_bytes(count).wrapping_add(1);
+    mem.write_all_at(&count.to_le_bytes(), addr)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_args() {
+        let regs = (0..21).map(|i| i as u64 | 0xffff_ff00).collect::<Vec<_>>();
+
+        let arg = Arg::parse("-4@%edi");
+        assert_eq!(arg.location, Location::Register(14));
+        assert_eq!(arg.value(&regs, None), Some(0xffff_ff0e_u32 as i32 as i64));
+
+        let arg = Arg::parse("8@%r12");
+        assert_eq!(arg.location, Location::Register(3));
+        assert_eq!(arg.value(&regs, None), Some(0xffff_ff03));
+
+        assert_eq!(Arg::parse("1@%r9b").value(&regs, None), Some(8));
+        assert_eq!(Arg::parse("-4@$-5").value(&regs, None), Some(-5));
+        assert_eq!(Arg::parse("8@-16(%rbp)").location, Location::Memory(4, -16));
+        assert_eq!(
+            Arg::parse("-2@-16(%rbp)").value(&regs, Some(0xfffe)),
+            Some(-2)
+        );
+        assert_eq!(Arg::parse("8@-16(%rbp)").value(&regs, None), None);
+        assert_eq!(Arg::parse("8@(%rax)").location, Location::Memory(10, 0));
+        assert_eq!(Arg::parse("8@foo(%rip)").location, Location::Unsupported);
+    }
+
+    #[test]
+    fn arg_specs() {
+        assert!(Arg::parse("-4@%edi").spec(None).is_none());
+
+        let spec = Arg::parse("8@%rsi").spec(Some(1)).unwrap();
+        assert_eq!((spec.kind, spec.reg, spec.slot), (ARG_STRING, 13, 1));
+
+        let spec = Arg::parse("8@$4096").spec(Some(0)).unwrap();
+        assert_eq!((spec.kind, spec.reg, spec.disp), (ARG_STRING, NO_REG, 4096));
+
+        let spec = Arg::parse("8@-16(%rbp)").spec(Some(0)).unwrap();
+        assert_eq!(spec.kind, ARG_MEMORY | ARG_STRING);
+        assert_eq!((spec.reg, spec.disp), (4, -16));
+    }
+}
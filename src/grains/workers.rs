@@ -0,0 +1,166 @@
+//! Reading perf buffers on a pool of worker threads.
+//!
+//! Each online CPU's perf buffers are read by the same worker, which is
+//! pinned to the CPUs it reads, so events are decoded on the CPU that
+//! produced them. Workers send measurements straight to the pipelines.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+
+use actix::{Actor, Arbiter, Context, Recipient, Running, StreamHandler};
+use redbpf::cpus;
+
+use crate::backends::Message;
+use crate::grains::{
+    GrainStream, MessageStream, MessageStreams, PerfMessageStream, SendToManyRecipients,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WorkersConfig {
+    /// The number of worker threads, one per online CPU if 0
+    #[serde(default)]
+    pub threads: usize,
+    /// Whether workers are pinned to the CPUs they read
+    #[serde(default = "default_pin")]
+    pub pin: bool,
+}
+
+fn default_pin() -> bool {
+    true
+}
+
+#[derive(Clone)]
+pub struct Workers {
+    arbiters: Vec<Arbiter>,
+    /// The worker of each online CPU
+    cpus: HashMap<usize, usize>,
+}
+
+impl Workers {
+    pub fn start(config: &WorkersConfig) -> Workers {
+        let online = cpus::get_online()
+            .expect("couldn't list online CPUs")
+            .into_iter()
+            .map(|cpu| cpu as usize)
+            .collect::<Vec<_>>();
+        let threads = match config.threads {
+            0 => online.len(),
+            n => n.min(online.len()),
+        };
+
+        let cpus = online
+            .iter()
+            .enumerate()
+            .map(|(i, cpu)| (*cpu, i % threads))
+            .collect::<HashMap<_, _>>();
+        let arbiters = (0..threads)
+            .map(|worker| {
+                let arbiter = Arbiter::new();
+                if config.pin {
+                    let pinned = assigned(&cpus, worker);
+                    arbiter.exec_fn(move || {
+                        if let Err(e) = pin(&pinned) {
+                            warn!("Failed to pin worker to CPUs {:?}: {}", pinned, e);
+                        }
+                    });
+                }
+                arbiter
+            })
+            .collect();
+
+        info!("Reading perf buffers on {} workers", threads);
+        Workers { arbiters, cpus }
+    }
+
+    /// Starts reading `streams` on the workers of their CPUs, and returns
+    /// the streams not bound to a CPU
+    pub fn spawn(
+        &self,
+        streams: MessageStreams,
+        recipients: &[Recipient<Message>],
+    ) -> Vec<Box<MessageStream>> {
+        let mut local = vec![];
+        let mut assigned: HashMap<usize, Vec<PerfMessageStream>> = HashMap::new();
+        for stream in streams {
+            match stream {
+                GrainStream::Cpu(cpu, stream) if self.cpus.contains_key(&cpu) => {
+                    assigned.entry(self.cpus[&cpu]).or_default().push(stream)
+                }
+                stream => local.push(stream.into_local()),
+            }
+        }
+
+        // the streams haven't been polled yet, so they register with the
+        // reactor of their worker, and are only ever read by it
+        for (worker, streams) in assigned {
+            let recipients = recipients.to_vec();
+            Actor::start_in_arbiter(&self.arbiters[worker], move |ctx| {
+                for stream in streams {
+                    ctx.add_stream(stream);
+                }
+                Worker { recipients }
+            });
+        }
+
+        local
+    }
+}
+
+fn assigned(cpus: &HashMap<usize, usize>, worker: usize) -> Vec<usize> {
+    let mut assigned = cpus
+        .iter()
+        .filter(|(_, w)| **w == worker)
+        .map(|(cpu, _)| *cpu)
+        .collect::<Vec<_>>();
+    assigned.sort();
+    assigned
+}
+
+/// Pins the calling thread to `cpus`
+fn pin(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+struct Worker {
+    recipients: Vec<Recipient<Message>>,
+}
+
+impl Actor for Worker {
+    type Context = Context<Self>;
+}
+
+impl StreamHandler<Vec<Message>, io::Error> for Worker {
+    fn handle(&mut self, messages: Vec<Message>, _ctx: &mut Context<Self>) {
+        for message in messages {
+            self.recipients.do_send(message);
+        }
+    }
+
+    fn error(&mut self, err: io::Error, _ctx: &mut Self::Context) -> Running {
+        error!("probe error: {}", err);
+        Running::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpus_of_workers() {
+        let cpus = [(0, 0), (1, 1), (2, 0), (3, 1)].iter().cloned().collect();
+        assert_eq!(assigned(&cpus, 0), vec![0, 2]);
+        assert_eq!(assigned(&cpus, 1), vec![1, 3]);
+    }
+}
@@ -0,0 +1,471 @@
+//! Tags measurements with Kubernetes metadata, when ingraind runs as a
+//! DaemonSet.
+//!
+//! Every measurement is tagged with the name of the node as `k8s_node`. With
+//! `api`, the pods running on the node are listed from the API server every
+//! `interval_s`, and the measurements of their processes are tagged with
+//! `k8s_namespace`, `k8s_pod`, and the configured `labels` and `annotations`
+//! of the pod. A process's pod is found through the `docker_id` tag of the
+//! `Container` aggregation, or the pod UID in its cgroups as kept by the
+//! process table of `grains::process_tree`.
+//!
+//! The labels and annotations of ingraind's own pod, read from a downward API
+//! volume, are the defaults of all measurements.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use actix::prelude::*;
+use failure::{bail, format_err, Error};
+use lazy_static::lazy_static;
+use rayon::prelude::*;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::backends::Message;
+use crate::grains::process_tree;
+use crate::metrics::Measurement;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref PODS: RwLock<Pods> = RwLock::new(Pods::default());
+    // cgroupfs paths have `pod<uid>`, systemd's have `-pod<uid_with_underscores>.slice`
+    static ref POD_PATTERN: Regex = Regex::new(
+        r"pod([0-9a-f]{8}[-_][0-9a-f]{4}[-_][0-9a-f]{4}[-_][0-9a-f]{4}[-_][0-9a-f]{12})"
+    )
+    .unwrap();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KubernetesConfig {
+    /// Defaults to the `NODE_NAME` environment variable
+    pub node_name: Option<String>,
+    /// A downward API volume with the `labels` and `annotations` of
+    /// ingraind's pod
+    pub pod_info: Option<String>,
+    /// Label keys, and the tags they are added as
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Annotation keys, and the tags they are added as
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    pub api: Option<ApiConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiConfig {
+    /// `host:port` of the API server. Defaults to the service in the
+    /// environment of every pod.
+    pub server: Option<String>,
+    /// The name the API server's certificate is checked against
+    #[serde(default = "default_server_name")]
+    pub server_name: String,
+    #[serde(default = "default_token_file")]
+    pub token_file: String,
+    #[serde(default = "default_ca_file")]
+    pub ca_file: String,
+    #[serde(default = "default_interval_s")]
+    pub interval_s: u64,
+}
+
+fn default_server_name() -> String {
+    "kubernetes.default.svc".to_string()
+}
+
+fn default_token_file() -> String {
+    "/var/run/secrets/kubernetes.io/serviceaccount/token".to_string()
+}
+
+fn default_ca_file() -> String {
+    "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt".to_string()
+}
+
+fn default_interval_s() -> u64 {
+    30
+}
+
+type PodTags = Vec<(String, String)>;
+
+/// The pods on the node, by UID and by the IDs of their containers
+#[derive(Default)]
+struct Pods {
+    by_uid: HashMap<String, Arc<PodTags>>,
+    by_container: HashMap<String, Arc<PodTags>>,
+}
+
+impl KubernetesConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        let api = match self.api {
+            Some(ref api) => api,
+            None => return Ok(()),
+        };
+        if api.interval_s == 0 {
+            bail!("kubernetes: interval_s must be greater than 0");
+        }
+        webpki::DNSNameRef::try_from_ascii_str(&api.server_name)
+            .map_err(|_| format_err!("kubernetes: invalid server name {}", api.server_name))?;
+        tls_config(api).map_err(|e| format_err!("kubernetes: {}", e))?;
+
+        Ok(())
+    }
+
+    fn node_name(&self) -> Option<String> {
+        self.node_name
+            .clone()
+            .or_else(|| env::var("NODE_NAME").ok())
+    }
+
+    /// The tags of the configured labels and annotations
+    fn tags(
+        &self,
+        labels: &BTreeMap<String, String>,
+        annotations: &BTreeMap<String, String>,
+    ) -> PodTags {
+        let tags = |keys: &BTreeMap<String, String>, values: &BTreeMap<String, String>| {
+            keys.iter()
+                .filter_map(|(key, tag)| Some((tag.clone(), values.get(key)?.clone())))
+                .collect::<Vec<_>>()
+        };
+
+        let mut pod_tags = tags(&self.labels, labels);
+        pod_tags.append(&mut tags(&self.annotations, annotations));
+        pod_tags
+    }
+}
+
+/// Starts listing the pods on the node, if `api` is set
+pub fn watch(config: &KubernetesConfig) {
+    let api = match config.api {
+        Some(ref api) => api.clone(),
+        None => return,
+    };
+    let node = match config.node_name() {
+        Some(node) => node,
+        None => {
+            warn!("The node name is unknown, not listing its pods");
+            return;
+        }
+    };
+    let tls = match tls_config(&api) {
+        Ok(tls) => Arc::new(tls),
+        Err(e) => {
+            error!("Invalid Kubernetes API settings, not listing pods: {}", e);
+            return;
+        }
+    };
+
+    let config = config.clone();
+    thread::Builder::new()
+        .name("kubernetes-pods".to_string())
+        .spawn(move || loop {
+            match list_pods(&api, &tls, &node) {
+                Ok(list) => *PODS.write().unwrap() = pods(&list, &config),
+                Err(e) => warn!("Failed to list the pods of {}: {}", node, e),
+            }
+            thread::sleep(Duration::from_secs(api.interval_s));
+        })
+        .unwrap();
+}
+
+fn tls_config(api: &ApiConfig) -> Result<rustls::ClientConfig, Error> {
+    let mut config = rustls::ClientConfig::new();
+    let mut ca = BufReader::new(File::open(&api.ca_file)?);
+    config
+        .root_store
+        .add_pem_file(&mut ca)
+        .map_err(|_| format_err!("Invalid certificates in {}", api.ca_file))?;
+
+    Ok(config)
+}
+
+fn list_pods(api: &ApiConfig, tls: &Arc<rustls::ClientConfig>, node: &str) -> Result<Value, Error> {
+    let server = match api.server {
+        Some(ref server) => server.clone(),
+        None => {
+            let host = env::var("KUBERNETES_SERVICE_HOST")?;
+            let port = env::var("KUBERNETES_SERVICE_PORT")?;
+            if host.contains(':') {
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            }
+        }
+    };
+    // service account tokens are rotated, so read it every time
+    let token = fs::read_to_string(&api.token_file)?;
+
+    let tcp = TcpStream::connect(&server)?;
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    let name = webpki::DNSNameRef::try_from_ascii_str(&api.server_name)
+        .map_err(|_| format_err!("Invalid server name {}", api.server_name))?;
+    let mut stream = rustls::StreamOwned::new(rustls::ClientSession::new(tls, name), tcp);
+
+    // HTTP/1.0 responses aren't chunked, and end when the connection closes
+    write!(
+        stream,
+        "GET /api/v1/pods?fieldSelector=spec.nodeName%3D{} HTTP/1.0\r\n\
+         Host: {}\r\n\
+         Authorization: Bearer {}\r\n\
+         Accept: application/json\r\n\r\n",
+        node,
+        api.server_name,
+        token.trim()
+    )?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+
+    Ok(serde_json::from_slice(body(&response)?)?)
+}
+
+/// The body of a successful HTTP response
+fn body(response: &[u8]) -> Result<&[u8], Error> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| format_err!("Incomplete response"))?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("Unexpected response: {}", status);
+    }
+
+    Ok(&response[end + 4..])
+}
+
+/// The pods of a pod list from the API server
+fn pods(list: &Value, config: &KubernetesConfig) -> Pods {
+    let mut pods = Pods::default();
+    let items = match list["items"].as_array() {
+        Some(items) => items,
+        None => return pods,
+    };
+
+    for pod in items {
+        let metadata = &pod["metadata"];
+        let uid = match metadata["uid"].as_str() {
+            Some(uid) => uid,
+            None => continue,
+        };
+
+        let mut tags = vec![];
+        for (tag, field) in &[("k8s_namespace", "namespace"), ("k8s_pod", "name")] {
+            if let Some(value) = metadata[*field].as_str() {
+                tags.push((tag.to_string(), value.to_string()));
+            }
+        }
+        tags.append(&mut config.tags(
+            &strings(&metadata["labels"]),
+            &strings(&metadata["annotations"]),
+        ));
+        let tags = Arc::new(tags);
+
+        let statuses = pod["status"]["containerStatuses"].as_array();
+        for status in statuses.into_iter().flatten() {
+            // like `containerd://<id>`
+            if let Some(id) = status["containerID"].as_str() {
+                let id = id.rsplit("://").next().unwrap();
+                pods.by_container.insert(id.to_string(), tags.clone());
+            }
+        }
+        pods.by_uid.insert(uid.to_string(), tags);
+    }
+
+    pods
+}
+
+fn strings(map: &Value) -> BTreeMap<String, String> {
+    map.as_object()
+        .map(|map| {
+            map.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads a downward API file of `key="value"` lines
+fn pod_info(path: &Path) -> BTreeMap<String, String> {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let eq = line.find('=')?;
+            let value = serde_json::from_str(&line[eq + 1..]).ok()?;
+            Some((line[..eq].to_string(), value))
+        })
+        .collect()
+}
+
+/// The UID of the pod in a cgroup path
+fn pod_uid(cgroup: &str) -> Option<String> {
+    POD_PATTERN
+        .captures(cgroup)
+        .and_then(|c| c.get(1))
+        .map(|uid| uid.as_str().replace('_', "-"))
+}
+
+fn pod_tags(msg: &Measurement) -> Option<Arc<PodTags>> {
+    {
+        let pods = PODS.read().unwrap();
+        if pods.by_uid.is_empty() {
+            return None;
+        }
+        if let Some(tags) = msg
+            .tags
+            .get("docker_id")
+            .and_then(|id| pods.by_container.get(id))
+        {
+            return Some(tags.clone());
+        }
+    }
+
+    // the process table reads the cgroups of a process once
+    let pid = msg.tags.get("process_id")?.parse().ok()?;
+    let process = process_tree::get(pid)?;
+    let uid = process.cgroups.iter().find_map(|path| pod_uid(path))?;
+    PODS.read().unwrap().by_uid.get(&uid).cloned()
+}
+
+/// The namespace of the pod `msg` was measured in
+pub fn namespace(msg: &Measurement) -> Option<String> {
+    pod_tags(msg)?
+        .iter()
+        .find(|(tag, _)| tag == "k8s_namespace")
+        .map(|(_, namespace)| namespace.clone())
+}
+
+fn add_tags(msg: &mut Measurement, defaults: &[(String, String)]) {
+    let pod = pod_tags(msg);
+    for (tag, value) in defaults.iter().chain(pod.iter().flat_map(|p| p.iter())) {
+        msg.tags.insert(tag, value);
+    }
+}
+
+pub struct AddKubernetesDetails {
+    defaults: Arc<PodTags>,
+    upstream: Recipient<Message>,
+}
+
+impl Actor for AddKubernetesDetails {
+    type Context = Context<Self>;
+}
+
+impl AddKubernetesDetails {
+    pub fn launch(config: &KubernetesConfig, upstream: Recipient<Message>) -> Recipient<Message> {
+        let mut defaults = vec![];
+        if let Some(node) = config.node_name() {
+            defaults.push(("k8s_node".to_string(), node));
+        }
+        if let Some(ref dir) = config.pod_info {
+            let dir = Path::new(dir);
+            defaults.append(&mut config.tags(
+                &pod_info(&dir.join("labels")),
+                &pod_info(&dir.join("annotations")),
+            ));
+        }
+
+        AddKubernetesDetails {
+            defaults: Arc::new(defaults),
+            upstream,
+        }
+        .start()
+        .recipient()
+    }
+}
+
+impl Handler<Message> for AddKubernetesDetails {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let defaults = self.defaults.clone();
+        match msg {
+            Message::List(ref mut ms) => ms.par_iter_mut().for_each(|m| add_tags(m, &defaults)),
+            Message::Single(ref mut m) => add_tags(m, &defaults),
+            Message::Flush => {}
+        }
+
+        self.upstream.do_send(msg).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> KubernetesConfig {
+        toml::from_str(
+            r#"
+node_name = "node-1"
+labels = { "app.kubernetes.io/name" = "app", "team" = "team" }
+annotations = { "example.com/owner" = "owner" }
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn pod_list() {
+        let list = serde_json::json!({
+            "items": [{
+                "metadata": {
+                    "name": "web-7d4b9",
+                    "namespace": "shop",
+                    "uid": "a21e738c-d6b6-11e8-82df-002590deaca4",
+                    "labels": { "app.kubernetes.io/name": "web", "pod-template-hash": "7d4b9" },
+                    "annotations": { "example.com/owner": "edge" }
+                },
+                "status": {
+                    "containerStatuses": [{ "containerID": "containerd://a844b859" }]
+                }
+            }]
+        });
+
+        let pods = pods(&list, &config());
+        let tags = &pods.by_container["a844b859"];
+        assert_eq!(
+            **tags,
+            vec![
+                ("k8s_namespace".to_string(), "shop".to_string()),
+                ("k8s_pod".to_string(), "web-7d4b9".to_string()),
+                ("app".to_string(), "web".to_string()),
+                ("owner".to_string(), "edge".to_string()),
+            ]
+        );
+        assert!(pods
+            .by_uid
+            .contains_key("a21e738c-d6b6-11e8-82df-002590deaca4"));
+    }
+
+    #[test]
+    fn cgroup_pod_uid() {
+        let cgroupfs =
+            "4:pids:/kubepods/besteffort/poda21e738c-d6b6-11e8-82df-002590deaca4/a844b859\n";
+        let systemd = "0::/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-poda21e738c_d6b6_11e8_82df_002590deaca4.slice/cri-containerd-a844b859.scope\n";
+
+        for cgroup in &[cgroupfs, systemd] {
+            assert_eq!(
+                pod_uid(cgroup).as_ref().map(String::as_str),
+                Some("a21e738c-d6b6-11e8-82df-002590deaca4")
+            );
+        }
+        assert_eq!(pod_uid("0::/user.slice/session-1.scope\n"), None);
+    }
+
+    #[test]
+    fn http_body() {
+        let response = b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}";
+        assert_eq!(body(response).unwrap(), b"{}");
+
+        let response = b"HTTP/1.0 403 Forbidden\r\n\r\n{}";
+        assert!(body(response).is_err());
+    }
+}
@@ -1,3 +1,6 @@
+//! The ingraind agent, for embedding in other applications. See
+//! [`agent::Agent`](agent/struct.Agent.html) to get started.
+
 #![deny(clippy::all)]
 
 #[macro_use]
@@ -6,14 +9,36 @@ extern crate actix;
 extern crate serde_derive;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "admin-endpoint")]
+pub mod admin;
+pub mod agent;
 pub mod aggregations;
 pub mod backends;
 pub mod config;
+pub mod containers;
+pub mod control;
 pub mod grains;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+pub mod logging;
+pub mod memlock;
 pub mod metrics;
+pub mod privileges;
+pub mod record;
+pub mod secrets;
+pub mod shedding;
+pub mod shutdown;
+pub mod systemd;
+pub mod telemetry;
+pub mod tenancy;
 #[cfg(feature = "capnp-encoding")]
 mod ingraind_capnp {
     #![allow(clippy::all)]
     #![allow(dead_code)]
     include!(concat!(env!("OUT_DIR"), "/schema/ingraind_capnp.rs"));
-}
\ No newline at end of file
+}
+#[cfg(feature = "protobuf-encoding")]
+mod ingraind_proto {
+    #![allow(clippy::all)]
+    include!(concat!(env!("OUT_DIR"), "/ingraind.rs"));
+}
@@ -0,0 +1,94 @@
+//! Structured logging through `tracing`.
+//!
+//! Grains log the grain, program and map an event relates to as fields,
+//! which are kept as separate keys when logs are written as JSON. Messages
+//! logged through the `log` macros are forwarded, too.
+//!
+//! With the other log backends, no subscriber is installed, and `tracing`
+//! events are logged through `log` instead, with their fields appended to
+//! the message.
+
+use std::collections::HashMap;
+
+use failure::{format_err, Error};
+use log::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TracingConfig {
+    #[serde(default = "default_log_level")]
+    pub log_level: LevelFilter,
+    /// Levels of individual modules, like `ingraind::grains::dns`
+    #[serde(default)]
+    pub modules: HashMap<String, LevelFilter>,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+fn default_log_level() -> LevelFilter {
+    LevelFilter::Info
+}
+
+impl TracingConfig {
+    fn directives(&self) -> String {
+        let mut modules = self
+            .modules
+            .iter()
+            .map(|(module, level)| format!("{}={}", module, level))
+            .collect::<Vec<_>>();
+        modules.sort();
+
+        let mut directives = vec![self.log_level.to_string()];
+        directives.append(&mut modules);
+        directives.join(",").to_lowercase()
+    }
+}
+
+/// Installs the global subscriber, and forwards messages logged with `log`
+/// to it
+pub fn init(config: &TracingConfig) -> Result<(), Error> {
+    let filter = EnvFilter::try_new(config.directives())?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }
+    .map_err(|e| format_err!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directives() {
+        let config: TracingConfig = toml::from_str(
+            r#"
+log_level = "WARN"
+format = "Json"
+[modules]
+"ingraind::grains::dns" = "DEBUG"
+"ingraind::backends" = "ERROR"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.directives(),
+            "warn,ingraind::backends=error,ingraind::grains::dns=debug"
+        );
+    }
+}
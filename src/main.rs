@@ -1,11 +1,11 @@
 #![deny(clippy::all)]
 
-use std::collections::HashMap;
 use std::env;
-use std::fs;
+use std::process;
 
-use actix::Recipient;
-use ingraind::{backends::Message, config};
+use actix::Actor;
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use ingraind::{agent::Agent, backends::supervisor, config, logging, record, systemd};
 
 #[cfg(feature = "capnp-encoding")]
 mod ingraind_capnp {
@@ -23,65 +23,146 @@ fn init_logging(config: &config::Config) {
             EnvLogger => env_logger::init(),
             Syslog(c) => syslog::init(Facility::LOG_USER, c.log_level, Some("ingraind"))
                 .expect("Could not initialise syslog backend!"),
+            Tracing(c) => logging::init(c).expect("Could not initialise tracing backend!"),
         };
     } else {
         env_logger::init();
     }
 }
 
+fn load_config(path: &str, overrides: &[&str]) -> config::Config {
+    config::Config::load_layered(path, overrides).unwrap_or_else(|e| {
+        eprintln!("Error while loading config file {}: {}", path, e);
+        process::exit(1)
+    })
+}
+
+fn check(path: &str, overrides: &[&str]) {
+    let config = load_config(path, overrides);
+    match config.check() {
+        Ok(programs) => {
+            for (probe, programs) in config.probe.iter().zip(programs) {
+                println!("{}: {} programs", probe.grain.name(), programs.len());
+            }
+            println!("{}: OK", path);
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1)
+        }
+    }
+}
+
+fn overrides<'a>(args: &'a ArgMatches) -> Vec<&'a str> {
+    args.values_of("set")
+        .map(Iterator::collect)
+        .unwrap_or_default()
+}
+
+fn list_probes() {
+    for (grain, options) in config::GRAINS {
+        if options.is_empty() {
+            println!("{}", grain);
+        } else {
+            println!("{}: {}", grain, options.join(", "));
+        }
+    }
+}
+
 fn main() {
     let panic_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |panic| {
         panic_hook(panic);
-        std::process::exit(1);
+        // supervisors restart the backends that panic
+        if !supervisor::is_supervised() {
+            std::process::exit(1);
+        }
     }));
 
-    let system = actix::System::new("userspace");
-    let io = actix::Arbiter::new();
+    let config_arg = Arg::with_name("config")
+        .help("Path to the configuration file")
+        .required(true)
+        .index(1);
+    let set_arg = Arg::with_name("set")
+        .long("set")
+        .value_name("KEY=VALUE")
+        .multiple(true)
+        .number_of_values(1)
+        .help("Overrides a configuration value, like pipeline.s3.config.bucket=logs");
+    let matches = App::new("ingraind")
+        .version(crate_version!())
+        .about("eBPF-based system monitoring agent")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .arg(config_arg.clone())
+        .arg(set_arg.clone())
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Prints the probes and pipelines without loading anything"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Records the raw events received from the probes"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE")
+                .conflicts_with("record")
+                .help("Replays recorded events through the probes and pipelines, then exits"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Validates the configuration and the probes it uses")
+                .arg(config_arg)
+                .arg(set_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("list-probes").about("Lists the available grains and options"),
+        )
+        .get_matches();
 
-    let mut config: config::Config = {
-        let file = env::args().nth(1).expect("Usage: ingraind <config file>");
-        let content = fs::read(file).expect("Unable to read config file");
-        toml::from_slice(content.as_slice()).expect("Error while parsing config file")
-    };
+    match matches.subcommand() {
+        ("check", Some(args)) => {
+            return check(args.value_of("config").unwrap(), &overrides(args));
+        }
+        ("list-probes", _) => return list_probes(),
+        _ => {}
+    }
+
+    let config = load_config(matches.value_of("config").unwrap(), &overrides(&matches));
+    if matches.is_present("dry-run") {
+        if let Err(e) = config.check() {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        print!("{}", config.describe());
+        return;
+    }
+
+    let system = actix::System::new("userspace");
 
     init_logging(&config);
-    let backends = config
-        .pipeline
-        .drain()
-        .map(|(key, pipeline)| {
-            let mut backend = pipeline.backend.into_recipient();
-            let mut steps = pipeline.steps.unwrap_or_else(|| vec![]);
-            steps.reverse();
-
-            for step in steps.drain(..) {
-                backend = step.into_recipient(backend);
-            }
 
-            (key, backend)
-        })
-        .collect::<HashMap<String, Recipient<Message>>>();
-
-    let probe_actors: Vec<_> = config
-        .probe
-        .drain(..)
-        .map(|probe| {
-            let recipients = probe
-                .pipelines
-                .iter()
-                .map(|p| {
-                    backends
-                        .get(p)
-                        .unwrap_or_else(|| panic!("Invalid configuration: pipeline {} not found!", p))
-                        .clone()
-                })
-                .collect::<Vec<Recipient<Message>>>();
-            probe.grain.into_probe_actor(recipients)
-        })
-        .collect();
-
-    for actor in probe_actors {
-        actor.start(&io);
+    let agent = Agent::with_config(config);
+    if let Some(path) = matches.value_of("replay") {
+        agent.replay(path);
+        system.run().unwrap();
+        return;
+    }
+
+    if let Some(path) = matches.value_of("record") {
+        record::start(path).unwrap_or_else(|e| panic!("Unable to record to {}: {}", path, e));
+        record::Flusher.start();
+    }
+
+    agent.start();
+
+    if env::var_os("NOTIFY_SOCKET").is_some() {
+        systemd::Systemd::new().start();
     }
 
     system.run().unwrap();
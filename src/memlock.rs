@@ -0,0 +1,203 @@
+//! Raises the limit of locked memory, and checks that the maps of all grains
+//! fit in it before any is loaded.
+//!
+//! Kernels before 5.11 charge the memory of BPF maps to the
+//! `RLIMIT_MEMLOCK` of the process creating them, and refuse to create the
+//! maps over it with a bare `EPERM`. At startup, the limit is raised to
+//! `limit_bytes`, or lifted without it, unless `raise` is `false`. Without
+//! `CAP_SYS_RESOURCE`, it's only raised up to the hard limit.
+//!
+//! The memory the maps of all grains take is then estimated as these kernels
+//! charge it, with the perf buffers of perf maps, and ingraind stops,
+//! listing the largest maps, if it's over the limit. Kernels from 5.11 on
+//! charge maps to the memory cgroup instead, so there's nothing to do.
+
+use std::io;
+
+use failure::{format_err, Error};
+use redbpf::uname::get_kernel_internal_version;
+use tracing::{info, warn};
+
+use crate::config::Probe;
+use crate::grains::limits::map_bytes;
+
+/// The most maps listed when they don't fit
+const MAX_LISTED: usize = 5;
+
+/// 5.11, the first kernel that doesn't charge maps to RLIMIT_MEMLOCK
+const MEMCG_ACCOUNTING: u32 = 0x05_0b_00;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemlockConfig {
+    #[serde(default = "default_raise")]
+    pub raise: bool,
+    /// Lifts the limit when unset
+    pub limit_bytes: Option<u64>,
+}
+
+fn default_raise() -> bool {
+    true
+}
+
+impl Default for MemlockConfig {
+    fn default() -> Self {
+        MemlockConfig {
+            raise: default_raise(),
+            limit_bytes: None,
+        }
+    }
+}
+
+/// The memory a map of a grain declares
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapMemory {
+    pub grain: String,
+    pub map: String,
+    pub bytes: u64,
+}
+
+/// Raises the limit if configured to, then checks the maps of `probes` fit
+/// in the limit in effect
+pub fn preflight(config: &MemlockConfig, probes: &[Probe]) -> Result<(), Error> {
+    if get_kernel_internal_version().map_or(false, |v| v >= MEMCG_ACCOUNTING) {
+        return Ok(());
+    }
+
+    let limit = if config.raise {
+        raise(config.limit_bytes)?
+    } else {
+        current()?
+    };
+
+    let cpus = redbpf::cpus::get_online()
+        .map_err(|_| format_err!("Unable to list the online CPUs"))?
+        .len();
+    let mut maps = vec![];
+    for probe in probes.iter() {
+        let code = match probe.grain.code() {
+            Some(code) => code,
+            None => continue,
+        };
+        let grain = probe.grain.name();
+        for (map, bytes) in map_bytes(code, cpus)? {
+            maps.push(MapMemory {
+                grain: grain.clone(),
+                map,
+                bytes,
+            });
+        }
+    }
+
+    check(maps, limit)
+}
+
+/// Fails if `maps` take more than `limit`, or `None` if there's no limit
+fn check(mut maps: Vec<MapMemory>, limit: Option<u64>) -> Result<(), Error> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    let total = maps.iter().map(|m| m.bytes).sum::<u64>();
+    if total <= limit {
+        return Ok(());
+    }
+
+    maps.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+    let largest = maps
+        .iter()
+        .take(MAX_LISTED)
+        .map(|m| format!("{}/{} {}", m.grain, m.map, m.bytes))
+        .collect::<Vec<_>>();
+    Err(format_err!(
+        "the maps of the grains take {} bytes of locked memory, over the \
+         RLIMIT_MEMLOCK of {} bytes. Raise it in [memlock], or with \
+         `ulimit -l`. The largest maps are: {}",
+        total,
+        limit,
+        largest.join(", ")
+    ))
+}
+
+/// Raises the limit to `bytes`, or lifts it, as far as allowed, and returns
+/// the limit in effect
+fn raise(bytes: Option<u64>) -> Result<Option<u64>, Error> {
+    let wanted = bytes.map_or(libc::RLIM_INFINITY, |b| b as libc::rlim_t);
+    let mut limit = get()?;
+    if limit.rlim_cur >= wanted {
+        return current();
+    }
+
+    let raised = libc::rlimit {
+        rlim_cur: wanted,
+        rlim_max: wanted.max(limit.rlim_max),
+    };
+    if let Err(e) = set(&raised) {
+        // the soft limit can go up to the hard one without privileges
+        warn!(error = %e, "unable to raise RLIMIT_MEMLOCK, raising it to the hard limit");
+        limit.rlim_cur = limit.rlim_max;
+        set(&limit)?;
+    }
+
+    let limit = current()?;
+    info!(limit = ?limit, "raised RLIMIT_MEMLOCK");
+    Ok(limit)
+}
+
+/// The soft limit, or `None` if there's none
+fn current() -> Result<Option<u64>, Error> {
+    let limit = get()?;
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return Ok(None);
+    }
+
+    Ok(Some(limit.rlim_cur as u64))
+}
+
+fn get() -> Result<libc::rlimit, Error> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(limit)
+}
+
+fn set(limit: &libc::rlimit) -> Result<(), Error> {
+    if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, limit) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_maps() {
+        let map = |grain: &str, map: &str, bytes| MapMemory {
+            grain: grain.to_string(),
+            map: map.to_string(),
+            bytes,
+        };
+        let maps = vec![
+            map("Network", "ip_connections", 4096),
+            map("Files", "actionlist", 65536),
+            map("Network", "host_pid", 1024),
+        ];
+
+        assert!(check(maps.clone(), None).is_ok());
+        assert!(check(maps.clone(), Some(70656)).is_ok());
+
+        let e = check(maps, Some(65536)).unwrap_err().to_string();
+        assert!(e.contains("70656 bytes"));
+        assert!(e.ends_with(
+            "Files/actionlist 65536, Network/ip_connections 4096, Network/host_pid 1024"
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+//! Keeps the timestamps of measurements steady when the wall clock steps.
+//!
+//! Once enabled, `timestamp_now()` is the monotonic clock plus an offset to
+//! the wall clock, so an NTP step, or someone setting the time, doesn't move
+//! timestamps back and forth between two checks. Every `interval_s`, the
+//! wall clock is compared to the monotonic clock. The offset follows small
+//! drifts between them, and when the wall clock moved by more than
+//! `max_skew_ms` since the previous check, a warning is logged and an
+//! `ingraind.clock.skew_ms` measurement is sent to `pipelines`. With
+//! `follow_steps`, the corrected time then jumps to the wall clock, otherwise
+//! it keeps following the monotonic clock.
+//!
+//! Measurements also get a `sequence` number, which orders them even when
+//! their timestamps were stepped.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix::{Actor, AsyncContext, Context, Recipient};
+
+use crate::backends::Message;
+use crate::grains::SendToManyRecipients;
+use crate::metrics::kind::GAUGE;
+use crate::metrics::{Measurement, Tags, Unit};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The wall clock minus the monotonic clock, in nanoseconds
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClockConfig {
+    #[serde(default = "default_interval_s")]
+    pub interval_s: u64,
+    #[serde(default = "default_max_skew_ms")]
+    pub max_skew_ms: u64,
+    #[serde(default = "default_follow_steps")]
+    pub follow_steps: bool,
+    /// Where `ingraind.clock.skew_ms` is sent
+    #[serde(default)]
+    pub pipelines: Vec<String>,
+}
+
+fn default_interval_s() -> u64 {
+    10
+}
+
+fn default_max_skew_ms() -> u64 {
+    500
+}
+
+fn default_follow_steps() -> bool {
+    true
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since the UNIX epoch, on the wall clock
+pub fn wall_now() -> u64 {
+    let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    duration.as_secs() * (1e9 as u64) + u64::from(duration.subsec_nanos())
+}
+
+/// Nanoseconds on the monotonic clock, like `bpf_ktime_get_ns()`
+pub fn monotonic_now() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * (1e9 as u64) + ts.tv_nsec as u64
+}
+
+/// Nanoseconds since the UNIX epoch, on the monotonic clock
+pub fn corrected_now() -> u64 {
+    monotonic_now().wrapping_add(OFFSET.load(Ordering::Relaxed))
+}
+
+/// The sequence number of the next measurement, if enabled
+pub fn next_sequence() -> Option<u64> {
+    if !enabled() {
+        return None;
+    }
+
+    Some(SEQUENCE.fetch_add(1, Ordering::Relaxed))
+}
+
+/// How far the wall clock moved from `offset` ahead of the monotonic clock,
+/// in nanoseconds, if that's more than `max_skew_ns`
+fn skew(offset: u64, monotonic: u64, wall: u64, max_skew_ns: u64) -> Option<i64> {
+    let skew = wall.wrapping_sub(monotonic.wrapping_add(offset)) as i64;
+    if skew.abs() as u64 > max_skew_ns {
+        Some(skew)
+    } else {
+        None
+    }
+}
+
+/// Checks the wall clock, and corrects the timestamps of measurements
+pub struct ClockWatch {
+    config: ClockConfig,
+    recipients: Vec<Recipient<Message>>,
+    /// The offset of the wall clock at the previous check
+    offset: u64,
+}
+
+impl ClockWatch {
+    pub fn with_config(config: ClockConfig, recipients: Vec<Recipient<Message>>) -> Self {
+        let offset = wall_now().wrapping_sub(monotonic_now());
+        OFFSET.store(offset, Ordering::Relaxed);
+        ENABLED.store(true, Ordering::Relaxed);
+
+        ClockWatch {
+            config,
+            recipients,
+            offset,
+        }
+    }
+
+    fn check(&mut self) {
+        let monotonic = monotonic_now();
+        let wall = wall_now();
+        let offset = wall.wrapping_sub(monotonic);
+        let max_skew_ns = self.config.max_skew_ms * 1_000_000;
+        let previous = self.offset;
+        self.offset = offset;
+
+        let skew = match skew(previous, monotonic, wall, max_skew_ns) {
+            Some(skew) => skew,
+            None => {
+                OFFSET.fetch_add(offset.wrapping_sub(previous), Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let skew_ms = skew as f64 / 1e6;
+        warn!(
+            "The wall clock stepped {:.0}ms {}",
+            skew_ms.abs(),
+            if skew > 0 { "forward" } else { "backward" }
+        );
+        if self.config.follow_steps {
+            OFFSET.store(offset, Ordering::Relaxed);
+        }
+
+        let mut tags = Tags::new();
+        tags.insert("follow_steps", self.config.follow_steps.to_string());
+        self.recipients.do_send(Message::Single(Measurement::new(
+            GAUGE,
+            "ingraind.clock.skew_ms".to_string(),
+            Unit::Gauge(skew_ms),
+            tags,
+        )));
+    }
+}
+
+impl Actor for ClockWatch {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = Duration::from_secs(self.config.interval_s);
+        ctx.run_interval(interval, |act, _ctx| act.check());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps() {
+        let offset = 1_600_000_000_000_000_000;
+        let max_skew_ns = 500_000_000;
+
+        assert_eq!(skew(offset, 1000, offset + 1000, max_skew_ns), None);
+        assert_eq!(
+            skew(offset, 1000, offset + 1000 + 400_000_000, max_skew_ns),
+            None
+        );
+        assert_eq!(
+            skew(offset, 1000, offset + 1000 + 2_000_000_000, max_skew_ns),
+            Some(2_000_000_000)
+        );
+        assert_eq!(
+            skew(offset, 1000, offset + 1000 - 600_000_000, max_skew_ns),
+            Some(-600_000_000)
+        );
+    }
+}
@@ -1,10 +1,24 @@
 use std::ops::RangeBounds;
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::vec::Drain;
-use std::hash::Hash;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::time::{Duration, Instant};
 
+use failure::{format_err, Error};
+
+pub mod clock;
+mod tag_str;
+mod tag_value;
+
+pub use self::tag_str::TagStr;
+pub use self::tag_value::{TagType, TagValue, Typed};
+
+/// The tags of a measurement. Keys are unique, and iterated in the order
+/// they were first inserted.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
-pub struct Tags(pub Vec<(String, String)>);
+pub struct Tags(Vec<(TagStr, TagValue)>);
 
 impl Tags {
     pub fn new() -> Tags {
@@ -15,38 +29,68 @@ impl Tags {
         self.0.len()
     }
 
-    pub fn insert(&mut self, k: impl Into<String>, v: impl Into<String>) {
-        self.0.push((k.into(), v.into()));
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 
+    /// Replaces the value of `k` if it's already set
+    pub fn insert(&mut self, k: impl Into<TagStr>, v: impl Into<TagValue>) {
+        let k = k.into();
+        let v = v.into();
+        match self.0.iter_mut().find(|(tk, _)| *tk == k) {
+            Some((_, tv)) => *tv = v,
+            None => self.0.push((k, v)),
+        }
+    }
+
+    /// Inserts all tags of `other`, which replace those already set
     pub fn append(&mut self, other: &mut Tags) {
-        self.0.append(&mut other.0);
+        for (k, v) in other.0.drain(..) {
+            self.insert(k, v);
+        }
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+    pub fn iter(&self) -> impl Iterator<Item = &(TagStr, TagValue)> {
         self.0.iter()
     }
 
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut (String, String)> {
-        self.0.iter_mut()
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&TagStr, &mut TagValue)> {
+        self.0.iter_mut().map(|(k, v)| (&*k, v))
     }
 
-    pub fn drain<R>(&mut self, r: R) -> Drain<(String, String)>
+    pub fn drain<R>(&mut self, r: R) -> Drain<(TagStr, TagValue)>
     where
         R: RangeBounds<usize>,
     {
         self.0.drain(r)
     }
 
-    pub fn get(&self, k: impl Into<String>) -> Option<&str> {
-        let ks = k.into();
-        self.0.iter().find_map(|(tk, tv)| {
-            if tk == &ks {
-                Some(tv.as_str())
-            } else {
-                None
-            }
-        })
+    pub fn retain(&mut self, mut f: impl FnMut(&TagStr, &TagValue) -> bool) {
+        self.0.retain(|(k, v)| f(k, v))
+    }
+
+    pub fn remove(&mut self, k: &str) -> Option<TagValue> {
+        let i = self.0.iter().position(|(tk, _)| tk == k)?;
+        Some(self.0.remove(i).1)
+    }
+
+    pub fn get(&self, k: &str) -> Option<&str> {
+        self.get_value(k).map(TagValue::as_str)
+    }
+
+    pub fn get_value(&self, k: &str) -> Option<&TagValue> {
+        self.0.iter().find(|(tk, _)| tk == k).map(|(_, tv)| tv)
+    }
+}
+
+impl<K: Into<TagStr>, V: Into<TagValue>> FromIterator<(K, V)> for Tags {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Tags {
+        let mut tags = Tags::new();
+        for (k, v) in iter {
+            tags.insert(k, v);
+        }
+
+        tags
     }
 }
 
@@ -61,6 +105,20 @@ impl ToTags for Tags {
 }
 
 pub mod kind {
+    //! The kind of a measurement says how its values add up. Kinds are
+    //! flags, so a measurement can have several, like `COUNTER | METER`.
+    //!
+    //! - `COUNTER` values are deltas, the events since the previous
+    //!   measurement, and are summed. With `CUMULATIVE`, they're running
+    //!   totals instead, and a total below the previous one means the
+    //!   counter was reset.
+    //! - `GAUGE` values are the current level of something, and replace the
+    //!   previous one. With `reset` unset, they adjust the level instead.
+    //! - `METER` counters are also reported as rates.
+    //! - `HISTOGRAM`, `TIMER` and `PERCENTILE` values are observations of a
+    //!   distribution.
+    //! - `SET` values are distinct elements, and `SET_UNIQUES` their count.
+
     pub type Kind = u16;
     pub const COUNTER: Kind = 1;
     pub const GAUGE: Kind = 2;
@@ -70,6 +128,35 @@ pub mod kind {
     pub const SET: Kind = 32;
     pub const SET_UNIQUES: Kind = 64;
     pub const PERCENTILE: Kind = 128;
+    pub const CUMULATIVE: Kind = 256;
+
+    /// How backends aggregate the values of a kind
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Semantics {
+        Delta,
+        Cumulative,
+        Gauge,
+        Distribution,
+        Set,
+    }
+
+    /// Gauges take precedence over counters, and counters over
+    /// distributions and sets. Kinds without any of them are counters.
+    pub fn semantics(kind: Kind) -> Semantics {
+        if kind & GAUGE != 0 {
+            Semantics::Gauge
+        } else if kind & (COUNTER | METER) != 0 && kind & CUMULATIVE != 0 {
+            Semantics::Cumulative
+        } else if kind & (COUNTER | METER) != 0 {
+            Semantics::Delta
+        } else if kind & (HISTOGRAM | TIMER | PERCENTILE) != 0 {
+            Semantics::Distribution
+        } else if kind & (SET | SET_UNIQUES) != 0 {
+            Semantics::Set
+        } else {
+            Semantics::Delta
+        }
+    }
 
     pub fn try_from_str(s: &str) -> Result<Kind, ()> {
         let k = match s.to_uppercase().as_str() {
@@ -81,6 +168,7 @@ pub mod kind {
             "SET" => SET,
             "SET_UNIQUES" => SET_UNIQUES,
             "PERCENTILE" => PERCENTILE,
+            "CUMULATIVE" => CUMULATIVE,
             _ => return Err(())
         };
 
@@ -90,31 +178,55 @@ pub mod kind {
 
 use self::kind::Kind;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Unit {
     #[serde(rename = "byte")]
     Byte(u64),
     #[serde(rename = "count")]
     Count(u64),
+    #[serde(rename = "gauge")]
+    Gauge(f64),
+    /// Nanoseconds
+    #[serde(rename = "duration")]
+    Duration(u64),
     #[serde(rename = "string")]
-    Str(String)
+    Str(String),
+    /// Raw bytes, such as a captured packet
+    #[serde(rename = "raw")]
+    Raw(Vec<u8>),
+    #[serde(rename = "histogram")]
+    Histogram(Buckets)
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum UnitType {
     Byte,
     Count,
-    Str
+    Gauge,
+    Duration,
+    Str,
+    Raw,
+    Histogram
 }
 
 impl UnitType {
-    pub fn to_unit(self, val: u64) -> Unit {
+    /// A value of this type, if it holds numbers
+    pub fn to_unit(self, val: u64) -> Result<Unit, Error> {
         use UnitType::*;
 
         match self {
-            Byte => Unit::Byte(val),
-            Count => Unit::Count(val),
-            _ => panic!("Invalid conversion")
+            Byte => Ok(Unit::Byte(val)),
+            Count => Ok(Unit::Count(val)),
+            Gauge => Ok(Unit::Gauge(val as f64)),
+            Duration => Ok(Unit::Duration(val)),
+            _ => Err(format_err!("{:?} values aren't numbers", self))
+        }
+    }
+
+    pub fn to_unit_f64(self, val: f64) -> Result<Unit, Error> {
+        match self {
+            UnitType::Gauge => Ok(Unit::Gauge(val)),
+            _ => self.to_unit(val as u64),
         }
     }
 
@@ -128,11 +240,22 @@ impl Unit {
         use self::Unit::*;
 
         match *self {
-            Byte(x) | Count(x) => x,
+            Byte(x) | Count(x) | Duration(x) => x,
+            Gauge(x) => x as u64,
             Str(_) =>  {
                 debug!("get() called on string metric");
                 0
             }
+            Raw(ref bytes) => bytes.len() as u64,
+            Histogram(ref buckets) => buckets.count(),
+        }
+    }
+
+    /// The value as a float, which is exact for gauges
+    pub fn get_f64(&self) -> f64 {
+        match *self {
+            Unit::Gauge(x) => x,
+            _ => self.get() as f64,
         }
     }
 
@@ -142,7 +265,11 @@ impl Unit {
         match *self {
             Byte(_) => UnitType::Byte,
             Count(_) => UnitType::Count,
+            Gauge(_) => UnitType::Gauge,
+            Duration(_) => UnitType::Duration,
             Str(_) => UnitType::Str,
+            Raw(_) => UnitType::Raw,
+            Histogram(_) => UnitType::Histogram,
         }
     }
 
@@ -152,6 +279,8 @@ impl Unit {
         let u = match s.to_uppercase().as_str() {
             "BYTE" => Byte(val),
             "COUNT" => Count(val),
+            "GAUGE" => Gauge(val as f64),
+            "DURATION" => Duration(val),
             _ => return Err(())
         };
 
@@ -159,6 +288,93 @@ impl Unit {
     }
 }
 
+/// A distribution of values, counted in buckets
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Buckets {
+    /// The inclusive upper bound of each bucket, in increasing order
+    pub bounds: Vec<u64>,
+    pub counts: Vec<u64>,
+    pub sum: u64,
+}
+
+impl Buckets {
+    /// Empty buckets with the upper bounds `bounds`, which have to be in
+    /// increasing order
+    pub fn new(bounds: Vec<u64>) -> Result<Buckets, Error> {
+        if bounds.is_empty() {
+            return Err(format_err!("histograms need at least one bucket"));
+        }
+        if bounds.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(format_err!("histogram bounds must be increasing"));
+        }
+
+        Ok(Buckets {
+            counts: vec![0; bounds.len()],
+            bounds,
+            sum: 0,
+        })
+    }
+
+    /// Power of two buckets, where bucket `i` counts the values whose
+    /// highest bit set is bit `i`. Zeros are counted in the first bucket.
+    pub fn log2(counts: &[u64], sum: u64) -> Buckets {
+        let bounds = (0..counts.len())
+            .map(|i| 1u64.checked_shl(i as u32 + 1).map_or(u64::MAX, |b| b - 1))
+            .collect();
+
+        Buckets {
+            bounds,
+            counts: counts.to_vec(),
+            sum,
+        }
+    }
+
+    /// Counts `value` in the first bucket it fits, or the last one
+    pub fn record(&mut self, value: u64) {
+        let i = self
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.bounds.len() - 1);
+
+        self.counts[i] += 1;
+        self.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The buckets that have values, as `(bound, count)` pairs
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.bounds
+            .iter()
+            .cloned()
+            .zip(self.counts.iter().cloned())
+            .filter(|(_, count)| *count > 0)
+    }
+
+    /// The bound of the bucket the `q` quantile falls in, or `None` if
+    /// nothing was recorded
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = ((q * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.bounds
+            .iter()
+            .zip(self.counts.iter())
+            .find(|(_, count)| {
+                seen += *count;
+                seen >= rank
+            })
+            .map(|(bound, _)| *bound)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Measurement {
     pub timestamp: u64,
@@ -168,6 +384,9 @@ pub struct Measurement {
     pub sample_rate: Option<f64>,
     pub reset: bool,
     pub tags: Tags,
+    /// Orders measurements when the clock is watched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
 }
 
 impl Measurement {
@@ -184,11 +403,197 @@ impl Measurement {
             sample_rate: Some(1.0),
             reset: true,
             tags,
+            sequence: clock::next_sequence(),
+        }
+    }
+}
+
+/// How long the total of a cumulative counter is kept without a new one
+const DELTAS_TTL: Duration = Duration::from_secs(600);
+
+/// Turns the totals of cumulative counters into deltas
+#[derive(Debug, Clone)]
+pub struct Deltas {
+    totals: HashMap<(String, u64), (Unit, Instant)>,
+    ttl: Duration,
+    swept: Instant,
+}
+
+impl Default for Deltas {
+    fn default() -> Deltas {
+        Deltas::with_ttl(DELTAS_TTL)
+    }
+}
+
+impl Deltas {
+    pub fn new() -> Deltas {
+        Deltas::default()
+    }
+
+    /// Forgets the totals of counters not seen for `ttl`, so the counters
+    /// of processes, connections and the like that are gone don't pile up
+    pub fn with_ttl(ttl: Duration) -> Deltas {
+        Deltas {
+            totals: HashMap::new(),
+            ttl,
+            swept: Instant::now(),
+        }
+    }
+
+    /// Replaces the total of a cumulative counter with its delta since the
+    /// previous one, and returns other measurements as they are. The first
+    /// total of every counter only sets where it starts, and is dropped.
+    pub fn apply(&mut self, mut m: Measurement) -> Option<Measurement> {
+        if kind::semantics(m.kind) != kind::Semantics::Cumulative {
+            return Some(m);
         }
+
+        let now = Instant::now();
+        if now.duration_since(self.swept) >= self.ttl {
+            let ttl = self.ttl;
+            self.totals
+                .retain(|_, (_, seen)| now.duration_since(*seen) < ttl);
+            self.swept = now;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        m.tags.hash(&mut hasher);
+        let key = (m.name.clone(), hasher.finish());
+        let (previous, _) = self.totals.insert(key, (m.value.clone(), now))?;
+        // a lower total means the counter was reset, and counted from 0 since
+        m.value = match (&m.value, previous) {
+            (Unit::Gauge(total), Unit::Gauge(previous)) if *total >= previous => {
+                Unit::Gauge(total - previous)
+            }
+            (Unit::Gauge(total), _) => Unit::Gauge(*total),
+            (total, previous) => {
+                let unit = total.get_type();
+                let (total, previous) = (total.get(), previous.get());
+                unit.to_unit(if total >= previous {
+                    total - previous
+                } else {
+                    total
+                })
+                .ok()?
+            }
+        };
+        m.kind &= !kind::CUMULATIVE;
+
+        Some(m)
     }
 }
 
+/// Nanoseconds since the UNIX epoch, corrected for steps of the wall clock
+/// if it's watched
 pub fn timestamp_now() -> u64 {
-    let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    duration.as_secs() * (1e9 as u64) + u64::from(duration.subsec_nanos())
+    if clock::enabled() {
+        clock::corrected_now()
+    } else {
+        clock::wall_now()
+    }
+}
+
+/// The time the monotonic clock started at, in nanoseconds since the UNIX
+/// epoch
+pub fn boot_time() -> u64 {
+    timestamp_now().saturating_sub(clock::monotonic_now())
+}
+
+/// Converts a timestamp from `bpf_ktime_get_ns()`, which is on the
+/// monotonic clock, to nanoseconds since the UNIX epoch
+pub fn from_kernel_time(ktime: u64) -> u64 {
+    boot_time().saturating_add(ktime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let mut tags = Tags::new();
+        tags.insert("process_str", "curl");
+        tags.insert("d_port", 443u16);
+        tags.insert("process_str", "wget");
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags.get("process_str"), Some("wget"));
+        assert_eq!(tags.get_value("d_port").unwrap().typed(), Typed::Int(443));
+
+        let mut other = vec![("d_port", "https"), ("team", "infra")]
+            .into_iter()
+            .collect::<Tags>();
+        tags.append(&mut other);
+        let keys = tags.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["process_str", "d_port", "team"]);
+        assert_eq!(tags.get("d_port"), Some("https"));
+    }
+
+    #[test]
+    fn deltas() {
+        let total = |value| {
+            Measurement::new(
+                kind::COUNTER | kind::CUMULATIVE,
+                "bytes".to_string(),
+                Unit::Byte(value),
+                Tags::new(),
+            )
+        };
+
+        let mut deltas = Deltas::new();
+        assert!(deltas.apply(total(100)).is_none());
+        let delta = deltas.apply(total(150)).unwrap();
+        assert_eq!(delta.value, Unit::Byte(50));
+        assert_eq!(kind::semantics(delta.kind), kind::Semantics::Delta);
+        // reset
+        assert_eq!(deltas.apply(total(20)).unwrap().value, Unit::Byte(20));
+
+        let gauge = Measurement::new(kind::GAUGE, "g".to_string(), Unit::Gauge(1.0), Tags::new());
+        assert_eq!(deltas.apply(gauge).unwrap().value, Unit::Gauge(1.0));
+
+        // expired totals start over
+        let mut deltas = Deltas::with_ttl(Duration::from_secs(0));
+        assert!(deltas.apply(total(100)).is_none());
+        assert!(deltas.apply(total(150)).is_none());
+    }
+
+    #[test]
+    fn log2_buckets() {
+        let buckets = Buckets::log2(&[1, 0, 2], 7);
+        assert_eq!(buckets.bounds, vec![1, 3, 7]);
+        assert_eq!(buckets.count(), 3);
+        assert_eq!(buckets.iter().collect::<Vec<_>>(), vec![(1, 1), (7, 2)]);
+        assert_eq!(Buckets::log2(&[0; 64], 0).bounds[63], u64::MAX);
+
+        assert!(Buckets::new(vec![]).is_err());
+        assert!(Buckets::new(vec![100, 10]).is_err());
+        let mut buckets = Buckets::new(vec![10, 100]).unwrap();
+        buckets.record(10);
+        buckets.record(1000);
+        assert_eq!(buckets.counts, vec![1, 1]);
+        assert_eq!(buckets.sum, 1010);
+    }
+
+    #[test]
+    fn quantiles() {
+        let buckets = Buckets::log2(&[90, 9, 0, 1], 100);
+        assert_eq!(buckets.quantile(0.5), Some(1));
+        assert_eq!(buckets.quantile(0.99), Some(3));
+        assert_eq!(buckets.quantile(1.0), Some(15));
+        assert_eq!(Buckets::log2(&[0; 4], 0).quantile(0.99), None);
+    }
+
+    #[test]
+    fn kernel_time() {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        let ktime = ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64;
+
+        let now = timestamp_now();
+        let converted = from_kernel_time(ktime - 1_000_000_000);
+        assert!(converted <= now - 1_000_000_000);
+        assert!(converted > now - 1_100_000_000);
+    }
 }
@@ -0,0 +1,215 @@
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::str;
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const INLINE_CAP: usize = 22;
+
+/// An immutable string for tag keys and values.
+///
+/// Short strings, like most keys and values, are stored inline and need no
+/// allocation. Longer ones are shared, so cloning measurements for several
+/// pipelines doesn't copy them.
+#[derive(Clone)]
+pub struct TagStr(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline { len: u8, buf: [u8; INLINE_CAP] },
+    Static(&'static str),
+    Heap(Arc<str>),
+}
+
+impl TagStr {
+    pub fn new(s: &str) -> TagStr {
+        if s.len() > INLINE_CAP {
+            return TagStr(Repr::Heap(Arc::from(s)));
+        }
+
+        let mut buf = [0u8; INLINE_CAP];
+        buf[..s.len()].copy_from_slice(s.as_bytes());
+        TagStr(Repr::Inline {
+            len: s.len() as u8,
+            buf,
+        })
+    }
+
+    pub const fn from_static(s: &'static str) -> TagStr {
+        TagStr(Repr::Static(s))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self.0 {
+            Repr::Inline { len, ref buf } => unsafe {
+                str::from_utf8_unchecked(&buf[..len as usize])
+            },
+            Repr::Static(s) => s,
+            Repr::Heap(ref s) => s,
+        }
+    }
+}
+
+impl Default for TagStr {
+    fn default() -> TagStr {
+        TagStr::from_static("")
+    }
+}
+
+impl Deref for TagStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for TagStr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for TagStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for TagStr {
+    fn from(s: &str) -> TagStr {
+        TagStr::new(s)
+    }
+}
+
+impl From<&String> for TagStr {
+    fn from(s: &String) -> TagStr {
+        TagStr::new(s)
+    }
+}
+
+impl From<String> for TagStr {
+    fn from(s: String) -> TagStr {
+        if s.len() > INLINE_CAP {
+            return TagStr(Repr::Heap(Arc::from(s)));
+        }
+
+        TagStr::new(&s)
+    }
+}
+
+impl From<Cow<'_, str>> for TagStr {
+    fn from(s: Cow<'_, str>) -> TagStr {
+        match s {
+            Cow::Borrowed(s) => TagStr::new(s),
+            Cow::Owned(s) => TagStr::from(s),
+        }
+    }
+}
+
+impl From<&TagStr> for TagStr {
+    fn from(s: &TagStr) -> TagStr {
+        s.clone()
+    }
+}
+
+impl From<TagStr> for String {
+    fn from(s: TagStr) -> String {
+        s.as_str().to_string()
+    }
+}
+
+impl PartialEq for TagStr {
+    fn eq(&self, other: &TagStr) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for TagStr {}
+
+impl PartialEq<str> for TagStr {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for TagStr {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for TagStr {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialOrd for TagStr {
+    fn partial_cmp(&self, other: &TagStr) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TagStr {
+    fn cmp(&self, other: &TagStr) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Hash for TagStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl fmt::Debug for TagStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for TagStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for TagStr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TagStr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TagStr, D::Error> {
+        String::deserialize(deserializer).map(TagStr::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    #[test]
+    fn tag_str() {
+        assert_eq!(size_of::<TagStr>(), size_of::<String>());
+
+        let short = TagStr::from("process_id");
+        assert!(match short.0 {
+            Repr::Inline { .. } => true,
+            _ => false,
+        });
+        assert_eq!(short, "process_id");
+
+        let long = TagStr::from("a".repeat(INLINE_CAP + 1));
+        assert_eq!(long.len(), INLINE_CAP + 1);
+        assert_eq!(long, TagStr::new(&long));
+        assert_eq!(TagStr::from_static("s_ip"), TagStr::new("s_ip"));
+    }
+}
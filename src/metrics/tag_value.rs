@@ -0,0 +1,297 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Deref;
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::TagStr;
+
+/// The type of a tag value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TagType {
+    Str,
+    Int,
+    Float,
+    Bool,
+    Ip,
+}
+
+/// A tag value that keeps its type.
+///
+/// Values are stored as text, so they're read as strings like before, and
+/// the type lets encoders emit numbers, booleans and addresses as such.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct TagValue {
+    text: TagStr,
+    kind: TagType,
+}
+
+/// The value of a tag, as its type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Typed<'a> {
+    Str(&'a str),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Ip(IpAddr),
+}
+
+impl TagValue {
+    fn with_type(text: impl Into<TagStr>, kind: TagType) -> TagValue {
+        TagValue {
+            text: text.into(),
+            kind,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.text.as_str()
+    }
+
+    pub fn kind(&self) -> TagType {
+        self.kind
+    }
+
+    pub fn typed(&self) -> Typed<'_> {
+        let text = self.as_str();
+        // the text of typed values always parses
+        match self.kind {
+            TagType::Str => Typed::Str(text),
+            TagType::Int => Typed::Int(text.parse().unwrap()),
+            TagType::Float => Typed::Float(text.parse().unwrap()),
+            TagType::Bool => Typed::Bool(text == "true"),
+            TagType::Ip => Typed::Ip(text.parse().unwrap()),
+        }
+    }
+}
+
+impl Deref for TagValue {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for TagValue {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<TagStr> for TagValue {
+    fn from(s: TagStr) -> TagValue {
+        TagValue::with_type(s, TagType::Str)
+    }
+}
+
+impl From<&TagStr> for TagValue {
+    fn from(s: &TagStr) -> TagValue {
+        TagValue::with_type(s, TagType::Str)
+    }
+}
+
+impl From<&str> for TagValue {
+    fn from(s: &str) -> TagValue {
+        TagValue::with_type(s, TagType::Str)
+    }
+}
+
+impl From<&String> for TagValue {
+    fn from(s: &String) -> TagValue {
+        TagValue::with_type(s, TagType::Str)
+    }
+}
+
+impl From<String> for TagValue {
+    fn from(s: String) -> TagValue {
+        TagValue::with_type(s, TagType::Str)
+    }
+}
+
+impl From<Cow<'_, str>> for TagValue {
+    fn from(s: Cow<'_, str>) -> TagValue {
+        TagValue::with_type(s, TagType::Str)
+    }
+}
+
+impl From<&TagValue> for TagValue {
+    fn from(v: &TagValue) -> TagValue {
+        v.clone()
+    }
+}
+
+macro_rules! from_int {
+    ($($t:ty),*) => {
+        $(impl From<$t> for TagValue {
+            fn from(x: $t) -> TagValue {
+                TagValue::with_type(x.to_string(), TagType::Int)
+            }
+        })*
+    };
+}
+
+from_int!(i8, i16, i32, i64, u8, u16, u32);
+
+/// Values beyond `i64::MAX` are kept as strings
+impl From<u64> for TagValue {
+    fn from(x: u64) -> TagValue {
+        match i64::try_from(x) {
+            Ok(x) => TagValue::from(x),
+            Err(_) => TagValue::from(x.to_string()),
+        }
+    }
+}
+
+impl From<f64> for TagValue {
+    fn from(x: f64) -> TagValue {
+        TagValue::with_type(x.to_string(), TagType::Float)
+    }
+}
+
+impl From<bool> for TagValue {
+    fn from(x: bool) -> TagValue {
+        TagValue::with_type(if x { "true" } else { "false" }, TagType::Bool)
+    }
+}
+
+impl From<IpAddr> for TagValue {
+    fn from(ip: IpAddr) -> TagValue {
+        TagValue::with_type(ip.to_string(), TagType::Ip)
+    }
+}
+
+impl From<Ipv4Addr> for TagValue {
+    fn from(ip: Ipv4Addr) -> TagValue {
+        TagValue::from(IpAddr::V4(ip))
+    }
+}
+
+impl From<Ipv6Addr> for TagValue {
+    fn from(ip: Ipv6Addr) -> TagValue {
+        TagValue::from(IpAddr::V6(ip))
+    }
+}
+
+impl From<TagValue> for String {
+    fn from(v: TagValue) -> String {
+        v.text.into()
+    }
+}
+
+impl PartialEq<str> for TagValue {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for TagValue {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Debug for TagValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.typed() {
+            Typed::Str(s) => fmt::Debug::fmt(s, f),
+            _ => fmt::Display::fmt(self.as_str(), f),
+        }
+    }
+}
+
+impl fmt::Display for TagValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+/// Numbers and booleans are serialized as such, and addresses as strings
+impl Serialize for TagValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.typed() {
+            Typed::Int(x) => serializer.serialize_i64(x),
+            Typed::Float(x) => serializer.serialize_f64(x),
+            Typed::Bool(x) => serializer.serialize_bool(x),
+            Typed::Str(_) | Typed::Ip(_) => serializer.serialize_str(self.as_str()),
+        }
+    }
+}
+
+/// Strings that are IP addresses are deserialized as addresses
+impl<'de> Deserialize<'de> for TagValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TagValue, D::Error> {
+        struct TagValueVisitor;
+
+        impl<'de> Visitor<'de> for TagValueVisitor {
+            type Value = TagValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string, number or boolean")
+            }
+
+            fn visit_bool<E: de::Error>(self, x: bool) -> Result<TagValue, E> {
+                Ok(x.into())
+            }
+
+            fn visit_i64<E: de::Error>(self, x: i64) -> Result<TagValue, E> {
+                Ok(x.into())
+            }
+
+            fn visit_u64<E: de::Error>(self, x: u64) -> Result<TagValue, E> {
+                Ok(x.into())
+            }
+
+            fn visit_f64<E: de::Error>(self, x: f64) -> Result<TagValue, E> {
+                Ok(x.into())
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<TagValue, E> {
+                Ok(match s.parse::<IpAddr>() {
+                    Ok(ip) => ip.into(),
+                    Err(_) => s.into(),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(TagValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed() {
+        assert_eq!(TagValue::from(1234u32).typed(), Typed::Int(1234));
+        assert_eq!(TagValue::from(1234u32), "1234");
+        assert_eq!(TagValue::from(u64::MAX).kind(), TagType::Str);
+        assert_eq!(TagValue::from(0.5).typed(), Typed::Float(0.5));
+        assert_eq!(TagValue::from(true).typed(), Typed::Bool(true));
+        assert_eq!(
+            TagValue::from(Ipv4Addr::new(10, 0, 0, 1)).typed(),
+            Typed::Ip(Ipv4Addr::new(10, 0, 0, 1).into())
+        );
+        assert_eq!(TagValue::from("curl").typed(), Typed::Str("curl"));
+    }
+
+    #[test]
+    fn serde() {
+        let values = vec![
+            TagValue::from(80u16),
+            TagValue::from("curl"),
+            TagValue::from(Ipv4Addr::new(10, 0, 0, 1)),
+            TagValue::from(false),
+        ];
+        let json = serde_json::to_string(&values).unwrap();
+        assert_eq!(json, r#"[80,"curl","10.0.0.1",false]"#);
+        assert_eq!(
+            serde_json::from_str::<Vec<TagValue>>(&json).unwrap(),
+            values
+        );
+    }
+}
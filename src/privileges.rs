@@ -0,0 +1,176 @@
+use std::ffi::CString;
+use std::io;
+use std::time::Duration;
+
+use actix::{Actor, AsyncContext, Context};
+use failure::{format_err, Error};
+
+use crate::grains::stats;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+const CAPABILITIES: &[(&str, u32)] = &[
+    ("CAP_DAC_OVERRIDE", 1),
+    ("CAP_DAC_READ_SEARCH", 2),
+    ("CAP_KILL", 5),
+    ("CAP_NET_ADMIN", 12),
+    ("CAP_NET_RAW", 13),
+    ("CAP_IPC_LOCK", 14),
+    ("CAP_SYS_PTRACE", 19),
+    ("CAP_SYS_ADMIN", 21),
+    ("CAP_SYS_RESOURCE", 24),
+    ("CAP_PERFMON", 38),
+    ("CAP_BPF", 39),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PrivilegesConfig {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Switches to an unprivileged user once all grains are attached.
+///
+/// Only the thread running the probes keeps the configured capabilities,
+/// every other thread loses all of them on the switch.
+pub struct DropPrivileges(PrivilegesConfig);
+
+impl DropPrivileges {
+    pub fn with_config(config: PrivilegesConfig) -> Self {
+        DropPrivileges(config)
+    }
+}
+
+impl Actor for DropPrivileges {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(POLL_INTERVAL, |act, ctx| {
+            if !stats::ready() {
+                return;
+            }
+
+            drop_privileges(&act.0).expect("Failed to drop privileges");
+            ctx.stop();
+        });
+    }
+}
+
+pub fn drop_privileges(config: &PrivilegesConfig) -> Result<(), Error> {
+    let user = match config.user {
+        Some(ref user) => user,
+        None => return Ok(()),
+    };
+    let (uid, user_gid) = lookup_user(user)?;
+    let gid = match config.group {
+        Some(ref group) => lookup_group(group)?,
+        None => user_gid,
+    };
+    let capabilities = capability_mask(&config.capabilities)?;
+
+    unsafe {
+        check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0))?;
+        check(libc::setgroups(1, &gid))?;
+        check(libc::setgid(gid))?;
+        check(libc::setuid(uid))?;
+        check(libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0))?;
+    }
+    set_capabilities(capabilities)?;
+
+    info!(
+        "Dropped privileges to {}:{}, retained capabilities: {:?}",
+        uid, gid, config.capabilities
+    );
+    Ok(())
+}
+
+fn set_capabilities(mask: u64) -> Result<(), Error> {
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    for (i, d) in data.iter_mut().enumerate() {
+        let bits = (mask >> (32 * i)) as u32;
+        d.effective = bits;
+        d.permitted = bits;
+    }
+
+    unsafe { check(libc::syscall(libc::SYS_capset, &header, data.as_ptr()) as i32) }
+}
+
+fn capability_mask(names: &[String]) -> Result<u64, Error> {
+    names.iter().try_fold(0u64, |mask, name| {
+        let bit = CAPABILITIES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, bit)| *bit)
+            .ok_or_else(|| format_err!("unknown capability: {}", name))?;
+
+        Ok(mask | 1 << bit)
+    })
+}
+
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), Error> {
+    let cname = CString::new(name)?;
+    let passwd = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format_err!("unknown user: {}", name));
+    }
+
+    unsafe { Ok(((*passwd).pw_uid, (*passwd).pw_gid)) }
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, Error> {
+    let cname = CString::new(name)?;
+    let group = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if group.is_null() {
+        return Err(format_err!("unknown group: {}", name));
+    }
+
+    unsafe { Ok((*group).gr_gid) }
+}
+
+fn check(ret: i32) -> Result<(), Error> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        let names = vec!["CAP_SYS_ADMIN".to_string(), "cap_bpf".to_string()];
+        assert_eq!(capability_mask(&names).unwrap(), 1 << 21 | 1 << 39);
+        assert!(capability_mask(&["CAP_CHOWN_EVERYTHING".to_string()]).is_err());
+    }
+
+    #[test]
+    fn users() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        assert!(lookup_user("no-such-user-ingraind").is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Recording of the raw events received from eBPF programs, and replaying
+//! them through the grains' handlers and the pipelines.
+//!
+//! A recording starts with the time the monotonic clock started at on the
+//! recording host, in nanoseconds since the UNIX epoch as a `u64`, so the
+//! kernel timestamps of events convert to the time they were seen at. It's
+//! followed by a sequence of events, each made of the timestamp in
+//! nanoseconds as a `u64`, followed by the grain name, the map name and the
+//! payload, each prefixed by its length as a `u32`. All integers are little
+//! endian.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix::{Actor, AsyncContext, Context, Recipient};
+use lazy_static::lazy_static;
+
+use crate::backends::Message;
+use crate::grains::{EventCallback, EventContext, SendToManyRecipients};
+use crate::metrics::{boot_time, timestamp_now};
+use crate::shutdown::flush_and_stop;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref RECORDER: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub timestamp: u64,
+    pub grain: String,
+    pub map: String,
+    pub data: Vec<u8>,
+}
+
+/// Starts recording all events to `path`
+pub fn start(path: &str) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&boot_time().to_le_bytes())?;
+    *RECORDER.lock().unwrap() = Some(writer);
+    RECORDING.store(true, Ordering::Release);
+
+    Ok(())
+}
+
+/// Records an event if recording is enabled
+pub fn record(grain: &str, map: &str, data: &[u8]) {
+    if !RECORDING.load(Ordering::Acquire) {
+        return;
+    }
+
+    if let Some(ref mut writer) = *RECORDER.lock().unwrap() {
+        if let Err(e) = write_event(writer, timestamp_now(), grain, map, data) {
+            error!("Failed to record event, recording stopped: {}", e);
+            RECORDING.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Writes out the buffered events
+pub fn flush() {
+    if let Some(ref mut writer) = *RECORDER.lock().unwrap() {
+        if let Err(e) = writer.flush() {
+            error!("Failed to flush recording: {}", e);
+        }
+    }
+}
+
+/// Flushes the recording every second, so it's usable even if the agent
+/// doesn't get to shut down cleanly.
+pub struct Flusher;
+
+impl Actor for Flusher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(FLUSH_INTERVAL, |_act, _ctx| flush());
+    }
+}
+
+pub fn write_event(
+    writer: &mut impl Write,
+    timestamp: u64,
+    grain: &str,
+    map: &str,
+    data: &[u8],
+) -> io::Result<()> {
+    writer.write_all(&timestamp.to_le_bytes())?;
+    for field in [grain.as_bytes(), map.as_bytes(), data].iter() {
+        writer.write_all(&(field.len() as u32).to_le_bytes())?;
+        writer.write_all(field)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the time the monotonic clock started at on the recording host
+pub fn read_boot_time(reader: &mut impl Read) -> io::Result<u64> {
+    let mut boot_time = [0u8; 8];
+    reader.read_exact(&mut boot_time)?;
+
+    Ok(u64::from_le_bytes(boot_time))
+}
+
+/// Reads the next event, or `None` at the end of the recording
+pub fn read_event(reader: &mut impl Read) -> io::Result<Option<Event>> {
+    let mut timestamp = [0u8; 8];
+    match reader.read_exact(&mut timestamp) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut field = || -> io::Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut buf = vec![0u8; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    };
+    let to_string = |buf: Vec<u8>| {
+        String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+
+    Ok(Some(Event {
+        timestamp: u64::from_le_bytes(timestamp),
+        grain: to_string(field()?)?,
+        map: to_string(field()?)?,
+        data: field()?,
+    }))
+}
+
+/// Returns the event handler of a grain for a map
+pub type Handlers = Box<dyn Fn(&str) -> EventCallback>;
+
+/// A probe to replay recorded events through
+pub struct ReplayProbe {
+    pub grain: String,
+    pub handlers: Handlers,
+    pub recipients: Vec<Recipient<Message>>,
+}
+
+/// Feeds a recording through the handlers of the configured probes, then
+/// flushes the pipelines and stops.
+///
+/// Events are replayed through every probe with the same grain as the one
+/// they were recorded from.
+pub struct Replay {
+    path: String,
+    probes: Vec<ReplayProbe>,
+    pipelines: Vec<Recipient<Message>>,
+    timeout: Duration,
+}
+
+impl Replay {
+    pub fn new(
+        path: String,
+        probes: Vec<ReplayProbe>,
+        pipelines: Vec<Recipient<Message>>,
+        timeout: Duration,
+    ) -> Self {
+        Replay {
+            path,
+            probes,
+            pipelines,
+            timeout,
+        }
+    }
+
+    fn replay(&self) -> io::Result<u64> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let context = EventContext {
+            boot_time: Some(read_boot_time(&mut reader)?),
+            ..Default::default()
+        };
+        let mut callbacks: HashMap<(usize, String), EventCallback> = HashMap::new();
+        let mut count = 0;
+
+        while let Some(event) = read_event(&mut reader)? {
+            count += 1;
+            for (i, probe) in self.probes.iter().enumerate() {
+                if probe.grain != event.grain {
+                    continue;
+                }
+
+                let callback = callbacks
+                    .entry((i, event.map.clone()))
+                    .or_insert_with(|| (probe.handlers)(&event.map));
+                match callback(&context, &event.data) {
+                    Ok(Some(message)) => probe.recipients.do_send(message),
+                    Ok(None) => {}
+                    Err(e) => debug!("Failed to decode {} event: {}", event.grain, e),
+                }
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+impl Actor for Replay {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        match self.replay() {
+            Ok(count) => info!("Replayed {} events from {}", count, self.path),
+            Err(e) => error!("Failed to replay {}: {}", self.path, e),
+        }
+
+        flush_and_stop(ctx, &self.pipelines, self.timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = 42u64.to_le_bytes().to_vec();
+        write_event(&mut buf, 1, "Network", "ip_connections", &[1, 2, 3]).unwrap();
+        write_event(&mut buf, 2, "DNS", "events", &[]).unwrap();
+
+        let mut reader = buf.as_slice();
+        assert_eq!(read_boot_time(&mut reader).unwrap(), 42);
+        let first = read_event(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            first,
+            Event {
+                timestamp: 1,
+                grain: "Network".to_string(),
+                map: "ip_connections".to_string(),
+                data: vec![1, 2, 3],
+            }
+        );
+        assert_eq!(read_event(&mut reader).unwrap().unwrap().grain, "DNS");
+        assert_eq!(read_event(&mut reader).unwrap(), None);
+
+        let mut truncated = &buf[8..18];
+        assert!(read_event(&mut truncated).is_err());
+    }
+}
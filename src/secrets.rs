@@ -0,0 +1,138 @@
+//! Credentials of backends, given in the configuration or read from files.
+//!
+//! Every secret option, like `password`, has a `*_file` variant, like
+//! `password_file`, that reads the secret from a file instead, such as a
+//! mounted Kubernetes or Docker secret. The file is read at startup, and read
+//! again whenever it changes, so rotated credentials are used without a
+//! restart. Trailing newlines are trimmed.
+//!
+//! Secrets can also be set from the environment, like any other option, with
+//! `INGRAIND__*` variables. Their `Debug` output is redacted, so they can't
+//! end up in logs.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use failure::{format_err, Error};
+
+pub const REDACTED: &str = "<redacted>";
+
+pub struct Secret {
+    file: Option<PathBuf>,
+    current: Mutex<Current>,
+}
+
+struct Current {
+    value: String,
+    modified: Option<SystemTime>,
+}
+
+impl Secret {
+    /// The secret `name` given as `value`, or read from `file`, or `None`
+    /// if neither is set
+    pub fn from_config(
+        name: &str,
+        value: Option<&String>,
+        file: Option<&String>,
+    ) -> Result<Option<Secret>, Error> {
+        let (value, file) = match (value, file) {
+            (Some(_), Some(_)) => {
+                return Err(format_err!("{} and {}_file are both set", name, name))
+            }
+            (None, None) => return Ok(None),
+            (Some(value), None) => (value.clone(), None),
+            (None, Some(file)) => {
+                let value = read(file).map_err(|e| format_err!("{}_file {}: {}", name, file, e))?;
+                (value, Some(PathBuf::from(file)))
+            }
+        };
+        let modified = file.as_ref().and_then(|file| modified(file));
+
+        Ok(Some(Secret {
+            file,
+            current: Mutex::new(Current { value, modified }),
+        }))
+    }
+
+    /// Like `from_config`, but one of `value` or `file` has to be set
+    pub fn required(
+        name: &str,
+        value: Option<&String>,
+        file: Option<&String>,
+    ) -> Result<Secret, Error> {
+        Secret::from_config(name, value, file)?
+            .ok_or_else(|| format_err!("{} or {}_file is required", name, name))
+    }
+
+    /// The current value of the secret, read again if its file changed
+    pub fn get(&self) -> String {
+        let mut current = self.current.lock().unwrap();
+        if let Some(ref file) = self.file {
+            let modified = modified(file);
+            if modified != current.modified {
+                // only warn once for every change that can't be read
+                current.modified = modified;
+                match read(file) {
+                    Ok(value) => current.value = value,
+                    Err(e) => warn!(
+                        "Failed to read {}, keeping the previous secret: {}",
+                        file.display(),
+                        e
+                    ),
+                }
+            }
+        }
+
+        current.value.clone()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+fn read(path: impl AsRef<Path>) -> io::Result<String> {
+    let value = fs::read_to_string(path)?;
+    Ok(value.trim_end_matches(&['\n', '\r'][..]).to_string())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn files() {
+        let path = env::temp_dir().join(format!("ingraind-secret-{}", process::id()));
+        let file = path.to_string_lossy().into_owned();
+        fs::write(&path, "hunter2\n").unwrap();
+
+        let secret = Secret::required("password", None, Some(&file)).unwrap();
+        assert_eq!(secret.get(), "hunter2");
+        assert_eq!(format!("{:?}", secret), REDACTED);
+
+        fs::write(&path, "correct horse").unwrap();
+        // the modification time may not have changed at its granularity
+        secret.current.lock().unwrap().modified = None;
+        assert_eq!(secret.get(), "correct horse");
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(secret.get(), "correct horse");
+
+        let value = "inline".to_string();
+        assert!(Secret::from_config("key", Some(&value), Some(&file)).is_err());
+        assert!(Secret::required("key", None, Some(&file)).is_err());
+        assert!(Secret::from_config("key", None, None).unwrap().is_none());
+    }
+}
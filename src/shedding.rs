@@ -0,0 +1,241 @@
+//! Sheds load when ingraind uses more CPU than its budget.
+//!
+//! Every `interval_s`, the CPU time used by ingraind since the last check
+//! is compared to `cpu_budget_percent` of one CPU. Over budget, load is shed
+//! one level at a time:
+//!
+//!  1. only one in `sample_every` events of eBPF grains is handled, and its
+//!     measurements are marked as sampled
+//!  2. the `low_priority` grains are disabled
+//!  3. pipelines with a flush schedule flush `flush_factor` times less often
+//!
+//! Once usage drops below `restore_ratio` of the budget, the levels are
+//! lifted in reverse, one at a time.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use actix::{Actor, AsyncContext, Context};
+use failure::{bail, Error};
+
+use crate::backends::Message;
+use crate::grains::stats;
+use crate::metrics::Measurement;
+use crate::telemetry::ProcessStats;
+
+pub const SAMPLING: usize = 1;
+pub const LOW_PRIORITY_DISABLED: usize = 2;
+pub const SLOW_FLUSH: usize = 3;
+
+static LEVEL: AtomicUsize = AtomicUsize::new(0);
+static SAMPLE_EVERY: AtomicU64 = AtomicU64::new(1);
+static FLUSH_FACTOR: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoadSheddingConfig {
+    /// Percent of one CPU
+    pub cpu_budget_percent: f64,
+    #[serde(default = "default_interval_s")]
+    pub interval_s: u64,
+    #[serde(default = "default_restore_ratio")]
+    pub restore_ratio: f64,
+    #[serde(default = "default_sample_every")]
+    pub sample_every: u64,
+    /// Grains disabled while shedding load
+    #[serde(default)]
+    pub low_priority: Vec<String>,
+    #[serde(default = "default_flush_factor")]
+    pub flush_factor: u64,
+}
+
+fn default_interval_s() -> u64 {
+    10
+}
+
+fn default_restore_ratio() -> f64 {
+    0.8
+}
+
+fn default_sample_every() -> u64 {
+    10
+}
+
+fn default_flush_factor() -> u64 {
+    4
+}
+
+impl LoadSheddingConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if !(self.cpu_budget_percent > 0.0 && self.cpu_budget_percent.is_finite()) {
+            bail!("load_shedding: cpu_budget_percent must be greater than 0");
+        }
+        if self.interval_s == 0 {
+            bail!("load_shedding: interval_s must be greater than 0");
+        }
+        if !(self.restore_ratio > 0.0 && self.restore_ratio <= 1.0) {
+            bail!("load_shedding: restore_ratio must be greater than 0, and at most 1");
+        }
+        if self.sample_every == 0 || self.flush_factor == 0 {
+            bail!("load_shedding: sample_every and flush_factor must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+/// The current level of load shedding, 0 when there's none
+pub fn level() -> usize {
+    LEVEL.load(Ordering::Relaxed)
+}
+
+/// The sample rate of the next event of a grain, or `None` if it should be
+/// dropped. `events` counts the grain's events while they're sampled, so
+/// every grain keeps one in `sample_every` of its own.
+pub fn sample(events: &AtomicU64) -> Option<f64> {
+    if level() < SAMPLING {
+        return Some(1.0);
+    }
+
+    let every = SAMPLE_EVERY.load(Ordering::Relaxed).max(1);
+    if events.fetch_add(1, Ordering::Relaxed) % every == 0 {
+        Some(1.0 / every as f64)
+    } else {
+        None
+    }
+}
+
+/// Marks the measurements of an event sampled at `rate`
+pub fn sampled(msg: Message, rate: f64) -> Message {
+    if rate >= 1.0 {
+        return msg;
+    }
+
+    let mark = |mut m: Measurement| {
+        m.sample_rate = Some(m.sample_rate.unwrap_or(1.0) * rate);
+        m
+    };
+    match msg {
+        Message::Single(m) => Message::Single(mark(m)),
+        Message::List(ms) => Message::List(ms.into_iter().map(mark).collect()),
+        Message::Flush => Message::Flush,
+    }
+}
+
+/// How many times less often pipelines flush
+pub fn flush_factor() -> u64 {
+    if level() < SLOW_FLUSH {
+        return 1;
+    }
+
+    FLUSH_FACTOR.load(Ordering::Relaxed).max(1)
+}
+
+/// The level to shed load at, given the CPU usage in percent of one CPU
+fn next_level(level: usize, usage: f64, config: &LoadSheddingConfig) -> usize {
+    if usage > config.cpu_budget_percent && level < SLOW_FLUSH {
+        level + 1
+    } else if usage < config.cpu_budget_percent * config.restore_ratio && level > 0 {
+        level - 1
+    } else {
+        level
+    }
+}
+
+/// Checks the CPU usage of ingraind, and sheds load accordingly
+pub struct LoadShedder {
+    config: LoadSheddingConfig,
+    cpu_ms: u64,
+    checked: Instant,
+}
+
+impl LoadShedder {
+    pub fn with_config(config: LoadSheddingConfig) -> Self {
+        SAMPLE_EVERY.store(config.sample_every, Ordering::Relaxed);
+        FLUSH_FACTOR.store(config.flush_factor, Ordering::Relaxed);
+
+        LoadShedder {
+            config,
+            cpu_ms: ProcessStats::current().unwrap_or_default().cpu_ms,
+            checked: Instant::now(),
+        }
+    }
+
+    fn check(&mut self) {
+        let process = match ProcessStats::current() {
+            Some(process) => process,
+            None => return,
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.checked);
+        let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        let usage =
+            process.cpu_ms.saturating_sub(self.cpu_ms) as f64 * 100.0 / elapsed_ms.max(1) as f64;
+        self.cpu_ms = process.cpu_ms;
+        self.checked = now;
+
+        let previous = level();
+        let level = next_level(previous, usage, &self.config);
+        if level == previous {
+            return;
+        }
+
+        if level > previous {
+            warn!(
+                "CPU usage {:.1}% is over the budget of {:.1}%, shedding load at level {}",
+                usage, self.config.cpu_budget_percent, level
+            );
+        } else {
+            info!(
+                "CPU usage {:.1}% is under budget, shedding load at level {}",
+                usage, level
+            );
+        }
+
+        if (previous < LOW_PRIORITY_DISABLED) != (level < LOW_PRIORITY_DISABLED) {
+            let shed = level >= LOW_PRIORITY_DISABLED;
+            for grain in self.config.low_priority.iter() {
+                stats::set_shed(grain, shed);
+            }
+        }
+        LEVEL.store(level, Ordering::Relaxed);
+    }
+}
+
+impl Actor for LoadShedder {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = Duration::from_secs(self.config.interval_s);
+        ctx.run_interval(interval, |act, _ctx| act.check());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let config: LoadSheddingConfig = toml::from_str("cpu_budget_percent = 10.0").unwrap();
+
+        assert_eq!(next_level(0, 5.0, &config), 0);
+        assert_eq!(next_level(0, 12.0, &config), SAMPLING);
+        assert_eq!(next_level(SAMPLING, 12.0, &config), LOW_PRIORITY_DISABLED);
+        assert_eq!(next_level(SLOW_FLUSH, 50.0, &config), SLOW_FLUSH);
+        assert_eq!(next_level(SLOW_FLUSH, 9.0, &config), SLOW_FLUSH);
+        assert_eq!(next_level(SLOW_FLUSH, 7.0, &config), LOW_PRIORITY_DISABLED);
+        assert_eq!(next_level(0, 1.0, &config), 0);
+    }
+
+    #[test]
+    fn check() {
+        let config = |s| toml::from_str::<LoadSheddingConfig>(s).unwrap().check();
+
+        assert!(config("cpu_budget_percent = 10.0").is_ok());
+        assert!(config("cpu_budget_percent = 0.0").is_err());
+        assert!(config("cpu_budget_percent = -5.0").is_err());
+        assert!(config("cpu_budget_percent = 10.0\ninterval_s = 0").is_err());
+        assert!(config("cpu_budget_percent = 10.0\nrestore_ratio = 1.5").is_err());
+        assert!(config("cpu_budget_percent = 10.0\nsample_every = 0").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use actix::{Actor, Arbiter, AsyncContext, Context, Recipient, Running, StreamHandler, System};
+use futures::{Future, Stream};
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+use crate::backends::Message;
+use crate::record;
+use crate::systemd;
+use crate::telemetry;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stops ingraind on SIGTERM or SIGINT.
+///
+/// Probes are stopped first by stopping the arbiter they run on, then every
+/// pipeline is flushed. The system is stopped once every backend has
+/// received the flush and has no requests in flight, or the timeout
+/// expires. A second signal stops the system immediately.
+pub struct Shutdown {
+    probes: Arbiter,
+    pipelines: Vec<Recipient<Message>>,
+    timeout: Duration,
+    stopping: bool,
+}
+
+/// Flushes every pipeline, and stops the system once all backends have
+/// delivered what they hold, or `timeout` expires
+pub fn flush_and_stop<A>(ctx: &mut Context<A>, pipelines: &[Recipient<Message>], timeout: Duration)
+where
+    A: Actor<Context = Context<A>>,
+{
+    for pipeline in pipelines.iter() {
+        let _ = pipeline.do_send(Message::Flush);
+    }
+
+    let deadline = Instant::now() + timeout;
+    ctx.run_interval(POLL_INTERVAL, move |_act, _ctx| {
+        let delivered = telemetry::backends()
+            .iter()
+            .all(|b| b.flushed && b.in_flight == 0);
+
+        if !delivered && Instant::now() < deadline {
+            return;
+        }
+        if !delivered {
+            warn!("Shutdown timed out, some measurements may not be delivered");
+        }
+
+        System::current().stop();
+    });
+}
+
+impl Shutdown {
+    pub fn new(probes: Arbiter, pipelines: Vec<Recipient<Message>>, timeout: Duration) -> Self {
+        Shutdown {
+            probes,
+            pipelines,
+            timeout,
+            stopping: false,
+        }
+    }
+}
+
+impl Actor for Shutdown {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let term = Signal::new(SIGTERM).flatten_stream();
+        let int = Signal::new(SIGINT).flatten_stream();
+        ctx.add_stream(term.select(int));
+    }
+}
+
+impl StreamHandler<i32, io::Error> for Shutdown {
+    fn handle(&mut self, signal: i32, ctx: &mut Context<Self>) {
+        if self.stopping {
+            warn!("Received signal {} again, exiting", signal);
+            System::current().stop();
+            return;
+        }
+
+        info!("Received signal {}, shutting down", signal);
+        self.stopping = true;
+        let _ = systemd::notify("STOPPING=1");
+        self.probes.stop();
+        record::flush();
+        flush_and_stop(ctx, &self.pipelines, self.timeout);
+    }
+
+    fn error(&mut self, err: io::Error, _ctx: &mut Self::Context) -> Running {
+        error!("Signal handler error: {}", err);
+        Running::Continue
+    }
+}
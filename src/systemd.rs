@@ -0,0 +1,148 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
+
+use actix::{Actor, AsyncContext, Context};
+
+use crate::grains::stats;
+use crate::telemetry::{self, BackendStatsSnapshot};
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sends `state` to the service manager, as described in sd_notify(3).
+///
+/// Returns `false` if ingraind is not running under systemd.
+pub fn notify(state: &str) -> io::Result<bool> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let path = path.as_bytes();
+
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid NOTIFY_SOCKET",
+        ));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(path.iter()) {
+        *dst = *src as libc::c_char;
+    }
+    // abstract namespace sockets are passed with a leading @
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let addr_len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    unsafe {
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let sent = libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            addr_len as libc::socklen_t,
+        );
+        let err = io::Error::last_os_error();
+        libc::close(fd);
+
+        if sent < 0 {
+            return Err(err);
+        }
+    }
+
+    Ok(true)
+}
+
+/// The interval at which the service manager expects keepalives, if the
+/// watchdog is enabled for this process
+fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec))
+}
+
+/// Reports readiness, status and watchdog keepalives to systemd.
+///
+/// Runs on the arbiter polling the probes, so that keepalives stop if the
+/// probes do.
+#[derive(Default)]
+pub struct Systemd {
+    ready: bool,
+    /// Whether keepalives are sent along with the status
+    watchdog: bool,
+    backends: Vec<BackendStatsSnapshot>,
+}
+
+impl Systemd {
+    pub fn new() -> Self {
+        Systemd::default()
+    }
+
+    fn report(&mut self) {
+        let grains = stats::all();
+        let attached = grains.iter().filter(|g| g.ready).count();
+
+        let current = telemetry::backends();
+        let healthy = current
+            .iter()
+            .filter(|b| {
+                self.backends
+                    .iter()
+                    .find(|p| p.pipeline == b.pipeline)
+                    .map_or(b.errors == 0, |p| p.errors == b.errors)
+            })
+            .count();
+        self.backends = current;
+
+        let mut state = format!(
+            "STATUS={} of {} probes attached, {} of {} backends healthy",
+            attached,
+            grains.len(),
+            healthy,
+            self.backends.len()
+        );
+        if !self.ready && stats::ready() {
+            self.ready = true;
+            state.push_str("\nREADY=1");
+        }
+        if self.watchdog {
+            state.push_str("\nWATCHDOG=1");
+        }
+
+        if let Err(e) = notify(&state) {
+            warn!("Failed to notify systemd: {}", e);
+        }
+    }
+}
+
+impl Actor for Systemd {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // keepalives go with the status, at least twice per timeout
+        let interval = match watchdog_timeout() {
+            Some(timeout) => {
+                self.watchdog = true;
+                STATUS_INTERVAL.min(timeout / 2)
+            }
+            None => STATUS_INTERVAL,
+        };
+
+        ctx.run_interval(interval, |act, _ctx| act.report());
+    }
+}
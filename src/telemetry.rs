@@ -0,0 +1,362 @@
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use actix::{Actor, AsyncContext, Context, Recipient};
+use failure::{bail, Error};
+use lazy_static::lazy_static;
+
+use crate::backends::Message;
+use crate::containers::{self, CacheStats};
+use crate::grains::SendToManyRecipients;
+use crate::metrics::kind::*;
+use crate::metrics::{Measurement, Tags, Unit};
+use crate::shedding;
+
+lazy_static! {
+    static ref BACKENDS: RwLock<Vec<Arc<BackendStats>>> = RwLock::new(Vec::new());
+    static ref CLOCK_TICKS: u64 = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    static ref PAGE_SIZE: u64 = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+}
+
+fn default_interval_s() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TelemetryConfig {
+    pub pipelines: Vec<String>,
+    #[serde(default = "default_interval_s")]
+    pub interval_s: u64,
+}
+
+impl TelemetryConfig {
+    pub fn check(&self) -> Result<(), Error> {
+        if self.interval_s == 0 {
+            bail!("telemetry: interval_s must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+/// Delivery statistics of a pipeline's backend.
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    pipeline: String,
+    messages: AtomicU64,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    in_flight: AtomicU64,
+    latency_ns: AtomicU64,
+    restarts: AtomicU64,
+    flushed: AtomicBool,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct BackendStatsSnapshot {
+    pub pipeline: String,
+    pub messages: u64,
+    pub requests: u64,
+    pub errors: u64,
+    pub in_flight: u64,
+    pub latency_ns: u64,
+    pub restarts: u64,
+    pub flushed: bool,
+}
+
+/// A delivery attempt of a backend, started with `BackendStats::request()`
+pub struct Request {
+    stats: Arc<BackendStats>,
+    start: Instant,
+}
+
+impl BackendStats {
+    pub fn register(pipeline: &str) -> Arc<BackendStats> {
+        let stats = Arc::new(BackendStats {
+            pipeline: pipeline.to_string(),
+            ..Default::default()
+        });
+        BACKENDS.write().unwrap().push(stats.clone());
+
+        stats
+    }
+
+    pub fn received(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a restart of the backend after it panicked
+    pub fn restarted(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks that a `Message::Flush` has reached the backend
+    pub fn flushed(&self) {
+        self.flushed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn request(self: &Arc<Self>) -> Request {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Request {
+            stats: self.clone(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn snapshot(&self) -> BackendStatsSnapshot {
+        BackendStatsSnapshot {
+            pipeline: self.pipeline.clone(),
+            messages: self.messages.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            latency_ns: self.latency_ns.load(Ordering::Relaxed),
+            restarts: self.restarts.load(Ordering::Relaxed),
+            flushed: self.flushed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Request {
+    pub fn finish(self, success: bool) {
+        let elapsed = self.start.elapsed();
+        let stats = &self.stats;
+
+        stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+        stats.requests.fetch_add(1, Ordering::Relaxed);
+        stats.latency_ns.fetch_add(
+            elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos()),
+            Ordering::Relaxed,
+        );
+        if !success {
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl BackendStatsSnapshot {
+    /// Reports the counters as the change since `previous`, and the latency
+    /// as the mean of the requests finished since then
+    pub fn to_measurements(&self, previous: &BackendStatsSnapshot) -> Vec<Measurement> {
+        let mut tags = Tags::new();
+        tags.insert("pipeline", self.pipeline.as_str());
+
+        let measurement = |kind, name: &str, value| {
+            Measurement::new(
+                kind,
+                format!("ingraind.self.backend.{}", name),
+                value,
+                tags.clone(),
+            )
+        };
+
+        let requests = self.requests - previous.requests;
+        let mut measurements = vec![
+            measurement(
+                COUNTER,
+                "messages",
+                Unit::Count(self.messages - previous.messages),
+            ),
+            measurement(COUNTER, "requests", Unit::Count(requests)),
+            measurement(
+                COUNTER,
+                "errors",
+                Unit::Count(self.errors - previous.errors),
+            ),
+            measurement(GAUGE, "in_flight", Unit::Count(self.in_flight)),
+            measurement(
+                COUNTER,
+                "restarts",
+                Unit::Count(self.restarts - previous.restarts),
+            ),
+        ];
+        if requests > 0 {
+            let latency = (self.latency_ns - previous.latency_ns) / requests;
+            measurements.push(measurement(TIMER, "latency", Unit::Duration(latency)));
+        }
+
+        measurements
+    }
+}
+
+/// Resource usage of the ingraind process
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ProcessStats {
+    pub cpu_ms: u64,
+    pub threads: u64,
+    pub rss_bytes: u64,
+    pub fds: u64,
+}
+
+impl ProcessStats {
+    pub fn current() -> Option<ProcessStats> {
+        let stat = fs::read_to_string("/proc/self/stat").ok()?;
+        let statm = fs::read_to_string("/proc/self/statm").ok()?;
+        let fds = fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+
+        let (cpu_ticks, threads) = parse_stat(&stat)?;
+        let rss_pages = statm.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+        Some(ProcessStats {
+            cpu_ms: cpu_ticks * 1000 / *CLOCK_TICKS,
+            threads,
+            rss_bytes: rss_pages * *PAGE_SIZE,
+            fds,
+        })
+    }
+
+    pub fn to_measurements(&self, previous: &ProcessStats) -> Vec<Measurement> {
+        let measurement = |kind, name: &str, value| {
+            Measurement::new(kind, format!("ingraind.self.{}", name), value, Tags::new())
+        };
+
+        vec![
+            measurement(
+                COUNTER,
+                "cpu_ms",
+                Unit::Count(self.cpu_ms - previous.cpu_ms),
+            ),
+            measurement(GAUGE, "threads", Unit::Count(self.threads)),
+            measurement(GAUGE, "rss", Unit::Byte(self.rss_bytes)),
+            measurement(GAUGE, "fds", Unit::Count(self.fds)),
+        ]
+    }
+}
+
+/// Returns the user and system CPU time in clock ticks, and the number of
+/// threads from the contents of `/proc/<pid>/stat`
+fn parse_stat(stat: &str) -> Option<(u64, u64)> {
+    // the command name can contain spaces and parens, so skip past the last
+    // paren, and count the fields from the state
+    let fields: Vec<_> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let utime = fields.get(11)?.parse::<u64>().ok()?;
+    let stime = fields.get(12)?.parse::<u64>().ok()?;
+    let threads = fields.get(17)?.parse::<u64>().ok()?;
+
+    Some((utime + stime, threads))
+}
+
+/// Statistics of all backends started so far
+pub fn backends() -> Vec<BackendStatsSnapshot> {
+    BACKENDS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|s| s.snapshot())
+        .collect()
+}
+
+/// Periodically reports the resource usage of ingraind, the delivery
+/// statistics of its backends, and the container cache's lookups under
+/// `ingraind.self.*`
+pub struct Telemetry {
+    interval: Duration,
+    recipients: Vec<Recipient<Message>>,
+    process: ProcessStats,
+    backends: Vec<BackendStatsSnapshot>,
+    containers: CacheStats,
+}
+
+impl Telemetry {
+    pub fn with_config(config: TelemetryConfig, recipients: Vec<Recipient<Message>>) -> Self {
+        Telemetry {
+            interval: Duration::from_secs(config.interval_s),
+            recipients,
+            process: ProcessStats::current().unwrap_or_default(),
+            backends: backends(),
+            containers: CacheStats::current(),
+        }
+    }
+
+    fn report(&mut self) {
+        let mut measurements = vec![];
+
+        if let Some(process) = ProcessStats::current() {
+            measurements.append(&mut process.to_measurements(&self.process));
+            self.process = process;
+        }
+        measurements.push(Measurement::new(
+            GAUGE,
+            "ingraind.self.load_shedding_level".to_string(),
+            Unit::Count(shedding::level() as u64),
+            Tags::new(),
+        ));
+
+        let current = backends();
+        for backend in current.iter() {
+            let previous = self
+                .backends
+                .iter()
+                .find(|p| p.pipeline == backend.pipeline)
+                .cloned()
+                .unwrap_or_default();
+            measurements.append(&mut backend.to_measurements(&previous));
+        }
+        self.backends = current;
+
+        if containers::watching() {
+            let current = CacheStats::current();
+            measurements.append(&mut current.to_measurements(&self.containers));
+            self.containers = current;
+        }
+
+        self.recipients.do_send(Message::List(measurements));
+    }
+}
+
+impl Actor for Telemetry {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |act, _ctx| act.report());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_stat() {
+        let stat = "1234 (ingraind (x)) S 1 1234 1234 0 -1 4194560 2508 0 0 0 \
+                    150 42 0 0 20 0 7 0 2863 1139470336 3110 18446744073709551615";
+
+        assert_eq!(parse_stat(stat), Some((192, 7)));
+        assert_eq!(parse_stat("1234 (ingraind) S 1"), None);
+    }
+
+    #[test]
+    fn backend_latency() {
+        let stats = BackendStats::register("test");
+        stats.received();
+        stats.request().finish(false);
+        let pending = stats.request();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests, 1);
+        assert_eq!(snapshot.errors, 1);
+        assert_eq!(snapshot.in_flight, 1);
+
+        pending.finish(true);
+        stats.restarted();
+        let measurements = stats.snapshot().to_measurements(&snapshot);
+        let names: Vec<_> = measurements.iter().map(|m| m.name.as_str()).collect();
+        assert!(names.contains(&"ingraind.self.backend.latency"));
+        assert_eq!(
+            measurements
+                .iter()
+                .find(|m| m.name == "ingraind.self.backend.errors")
+                .map(|m| m.value.clone()),
+            Some(Unit::Count(0))
+        );
+        assert_eq!(
+            measurements
+                .iter()
+                .find(|m| m.name == "ingraind.self.backend.restarts")
+                .map(|m| m.value.clone()),
+            Some(Unit::Count(1))
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Labels the measurements of shared hosts with the tenant they belong to.
+//!
+//! Every measurement of the probes is tagged with the name of the first
+//! `tenant` it matches as `tenant`. A tenant matches the measurements of the
+//! processes that run as any of its `uids`, given as ranges like
+//! `1000-1999`, in a cgroup under any of its `cgroups`, or in a pod of any
+//! of its `k8s_namespaces`. Measurements that match no tenant are tagged
+//! with `default`, if it's set.
+//!
+//! The uid and cgroups of processes are looked up in the process table of
+//! `grains::process_tree`, which keeps them up to date on exec when the
+//! `ProcessTree` grain is loaded, and remembers the processes that exited
+//! for a while.
+//!
+//! The measurements of tenants with `pipelines` are sent there, instead of
+//! to the pipelines of the probe, so every tenant can have its own backends.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use actix::prelude::*;
+use failure::{format_err, Error};
+use rayon::prelude::*;
+
+use crate::backends::Message;
+use crate::grains::{process_tree, SendToManyRecipients};
+use crate::metrics::Measurement;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TenancyConfig {
+    /// The tenant of the measurements that match no other
+    pub default: Option<String>,
+    #[serde(default)]
+    pub tenant: Vec<TenantConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TenantConfig {
+    pub name: String,
+    #[serde(default)]
+    pub uids: Vec<String>,
+    #[serde(default)]
+    pub cgroups: Vec<String>,
+    #[serde(default)]
+    pub k8s_namespaces: Vec<String>,
+    pub pipelines: Option<Vec<String>>,
+}
+
+struct Tenant {
+    name: String,
+    uids: Vec<RangeInclusive<u32>>,
+    cgroups: Vec<String>,
+    k8s_namespaces: Vec<String>,
+}
+
+/// The process a measurement was taken from, as far as tenants need it
+#[derive(Default)]
+struct Owner {
+    uid: Option<u32>,
+    cgroups: Vec<String>,
+    k8s_namespace: Option<String>,
+}
+
+impl Tenant {
+    fn matches(&self, owner: &Owner) -> bool {
+        let uid = owner
+            .uid
+            .map_or(false, |uid| self.uids.iter().any(|r| r.contains(&uid)));
+        let cgroup = owner
+            .cgroups
+            .iter()
+            .any(|path| self.cgroups.iter().any(|cgroup| in_cgroup(path, cgroup)));
+        let namespace = owner
+            .k8s_namespace
+            .as_ref()
+            .map_or(false, |ns| self.k8s_namespaces.contains(ns));
+
+        uid || cgroup || namespace
+    }
+}
+
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+    default: Option<String>,
+    /// The pipelines of the tenants that set them
+    pipelines: HashMap<String, Vec<String>>,
+}
+
+impl Tenants {
+    pub fn new(config: &TenancyConfig) -> Result<Tenants, Error> {
+        let mut tenants = vec![];
+        let mut pipelines = HashMap::new();
+        for tenant in config.tenant.iter() {
+            let uids = tenant
+                .uids
+                .iter()
+                .map(|uids| {
+                    uid_range(uids)
+                        .ok_or_else(|| format_err!("tenant {}: invalid uids {}", tenant.name, uids))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(ref p) = tenant.pipelines {
+                pipelines.insert(tenant.name.clone(), p.clone());
+            }
+
+            tenants.push(Tenant {
+                name: tenant.name.clone(),
+                uids,
+                cgroups: tenant.cgroups.clone(),
+                k8s_namespaces: tenant.k8s_namespaces.clone(),
+            });
+        }
+
+        Ok(Tenants {
+            tenants,
+            default: config.default.clone(),
+            pipelines,
+        })
+    }
+
+    /// The tenants that have their own pipelines, and the pipelines
+    pub fn pipelines(&self) -> &HashMap<String, Vec<String>> {
+        &self.pipelines
+    }
+
+    fn owner(&self, msg: &Measurement) -> Owner {
+        let mut owner = Owner::default();
+        let uids = self.tenants.iter().any(|t| !t.uids.is_empty());
+        let cgroups = self.tenants.iter().any(|t| !t.cgroups.is_empty());
+        let tagged_uid = msg.tags.get("uid").and_then(|uid| uid.parse().ok());
+
+        if (uids && tagged_uid.is_none()) || cgroups {
+            let process = msg
+                .tags
+                .get("process_id")
+                .and_then(|pid| pid.parse().ok())
+                .and_then(process_tree::get);
+            if let Some(process) = process {
+                owner.uid = process.uid;
+                owner.cgroups = process.cgroups;
+            }
+        }
+        if tagged_uid.is_some() {
+            owner.uid = tagged_uid;
+        }
+        if self.tenants.iter().any(|t| !t.k8s_namespaces.is_empty()) {
+            owner.k8s_namespace = k8s_namespace(msg);
+        }
+
+        owner
+    }
+
+    fn tenant(&self, owner: &Owner) -> Option<&str> {
+        self.tenants
+            .iter()
+            .find(|t| t.matches(owner))
+            .map(|t| t.name.as_str())
+            .or_else(|| self.default.as_ref().map(String::as_str))
+    }
+
+    /// Tags `msg` with its tenant, and returns it
+    fn label(&self, msg: &mut Measurement) -> Option<String> {
+        let tenant = self.tenant(&self.owner(msg))?.to_string();
+        msg.tags.insert("tenant", tenant.as_str());
+
+        Some(tenant)
+    }
+}
+
+/// A uid like `1000`, or a range like `1000-1999`
+fn uid_range(uids: &str) -> Option<RangeInclusive<u32>> {
+    let mut bounds = uids.splitn(2, '-').map(|b| b.trim().parse::<u32>());
+    let start = bounds.next()?.ok()?;
+    let end = match bounds.next() {
+        Some(end) => end.ok()?,
+        None => start,
+    };
+
+    if start > end {
+        return None;
+    }
+    Some(start..=end)
+}
+
+/// Whether the cgroup at `path` is `cgroup` or one of its descendants
+fn in_cgroup(path: &str, cgroup: &str) -> bool {
+    let cgroup = cgroup.trim_end_matches('/');
+    path.starts_with(cgroup)
+        && (path.len() == cgroup.len() || path[cgroup.len()..].starts_with('/'))
+}
+
+#[cfg(feature = "kubernetes")]
+fn k8s_namespace(msg: &Measurement) -> Option<String> {
+    match msg.tags.get("k8s_namespace") {
+        Some(ns) => Some(ns.to_string()),
+        None => crate::kubernetes::namespace(msg),
+    }
+}
+
+#[cfg(not(feature = "kubernetes"))]
+fn k8s_namespace(msg: &Measurement) -> Option<String> {
+    msg.tags.get("k8s_namespace").map(String::from)
+}
+
+/// Labels the measurements of a probe with their tenant, and sends them to
+/// its pipelines
+pub struct Tenancy {
+    tenants: Arc<Tenants>,
+    /// The pipelines of the probe
+    upstream: Vec<Recipient<Message>>,
+    routes: Arc<HashMap<String, Vec<Recipient<Message>>>>,
+}
+
+impl Actor for Tenancy {
+    type Context = Context<Self>;
+}
+
+impl Tenancy {
+    pub fn launch(
+        tenants: Arc<Tenants>,
+        routes: Arc<HashMap<String, Vec<Recipient<Message>>>>,
+        upstream: Vec<Recipient<Message>>,
+    ) -> Recipient<Message> {
+        Tenancy {
+            tenants,
+            upstream,
+            routes,
+        }
+        .start()
+        .recipient()
+    }
+
+    fn recipients(&self, tenant: Option<&String>) -> &Vec<Recipient<Message>> {
+        tenant
+            .and_then(|t| self.routes.get(t))
+            .unwrap_or(&self.upstream)
+    }
+}
+
+impl Handler<Message> for Tenancy {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let tenants = self.tenants.clone();
+        match msg {
+            Message::Single(mut m) => {
+                let tenant = tenants.label(&mut m);
+                self.recipients(tenant.as_ref()).do_send(Message::Single(m));
+            }
+            Message::List(mut ms) => {
+                let labels = ms
+                    .par_iter_mut()
+                    .map(|m| tenants.label(m))
+                    .collect::<Vec<_>>();
+
+                let mut batches = HashMap::new();
+                for (m, tenant) in ms.into_iter().zip(labels) {
+                    let route = tenant.filter(|t| self.routes.contains_key(t));
+                    batches.entry(route).or_insert_with(Vec::new).push(m);
+                }
+                for (route, batch) in batches {
+                    self.recipients(route.as_ref())
+                        .do_send(Message::List(batch));
+                }
+            }
+            Message::Flush => {
+                self.upstream.do_send(Message::Flush);
+                for route in self.routes.values() {
+                    route.do_send(Message::Flush);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenants() {
+        let config: TenancyConfig = toml::from_str(
+            r#"
+default = "shared"
+
+[[tenant]]
+name = "team-a"
+uids = ["1000-1999", "2500"]
+pipelines = ["team_a"]
+
+[[tenant]]
+name = "team-b"
+cgroups = ["/system.slice/team-b"]
+k8s_namespaces = ["team-b"]
+"#,
+        )
+        .unwrap();
+        let tenants = Tenants::new(&config).unwrap();
+        let tenant = |owner| tenants.tenant(&owner);
+
+        assert_eq!(
+            tenant(Owner {
+                uid: Some(2500),
+                ..Owner::default()
+            }),
+            Some("team-a")
+        );
+        assert_eq!(
+            tenant(Owner {
+                uid: Some(2000),
+                cgroups: process_tree::cgroup_paths("0::/system.slice/team-b/worker.service\n"),
+                ..Owner::default()
+            }),
+            Some("team-b")
+        );
+        assert_eq!(
+            tenant(Owner {
+                k8s_namespace: Some("team-b".to_string()),
+                ..Owner::default()
+            }),
+            Some("team-b")
+        );
+        assert_eq!(
+            tenant(Owner {
+                cgroups: vec!["/system.slice/team-bc".to_string()],
+                ..Owner::default()
+            }),
+            Some("shared")
+        );
+        assert_eq!(tenant(Owner::default()), Some("shared"));
+        assert_eq!(tenants.pipelines()["team-a"], vec!["team_a".to_string()]);
+
+        assert!(in_cgroup("/system.slice/team-b", "/system.slice/team-b/"));
+        assert!(in_cgroup("/system.slice", "/"));
+        assert_eq!(uid_range("1999-1000"), None);
+        assert_eq!(uid_range("root"), None);
+    }
+}
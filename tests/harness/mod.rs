@@ -0,0 +1,106 @@
+//! Runs a grain in an agent, and waits for a measurement to arrive.
+//!
+//! A test passes the grain, some activity for it to observe, and what the
+//! measurement it expects looks like:
+//!
+//! ```ignore
+//! let m = harness::expect(grain, || generate_activity(), |m| m.name == "...");
+//! ```
+//!
+//! The activity is repeated until the measurement arrives, since the
+//! grain's programs attach some time after the agent starts.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use actix::prelude::*;
+
+use ingraind::agent::Agent;
+use ingraind::backends::Message;
+use ingraind::config::Grain;
+use ingraind::metrics::Measurement;
+
+/// How long tests wait for the measurement they expect
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+const ACTIVITY_INTERVAL: Duration = Duration::from_millis(200);
+const PIPELINE: &str = "test";
+
+type Expected = Box<dyn Fn(&Measurement) -> bool>;
+
+/// A backend that keeps the first measurement that's expected, and stops
+/// the system
+struct Collector {
+    expected: Expected,
+    found: Arc<Mutex<Option<Measurement>>>,
+}
+
+impl Actor for Collector {
+    type Context = Context<Self>;
+}
+
+impl Handler<Message> for Collector {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, _ctx: &mut Context<Self>) -> Self::Result {
+        let measurements = match msg {
+            Message::Single(m) => vec![m],
+            Message::List(ms) => ms,
+            Message::Flush => return,
+        };
+
+        let mut found = self.found.lock().unwrap();
+        if found.is_some() {
+            return;
+        }
+        if let Some(m) = measurements.into_iter().find(|m| (self.expected)(m)) {
+            *found = Some(m);
+            System::current().stop();
+        }
+    }
+}
+
+/// Runs `grain` while repeating `activity`, and returns the first
+/// measurement that's `expected`, or `None` after `TIMEOUT`
+pub fn expect<A, E>(grain: Grain, activity: A, expected: E) -> Option<Measurement>
+where
+    A: Fn() + Send + 'static,
+    E: Fn(&Measurement) -> bool + 'static,
+{
+    let found = Arc::new(Mutex::new(None));
+    let done = Arc::new(AtomicBool::new(false));
+    let system = System::new("ingraind-test");
+
+    let collector = Collector {
+        expected: Box::new(expected),
+        found: found.clone(),
+    }
+    .start();
+    Agent::builder()
+        .grain(grain, &[PIPELINE])
+        .recipient(PIPELINE, collector.recipient())
+        .build()
+        .unwrap()
+        .start();
+
+    let running = done.clone();
+    let activity = thread::spawn(move || {
+        while !running.load(Ordering::Relaxed) {
+            activity();
+            thread::sleep(ACTIVITY_INTERVAL);
+        }
+    });
+    let current = System::current();
+    thread::spawn(move || {
+        thread::sleep(TIMEOUT);
+        current.stop();
+    });
+
+    system.run().unwrap();
+    done.store(true, Ordering::Relaxed);
+    activity.join().unwrap();
+
+    let mut found = found.lock().unwrap();
+    found.take()
+}
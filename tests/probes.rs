@@ -0,0 +1,90 @@
+//! End-to-end tests of the bundled probes.
+//!
+//! They load eBPF programs into the running kernel, so they need root, and
+//! only run with the `privileged-tests` feature:
+//!
+//!     $ sudo -E cargo test --features privileged-tests --test probes
+//!
+//! `Grain::Harness` loads the smallest bundled probe, and checks that
+//! loading, attaching and decoding events work at all. To test another
+//! grain, generate the activity it observes, and pass it to
+//! `harness::expect` with the measurement it should produce.
+
+#![cfg(feature = "privileged-tests")]
+
+mod harness;
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use ingraind::config::Grain;
+use ingraind::grains::file::FilesConfig;
+use ingraind::grains::network::NetworkConfig;
+
+#[test]
+fn sleeps() {
+    let pid = process::id().to_string();
+    let m = harness::expect(
+        Grain::Harness,
+        || thread::sleep(Duration::from_millis(1)),
+        move |m| m.name == "harness.sleep" && m.tags.get("process_id") == Some(&*pid),
+    );
+
+    let m = m.expect("no harness.sleep measurement");
+    assert_eq!(m.value.get(), 1);
+}
+
+#[test]
+fn file_writes() {
+    let dir = env::temp_dir().join(format!("ingraind-test-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("written");
+
+    let grain = Grain::Files(FilesConfig {
+        monitor_dirs: vec![dir.to_string_lossy().into_owned()],
+        aggregate_interval_ms: None,
+        filters: None,
+        disk_usage: None,
+    });
+    let file = path.clone();
+    let m = harness::expect(
+        grain,
+        move || fs::write(&file, b"ingraind").unwrap(),
+        |m| {
+            m.name == "file.write"
+                && m.tags
+                    .get("path_str")
+                    .map_or(false, |p| p.ends_with("written"))
+        },
+    );
+    fs::remove_dir_all(&dir).unwrap();
+
+    let m = m.expect("no file.write measurement");
+    assert_eq!(m.value.get(), 8);
+}
+
+#[test]
+fn loopback_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let _ = stream.map(|mut s| s.read(&mut [0; 16]));
+        }
+    });
+
+    let m = harness::expect(
+        Grain::Network(NetworkConfig::default()),
+        move || drop(TcpStream::connect(("127.0.0.1", port))),
+        move |m| m.name == "connection.out" && m.tags.get("d_port") == Some(&*port.to_string()),
+    );
+
+    let m = m.expect("no connection.out measurement");
+    assert_eq!(m.tags.get("d_ip"), Some("127.0.0.1"));
+    assert_eq!(m.tags.get("s_ip"), Some("127.0.0.1"));
+}